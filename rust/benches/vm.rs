@@ -0,0 +1,87 @@
+//! Criterion micro-benchmarks for the bytecode VM hot path: arithmetic,
+//! calls, containers and strings, each one script function run per
+//! iteration on a VM created once.
+//!
+//! Run: `cargo bench --manifest-path rust/Cargo.toml --bench vm`
+//! Record before/after numbers in `benchmarks/reports/optimization/`.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use elpian_vm::sdk::vm::VM;
+
+const ARITHMETIC: &str = r#"func work() {
+    def i = 0
+    def sum = 0
+    def f = 0.5
+    loop i < 2000 {
+        sum = sum + i * 3 - i / 2
+        f = f * 1.0001 + 0.25
+        i = i + 1
+    }
+    return sum
+}
+"#;
+
+const CALLS: &str = r#"func fib(n) {
+    if n < 2 {
+        return n
+    }
+    return fib(n - 1) + fib(n - 2)
+}
+func work() {
+    return fib(15)
+}
+"#;
+
+const CONTAINERS: &str = r#"func work() {
+    def items = []
+    def i = 0
+    loop i < 300 {
+        items = items + { id: i, score: i * 2 }
+        i = i + 1
+    }
+    def total = 0
+    i = 0
+    loop i < 300 {
+        total = total + items[i].score
+        i = i + 1
+    }
+    return total
+}
+"#;
+
+const STRINGS: &str = r#"func work() {
+    def text = ""
+    def i = 0
+    loop i < 300 {
+        text = text + "item " + i + ", "
+        i = i + 1
+    }
+    return text == ""
+}
+"#;
+
+fn vm_of(code: &str) -> VM {
+    let mut vm = VM::compile_and_create_of_code("bench".to_string(), code.to_string(), 0, vec![])
+        .expect("benchmark script must compile");
+    vm.run().expect("benchmark script must run");
+    vm
+}
+
+fn bench_scripts(c: &mut Criterion) {
+    let mut group = c.benchmark_group("vm_script");
+    for (name, code) in [
+        ("arithmetic", ARITHMETIC),
+        ("calls", CALLS),
+        ("containers", CONTAINERS),
+        ("strings", STRINGS),
+    ] {
+        let mut vm = vm_of(code);
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter(|| vm.run_func_with_input("work", None, 0).unwrap());
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_scripts);
+criterion_main!(benches);
@@ -8,12 +8,15 @@
 use std::ffi::{CStr, CString};
 use std::os::raw::c_char;
 
-use serde_json::json;
-
 use super::{
-    continue_execution, create_vm_from_ast, create_vm_from_code, destroy_vm, execute_vm,
-    execute_vm_func, execute_vm_func_with_input, init_vm_system, validate_ast, vm_exists,
-    VmExecResult,
+    abort_vm_execution, collect_vm_garbage, compile_ast_to_bytecode, compile_to_bytecode,
+    continue_execution, continue_execution_binary, continue_execution_with_error,
+    create_vm_from_ast, create_vm_from_bytecode, create_vm_from_code, create_vm_from_modules,
+    debug_vm, destroy_vm, disassemble_code, disassemble_vm, exec_result_json, execute_vm,
+    execute_vm_func, execute_vm_func_with_input, get_host_call_input_binary, get_vm_gc_stats,
+    get_vm_memory_usage, init_vm_system, parse_capabilities, reject_host_call, reload_vm_code,
+    resolve_host_call, restore_vm, resume_vm_with_fuel, set_vm_fuel_limit, set_vm_memory_limit,
+    snapshot_vm, tick_vm, validate_ast, vm_exists, VmExecResult,
 };
 
 /// Helper: convert C string pointer to Rust String.
@@ -30,14 +33,33 @@ fn string_to_c_str(s: String) -> *mut c_char {
     CString::new(s).unwrap_or_default().into_raw()
 }
 
+/// Helper: hand a byte buffer to the caller, writing its length to `out_len`.
+/// Caller must free with `elpian_free_bytes`. `None` becomes a null pointer.
+unsafe fn bytes_to_c_buf(bytes: Option<Vec<u8>>, out_len: *mut usize) -> *mut u8 {
+    let Some(bytes) = bytes else {
+        if !out_len.is_null() {
+            *out_len = 0;
+        }
+        return std::ptr::null_mut();
+    };
+    let boxed = bytes.into_boxed_slice();
+    if !out_len.is_null() {
+        *out_len = boxed.len();
+    }
+    Box::into_raw(boxed) as *mut u8
+}
+
+/// Helper: copy `len` bytes from a caller buffer.
+unsafe fn c_buf_to_vec(ptr: *const u8, len: usize) -> Vec<u8> {
+    if ptr.is_null() {
+        return vec![];
+    }
+    std::slice::from_raw_parts(ptr, len).to_vec()
+}
+
 /// Helper: serialize VmExecResult to JSON C string.
 fn result_to_c_str(r: VmExecResult) -> *mut c_char {
-    let json = json!({
-        "hasHostCall": r.has_host_call,
-        "hostCallData": r.host_call_data,
-        "resultValue": r.result_value,
-    });
-    string_to_c_str(json.to_string())
+    string_to_c_str(exec_result_json(&r).to_string())
 }
 
 // ── Public FFI Functions ────────────────────────────────────────────
@@ -52,6 +74,18 @@ pub extern "C" fn elpian_free_string(ptr: *mut c_char) {
     }
 }
 
+/// Free a buffer previously returned by `elpian_compile_to_bytecode`,
+/// `elpian_compile_ast_to_bytecode` or `elpian_get_host_call_binary`, with
+/// the length reported for it.
+#[unsafe(no_mangle)]
+pub extern "C" fn elpian_free_bytes(ptr: *mut u8, len: usize) {
+    if !ptr.is_null() {
+        unsafe {
+            drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(ptr, len)));
+        }
+    }
+}
+
 /// Initialize the VM subsystem.
 #[unsafe(no_mangle)]
 pub extern "C" fn elpian_init() {
@@ -66,7 +100,30 @@ pub extern "C" fn elpian_create_vm_from_ast(
 ) -> i32 {
     let mid = unsafe { c_str_to_string(machine_id) };
     let ast = unsafe { c_str_to_string(ast_json) };
-    if create_vm_from_ast(mid, ast) {
+    if create_vm_from_ast(mid, ast, None) {
+        1
+    } else {
+        0
+    }
+}
+
+/// Create a VM from AST JSON restricted to the host apis listed in
+/// `capabilities_json` (a JSON string array, wildcards like "canvas.*" allowed).
+/// Returns 1 on success, 0 on failure.
+#[unsafe(no_mangle)]
+pub extern "C" fn elpian_create_vm_from_ast_with_capabilities(
+    machine_id: *const c_char,
+    ast_json: *const c_char,
+    capabilities_json: *const c_char,
+) -> i32 {
+    let mid = unsafe { c_str_to_string(machine_id) };
+    let ast = unsafe { c_str_to_string(ast_json) };
+    let caps = unsafe { c_str_to_string(capabilities_json) };
+    let capabilities = match parse_capabilities(&caps) {
+        Some(c) => c,
+        None => return 0,
+    };
+    if create_vm_from_ast(mid, ast, Some(capabilities)) {
         1
     } else {
         0
@@ -81,13 +138,142 @@ pub extern "C" fn elpian_create_vm_from_code(
 ) -> i32 {
     let mid = unsafe { c_str_to_string(machine_id) };
     let c = unsafe { c_str_to_string(code) };
-    if create_vm_from_code(mid, c) {
+    if create_vm_from_code(mid, c, None) {
+        1
+    } else {
+        0
+    }
+}
+
+/// Recompile a VM's code keeping its globals (see `reload_vm_code`).
+/// Returns JSON string (must be freed).
+#[unsafe(no_mangle)]
+pub extern "C" fn elpian_reload_vm_code(
+    machine_id: *const c_char,
+    code: *const c_char,
+) -> *mut c_char {
+    let mid = unsafe { c_str_to_string(machine_id) };
+    let c = unsafe { c_str_to_string(code) };
+    string_to_c_str(reload_vm_code(mid, c))
+}
+
+/// Create a VM from source code restricted to the host apis listed in
+/// `capabilities_json`. Returns 1 on success, 0 on failure.
+#[unsafe(no_mangle)]
+pub extern "C" fn elpian_create_vm_from_code_with_capabilities(
+    machine_id: *const c_char,
+    code: *const c_char,
+    capabilities_json: *const c_char,
+) -> i32 {
+    let mid = unsafe { c_str_to_string(machine_id) };
+    let c = unsafe { c_str_to_string(code) };
+    let caps = unsafe { c_str_to_string(capabilities_json) };
+    let capabilities = match parse_capabilities(&caps) {
+        Some(c) => c,
+        None => return 0,
+    };
+    if create_vm_from_code(mid, c, Some(capabilities)) {
         1
     } else {
         0
     }
 }
 
+/// Create a VM from source code that imports the modules in `modules_json`,
+/// a JSON object from module name to source (see `create_vm_from_modules`).
+/// Returns JSON string (must be freed).
+#[unsafe(no_mangle)]
+pub extern "C" fn elpian_create_vm_from_modules(
+    machine_id: *const c_char,
+    code: *const c_char,
+    modules_json: *const c_char,
+) -> *mut c_char {
+    let mid = unsafe { c_str_to_string(machine_id) };
+    let c = unsafe { c_str_to_string(code) };
+    let modules = unsafe { c_str_to_string(modules_json) };
+    string_to_c_str(create_vm_from_modules(mid, c, modules, None))
+}
+
+/// Like `elpian_create_vm_from_modules`, restricted to the host apis listed
+/// in `capabilities_json`. Malformed capabilities give
+/// `{"error": "bad_capabilities"}`. Returns JSON string (must be freed).
+#[unsafe(no_mangle)]
+pub extern "C" fn elpian_create_vm_from_modules_with_capabilities(
+    machine_id: *const c_char,
+    code: *const c_char,
+    modules_json: *const c_char,
+    capabilities_json: *const c_char,
+) -> *mut c_char {
+    let mid = unsafe { c_str_to_string(machine_id) };
+    let c = unsafe { c_str_to_string(code) };
+    let modules = unsafe { c_str_to_string(modules_json) };
+    let caps = unsafe { c_str_to_string(capabilities_json) };
+    let result = match parse_capabilities(&caps) {
+        Some(capabilities) => create_vm_from_modules(mid, c, modules, Some(capabilities)),
+        None => "{\"error\":\"bad_capabilities\"}".to_string(),
+    };
+    string_to_c_str(result)
+}
+
+/// Create a VM from a bytecode container of `len` bytes. The container is
+/// verified first. Returns 1 on success, 0 on failure.
+#[unsafe(no_mangle)]
+pub extern "C" fn elpian_create_vm_from_bytecode(
+    machine_id: *const c_char,
+    bytecode: *const u8,
+    len: usize,
+) -> i32 {
+    let mid = unsafe { c_str_to_string(machine_id) };
+    let bytes = unsafe { c_buf_to_vec(bytecode, len) };
+    if create_vm_from_bytecode(mid, bytes, None) {
+        1
+    } else {
+        0
+    }
+}
+
+/// Create a VM from a bytecode container restricted to the host apis listed
+/// in `capabilities_json`. Returns 1 on success, 0 on failure.
+#[unsafe(no_mangle)]
+pub extern "C" fn elpian_create_vm_from_bytecode_with_capabilities(
+    machine_id: *const c_char,
+    bytecode: *const u8,
+    len: usize,
+    capabilities_json: *const c_char,
+) -> i32 {
+    let mid = unsafe { c_str_to_string(machine_id) };
+    let bytes = unsafe { c_buf_to_vec(bytecode, len) };
+    let caps = unsafe { c_str_to_string(capabilities_json) };
+    let capabilities = match parse_capabilities(&caps) {
+        Some(c) => c,
+        None => return 0,
+    };
+    if create_vm_from_bytecode(mid, bytes, Some(capabilities)) {
+        1
+    } else {
+        0
+    }
+}
+
+/// Compile source code into a bytecode container. Returns the buffer and
+/// writes its length to `out_len` (must be freed with `elpian_free_bytes`),
+/// or returns null when the code has syntax errors.
+#[unsafe(no_mangle)]
+pub extern "C" fn elpian_compile_to_bytecode(code: *const c_char, out_len: *mut usize) -> *mut u8 {
+    let c = unsafe { c_str_to_string(code) };
+    unsafe { bytes_to_c_buf(compile_to_bytecode(c), out_len) }
+}
+
+/// Compile AST JSON into a bytecode container, like `elpian_compile_to_bytecode`.
+#[unsafe(no_mangle)]
+pub extern "C" fn elpian_compile_ast_to_bytecode(
+    ast_json: *const c_char,
+    out_len: *mut usize,
+) -> *mut u8 {
+    let ast = unsafe { c_str_to_string(ast_json) };
+    unsafe { bytes_to_c_buf(compile_ast_to_bytecode(ast), out_len) }
+}
+
 /// Validate AST JSON. Returns 1 if valid, 0 if not.
 #[unsafe(no_mangle)]
 pub extern "C" fn elpian_validate_ast(ast_json: *const c_char) -> i32 {
@@ -99,6 +285,20 @@ pub extern "C" fn elpian_validate_ast(ast_json: *const c_char) -> i32 {
     }
 }
 
+/// Compile source code and disassemble it. Returns JSON string (must be freed).
+#[unsafe(no_mangle)]
+pub extern "C" fn elpian_disassemble_code(code: *const c_char) -> *mut c_char {
+    let c = unsafe { c_str_to_string(code) };
+    string_to_c_str(disassemble_code(c))
+}
+
+/// Disassemble a VM's program. Returns JSON string (must be freed).
+#[unsafe(no_mangle)]
+pub extern "C" fn elpian_disassemble_vm(machine_id: *const c_char) -> *mut c_char {
+    let mid = unsafe { c_str_to_string(machine_id) };
+    string_to_c_str(disassemble_vm(mid))
+}
+
 /// Execute a VM's main program. Returns JSON string (must be freed).
 #[unsafe(no_mangle)]
 pub extern "C" fn elpian_execute(machine_id: *const c_char) -> *mut c_char {
@@ -143,6 +343,170 @@ pub extern "C" fn elpian_continue_execution(
     result_to_c_str(continue_execution(mid, input))
 }
 
+/// Answer the pending host call with a value of `len` bytes in the binary
+/// wire encoding. Returns JSON string (must be freed).
+#[unsafe(no_mangle)]
+pub extern "C" fn elpian_continue_execution_binary(
+    machine_id: *const c_char,
+    input: *const u8,
+    len: usize,
+) -> *mut c_char {
+    let mid = unsafe { c_str_to_string(machine_id) };
+    let bytes = unsafe { c_buf_to_vec(input, len) };
+    result_to_c_str(continue_execution_binary(mid, bytes))
+}
+
+/// Get the input of the pending host call in the binary wire encoding.
+/// Returns the buffer and writes its length to `out_len` (must be freed
+/// with `elpian_free_bytes`), or null when no host call is pending.
+#[unsafe(no_mangle)]
+pub extern "C" fn elpian_get_host_call_binary(
+    machine_id: *const c_char,
+    out_len: *mut usize,
+) -> *mut u8 {
+    let mid = unsafe { c_str_to_string(machine_id) };
+    unsafe { bytes_to_c_buf(get_host_call_input_binary(mid), out_len) }
+}
+
+/// Fail the pending host call, the error value is thrown in the script.
+/// Returns JSON string (must be freed).
+#[unsafe(no_mangle)]
+pub extern "C" fn elpian_continue_execution_with_error(
+    machine_id: *const c_char,
+    error_json: *const c_char,
+) -> *mut c_char {
+    let mid = unsafe { c_str_to_string(machine_id) };
+    let error = unsafe { c_str_to_string(error_json) };
+    result_to_c_str(continue_execution_with_error(mid, error))
+}
+
+/// Settle the promise of the async host call `call_id` with a typed value.
+/// Returns JSON string (must be freed).
+#[unsafe(no_mangle)]
+pub extern "C" fn elpian_resolve_host_call(
+    machine_id: *const c_char,
+    call_id: i64,
+    value_json: *const c_char,
+) -> *mut c_char {
+    let mid = unsafe { c_str_to_string(machine_id) };
+    let value = unsafe { c_str_to_string(value_json) };
+    result_to_c_str(resolve_host_call(mid, call_id, value))
+}
+
+/// Reject the promise of the async host call `call_id`, the error value is
+/// thrown where the script awaits it. Returns JSON string (must be freed).
+#[unsafe(no_mangle)]
+pub extern "C" fn elpian_reject_host_call(
+    machine_id: *const c_char,
+    call_id: i64,
+    error_json: *const c_char,
+) -> *mut c_char {
+    let mid = unsafe { c_str_to_string(machine_id) };
+    let error = unsafe { c_str_to_string(error_json) };
+    result_to_c_str(reject_host_call(mid, call_id, error))
+}
+
+/// Run the timer callbacks due at host time `now_ms`.
+/// Returns JSON string (must be freed).
+#[unsafe(no_mangle)]
+pub extern "C" fn elpian_tick_vm(machine_id: *const c_char, now_ms: i64) -> *mut c_char {
+    let mid = unsafe { c_str_to_string(machine_id) };
+    result_to_c_str(tick_vm(mid, now_ms))
+}
+
+/// Set the per-run instruction budget (0 or less = unlimited).
+/// Returns 1 if the VM exists, 0 if not.
+#[unsafe(no_mangle)]
+pub extern "C" fn elpian_set_fuel_limit(machine_id: *const c_char, limit: i64) -> i32 {
+    let mid = unsafe { c_str_to_string(machine_id) };
+    if set_vm_fuel_limit(mid, limit) {
+        1
+    } else {
+        0
+    }
+}
+
+/// Resume a VM that ran out of fuel. Returns JSON string (must be freed).
+#[unsafe(no_mangle)]
+pub extern "C" fn elpian_resume_with_fuel(machine_id: *const c_char, fuel: i64) -> *mut c_char {
+    let mid = unsafe { c_str_to_string(machine_id) };
+    result_to_c_str(resume_vm_with_fuel(mid, fuel))
+}
+
+/// Send a debugger command (see `debug_vm`). Returns JSON string (must be freed).
+#[unsafe(no_mangle)]
+pub extern "C" fn elpian_debug_command(
+    machine_id: *const c_char,
+    command_json: *const c_char,
+) -> *mut c_char {
+    let mid = unsafe { c_str_to_string(machine_id) };
+    let command = unsafe { c_str_to_string(command_json) };
+    string_to_c_str(debug_vm(mid, command))
+}
+
+/// Save a VM's runtime state (see `snapshot_vm`). Returns JSON string (must be freed).
+#[unsafe(no_mangle)]
+pub extern "C" fn elpian_snapshot_vm(machine_id: *const c_char) -> *mut c_char {
+    let mid = unsafe { c_str_to_string(machine_id) };
+    string_to_c_str(snapshot_vm(mid))
+}
+
+/// Restore a saved state into a VM (see `restore_vm`). Returns JSON string (must be freed).
+#[unsafe(no_mangle)]
+pub extern "C" fn elpian_restore_vm(
+    machine_id: *const c_char,
+    snapshot: *const c_char,
+) -> *mut c_char {
+    let mid = unsafe { c_str_to_string(machine_id) };
+    let snapshot = unsafe { c_str_to_string(snapshot) };
+    string_to_c_str(restore_vm(mid, snapshot))
+}
+
+/// Abort a paused run. Returns 1 if the VM exists, 0 if not.
+#[unsafe(no_mangle)]
+pub extern "C" fn elpian_abort_execution(machine_id: *const c_char) -> i32 {
+    let mid = unsafe { c_str_to_string(machine_id) };
+    if abort_vm_execution(mid) {
+        1
+    } else {
+        0
+    }
+}
+
+/// Cap the approximate bytes a script may hold (0 or less = unlimited).
+/// Returns 1 if the VM exists, 0 if not.
+#[unsafe(no_mangle)]
+pub extern "C" fn elpian_set_memory_limit(machine_id: *const c_char, limit_bytes: i64) -> i32 {
+    let mid = unsafe { c_str_to_string(machine_id) };
+    if set_vm_memory_limit(mid, limit_bytes) {
+        1
+    } else {
+        0
+    }
+}
+
+/// Get a VM's memory usage. Returns JSON string (must be freed).
+#[unsafe(no_mangle)]
+pub extern "C" fn elpian_get_memory_usage(machine_id: *const c_char) -> *mut c_char {
+    let mid = unsafe { c_str_to_string(machine_id) };
+    string_to_c_str(get_vm_memory_usage(mid))
+}
+
+/// Get a VM's cycle collector stats. Returns JSON string (must be freed).
+#[unsafe(no_mangle)]
+pub extern "C" fn elpian_get_gc_stats(machine_id: *const c_char) -> *mut c_char {
+    let mid = unsafe { c_str_to_string(machine_id) };
+    string_to_c_str(get_vm_gc_stats(mid))
+}
+
+/// Free a VM's unreachable reference cycles now. Returns the collector
+/// stats as JSON string (must be freed).
+#[unsafe(no_mangle)]
+pub extern "C" fn elpian_collect_garbage(machine_id: *const c_char) -> *mut c_char {
+    let mid = unsafe { c_str_to_string(machine_id) };
+    string_to_c_str(collect_vm_garbage(mid))
+}
+
 /// Destroy a VM. Returns 1 if found and destroyed, 0 if not found.
 #[unsafe(no_mangle)]
 pub extern "C" fn elpian_destroy_vm(machine_id: *const c_char) -> i32 {
@@ -9,7 +9,14 @@ use std::sync::Mutex;
 use once_cell::sync::Lazy;
 use serde_json::{json, Value};
 
+use crate::sdk::bytecode::BytecodeModule;
 use crate::sdk::compiler;
+use crate::sdk::data::Val;
+use crate::sdk::debugger::StepMode;
+use crate::sdk::disassembler;
+use crate::sdk::error::VmError;
+use crate::sdk::reload::ReloadError;
+use crate::sdk::source_map::StackFrame;
 use crate::sdk::vm::VM;
 
 // Thread-safe VM storage for FRB
@@ -124,10 +131,32 @@ fn all_host_apis() -> Vec<String> {
 pub struct VmExecResult {
     /// Whether the VM is paused waiting for a host call response
     pub has_host_call: bool,
-    /// JSON string of the host call request: {"machineId", "apiName", "payload"}
+    /// JSON string of the host call request: {"machineId", "apiName",
+    /// "payload", "input"}. `input` is the typed wire encoding of the value
+    /// the script passed, `payload` the same value stringified
     pub host_call_data: String,
     /// Stringified result value (only meaningful when has_host_call is false)
     pub result_value: String,
+    /// Error that aborted the run (type mismatch, denied host api, bad host reply, ...)
+    pub error: Option<VmError>,
+    /// Script call stack at the point `error` was raised, innermost first
+    pub stack_trace: Vec<StackFrame>,
+    /// Whether the run used up its instruction budget and is waiting for
+    /// `resume_vm_with_fuel` or `abort_vm_execution`
+    pub out_of_fuel: bool,
+    /// Whether a breakpoint, a step or a script error stopped the run and it
+    /// waits for a `debug_vm` command or `abort_vm_execution`
+    pub paused: bool,
+    /// Whether the run awaits a promise and goes on once `resolve_host_call`
+    /// or `reject_host_call` settles it
+    pub awaiting: bool,
+    /// Async host calls the script made during this step, JSON strings of
+    /// {"machineId", "callId", "apiName", "payload", "input"}. Each is answered with
+    /// `resolve_host_call` or `reject_host_call`
+    pub host_calls: Vec<String>,
+    /// Host time in milliseconds at which the earliest timer the script
+    /// scheduled is due, the next `tick_vm` should come no later than that
+    pub next_timer_ms: Option<i64>,
 }
 
 impl VmExecResult {
@@ -136,6 +165,13 @@ impl VmExecResult {
             has_host_call: true,
             host_call_data: data,
             result_value: String::new(),
+            error: None,
+            stack_trace: vec![],
+            out_of_fuel: false,
+            paused: false,
+            awaiting: false,
+            host_calls: vec![],
+            next_timer_ms: None,
         }
     }
 
@@ -144,62 +180,328 @@ impl VmExecResult {
             has_host_call: false,
             host_call_data: String::new(),
             result_value: result_value.to_string(),
+            error: None,
+            stack_trace: vec![],
+            out_of_fuel: false,
+            paused: false,
+            awaiting: false,
+            host_calls: vec![],
+            next_timer_ms: None,
         }
     }
-}
 
-/// Check a VM for a pending host call after execution, returning an appropriate result.
-fn check_host_call(vm: &mut VM, fallback_result: &str) -> VmExecResult {
-    if let Some(data) = vm.sending_host_call_data.take() {
-        VmExecResult::host_call(data)
-    } else {
-        VmExecResult::done(fallback_result)
+    fn failed(error: VmError, stack_trace: Vec<StackFrame>) -> Self {
+        VmExecResult {
+            has_host_call: false,
+            host_call_data: String::new(),
+            result_value: String::new(),
+            error: Some(error),
+            stack_trace,
+            out_of_fuel: false,
+            paused: false,
+            awaiting: false,
+            host_calls: vec![],
+            next_timer_ms: None,
+        }
+    }
+
+    fn out_of_fuel() -> Self {
+        VmExecResult {
+            has_host_call: false,
+            host_call_data: String::new(),
+            result_value: String::new(),
+            error: None,
+            stack_trace: vec![],
+            out_of_fuel: true,
+            paused: false,
+            awaiting: false,
+            host_calls: vec![],
+            next_timer_ms: None,
+        }
+    }
+
+    fn paused() -> Self {
+        VmExecResult {
+            has_host_call: false,
+            host_call_data: String::new(),
+            result_value: String::new(),
+            error: None,
+            stack_trace: vec![],
+            out_of_fuel: false,
+            paused: true,
+            awaiting: false,
+            host_calls: vec![],
+            next_timer_ms: None,
+        }
+    }
+
+    fn awaiting() -> Self {
+        VmExecResult {
+            has_host_call: false,
+            host_call_data: String::new(),
+            result_value: String::new(),
+            error: None,
+            stack_trace: vec![],
+            out_of_fuel: false,
+            paused: false,
+            awaiting: true,
+            host_calls: vec![],
+            next_timer_ms: None,
+        }
     }
 }
 
+/// The JSON shape of a VmExecResult used by the FFI layers and `debug_vm`.
+pub(crate) fn exec_result_json(r: &VmExecResult) -> Value {
+    json!({
+        "hasHostCall": r.has_host_call,
+        "hostCallData": r.host_call_data,
+        "resultValue": r.result_value,
+        "error": r.error.as_ref().map(|e| e.to_json()),
+        "stackTrace": r.stack_trace.iter().map(|f| f.to_json()).collect::<Vec<_>>(),
+        "outOfFuel": r.out_of_fuel,
+        "paused": r.paused,
+        "awaiting": r.awaiting,
+        "hostCalls": r.host_calls,
+        "nextTimerMs": r.next_timer_ms,
+    })
+}
+
+/// Check a VM for a script error, an exhausted instruction budget, a debugger
+/// pause, a pending host call or an awaited promise after execution,
+/// returning an appropriate result. Async host calls made meanwhile and the
+/// time the next timer is due are attached to any of them.
+fn check_host_call(vm: &mut VM, result: Result<String, VmError>) -> VmExecResult {
+    vm.last_error = None;
+    let host_calls = std::mem::take(&mut vm.sending_async_host_calls);
+    let mut exec_result = match result {
+        Err(error) => VmExecResult::failed(error, std::mem::take(&mut vm.last_stack_trace)),
+        Ok(_) if vm.out_of_fuel => {
+            vm.out_of_fuel = false;
+            VmExecResult::out_of_fuel()
+        }
+        Ok(_) if vm.is_paused() => VmExecResult::paused(),
+        Ok(fallback_result) => match vm.sending_host_call_data.take() {
+            Some(data) => VmExecResult::host_call(data),
+            None if vm.is_awaiting() => VmExecResult::awaiting(),
+            None => VmExecResult::done(&fallback_result),
+        },
+    };
+    exec_result.host_calls = host_calls;
+    exec_result.next_timer_ms = vm.next_timer_due();
+    exec_result
+}
+
 /// Initialize the VM subsystem. Call once at app startup.
 pub fn init_vm_system() {
     // Force initialization of the lazy static
     drop(VMS.lock().unwrap());
 }
 
+/// Parse a JSON array of host api names (e.g. `["println", "canvas.*"]`)
+/// into a capability set for `create_vm_from_ast`/`create_vm_from_code`.
+pub fn parse_capabilities(capabilities_json: &str) -> Option<Vec<String>> {
+    serde_json::from_str::<Vec<String>>(capabilities_json).ok()
+}
+
 /// Create a new VM instance from an AST JSON string.
 ///
 /// The AST follows the Elpian compiler format with node types like
-/// "program", "definition", "assignment", "functionCall", etc.
-pub fn create_vm_from_ast(machine_id: String, ast_json: String) -> bool {
+/// "program", "definition", "assignment", "functionCall", etc. Returns
+/// false when the JSON does not parse or the AST is malformed.
+///
+/// `capabilities` lists the host apis the script may call through `askHost`
+/// (wildcard groups like `canvas.*` are accepted). `None` grants every api
+/// in the built-in catalog.
+pub fn create_vm_from_ast(
+    machine_id: String,
+    ast_json: String,
+    capabilities: Option<Vec<String>>,
+) -> bool {
     let ast_obj: Value = match serde_json::from_str(&ast_json) {
         Ok(v) => v,
         Err(_) => return false,
     };
-    let vm = VM::compile_and_create_of_ast(machine_id.clone(), ast_obj, 1, all_host_apis());
+    let vm = match VM::compile_and_create_of_ast(
+        machine_id.clone(),
+        ast_obj,
+        1,
+        capabilities.unwrap_or_else(all_host_apis),
+    ) {
+        Ok(vm) => vm,
+        Err(_) => return false,
+    };
     let mut vms = VMS.lock().unwrap();
     vms.insert(machine_id, vm);
     true
 }
 
 /// Create a new VM instance from source code string.
-pub fn create_vm_from_code(machine_id: String, code: String) -> bool {
-    let vm = VM::compile_and_create_of_code(machine_id.clone(), code, 1, all_host_apis());
+/// Returns false when the code has syntax errors, `disassemble_code`
+/// reports them with their positions.
+///
+/// See `create_vm_from_ast` for the meaning of `capabilities`.
+pub fn create_vm_from_code(
+    machine_id: String,
+    code: String,
+    capabilities: Option<Vec<String>>,
+) -> bool {
+    let vm = match VM::compile_and_create_of_code(
+        machine_id.clone(),
+        code,
+        1,
+        capabilities.unwrap_or_else(all_host_apis),
+    ) {
+        Ok(vm) => vm,
+        Err(_) => return false,
+    };
     let mut vms = VMS.lock().unwrap();
     vms.insert(machine_id, vm);
     true
 }
 
+/// Create a new VM instance from source code that imports modules.
+/// `modules_json` is a JSON object from module name to module source.
+/// Returns `{"created": true}`; syntax and link errors (a missing module or
+/// export, a cyclic import) come back like in `disassemble_code`, errors in
+/// an imported module with its name as `module`. Malformed `modules_json`
+/// gives `{"error": "bad_modules"}`.
+///
+/// See `create_vm_from_ast` for the meaning of `capabilities`.
+pub fn create_vm_from_modules(
+    machine_id: String,
+    code: String,
+    modules_json: String,
+    capabilities: Option<Vec<String>>,
+) -> String {
+    let Ok(modules) = serde_json::from_str::<HashMap<String, String>>(&modules_json) else {
+        return json!({ "error": "bad_modules" }).to_string();
+    };
+    let vm = match VM::compile_and_create_of_modules(
+        machine_id.clone(),
+        code,
+        modules,
+        capabilities.unwrap_or_else(all_host_apis),
+    ) {
+        Ok(vm) => vm,
+        Err(errors) => {
+            return json!({
+                "errors": errors.iter().map(|e| e.to_json()).collect::<Vec<_>>(),
+            })
+            .to_string()
+        }
+    };
+    let mut vms = VMS.lock().unwrap();
+    vms.insert(machine_id, vm);
+    json!({ "created": true }).to_string()
+}
+
+/// Recompile a VM's program from source while keeping its global state,
+/// for hot reload during development. Functions are rebound to their new
+/// definitions, other globals keep their values. Returns
+/// `{"reloaded": true, "dropped": [..], "changed": [{"name", "from", "to"}]}`:
+/// `dropped` lists globals removed because they held a function the new code
+/// no longer defines, `changed` globals that now hold a function instead of
+/// another type. Syntax errors come back like in `disassemble_code`, a VM in
+/// the middle of a run as `{"error": "vm_busy"}`; both leave the VM as it was.
+pub fn reload_vm_code(machine_id: String, code: String) -> String {
+    let mut vms = VMS.lock().unwrap();
+    let Some(vm) = vms.get_mut(&machine_id) else {
+        return "\"vm_not_found\"".to_string();
+    };
+    match vm.reload_code(code) {
+        Ok(report) => {
+            let mut response = report.to_json();
+            response["reloaded"] = json!(true);
+            response.to_string()
+        }
+        Err(ReloadError::Busy) => json!({ "error": "vm_busy" }).to_string(),
+        Err(ReloadError::Syntax(errors)) => json!({
+            "errors": errors.iter().map(|e| e.to_json()).collect::<Vec<_>>(),
+        })
+        .to_string(),
+    }
+}
+
+/// Create a new VM instance from a bytecode container produced by
+/// `compile_to_bytecode` or `compile_ast_to_bytecode`. The container is
+/// verified first; returns false when it is malformed, truncated or from
+/// another format version.
+///
+/// Stack traces of VMs created this way carry function names and offsets
+/// but no source locations. See `create_vm_from_ast` for `capabilities`.
+pub fn create_vm_from_bytecode(
+    machine_id: String,
+    bytecode: Vec<u8>,
+    capabilities: Option<Vec<String>>,
+) -> bool {
+    let vm = match VM::compile_and_create_of_bytecode(
+        machine_id.clone(),
+        bytecode,
+        capabilities.unwrap_or_else(all_host_apis),
+    ) {
+        Ok(vm) => vm,
+        Err(_) => return false,
+    };
+    let mut vms = VMS.lock().unwrap();
+    vms.insert(machine_id, vm);
+    true
+}
+
+/// Compile source code into a bytecode container that can be stored and
+/// later loaded with `create_vm_from_bytecode`. Returns None when the code
+/// has syntax errors, `disassemble_code` reports them.
+pub fn compile_to_bytecode(code: String) -> Option<Vec<u8>> {
+    let program = compiler::compile_code(code).ok()?;
+    Some(BytecodeModule::new(program).ok()?.encode())
+}
+
+/// Like `compile_to_bytecode`, for an AST JSON string. Returns None when
+/// the AST is malformed, `validate_ast` tells.
+pub fn compile_ast_to_bytecode(ast_json: String) -> Option<Vec<u8>> {
+    let ast_obj: Value = serde_json::from_str(&ast_json).ok()?;
+    let program = compiler::compile_ast(ast_obj, 0).ok()?;
+    Some(BytecodeModule::new(program).ok()?.encode())
+}
+
 /// Validate an AST JSON string without creating a VM.
 pub fn validate_ast(ast_json: String) -> bool {
     let ast_obj: Value = match serde_json::from_str(&ast_json) {
         Ok(v) => v,
         Err(_) => return false,
     };
-    compiler::compile_ast(ast_obj, 0);
-    true
+    compiler::compile_ast(ast_obj, 0).is_ok()
+}
+
+fn disassembly_json(program: &[u8]) -> String {
+    match disassembler::disassemble(program) {
+        Ok(listing) => json!({ "bytecodeLength": program.len(), "disassembly": listing }),
+        Err(error) => json!({ "error": error.to_json() }),
+    }
+    .to_string()
+}
+
+/// Compile source code and disassemble the bytecode (for debugging/inspection).
+/// Returns `{"bytecodeLength", "disassembly"}`; syntax errors come back as
+/// `{"errors": [{"message", "line", "column", "offset", "length"}]}`.
+pub fn disassemble_code(code: String) -> String {
+    match compiler::compile_code(code) {
+        Ok(bytecode) => disassembly_json(&bytecode),
+        Err(errors) => json!({
+            "errors": errors.iter().map(|e| e.to_json()).collect::<Vec<_>>(),
+        })
+        .to_string(),
+    }
 }
 
-/// Compile source code to AST JSON (for debugging/inspection).
-pub fn compile_code_to_ast(code: String) -> String {
-    let bytecode = compiler::compile_code(code);
-    json!({ "bytecodeLength": bytecode.len() }).to_string()
+/// Disassemble the program a VM was created with. Same JSON shape as
+/// `disassemble_code`, `{"error"}` if the bytecode is malformed.
+pub fn disassemble_vm(machine_id: String) -> String {
+    let vms = VMS.lock().unwrap();
+    match vms.get(&machine_id) {
+        Some(vm) => disassembly_json(&vm.program),
+        None => "\"vm_not_found\"".to_string(),
+    }
 }
 
 /// Execute the main program of a VM.
@@ -210,8 +512,8 @@ pub fn execute_vm(machine_id: String) -> VmExecResult {
         if vm.is_exec_processing() {
             return VmExecResult::done("\"vm_busy\"");
         }
-        vm.run();
-        check_host_call(vm, "\"done\"")
+        let res = vm.run();
+        check_host_call(vm, res.map(|_| "\"done\"".to_string()))
     } else {
         VmExecResult::done("\"vm_not_found\"")
     }
@@ -225,7 +527,7 @@ pub fn execute_vm_func(machine_id: String, func_name: String, cb_id: i64) -> VmE
             return VmExecResult::done("\"vm_busy\"");
         }
         let res = vm.run_func_with_input(&func_name, None, cb_id);
-        check_host_call(vm, &res.stringify())
+        check_host_call(vm, res.map(|val| val.stringify()))
     } else {
         VmExecResult::done("\"vm_not_found\"")
     }
@@ -244,7 +546,7 @@ pub fn execute_vm_func_with_input(
             return VmExecResult::done("\"vm_busy\"");
         }
         let res = vm.run_func_with_input(&func_name, Some(&input_json), cb_id);
-        check_host_call(vm, &res.stringify())
+        check_host_call(vm, res.map(|val| val.stringify()))
     } else {
         VmExecResult::done("\"vm_not_found\"")
     }
@@ -255,13 +557,318 @@ pub fn execute_vm_func_with_input(
 pub fn continue_execution(machine_id: String, input_json: String) -> VmExecResult {
     let mut vms = VMS.lock().unwrap();
     if let Some(vm) = vms.get_mut(&machine_id) {
-        vm.continue_run(input_json);
-        check_host_call(vm, "\"done\"")
+        let res = vm.continue_run(input_json);
+        check_host_call(vm, res.map(|_| "\"done\"".to_string()))
     } else {
         VmExecResult::done("\"vm_not_found\"")
     }
 }
 
+/// Answer a pending host call with a value in the binary wire encoding
+/// (see `wire::to_binary`) instead of typed JSON.
+pub fn continue_execution_binary(machine_id: String, input: Vec<u8>) -> VmExecResult {
+    let mut vms = VMS.lock().unwrap();
+    if let Some(vm) = vms.get_mut(&machine_id) {
+        let res = vm.continue_run_binary(&input);
+        check_host_call(vm, res.map(|_| "\"done\"".to_string()))
+    } else {
+        VmExecResult::done("\"vm_not_found\"")
+    }
+}
+
+/// The input of the host call a VM waits on in the binary wire encoding,
+/// `None` when the VM does not exist or no host call is pending.
+pub fn get_host_call_input_binary(machine_id: String) -> Option<Vec<u8>> {
+    let vms = VMS.lock().unwrap();
+    vms.get(&machine_id)?.host_call_input_binary()
+}
+
+/// Fail a pending host call. `error_json` is a typed value like
+/// `continue_execution` takes; the script gets it thrown where the call was
+/// made and may catch it.
+pub fn continue_execution_with_error(machine_id: String, error_json: String) -> VmExecResult {
+    let mut vms = VMS.lock().unwrap();
+    if let Some(vm) = vms.get_mut(&machine_id) {
+        let res = vm.continue_run_with_error(error_json);
+        check_host_call(vm, res.map(|_| "\"done\"".to_string()))
+    } else {
+        VmExecResult::done("\"vm_not_found\"")
+    }
+}
+
+/// Settle the promise of an async host call with a typed value. A run that
+/// awaits it goes on and its result is returned; otherwise the value waits
+/// for the script to await the promise and `resultValue` is `"settled"`
+/// (or `awaiting` is set while the run waits on another call).
+pub fn resolve_host_call(machine_id: String, call_id: i64, value_json: String) -> VmExecResult {
+    let mut vms = VMS.lock().unwrap();
+    if let Some(vm) = vms.get_mut(&machine_id) {
+        let res = vm.resolve_host_call(call_id, value_json);
+        check_host_call(vm, res.map(settled_result))
+    } else {
+        VmExecResult::done("\"vm_not_found\"")
+    }
+}
+
+/// Reject the promise of an async host call. The typed value is thrown where
+/// the script awaits the promise.
+pub fn reject_host_call(machine_id: String, call_id: i64, error_json: String) -> VmExecResult {
+    let mut vms = VMS.lock().unwrap();
+    if let Some(vm) = vms.get_mut(&machine_id) {
+        let res = vm.reject_host_call(call_id, error_json);
+        check_host_call(vm, res.map(settled_result))
+    } else {
+        VmExecResult::done("\"vm_not_found\"")
+    }
+}
+
+// a settled promise nobody awaited yet leaves the vm idle
+fn settled_result(val: Val) -> String {
+    if val.typ() == 254 {
+        "\"settled\"".to_string()
+    } else {
+        "\"done\"".to_string()
+    }
+}
+
+/// Run the timer callbacks of a VM that are due at host time `now_ms`. A
+/// callback that makes a host call pauses the tick like any run; once the
+/// call is answered and the callback returns, the remaining ones run.
+/// `nextTimerMs` of the result tells when to tick again.
+pub fn tick_vm(machine_id: String, now_ms: i64) -> VmExecResult {
+    let mut vms = VMS.lock().unwrap();
+    if let Some(vm) = vms.get_mut(&machine_id) {
+        if vm.is_exec_processing() {
+            return VmExecResult::done("\"vm_busy\"");
+        }
+        let res = vm.tick(now_ms);
+        check_host_call(vm, res.map(|_| "\"done\"".to_string()))
+    } else {
+        VmExecResult::done("\"vm_not_found\"")
+    }
+}
+
+/// Set the per-run instruction budget of a VM. A `limit` of 0 or less removes
+/// the limit. Returns false if the VM does not exist.
+pub fn set_vm_fuel_limit(machine_id: String, limit: i64) -> bool {
+    let mut vms = VMS.lock().unwrap();
+    if let Some(vm) = vms.get_mut(&machine_id) {
+        vm.set_fuel_limit(if limit > 0 { Some(limit as u64) } else { None });
+        true
+    } else {
+        false
+    }
+}
+
+/// Resume a VM that ran out of fuel, granting it `fuel` more instructions.
+pub fn resume_vm_with_fuel(machine_id: String, fuel: i64) -> VmExecResult {
+    let mut vms = VMS.lock().unwrap();
+    if let Some(vm) = vms.get_mut(&machine_id) {
+        let res = vm.resume_with_fuel(fuel.max(0) as u64);
+        check_host_call(vm, res.map(|_| "\"done\"".to_string()))
+    } else {
+        VmExecResult::done("\"vm_not_found\"")
+    }
+}
+
+/// Abort a run that is paused (out of fuel, waiting on a host call or
+/// stopped by the debugger), discarding its in-flight state. Globals
+/// defined so far are kept.
+pub fn abort_vm_execution(machine_id: String) -> bool {
+    let mut vms = VMS.lock().unwrap();
+    if let Some(vm) = vms.get_mut(&machine_id) {
+        vm.abort_run();
+        true
+    } else {
+        false
+    }
+}
+
+/// Save a VM's runtime state as a JSON blob for `restore_vm`, e.g. to
+/// survive an app suspend. Fails with `{"error"}` while the run is stopped
+/// by the debugger.
+pub fn snapshot_vm(machine_id: String) -> String {
+    let vms = VMS.lock().unwrap();
+    match vms.get(&machine_id) {
+        Some(vm) => match vm.snapshot() {
+            Ok(snapshot) => snapshot,
+            Err(error) => json!({ "error": error.to_json() }).to_string(),
+        },
+        None => "\"vm_not_found\"".to_string(),
+    }
+}
+
+/// Restore a state saved by `snapshot_vm` into a VM created from the same
+/// program. Returns the execution result of the restored run (same keys as
+/// the FFI result): a pending host call is reported again and has to be
+/// answered with `continue_execution`, a run out of fuel reports
+/// `outOfFuel`. Otherwise `resultValue` is `"restored"`. A snapshot that
+/// does not fit the VM comes back as `{"error"}` and changes nothing.
+pub fn restore_vm(machine_id: String, snapshot: String) -> String {
+    let mut vms = VMS.lock().unwrap();
+    let Some(vm) = vms.get_mut(&machine_id) else {
+        return "\"vm_not_found\"".to_string();
+    };
+    if let Err(error) = vm.restore(&snapshot) {
+        return json!({ "error": error.to_json() }).to_string();
+    }
+    let result = check_host_call(vm, Ok("\"restored\"".to_string()));
+    exec_result_json(&result).to_string()
+}
+
+/// Drive the debugger of a VM with a JSON command, returning a JSON response.
+/// Breakpoints and pause-on-error are set before or between runs; a run that
+/// stops comes back from `execute_vm*`/`continue_execution` with `paused`.
+///
+/// - `{"command": "setBreakpoints", "offsets": [..], "lines": [..]}` replaces
+///   the breakpoints. Lines resolve to their first statement for VMs created
+///   from code. Returns `{"breakpoints", "unresolvedOffsets", "unresolvedLines"}`.
+/// - `{"command": "setPauseOnError", "enabled": true}` returns `{"pauseOnError"}`.
+/// - `{"command": "continue" | "stepInto" | "stepOver" | "stepOut"}` resumes a
+///   paused run. Returns the execution result (same keys as the FFI result)
+///   plus `"pause"`, where and why it stopped again (or null).
+/// - `{"command": "state"}` returns `{"paused", "pause", "breakpoints", "pauseOnError"}`.
+/// - `{"command": "scopes"}` returns `{"scopes": [{"tag", "function",
+///   "variables": [{"name", "value"}]}]}`, global scope first, values
+///   rendered like `stringify`.
+///
+/// Failures come back as `{"error": "bad_command" | "unknown_command" | "not_paused"}`.
+pub fn debug_vm(machine_id: String, command_json: String) -> String {
+    let mut vms = VMS.lock().unwrap();
+    let Some(vm) = vms.get_mut(&machine_id) else {
+        return "\"vm_not_found\"".to_string();
+    };
+    let Ok(command) = serde_json::from_str::<Value>(&command_json) else {
+        return json!({ "error": "bad_command" }).to_string();
+    };
+    let numbers = |key: &str| -> Vec<usize> {
+        command[key]
+            .as_array()
+            .map(|items| {
+                items
+                    .iter()
+                    .filter_map(|item| item.as_u64().map(|n| n as usize))
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+    let step = match command["command"].as_str().unwrap_or("") {
+        "setBreakpoints" => {
+            let offsets = numbers("offsets");
+            let lines = numbers("lines");
+            let mut wanted = offsets.clone();
+            let mut unresolved_lines = vec![];
+            for line in lines {
+                match vm.line_offset(line) {
+                    Some(offset) => wanted.push(offset),
+                    None => unresolved_lines.push(line),
+                }
+            }
+            let breakpoints = vm.set_breakpoints(&wanted);
+            let unresolved_offsets: Vec<usize> = offsets
+                .into_iter()
+                .filter(|offset| !breakpoints.contains(offset))
+                .collect();
+            return json!({
+                "breakpoints": breakpoints,
+                "unresolvedOffsets": unresolved_offsets,
+                "unresolvedLines": unresolved_lines,
+            })
+            .to_string();
+        }
+        "setPauseOnError" => {
+            vm.set_pause_on_error(command["enabled"].as_bool().unwrap_or(false));
+            return json!({ "pauseOnError": vm.pause_on_error() }).to_string();
+        }
+        "state" => {
+            let pause = vm.pause_info();
+            return json!({
+                "paused": pause.is_some(),
+                "pause": pause.map(|p| p.to_json()),
+                "breakpoints": vm.breakpoints(),
+                "pauseOnError": vm.pause_on_error(),
+            })
+            .to_string();
+        }
+        "scopes" => {
+            let scopes: Vec<Value> = vm.scope_chain().iter().map(|s| s.to_json()).collect();
+            return json!({ "scopes": scopes }).to_string();
+        }
+        "continue" => None,
+        "stepInto" => Some(StepMode::Into),
+        "stepOver" => Some(StepMode::Over),
+        "stepOut" => Some(StepMode::Out),
+        _ => return json!({ "error": "unknown_command" }).to_string(),
+    };
+    if !vm.is_paused() {
+        return json!({ "error": "not_paused" }).to_string();
+    }
+    let res = vm.debug_resume(step);
+    let result = check_host_call(vm, res.map(|_| "\"done\"".to_string()));
+    let mut response = exec_result_json(&result);
+    response["pause"] = json!(vm.pause_info().map(|p| p.to_json()));
+    response.to_string()
+}
+
+/// Cap the approximate bytes a VM's script may hold. A `limit_bytes` of 0 or
+/// less removes the cap. Returns false if the VM does not exist.
+pub fn set_vm_memory_limit(machine_id: String, limit_bytes: i64) -> bool {
+    let mut vms = VMS.lock().unwrap();
+    if let Some(vm) = vms.get_mut(&machine_id) {
+        vm.set_memory_limit(if limit_bytes > 0 {
+            Some(limit_bytes as usize)
+        } else {
+            None
+        });
+        true
+    } else {
+        false
+    }
+}
+
+/// Report a VM's memory accounting as JSON:
+/// `{"usedBytes": .., "allocatedBytes": .., "limitBytes": ..|null}`.
+/// `usedBytes` is measured from the live scope chain, `allocatedBytes` is the
+/// running total charged since the VM was created.
+pub fn get_vm_memory_usage(machine_id: String) -> String {
+    let vms = VMS.lock().unwrap();
+    if let Some(vm) = vms.get(&machine_id) {
+        json!({
+            "usedBytes": vm.memory_usage(),
+            "allocatedBytes": vm.allocated_bytes(),
+            "limitBytes": vm.memory_limit(),
+        })
+        .to_string()
+    } else {
+        "\"vm_not_found\"".to_string()
+    }
+}
+
+/// Report what a VM's cycle collector did as JSON:
+/// `{"collections": .., "freed": .., "lastFreed": .., "tracked": ..}`.
+/// `freed` counts the values freed over all collections, `tracked` the
+/// containers currently watched for cycles.
+pub fn get_vm_gc_stats(machine_id: String) -> String {
+    let vms = VMS.lock().unwrap();
+    if let Some(vm) = vms.get(&machine_id) {
+        vm.gc_stats().to_json().to_string()
+    } else {
+        "\"vm_not_found\"".to_string()
+    }
+}
+
+/// Free a VM's unreachable reference cycles now and report the collector
+/// stats like `get_vm_gc_stats`.
+pub fn collect_vm_garbage(machine_id: String) -> String {
+    let vms = VMS.lock().unwrap();
+    if let Some(vm) = vms.get(&machine_id) {
+        vm.collect_garbage();
+        vm.gc_stats().to_json().to_string()
+    } else {
+        "\"vm_not_found\"".to_string()
+    }
+}
+
 /// Destroy a VM instance and free its resources.
 pub fn destroy_vm(machine_id: String) -> bool {
     let mut vms = VMS.lock().unwrap();
@@ -2,22 +2,22 @@
 /// Uses wasm-bindgen to expose the VM API to JavaScript/Dart on web.
 #[cfg(target_arch = "wasm32")]
 mod wasm {
-    use serde_json::json;
     use wasm_bindgen::prelude::*;
 
     use crate::api::{
-        continue_execution, create_vm_from_ast, create_vm_from_code, destroy_vm, execute_vm,
-        execute_vm_func, execute_vm_func_with_input, init_vm_system, validate_ast, vm_exists,
+        abort_vm_execution, collect_vm_garbage, compile_ast_to_bytecode, compile_to_bytecode,
+        continue_execution, continue_execution_with_error, create_vm_from_ast,
+        create_vm_from_bytecode, create_vm_from_code, create_vm_from_modules, debug_vm, destroy_vm,
+        exec_result_json,
+        execute_vm, execute_vm_func, execute_vm_func_with_input, get_vm_gc_stats,
+        get_vm_memory_usage, init_vm_system, parse_capabilities,
+        reject_host_call, reload_vm_code, resolve_host_call, restore_vm, resume_vm_with_fuel,
+        set_vm_fuel_limit, set_vm_memory_limit, snapshot_vm, tick_vm, validate_ast, vm_exists,
         VmExecResult,
     };
 
     fn result_to_json(r: VmExecResult) -> String {
-        json!({
-            "hasHostCall": r.has_host_call,
-            "hostCallData": r.host_call_data,
-            "resultValue": r.result_value,
-        })
-        .to_string()
+        exec_result_json(&r).to_string()
     }
 
     #[wasm_bindgen]
@@ -27,12 +27,94 @@ mod wasm {
 
     #[wasm_bindgen]
     pub fn elpian_wasm_create_vm_from_ast(machine_id: String, ast_json: String) -> bool {
-        create_vm_from_ast(machine_id, ast_json)
+        create_vm_from_ast(machine_id, ast_json, None)
+    }
+
+    #[wasm_bindgen]
+    pub fn elpian_wasm_create_vm_from_ast_with_capabilities(
+        machine_id: String,
+        ast_json: String,
+        capabilities_json: String,
+    ) -> bool {
+        match parse_capabilities(&capabilities_json) {
+            Some(capabilities) => create_vm_from_ast(machine_id, ast_json, Some(capabilities)),
+            None => false,
+        }
     }
 
     #[wasm_bindgen]
     pub fn elpian_wasm_create_vm_from_code(machine_id: String, code: String) -> bool {
-        create_vm_from_code(machine_id, code)
+        create_vm_from_code(machine_id, code, None)
+    }
+
+    #[wasm_bindgen]
+    pub fn elpian_wasm_reload_vm_code(machine_id: String, code: String) -> String {
+        reload_vm_code(machine_id, code)
+    }
+
+    #[wasm_bindgen]
+    pub fn elpian_wasm_create_vm_from_code_with_capabilities(
+        machine_id: String,
+        code: String,
+        capabilities_json: String,
+    ) -> bool {
+        match parse_capabilities(&capabilities_json) {
+            Some(capabilities) => create_vm_from_code(machine_id, code, Some(capabilities)),
+            None => false,
+        }
+    }
+
+    #[wasm_bindgen]
+    pub fn elpian_wasm_create_vm_from_modules(
+        machine_id: String,
+        code: String,
+        modules_json: String,
+    ) -> String {
+        create_vm_from_modules(machine_id, code, modules_json, None)
+    }
+
+    #[wasm_bindgen]
+    pub fn elpian_wasm_create_vm_from_modules_with_capabilities(
+        machine_id: String,
+        code: String,
+        modules_json: String,
+        capabilities_json: String,
+    ) -> String {
+        match parse_capabilities(&capabilities_json) {
+            Some(capabilities) => {
+                create_vm_from_modules(machine_id, code, modules_json, Some(capabilities))
+            }
+            None => "{\"error\":\"bad_capabilities\"}".to_string(),
+        }
+    }
+
+    #[wasm_bindgen]
+    pub fn elpian_wasm_create_vm_from_bytecode(machine_id: String, bytecode: Vec<u8>) -> bool {
+        create_vm_from_bytecode(machine_id, bytecode, None)
+    }
+
+    #[wasm_bindgen]
+    pub fn elpian_wasm_create_vm_from_bytecode_with_capabilities(
+        machine_id: String,
+        bytecode: Vec<u8>,
+        capabilities_json: String,
+    ) -> bool {
+        match parse_capabilities(&capabilities_json) {
+            Some(capabilities) => {
+                create_vm_from_bytecode(machine_id, bytecode, Some(capabilities))
+            }
+            None => false,
+        }
+    }
+
+    #[wasm_bindgen]
+    pub fn elpian_wasm_compile_to_bytecode(code: String) -> Option<Vec<u8>> {
+        compile_to_bytecode(code)
+    }
+
+    #[wasm_bindgen]
+    pub fn elpian_wasm_compile_ast_to_bytecode(ast_json: String) -> Option<Vec<u8>> {
+        compile_ast_to_bytecode(ast_json)
     }
 
     #[wasm_bindgen]
@@ -67,6 +149,87 @@ mod wasm {
         result_to_json(continue_execution(machine_id, input_json))
     }
 
+    #[wasm_bindgen]
+    pub fn elpian_wasm_continue_execution_with_error(
+        machine_id: String,
+        error_json: String,
+    ) -> String {
+        result_to_json(continue_execution_with_error(machine_id, error_json))
+    }
+
+    #[wasm_bindgen]
+    pub fn elpian_wasm_resolve_host_call(
+        machine_id: String,
+        call_id: i32,
+        value_json: String,
+    ) -> String {
+        result_to_json(resolve_host_call(machine_id, call_id as i64, value_json))
+    }
+
+    #[wasm_bindgen]
+    pub fn elpian_wasm_reject_host_call(
+        machine_id: String,
+        call_id: i32,
+        error_json: String,
+    ) -> String {
+        result_to_json(reject_host_call(machine_id, call_id as i64, error_json))
+    }
+
+    #[wasm_bindgen]
+    pub fn elpian_wasm_tick_vm(machine_id: String, now_ms: f64) -> String {
+        result_to_json(tick_vm(machine_id, now_ms as i64))
+    }
+
+    #[wasm_bindgen]
+    pub fn elpian_wasm_set_fuel_limit(machine_id: String, limit: i32) -> bool {
+        set_vm_fuel_limit(machine_id, limit as i64)
+    }
+
+    #[wasm_bindgen]
+    pub fn elpian_wasm_resume_with_fuel(machine_id: String, fuel: i32) -> String {
+        result_to_json(resume_vm_with_fuel(machine_id, fuel as i64))
+    }
+
+    #[wasm_bindgen]
+    pub fn elpian_wasm_debug_command(machine_id: String, command_json: String) -> String {
+        debug_vm(machine_id, command_json)
+    }
+
+    #[wasm_bindgen]
+    pub fn elpian_wasm_snapshot_vm(machine_id: String) -> String {
+        snapshot_vm(machine_id)
+    }
+
+    #[wasm_bindgen]
+    pub fn elpian_wasm_restore_vm(machine_id: String, snapshot: String) -> String {
+        restore_vm(machine_id, snapshot)
+    }
+
+    #[wasm_bindgen]
+    pub fn elpian_wasm_abort_execution(machine_id: String) -> bool {
+        abort_vm_execution(machine_id)
+    }
+
+    #[wasm_bindgen]
+    pub fn elpian_wasm_set_memory_limit(machine_id: String, limit_bytes: i32) -> bool {
+        set_vm_memory_limit(machine_id, limit_bytes as i64)
+    }
+
+    #[wasm_bindgen]
+    pub fn elpian_wasm_get_memory_usage(machine_id: String) -> String {
+        get_vm_memory_usage(machine_id)
+    }
+
+    #[wasm_bindgen]
+    pub fn elpian_wasm_get_gc_stats(machine_id: String) -> String {
+        get_vm_gc_stats(machine_id)
+    }
+
+    #[wasm_bindgen]
+    pub fn elpian_wasm_collect_garbage(machine_id: String) -> String {
+        collect_vm_garbage(machine_id)
+    }
+
     #[wasm_bindgen]
     pub fn elpian_wasm_destroy_vm(machine_id: String) -> bool {
         destroy_vm(machine_id)
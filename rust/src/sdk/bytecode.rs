@@ -0,0 +1,1314 @@
+use std::{collections::HashMap, fmt};
+
+use crate::sdk::{data::Val, error::VmError};
+
+pub const MAGIC: [u8; 4] = *b"ELPB";
+/// Bumped whenever the container or the instruction encoding changes.
+pub const FORMAT_VERSION: u16 = 4;
+// no flags are defined yet, containers with any set are rejected
+const KNOWN_FLAGS: u16 = 0;
+
+/// Opcodes of the register machine. Registers, cells, upvalues, counts
+/// and function indices are u16 operands, constants and addresses u32.
+pub mod op {
+    pub const NOP: u8 = 0x00;
+    /// `const r k`: loads constant `k`.
+    pub const CONST: u8 = 0x01;
+    pub const NULL: u8 = 0x02;
+    /// `bool r b`, `b` a single byte.
+    pub const BOOL: u8 = 0x03;
+    /// `move dst src`.
+    pub const MOVE: u8 = 0x04;
+    /// `getg r k`: reads the global named by string constant `k`.
+    pub const GET_GLOBAL: u8 = 0x05;
+    /// `defg k r`: defines a global.
+    pub const DEF_GLOBAL: u8 = 0x06;
+    /// `setg k r`: updates a global, defining it when missing.
+    pub const SET_GLOBAL: u8 = 0x07;
+    pub const GET_CELL: u8 = 0x08;
+    pub const SET_CELL: u8 = 0x09;
+    /// `newc c`: replaces cell `c` with a fresh one holding null.
+    pub const NEW_CELL: u8 = 0x0a;
+    pub const GET_UPVALUE: u8 = 0x0b;
+    pub const SET_UPVALUE: u8 = 0x0c;
+    /// `closure r f`: creates a value of function `f`, capturing the
+    /// upvalues its table entry lists.
+    pub const CLOSURE: u8 = 0x0d;
+    /// `array r first n`: collects registers `first..first + n`.
+    pub const ARRAY: u8 = 0x0e;
+    /// `object r first n` then `n` string constants, one key per register.
+    pub const OBJECT: u8 = 0x0f;
+    /// `index dst target index`.
+    pub const INDEX: u8 = 0x10;
+    /// `setindex target index value k`, `k` naming the target variable.
+    pub const SET_INDEX: u8 = 0x11;
+    pub const NOT: u8 = 0x12;
+    /// `cast dst src k`, `k` the target type name.
+    pub const CAST: u8 = 0x13;
+    pub const AWAIT: u8 = 0x14;
+    pub const JUMP: u8 = 0x15;
+    /// `jumpf r a`: jumps unless `r` holds `true`.
+    pub const JUMP_IF_NOT: u8 = 0x16;
+    /// `call dst callee first n`.
+    pub const CALL: u8 = 0x17;
+    pub const RETURN: u8 = 0x18;
+    pub const RETURN_NULL: u8 = 0x19;
+    pub const THROW: u8 = 0x1a;
+    /// `try catch slot-kind slot finally after`: opens a protected region
+    /// that ends at the matching `tryend`. Absent clauses have the address
+    /// `u32::MAX`; slot kind 0 means no catch parameter, 1 a register and
+    /// 2 a cell.
+    pub const TRY: u8 = 0x1b;
+    /// Closes the innermost protected region, clause or finally.
+    pub const TRY_END: u8 = 0x1c;
+    /// `class c first`: makes the blueprint of class `c` of the class
+    /// table, the registers from `first` on hold the defaults of the
+    /// fields it declares itself.
+    pub const CLASS: u8 = 0x1d;
+    /// `new dst k first n`: an instance of the class named by string
+    /// constant `k`, its `init` method called with `n` registers.
+    pub const NEW: u8 = 0x1e;
+    /// `invoke dst first n k c`: calls method `k` of the value in `first`
+    /// with the `n` registers after it. `c` names the class the method is
+    /// looked up from, `NO_CLASS` for the value's own; values without the
+    /// method call their property `k` instead.
+    pub const INVOKE: u8 = 0x1f;
+    /// `op dst a b` for the binary operators, in `BinOp` order.
+    pub const BINARY: u8 = 0x20;
+    /// `is dst src k`: whether `src` is an instance of the class named by
+    /// `k` or of a class extending it.
+    pub const IS: u8 = 0x2c;
+    /// `keys dst src`: an array of the property names of object `src`.
+    pub const KEYS: u8 = 0x2d;
+    /// `next dst src i a`: while `i` is below the length of array `src`,
+    /// loads item `i` into `dst` and counts `i` up, else jumps to `a`.
+    pub const NEXT: u8 = 0x2e;
+    /// `leave n a`: leaves the `n` innermost try statements of the frame,
+    /// running their finally clauses, then jumps to `a`.
+    pub const LEAVE: u8 = 0x2f;
+}
+
+/// Address operand of an absent try clause.
+pub const NO_ADDRESS: u32 = u32::MAX;
+/// Class operand of an `invoke` on the receiver's own class.
+pub const NO_CLASS: u32 = u32::MAX;
+// parent of a class table entry that extends nothing
+const NO_PARENT: u16 = u16::MAX;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BinOp {
+    Eq,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+    Ne,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Pow,
+}
+
+impl BinOp {
+    pub const ALL: [BinOp; 12] = [
+        BinOp::Eq,
+        BinOp::Gt,
+        BinOp::Ge,
+        BinOp::Lt,
+        BinOp::Le,
+        BinOp::Ne,
+        BinOp::Add,
+        BinOp::Sub,
+        BinOp::Mul,
+        BinOp::Div,
+        BinOp::Mod,
+        BinOp::Pow,
+    ];
+    pub fn from_symbol(symbol: &str) -> Option<BinOp> {
+        BinOp::ALL.into_iter().find(|op| op.symbol() == symbol)
+    }
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            BinOp::Eq => "==",
+            BinOp::Gt => ">",
+            BinOp::Ge => ">=",
+            BinOp::Lt => "<",
+            BinOp::Le => "<=",
+            BinOp::Ne => "!=",
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Mul => "*",
+            BinOp::Div => "/",
+            BinOp::Mod => "%",
+            BinOp::Pow => "^",
+        }
+    }
+    pub fn opcode(&self) -> u8 {
+        op::BINARY + *self as u8
+    }
+}
+
+/// An entry of the constant pool.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Constant {
+    I16(i16),
+    I32(i32),
+    I64(i64),
+    F32(f32),
+    F64(f64),
+    Str(String),
+}
+
+impl Constant {
+    pub fn to_val(&self) -> Val {
+        match self {
+            Constant::I16(v) => Val::I16(*v),
+            Constant::I32(v) => Val::I32(*v),
+            Constant::I64(v) => Val::I64(*v),
+            Constant::F32(v) => Val::F32(*v),
+            Constant::F64(v) => Val::F64(*v),
+            Constant::Str(v) => Val::string(v.as_str()),
+        }
+    }
+    fn tag(&self) -> u8 {
+        match self {
+            Constant::I16(_) => 1,
+            Constant::I32(_) => 2,
+            Constant::I64(_) => 3,
+            Constant::F32(_) => 4,
+            Constant::F64(_) => 5,
+            Constant::Str(_) => 7,
+        }
+    }
+}
+
+impl fmt::Display for Constant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Constant::I16(v) => write!(f, "i16 {}", v),
+            Constant::I32(v) => write!(f, "i32 {}", v),
+            Constant::I64(v) => write!(f, "i64 {}", v),
+            Constant::F32(v) => write!(f, "f32 {}", v),
+            Constant::F64(v) => write!(f, "f64 {}", v),
+            Constant::Str(v) => write!(f, "{:?}", v),
+        }
+    }
+}
+
+/// Where a local variable lives in its function's frame. Variables inner
+/// functions capture live in cells, all others in registers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Slot {
+    Register(u16),
+    Cell(u16),
+}
+
+impl fmt::Display for Slot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Slot::Register(r) => write!(f, "r{}", r),
+            Slot::Cell(c) => write!(f, "cell {}", c),
+        }
+    }
+}
+
+/// Where a closure takes a captured variable from when it is created: a
+/// cell of the enclosing frame or one of the enclosing function's own
+/// upvalues.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Capture {
+    Cell(u16),
+    Upvalue(u16),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Upvalue {
+    pub name: String,
+    pub from: Capture,
+}
+
+/// A named local for debuggers, live over `start..end`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LocalEntry {
+    pub name: String,
+    pub slot: Slot,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// A function of the program. Entry 0 is the program's main body, which
+/// spans the whole code; every other body is nested in the code of the
+/// function defining it and skipped over by a jump.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FunctionEntry {
+    pub name: String,
+    pub params: Vec<String>,
+    pub start: usize,
+    pub end: usize,
+    /// Frame size: locals and temporaries.
+    pub registers: u16,
+    /// Captured locals, created fresh on every call.
+    pub cells: u16,
+    /// Where each parameter is stored on entry.
+    pub param_slots: Vec<Slot>,
+    pub upvalues: Vec<Upvalue>,
+    pub locals: Vec<LocalEntry>,
+    /// Defined in the program's outermost block, which a run binds in
+    /// the global scope.
+    pub top_level: bool,
+}
+
+/// A class of the program. Classes only referred to by an `extends` the
+/// program does not declare are listed without fields and methods.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClassEntry {
+    pub name: String,
+    /// Index of the class it extends.
+    pub parent: Option<u16>,
+    /// The fields it declares itself, in the order `class` takes their
+    /// defaults.
+    pub fields: Vec<String>,
+    /// Its own methods, by name, and the functions implementing them.
+    /// They take the instance as their first parameter.
+    pub methods: Vec<(String, u16)>,
+}
+
+/// A compiled program: the constant pool, the function and class tables,
+/// the offsets statements start at and the code.
+///
+/// Image layout, all integers big-endian like the instructions:
+///
+/// ```text
+/// constants:  count u32, then per constant: type tag u8, value
+///             (strings as len u32 and utf-8 bytes)
+/// functions:  count u32, then per function: name, params, start u32,
+///             end u32, registers u16, cells u16, parameter slots,
+///             upvalues, locals, top-level flag u8
+/// classes:    count u32, then per class: name, parent u16 (0xffff for
+///             none), fields, methods (name, function u16)
+/// statements: count u32, offsets u32
+/// code:       len u32, instruction bytes
+/// ```
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct Program {
+    pub constants: Vec<Constant>,
+    pub functions: Vec<FunctionEntry>,
+    pub classes: Vec<ClassEntry>,
+    pub statements: Vec<usize>,
+    pub code: Vec<u8>,
+}
+
+impl Program {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = vec![];
+        put_u32(&mut out, self.constants.len());
+        for constant in self.constants.iter() {
+            out.push(constant.tag());
+            match constant {
+                Constant::I16(v) => out.extend_from_slice(&v.to_be_bytes()),
+                Constant::I32(v) => out.extend_from_slice(&v.to_be_bytes()),
+                Constant::I64(v) => out.extend_from_slice(&v.to_be_bytes()),
+                Constant::F32(v) => out.extend_from_slice(&v.to_be_bytes()),
+                Constant::F64(v) => out.extend_from_slice(&v.to_be_bytes()),
+                Constant::Str(v) => put_str(&mut out, v),
+            }
+        }
+        put_u32(&mut out, self.functions.len());
+        for func in self.functions.iter() {
+            put_str(&mut out, &func.name);
+            put_u32(&mut out, func.params.len());
+            for param in func.params.iter() {
+                put_str(&mut out, param);
+            }
+            put_u32(&mut out, func.start);
+            put_u32(&mut out, func.end);
+            out.extend_from_slice(&func.registers.to_be_bytes());
+            out.extend_from_slice(&func.cells.to_be_bytes());
+            for slot in func.param_slots.iter() {
+                put_slot(&mut out, *slot);
+            }
+            put_u32(&mut out, func.upvalues.len());
+            for upvalue in func.upvalues.iter() {
+                put_str(&mut out, &upvalue.name);
+                let (kind, index) = match upvalue.from {
+                    Capture::Cell(c) => (0u8, c),
+                    Capture::Upvalue(u) => (1u8, u),
+                };
+                out.push(kind);
+                out.extend_from_slice(&index.to_be_bytes());
+            }
+            put_u32(&mut out, func.locals.len());
+            for local in func.locals.iter() {
+                put_str(&mut out, &local.name);
+                put_slot(&mut out, local.slot);
+                put_u32(&mut out, local.start);
+                put_u32(&mut out, local.end);
+            }
+            out.push(func.top_level as u8);
+        }
+        put_u32(&mut out, self.classes.len());
+        for class in self.classes.iter() {
+            put_str(&mut out, &class.name);
+            out.extend_from_slice(&class.parent.unwrap_or(NO_PARENT).to_be_bytes());
+            put_u32(&mut out, class.fields.len());
+            for field in class.fields.iter() {
+                put_str(&mut out, field);
+            }
+            put_u32(&mut out, class.methods.len());
+            for (name, func) in class.methods.iter() {
+                put_str(&mut out, name);
+                out.extend_from_slice(&func.to_be_bytes());
+            }
+        }
+        put_u32(&mut out, self.statements.len());
+        for statement in self.statements.iter() {
+            put_u32(&mut out, *statement);
+        }
+        put_u32(&mut out, self.code.len());
+        out.extend_from_slice(&self.code);
+        out
+    }
+
+    /// Parses an image and verifies its code, see `decode_code`.
+    pub fn decode(image: &[u8]) -> Result<Self, VmError> {
+        Ok(Self::load(image)?.0)
+    }
+
+    /// Like `decode`, also handing out the decoded code.
+    pub(crate) fn load(image: &[u8]) -> Result<(Self, Decoded), VmError> {
+        let mut reader = Reader {
+            bytes: image,
+            pointer: 0,
+        };
+        let program = reader.program()?;
+        if reader.pointer != image.len() {
+            return Err(bad("unexpected bytes after the code section"));
+        }
+        let decoded = program.decode_code()?;
+        Ok((program, decoded))
+    }
+
+    /// Decodes the code into instructions and checks it: every opcode
+    /// and operand, that addresses land on instructions of the same
+    /// function, that constant, register, cell, upvalue and function
+    /// operands exist, and that no body runs past its end.
+    pub(crate) fn decode_code(&self) -> Result<Decoded, VmError> {
+        Verifier::new(self)?.run()
+    }
+
+    /// The entries of the functions defined in the program's outermost
+    /// block.
+    pub fn top_level(&self) -> impl Iterator<Item = &FunctionEntry> {
+        self.functions.iter().filter(|func| func.top_level)
+    }
+}
+
+/// A program packaged for storage or transfer:
+///
+/// ```text
+/// magic "ELPB" | version u16 | flags u16 | program image | checksum u32
+/// ```
+///
+/// The checksum is FNV-1a of everything before it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BytecodeModule {
+    pub version: u16,
+    pub flags: u16,
+    pub program: Program,
+}
+
+impl BytecodeModule {
+    /// Packages a compiled program image, verifying it on the way.
+    pub fn new(image: Vec<u8>) -> Result<Self, VmError> {
+        Ok(BytecodeModule {
+            version: FORMAT_VERSION,
+            flags: 0,
+            program: Program::decode(&image)?,
+        })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.extend_from_slice(&self.version.to_be_bytes());
+        out.extend_from_slice(&self.flags.to_be_bytes());
+        out.extend_from_slice(&self.program.encode());
+        let checksum = fnv1a(&out);
+        out.extend_from_slice(&checksum.to_be_bytes());
+        out
+    }
+
+    /// Parses and verifies a container. Anything malformed, truncated,
+    /// from another format version or failing `verify` is a `BadBytecode`
+    /// error; nothing in `bytes` is trusted.
+    pub fn decode(bytes: &[u8]) -> Result<Self, VmError> {
+        if bytes.len() < MAGIC.len() || bytes[..MAGIC.len()] != MAGIC {
+            return Err(bad("missing the bytecode magic number"));
+        }
+        let Some(body_len) = bytes.len().checked_sub(4) else {
+            return Err(bad("container is truncated"));
+        };
+        let mut reader = Reader {
+            bytes: &bytes[..body_len],
+            pointer: MAGIC.len(),
+        };
+        let version = u16::from_be_bytes(reader.take()?);
+        if version != FORMAT_VERSION {
+            return Err(bad(&format!(
+                "format version {} is not supported, expected {}",
+                version, FORMAT_VERSION
+            )));
+        }
+        let flags = u16::from_be_bytes(reader.take()?);
+        let checksum = u32::from_be_bytes(bytes[body_len..].try_into().unwrap());
+        if checksum != fnv1a(&bytes[..body_len]) {
+            return Err(bad("checksum mismatch"));
+        }
+        let program = reader.program()?;
+        if reader.pointer != body_len {
+            return Err(bad("unexpected bytes after the code section"));
+        }
+        let module = BytecodeModule {
+            version,
+            flags,
+            program,
+        };
+        module.verify()?;
+        Ok(module)
+    }
+
+    /// Checks the flags and the program's code, see `Program::decode_code`.
+    pub fn verify(&self) -> Result<(), VmError> {
+        if self.flags & !KNOWN_FLAGS != 0 {
+            return Err(bad(&format!("unknown flags 0x{:04x}", self.flags)));
+        }
+        self.program.decode_code()?;
+        Ok(())
+    }
+}
+
+fn bad(detail: &str) -> VmError {
+    VmError::BadBytecode(detail.to_string())
+}
+
+fn put_u32(out: &mut Vec<u8>, value: usize) {
+    out.extend_from_slice(&(value as u32).to_be_bytes());
+}
+
+fn put_str(out: &mut Vec<u8>, text: &str) {
+    put_u32(out, text.len());
+    out.extend_from_slice(text.as_bytes());
+}
+
+fn put_slot(out: &mut Vec<u8>, slot: Slot) {
+    let (kind, index) = match slot {
+        Slot::Register(r) => (1u8, r),
+        Slot::Cell(c) => (2u8, c),
+    };
+    out.push(kind);
+    out.extend_from_slice(&index.to_be_bytes());
+}
+
+pub(crate) fn fnv1a(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c9dc5, |hash, byte| {
+        (hash ^ *byte as u32).wrapping_mul(0x01000193)
+    })
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pointer: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn slice(&mut self, len: usize) -> Result<&'a [u8], VmError> {
+        let end = self
+            .pointer
+            .checked_add(len)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or_else(|| bad(&format!("bytecode is truncated at offset {}", self.pointer)))?;
+        let slice = &self.bytes[self.pointer..end];
+        self.pointer = end;
+        Ok(slice)
+    }
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], VmError> {
+        Ok(self.slice(N)?.try_into().unwrap())
+    }
+    fn u8(&mut self) -> Result<u8, VmError> {
+        Ok(self.take::<1>()?[0])
+    }
+    fn u16(&mut self) -> Result<u16, VmError> {
+        Ok(u16::from_be_bytes(self.take()?))
+    }
+    fn u32(&mut self) -> Result<usize, VmError> {
+        Ok(u32::from_be_bytes(self.take()?) as usize)
+    }
+    fn str(&mut self) -> Result<String, VmError> {
+        let len = self.u32()?;
+        String::from_utf8(self.slice(len)?.to_vec()).map_err(|_| bad("string is not utf-8"))
+    }
+    fn slot(&mut self) -> Result<Slot, VmError> {
+        let kind = self.u8()?;
+        let index = self.u16()?;
+        match kind {
+            1 => Ok(Slot::Register(index)),
+            2 => Ok(Slot::Cell(index)),
+            _ => Err(bad(&format!("unknown slot kind {}", kind))),
+        }
+    }
+
+    fn program(&mut self) -> Result<Program, VmError> {
+        let mut constants = vec![];
+        for _ in 0..self.u32()? {
+            let tag = self.u8()?;
+            constants.push(match tag {
+                1 => Constant::I16(i16::from_be_bytes(self.take()?)),
+                2 => Constant::I32(i32::from_be_bytes(self.take()?)),
+                3 => Constant::I64(i64::from_be_bytes(self.take()?)),
+                4 => Constant::F32(f32::from_be_bytes(self.take()?)),
+                5 => Constant::F64(f64::from_be_bytes(self.take()?)),
+                7 => Constant::Str(self.str()?),
+                _ => return Err(bad(&format!("unknown constant type {}", tag))),
+            });
+        }
+        let mut functions = vec![];
+        for _ in 0..self.u32()? {
+            let name = self.str()?;
+            let mut params = vec![];
+            for _ in 0..self.u32()? {
+                params.push(self.str()?);
+            }
+            let start = self.u32()?;
+            let end = self.u32()?;
+            let registers = self.u16()?;
+            let cells = self.u16()?;
+            let mut param_slots = vec![];
+            for _ in 0..params.len() {
+                param_slots.push(self.slot()?);
+            }
+            let mut upvalues = vec![];
+            for _ in 0..self.u32()? {
+                let name = self.str()?;
+                let kind = self.u8()?;
+                let index = self.u16()?;
+                let from = match kind {
+                    0 => Capture::Cell(index),
+                    1 => Capture::Upvalue(index),
+                    _ => return Err(bad(&format!("unknown capture kind {}", kind))),
+                };
+                upvalues.push(Upvalue { name, from });
+            }
+            let mut locals = vec![];
+            for _ in 0..self.u32()? {
+                locals.push(LocalEntry {
+                    name: self.str()?,
+                    slot: self.slot()?,
+                    start: self.u32()?,
+                    end: self.u32()?,
+                });
+            }
+            let top_level = self.u8()? != 0;
+            functions.push(FunctionEntry {
+                name,
+                params,
+                start,
+                end,
+                registers,
+                cells,
+                param_slots,
+                upvalues,
+                locals,
+                top_level,
+            });
+        }
+        let mut classes = vec![];
+        for _ in 0..self.u32()? {
+            let name = self.str()?;
+            let parent = Some(self.u16()?).filter(|parent| *parent != NO_PARENT);
+            let mut fields = vec![];
+            for _ in 0..self.u32()? {
+                fields.push(self.str()?);
+            }
+            let mut methods = vec![];
+            for _ in 0..self.u32()? {
+                methods.push((self.str()?, self.u16()?));
+            }
+            classes.push(ClassEntry {
+                name,
+                parent,
+                fields,
+                methods,
+            });
+        }
+        let mut statements = vec![];
+        for _ in 0..self.u32()? {
+            statements.push(self.u32()?);
+        }
+        let code_len = self.u32()?;
+        let code = self.slice(code_len)?.to_vec();
+        Ok(Program {
+            constants,
+            functions,
+            classes,
+            statements,
+            code,
+        })
+    }
+}
+
+/// A decoded instruction. Addresses are resolved to instruction indices,
+/// key lists and try clauses point into the side tables of `Decoded`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Instr {
+    Nop,
+    Const(u16, u32),
+    Null(u16),
+    Bool(u16, bool),
+    Move(u16, u16),
+    GetGlobal(u16, u32),
+    DefGlobal(u32, u16),
+    SetGlobal(u32, u16),
+    GetCell(u16, u16),
+    SetCell(u16, u16),
+    NewCell(u16),
+    GetUpvalue(u16, u16),
+    SetUpvalue(u16, u16),
+    Closure(u16, u16),
+    Array(u16, u16, u16),
+    Object(u16, u16, u16, u32),
+    Index(u16, u16, u16),
+    SetIndex(u16, u16, u16, u32),
+    Not(u16, u16),
+    Cast(u16, u16, u32),
+    Await(u16, u16),
+    Jump(u32),
+    JumpIfNot(u16, u32),
+    Call(u16, u16, u16, u16),
+    Return(u16),
+    ReturnNull,
+    Throw(u16),
+    Try(u32),
+    TryEnd,
+    Class(u16, u16),
+    New(u16, u32, u16, u16),
+    Invoke(u16, u16, u16, u32, u32),
+    Binary(BinOp, u16, u16, u16),
+    Is(u16, u16, u32),
+    Keys(u16, u16),
+    Next(u16, u16, u16, u32),
+    Leave(u16, u32),
+}
+
+impl Instr {
+    // instructions after which execution never continues with the next one
+    fn ends_flow(&self) -> bool {
+        matches!(
+            self,
+            Instr::Jump(_)
+                | Instr::Leave(..)
+                | Instr::Return(_)
+                | Instr::ReturnNull
+                | Instr::Throw(_)
+                | Instr::TryEnd
+        )
+    }
+}
+
+/// The clauses of a `try` instruction, as instruction indices.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct TryInfo {
+    pub catch: Option<(u32, Option<Slot>)>,
+    pub finally: Option<u32>,
+    pub after: u32,
+}
+
+/// Verified, decoded code of a program.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Decoded {
+    pub instrs: Vec<Instr>,
+    /// Code offset of every instruction, plus the code length.
+    pub offsets: Vec<usize>,
+    /// The function each instruction belongs to.
+    pub owners: Vec<u16>,
+    /// Instruction index each function's body starts at.
+    pub entries: Vec<u32>,
+    /// The function each function is defined in, none for main.
+    pub parents: Vec<Option<usize>>,
+    /// Key constants of the object literals.
+    pub keys: Vec<Vec<u32>>,
+    pub tries: Vec<TryInfo>,
+}
+
+// the raw address operands of a `try`, resolved once all offsets are known
+struct RawTry {
+    at: usize,
+    catch: Option<(usize, Option<Slot>)>,
+    finally: Option<usize>,
+    after: usize,
+}
+
+struct Verifier<'a> {
+    program: &'a Program,
+    pointer: usize,
+    // parent of every function, main has none
+    parents: Vec<Option<usize>>,
+}
+
+impl<'a> Verifier<'a> {
+    fn new(program: &'a Program) -> Result<Self, VmError> {
+        let functions = &program.functions;
+        let len = program.code.len();
+        match functions.first() {
+            Some(main) if main.start == 0 && main.end == len => {}
+            _ => return Err(bad("the first function must span the code")),
+        }
+        for func in functions.iter().skip(1) {
+            if func.start >= func.end || func.start == 0 || func.end > len {
+                return Err(bad(&format!(
+                    "function {} does not fit the code",
+                    func.name
+                )));
+            }
+        }
+        // bodies either nest or stay apart, the innermost one enclosing
+        // a body is its parent
+        let mut order: Vec<usize> = (1..functions.len()).collect();
+        order.sort_by_key(|index| (functions[*index].start, usize::MAX - functions[*index].end));
+        let mut parents = vec![None; functions.len()];
+        let mut open = vec![0];
+        for index in order {
+            let func = &functions[index];
+            while functions[*open.last().unwrap()].end <= func.start {
+                open.pop();
+            }
+            let parent = *open.last().unwrap();
+            if func.end > functions[parent].end
+                || (func.start, func.end) == (functions[parent].start, functions[parent].end)
+            {
+                return Err(bad(&format!(
+                    "function {} overlaps function {}",
+                    func.name, functions[parent].name
+                )));
+            }
+            parents[index] = Some(parent);
+            open.push(index);
+        }
+        Ok(Verifier {
+            program,
+            pointer: 0,
+            parents,
+        })
+    }
+
+    fn bytes<const N: usize>(&mut self) -> Result<[u8; N], VmError> {
+        let end = self.pointer + N;
+        let bytes = self
+            .program
+            .code
+            .get(self.pointer..end)
+            .ok_or_else(|| {
+                bad(&format!(
+                    "operand at offset {} runs past the end of the program",
+                    self.pointer
+                ))
+            })?
+            .try_into()
+            .unwrap();
+        self.pointer = end;
+        Ok(bytes)
+    }
+    fn u8(&mut self) -> Result<u8, VmError> {
+        Ok(self.bytes::<1>()?[0])
+    }
+    fn u16(&mut self) -> Result<u16, VmError> {
+        Ok(u16::from_be_bytes(self.bytes()?))
+    }
+    fn u32(&mut self) -> Result<u32, VmError> {
+        Ok(u32::from_be_bytes(self.bytes()?))
+    }
+    fn address(&mut self) -> Result<usize, VmError> {
+        let at = self.pointer;
+        let address = self.u32()? as usize;
+        if address > self.program.code.len() {
+            return Err(bad(&format!(
+                "address {} at offset {} is outside the program",
+                address, at
+            )));
+        }
+        Ok(address)
+    }
+
+    // decodes one instruction, addresses are left as offsets for now
+    fn instr(
+        &mut self,
+        keys: &mut Vec<Vec<u32>>,
+        tries: &mut Vec<RawTry>,
+    ) -> Result<Instr, VmError> {
+        let at = self.pointer;
+        let opcode = self.u8()?;
+        Ok(match opcode {
+            op::NOP => Instr::Nop,
+            op::CONST => Instr::Const(self.u16()?, self.u32()?),
+            op::NULL => Instr::Null(self.u16()?),
+            op::BOOL => Instr::Bool(self.u16()?, self.u8()? != 0),
+            op::MOVE => Instr::Move(self.u16()?, self.u16()?),
+            op::GET_GLOBAL => Instr::GetGlobal(self.u16()?, self.u32()?),
+            op::DEF_GLOBAL => Instr::DefGlobal(self.u32()?, self.u16()?),
+            op::SET_GLOBAL => Instr::SetGlobal(self.u32()?, self.u16()?),
+            op::GET_CELL => Instr::GetCell(self.u16()?, self.u16()?),
+            op::SET_CELL => Instr::SetCell(self.u16()?, self.u16()?),
+            op::NEW_CELL => Instr::NewCell(self.u16()?),
+            op::GET_UPVALUE => Instr::GetUpvalue(self.u16()?, self.u16()?),
+            op::SET_UPVALUE => Instr::SetUpvalue(self.u16()?, self.u16()?),
+            op::CLOSURE => Instr::Closure(self.u16()?, self.u16()?),
+            op::ARRAY => Instr::Array(self.u16()?, self.u16()?, self.u16()?),
+            op::OBJECT => {
+                let (dst, first, count) = (self.u16()?, self.u16()?, self.u16()?);
+                let mut list = vec![];
+                for _ in 0..count {
+                    list.push(self.u32()?);
+                }
+                keys.push(list);
+                Instr::Object(dst, first, count, keys.len() as u32 - 1)
+            }
+            op::INDEX => Instr::Index(self.u16()?, self.u16()?, self.u16()?),
+            op::SET_INDEX => Instr::SetIndex(self.u16()?, self.u16()?, self.u16()?, self.u32()?),
+            op::NOT => Instr::Not(self.u16()?, self.u16()?),
+            op::CAST => Instr::Cast(self.u16()?, self.u16()?, self.u32()?),
+            op::AWAIT => Instr::Await(self.u16()?, self.u16()?),
+            op::JUMP => Instr::Jump(self.address()? as u32),
+            op::JUMP_IF_NOT => {
+                let cond = self.u16()?;
+                Instr::JumpIfNot(cond, self.address()? as u32)
+            }
+            op::CALL => Instr::Call(self.u16()?, self.u16()?, self.u16()?, self.u16()?),
+            op::RETURN => Instr::Return(self.u16()?),
+            op::RETURN_NULL => Instr::ReturnNull,
+            op::THROW => Instr::Throw(self.u16()?),
+            op::TRY => {
+                let catch = self.u32()?;
+                let kind = self.u8()?;
+                let index = self.u16()?;
+                let finally = self.u32()?;
+                let after = self.address()?;
+                let slot = match kind {
+                    0 => None,
+                    1 => Some(Slot::Register(index)),
+                    2 => Some(Slot::Cell(index)),
+                    _ => {
+                        return Err(bad(&format!(
+                            "unknown catch slot kind {} at offset {}",
+                            kind, at
+                        )))
+                    }
+                };
+                let clause = |address: u32| -> Result<Option<usize>, VmError> {
+                    if address == NO_ADDRESS {
+                        Ok(None)
+                    } else if address as usize > self.program.code.len() {
+                        Err(bad(&format!(
+                            "address {} at offset {} is outside the program",
+                            address, at
+                        )))
+                    } else {
+                        Ok(Some(address as usize))
+                    }
+                };
+                tries.push(RawTry {
+                    at,
+                    catch: clause(catch)?.map(|address| (address, slot)),
+                    finally: clause(finally)?,
+                    after,
+                });
+                Instr::Try(tries.len() as u32 - 1)
+            }
+            op::TRY_END => Instr::TryEnd,
+            op::CLASS => Instr::Class(self.u16()?, self.u16()?),
+            op::NEW => Instr::New(self.u16()?, self.u32()?, self.u16()?, self.u16()?),
+            op::INVOKE => Instr::Invoke(
+                self.u16()?,
+                self.u16()?,
+                self.u16()?,
+                self.u32()?,
+                self.u32()?,
+            ),
+            op::IS => Instr::Is(self.u16()?, self.u16()?, self.u32()?),
+            op::KEYS => Instr::Keys(self.u16()?, self.u16()?),
+            op::NEXT => {
+                let (dst, src, index) = (self.u16()?, self.u16()?, self.u16()?);
+                Instr::Next(dst, src, index, self.address()? as u32)
+            }
+            op::LEAVE => {
+                let count = self.u16()?;
+                Instr::Leave(count, self.address()? as u32)
+            }
+            code if (op::BINARY..op::BINARY + 12).contains(&code) => Instr::Binary(
+                BinOp::ALL[(code - op::BINARY) as usize],
+                self.u16()?,
+                self.u16()?,
+                self.u16()?,
+            ),
+            _ => {
+                return Err(bad(&format!(
+                    "unknown opcode 0x{:02x} at offset {}",
+                    opcode, at
+                )))
+            }
+        })
+    }
+
+    fn run(mut self) -> Result<Decoded, VmError> {
+        let program = self.program;
+        let functions = &program.functions;
+        let len = program.code.len();
+        let mut instrs = vec![];
+        let mut offsets = vec![];
+        let mut keys = vec![];
+        let mut raw_tries = vec![];
+        while self.pointer < len {
+            offsets.push(self.pointer);
+            instrs.push(self.instr(&mut keys, &mut raw_tries)?);
+        }
+        offsets.push(len);
+        let mut index_of: HashMap<usize, u32> = HashMap::with_capacity(offsets.len());
+        for (index, offset) in offsets.iter().enumerate() {
+            index_of.insert(*offset, index as u32);
+        }
+
+        // owner of every instruction: the innermost body containing it
+        let mut owners = vec![0u16; instrs.len()];
+        let mut entries = vec![0u32; functions.len()];
+        for (index, func) in functions.iter().enumerate() {
+            let (Some(start), Some(end)) = (index_of.get(&func.start), index_of.get(&func.end))
+            else {
+                return Err(bad(&format!(
+                    "function {} does not start and end on instructions",
+                    func.name
+                )));
+            };
+            entries[index] = *start;
+            for owner in owners[*start as usize..*end as usize].iter_mut() {
+                let current = &functions[*owner as usize];
+                if *owner == 0 || (current.start <= func.start && func.end <= current.end) {
+                    *owner = index as u16;
+                }
+            }
+        }
+
+        for (index, func) in functions.iter().enumerate() {
+            let check = |slot: Slot| match slot {
+                Slot::Register(r) if r >= func.registers => Err(bad(&format!(
+                    "register {} of function {} is outside its frame",
+                    r, func.name
+                ))),
+                Slot::Cell(c) if c >= func.cells => Err(bad(&format!(
+                    "cell {} of function {} is outside its frame",
+                    c, func.name
+                ))),
+                _ => Ok(()),
+            };
+            if func.param_slots.len() != func.params.len() {
+                return Err(bad(&format!(
+                    "function {} has no slot for a parameter",
+                    func.name
+                )));
+            }
+            for slot in func.param_slots.iter() {
+                check(*slot)?;
+            }
+            for local in func.locals.iter() {
+                check(local.slot)?;
+            }
+            if let Some(parent) = self.parents[index] {
+                let parent = &functions[parent];
+                for upvalue in func.upvalues.iter() {
+                    let fits = match upvalue.from {
+                        Capture::Cell(c) => c < parent.cells,
+                        Capture::Upvalue(u) => (u as usize) < parent.upvalues.len(),
+                    };
+                    if !fits {
+                        return Err(bad(&format!(
+                            "upvalue {} of function {} is not in {}",
+                            upvalue.name, func.name, parent.name
+                        )));
+                    }
+                }
+            } else if !func.upvalues.is_empty() {
+                return Err(bad("the main function can not capture upvalues"));
+            }
+        }
+
+        let classes = &program.classes;
+        for class in classes.iter() {
+            // following the parents ends before it visited every class
+            let mut parent = class.parent;
+            for _ in 0..classes.len() {
+                let Some(index) = parent else {
+                    break;
+                };
+                parent = classes
+                    .get(index as usize)
+                    .ok_or_else(|| {
+                        bad(&format!(
+                            "class {} extends a class that is not listed",
+                            class.name
+                        ))
+                    })?
+                    .parent;
+            }
+            if parent.is_some() {
+                return Err(bad(&format!("class {} extends itself", class.name)));
+            }
+            for (name, func) in class.methods.iter() {
+                if *func == 0 || *func as usize >= functions.len() {
+                    return Err(bad(&format!(
+                        "method {} of class {} is not in the function table",
+                        name, class.name
+                    )));
+                }
+            }
+        }
+
+        let resolve = |at: usize, address: usize, owner: u16| -> Result<u32, VmError> {
+            let Some(index) = index_of.get(&address) else {
+                return Err(bad(&format!(
+                    "address {} at offset {} does not start an instruction",
+                    address, at
+                )));
+            };
+            if owners.get(*index as usize) != Some(&owner) {
+                return Err(bad(&format!(
+                    "address {} at offset {} is outside the body of {}",
+                    address, at, functions[owner as usize].name
+                )));
+            }
+            Ok(*index)
+        };
+        let mut tries = vec![];
+        for raw in raw_tries.iter() {
+            let owner = owners[index_of[&raw.at] as usize];
+            let catch = match raw.catch {
+                Some((address, slot)) => Some((resolve(raw.at, address, owner)?, slot)),
+                None => None,
+            };
+            let finally = match raw.finally {
+                Some(address) => Some(resolve(raw.at, address, owner)?),
+                None => None,
+            };
+            tries.push(TryInfo {
+                catch,
+                finally,
+                after: resolve(raw.at, raw.after, owner)?,
+            });
+        }
+
+        for index in 0..instrs.len() {
+            let at = offsets[index];
+            let owner = owners[index];
+            let func = &functions[owner as usize];
+            let reg = |r: u16| {
+                if r < func.registers {
+                    Ok(())
+                } else {
+                    Err(bad(&format!(
+                        "register {} at offset {} is outside the frame of {}",
+                        r, at, func.name
+                    )))
+                }
+            };
+            let span = |first: u16, count: u16| {
+                if first as usize + count as usize <= func.registers as usize {
+                    Ok(())
+                } else {
+                    Err(bad(&format!(
+                        "registers {}..{} at offset {} are outside the frame of {}",
+                        first,
+                        first as usize + count as usize,
+                        at,
+                        func.name
+                    )))
+                }
+            };
+            let cell = |c: u16| {
+                if c < func.cells {
+                    Ok(())
+                } else {
+                    Err(bad(&format!(
+                        "cell {} at offset {} is outside the frame of {}",
+                        c, at, func.name
+                    )))
+                }
+            };
+            let upvalue = |u: u16| {
+                if (u as usize) < func.upvalues.len() {
+                    Ok(())
+                } else {
+                    Err(bad(&format!(
+                        "upvalue {} at offset {} is not captured by {}",
+                        u, at, func.name
+                    )))
+                }
+            };
+            let constant = |k: u32| match program.constants.get(k as usize) {
+                Some(_) => Ok(()),
+                None => Err(bad(&format!(
+                    "constant {} at offset {} is outside the constant pool",
+                    k, at
+                ))),
+            };
+            let name = |k: u32| match program.constants.get(k as usize) {
+                Some(Constant::Str(_)) => Ok(()),
+                Some(_) => Err(bad(&format!(
+                    "constant {} at offset {} is not a string",
+                    k, at
+                ))),
+                None => constant(k),
+            };
+            let instr = &mut instrs[index];
+            match *instr {
+                Instr::Nop | Instr::ReturnNull | Instr::TryEnd => {}
+                Instr::Const(r, k) => {
+                    reg(r)?;
+                    constant(k)?;
+                }
+                Instr::Null(r) | Instr::Bool(r, _) | Instr::Return(r) | Instr::Throw(r) => reg(r)?,
+                Instr::Move(a, b) | Instr::Not(a, b) | Instr::Await(a, b) | Instr::Keys(a, b) => {
+                    reg(a)?;
+                    reg(b)?;
+                }
+                Instr::GetGlobal(r, k) | Instr::DefGlobal(k, r) | Instr::SetGlobal(k, r) => {
+                    reg(r)?;
+                    name(k)?;
+                }
+                Instr::GetCell(r, c) | Instr::SetCell(c, r) => {
+                    reg(r)?;
+                    cell(c)?;
+                }
+                Instr::NewCell(c) => cell(c)?,
+                Instr::GetUpvalue(r, u) | Instr::SetUpvalue(u, r) => {
+                    reg(r)?;
+                    upvalue(u)?;
+                }
+                Instr::Closure(r, f) => {
+                    reg(r)?;
+                    if self.parents.get(f as usize) != Some(&Some(owner as usize)) {
+                        return Err(bad(&format!(
+                            "function {} at offset {} is not defined in {}",
+                            f, at, func.name
+                        )));
+                    }
+                }
+                Instr::Array(r, first, count) => {
+                    reg(r)?;
+                    span(first, count)?;
+                }
+                Instr::Object(r, first, count, list) => {
+                    reg(r)?;
+                    span(first, count)?;
+                    for key in keys[list as usize].iter() {
+                        name(*key)?;
+                    }
+                }
+                Instr::Index(a, b, c) | Instr::Binary(_, a, b, c) => {
+                    reg(a)?;
+                    reg(b)?;
+                    reg(c)?;
+                }
+                Instr::SetIndex(a, b, c, k) => {
+                    reg(a)?;
+                    reg(b)?;
+                    reg(c)?;
+                    name(k)?;
+                }
+                Instr::Cast(a, b, k) => {
+                    reg(a)?;
+                    reg(b)?;
+                    name(k)?;
+                }
+                Instr::Jump(ref mut address) => {
+                    *address = resolve(at + 1, *address as usize, owner)?;
+                }
+                Instr::JumpIfNot(r, ref mut address) => {
+                    reg(r)?;
+                    *address = resolve(at + 3, *address as usize, owner)?;
+                }
+                Instr::Next(d, s, i, ref mut address) => {
+                    reg(d)?;
+                    reg(s)?;
+                    reg(i)?;
+                    *address = resolve(at + 7, *address as usize, owner)?;
+                }
+                Instr::Leave(_, ref mut address) => {
+                    *address = resolve(at + 3, *address as usize, owner)?;
+                }
+                Instr::Call(d, callee, first, count) => {
+                    reg(d)?;
+                    reg(callee)?;
+                    span(first, count)?;
+                }
+                Instr::Try(t) => {
+                    if let Some((_, Some(slot))) = tries[t as usize].catch {
+                        match slot {
+                            Slot::Register(r) => reg(r)?,
+                            Slot::Cell(c) => cell(c)?,
+                        }
+                    }
+                }
+                Instr::Class(c, first) => match classes.get(c as usize) {
+                    Some(class) => span(first, class.fields.len() as u16)?,
+                    None => {
+                        return Err(bad(&format!(
+                            "class {} at offset {} is not in the class table",
+                            c, at
+                        )))
+                    }
+                },
+                Instr::New(d, k, first, count) => {
+                    reg(d)?;
+                    name(k)?;
+                    span(first, count)?;
+                }
+                Instr::Invoke(d, first, count, k, c) => {
+                    reg(d)?;
+                    reg(first)?;
+                    span(first + 1, count)?;
+                    name(k)?;
+                    if c != NO_CLASS {
+                        name(c)?;
+                    }
+                }
+                Instr::Is(d, s, k) => {
+                    reg(d)?;
+                    reg(s)?;
+                    name(k)?;
+                }
+            }
+            if !instr.ends_flow() && owners.get(index + 1) != Some(&owner) {
+                return Err(bad(&format!(
+                    "instruction at offset {} runs past the end of {}",
+                    at, func.name
+                )));
+            }
+        }
+
+        for statement in program.statements.iter() {
+            if *statement >= len || !index_of.contains_key(statement) {
+                return Err(bad(&format!(
+                    "statement offset {} does not start an instruction",
+                    statement
+                )));
+            }
+        }
+
+        Ok(Decoded {
+            instrs,
+            offsets,
+            owners,
+            entries,
+            parents: self.parents,
+            keys,
+            tries,
+        })
+    }
+}
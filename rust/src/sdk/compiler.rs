@@ -1,1352 +1,1606 @@
-use std::{cell::RefCell, collections::HashMap, rc::Rc};
+use std::collections::{HashMap, HashSet};
 
-use serde_json::{json, Value};
+use serde_json::{json, Map, Value};
 
-// #[wasm_bindgen]
-// extern "C" {
-//     #[wasm_bindgen(js_namespace = console)]
-//     fn log(s: &str);
+use crate::sdk::{
+    bytecode::{
+        op, BinOp, Capture, ClassEntry, Constant, FunctionEntry, LocalEntry, Program, Slot,
+        Upvalue, NO_ADDRESS, NO_CLASS,
+    },
+    error::SyntaxError,
+    lexer::Span,
+    linker::{self, Linked, ModuleResolver},
+    parser,
+    source_map::{SourceMap, SourceMapEntry},
+};
 
-//     #[wasm_bindgen(js_namespace = console, js_name = log)]
-//     fn log_u32(a: u32);
+// a local whose debug range has not started yet
+const UNSET: usize = usize::MAX;
 
-//     #[wasm_bindgen(js_namespace = console, js_name = log)]
-//     fn log_many(a: &str, b: &str);
-// }
+// what a name refers to at some point of the code
+enum Var {
+    Local(Slot),
+    Upvalue(u16),
+    Global,
+}
 
-fn log(s: &str) {
-    println!("{}", s);
+// a name declared in a block, `local` indexes the function's debug locals
+struct Declared {
+    name: String,
+    slot: Slot,
+    local: usize,
 }
 
-fn serialize_expr(val: serde_json::Value) -> Vec<u8> {
-    // log(&val.to_string());
-    let mut result: Vec<u8> = vec![];
-    match val["type"].as_str().unwrap() {
-        "i16" => {
-            result.push(1);
-            result.append(
-                &mut i16::to_be_bytes(val["data"]["value"].as_i64().unwrap() as i16).to_vec(),
-            );
-        }
-        "i32" => {
-            result.push(2);
-            result.append(
-                &mut i32::to_be_bytes(val["data"]["value"].as_i64().unwrap() as i32).to_vec(),
-            );
-        }
-        "i64" => {
-            result.push(3);
-            result.append(
-                &mut i64::to_be_bytes(val["data"]["value"].as_i64().unwrap() as i64).to_vec(),
-            );
-        }
-        "f32" => {
-            result.push(4);
-            result.append(
-                &mut f32::to_be_bytes(val["data"]["value"].as_f64().unwrap() as f32).to_vec(),
-            );
-        }
-        "f64" => {
-            result.push(5);
-            result.append(
-                &mut f64::to_be_bytes(val["data"]["value"].as_f64().unwrap() as f64).to_vec(),
-            );
-        }
-        "bool" => {
-            result.push(6);
-            result.push(if val["data"]["value"].as_bool().unwrap() {
-                0x01
-            } else {
-                0x00
-            });
-        }
-        "string" => {
-            result.push(7);
-            let mut value_bytes = val["data"]["value"].as_str().unwrap().as_bytes().to_vec();
-            result.append(&mut i32::to_be_bytes(value_bytes.len() as i32).to_vec());
-            result.append(&mut value_bytes);
-        }
-        "identifier" => {
-            result.push(0x0b);
-            let mut value_bytes = val["data"]["name"].as_str().unwrap().as_bytes().to_vec();
-            result.append(&mut i32::to_be_bytes(value_bytes.len() as i32).to_vec());
-            result.append(&mut value_bytes);
-        }
-        "indexer" => {
-            result.push(0x0c);
-            result.append(&mut serialize_expr(val["data"]["target"].clone()));
-            result.append(&mut serialize_expr(val["data"]["index"].clone()));
-        }
-        "cast" => {
-            result.push(0xfd);
-            result.append(&mut serialize_expr(val["data"]["value"].clone()));
-            let mut tt_bytes = val["data"]["targetType"]
-                .as_str()
-                .unwrap()
-                .as_bytes()
-                .to_vec();
-            result.append(&mut i32::to_be_bytes(tt_bytes.len() as i32).to_vec());
-            result.append(&mut tt_bytes);
-        }
-        "object" => {
-            result.push(8);
-            result.append(&mut i64::to_be_bytes(-2).to_vec());
-            result.append(&mut i32::to_be_bytes(val["data"]["value"].as_object().unwrap().iter().len() as i32).to_vec());
-            for (k, v) in val["data"]["value"].as_object().unwrap().iter() {
-                result.push(7);
-                let mut key_bytes = k.as_bytes().to_vec();
-                result.append(&mut i32::to_be_bytes(key_bytes.len() as i32).to_vec());
-                result.append(&mut key_bytes);
-                result.append(&mut serialize_expr(v.clone()));
-            }
-        }
-        "array" => {
-            result.push(9);
-            result.append(
-                &mut i32::to_be_bytes(val["data"]["value"].as_array().unwrap().iter().len() as i32)
-                    .to_vec(),
-            );
-            for v in val["data"]["value"].as_array().unwrap().iter() {
-                result.append(&mut serialize_expr(v.clone()));
-            }
-        }
-        "callback" => {
-            result.append(&mut serialize_expr(val["data"]["value"]["funcId"].clone()));
-        }
-        "not" => {
-            result.push(0xfc);
-            result.append(&mut serialize_expr(val["data"]["value"].clone()));
-        }
-        "arithmetic" => {
-            match val["data"]["operation"].as_str().unwrap() {
-                "==" => {
-                    result.push(0xf0);
-                }
-                ">" => {
-                    result.push(0xf1);
-                }
-                ">=" => {
-                    result.push(0xf2);
-                }
-                "<" => {
-                    result.push(0xf3);
-                }
-                "<=" => {
-                    result.push(0xf4);
-                }
-                "!=" => {
-                    result.push(0xf5);
-                }
-                "+" => {
-                    result.push(0xf6);
-                }
-                "-" => {
-                    result.push(0xf7);
-                }
-                "*" => {
-                    result.push(0xf8);
-                }
-                "/" => {
-                    result.push(0xf9);
-                }
-                "%" => {
-                    result.push(0xfa);
-                }
-                "^" => {
-                    result.push(0xfb);
-                }
-                _ => {}
-            };
-            result.append(&mut serialize_expr(val["data"]["operand1"].clone()));
-            result.append(&mut serialize_expr(val["data"]["operand2"].clone()));
-        }
-        "functionCall" => {
-            result.push(0x0d);
-            result.append(&mut serialize_expr(val["data"]["callee"].clone()));
-            result.append(
-                &mut i32::to_be_bytes(val["data"]["args"].as_array().unwrap().len() as i32)
-                    .to_vec(),
-            );
-            val["data"]["args"]
-                .as_array()
-                .unwrap()
-                .iter()
-                .for_each(|arg| {
-                    result.append(&mut serialize_expr(arg.clone()));
+// a loop being compiled: the `break` and `continue` jumps to patch once
+// its end and its next round are known, and how many try statements were
+// open when it started
+struct LoopState {
+    label: Option<String>,
+    tries: u16,
+    breaks: Vec<usize>,
+    continues: Vec<usize>,
+}
+
+// the function being compiled: its blocks, innermost last, and frame
+struct FuncState {
+    proto: usize,
+    scopes: Vec<Vec<Declared>>,
+    next_reg: u16,
+    max_reg: u16,
+    cells: u16,
+    upvalues: Vec<Upvalue>,
+    locals: Vec<LocalEntry>,
+    loops: Vec<LoopState>,
+    // try statements the code being compiled is inside of
+    tries: u16,
+}
+
+impl FuncState {
+    fn new(proto: usize) -> Self {
+        FuncState {
+            proto,
+            scopes: vec![],
+            next_reg: 0,
+            max_reg: 0,
+            cells: 0,
+            upvalues: vec![],
+            locals: vec![],
+            loops: vec![],
+            tries: 0,
+        }
+    }
+    fn find(&self, name: &str) -> Option<&Declared> {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.iter().rev().find(|declared| declared.name == name))
+    }
+}
+
+// `jumpOperation` and `conditionalBranch` address the statements of their
+// block by 1-based step number
+#[derive(Default)]
+struct Steps {
+    starts: HashMap<i64, usize>,
+    patches: Vec<(usize, i64)>,
+}
+
+struct Compiler<'m> {
+    constants: Vec<Constant>,
+    strings: HashMap<String, u32>,
+    numbers: HashMap<(u8, u64), u32>,
+    functions: Vec<FunctionEntry>,
+    statements: Vec<usize>,
+    code: Vec<u8>,
+    map: Option<&'m mut SourceMap>,
+    funcs: Vec<FuncState>,
+    // the module being compiled, `None` for the program itself, and the
+    // globals its top-level names stand for (see `linker::Unit`)
+    module: Option<String>,
+    globals: HashMap<String, String>,
+    classes: Vec<ClassEntry>,
+    // the class whose methods are being compiled
+    class: Option<usize>,
+    // malformed nodes found so far, and where the statement being compiled
+    // sits, to anchor them to
+    errors: Vec<SyntaxError>,
+    span: Span,
+    path: String,
+}
+
+impl<'m> Compiler<'m> {
+    fn new(start_point: usize, map: Option<&'m mut SourceMap>) -> Self {
+        Compiler {
+            constants: vec![],
+            strings: HashMap::new(),
+            numbers: HashMap::new(),
+            functions: vec![],
+            statements: vec![],
+            code: vec![op::NOP; start_point],
+            map,
+            funcs: vec![],
+            module: None,
+            globals: HashMap::new(),
+            classes: vec![],
+            class: None,
+            errors: vec![],
+            span: Span::default(),
+            path: String::new(),
+        }
+    }
+
+    fn compile(self, program: &Value) -> Result<Program, Vec<SyntaxError>> {
+        self.compile_units(&[(None, program, HashMap::new())])
+    }
+
+    // Compiles modules one after the other into the main function, so the
+    // top-level statements of each run in that order. Fails with every
+    // malformed node of the AST.
+    fn compile_units(
+        mut self,
+        units: &[(Option<String>, &Value, HashMap<String, String>)],
+    ) -> Result<Program, Vec<SyntaxError>> {
+        self.functions.push(entry("<main>", vec![], false));
+        self.funcs.push(FuncState::new(0));
+        // every class is listed up front, so code can refer to classes
+        // declared further down
+        for (_, program, globals) in units {
+            self.globals = globals.clone();
+            for class in class_definitions(program) {
+                let name = self.text(&class["name"], "a class name");
+                let name = self.global(name);
+                let fields = self
+                    .items(&class["fields"], "the fields of a class")
+                    .iter()
+                    .map(|field| self.text(&field["name"], "a field name").to_string())
+                    .collect();
+                self.classes.push(ClassEntry {
+                    name,
+                    parent: None,
+                    fields,
+                    methods: vec![],
                 });
+            }
         }
-        "host_call" => {
-            result.push(0x0d);
-            result.append(&mut serialize_expr(json!(
-                {
-                    "type": "identifier",
-                    "data": {
-                        "name": "askHost",
-                    }
-                }
-            )));
-            result.append(&mut i32::to_be_bytes(2).to_vec());
-            result.append(&mut serialize_expr(json!(
-                {
-                    "type": "string",
-                    "data": {
-                        "value": val["data"]["name"].as_str().unwrap().to_string(),
-                    }
+        let mut index = 0;
+        for (_, program, globals) in units {
+            self.globals = globals.clone();
+            for class in class_definitions(program) {
+                if let Some(parent) = class["parent"].as_str() {
+                    let parent = self.class_index(&self.global(parent));
+                    self.classes[index].parent = Some(parent);
                 }
-            )));
-            let args = val["data"]["args"].as_array().unwrap().clone();
-            let input = json!({
-                "type": "array",
-                "data": {
-                    "value": args
-                },
-            });
-            result.append(&mut serialize_expr(input.clone()));
+                index += 1;
+            }
+        }
+        for (module, program, globals) in units {
+            self.module = module.clone();
+            self.globals = globals.clone();
+            // the outermost block of a module is the global scope, its
+            // variables and functions are globals and not frame slots
+            let path = module.as_deref().unwrap_or("");
+            self.statements_of(&program["body"], path);
         }
-        _ => {
-            panic!("unknown val type");
+        if !self.errors.is_empty() {
+            return Err(self.errors);
         }
+        self.emit(op::RETURN_NULL);
+        let state = self.funcs.pop().unwrap();
+        self.finish_function(state, 0);
+        self.statements.sort_unstable();
+        Ok(Program {
+            constants: self.constants,
+            functions: self.functions,
+            classes: self.classes,
+            statements: self.statements,
+            code: self.code,
+        })
     }
-    result
-}
 
-fn serialize_condition_chain(
-    operation: Value,
-    is_conditioned: bool,
-    start_point: usize,
-) -> (Vec<u8>, Vec<usize>) {
-    let mut result: Vec<u8> = vec![];
-    let mut baps: Vec<usize> = vec![];
-    result.push(0x10);
-    if is_conditioned {
-        result.push(0x01);
-        result.append(&mut serialize_expr(operation["data"]["condition"].clone()).to_vec());
-    } else {
-        result.push(0x00);
+    // --- emitting ---
+
+    fn emit(&mut self, opcode: u8) {
+        self.code.push(opcode);
+    }
+    fn u8(&mut self, value: u8) {
+        self.code.push(value);
+    }
+    fn u16(&mut self, value: u16) {
+        self.code.extend_from_slice(&value.to_be_bytes());
+    }
+    fn u32(&mut self, value: u32) {
+        self.code.extend_from_slice(&value.to_be_bytes());
+    }
+    fn ops(&mut self, opcode: u8, operands: &[u16]) {
+        self.emit(opcode);
+        for operand in operands {
+            self.u16(*operand);
+        }
+    }
+    // an address operand to fill in later, returns where it sits
+    fn hole(&mut self) -> usize {
+        let at = self.code.len();
+        self.u32(0);
+        at
+    }
+    fn patch(&mut self, at: usize, address: usize) {
+        self.code[at..at + 4].copy_from_slice(&(address as u32).to_be_bytes());
+    }
+    fn patch_here(&mut self, at: usize) {
+        self.patch(at, self.code.len());
+    }
+    fn jump(&mut self) -> usize {
+        self.emit(op::JUMP);
+        self.hole()
+    }
+    fn jump_if_not(&mut self, cond: u16) -> usize {
+        self.ops(op::JUMP_IF_NOT, &[cond]);
+        self.hole()
     }
-    let body_start = if is_conditioned {
-        start_point + result.len() + 8 + 8 + 8 + 8
-    } else {
-        start_point + result.len() + 8 + 8 + 8
-    };
-    let body = compile_ast(operation["data"].clone(), body_start);
-    let body_end = body_start + body.len();
-    result.append(&mut i64::to_be_bytes(body_start as i64).to_vec());
-    result.append(&mut i64::to_be_bytes(body_end as i64).to_vec());
-    let mut after_body: Vec<u8> = vec![];
-    if let Some(elseif_stmt) = operation["data"].get("elseifStmt") {
-        let (mut compiled_body, mut branch_after_points) =
-            serialize_condition_chain(elseif_stmt.clone(), true, body_end);
-        after_body.append(&mut compiled_body);
-        baps.append(&mut branch_after_points);
-    } else if let Some(else_stmt) = operation["data"].get("elseStmt") {
-        let (mut compiled_body, mut branch_after_points) =
-            serialize_condition_chain(else_stmt.clone(), false, body_end);
-        after_body.append(&mut compiled_body);
-        baps.append(&mut branch_after_points);
-    }
-    if is_conditioned {
-        result.append(&mut i64::to_be_bytes(body_end as i64).to_vec());
-    }
-    baps.push(start_point + result.len());
-    result.append(&mut vec![0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
-    result.append(&mut body.clone());
-    result.append(&mut after_body);
-    (result, baps)
-}
 
-pub fn compile_ast(program: serde_json::Value, start_point: usize) -> Vec<u8> {
-    let mut result: Vec<u8> = vec![];
-    let mut op_counter: i64 = 1;
-    let mut step_start_map: HashMap<i64, usize> = HashMap::new();
-    let mut reserved_branch_map: HashMap<i64, Vec<usize>> = HashMap::new();
-    for operation in program["body"].as_array().unwrap().iter() {
-        step_start_map
-            .entry(op_counter)
-            .or_insert(start_point + result.len());
-        match operation["type"].as_str().unwrap() {
-            "jumpOperation" => {
-                result.push(0x15);
-                let true_branch = result.len();
-                result.extend_from_slice(&[0u8; 8]);
-                let true_step = operation["data"]["stepNumber"].as_i64().unwrap();
-                reserved_branch_map
-                    .entry(true_step)
-                    .or_default()
-                    .push(true_branch);
+    fn string(&mut self, text: &str) -> u32 {
+        if let Some(index) = self.strings.get(text) {
+            return *index;
+        }
+        let index = self.push_constant(Constant::Str(text.to_string()));
+        self.strings.insert(text.to_string(), index);
+        index
+    }
+    fn number(&mut self, constant: Constant) -> u32 {
+        let key = match constant {
+            Constant::I16(v) => (1, v as u64),
+            Constant::I32(v) => (2, v as u64),
+            Constant::I64(v) => (3, v as u64),
+            Constant::F32(v) => (4, v.to_bits() as u64),
+            Constant::F64(v) => (5, v.to_bits()),
+            Constant::Str(ref text) => return self.string(text),
+        };
+        if let Some(index) = self.numbers.get(&key) {
+            return *index;
+        }
+        let index = self.push_constant(constant);
+        self.numbers.insert(key, index);
+        index
+    }
+    fn push_constant(&mut self, constant: Constant) -> u32 {
+        self.constants.push(constant);
+        u32::try_from(self.constants.len() - 1).expect("too many constants")
+    }
+
+    // --- frames ---
+
+    fn func(&mut self) -> &mut FuncState {
+        self.funcs.last_mut().unwrap()
+    }
+    fn at_global_level(&self) -> bool {
+        self.funcs.len() == 1 && self.funcs[0].scopes.is_empty()
+    }
+    fn temp(&mut self) -> u16 {
+        self.temps(1)
+    }
+    // `count` consecutive registers
+    fn temps(&mut self, count: usize) -> u16 {
+        let func = self.func();
+        let first = func.next_reg;
+        func.next_reg = u16::try_from(first as usize + count)
+            .expect("a function needs more than 65535 registers");
+        func.max_reg = func.max_reg.max(func.next_reg);
+        first
+    }
+    fn target(&mut self, want: Option<u16>) -> u16 {
+        match want {
+            Some(dst) => dst,
+            None => self.temp(),
+        }
+    }
+    fn declare(&mut self, name: &str, captured: bool) -> Slot {
+        let slot = if captured {
+            let func = self.func();
+            func.cells += 1;
+            Slot::Cell(func.cells - 1)
+        } else {
+            Slot::Register(self.temp())
+        };
+        let func = self.func();
+        func.locals.push(LocalEntry {
+            name: name.to_string(),
+            slot,
+            start: UNSET,
+            end: UNSET,
+        });
+        let local = func.locals.len() - 1;
+        func.scopes.last_mut().unwrap().push(Declared {
+            name: name.to_string(),
+            slot,
+            local,
+        });
+        slot
+    }
+    // the debug range of a local starts once it got its first value
+    fn mark_defined(&mut self, name: &str) {
+        let here = self.code.len();
+        let func = self.funcs.last_mut().unwrap();
+        if let Some(index) = func.find(name).map(|declared| declared.local) {
+            let local = &mut func.locals[index];
+            if local.start == UNSET {
+                local.start = here;
             }
-            "conditionalBranch" => {
-                result.push(0x16);
-                result.append(&mut serialize_expr(operation["data"]["condition"].clone()));
-                let true_branch = result.len();
-                result.extend_from_slice(&[0u8; 8]);
-                let false_branch = result.len();
-                result.extend_from_slice(&[0u8; 8]);
-                let true_step = operation["data"]["trueBranch"].as_i64().unwrap();
-                let false_step = operation["data"]["falseBranch"].as_i64().unwrap();
-                reserved_branch_map
-                    .entry(true_step)
-                    .or_default()
-                    .push(true_branch);
-                reserved_branch_map
-                    .entry(false_step)
-                    .or_default()
-                    .push(false_branch);
+        }
+    }
+    fn close_scope(&mut self) {
+        let here = self.code.len();
+        let func = self.funcs.last_mut().unwrap();
+        for declared in func.scopes.pop().unwrap() {
+            let local = &mut func.locals[declared.local];
+            if local.start == UNSET {
+                local.start = here;
             }
-            "host_call" => {
-                result.push(0x0d);
-                result.append(&mut serialize_expr(json!(
-                    {
-                        "type": "identifier",
-                        "data": {
-                            "name": "askHost",
-                        }
-                    }
-                )));
-                result.append(&mut i32::to_be_bytes(2).to_vec());
-                result.append(&mut serialize_expr(json!(
-                    {
-                        "type": "string",
-                        "data": {
-                            "value": operation["data"]["name"].as_str().unwrap().to_string(),
-                        }
-                    }
-                )));
-                let args = operation["data"]["args"].as_array().unwrap().clone();
-                let input = json!({
-                    "type": "array",
-                    "data": {
-                        "value": args
-                    },
+            local.end = here;
+        }
+    }
+
+    fn resolve(&mut self, name: &str) -> Var {
+        self.resolve_at(self.funcs.len() - 1, name)
+    }
+    fn resolve_at(&mut self, level: usize, name: &str) -> Var {
+        if let Some(declared) = self.funcs[level].find(name) {
+            return Var::Local(declared.slot);
+        }
+        if level == 0 {
+            return Var::Global;
+        }
+        let from = match self.resolve_at(level - 1, name) {
+            Var::Local(Slot::Cell(c)) => Capture::Cell(c),
+            Var::Upvalue(u) => Capture::Upvalue(u),
+            // captured variables are always given cells, see
+            // `captured_names`
+            Var::Local(Slot::Register(_)) | Var::Global => return Var::Global,
+        };
+        let upvalues = &mut self.funcs[level].upvalues;
+        let index = match upvalues.iter().position(|upvalue| upvalue.name == name) {
+            Some(index) => index,
+            None => {
+                upvalues.push(Upvalue {
+                    name: name.to_string(),
+                    from,
                 });
-                result.append(&mut serialize_expr(input.clone()));
+                upvalues.len() - 1
             }
-            "returnOperation" => {
-                result.push(0x14);
-                result.append(&mut serialize_expr(operation["data"]["value"].clone()).to_vec());
+        };
+        Var::Upvalue(index as u16)
+    }
+
+    // the class table index of the class named `name`, listing it when the
+    // program does not declare it
+    fn class_index(&mut self, name: &str) -> u16 {
+        let index = match self.classes.iter().position(|class| class.name == name) {
+            Some(index) => index,
+            None => {
+                self.classes.push(ClassEntry {
+                    name: name.to_string(),
+                    parent: None,
+                    fields: vec![],
+                    methods: vec![],
+                });
+                self.classes.len() - 1
             }
-            "ifStmt" => {
-                let (mut compiled_code, baps) =
-                    serialize_condition_chain(operation.clone(), true, start_point + result.len());
-                let branch_after =
-                    i64::to_be_bytes((start_point + result.len() + compiled_code.len()) as i64)
-                        .to_vec();
-                for bap in baps.iter() {
-                    let s = *bap - start_point - result.len();
-                    let e = *bap + 8 - start_point - result.len();
-                    compiled_code[s..e].copy_from_slice(branch_after.as_slice());
+        };
+        u16::try_from(index).expect("too many classes")
+    }
+
+    // the global a top-level name of the module being compiled stands for
+    fn global(&self, name: &str) -> String {
+        match self.globals.get(name) {
+            Some(global) => global.clone(),
+            None => name.to_string(),
+        }
+    }
+
+    // reads a variable into `want`, or wherever it already is
+    fn load(&mut self, name: &str, want: Option<u16>) -> u16 {
+        match self.resolve(name) {
+            Var::Local(Slot::Register(r)) => match want {
+                Some(dst) if dst != r => {
+                    self.ops(op::MOVE, &[dst, r]);
+                    dst
                 }
-                result.append(&mut compiled_code);
-            }
-            "loopStmt" => {
-                let loop_start = start_point + result.len();
-                result.push(0x11);
-                result.append(&mut serialize_expr(operation["data"]["condition"].clone()).to_vec());
-                let body_start = start_point + result.len() + 8 + 8 + 8;
-                let mut body = compile_ast(operation["data"].clone(), body_start);
-                body.push(0x15);
-                body.append(&mut i64::to_be_bytes(loop_start as i64).to_vec());
-                let body_end = body_start + body.len();
-                result.append(&mut i64::to_be_bytes(body_start as i64).to_vec());
-                result.append(&mut i64::to_be_bytes(body_end as i64).to_vec());
-                result.append(&mut i64::to_be_bytes(body_end as i64).to_vec());
-                result.append(&mut body.clone());
+                _ => r,
+            },
+            Var::Local(Slot::Cell(c)) => {
+                let dst = self.target(want);
+                self.ops(op::GET_CELL, &[dst, c]);
+                dst
             }
-            "switchStmt" => {
-                result.push(0x12);
-                result.append(&mut serialize_expr(operation["data"]["value"].clone()).to_vec());
-                let mut inner: Vec<u8> = vec![];
-                for case_val in operation["data"]["cases"].as_array().unwrap().iter() {
-                    inner.append(&mut serialize_expr(case_val["value"].clone()));
-                    let body_start = start_point + result.len() + 8 + 8 + inner.len() + 8 + 8;
-                    let mut body: Vec<u8> = compile_ast(case_val["body"].clone(), body_start);
-                    let body_end = body_start + body.len();
-                    inner.append(&mut i64::to_be_bytes(body_start as i64).to_vec());
-                    inner.append(&mut i64::to_be_bytes(body_end as i64).to_vec());
-                    inner.append(&mut body);
-                }
-                result.append(
-                    &mut i64::to_be_bytes(
-                        (start_point + result.len() + inner.len() + 8 + 8) as i64,
-                    )
-                    .to_vec(),
-                );
-                result.append(
-                    &mut i64::to_be_bytes(
-                        operation["data"]["cases"].as_array().unwrap().len() as i64
-                    )
-                    .to_vec(),
-                );
-                result.append(&mut inner);
+            Var::Upvalue(u) => {
+                let dst = self.target(want);
+                self.ops(op::GET_UPVALUE, &[dst, u]);
+                dst
             }
-            "functionDefinition" => {
-                result.push(0x13);
-                let mut str_bytes = operation["data"]["name"]
-                    .as_str()
-                    .unwrap()
-                    .as_bytes()
-                    .to_vec();
-                let mut len_bytes = i32::to_be_bytes(str_bytes.len() as i32).to_vec();
-                result.append(&mut len_bytes);
-                result.append(&mut str_bytes);
-                result.append(
-                    &mut i32::to_be_bytes(
-                        operation["data"]["params"].as_array().unwrap().len() as i32
-                    )
-                    .to_vec(),
-                );
-                for p_name in operation["data"]["params"].as_array().unwrap().iter() {
-                    let mut str_bytes = p_name.as_str().unwrap().as_bytes().to_vec();
-                    let mut len_bytes = i32::to_be_bytes(str_bytes.len() as i32).to_vec();
-                    result.append(&mut len_bytes);
-                    result.append(&mut str_bytes);
-                }
-                let func_start = start_point + result.len() + 8 + 8;
-                let body = compile_ast(operation["data"].clone(), func_start);
-                let func_end = func_start + body.len();
-                result.append(&mut i64::to_be_bytes(func_start as i64).to_vec());
-                result.append(&mut i64::to_be_bytes(func_end as i64).to_vec());
-                result.append(&mut body.clone());
+            Var::Global => {
+                let dst = self.target(want);
+                self.get_global(dst, &self.global(name));
+                dst
             }
-            "functionCall" => {
-                result.push(0x0d);
-                result.append(&mut serialize_expr(operation["data"]["callee"].clone()));
-                result.append(
-                    &mut i32::to_be_bytes(
-                        operation["data"]["args"].as_array().unwrap().len() as i32
-                    )
-                    .to_vec(),
-                );
-                operation["data"]["args"]
-                    .as_array()
-                    .unwrap()
-                    .iter()
-                    .for_each(|arg| {
-                        result.append(&mut serialize_expr(arg.clone()));
-                    });
-            }
-            "definition" => {
-                result.push(0x0e);
-                if operation["data"]["leftSide"]["type"].as_str().unwrap() == "identifier" {
-                    result.push(0x0b);
-                    let mut str_bytes = operation["data"]["leftSide"]["data"]["name"]
-                        .as_str()
-                        .unwrap()
-                        .as_bytes()
-                        .to_vec();
-                    let mut len_bytes = i32::to_be_bytes(str_bytes.len() as i32).to_vec();
-                    result.append(&mut len_bytes);
-                    result.append(&mut str_bytes);
-                    result.append(&mut serialize_expr(operation["data"]["rightSide"].clone()));
-                }
+        }
+    }
+    fn get_global(&mut self, dst: u16, name: &str) {
+        let k = self.string(name);
+        self.ops(op::GET_GLOBAL, &[dst]);
+        self.u32(k);
+    }
+    // stores the value of `value` in the variable `name`: globals are
+    // defined or updated as `define` says, locals are written in place
+    fn store(&mut self, name: &str, value: &Value, define: bool) {
+        match self.resolve(name) {
+            Var::Local(Slot::Register(r)) => {
+                self.expr(value, Some(r));
             }
-            "assignment" => {
-                result.push(0x0f);
-                if operation["data"]["leftSide"]["type"].as_str().unwrap() == "identifier" {
-                    result.push(0x0b);
-                    let mut str_bytes = operation["data"]["leftSide"]["data"]["name"]
-                        .as_str()
-                        .unwrap()
-                        .as_bytes()
-                        .to_vec();
-                    let mut len_bytes = i32::to_be_bytes(str_bytes.len() as i32).to_vec();
-                    result.append(&mut len_bytes);
-                    result.append(&mut str_bytes);
-                    result.append(&mut serialize_expr(operation["data"]["rightSide"].clone()));
-                } else if operation["data"]["leftSide"]["type"].as_str().unwrap() == "indexer" {
-                    result.push(0x0c);
-                    let mut str_bytes = operation["data"]["leftSide"]["data"]["target"]["data"]
-                        ["name"]
-                        .as_str()
-                        .unwrap()
-                        .as_bytes()
-                        .to_vec();
-                    let mut len_bytes = i32::to_be_bytes(str_bytes.len() as i32).to_vec();
-                    result.append(&mut len_bytes);
-                    result.append(&mut str_bytes);
-                    result.append(&mut serialize_expr(operation["data"]["rightSide"].clone()));
-                }
+            Var::Local(Slot::Cell(c)) => {
+                let src = self.expr(value, None);
+                self.ops(op::SET_CELL, &[c, src]);
             }
-            _ => {
-                // skip
+            Var::Upvalue(u) => {
+                let src = self.expr(value, None);
+                self.ops(op::SET_UPVALUE, &[u, src]);
+            }
+            Var::Global => {
+                let src = self.expr(value, None);
+                self.store_global(&self.global(name), src, define);
             }
-        }
-        op_counter += 1;
-    }
-    for (key, value) in reserved_branch_map {
-        let step_point = *step_start_map.get(&key).unwrap();
-        let sp_bytes = i64::to_be_bytes(step_point as i64).to_vec();
-        for space in value.iter() {
-            let address: usize = *space;
-            result[address..address + 8].copy_from_slice(sp_bytes.as_slice());
         }
     }
-    if result.is_empty() {
-        result.push(0x00);
+    fn store_global(&mut self, name: &str, src: u16, define: bool) {
+        let k = self.string(name);
+        self.emit(if define {
+            op::DEF_GLOBAL
+        } else {
+            op::SET_GLOBAL
+        });
+        self.u32(k);
+        self.u16(src);
     }
-    result
-}
 
-pub fn parse_code(program: String) -> serde_json::Value {
-    let temp_prog = program.clone();
-    let mut tokens: Vec<String> = vec![];
-    let mut temp_token = "".to_string();
-    let mut inside_string = false;
-    for c in temp_prog.chars() {
-        if c == '"' {
-            if inside_string {
-                inside_string = false;
-                temp_token.push(c);
-                tokens.push(temp_token);
-                temp_token = "".to_string();
-            } else {
-                inside_string = true;
-                temp_token.push(c);
+    // --- reading the AST ---
+    //
+    // The AST may come from the host as it is. A node of the wrong shape is
+    // recorded as an error against the statement being compiled and read
+    // as an empty value, so every malformed node is reported at once.
+
+    fn malformed(&mut self, message: String) {
+        let message = match self.path.as_str() {
+            "" => message,
+            path => format!("{}: {}", path, message),
+        };
+        let error = SyntaxError::new(message, self.span).in_module(self.module.as_deref());
+        self.errors.push(error);
+    }
+    fn text<'v>(&mut self, value: &'v Value, what: &str) -> &'v str {
+        match value.as_str() {
+            Some(text) => text,
+            None => {
+                self.malformed(expected("a string", what, value));
+                ""
             }
-            continue;
         }
-        let c_stred: &str = &c.to_string();
-        if c == ' ' || c == '\n' || c == '\t' {
-            if temp_token.len() > 0 {
-                tokens.push(temp_token);
-                temp_token = "".to_string();
+    }
+    fn items<'v>(&mut self, value: &'v Value, what: &str) -> &'v [Value] {
+        match value.as_array() {
+            Some(items) => items,
+            None => {
+                self.malformed(expected("an array", what, value));
+                &[]
             }
-            continue;
-        } else if vec![
-            "=", "+", "-", "*", "/", "^", "%", "==", ">", "<", ">=", "<=", "!=", ".", "(", ")",
-            "[", "]", "{", "}", ":", ",",
-        ]
-        .contains(&c_stred)
-        {
-            if temp_token.len() > 0 {
-                tokens.push(temp_token);
-                temp_token = "".to_string();
-            }
-            tokens.push(c.to_string());
-            continue;
         }
-        temp_token.push(c);
-    }
-    if temp_token.len() > 0 {
-        tokens.push(temp_token);
-    }
-    // log(&format!("{:?}", tokens));
-    let mut result = json!({});
-    let mut state_num = 0;
-    let mut stack: Vec<HashMap<String, Value>> = vec![];
-    let mut first_stage: HashMap<String, Value> = HashMap::new();
-    first_stage.insert("body".to_string(), json!([]));
-    first_stage.insert("type".to_string(), json!("program".to_string()));
-    stack.push(first_stage);
-    let mut p: usize = 0;
-    let mut current_reg: Value = json!(0);
-    let mut counter = 0;
-    let mut reserved_identifier = "".to_string();
-    loop {
-        counter += 1;
-        // log(&p.to_string());
-        // log(&state_num.to_string());
-        // log(&format!("{:?}", stack));
-        if counter > 50 {
-            break;
-        }
-        if stack.len() == 0 && p >= tokens.len() {
-            break;
-        }
-        if p >= tokens.len() {
-            if state_num == 0 {
-                result["type"] = json!("program");
-                result["body"] = stack.last().unwrap().get("body").unwrap().clone();
-                stack.pop();
-                continue;
-            } else if state_num == 101 {
-                if current_reg
-                    .get("type")
-                    .unwrap()
-                    .as_str()
-                    .unwrap()
-                    .to_string()
-                    == "functionCall"
-                {
-                    stack
-                        .last_mut()
-                        .unwrap()
-                        .get_mut("body")
-                        .unwrap()
-                        .as_array_mut()
-                        .unwrap()
-                        .push(current_reg.clone());
-                    state_num = 0;
-                    continue;
-                }
-                let last_stage = stack.last().unwrap().clone();
-                stack.pop();
-                let last_type = last_stage["type"].as_str().unwrap().to_string();
-                if last_type == "arithmetic" {
-                    current_reg = json!({
-                        "type": "arithmetic",
-                        "data": {
-                            "operation": last_stage.get("operation").unwrap().clone(),
-                            "operand1": last_stage.get("operand1").unwrap().clone(),
-                            "operand2": current_reg
-                        }
-                    });
-                } else if last_type == "definition" {
-                    stack.last_mut().unwrap().get_mut("body").unwrap().as_array_mut().unwrap().push(json!({
-                        "type": "definition",
-                        "data": {
-                            "leftSide": {
-                                "type": "identifier",
-                                "data": {
-                                    "name": last_stage.get("leftSide").unwrap().as_str().unwrap().to_string()
-                                }
-                            },
-                            "rightSide": current_reg
-                        }
-                    }));
-                    state_num = 0;
-                } else if last_type == "assignment" {
-                    stack.last_mut().unwrap().get_mut("body").unwrap().as_array_mut().unwrap().push(json!({
-                        "type": "assignment",
-                        "data": {
-                            "leftSide": {
-                                "type": "identifier",
-                                "data": {
-                                    "name": last_stage.get("leftSide").unwrap().as_str().unwrap().to_string()
-                                }
-                            },
-                            "rightSide": current_reg
-                        }
-                    }));
-                    state_num = 0;
-                }
-                continue;
+    }
+    fn integer(&mut self, value: &Value, what: &str) -> i64 {
+        value.as_i64().unwrap_or_else(|| {
+            self.malformed(expected("an integer", what, value));
+            0
+        })
+    }
+    fn float(&mut self, value: &Value, what: &str) -> f64 {
+        value.as_f64().unwrap_or_else(|| {
+            self.malformed(expected("a number", what, value));
+            0.0
+        })
+    }
+
+    // --- expressions ---
+
+    // Compiles `node` into register `want`, or a fresh temporary, and
+    // returns the register holding the value. Variables held in registers
+    // are returned as they are when no target is given.
+    fn expr(&mut self, node: &Value, want: Option<u16>) -> u16 {
+        let data = &node["data"];
+        match self.text(&node["type"], "the type of an expression") {
+            "i16" => {
+                let v = self.integer(&data["value"], "an i16 value");
+                let v = i16::try_from(v).unwrap_or_else(|_| {
+                    self.malformed(format!("{} does not fit in an i16", v));
+                    0
+                });
+                self.constant(Constant::I16(v), want)
             }
-        }
-        let token = tokens[p].clone();
-        if state_num == 0 {
-            if token == "def" {
-                p += 1;
-                state_num = 1;
-                stack.push(HashMap::new());
-                stack
-                    .last_mut()
-                    .unwrap()
-                    .insert("type".to_string(), json!("definition"));
-                continue;
-            } else {
-                p += 1;
-                reserved_identifier = token.clone();
-                state_num = 3;
-            }
-        } else if state_num == 1 {
-            p += 1;
-            stack
-                .last_mut()
-                .unwrap()
-                .insert("leftSide".to_string(), json!(token.clone()));
-            state_num = 2;
-            continue;
-        } else if state_num == 2 {
-            if token == "=" {
-                p += 1;
-                state_num = 100;
-                continue;
+            "i32" => {
+                let v = self.integer(&data["value"], "an i32 value");
+                let v = i32::try_from(v).unwrap_or_else(|_| {
+                    self.malformed(format!("{} does not fit in an i32", v));
+                    0
+                });
+                self.constant(Constant::I32(v), want)
             }
-        } else if state_num == 3 {
-            if token == "=" {
-                p += 1;
-                stack.push(HashMap::new());
-                stack
-                    .last_mut()
-                    .unwrap()
-                    .insert("type".to_string(), json!("assignment"));
-                stack
-                    .last_mut()
-                    .unwrap()
-                    .insert("leftSide".to_string(), json!(reserved_identifier.clone()));
-                reserved_identifier = "".to_string();
-                state_num = 100;
-                continue;
-            } else if token == "(" {
-                p += 1;
-                stack.push(HashMap::new());
-                stack
-                    .last_mut()
-                    .unwrap()
-                    .insert("type".to_string(), json!("functionCall"));
-                stack.last_mut().unwrap().insert(
-                    "callee".to_string(),
-                    json!({
-                        "type": "identifier",
-                        "data": {
-                            "name": reserved_identifier.clone(),
-                        }
-                    }),
-                );
-                stack
-                    .last_mut()
-                    .unwrap()
-                    .insert("args".to_string(), json!(vec![] as Vec<Value>));
-                reserved_identifier = "".to_string();
-                state_num = 100;
-                continue;
+            "i64" => {
+                let v = self.integer(&data["value"], "an i64 value");
+                self.constant(Constant::I64(v), want)
             }
-        } else if state_num == 100 {
-            if token == "{" {
-                stack.push(HashMap::new());
-                stack
-                    .last_mut()
-                    .unwrap()
-                    .insert("objectData".to_string(), json!({}));
-                stack
-                    .last_mut()
-                    .unwrap()
-                    .insert("type".to_string(), json!("objectExpr"));
-                p += 1;
-                state_num = 102;
-                continue;
+            "f32" => {
+                let v = self.float(&data["value"], "an f32 value");
+                self.constant(Constant::F32(v as f32), want)
             }
-            if token == "(" {
-                stack.push(HashMap::new());
-                stack
-                    .last_mut()
-                    .unwrap()
-                    .insert("type".to_string(), json!("paren"));
-                p += 1;
-                continue;
+            "f64" => {
+                let v = self.float(&data["value"], "an f64 value");
+                self.constant(Constant::F64(v), want)
             }
-            let parse_res_i16 = token.parse::<i16>();
-            if parse_res_i16.is_ok() {
-                current_reg = json!({
-                    "type": "i16",
-                    "data": { "value": parse_res_i16.unwrap() }
-                });
-                p += 1;
-                state_num = 101;
-                continue;
+            "string" => {
+                let text = self.text(&data["value"], "a string value");
+                self.constant(Constant::Str(text.to_string()), want)
             }
-            let parse_res_i32 = token.parse::<i32>();
-            if parse_res_i32.is_ok() {
-                current_reg = json!({
-                    "type": "i32",
-                    "data": { "value": parse_res_i32.unwrap() }
+            "bool" => {
+                let v = data["value"].as_bool().unwrap_or_else(|| {
+                    self.malformed(expected("a bool", "a bool value", &data["value"]));
+                    false
                 });
-                p += 1;
-                state_num = 101;
-                continue;
+                let dst = self.target(want);
+                self.ops(op::BOOL, &[dst]);
+                self.u8(v as u8);
+                dst
             }
-            let parse_res_i64 = token.parse::<i64>();
-            if parse_res_i64.is_ok() {
-                current_reg = json!({
-                    "type": "i64",
-                    "data": { "value": parse_res_i64.unwrap() }
-                });
-                p += 1;
-                state_num = 101;
-                continue;
+            "identifier" => {
+                let name = self.text(&data["name"], "an identifier name");
+                self.load(name, want)
             }
-            let parse_res_f32 = token.parse::<f32>();
-            if parse_res_f32.is_ok() {
-                current_reg = json!({
-                    "type": "f32",
-                    "data": { "value": parse_res_f32.unwrap() }
-                });
-                p += 1;
-                state_num = 101;
-                continue;
+            "indexer" => {
+                let target = self.expr(&data["target"], None);
+                let index = self.expr(&data["index"], None);
+                let dst = self.target(want);
+                self.ops(op::INDEX, &[dst, target, index]);
+                dst
             }
-            let parse_res_f64 = token.parse::<f64>();
-            if parse_res_f64.is_ok() {
-                current_reg = json!({
-                    "type": "f64",
-                    "data": { "value": parse_res_f64.unwrap() }
-                });
-                p += 1;
-                state_num = 101;
-                continue;
+            "cast" => {
+                let src = self.expr(&data["value"], None);
+                let typ = self.text(&data["targetType"], "the type of a cast");
+                let k = self.string(typ);
+                let dst = self.target(want);
+                self.ops(op::CAST, &[dst, src]);
+                self.u32(k);
+                dst
             }
-            let parse_res_bool = token.parse::<bool>();
-            if parse_res_bool.is_ok() {
-                current_reg = json!({
-                    "type": "bool",
-                    "data": { "value": parse_res_bool.unwrap() }
+            "object" => {
+                let empty = Map::new();
+                let props = data["value"].as_object().unwrap_or_else(|| {
+                    self.malformed(expected(
+                        "an object",
+                        "the props of an object",
+                        &data["value"],
+                    ));
+                    &empty
                 });
-                p += 1;
-                state_num = 101;
-                continue;
+                let first = self.temps(props.len());
+                let mut keys = vec![];
+                for (i, (key, value)) in props.iter().enumerate() {
+                    self.expr(value, Some(first + i as u16));
+                    keys.push(self.string(key));
+                }
+                let dst = self.target(want);
+                self.ops(op::OBJECT, &[dst, first, props.len() as u16]);
+                for key in keys {
+                    self.u32(key);
+                }
+                dst
             }
-            if token.len() >= 2 && token.starts_with('"') && token.ends_with('"') {
-                current_reg = json!({
-                    "type": "string",
-                    "data": { "value": token[1..token.len()-1] }
-                });
-                p += 1;
-                state_num = 101;
-                continue;
+            "array" => {
+                let items = self.items(&data["value"], "the items of an array");
+                let first = self.args(items);
+                let dst = self.target(want);
+                self.ops(op::ARRAY, &[dst, first, items.len() as u16]);
+                dst
             }
-            current_reg = json!({
-                "type": "identifier",
-                "data": { "name": token }
-            });
-            p += 1;
-            state_num = 101;
-            continue;
-        } else if state_num == 101 {
-            if stack.last().unwrap().get("type").unwrap() == "objectExpr"
-                && stack.last().unwrap().contains_key("currentKey")
-            {
-                let key = stack
-                    .last_mut()
-                    .unwrap()
-                    .remove("currentKey")
-                    .unwrap()
-                    .as_str()
-                    .unwrap()
-                    .to_string();
-                stack
-                    .last_mut()
-                    .unwrap()
-                    .get_mut("objectData")
-                    .unwrap()
-                    .as_object_mut()
-                    .unwrap()
-                    .insert(key, current_reg.clone());
-                state_num = 103;
-                continue;
-            } else if stack
-                .last()
-                .unwrap()
-                .get("type")
-                .unwrap()
-                .as_str()
-                .unwrap()
-                .to_string()
-                == "arithmetic"
-            {
-                let last_stage = stack.last().unwrap().clone();
-                stack.pop();
-                current_reg = json!({
-                    "type": "arithmetic",
-                    "data": {
-                        "operation": last_stage.get("operation").unwrap().clone(),
-                        "operand1": last_stage.get("operand1").unwrap().clone(),
-                        "operand2": current_reg
-                    }
-                });
-                continue;
-            } else if stack
-                .last()
-                .unwrap()
-                .get("type")
-                .unwrap()
-                .as_str()
-                .unwrap()
-                .to_string()
-                == "definition"
-            {
-                let last_stage = stack.last().unwrap().clone();
-                stack.pop();
-                stack.last_mut().unwrap().get_mut("body").unwrap().as_array_mut().unwrap().push(json!({
-                        "type": "definition",
-                        "data": {
-                            "leftSide": {
-                                "type": "identifier",
-                                "data": {
-                                    "name": last_stage.get("leftSide").unwrap().as_str().unwrap().to_string()
-                                }
-                            },
-                            "rightSide": current_reg
-                        }
-                    }));
-                state_num = 0;
-                continue;
-            } else if stack
-                .last()
-                .unwrap()
-                .get("type")
-                .unwrap()
-                .as_str()
-                .unwrap()
-                .to_string()
-                == "assignment"
+            "callback" => self.expr(&data["value"]["funcId"], want),
+            "not" => {
+                let src = self.expr(&data["value"], None);
+                let dst = self.target(want);
+                self.ops(op::NOT, &[dst, src]);
+                dst
+            }
+            "await" => {
+                let src = self.expr(&data["value"], None);
+                let dst = self.target(want);
+                self.ops(op::AWAIT, &[dst, src]);
+                dst
+            }
+            "arithmetic" => {
+                let operation = self.text(&data["operation"], "an operator");
+                let Some(op) = BinOp::from_symbol(operation) else {
+                    self.malformed(format!("unknown operator '{}'", operation));
+                    return self.target(want);
+                };
+                let a = self.expr(&data["operand1"], None);
+                let b = self.expr(&data["operand2"], None);
+                let dst = self.target(want);
+                self.ops(op.opcode(), &[dst, a, b]);
+                dst
+            }
+            // `a.b(args)` calls method `b` of instances, a function
+            // held by property `b` of anything else
+            "functionCall"
+                if data["callee"]["type"] == "indexer"
+                    && data["callee"]["data"]["index"]["type"] == "string" =>
             {
-                let last_stage = stack.last().unwrap().clone();
-                stack.pop();
-                stack.last_mut().unwrap().get_mut("body").unwrap().as_array_mut().unwrap().push(json!({
-                        "type": "assignment",
-                        "data": {
-                            "leftSide": {
-                                "type": "identifier",
-                                "data": {
-                                    "name": last_stage.get("leftSide").unwrap().as_str().unwrap().to_string()
-                                }
-                            },
-                            "rightSide": current_reg
-                        }
-                    }));
-                state_num = 0;
+                let callee = &data["callee"]["data"];
+                let method = self.text(&callee["index"]["data"]["value"], "a method name");
+                self.invoke(&callee["target"], method, &data["args"], NO_CLASS, want)
+            }
+            "superCall" => {
+                let parent = self
+                    .class
+                    .and_then(|class| self.classes[class].parent)
+                    .map(|parent| self.classes[parent as usize].name.clone());
+                let from = match parent {
+                    Some(parent) => self.string(&parent),
+                    None => NO_CLASS,
+                };
+                let this = json!({ "type": "identifier", "data": { "name": "this" } });
+                let method = self.text(&data["method"], "a method name");
+                self.invoke(&this, method, &data["args"], from, want)
+            }
+            "new" => {
+                let class = self.text(&data["class"], "a class name");
+                let k = self.string(&self.global(class));
+                let args = self.items(&data["args"], "the arguments of a call");
+                let first = self.args(args);
+                let dst = self.target(want);
+                self.ops(op::NEW, &[dst]);
+                self.u32(k);
+                self.u16(first);
+                self.u16(args.len() as u16);
+                dst
+            }
+            "instanceOf" => {
+                let src = self.expr(&data["value"], None);
+                let class = self.text(&data["class"], "a class name");
+                let k = self.string(&self.global(class));
+                let dst = self.target(want);
+                self.ops(op::IS, &[dst, src]);
+                self.u32(k);
+                dst
+            }
+            "functionCall" => {
+                let callee = self.expr(&data["callee"], None);
+                let args = self.items(&data["args"], "the arguments of a call");
+                let first = self.args(args);
+                let dst = self.target(want);
+                self.ops(op::CALL, &[dst, callee, first, args.len() as u16]);
+                dst
+            }
+            "host_call" => {
+                // askHost(name, [args...]); the callee is looked up as a
+                // global so local variables can not shadow it
+                let callee = self.temp();
+                self.get_global(callee, host_api_callee(node));
+                let first = self.temps(2);
+                let name = self.text(&data["name"], "a host api name").to_string();
+                self.constant(Constant::Str(name), Some(first));
+                let args = self.items(&data["args"], "the arguments of a call");
+                let items = self.args(args);
+                self.ops(op::ARRAY, &[first + 1, items, args.len() as u16]);
+                let dst = self.target(want);
+                self.ops(op::CALL, &[dst, callee, first, 2]);
+                dst
+            }
+            typ => {
+                self.malformed(format!("unknown expression type '{}'", typ));
+                self.target(want)
+            }
+        }
+    }
+    fn constant(&mut self, constant: Constant, want: Option<u16>) -> u16 {
+        let k = self.number(constant);
+        let dst = self.target(want);
+        self.ops(op::CONST, &[dst]);
+        self.u32(k);
+        dst
+    }
+    // the receiver goes in the register before the arguments
+    fn invoke(
+        &mut self,
+        receiver: &Value,
+        method: &str,
+        args: &Value,
+        from: u32,
+        want: Option<u16>,
+    ) -> u16 {
+        let args = self.items(args, "the arguments of a call");
+        let first = self.temps(args.len() + 1);
+        self.expr(receiver, Some(first));
+        for (i, arg) in args.iter().enumerate() {
+            self.expr(arg, Some(first + 1 + i as u16));
+        }
+        let k = self.string(method);
+        let dst = self.target(want);
+        self.ops(op::INVOKE, &[dst, first, args.len() as u16]);
+        self.u32(k);
+        self.u32(from);
+        dst
+    }
+    // evaluates `items` into consecutive registers, returns the first
+    fn args(&mut self, items: &[Value]) -> u16 {
+        let first = self.temps(items.len());
+        for (i, item) in items.iter().enumerate() {
+            self.expr(item, Some(first + i as u16));
+        }
+        first
+    }
+
+    // --- statements ---
+
+    // Compiles a nested block in a scope of its own. `params` are declared
+    // first, their slots are filled by whoever enters the block: the call
+    // for a function body, the error handler for a catch clause.
+    fn block(&mut self, node: &Value, path: &str, params: &[String], is_body: bool) -> Vec<Slot> {
+        let body = &node["body"];
+        let captured = captured_names(body);
+        self.func().scopes.push(vec![]);
+        let saved_reg = self.func().next_reg;
+        let mut slots = vec![];
+        for param in params {
+            slots.push(self.declare(param, captured.contains(param.as_str())));
+            self.mark_defined(param);
+        }
+        let mut fresh = vec![];
+        for name in block_decls(body) {
+            let scope = self.func().scopes.last().unwrap();
+            if scope.iter().any(|declared| declared.name == name) {
                 continue;
+            }
+            if let Slot::Cell(c) = self.declare(&name, captured.contains(name.as_str())) {
+                fresh.push(c);
+            }
+        }
+        // a call starts with fresh cells, a nested block that runs again
+        // (a loop body) needs new ones for closures to capture
+        if !is_body {
+            for c in fresh {
+                self.ops(op::NEW_CELL, &[c]);
+            }
+        }
+        self.statements_of(body, path);
+        self.close_scope();
+        self.func().next_reg = saved_reg;
+        slots
+    }
+
+    fn statements_of(&mut self, body: &Value, path: &str) {
+        let mut steps = Steps::default();
+        for (index, statement) in self.items(body, "a block body").iter().enumerate() {
+            let start = self.code.len();
+            let statement_path = if path.is_empty() {
+                format!("body[{}]", index)
             } else {
-                if token == "}" {
-                    p += 1;
-                    if stack
-                        .last()
-                        .unwrap()
-                        .get("type")
-                        .unwrap()
-                        .as_str()
-                        .unwrap()
-                        .to_string()
-                        == "objPropValue"
-                    {
-                        stack.pop();
-                        let last_stage = stack.last_mut().unwrap();
-                        let ck = last_stage
-                            .get("currentKey")
-                            .unwrap()
-                            .as_str()
-                            .unwrap()
-                            .to_string();
-                        last_stage
-                            .get_mut("objectData")
-                            .unwrap()
-                            .as_object_mut()
-                            .unwrap()
-                            .insert(ck, current_reg.clone());
-                    }
-                    let last_stage = stack.last().unwrap().clone();
-                    stack.pop();
-                    if last_stage
-                        .get("type")
-                        .unwrap()
-                        .as_str()
-                        .unwrap()
-                        .to_string()
-                        == "objectExpr"
-                    {
-                        current_reg = json!({
-                            "type": "object",
-                            "data": {
-                                "value": last_stage.get("objectData").unwrap().clone(),
-                            }
-                        });
-                    }
-                    continue;
-                } else if token == ")" {
-                    if stack
-                        .last()
-                        .unwrap()
-                        .get("type")
-                        .unwrap()
-                        .as_str()
-                        .unwrap()
-                        .to_string()
-                        == "paren"
-                    {
-                        p += 1;
-                        stack.pop();
-                        continue;
-                    } else if stack
-                        .last()
-                        .unwrap()
-                        .get("type")
-                        .unwrap()
-                        .as_str()
-                        .unwrap()
-                        .to_string()
-                        == "functionCall"
-                    {
-                        p += 1;
-                        let mut last_sage = stack.pop().unwrap();
-                        last_sage
-                            .get_mut("args")
-                            .unwrap()
-                            .as_array_mut()
-                            .unwrap()
-                            .push(current_reg.clone());
-                        current_reg = json!({
-                            "type": "functionCall",
-                            "data": {
-                                "callee": last_sage.get("callee").unwrap().clone(),
-                                "args": last_sage.get("args").unwrap().clone(),
-                            }
-                        });
-                        continue;
-                    }
-                } else if vec!["+", "-", "/", "*", "^", "%"]
-                    .iter()
-                    .any(|op| op.to_string() == token)
-                {
-                    stack.push(HashMap::new());
-                    stack
-                        .last_mut()
-                        .unwrap()
-                        .insert("type".to_string(), json!("arithmetic"));
-                    stack
-                        .last_mut()
-                        .unwrap()
-                        .insert("operand1".to_string(), current_reg.clone());
-                    stack
-                        .last_mut()
-                        .unwrap()
-                        .insert("operation".to_string(), json!(token.clone()));
-                    p += 1;
-                    state_num = 100;
-                    continue;
-                } else if token == "," {
-                    if stack
-                        .last()
-                        .unwrap()
-                        .get("type")
-                        .unwrap()
-                        .as_str()
-                        .unwrap()
-                        .to_string()
-                        == "functionCall"
-                    {
-                        p += 1;
-                        stack
-                            .last_mut()
-                            .unwrap()
-                            .get_mut("args")
-                            .unwrap()
-                            .as_array_mut()
-                            .unwrap()
-                            .push(current_reg.clone());
-                        state_num = 100;
-                        continue;
-                    }
+                format!("{}.body[{}]", path, index)
+            };
+            steps.starts.entry(index as i64 + 1).or_insert(start);
+            let saved_reg = self.func().next_reg;
+            let span = Span::from_json(&statement["span"]).unwrap_or_default();
+            let saved_span = std::mem::replace(&mut self.span, span);
+            let saved_path = std::mem::replace(&mut self.path, statement_path.clone());
+            self.statement(statement, &statement_path, &mut steps);
+            self.span = saved_span;
+            self.path = saved_path;
+            self.func().next_reg = saved_reg;
+            if self.code.len() > start {
+                self.statements.push(start);
+            }
+            if let Some(map) = self.map.as_deref_mut() {
+                map.entries.push(SourceMapEntry {
+                    start,
+                    end: self.code.len(),
+                    path: statement_path,
+                    span: Span::from_json(&statement["span"]),
+                    module: self.module.clone(),
+                });
+            }
+        }
+        let end = self.code.len();
+        for (at, step) in steps.patches {
+            let address = steps.starts.get(&step).copied().unwrap_or(end);
+            self.patch(at, address);
+        }
+    }
+
+    fn statement(&mut self, statement: &Value, path: &str, steps: &mut Steps) {
+        let data = &statement["data"];
+        match self.text(&statement["type"], "the type of a statement") {
+            "jumpOperation" => {
+                let at = self.jump();
+                let step = self.integer(&data["stepNumber"], "a step number");
+                steps.patches.push((at, step));
+            }
+            "conditionalBranch" => {
+                let cond = self.expr(&data["condition"], None);
+                let at = self.jump_if_not(cond);
+                let step = self.integer(&data["falseBranch"], "a step number");
+                steps.patches.push((at, step));
+                let at = self.jump();
+                let step = self.integer(&data["trueBranch"], "a step number");
+                steps.patches.push((at, step));
+            }
+            "definition" if data["leftSide"]["type"].as_str() == Some("identifier") => {
+                let name = self.text(&data["leftSide"]["data"]["name"], "a variable name");
+                self.store(name, &data["rightSide"], true);
+                self.mark_defined(name);
+            }
+            "assignment" => match self.text(&data["leftSide"]["type"], "an assignment target") {
+                "identifier" => {
+                    let name = self.text(&data["leftSide"]["data"]["name"], "a variable name");
+                    self.store(name, &data["rightSide"], false);
+                }
+                "indexer" => {
+                    let name = self.text(
+                        &data["leftSide"]["data"]["target"]["data"]["name"],
+                        "a variable name",
+                    );
+                    let target = self.load(name, None);
+                    let index = self.expr(&data["leftSide"]["data"]["index"], None);
+                    let value = self.expr(&data["rightSide"], None);
+                    let k = self.string(name);
+                    self.ops(op::SET_INDEX, &[target, index, value]);
+                    self.u32(k);
                 }
+                _ => {}
+            },
+            "functionDefinition" => {
+                let name = self.text(&data["name"], "a function name");
+                let dst = self.function(statement, path);
+                match self.resolve(name) {
+                    Var::Local(Slot::Register(r)) => self.ops(op::MOVE, &[r, dst]),
+                    Var::Local(Slot::Cell(c)) => self.ops(op::SET_CELL, &[c, dst]),
+                    Var::Upvalue(u) => self.ops(op::SET_UPVALUE, &[u, dst]),
+                    Var::Global => self.store_global(&self.global(name), dst, true),
+                }
+                self.mark_defined(name);
             }
-            if !stack.last().unwrap().get("body").is_none() {
-                if current_reg
-                    .get("type")
-                    .unwrap()
-                    .as_str()
-                    .unwrap()
-                    .to_string()
-                    == "functionCall"
-                {
-                    stack
-                        .last_mut()
-                        .unwrap()
-                        .get_mut("body")
-                        .unwrap()
-                        .as_array_mut()
-                        .unwrap()
-                        .push(current_reg.clone());
-                    current_reg = json!({});
+            "returnOperation" => {
+                if data["value"].is_null() {
+                    self.emit(op::RETURN_NULL);
+                } else {
+                    let value = self.expr(&data["value"], None);
+                    self.ops(op::RETURN, &[value]);
                 }
-                state_num = 0;
-                continue;
             }
-        } else if state_num == 102 {
-            stack.last_mut().unwrap().insert(
-                "currentKey".to_string(),
-                json!(token[1..token.len() - 1].to_string()),
-            );
-            stack.push(HashMap::new());
-            stack
-                .last_mut()
-                .unwrap()
-                .insert("type".to_string(), json!("objPropValue".to_string()));
-            p += 1;
-            state_num = 104;
-            continue;
-        } else if state_num == 103 {
-            if token == "," {
-                state_num = 102;
-                p += 1;
-                continue;
-            } else if token == "}" {
-                state_num = 101;
-                continue;
+            "throwStmt" => {
+                let value = self.expr(&data["value"], None);
+                self.ops(op::THROW, &[value]);
+            }
+            "ifStmt" => {
+                let mut ends = vec![];
+                self.if_chain(statement, path, &mut ends);
+                for at in ends {
+                    self.patch_here(at);
+                }
+            }
+            "loopStmt" | "whileStmt" => {
+                let top = self.code.len();
+                let cond = self.expr(&data["condition"], None);
+                let exit = self.jump_if_not(cond);
+                let done = self.loop_body(data, path);
+                self.next_round(done, top, top);
+                self.patch_here(exit);
+            }
+            "forStmt" => self.for_statement(data, path),
+            "forInStmt" | "forOfStmt" => {
+                self.for_each(data, path, statement["type"] == "forInStmt")
+            }
+            "breakStmt" | "continueStmt" => {
+                let func = self.funcs.last().unwrap();
+                let target = match data["label"].as_str() {
+                    Some(label) => func
+                        .loops
+                        .iter()
+                        .rposition(|state| state.label.as_deref() == Some(label)),
+                    None => func.loops.len().checked_sub(1),
+                };
+                // only the parser checks that there is such a loop
+                let Some(target) = target else {
+                    return;
+                };
+                let leave = func.tries - func.loops[target].tries;
+                let at = if leave == 0 {
+                    self.jump()
+                } else {
+                    self.ops(op::LEAVE, &[leave]);
+                    self.hole()
+                };
+                let state = &mut self.func().loops[target];
+                if statement["type"] == "breakStmt" {
+                    state.breaks.push(at);
+                } else {
+                    state.continues.push(at);
+                }
+            }
+            "switchStmt" => {
+                // the first case equal to the value runs, then the statement ends
+                let value = self.temp();
+                self.expr(&data["value"], Some(value));
+                let mut ends = vec![];
+                let cases = self.items(&data["cases"], "the cases of a switch");
+                for (i, case) in cases.iter().enumerate() {
+                    let case_value = self.expr(&case["value"], None);
+                    let matched = self.temp();
+                    self.ops(BinOp::Eq.opcode(), &[matched, value, case_value]);
+                    let next = self.jump_if_not(matched);
+                    let case_path = format!("{}.data.cases[{}].body", path, i);
+                    self.block(&case["body"], &case_path, &[], false);
+                    ends.push(self.jump());
+                    self.patch_here(next);
+                }
+                for at in ends {
+                    self.patch_here(at);
+                }
             }
-        } else if state_num == 104 {
-            if token == ":" {
-                p += 1;
-                state_num = 100;
+            "tryStmt" => self.try_statement(data, path),
+            "classDefinition" => self.class_definition(data, path),
+            "functionCall" | "host_call" | "await" | "new" | "superCall" => {
+                self.expr(statement, None);
+            }
+            _ => {
+                // skip
             }
         }
     }
-    result
-}
 
-#[derive(Clone, Debug)]
-struct Path {
-    id: i32,
-    prefix: String,
-    nexts: Vec<Rc<RefCell<Path>>>,
-}
+    fn if_chain(&mut self, node: &Value, path: &str, ends: &mut Vec<usize>) {
+        let data = &node["data"];
+        let cond = self.expr(&data["condition"], None);
+        let next = self.jump_if_not(cond);
+        self.block(data, &format!("{}.data", path), &[], false);
+        let elseif = data.get("elseifStmt");
+        let else_stmt = data.get("elseStmt");
+        if elseif.is_some() || else_stmt.is_some() {
+            ends.push(self.jump());
+        }
+        self.patch_here(next);
+        if let Some(elseif) = elseif {
+            self.if_chain(elseif, &format!("{}.data.elseifStmt", path), ends);
+        } else if let Some(else_stmt) = else_stmt {
+            let else_path = format!("{}.data.elseStmt.data", path);
+            self.block(&else_stmt["data"], &else_path, &[], false);
+        }
+    }
 
-pub fn compile_code(p: String) -> Vec<u8> {
-    let program = p;
+    // Compiles the body of a loop statement, `break` and `continue` in it
+    // refer to the loop. Returns the loop's jumps.
+    fn loop_body(&mut self, data: &Value, path: &str) -> LoopState {
+        let func = self.func();
+        func.loops.push(LoopState {
+            label: data["label"].as_str().map(str::to_string),
+            tries: func.tries,
+            breaks: vec![],
+            continues: vec![],
+        });
+        self.block(data, &format!("{}.data", path), &[], false);
+        self.func().loops.pop().unwrap()
+    }
+    // Ends a loop body with the jump back to `top`. `continue` goes to
+    // `next`, `break` to whatever follows.
+    fn next_round(&mut self, done: LoopState, next: usize, top: usize) {
+        for at in done.continues {
+            self.patch(at, next);
+        }
+        let at = self.jump();
+        self.patch(at, top);
+        for at in done.breaks {
+            self.patch_here(at);
+        }
+    }
 
-    let temp_prog = program;
-    let mut tokens: Vec<String> = vec![];
-    let mut temp_token = "".to_string();
-    let mut inside_string = false;
-    for c in temp_prog.chars() {
-        if c == '"' {
-            if inside_string {
-                inside_string = false;
-                temp_token.push(c);
-                tokens.push(temp_token);
-                temp_token = "".to_string();
-            } else {
-                inside_string = true;
-                temp_token.push(c);
+    // `for (init; condition; update)`, a variable the first statement
+    // defines belongs to the loop
+    fn for_statement(&mut self, data: &Value, path: &str) {
+        let captured = captured_names(&data["body"]);
+        self.func().scopes.push(vec![]);
+        let init = &data["init"];
+        if init["type"] == "definition" {
+            let name = self.text(&init["data"]["leftSide"]["data"]["name"], "a variable name");
+            if let Slot::Cell(c) = self.declare(name, captured.contains(name)) {
+                self.ops(op::NEW_CELL, &[c]);
             }
-            continue;
         }
-        if inside_string {
-            temp_token.push(c);
-            continue;
+        let mut steps = Steps::default();
+        if !init.is_null() {
+            self.statement(init, &format!("{}.data.init", path), &mut steps);
         }
-        let c_stred: &str = &c.to_string();
-        if c == ' ' || c == '\n' || c == '\t' {
-            if temp_token.len() > 0 {
-                tokens.push(temp_token);
-                temp_token = "".to_string();
-            }
-            continue;
-        } else if vec![
-            "=", "+", "-", "*", "/", "^", "%", "==", ">", "<", ">=", "<=", "!=", ".", "(", ")",
-            "[", "]", "{", "}", ":", ",",
-        ]
-        .contains(&c_stred)
-        {
-            if temp_token.len() > 0 {
-                tokens.push(temp_token);
-                temp_token = "".to_string();
-            }
-            tokens.push(c.to_string());
-            continue;
+        let top = self.code.len();
+        let exit = if data["condition"].is_null() {
+            None
+        } else {
+            let cond = self.expr(&data["condition"], None);
+            Some(self.jump_if_not(cond))
+        };
+        let done = self.loop_body(data, path);
+        let next = self.code.len();
+        if !data["update"].is_null() {
+            let update_path = format!("{}.data.update", path);
+            self.statement(&data["update"], &update_path, &mut steps);
         }
-        temp_token.push(c);
+        self.next_round(done, next, top);
+        if let Some(exit) = exit {
+            self.patch_here(exit);
+        }
+        self.close_scope();
     }
-    if temp_token.len() > 0 {
-        tokens.push(temp_token);
+
+    // `for (k in object)` over a copy of the object's keys taken up front,
+    // `for (v of array)` over the array as it is when each round starts.
+    // The position lives in a register, so a pause in the body keeps it.
+    fn for_each(&mut self, data: &Value, path: &str, keys: bool) {
+        let name = self.text(&data["name"], "a loop variable name");
+        let captured = captured_names(&data["body"]);
+        let items = self.temp();
+        self.expr(&data["value"], Some(items));
+        if keys {
+            self.ops(op::KEYS, &[items, items]);
+        }
+        let index = self.temp();
+        let zero = self.number(Constant::I16(0));
+        self.ops(op::CONST, &[index]);
+        self.u32(zero);
+        self.func().scopes.push(vec![]);
+        let slot = self.declare(name, captured.contains(name));
+        let item = match slot {
+            Slot::Register(r) => r,
+            Slot::Cell(_) => self.temp(),
+        };
+        let top = self.code.len();
+        self.ops(op::NEXT, &[item, items, index]);
+        let exit = self.hole();
+        // every round gets a fresh variable for closures to capture
+        if let Slot::Cell(c) = slot {
+            self.ops(op::NEW_CELL, &[c]);
+            self.ops(op::SET_CELL, &[c, item]);
+        }
+        self.mark_defined(name);
+        let done = self.loop_body(data, path);
+        self.next_round(done, top, top);
+        self.patch_here(exit);
+        self.close_scope();
     }
-    log(&format!("{:?}", tokens));
 
-    let mut stack: Vec<(String, Path, i32, usize, i32)> = vec![];
+    // try catch finally: the protected body, each clause and the finally
+    // body end in a `tryend` that moves on to the next stage
+    fn try_statement(&mut self, data: &Value, path: &str) {
+        self.func().tries += 1;
+        self.emit(op::TRY);
+        let catch_at = self.hole();
+        let slot_at = self.code.len();
+        self.u8(0);
+        self.u16(0);
+        let finally_at = self.hole();
+        let after_at = self.hole();
+        self.block(data, &format!("{}.data", path), &[], false);
+        self.emit(op::TRY_END);
+        let mut catch_address = NO_ADDRESS as usize;
+        if data["catch"].is_object() {
+            catch_address = self.code.len();
+            let param = data["catch"]["param"].as_str().unwrap_or("");
+            let params = if param.is_empty() {
+                vec![]
+            } else {
+                vec![param.to_string()]
+            };
+            let catch_path = format!("{}.data.catch", path);
+            let slots = self.block(&data["catch"], &catch_path, &params, false);
+            let (kind, index) = match slots.first() {
+                None => (0u8, 0u16),
+                Some(Slot::Register(r)) => (1, *r),
+                Some(Slot::Cell(c)) => (2, *c),
+            };
+            self.code[slot_at] = kind;
+            self.code[slot_at + 1..slot_at + 3].copy_from_slice(&index.to_be_bytes());
+            self.emit(op::TRY_END);
+        }
+        let mut finally_address = NO_ADDRESS as usize;
+        if data["finally"].is_object() {
+            finally_address = self.code.len();
+            let finally_path = format!("{}.data.finally", path);
+            self.block(&data["finally"], &finally_path, &[], false);
+            self.emit(op::TRY_END);
+        }
+        self.patch(catch_at, catch_address);
+        self.patch(finally_at, finally_address);
+        self.patch_here(after_at);
+        self.func().tries -= 1;
+    }
 
-    let start_path = Rc::new(RefCell::new(Path {
-        id: 1,
-        prefix: "start".to_string(),
-        nexts: vec![],
-    }));
-    let end_path = Rc::new(RefCell::new(Path {
-        id: 2,
-        prefix: "end".to_string(),
-        nexts: vec![],
-    }));
-    {
-        start_path.borrow_mut().nexts.push(end_path.clone());
-    }
-    let expr_path = Rc::new(RefCell::new(Path {
-        id: 3,
-        prefix: "".to_string(),
-        nexts: vec![],
-    }));
-    {
-        start_path.borrow_mut().nexts.push(expr_path.clone());
-    }
-    let expr_2_path = Rc::new(RefCell::new(Path {
-        id: 4,
-        prefix: "string".to_string(),
-        nexts: vec![],
-    }));
-    {
-        expr_path.borrow_mut().nexts.push(expr_2_path.clone());
-    }
-    {
-        expr_path.borrow_mut().nexts.push(end_path.clone());
-    }
-    let expr_3_path = Rc::new(RefCell::new(Path {
-        id: 5,
-        prefix: "+".to_string(),
-        nexts: vec![],
-    }));
-    {
-        expr_2_path.borrow_mut().nexts.push(expr_3_path.clone());
-    }
-    let expr_4_path = Rc::new(RefCell::new(Path {
-        id: 6,
-        prefix: "string".to_string(),
-        nexts: vec![],
-    }));
-    {
-        expr_3_path.borrow_mut().nexts.push(expr_4_path.clone());
-    }
-    {
-        expr_4_path.borrow_mut().nexts.push(expr_3_path.clone());
-    }
-    {
-        expr_4_path.borrow_mut().nexts.push(end_path.clone());
+    // Compiles a function body in place, behind a jump over it, and emits
+    // the closure creating its value. Returns the register holding it.
+    fn function(&mut self, statement: &Value, path: &str) -> u16 {
+        let name = self.text(&statement["data"]["name"], "a function name");
+        // top-level functions are named after their global, which reloads
+        // rebind them by
+        let top_level = self.at_global_level();
+        let name = if top_level {
+            self.global(name)
+        } else {
+            name.to_string()
+        };
+        let params = function_params(statement);
+        let proto = self.function_body(statement, path, &name, params, top_level);
+        let dst = self.temp();
+        self.ops(op::CLOSURE, &[dst, proto as u16]);
+        dst
     }
 
-    let function_call_path = Rc::new(RefCell::new(Path {
-        id: 7,
-        prefix: "id".to_string(),
-        nexts: vec![],
-    }));
-    {
-        start_path
-            .borrow_mut()
-            .nexts
-            .push(function_call_path.clone());
-    }
-    let function_call_2_path = Rc::new(RefCell::new(Path {
-        id: 8,
-        prefix: "(".to_string(),
-        nexts: vec![],
-    }));
-    {
-        function_call_path
-            .borrow_mut()
-            .nexts
-            .push(function_call_2_path.clone());
-    }
-    {
-        function_call_2_path
-            .borrow_mut()
-            .nexts
-            .push(expr_path.clone());
-    }
-    let function_call_4_path = Rc::new(RefCell::new(Path {
-        id: 10,
-        prefix: ")".to_string(),
-        nexts: vec![],
-    }));
-    {
-        expr_4_path
-            .borrow_mut()
-            .nexts
-            .push(function_call_4_path.clone());
-        expr_2_path
-            .borrow_mut()
-            .nexts
-            .push(function_call_4_path.clone());
-        function_call_4_path
-            .borrow_mut()
-            .nexts
-            .push(end_path.clone());
+    // Compiles the body of a function definition behind a jump over it,
+    // returns its function table index.
+    fn function_body(
+        &mut self,
+        statement: &Value,
+        path: &str,
+        name: &str,
+        params: Vec<String>,
+        top_level: bool,
+    ) -> usize {
+        let data = &statement["data"];
+        let skip = self.jump();
+        let start = self.code.len();
+        let proto = self.functions.len();
+        self.functions.push(entry(name, params.clone(), top_level));
+        self.functions[proto].start = start;
+        self.funcs.push(FuncState::new(proto));
+        let slots = self.block(data, &format!("{}.data", path), &params, true);
+        self.emit(op::RETURN_NULL);
+        let mut state = self.funcs.pop().unwrap();
+        // parameters are live over the whole body
+        for local in state.locals.iter_mut().take(params.len()) {
+            local.start = start;
+        }
+        self.functions[proto].param_slots = slots;
+        self.finish_function(state, start);
+        self.patch_here(skip);
+        proto
     }
 
-    let genesis_path = Rc::new(RefCell::new(Path {
-        id: 11,
-        prefix: "".to_string(),
-        nexts: vec![start_path.clone()],
-    }));
+    // Compiles the methods of a class and then the `class` instruction
+    // making its blueprint from the field defaults. Methods are named
+    // `Class.method` and get the instance as their first parameter, `this`.
+    fn class_definition(&mut self, data: &Value, path: &str) {
+        let name = self.text(&data["name"], "a class name");
+        let name = self.global(name);
+        let Some(index) = self.classes.iter().position(|class| class.name == name) else {
+            // only classes at the top level are declared
+            return;
+        };
+        self.class = Some(index);
+        let methods = self.items(&data["methods"], "the methods of a class");
+        for (i, method) in methods.iter().enumerate() {
+            let method_name = self.text(&method["data"]["name"], "a method name");
+            let mut params = vec!["this".to_string()];
+            params.extend(function_params(method));
+            let method_path = format!("{}.data.methods[{}]", path, i);
+            let full_name = format!("{}.{}", name, method_name);
+            let proto = self.function_body(method, &method_path, &full_name, params, false);
+            self.classes[index]
+                .methods
+                .push((method_name.to_string(), proto as u16));
+        }
+        self.class = None;
+        let fields = self.items(&data["fields"], "the fields of a class");
+        let first = self.temps(fields.len());
+        for (i, field) in fields.iter().enumerate() {
+            let dst = first + i as u16;
+            if field["value"].is_null() {
+                self.ops(op::NULL, &[dst]);
+            } else {
+                self.expr(&field["value"], Some(dst));
+            }
+        }
+        self.ops(op::CLASS, &[index as u16, first]);
+    }
 
-    stack.push(("".to_string(), genesis_path.borrow_mut().clone(), 0, 0, 0));
+    fn finish_function(&mut self, state: FuncState, start: usize) {
+        let entry = &mut self.functions[state.proto];
+        entry.start = start;
+        entry.end = self.code.len();
+        entry.registers = state.max_reg;
+        entry.cells = state.cells;
+        entry.upvalues = state.upvalues;
+        entry.locals = state
+            .locals
+            .into_iter()
+            .filter(|local| local.start < local.end)
+            .collect();
+    }
+}
 
-    let mut keyword_map: HashMap<String, bool> = HashMap::new();
-    keyword_map.insert("start".to_string(), true);
-    keyword_map.insert("end".to_string(), true);
-    keyword_map.insert("(".to_string(), true);
-    keyword_map.insert(")".to_string(), true);
-    keyword_map.insert("+".to_string(), true);
+fn entry(name: &str, params: Vec<String>, top_level: bool) -> FunctionEntry {
+    FunctionEntry {
+        name: name.to_string(),
+        params,
+        start: 0,
+        end: 0,
+        registers: 0,
+        cells: 0,
+        param_slots: vec![],
+        upvalues: vec![],
+        locals: vec![],
+        top_level,
+    }
+}
 
-    loop {
-        let mut found = false;
-        let paths = stack.last().unwrap().1.nexts.clone();
-        let checkpoint = stack.last().unwrap().2;
-        let mut counter = 0;
-        let curr_token = tokens[stack.last().unwrap().4 as usize].clone();
-        for pa in paths.iter() {
-            if counter < checkpoint {
-                counter += 1;
-                continue;
+// async host calls hand back a promise instead of pausing the run
+fn host_api_callee(call: &Value) -> &'static str {
+    if call["data"]["async"].as_bool() == Some(true) {
+        "askHostAsync"
+    } else {
+        "askHost"
+    }
+}
+
+// the message for an AST value of the wrong kind
+fn expected(kind: &str, what: &str, value: &Value) -> String {
+    let found = match value {
+        Value::Null => "nothing",
+        Value::Bool(_) => "a bool",
+        Value::Number(_) => "a number",
+        Value::String(_) => "a string",
+        Value::Array(_) => "an array",
+        Value::Object(_) => "an object",
+    };
+    format!("expected {} for {}, found {}", kind, what, found)
+}
+
+// --- capture analysis ---
+//
+// A local lives in a cell when a function defined inside its block
+// refers to its name. Shadowing is ignored, which at worst gives a
+// variable a cell it does not need.
+
+// names a block declares: its definitions and function definitions
+fn block_decls(body: &Value) -> Vec<String> {
+    let mut names: Vec<String> = vec![];
+    for statement in body.as_array().into_iter().flatten() {
+        let name = match statement["type"].as_str() {
+            Some("definition") => statement["data"]["leftSide"]["data"]["name"].as_str(),
+            Some("functionDefinition") => statement["data"]["name"].as_str(),
+            _ => None,
+        };
+        if let Some(name) = name {
+            if !names.iter().any(|known| known == name) {
+                names.push(name.to_string());
             }
-            let path = pa.borrow().clone();
-            if path.prefix == "" {
-                let mut prev_exists = false;
-                for hist in stack.clone().into_iter().rev() {
-                    if hist.1.id == path.id && hist.3 == stack.len() {
-                        prev_exists = true;
-                        break;
-                    }
-                }
-                if prev_exists {
-                    counter += 1;
+        }
+    }
+    names
+}
+
+// the blocks nested in a statement, with the names each declares on entry
+fn sub_blocks(statement: &Value) -> Vec<(&Value, Vec<String>)> {
+    let data = &statement["data"];
+    let mut blocks = vec![];
+    match statement["type"].as_str() {
+        Some("ifStmt") => {
+            let mut node = statement;
+            loop {
+                blocks.push((&node["data"]["body"], vec![]));
+                if let Some(elseif) = node["data"].get("elseifStmt") {
+                    node = elseif;
                     continue;
                 }
-                println!("trying non-prefix {}", curr_token);
-                counter += 1;
-                stack.last_mut().unwrap().2 = counter;
-                found = true;
-                stack.push((
-                    curr_token,
-                    path.clone(),
-                    0,
-                    stack.len(),
-                    stack.last().unwrap().4,
-                ));
-                break;
-            } else if !keyword_map.contains_key(&curr_token) {
-                if curr_token.starts_with("\"")
-                    && curr_token.ends_with("\"")
-                    && path.prefix == "string"
-                {
-                    println!("matched string {}", curr_token);
-                    counter += 1;
-                    stack.last_mut().unwrap().2 = counter;
-                    found = true;
-                    stack.push((
-                        curr_token,
-                        path.clone(),
-                        0,
-                        stack.len(),
-                        stack.last().unwrap().4 + 1,
-                    ));
-                    break;
-                } else if path.prefix == "id" {
-                    println!("matched identifier {}", curr_token);
-                    counter += 1;
-                    stack.last_mut().unwrap().2 = counter;
-                    found = true;
-                    stack.push((
-                        curr_token,
-                        path.clone(),
-                        0,
-                        stack.len(),
-                        stack.last().unwrap().4 + 1,
-                    ));
-                    break;
+                if let Some(else_stmt) = node["data"].get("elseStmt") {
+                    blocks.push((&else_stmt["data"]["body"], vec![]));
                 }
-            } else if path.prefix == curr_token {
-                println!("matched {}", curr_token);
-                counter += 1;
-                stack.last_mut().unwrap().2 = counter;
-                found = true;
-                stack.push((
-                    curr_token,
-                    path.clone(),
-                    0,
-                    stack.len(),
-                    stack.last().unwrap().4 + 1,
-                ));
                 break;
             }
-            counter += 1;
         }
-        if stack.last().unwrap().0 == "end" {
-            println!("Finished !");
-            break;
+        Some("loopStmt") | Some("whileStmt") => blocks.push((&data["body"], vec![])),
+        Some("forStmt") => {
+            let init = &data["init"];
+            let names = match init["type"].as_str() {
+                Some("definition") => init["data"]["leftSide"]["data"]["name"]
+                    .as_str()
+                    .map(|name| vec![name.to_string()])
+                    .unwrap_or_default(),
+                _ => vec![],
+            };
+            blocks.push((&data["body"], names));
+        }
+        Some("forInStmt") | Some("forOfStmt") => {
+            let names = data["name"].as_str().map(str::to_string);
+            blocks.push((&data["body"], names.into_iter().collect()));
         }
-        if !found {
-            if stack.len() > 0 {
-                stack.pop();
+        Some("switchStmt") => {
+            for case in data["cases"].as_array().into_iter().flatten() {
+                blocks.push((&case["body"]["body"], vec![]));
             }
         }
-        if stack.len() == 0 {
-            break;
+        Some("tryStmt") => {
+            blocks.push((&data["body"], vec![]));
+            if data["catch"].is_object() {
+                let params = data["catch"]["param"]
+                    .as_str()
+                    .filter(|param| !param.is_empty())
+                    .map(|param| vec![param.to_string()])
+                    .unwrap_or_default();
+                blocks.push((&data["catch"]["body"], params));
+            }
+            if data["finally"].is_object() {
+                blocks.push((&data["finally"]["body"], vec![]));
+            }
         }
+        _ => {}
     }
+    blocks
+}
+
+// the expressions a statement evaluates itself, outside nested blocks
+fn statement_exprs(statement: &Value) -> Vec<&Value> {
+    let data = &statement["data"];
+    match statement["type"].as_str() {
+        Some("definition") | Some("assignment") => {
+            vec![&data["leftSide"], &data["rightSide"]]
+        }
+        Some("returnOperation") | Some("throwStmt") => vec![&data["value"]],
+        Some("loopStmt") | Some("whileStmt") | Some("conditionalBranch") => {
+            vec![&data["condition"]]
+        }
+        // the loop variable is read here as well, which at worst gives an
+        // outer variable of the same name a cell
+        Some("forStmt") => {
+            let mut exprs = vec![&data["condition"]];
+            exprs.extend(statement_exprs(&data["init"]));
+            exprs.extend(statement_exprs(&data["update"]));
+            exprs
+        }
+        Some("forInStmt") | Some("forOfStmt") => vec![&data["value"]],
+        Some("ifStmt") => {
+            let mut exprs = vec![];
+            let mut node = statement;
+            loop {
+                exprs.push(&node["data"]["condition"]);
+                match node["data"].get("elseifStmt") {
+                    Some(elseif) => node = elseif,
+                    None => break,
+                }
+            }
+            exprs
+        }
+        Some("switchStmt") => {
+            let mut exprs = vec![&data["value"]];
+            for case in data["cases"].as_array().into_iter().flatten() {
+                exprs.push(&case["value"]);
+            }
+            exprs
+        }
+        Some("functionCall") | Some("host_call") | Some("await") | Some("new")
+        | Some("superCall") => vec![statement],
+        Some("classDefinition") => data["fields"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|field| &field["value"])
+            .collect(),
+        _ => vec![],
+    }
+}
+
+// identifiers an expression reads
+fn expr_names<'a>(expr: &'a Value, out: &mut Vec<&'a str>) {
+    let data = &expr["data"];
+    match expr["type"].as_str() {
+        Some("identifier") => {
+            if let Some(name) = data["name"].as_str() {
+                out.push(name);
+            }
+        }
+        Some("indexer") => {
+            expr_names(&data["target"], out);
+            expr_names(&data["index"], out);
+        }
+        Some("cast") | Some("not") | Some("await") | Some("instanceOf") => {
+            expr_names(&data["value"], out)
+        }
+        Some("callback") => expr_names(&data["value"]["funcId"], out),
+        Some("arithmetic") => {
+            expr_names(&data["operand1"], out);
+            expr_names(&data["operand2"], out);
+        }
+        Some("functionCall") => {
+            expr_names(&data["callee"], out);
+            for arg in data["args"].as_array().into_iter().flatten() {
+                expr_names(arg, out);
+            }
+        }
+        Some("host_call") | Some("new") | Some("superCall") => {
+            // a super call reads the instance
+            if expr["type"] == "superCall" {
+                out.push("this");
+            }
+            for arg in data["args"].as_array().into_iter().flatten() {
+                expr_names(arg, out);
+            }
+        }
+        Some("array") => {
+            for item in data["value"].as_array().into_iter().flatten() {
+                expr_names(item, out);
+            }
+        }
+        Some("object") => {
+            for value in data["value"].as_object().into_iter().flatten() {
+                expr_names(value.1, out);
+            }
+        }
+        _ => {}
+    }
+}
+
+// the classes a program declares at its top level
+fn class_definitions(program: &Value) -> impl Iterator<Item = &Value> {
+    program["body"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|statement| statement["type"] == "classDefinition")
+        .map(|statement| &statement["data"])
+}
+
+fn function_params(statement: &Value) -> Vec<String> {
+    statement["data"]["params"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|param| param.as_str().map(str::to_string))
+        .collect()
+}
+
+// names a function refers to that it does not declare itself
+fn free_names(statement: &Value) -> HashSet<String> {
+    let mut free = HashSet::new();
+    let mut scopes = vec![function_params(statement)];
+    walk_block(&statement["data"]["body"], &mut scopes, &mut free);
+    free
+}
+
+fn walk_block(body: &Value, scopes: &mut Vec<Vec<String>>, free: &mut HashSet<String>) {
+    let mut declared = block_decls(body);
+    if scopes.len() == 1 {
+        // a function body shares its scope with the parameters
+        declared.extend(scopes.pop().unwrap());
+    }
+    scopes.push(declared);
+    let is_declared = |scopes: &Vec<Vec<String>>, name: &str| {
+        scopes
+            .iter()
+            .any(|scope| scope.iter().any(|known| known == name))
+    };
+    for statement in body.as_array().into_iter().flatten() {
+        if statement["type"].as_str() == Some("functionDefinition") {
+            for name in free_names(statement) {
+                if !is_declared(scopes, &name) {
+                    free.insert(name);
+                }
+            }
+            continue;
+        }
+        let mut names = vec![];
+        for expr in statement_exprs(statement) {
+            expr_names(expr, &mut names);
+        }
+        for name in names {
+            if !is_declared(scopes, name) {
+                free.insert(name.to_string());
+            }
+        }
+        for (block, params) in sub_blocks(statement) {
+            scopes.push(params);
+            walk_block(block, scopes, free);
+            scopes.pop();
+        }
+    }
+    scopes.pop();
+}
+
+// names the functions defined anywhere inside a block refer to
+fn captured_names(body: &Value) -> HashSet<String> {
+    let mut captured = HashSet::new();
+    for statement in body.as_array().into_iter().flatten() {
+        if statement["type"].as_str() == Some("functionDefinition") {
+            captured.extend(free_names(statement));
+            continue;
+        }
+        for (block, _) in sub_blocks(statement) {
+            captured.extend(captured_names(block));
+        }
+    }
+    captured
+}
+
+/// Compiles an AST into a program image (see `bytecode::Program`).
+/// `start_point` leaves that many `nop`s in front of the code. Fails with
+/// every node of the wrong shape, each message starting with the path of
+/// its statement (`body[2].data.body[0]`).
+pub fn compile_ast(
+    program: serde_json::Value,
+    start_point: usize,
+) -> Result<Vec<u8>, Vec<SyntaxError>> {
+    Ok(Compiler::new(start_point, None).compile(&program)?.encode())
+}
+
+/// Like `compile_ast`, and also returns the table mapping every compiled
+/// statement back to its AST node and, for parsed code, its source span.
+pub fn compile_ast_with_source_map(
+    program: serde_json::Value,
+    start_point: usize,
+) -> Result<(Vec<u8>, SourceMap), Vec<SyntaxError>> {
+    let mut map = SourceMap::new();
+    let image = Compiler::new(start_point, Some(&mut map))
+        .compile(&program)?
+        .encode();
+    Ok((image, map))
+}
+
+// compiles linked modules, see `linker::link`
+fn compile_linked(linked: &Linked) -> Result<(Vec<u8>, SourceMap), Vec<SyntaxError>> {
+    let units: Vec<_> = linked
+        .units
+        .iter()
+        .map(|unit| (unit.name.clone(), &unit.ast, unit.globals.clone()))
+        .collect();
+    let mut map = SourceMap::new();
+    let image = Compiler::new(0, Some(&mut map))
+        .compile_units(&units)?
+        .encode();
+    Ok((image, map))
+}
+
+/// A program compiled along with the modules it imports.
+pub struct LinkedProgram {
+    pub image: Vec<u8>,
+    pub source_map: SourceMap,
+    /// The source of every imported module, by name.
+    pub modules: HashMap<String, String>,
+}
+
+/// Compiles `program` along with the modules it imports into one program
+/// image. `resolve` is asked for the source of every imported module once.
+/// Each module's top-level statements run once, after those of the
+/// modules it imports and before the program's own. Fails with the syntax
+/// and link errors of all modules, see `linker::link`.
+pub fn compile_modules(
+    program: String,
+    resolve: &mut ModuleResolver,
+) -> Result<LinkedProgram, Vec<SyntaxError>> {
+    let linked = linker::link(&program, resolve)?;
+    let (image, source_map) = compile_linked(&linked)?;
+    Ok(LinkedProgram {
+        image,
+        source_map,
+        modules: linked.sources,
+    })
+}
+
+/// Parses script source into the AST `compile_ast` consumes. See
+/// `parser::parse` for the grammar.
+pub fn parse_code(program: String) -> Result<serde_json::Value, Vec<SyntaxError>> {
+    parser::parse(&program)
+}
+
+/// Compiles a program that imports no modules, an import fails to link.
+pub fn compile_code(p: String) -> Result<Vec<u8>, Vec<SyntaxError>> {
+    Ok(compile_code_with_source_map(p)?.0)
+}
 
-    vec![]
+/// Like `compile_code`, the source map spans point into `p`.
+pub fn compile_code_with_source_map(p: String) -> Result<(Vec<u8>, SourceMap), Vec<SyntaxError>> {
+    let linked = linker::link(&p, &mut |_| None)?;
+    compile_linked(&linked)
 }
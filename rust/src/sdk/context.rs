@@ -1,160 +1,103 @@
-use std::{cell::RefCell, collections::HashMap, rc::Rc};
+use std::{cell::RefCell, rc::Rc};
 
-use crate::sdk::data::{Val, ValGroup};
+use serde::{Deserialize, Serialize};
 
-pub struct Scope {
-    pub tag: String,
-    pub memory: Rc<RefCell<ValGroup>>,
-    pub frozen_start: usize,
-    pub frozen_end: usize,
-    pub frozen_pointer: usize,
+use crate::sdk::data::{Blueprint, Function, Val, ValGroup, VarCell};
+
+/// Where the value of a returning call goes.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Ret {
+    /// An absolute index into the register stack.
+    Register(usize),
+    /// The callback of an array walk, its result feeds the walk.
+    Walk,
+    /// The function a run started with, its return ends the run.
+    Entry,
+    /// The `init` method `new` calls, its result is dropped.
+    Discard,
 }
-impl Scope {
-    pub fn new(
-        tag: String,
-        initial_pointer: usize,
-        frozen_start: usize,
-        frozen_end: usize,
-    ) -> Self {
-        Scope {
-            tag,
-            memory: Rc::new(RefCell::new(ValGroup::new_empty())),
-            frozen_pointer: initial_pointer,
-            frozen_start,
-            frozen_end,
-        }
-    }
-    pub fn new_with_args(
-        tag: String,
-        initial_pointer: usize,
-        frozen_start: usize,
-        frozen_end: usize,
-        args: HashMap<String, Val>,
-    ) -> Self {
-        Scope {
-            tag,
-            memory: Rc::new(RefCell::new(ValGroup::new(args))),
-            frozen_pointer: initial_pointer,
-            frozen_start,
-            frozen_end,
-        }
-    }
-    pub fn update_frozen_pointer(&mut self, pointer: usize) {
-        self.frozen_pointer = pointer;
-    }
-    pub fn update_initial_pointer_info(
-        &mut self,
-        pointer: usize,
-        frozen_start: usize,
-        frozen_end: usize,
-    ) {
-        self.frozen_pointer = pointer;
-        self.frozen_start = frozen_start;
-        self.frozen_end = frozen_end;
-    }
-    pub fn find_val(&self, name: String) -> Val {
-        let v = self.memory.borrow();
-        let val = v.data.get(&name);
-        if val.is_none() {
-            return Val::new(0, Rc::new(RefCell::new(Box::new(0))));
-        } else {
-            return val.unwrap().clone();
-        }
-    }
-    pub fn update_val(&mut self, name: String, val: Val) -> bool {
-        let mut v = self.memory.borrow_mut();
-        if v.data.contains_key(&name) {
-            v.data.insert(name, val);
-            return true;
-        }
-        false
-    }
-    pub fn define_val(&mut self, name: String, val: Val) {
-        let mut v = self.memory.borrow_mut();
-        v.data.insert(name, val);
-    }
+
+/// The activation of a function: its registers are
+/// `registers[base..base + proto.registers]`.
+pub struct Frame {
+    /// The function value called, `None` for the program's main body.
+    pub func: Option<Rc<RefCell<Function>>>,
+    /// Index into the program's function table.
+    pub proto: usize,
+    /// The next instruction to run.
+    pub pc: usize,
+    pub base: usize,
+    pub cells: Vec<VarCell>,
+    pub upvalues: Vec<VarCell>,
+    pub ret: Ret,
 }
 
+/// The state of the running program: the globals, the classes declared
+/// so far and the call stack with its register stack.
 pub struct Context {
-    pub memory: Vec<Rc<RefCell<Scope>>>,
+    pub globals: ValGroup,
+    /// Blueprints by class name. A class's instances have its position
+    /// plus one as their type id, which it keeps when it is declared again.
+    pub blueprints: Vec<(String, Blueprint)>,
+    pub frames: Vec<Frame>,
+    pub registers: Vec<Val>,
+}
+
+impl Default for Context {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Context {
     pub fn new() -> Self {
-        Context { memory: vec![] }
-    }
-    pub fn push_scope(
-        &mut self,
-        tag: String,
-        inital_pointer: usize,
-        frozen_start: usize,
-        frozen_end: usize,
-    ) {
-        self.memory.push(Rc::new(RefCell::new(Scope::new(
-            tag,
-            inital_pointer,
-            frozen_start,
-            frozen_end,
-        ))));
-    }
-    pub fn push_scope_with_args(
-        &mut self,
-        tag: String,
-        inital_pointer: usize,
-        frozen_start: usize,
-        frozen_end: usize,
-        args: HashMap<String, Val>,
-    ) {
-        self.memory.push(Rc::new(RefCell::new(Scope::new_with_args(
-            tag,
-            inital_pointer,
-            frozen_start,
-            frozen_end,
-            args,
-        ))));
+        Context {
+            globals: ValGroup::new_empty(),
+            blueprints: vec![],
+            frames: vec![],
+            registers: vec![],
+        }
     }
-    pub fn pop_scope(&mut self) {
-        self.memory.pop();
+    pub fn find_global(&self, name: &str) -> Val {
+        self.globals.data.get(name).cloned().unwrap_or(Val::Null)
     }
-    pub fn get_scope(&mut self, index: usize) -> Rc<RefCell<Scope>> {
-        self.memory.get(index).unwrap().clone()
+    pub fn define_global(&mut self, name: &str, val: Val) {
+        self.globals.data.insert(name.to_string(), val);
     }
-    pub fn find_val_globally(&mut self, name: String) -> Val {
-        for scope in self.memory.iter().rev() {
-            let val = scope.borrow().find_val(name.clone());
-            if !val.is_empty() {
-                return val;
+    /// Updates a global, defining it when it does not exist yet.
+    pub fn update_global(&mut self, name: &str, val: Val) {
+        match self.globals.data.get_mut(name) {
+            Some(slot) => *slot = val,
+            None => {
+                self.globals.data.insert(name.to_string(), val);
             }
         }
-        Val::new(0, Rc::new(RefCell::new(Box::new(0))))
-    }
-    pub fn define_val_globally(&mut self, name: String, val: Val) {
-        self.memory
-            .last()
-            .unwrap()
-            .borrow_mut()
-            .define_val(name.clone(), val.clone());
     }
-    pub fn update_val_globally(&mut self, name: String, val: Val) {
-        let mut found = false;
-        for scope in self.memory.iter().rev() {
-            if scope.borrow_mut().update_val(name.clone(), val.clone()) {
-                found = true;
-                break;
+    pub fn blueprint(&self, class: &str) -> Option<&Blueprint> {
+        self.blueprints
+            .iter()
+            .find(|(name, _)| name == class)
+            .map(|(_, blueprint)| blueprint)
+    }
+    /// The class of instances of type `typ`.
+    pub fn class_of(&self, typ: i64) -> Option<&str> {
+        let index = usize::try_from(typ).ok()?.checked_sub(1)?;
+        self.blueprints.get(index).map(|(name, _)| name.as_str())
+    }
+    /// Makes or replaces the blueprint of `class`.
+    pub fn define_class(&mut self, class: &str, def_props: ValGroup) {
+        match self.blueprints.iter().position(|(name, _)| name == class) {
+            Some(index) => self.blueprints[index].1.def_props = def_props,
+            None => {
+                let typ_id = self.blueprints.len() as i64 + 1;
+                self.blueprints
+                    .push((class.to_string(), Blueprint::new(typ_id, def_props)));
             }
         }
-        if !found {
-            self.memory
-                .last()
-                .unwrap()
-                .borrow_mut()
-                .define_val(name.clone(), val.clone());
-        }
-    }
-    pub fn find_val_in_last_scope(&mut self, name: String) -> Val {
-        self.memory.last().unwrap().borrow().find_val(name.clone())
     }
-    pub fn find_val_in_first_scope(&mut self, name: String) -> Val {
-        self.memory.first().unwrap().borrow().find_val(name.clone())
+    /// Drops every frame, leaving the globals.
+    pub fn unwind(&mut self) {
+        self.frames.clear();
+        self.registers.clear();
     }
 }
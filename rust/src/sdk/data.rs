@@ -1,131 +1,274 @@
 use std::any::Any;
 use std::cell::RefCell;
 use std::collections::HashMap;
+use std::fmt;
 use std::rc::Rc;
 
-#[derive(Clone, Debug)]
-pub struct Val {
-    pub typ: i64,
-    pub data: Rc<RefCell<Box<dyn Any>>>,
+use crate::sdk::error::VmError;
+
+/// A runtime value. Scalars and strings are stored inline, objects, arrays,
+/// functions and promises are shared through `Rc` so every copy of the
+/// value sees the same container. `typ()` gives the type code the typed
+/// JSON format and the bytecode use for each variant.
+#[derive(Clone)]
+pub enum Val {
+    /// Type code 0, also what a missing variable reads as.
+    Null,
+    I16(i16),
+    I32(i32),
+    I64(i64),
+    F32(f32),
+    F64(f64),
+    Bool(bool),
+    Str(Rc<str>),
+    Object(Rc<RefCell<Object>>),
+    Array(Rc<RefCell<Array>>),
+    Function(Rc<RefCell<Function>>),
+    Promise(Rc<RefCell<Promise>>),
+    /// Type code 249, the run is suspended on an `await`.
+    Awaiting,
+    /// Type code 250, the run is stopped by the debugger.
+    Paused,
+    /// Type code 251, the run ran out of fuel.
+    OutOfFuel,
+    /// Type code 252, the run failed with the error on its way to the vm.
+    Error(Rc<VmError>),
+    /// Type code 253, the run waits for a host call.
+    HostCall,
+    /// Type code 254, the run ended without a value.
+    NoResult,
+    /// Type code 255, a function run by the vm itself, like `askHost` or
+    /// `Math.sqrt`, by name.
+    Native(Rc<str>),
 }
 
 unsafe impl Send for Val {}
 
+// containers may contain themselves, so only scalars are printed in full
+impl fmt::Debug for Val {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Val::I16(v) => f.debug_tuple("I16").field(v).finish(),
+            Val::I32(v) => f.debug_tuple("I32").field(v).finish(),
+            Val::I64(v) => f.debug_tuple("I64").field(v).finish(),
+            Val::F32(v) => f.debug_tuple("F32").field(v).finish(),
+            Val::F64(v) => f.debug_tuple("F64").field(v).finish(),
+            Val::Bool(v) => f.debug_tuple("Bool").field(v).finish(),
+            Val::Str(v) => f.debug_tuple("Str").field(v).finish(),
+            Val::Native(v) => f.debug_tuple("Native").field(v).finish(),
+            Val::Error(v) => f.debug_tuple("Error").field(v).finish(),
+            Val::Function(v) => f.debug_tuple("Function").field(&v.borrow().name).finish(),
+            _ => f.write_str(self.type_name()),
+        }
+    }
+}
+
 impl Val {
+    /// Builds a value from the boxed form `Val` had before it became an
+    /// enum: a type code and a payload of the matching Rust type.
+    #[deprecated(note = "build the `Val` variants directly")]
     pub fn new(typ: i64, data: Rc<RefCell<Box<dyn Any>>>) -> Self {
-        Val { typ, data }
+        let data = data.borrow();
+        match typ {
+            1 => Val::I16(*data.downcast_ref().unwrap()),
+            2 => Val::I32(*data.downcast_ref().unwrap()),
+            3 => Val::I64(*data.downcast_ref().unwrap()),
+            4 => Val::F32(*data.downcast_ref().unwrap()),
+            5 => Val::F64(*data.downcast_ref().unwrap()),
+            6 => Val::Bool(*data.downcast_ref().unwrap()),
+            7 => Val::Str(data.downcast_ref::<String>().unwrap().as_str().into()),
+            8 => Val::Object(data.downcast_ref::<Rc<RefCell<Object>>>().unwrap().clone()),
+            9 => Val::Array(data.downcast_ref::<Rc<RefCell<Array>>>().unwrap().clone()),
+            10 => Val::Function(
+                data.downcast_ref::<Rc<RefCell<Function>>>()
+                    .unwrap()
+                    .clone(),
+            ),
+            11 => Val::Promise(data.downcast_ref::<Rc<RefCell<Promise>>>().unwrap().clone()),
+            249 => Val::Awaiting,
+            250 => Val::Paused,
+            251 => Val::OutOfFuel,
+            252 => Val::Error(Rc::new(data.downcast_ref::<VmError>().unwrap().clone())),
+            253 => Val::HostCall,
+            254 => Val::NoResult,
+            255 => Val::Native(data.downcast_ref::<String>().unwrap().as_str().into()),
+            _ => Val::Null,
+        }
+    }
+    pub fn string(text: impl Into<Rc<str>>) -> Self {
+        Val::Str(text.into())
+    }
+    pub fn object(object: Object) -> Self {
+        Val::Object(Rc::new(RefCell::new(object)))
+    }
+    pub fn array(array: Array) -> Self {
+        Val::Array(Rc::new(RefCell::new(array)))
+    }
+    pub fn func(func: Function) -> Self {
+        Val::Function(Rc::new(RefCell::new(func)))
+    }
+    /// The type code of the value, see `Val`'s variants.
+    pub fn typ(&self) -> i64 {
+        match self {
+            Val::Null => 0,
+            Val::I16(_) => 1,
+            Val::I32(_) => 2,
+            Val::I64(_) => 3,
+            Val::F32(_) => 4,
+            Val::F64(_) => 5,
+            Val::Bool(_) => 6,
+            Val::Str(_) => 7,
+            Val::Object(_) => 8,
+            Val::Array(_) => 9,
+            Val::Function(_) => 10,
+            Val::Promise(_) => 11,
+            Val::Awaiting => 249,
+            Val::Paused => 250,
+            Val::OutOfFuel => 251,
+            Val::Error(_) => 252,
+            Val::HostCall => 253,
+            Val::NoResult => 254,
+            Val::Native(_) => 255,
+        }
     }
     pub fn stringify(&self) -> String {
-        match self.typ {
-            1 => self.as_i16().to_string(),
-            2 => self.as_i32().to_string(),
-            3 => self.as_i64().to_string(),
-            4 => self.as_f32().to_string(),
-            5 => self.as_f64().to_string(),
-            6 => self.as_bool().to_string(),
-            7 => serde_json::json!(self.as_string()).to_string(),
-            8 => self.as_object().borrow().stringify(),
-            9 => self.as_array().borrow().stringify(),
-            10 => format!("\"{}\"", self.as_func().borrow().name.clone()),
+        match self {
+            Val::I16(v) => v.to_string(),
+            Val::I32(v) => v.to_string(),
+            Val::I64(v) => v.to_string(),
+            Val::F32(v) => v.to_string(),
+            Val::F64(v) => v.to_string(),
+            Val::Bool(v) => v.to_string(),
+            Val::Str(v) => serde_json::json!(&**v).to_string(),
+            Val::Object(v) => v.borrow().stringify(),
+            Val::Array(v) => v.borrow().stringify(),
+            Val::Function(v) => format!("\"{}\"", v.borrow().name),
+            Val::Promise(_) => "\"[promise]\"".to_string(),
             _ => "\"[undefined]\"".to_string(),
         }
     }
+    // copies objects and arrays all the way down, functions and promises
+    // stay shared
     fn clone_data(&self) -> Self {
-        match self.typ {
-            1 => Val {
-                typ: self.typ,
-                data: Rc::new(RefCell::new(Box::new(self.as_i16().clone()))),
-            },
-            2 => Val {
-                typ: self.typ,
-                data: Rc::new(RefCell::new(Box::new(self.as_i32().clone()))),
-            },
-            3 => Val {
-                typ: self.typ,
-                data: Rc::new(RefCell::new(Box::new(self.as_i64().clone()))),
-            },
-            4 => Val {
-                typ: self.typ,
-                data: Rc::new(RefCell::new(Box::new(self.as_f32().clone()))),
-            },
-            5 => Val {
-                typ: self.typ,
-                data: Rc::new(RefCell::new(Box::new(self.as_f64().clone()))),
-            },
-            6 => Val {
-                typ: self.typ,
-                data: Rc::new(RefCell::new(Box::new(self.as_bool().clone()))),
-            },
-            7 => Val {
-                typ: self.typ,
-                data: Rc::new(RefCell::new(Box::new(self.as_string().clone()))),
-            },
-            8 => Val {
-                typ: self.typ,
-                data: Rc::new(RefCell::new(Box::new(Rc::new(RefCell::new(
-                    self.as_object().borrow().clone_object(),
-                ))))),
-            },
-            9 => Val {
-                typ: self.typ,
-                data: Rc::new(RefCell::new(Box::new(Rc::new(RefCell::new(
-                    self.as_array().borrow().clone_arr(),
-                ))))),
-            },
-            10 => Val {
-                typ: self.typ,
-                data: Rc::new(RefCell::new(Box::new(self.as_func()))),
-            },
-            _ => Val {
-                typ: self.typ,
-                data: Rc::new(RefCell::new(Box::new(0))),
-            },
-        }
-    }
-    pub fn as_i16(&self) -> i16 {
-        *self.data.borrow().downcast_ref::<i16>().unwrap()
-    }
-    pub fn as_i32(&self) -> i32 {
-        *self.data.borrow().downcast_ref::<i32>().unwrap()
-    }
-    pub fn as_i64(&self) -> i64 {
-        *self.data.borrow().downcast_ref::<i64>().unwrap()
-    }
-    pub fn as_f32(&self) -> f32 {
-        *self.data.borrow().downcast_ref::<f32>().unwrap()
-    }
-    pub fn as_f64(&self) -> f64 {
-        *self.data.borrow().downcast_ref::<f64>().unwrap()
-    }
-    pub fn as_bool(&self) -> bool {
-        *self.data.borrow().downcast_ref::<bool>().unwrap()
-    }
-    pub fn as_string(&self) -> String {
-        self.data.borrow().downcast_ref::<String>().unwrap().clone()
-    }
-    pub fn as_object(&self) -> Rc<RefCell<Object>> {
-        self.data
-            .borrow()
-            .downcast_ref::<Rc<RefCell<Object>>>()
-            .unwrap()
-            .clone()
-    }
-    pub fn as_array(&self) -> Rc<RefCell<Array>> {
-        self.data
-            .borrow()
-            .downcast_ref::<Rc<RefCell<Array>>>()
-            .unwrap()
-            .clone()
-    }
-    pub fn as_func(&self) -> Rc<RefCell<Function>> {
-        self.data
-            .borrow()
-            .downcast_ref::<Rc<RefCell<Function>>>()
-            .unwrap()
-            .clone()
+        match self {
+            Val::Object(v) => Val::object(v.borrow().clone_object()),
+            Val::Array(v) => Val::array(v.borrow().clone_arr()),
+            other => other.clone(),
+        }
+    }
+    fn mismatch(&self, expected: &str) -> VmError {
+        VmError::TypeMismatch(format!(
+            "expected a {} value, found {}",
+            expected,
+            self.type_name()
+        ))
+    }
+    pub fn as_i16(&self) -> Result<i16, VmError> {
+        match self {
+            Val::I16(v) => Ok(*v),
+            _ => Err(self.mismatch("i16")),
+        }
+    }
+    pub fn as_i32(&self) -> Result<i32, VmError> {
+        match self {
+            Val::I32(v) => Ok(*v),
+            _ => Err(self.mismatch("i32")),
+        }
+    }
+    pub fn as_i64(&self) -> Result<i64, VmError> {
+        match self {
+            Val::I64(v) => Ok(*v),
+            _ => Err(self.mismatch("i64")),
+        }
+    }
+    pub fn as_f32(&self) -> Result<f32, VmError> {
+        match self {
+            Val::F32(v) => Ok(*v),
+            _ => Err(self.mismatch("f32")),
+        }
+    }
+    pub fn as_f64(&self) -> Result<f64, VmError> {
+        match self {
+            Val::F64(v) => Ok(*v),
+            _ => Err(self.mismatch("f64")),
+        }
+    }
+    pub fn as_bool(&self) -> Result<bool, VmError> {
+        match self {
+            Val::Bool(v) => Ok(*v),
+            _ => Err(self.mismatch("bool")),
+        }
+    }
+    pub fn as_string(&self) -> Result<String, VmError> {
+        self.as_str().map(str::to_string)
+    }
+    /// The text of a string or the name of a native function.
+    pub fn as_str(&self) -> Result<&str, VmError> {
+        match self {
+            Val::Str(v) | Val::Native(v) => Ok(v),
+            _ => Err(self.mismatch("string")),
+        }
+    }
+    pub fn as_object(&self) -> Result<Rc<RefCell<Object>>, VmError> {
+        match self {
+            Val::Object(v) => Ok(v.clone()),
+            _ => Err(self.mismatch("object")),
+        }
+    }
+    pub fn as_array(&self) -> Result<Rc<RefCell<Array>>, VmError> {
+        match self {
+            Val::Array(v) => Ok(v.clone()),
+            _ => Err(self.mismatch("array")),
+        }
+    }
+    pub fn as_func(&self) -> Result<Rc<RefCell<Function>>, VmError> {
+        match self {
+            Val::Function(v) => Ok(v.clone()),
+            _ => Err(self.mismatch("function")),
+        }
+    }
+    pub fn as_promise(&self) -> Result<Rc<RefCell<Promise>>, VmError> {
+        match self {
+            Val::Promise(v) => Ok(v.clone()),
+            _ => Err(self.mismatch("promise")),
+        }
+    }
+    /// Integers of any width as `i64`.
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Val::I16(v) => Some(*v as i64),
+            Val::I32(v) => Some(*v as i64),
+            Val::I64(v) => Some(*v),
+            _ => None,
+        }
+    }
+    /// Numbers of any type as `f64`.
+    pub fn as_num(&self) -> Option<f64> {
+        match self {
+            Val::F32(v) => Some(*v as f64),
+            Val::F64(v) => Some(*v),
+            _ => self.as_int().map(|v| v as f64),
+        }
     }
     pub fn is_empty(&self) -> bool {
-        self.typ == 0
+        matches!(self, Val::Null)
+    }
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Val::I16(_) => "i16",
+            Val::I32(_) => "i32",
+            Val::I64(_) => "i64",
+            Val::F32(_) => "f32",
+            Val::F64(_) => "f64",
+            Val::Bool(_) => "bool",
+            Val::Str(_) => "string",
+            Val::Object(_) => "object",
+            Val::Array(_) => "array",
+            Val::Function(_) => "function",
+            Val::Promise(_) => "promise",
+            Val::Native(_) => "native function",
+            _ => "undefined",
+        }
     }
 }
 
@@ -225,12 +368,33 @@ impl Array {
     }
 }
 
-#[derive(Clone, Debug)]
+/// A variable shared between the frame declaring it and the closures
+/// capturing it.
+pub type VarCell = Rc<RefCell<Val>>;
+
+#[derive(Clone)]
 pub struct Function {
     pub name: String,
     pub start: usize,
     pub end: usize,
     pub params: Vec<String>,
+    /// The variables of enclosing functions the body refers to, by name,
+    /// in the order of the function's upvalues; empty for functions
+    /// defined at the top level.
+    pub captured: Vec<(String, VarCell)>,
+}
+
+// captured variables can lead back to the function, so they are only counted
+impl std::fmt::Debug for Function {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Function")
+            .field("name", &self.name)
+            .field("start", &self.start)
+            .field("end", &self.end)
+            .field("params", &self.params)
+            .field("captured", &self.captured.len())
+            .finish()
+    }
 }
 
 impl Function {
@@ -240,9 +404,43 @@ impl Function {
             start,
             end,
             params,
+            captured: vec![],
         }
     }
     pub fn clone_func(&self) -> Self {
-        Function::new(self.name.clone(), self.start, self.end, self.params.clone())
+        Function {
+            captured: self.captured.clone(),
+            ..Function::new(self.name.clone(), self.start, self.end, self.params.clone())
+        }
+    }
+}
+
+/// The result of an async host call, type code 11. The host settles it by
+/// its call id; `await` hands out the value or throws the rejection.
+pub struct Promise {
+    pub call_id: i64,
+    pub api_name: String,
+    pub payload: String,
+    /// The input of the call in the typed wire encoding.
+    pub input: serde_json::Value,
+    pub state: PromiseState,
+}
+
+#[derive(Clone)]
+pub enum PromiseState {
+    Pending,
+    Resolved(Val),
+    Rejected(Val),
+}
+
+impl Promise {
+    pub fn new(call_id: i64, api_name: String, payload: String, input: serde_json::Value) -> Self {
+        Promise {
+            call_id,
+            api_name,
+            payload,
+            input,
+            state: PromiseState::Pending,
+        }
     }
 }
@@ -0,0 +1,186 @@
+use std::collections::{BTreeSet, HashSet};
+
+use serde_json::{json, Value};
+
+use crate::sdk::{error::VmError, source_map::StackFrame};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StepMode {
+    /// Stop at the next statement, entering called functions.
+    Into,
+    /// Stop at the next statement of the current function or its callers.
+    Over,
+    /// Stop at the next statement after the current function returns.
+    Out,
+}
+
+impl StepMode {
+    /// Wire code carried by the resume request, 0 means plain continue.
+    pub fn code(mode: Option<StepMode>) -> i64 {
+        match mode {
+            None => 0,
+            Some(StepMode::Into) => 1,
+            Some(StepMode::Over) => 2,
+            Some(StepMode::Out) => 3,
+        }
+    }
+    pub fn from_code(code: i64) -> Option<StepMode> {
+        match code {
+            1 => Some(StepMode::Into),
+            2 => Some(StepMode::Over),
+            3 => Some(StepMode::Out),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PauseReason {
+    Breakpoint,
+    Step,
+    Error,
+}
+
+impl PauseReason {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PauseReason::Breakpoint => "breakpoint",
+            PauseReason::Step => "step",
+            PauseReason::Error => "error",
+        }
+    }
+}
+
+/// Where and why a run stopped. `offset` is the statement about to run, or
+/// the instruction that raised `error`; `stack_trace` is innermost first.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PauseInfo {
+    pub reason: PauseReason,
+    pub offset: usize,
+    pub stack_trace: Vec<StackFrame>,
+    pub error: Option<VmError>,
+}
+
+impl PauseInfo {
+    pub fn to_json(&self) -> Value {
+        let top = self.stack_trace.first();
+        let span = top.and_then(|frame| frame.span);
+        json!({
+            "reason": self.reason.name(),
+            "offset": self.offset,
+            "line": span.map(|span| span.line),
+            "column": span.map(|span| span.column),
+            "module": top.and_then(|frame| frame.module.clone()),
+            "stackTrace": self.stack_trace.iter().map(|f| f.to_json()).collect::<Vec<_>>(),
+            "error": self.error.as_ref().map(|e| e.to_json()),
+        })
+    }
+}
+
+/// One entry of the scope chain with its variables rendered through
+/// `Val::stringify`, sorted by name.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScopeView {
+    pub tag: String,
+    pub function: Option<String>,
+    pub variables: Vec<(String, String)>,
+}
+
+impl ScopeView {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "tag": self.tag,
+            "function": self.function,
+            "variables": self
+                .variables
+                .iter()
+                .map(|(name, value)| json!({ "name": name, "value": value }))
+                .collect::<Vec<_>>(),
+        })
+    }
+}
+
+/// Breakpoints, stepping and pause-on-error state of an executor. The run
+/// loop consults it before each statement only while it is armed, so an
+/// unused debugger costs a single check per instruction.
+#[derive(Default)]
+pub struct Debugger {
+    breakpoints: BTreeSet<usize>,
+    pub pause_on_error: bool,
+    // statement offsets of the program, decoded on first use
+    statements: Option<HashSet<usize>>,
+    // step mode with the call depth it was requested at
+    step: Option<(StepMode, usize)>,
+    // the statement a resumed run starts on, so it does not pause there again
+    resumed_at: Option<usize>,
+    pub paused: Option<PauseInfo>,
+}
+
+impl Debugger {
+    pub fn load_statements(&mut self, offsets: impl FnOnce() -> HashSet<usize>) {
+        if self.statements.is_none() {
+            self.statements = Some(offsets());
+        }
+    }
+    /// Drops the breakpoints and statement offsets of a program that was
+    /// replaced, they do not carry over to the new code.
+    pub fn forget_program(&mut self) {
+        self.breakpoints.clear();
+        self.statements = None;
+    }
+    /// Replaces the breakpoints, keeping only offsets where a statement
+    /// starts. Returns the accepted offsets in order.
+    pub fn set_breakpoints(&mut self, offsets: &[usize]) -> Vec<usize> {
+        let statements = self.statements.as_ref();
+        self.breakpoints = offsets
+            .iter()
+            .copied()
+            .filter(|offset| statements.is_some_and(|s| s.contains(offset)))
+            .collect();
+        self.breakpoints.iter().copied().collect()
+    }
+    pub fn breakpoints(&self) -> Vec<usize> {
+        self.breakpoints.iter().copied().collect()
+    }
+    pub fn is_armed(&self) -> bool {
+        self.step.is_some() || !self.breakpoints.is_empty()
+    }
+    pub fn is_stepping(&self) -> bool {
+        self.step.is_some()
+    }
+    pub fn resume(&mut self, at: usize, step: Option<StepMode>, depth: usize) {
+        self.resumed_at = Some(at);
+        self.step = step.map(|mode| (mode, depth));
+    }
+    /// Ends stepping once the run is over.
+    pub fn finish_run(&mut self) {
+        self.step = None;
+        self.resumed_at = None;
+        self.paused = None;
+    }
+    /// Decides whether the run stops at `pointer`, `depth` being the number
+    /// of function scopes on the chain.
+    pub fn should_pause(&mut self, pointer: usize, depth: usize) -> Option<PauseReason> {
+        if self.resumed_at.take() == Some(pointer) {
+            return None;
+        }
+        if self.breakpoints.contains(&pointer) {
+            return Some(PauseReason::Breakpoint);
+        }
+        let is_statement = self
+            .statements
+            .as_ref()
+            .is_some_and(|statements| statements.contains(&pointer));
+        match self.step {
+            Some((mode, from)) if is_statement => {
+                let stop = match mode {
+                    StepMode::Into => true,
+                    StepMode::Over => depth <= from,
+                    StepMode::Out => depth < from,
+                };
+                stop.then_some(PauseReason::Step)
+            }
+            _ => None,
+        }
+    }
+}
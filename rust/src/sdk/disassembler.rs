@@ -0,0 +1,232 @@
+use std::fmt::Write;
+
+use crate::sdk::{
+    bytecode::{Capture, Decoded, Instr, Program, Slot, NO_CLASS},
+    error::VmError,
+};
+
+struct Listing<'a> {
+    program: &'a Program,
+    decoded: &'a Decoded,
+    // nesting depth of every function, main is 0
+    depths: Vec<usize>,
+}
+
+impl<'a> Listing<'a> {
+    fn name(&self, k: u32) -> String {
+        self.program.constants[k as usize]
+            .to_string()
+            .trim_matches('"')
+            .to_string()
+    }
+    fn address(&self, index: u32) -> String {
+        format!("@{:04x}", self.decoded.offsets[index as usize])
+    }
+    fn registers(first: u16, count: u16) -> String {
+        (first..first + count)
+            .map(|r| format!("r{}", r))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+    fn upvalue(&self, owner: u16, u: u16) -> String {
+        let func = &self.program.functions[owner as usize];
+        format!("upvalue {} ({})", u, func.upvalues[u as usize].name)
+    }
+
+    fn text(&self, instr: &Instr, owner: u16) -> String {
+        let program = self.program;
+        match *instr {
+            Instr::Nop => "nop".to_string(),
+            Instr::Const(r, k) => format!("r{} = {}", r, program.constants[k as usize]),
+            Instr::Null(r) => format!("r{} = null", r),
+            Instr::Bool(r, b) => format!("r{} = {}", r, b),
+            Instr::Move(d, s) => format!("r{} = r{}", d, s),
+            Instr::GetGlobal(r, k) => format!("r{} = global {}", r, self.name(k)),
+            Instr::DefGlobal(k, r) => format!("def global {} = r{}", self.name(k), r),
+            Instr::SetGlobal(k, r) => format!("global {} = r{}", self.name(k), r),
+            Instr::GetCell(r, c) => format!("r{} = cell {}", r, c),
+            Instr::SetCell(c, r) => format!("cell {} = r{}", c, r),
+            Instr::NewCell(c) => format!("new cell {}", c),
+            Instr::GetUpvalue(r, u) => format!("r{} = {}", r, self.upvalue(owner, u)),
+            Instr::SetUpvalue(u, r) => format!("{} = r{}", self.upvalue(owner, u), r),
+            Instr::Closure(r, f) => {
+                format!("r{} = func {}", r, program.functions[f as usize].name)
+            }
+            Instr::Array(r, first, count) => {
+                format!("r{} = [{}]", r, Self::registers(first, count))
+            }
+            Instr::Object(r, first, count, list) => {
+                let props: Vec<String> = self.decoded.keys[list as usize]
+                    .iter()
+                    .zip(first..first + count)
+                    .map(|(key, reg)| format!("{}: r{}", self.name(*key), reg))
+                    .collect();
+                format!("r{} = {{{}}}", r, props.join(", "))
+            }
+            Instr::Index(d, t, i) => format!("r{} = r{}[r{}]", d, t, i),
+            Instr::SetIndex(t, i, v, _) => format!("r{}[r{}] = r{}", t, i, v),
+            Instr::Not(d, s) => format!("r{} = !r{}", d, s),
+            Instr::Cast(d, s, k) => format!("r{} = r{} as {}", d, s, self.name(k)),
+            Instr::Await(d, s) => format!("r{} = await r{}", d, s),
+            Instr::Jump(a) => format!("jump {}", self.address(a)),
+            Instr::JumpIfNot(r, a) => format!("jump {} unless r{}", self.address(a), r),
+            Instr::Call(d, f, first, count) => {
+                format!("r{} = call r{}({})", d, f, Self::registers(first, count))
+            }
+            Instr::Return(r) => format!("return r{}", r),
+            Instr::ReturnNull => "return".to_string(),
+            Instr::Throw(r) => format!("throw r{}", r),
+            Instr::Try(t) => {
+                let info = &self.decoded.tries[t as usize];
+                let mut parts = vec![];
+                if let Some((catch, slot)) = info.catch {
+                    parts.push(match slot {
+                        Some(slot) => format!("catch {} into {}", self.address(catch), slot),
+                        None => format!("catch {}", self.address(catch)),
+                    });
+                }
+                if let Some(finally) = info.finally {
+                    parts.push(format!("finally {}", self.address(finally)));
+                }
+                parts.push(format!("after {}", self.address(info.after)));
+                format!("try {}", parts.join(", "))
+            }
+            Instr::TryEnd => "end try".to_string(),
+            Instr::Class(c, first) => {
+                let class = &program.classes[c as usize];
+                let fields: Vec<String> = class
+                    .fields
+                    .iter()
+                    .zip(first..)
+                    .map(|(field, reg)| format!("{}: r{}", field, reg))
+                    .collect();
+                let parent = match class.parent {
+                    Some(parent) => format!(" extends {}", program.classes[parent as usize].name),
+                    None => String::new(),
+                };
+                format!("class {}{} {{{}}}", class.name, parent, fields.join(", "))
+            }
+            Instr::New(d, k, first, count) => format!(
+                "r{} = new {}({})",
+                d,
+                self.name(k),
+                Self::registers(first, count)
+            ),
+            Instr::Invoke(d, first, count, k, c) => {
+                let from = if c == NO_CLASS {
+                    String::new()
+                } else {
+                    format!(" from {}", self.name(c))
+                };
+                format!(
+                    "r{} = invoke r{}.{}({}){}",
+                    d,
+                    first,
+                    self.name(k),
+                    Self::registers(first + 1, count),
+                    from
+                )
+            }
+            Instr::Binary(op, d, a, b) => format!("r{} = r{} {} r{}", d, a, op.symbol(), b),
+            Instr::Is(d, s, k) => format!("r{} = r{} is {}", d, s, self.name(k)),
+            Instr::Keys(d, s) => format!("r{} = keys r{}", d, s),
+            Instr::Next(d, s, i, a) => {
+                format!("r{} = next r{}[r{}] else jump {}", d, s, i, self.address(a))
+            }
+            Instr::Leave(n, a) => format!("leave {} try, jump {}", n, self.address(a)),
+        }
+    }
+
+    fn header(&self, index: usize) -> String {
+        let func = &self.program.functions[index];
+        let mut text = format!(
+            "func {}({}): {} registers, {} cells",
+            func.name,
+            func.params.join(", "),
+            func.registers,
+            func.cells
+        );
+        if !func.upvalues.is_empty() {
+            let upvalues: Vec<String> = func
+                .upvalues
+                .iter()
+                .map(|upvalue| match upvalue.from {
+                    Capture::Cell(c) => format!("{} from cell {}", upvalue.name, c),
+                    Capture::Upvalue(u) => format!("{} from upvalue {}", upvalue.name, u),
+                })
+                .collect();
+            text.push_str(&format!(", captures {}", upvalues.join(", ")));
+        }
+        let params: Vec<String> = func
+            .params
+            .iter()
+            .zip(func.param_slots.iter())
+            .filter(|(_, slot)| matches!(slot, Slot::Cell(_)))
+            .map(|(param, slot)| format!("{} in {}", param, slot))
+            .collect();
+        if !params.is_empty() {
+            text.push_str(&format!(", {}", params.join(", ")));
+        }
+        text
+    }
+
+    fn render(&self) -> String {
+        let mut out = String::new();
+        let decoded = self.decoded;
+        for (index, instr) in decoded.instrs.iter().enumerate() {
+            let offset = decoded.offsets[index];
+            let owner = decoded.owners[index];
+            let depth = self.depths[owner as usize];
+            if owner != 0 && decoded.entries[owner as usize] as usize == index {
+                let _ = writeln!(
+                    out,
+                    "{:04x}      {}{}",
+                    offset,
+                    "  ".repeat(depth - 1),
+                    self.header(owner as usize)
+                );
+            }
+            let _ = writeln!(
+                out,
+                "{:04x}  {:02x}  {}{}",
+                offset,
+                self.program.code[offset],
+                "  ".repeat(depth),
+                self.text(instr, owner)
+            );
+        }
+        out
+    }
+}
+
+/// Renders a program image as one instruction per line: the offset and
+/// opcode in hex, then the instruction. Registers are written `r0`,
+/// addresses `@offset`. Every function body starts with a header line
+/// giving its frame layout and is indented under it.
+///
+/// ```text
+/// 0000  01  r0 = i16 0
+/// 0007  06  def global total = r0
+/// 000e  05  r0 = global total
+/// 0015  01  r1 = i16 3
+/// 001c  23  r2 = r0 < r1
+/// 0023  16  jump @0044 unless r2
+/// ```
+pub fn disassemble(program: &[u8]) -> Result<String, VmError> {
+    let program = Program::decode(program)?;
+    let decoded = program.decode_code()?;
+    let mut depths = vec![0; program.functions.len()];
+    // bodies nest inside their parents, so a parent comes first by start
+    let mut order: Vec<usize> = (1..program.functions.len()).collect();
+    order.sort_by_key(|index| program.functions[*index].start);
+    for index in order {
+        let parent = decoded.parents[index].unwrap_or(0);
+        depths[index] = depths[parent] + 1;
+    }
+    Ok(Listing {
+        program: &program,
+        decoded: &decoded,
+        depths,
+    }
+    .render())
+}
@@ -0,0 +1,155 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::sdk::lexer::Span;
+
+/// Errors that abort a run unless a `try` statement catches them. The
+/// executor drops its in-flight state when one goes uncaught, so the VM
+/// stays usable for later calls.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum VmError {
+    /// An operator or statement got a value of a type it can not work with.
+    TypeMismatch(String),
+    /// A name that no scope defines was required, e.g. a missing global function.
+    UndefinedVariable(String),
+    /// The program bytes are malformed: a truncated operand, an unknown
+    /// opcode or a jump outside the program.
+    BadBytecode(String),
+    /// The host answered a host call with something the VM can not decode,
+    /// or answered when no host call was pending.
+    BadHostReply(String),
+    /// Nested calls went deeper than the executor allows. Holds the depth.
+    StackOverflow(usize),
+    /// An index was outside the bounds of the array it was applied to.
+    IndexOutOfBounds { index: i64, len: usize },
+    /// A host api outside the VM's allowlist was called.
+    HostApiDenied(String),
+    /// An allocation did not fit in the memory quota. Holds the limit in bytes.
+    MemoryLimitExceeded(usize),
+    /// A snapshot could not be taken in the VM's current state, or the blob
+    /// to restore is malformed, from another version or for another program.
+    BadSnapshot(String),
+    /// A value passed to `throw`, or sent by the host to fail a host call,
+    /// that no `catch` clause handled. Holds the value, stringified.
+    Thrown(String),
+}
+
+impl VmError {
+    /// Stable identifier of the variant, used as `kind` in FFI results.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            VmError::TypeMismatch(_) => "typeMismatch",
+            VmError::UndefinedVariable(_) => "undefinedVariable",
+            VmError::BadBytecode(_) => "badBytecode",
+            VmError::BadHostReply(_) => "badHostReply",
+            VmError::StackOverflow(_) => "stackOverflow",
+            VmError::IndexOutOfBounds { .. } => "indexOutOfBounds",
+            VmError::HostApiDenied(_) => "hostApiDenied",
+            VmError::MemoryLimitExceeded(_) => "memoryLimitExceeded",
+            VmError::BadSnapshot(_) => "badSnapshot",
+            VmError::Thrown(_) => "thrown",
+        }
+    }
+    /// Whether a `catch` clause may handle the error. Bad bytecode and an
+    /// exhausted memory quota always end the run.
+    pub fn is_catchable(&self) -> bool {
+        !matches!(
+            self,
+            VmError::BadBytecode(_) | VmError::MemoryLimitExceeded(_) | VmError::BadSnapshot(_)
+        )
+    }
+    pub fn to_json(&self) -> Value {
+        json!({
+            "kind": self.kind(),
+            "message": self.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmError::TypeMismatch(detail) => write!(f, "elpian error: {}", detail),
+            VmError::UndefinedVariable(name) => {
+                write!(f, "elpian error: '{}' is not defined", name)
+            }
+            VmError::BadBytecode(detail) => write!(f, "elpian error: bad bytecode, {}", detail),
+            VmError::BadHostReply(detail) => {
+                write!(f, "elpian error: bad host reply, {}", detail)
+            }
+            VmError::StackOverflow(depth) => write!(
+                f,
+                "elpian error: stack overflow, call depth exceeded {}",
+                depth
+            ),
+            VmError::IndexOutOfBounds { index, len } => write!(
+                f,
+                "elpian error: index {} is out of bounds for array of length {}",
+                index, len
+            ),
+            VmError::HostApiDenied(api_name) => write!(
+                f,
+                "elpian error: host api '{}' is not allowed for this vm",
+                api_name
+            ),
+            VmError::MemoryLimitExceeded(limit) => {
+                write!(f, "elpian error: memory limit of {} bytes exceeded", limit)
+            }
+            VmError::BadSnapshot(detail) => write!(f, "elpian error: bad snapshot, {}", detail),
+            VmError::Thrown(value) => write!(f, "elpian error: uncaught throw of {}", value),
+        }
+    }
+}
+
+impl std::error::Error for VmError {}
+
+/// A problem found while parsing or linking script source, anchored to the
+/// offending token. `module` names the imported module whose source `span`
+/// points into, `None` for the program itself.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SyntaxError {
+    pub message: String,
+    pub span: Span,
+    pub module: Option<String>,
+}
+
+impl SyntaxError {
+    pub fn new(message: String, span: Span) -> Self {
+        SyntaxError {
+            message,
+            span,
+            module: None,
+        }
+    }
+    pub fn in_module(self, module: Option<&str>) -> Self {
+        SyntaxError {
+            module: module.map(str::to_string),
+            ..self
+        }
+    }
+    pub fn to_json(&self) -> Value {
+        let mut value = self.span.to_json();
+        value["message"] = json!(self.message);
+        if let Some(module) = &self.module {
+            value["module"] = json!(module);
+        }
+        value
+    }
+}
+
+impl fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(module) = &self.module {
+            write!(f, "{}:", module)?;
+        }
+        write!(
+            f,
+            "{}:{}: {}",
+            self.span.line, self.span.column, self.message
+        )
+    }
+}
+
+impl std::error::Error for SyntaxError {}
@@ -1,1004 +1,1545 @@
-// use wasm_bindgen::prelude::wasm_bindgen;
-
 use crate::sdk::{
-    context::Context,
-    data::{Array, Function, Object, Val, ValGroup},
+    bytecode::{BinOp, Capture, Constant, Decoded, FunctionEntry, Instr, Program, Slot, NO_CLASS},
+    context::{Context, Frame, Ret},
+    data::{Array, Blueprint, Function, Object, Promise, PromiseState, Val, ValGroup, VarCell},
+    debugger::{Debugger, PauseInfo, PauseReason, ScopeView, StepMode},
+    error::VmError,
+    gc::{self, CycleCollector, GcStats},
+    memory::{
+        array_size, context_size, deep_size, entry_size, frame_size, repeated_size, shallow_size,
+        slot_size, string_size, MemoryMeter,
+    },
+    reload::{self, ReloadReport},
+    snapshot::{
+        self, ExecutorSnapshot, FrameData, Loader, Saver, TimerData, TimerQueueData, WalkData,
+    },
+    source_map::{SourceMap, StackFrame},
+    stdlib,
+    timers::{Timer, TimerQueue, TIMER_FUNCTIONS},
+    vm::CallbackHolder,
+    wire::{self, Refs},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    cell::RefCell,
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    rc::Rc,
 };
-use core::panic;
-use std::{any::Any, cell::RefCell, collections::HashMap, fmt, i16, rc::Rc};
-
-use std::vec;
-
-#[derive(Clone, Debug, PartialEq)]
-pub enum OperationTypes {
-    DefineVar,
-    AssignVar,
-    CallFunc,
-    ReturnVal,
-    IfStmt,
-    LoopStmt,
-    SwitchStmt,
-    Arithmetic,
-    Indexer,
-    NotVal,
-    ObjExpr,
-    ArrExpr,
-    CondBrch,
-    CastOprt,
-    Dummy,
-}
 
-impl fmt::Display for OperationTypes {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:?}", self)
-    }
-}
+// function calls a run may nest before it fails with a stack overflow
+const MAX_CALL_DEPTH: usize = 1024;
 
-#[derive(Clone, Debug, PartialEq)]
-pub enum ExecStates {
-    AssignVarExtractName,
-    AssignVarExtractIndex,
-    AssignVarExtractValue,
-    DefineVarExtractName,
-    DefineVarExtractValue,
-    CallFuncStarted,
-    CallFuncExtractFunc,
-    CallFuncExtractParam,
-    CallFuncFinished,
-    ReturnValStarted,
-    ReturnValFinished,
-    IfStmtStarted,
-    IfStmtIsConditioned,
-    IfStmtFinished,
-    LoopStmtStarted,
-    LoopStmtFinished,
-    SwitchStmtStarted,
-    SwitchStmtExtractVal,
-    SwitchStmtExtractCase,
-    SwitchStmtFinished,
-    ArithmeticStarted,
-    ArithmeticExtractOp,
-    ArithmeticExtractArg1,
-    ArithmeticExtractArg2,
-    IndexerStarted,
-    IndexerExtractVarName,
-    IndexerExtractIndex,
-    NotValStarted,
-    NotValFinished,
-    ObjExprStarted,
-    ObjExprExtractInfo,
-    ObjExprExtractProp,
-    ObjExprFinished,
-    ArrExprStarted,
-    ArrExprExtractInfo,
-    ArrExprExtractItem,
-    ArrExprFinished,
-    CondBranchStarted,
-    CondBranchFinished,
-    CastOprtStarted,
-    CastOprtFinished,
-    Dummy,
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) enum TryStage {
+    Try,
+    Catch,
+    Finally,
 }
 
-impl fmt::Display for ExecStates {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:?}", self)
-    }
+/// Why a finally clause runs besides the try or catch body ending: an
+/// error that is raised again, a return that goes on, or a `break` or
+/// `continue` leaving that many more try statements on the way to the
+/// instruction it jumps to, once it is done.
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) enum Completion<V> {
+    Error(VmError, Option<V>),
+    Return(V),
+    Leave(u16, u32),
 }
 
-pub trait Operation {
-    fn get_type(&self) -> OperationTypes;
-    fn get_state(&self) -> ExecStates;
-    fn set_state(&mut self, state: ExecStates, data: Box<dyn Any>);
-    fn get_data(&self) -> Vec<Val>;
+/// A try statement in progress. It belongs to the frame at `frame` on the
+/// call stack; errors unwind the frames and array walks above it. Its
+/// clauses are `Decoded::tries[clauses]`.
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct TryFrame<V> {
+    pub frame: usize,
+    pub walks: usize,
+    pub clauses: u32,
+    pub stage: TryStage,
+    pub pending: Option<Completion<V>>,
 }
 
-impl fmt::Debug for dyn Operation {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Operation{{{} {}}}", self.get_type(), self.get_state())
+impl<V> TryFrame<V> {
+    pub fn map_vals<W>(
+        self,
+        mut f: impl FnMut(V) -> Result<W, VmError>,
+    ) -> Result<TryFrame<W>, VmError> {
+        let pending = match self.pending {
+            Some(Completion::Error(error, thrown)) => {
+                Some(Completion::Error(error, thrown.map(&mut f).transpose()?))
+            }
+            Some(Completion::Return(val)) => Some(Completion::Return(f(val)?)),
+            Some(Completion::Leave(count, target)) => Some(Completion::Leave(count, target)),
+            None => None,
+        };
+        Ok(TryFrame {
+            frame: self.frame,
+            walks: self.walks,
+            clauses: self.clauses,
+            stage: self.stage,
+            pending,
+        })
     }
 }
 
-struct DefineVariable {
-    typ: OperationTypes,
-    state: ExecStates,
-    pub var_name: Option<String>,
-    pub var_value: Option<Val>,
+// what a catch clause gets for an error the vm raised itself
+fn error_value(error: &VmError) -> Val {
+    let string = |text: String| Val::string(text);
+    let mut props = HashMap::new();
+    props.insert("kind".to_string(), string(error.kind().to_string()));
+    props.insert("message".to_string(), string(error.to_string()));
+    Val::object(Object::new(0, ValGroup::new(props)))
 }
 
-impl DefineVariable {
-    pub fn new() -> Self {
-        DefineVariable {
-            typ: OperationTypes::DefineVar,
-            state: ExecStates::DefineVarExtractName,
-            var_name: None,
-            var_value: None,
-        }
-    }
+/// A host call as the vm hands it out: the api, its input stringified and
+/// in the typed wire encoding, and the call id of async calls.
+pub struct HostRequest {
+    pub call_id: i64,
+    pub api_name: String,
+    /// Deprecated legacy output, read `input` instead.
+    pub payload: String,
+    pub input: Value,
 }
 
-impl Operation for DefineVariable {
-    fn get_state(&self) -> ExecStates {
-        self.state.clone()
-    }
-
-    fn get_type(&self) -> OperationTypes {
-        self.typ.clone()
-    }
-
-    fn set_state(&mut self, state: ExecStates, data: Box<dyn Any>) {
-        self.state = state.clone();
-        if state == ExecStates::DefineVarExtractName {
-            self.var_name = Some(*data.downcast::<String>().unwrap());
-        } else if state == ExecStates::DefineVarExtractValue {
-            self.var_value = Some(*data.downcast::<Val>().unwrap());
-        }
-    }
-
-    fn get_data(&self) -> Vec<Val> {
-        vec![
-            Val {
-                typ: 7,
-                data: Rc::new(RefCell::new(Box::new(self.var_name.clone().unwrap()))),
-            },
-            self.var_value.clone().unwrap(),
-        ]
+fn host_request(promise: &Rc<RefCell<Promise>>) -> HostRequest {
+    let promise = promise.borrow();
+    HostRequest {
+        call_id: promise.call_id,
+        api_name: promise.api_name.clone(),
+        payload: promise.payload.clone(),
+        input: promise.input.clone(),
     }
 }
 
-struct AssignVariable {
-    typ: OperationTypes,
-    state: ExecStates,
-    pub var_name: Option<String>,
-    pub assign_target_type: i16,
-    pub index: Option<Val>,
-    pub var_value: Option<Val>,
+// numeric operands, compared and combined as integers unless one of them
+// is a float
+enum Numbers {
+    Ints(i64, i64),
+    Floats(f64, f64),
 }
 
-impl AssignVariable {
-    pub fn new() -> Self {
-        AssignVariable {
-            typ: OperationTypes::AssignVar,
-            state: ExecStates::AssignVarExtractName,
-            var_name: None,
-            assign_target_type: 0,
-            index: None,
-            var_value: None,
-        }
+fn numbers(a: &Val, b: &Val) -> Option<Numbers> {
+    match (a.as_int(), b.as_int()) {
+        (Some(a), Some(b)) => Some(Numbers::Ints(a, b)),
+        _ => Some(Numbers::Floats(a.as_num()?, b.as_num()?)),
     }
 }
 
-impl Operation for AssignVariable {
-    fn get_state(&self) -> ExecStates {
-        self.state.clone()
-    }
-
-    fn get_type(&self) -> OperationTypes {
-        self.typ.clone()
-    }
-
-    fn set_state(&mut self, state: ExecStates, data: Box<dyn Any>) {
-        self.state = state.clone();
-        if state == ExecStates::AssignVarExtractName {
-            let (var_name, assign_target_type) = *data.downcast::<(String, i16)>().unwrap();
-            self.var_name = Some(var_name.clone());
-            self.assign_target_type = assign_target_type;
-        } else if state == ExecStates::AssignVarExtractIndex {
-            if self.assign_target_type == 2 {
-                self.index = Some(*data.downcast::<Val>().unwrap());
-            } else {
-                panic!("elpian error: wrong state set to assignment operation");
-            }
-        } else if state == ExecStates::AssignVarExtractValue {
-            self.var_value = Some(*data.downcast::<Val>().unwrap());
-        }
-    }
-
-    fn get_data(&self) -> Vec<Val> {
-        if self.assign_target_type == 2 {
-            if self.var_value.is_none() {
-                return vec![
-                    Val {
-                        typ: 7,
-                        data: Rc::new(RefCell::new(Box::new(self.var_name.clone().unwrap()))),
-                    },
-                    Val {
-                        typ: 6,
-                        data: Rc::new(RefCell::new(Box::new(self.assign_target_type))),
-                    },
-                    self.index.clone().unwrap(),
-                    Val {
-                        typ: 0,
-                        data: Rc::new(RefCell::new(Box::new(0))),
-                    },
-                ];
-            } else {
-                return vec![
-                    Val {
-                        typ: 7,
-                        data: Rc::new(RefCell::new(Box::new(self.var_name.clone().unwrap()))),
-                    },
-                    Val {
-                        typ: 6,
-                        data: Rc::new(RefCell::new(Box::new(self.assign_target_type))),
-                    },
-                    self.index.clone().unwrap(),
-                    self.var_value.clone().unwrap(),
-                ];
-            }
-        } else {
-            if self.var_value.is_none() {
-                return vec![
-                    Val {
-                        typ: 7,
-                        data: Rc::new(RefCell::new(Box::new(self.var_name.clone().unwrap()))),
-                    },
-                    Val {
-                        typ: 6,
-                        data: Rc::new(RefCell::new(Box::new(self.assign_target_type))),
-                    },
-                    Val {
-                        typ: 0,
-                        data: Rc::new(RefCell::new(Box::new(0))),
-                    },
-                    Val {
-                        typ: 0,
-                        data: Rc::new(RefCell::new(Box::new(0))),
-                    },
-                ];
-            } else {
-                return vec![
-                    Val {
-                        typ: 7,
-                        data: Rc::new(RefCell::new(Box::new(self.var_name.clone().unwrap()))),
-                    },
-                    Val {
-                        typ: 6,
-                        data: Rc::new(RefCell::new(Box::new(self.assign_target_type))),
-                    },
-                    Val {
-                        typ: 0,
-                        data: Rc::new(RefCell::new(Box::new(0))),
-                    },
-                    self.var_value.clone().unwrap(),
-                ];
-            }
-        }
+// how a value reads when it is joined to a string
+fn text_of(val: &Val) -> Option<String> {
+    match val {
+        Val::Str(text) => Some(text.to_string()),
+        Val::Function(_) | Val::Promise(_) => None,
+        _ if (1..=9).contains(&val.typ()) => Some(val.stringify()),
+        _ => None,
     }
 }
 
-struct CallFunction {
-    typ: OperationTypes,
-    state: ExecStates,
-    pub func: Option<Rc<RefCell<Function>>>,
-    pub is_native: bool,
-    pub param_count: i32,
-    pub params: Vec<Val>,
-}
-
-impl CallFunction {
-    pub fn new() -> Self {
-        CallFunction {
-            typ: OperationTypes::CallFunc,
-            state: ExecStates::CallFuncStarted,
-            func: None,
-            param_count: 0,
-            is_native: false,
-            params: vec![],
-        }
-    }
+fn mismatch(a: &Val, b: &Val, verb: &str) -> VmError {
+    let kind = |val: &Val| match val.typ() {
+        1..=3 => "integer",
+        4 | 5 => "float",
+        6..=11 => val.type_name(),
+        _ => "unknown data type",
+    };
+    VmError::TypeMismatch(format!("{} and {} can not be {}", kind(a), kind(b), verb))
 }
 
-impl Operation for CallFunction {
-    fn get_state(&self) -> ExecStates {
-        self.state.clone()
-    }
-
-    fn get_type(&self) -> OperationTypes {
-        self.typ.clone()
-    }
-
-    fn set_state(&mut self, state: ExecStates, data: Box<dyn Any>) {
-        self.state = state.clone();
-        if state == ExecStates::CallFuncExtractFunc {
-            let val = data.downcast::<(Val, usize)>().unwrap();
-            if val.as_ref().0.typ == 10 {
-                self.func = Some(val.as_ref().0.as_func());
-                self.param_count = val.as_ref().1 as i32;
-                self.is_native = false;
-            } else if val.as_ref().0.typ == 255 {
-                self.func = Some(Rc::new(RefCell::new(Function::new(
-                    "".to_string(),
-                    0,
-                    0,
-                    vec!["apiName".to_string(), "input".to_string()],
-                ))));
-                self.param_count = 2;
-                self.is_native = true;
-            } else {
-                panic!("elpian error: the specified data is not runnable");
-            }
-        } else if state == ExecStates::CallFuncExtractParam {
-            self.params.push(*data.downcast::<Val>().unwrap());
-        }
-        if let Some(func) = &self.func {
-            if func.borrow().params.len() == self.params.len() {
-                self.state = ExecStates::CallFuncFinished;
+// `value as target`; strings are parsed, anything that does not convert
+// gives null
+fn cast(data: &Val, target: &str) -> Val {
+    match target {
+        "i16" | "i32" | "i64" => {
+            let num = match data {
+                Val::F32(v) => Some(*v as i64),
+                Val::F64(v) => Some(*v as i64),
+                Val::Bool(v) => Some(*v as i64),
+                Val::Str(text) => match target {
+                    "i16" => text.parse::<i16>().ok().map(i64::from),
+                    "i32" => text.parse::<i32>().ok().map(i64::from),
+                    _ => text.parse::<i64>().ok(),
+                },
+                _ => data.as_int(),
+            };
+            match (num, target) {
+                (None, _) => Val::Null,
+                (Some(num), "i16") => Val::I16(num as i16),
+                (Some(num), "i32") => Val::I32(num as i32),
+                (Some(num), _) => Val::I64(num),
             }
         }
-    }
-
-    fn get_data(&self) -> Vec<Val> {
-        vec![
-            Val {
-                typ: 10,
-                data: Rc::new(RefCell::new(Box::new(self.func.clone().unwrap()))),
+        "f32" => match data {
+            Val::F32(v) => Val::F32(*v),
+            Val::F64(v) => Val::F32(*v as f32),
+            Val::Bool(v) => Val::F32(*v as i64 as f32),
+            Val::Str(text) => text.parse::<f32>().ok().map(Val::F32).unwrap_or(Val::Null),
+            _ => match data.as_int() {
+                Some(num) => Val::F32(num as f32),
+                None => Val::Null,
             },
-            Val {
-                typ: 6,
-                data: Rc::new(RefCell::new(Box::new(self.is_native))),
+        },
+        "f64" => match data {
+            Val::F32(v) => Val::F64(*v as f64),
+            Val::F64(v) => Val::F64(*v),
+            Val::Bool(v) => Val::F64(*v as i64 as f64),
+            Val::Str(text) => text.parse::<f64>().ok().map(Val::F64).unwrap_or(Val::Null),
+            _ => match data.as_int() {
+                Some(num) => Val::F64(num as f64),
+                None => Val::Null,
             },
-            Val {
-                typ: 2,
-                data: Rc::new(RefCell::new(Box::new(self.param_count))),
+        },
+        "bool" => match data {
+            Val::F32(v) => Val::Bool(*v > 0.0),
+            Val::F64(v) => Val::Bool(*v > 0.0),
+            Val::Bool(v) => Val::Bool(*v),
+            Val::Str(text) => Val::Bool(&**text == "true"),
+            _ => match data.as_int() {
+                Some(num) => Val::Bool(num > 0),
+                None => Val::Null,
             },
-            Val {
-                typ: 9,
-                data: Rc::new(RefCell::new(Box::new(Rc::new(RefCell::new(Array::new(
-                    self.params.clone(),
-                )))))),
+        },
+        "string" => match data {
+            Val::F32(v) => Val::string(v.to_string()),
+            Val::F64(v) => Val::string(v.to_string()),
+            Val::Bool(v) => Val::string(v.to_string()),
+            Val::Str(_) => data.clone(),
+            Val::Object(_) | Val::Array(_) => Val::string(data.stringify()),
+            _ => match data.as_int() {
+                Some(num) => Val::string(num.to_string()),
+                None => Val::Null,
             },
-        ]
-    }
-}
-
-struct ReturnValue {
-    typ: OperationTypes,
-    state: ExecStates,
-    pub value: Option<Val>,
-}
-
-impl ReturnValue {
-    pub fn new() -> Self {
-        ReturnValue {
-            typ: OperationTypes::ReturnVal,
-            state: ExecStates::ReturnValStarted,
-            value: None,
-        }
+        },
+        _ => Val::Null,
     }
 }
 
-impl Operation for ReturnValue {
-    fn get_state(&self) -> ExecStates {
-        self.state.clone()
-    }
-
-    fn get_type(&self) -> OperationTypes {
-        self.typ.clone()
-    }
-
-    fn set_state(&mut self, state: ExecStates, data: Box<dyn Any>) {
-        self.state = state.clone();
-        if state == ExecStates::ReturnValFinished {
-            self.value = Some(*data.downcast::<Val>().unwrap());
-        }
+// `target[index]`, null when there is nothing at the index
+fn index_value(indexed: &Val, index: &Val) -> Result<Val, VmError> {
+    if let Val::Str(key) = index {
+        return match indexed {
+            Val::Object(object) => Ok(object
+                .borrow()
+                .data
+                .data
+                .get(&**key)
+                .cloned()
+                .unwrap_or(Val::Null)),
+            _ => Err(VmError::TypeMismatch(
+                "non object value can not be indexed by string".to_string(),
+            )),
+        };
     }
-
-    fn get_data(&self) -> Vec<Val> {
-        vec![self.value.clone().unwrap()]
+    let Some(position) = index.as_int() else {
+        return Err(VmError::TypeMismatch(
+            "types other than integer and string can not be used to index anything".to_string(),
+        ));
+    };
+    match indexed {
+        Val::Array(items) => Ok(usize::try_from(position)
+            .ok()
+            .and_then(|position| items.borrow().data.get(position).cloned())
+            .unwrap_or(Val::Null)),
+        _ => Err(VmError::TypeMismatch(
+            "non array value can not be indexed by integer".to_string(),
+        )),
     }
 }
 
-struct IfStmt {
-    typ: OperationTypes,
-    state: ExecStates,
-    pub has_condition: bool,
-    pub condition: Option<Val>,
+// the program an executor runs, decoded once when it is loaded
+#[derive(Default)]
+struct Code {
+    program: Program,
+    decoded: Decoded,
+    // the constant pool as values, strings shared by every load
+    consts: Vec<Val>,
+    // function table index by body start, main excluded: function values
+    // find their code through it
+    protos: HashMap<usize, usize>,
+    // class table index by name
+    classes: HashMap<String, usize>,
 }
 
-impl IfStmt {
-    pub fn new() -> Self {
-        IfStmt {
-            typ: OperationTypes::IfStmt,
-            state: ExecStates::IfStmtStarted,
-            has_condition: false,
-            condition: None,
+impl Code {
+    fn load(image: &[u8]) -> Result<Code, VmError> {
+        let (program, decoded) = Program::load(image)?;
+        let consts = program.constants.iter().map(Constant::to_val).collect();
+        let protos = program
+            .functions
+            .iter()
+            .enumerate()
+            .skip(1)
+            .map(|(index, func)| (func.start, index))
+            .collect();
+        let classes = program
+            .classes
+            .iter()
+            .enumerate()
+            .map(|(index, class)| (class.name.clone(), index))
+            .collect();
+        Ok(Code {
+            program,
+            decoded,
+            consts,
+            protos,
+            classes,
+        })
+    }
+    // `class` and the classes it extends, nearest first; just the name for
+    // a class the program no longer declares
+    fn lineage<'a>(&'a self, class: &'a str) -> Vec<&'a str> {
+        let mut lineage = vec![class];
+        let mut index = self.classes.get(class).copied();
+        while let Some(parent) = index.and_then(|index| self.program.classes[index].parent) {
+            let parent = &self.program.classes[parent as usize];
+            lineage.push(&parent.name);
+            index = self.classes.get(&parent.name).copied();
         }
-    }
-}
-
-impl Operation for IfStmt {
-    fn get_state(&self) -> ExecStates {
-        self.state.clone()
-    }
-
-    fn get_type(&self) -> OperationTypes {
-        self.typ.clone()
-    }
-
-    fn set_state(&mut self, state: ExecStates, data: Box<dyn Any>) {
-        self.state = state.clone();
-        if state == ExecStates::IfStmtIsConditioned {
-            self.has_condition = *data.downcast::<bool>().unwrap();
-            if !self.has_condition {
-                self.condition = None;
-                self.state = ExecStates::IfStmtFinished;
-            }
-        } else if state == ExecStates::IfStmtFinished {
-            self.condition = Some(*data.downcast::<Val>().unwrap());
+        lineage
+    }
+    // the function implementing method `name` of `class`, possibly inherited
+    fn method(&self, class: &str, name: &str) -> Option<Rc<RefCell<Function>>> {
+        self.lineage(class).into_iter().find_map(|class| {
+            let entry = &self.program.classes[*self.classes.get(class)?];
+            let (_, proto) = entry.methods.iter().find(|(method, _)| method == name)?;
+            let func = &self.program.functions[*proto as usize];
+            Some(Rc::new(RefCell::new(Function::new(
+                func.name.clone(),
+                func.start,
+                func.end,
+                func.params.clone(),
+            ))))
+        })
+    }
+    // a name operand, the verifier made sure it is a string constant
+    fn name(&self, k: u32) -> &str {
+        match &self.program.constants[k as usize] {
+            Constant::Str(text) => text,
+            _ => "",
         }
     }
-
-    fn get_data(&self) -> Vec<Val> {
-        vec![
-            Val {
-                typ: 6,
-                data: Rc::new(RefCell::new(Box::new(self.has_condition))),
-            },
-            self.condition.clone().unwrap(),
-        ]
-    }
-}
-
-struct LoopStmt {
-    typ: OperationTypes,
-    state: ExecStates,
-    pub condition: Option<Val>,
 }
 
-impl LoopStmt {
-    pub fn new() -> Self {
-        LoopStmt {
-            typ: OperationTypes::LoopStmt,
-            state: ExecStates::LoopStmtStarted,
-            condition: None,
-        }
-    }
+// `map`, `filter` or `reduce` calling its function for one item after
+// the other; `dst` is the register the result goes to once it is done
+struct Walk {
+    // the native name, e.g. "Array.map"
+    kind: String,
+    // a copy, later changes to the array do not affect the walk
+    items: Vec<Val>,
+    func: Rc<RefCell<Function>>,
+    // the result array of map and filter, the accumulator of reduce
+    acc: Val,
+    // the item handed to the pending call
+    index: usize,
+    dst: usize,
+    // the frame that called the native
+    frame: usize,
 }
 
-impl Operation for LoopStmt {
-    fn get_state(&self) -> ExecStates {
-        self.state.clone()
-    }
-
-    fn get_type(&self) -> OperationTypes {
-        self.typ.clone()
-    }
-
-    fn set_state(&mut self, state: ExecStates, data: Box<dyn Any>) {
-        self.state = state.clone();
-        if state == ExecStates::LoopStmtFinished {
-            self.condition = Some(*data.downcast::<Val>().unwrap());
+impl Walk {
+    // the arguments of the call for the current item
+    fn call_args(&self) -> Vec<Val> {
+        let item = self.items[self.index].clone();
+        let index = Val::I64(self.index as i64);
+        if self.kind == "Array.reduce" {
+            vec![self.acc.clone(), item, index]
+        } else {
+            vec![item, index]
         }
     }
-
-    fn get_data(&self) -> Vec<Val> {
-        vec![self.condition.clone().unwrap()]
-    }
 }
 
-struct SwitchStmt {
-    typ: OperationTypes,
-    state: ExecStates,
-    pub comparing_value: Option<Val>,
-    pub branch_after_start: usize,
-    pub case_count: usize,
-    pub cases: Vec<(Val, usize, usize)>,
+pub struct Executor {
+    executor_id: i16,
+    code: Rc<Code>,
+    // why the program could not be loaded, raised by every run
+    load_error: Option<VmError>,
+    ctx: Context,
+    cb_counter: i64,
+    allowed_api: HashMap<String, bool>,
+    run_cb_id: i64,
+    exec_globally: bool,
+    reserved_host_call: Option<(u8, i64, Val)>,
+    // the input of the host call the run waits on, in the wire encoding
+    host_input: Option<Value>,
+    reserved_error: Option<VmError>,
+    fuel_limit: Option<u64>,
+    fuel: u64,
+    // the value the entry function returned once the run is over
+    result: Option<Val>,
+    // array walks waiting on their function, innermost last
+    walks: Vec<Walk>,
+    // the register the answer of a host call or an await goes to
+    resume_to: Option<usize>,
+    // set when the budget ran out or the debugger paused, the run goes on
+    // at the instruction it stopped before
+    suspended: bool,
+    memory: MemoryMeter,
+    // frees the reference cycles the script leaves behind
+    gc: CycleCollector,
+    source_map: Option<SourceMap>,
+    // frames of the last run that failed, taken by the vm with the error
+    stack_trace: Vec<StackFrame>,
+    debugger: Debugger,
+    // functions handed to the host, by name, so the host can call them back
+    // with their captured variables even when they are not globals
+    host_callbacks: HashMap<String, Rc<RefCell<Function>>>,
+    // host apis implemented in Rust, called without pausing the run
+    host_functions: HashMap<String, Rc<CallbackHolder>>,
+    // try statements of the current run, innermost last
+    try_frames: Vec<TryFrame<Val>>,
+    // the value behind a pending `VmError::Thrown`, handed to catch clauses
+    thrown: Option<Val>,
+    // promises of async host calls the host has not settled yet, by call id
+    promises: HashMap<i64, Rc<RefCell<Promise>>>,
+    // async host calls made since the vm last collected them
+    host_requests: Vec<Rc<RefCell<Promise>>>,
+    // the promise an `await` suspended the run on
+    awaiting: Option<Rc<RefCell<Promise>>>,
+    // callbacks scheduled by the timer functions, shared with the vm that
+    // runs them on its ticks
+    timers: Rc<RefCell<TimerQueue>>,
+    // state of the generator behind Math.random
+    rng: u64,
+    pub processing: bool,
 }
 
-impl SwitchStmt {
-    pub fn new() -> Self {
-        SwitchStmt {
-            typ: OperationTypes::SwitchStmt,
-            state: ExecStates::SwitchStmtStarted,
-            comparing_value: None,
-            branch_after_start: 0,
-            case_count: 0,
-            cases: vec![],
+impl Executor {
+    pub fn create_in_single_thread(
+        program: Vec<u8>,
+        exec_id: i16,
+        func_group: Vec<String>,
+    ) -> Self {
+        let mut allowed_api: HashMap<String, bool> = HashMap::new();
+        for api_name in func_group.iter() {
+            allowed_api.insert(api_name.clone(), true);
+        }
+        let (code, load_error) = match Code::load(&program) {
+            Ok(code) => (code, None),
+            Err(error) => (Code::default(), Some(error)),
+        };
+        Executor {
+            allowed_api,
+            executor_id: exec_id,
+            code: Rc::new(code),
+            load_error,
+            ctx: Context::new(),
+            cb_counter: 0,
+            run_cb_id: 0,
+            exec_globally: false,
+            reserved_host_call: None,
+            host_input: None,
+            reserved_error: None,
+            fuel_limit: None,
+            fuel: 0,
+            result: None,
+            walks: vec![],
+            resume_to: None,
+            suspended: false,
+            memory: MemoryMeter::new(),
+            gc: CycleCollector::default(),
+            source_map: None,
+            stack_trace: vec![],
+            debugger: Debugger::default(),
+            host_callbacks: HashMap::new(),
+            host_functions: HashMap::new(),
+            try_frames: vec![],
+            thrown: None,
+            promises: HashMap::new(),
+            host_requests: vec![],
+            awaiting: None,
+            timers: Rc::new(RefCell::new(TimerQueue::default())),
+            rng: stdlib::DEFAULT_SEED,
+            processing: false,
         }
     }
-}
-
-impl Operation for SwitchStmt {
-    fn get_state(&self) -> ExecStates {
-        self.state.clone()
-    }
-
-    fn get_type(&self) -> OperationTypes {
-        self.typ.clone()
-    }
-
-    fn set_state(&mut self, state: ExecStates, data: Box<dyn Any>) {
-        self.state = state.clone();
-        if state == ExecStates::SwitchStmtExtractVal {
-            let (comparing_val, branch_after_start, case_count) =
-                *data.downcast::<(Val, usize, usize)>().unwrap();
-            self.comparing_value = Some(comparing_val.clone());
-            self.branch_after_start = branch_after_start;
-            self.case_count = case_count;
-        } else if state == ExecStates::SwitchStmtExtractCase {
-            self.cases
-                .push(*data.downcast::<(Val, usize, usize)>().unwrap());
+    /// Checks an `askHost` api name against the VM's allowlist. Besides exact
+    /// names, entries of the form `group.*` grant every api below that group
+    /// (e.g. `canvas.*` allows `canvas.fillRect`) and a bare `*` allows all.
+    pub fn is_api_allowed(&self, api_name: &str) -> bool {
+        if self.allowed_api.contains_key(api_name) {
+            return true;
         }
-        if self.case_count == self.cases.len() {
-            self.state = ExecStates::SwitchStmtFinished;
+        let mut group = api_name;
+        while let Some(index) = group.rfind('.') {
+            group = &group[..index];
+            if self.allowed_api.contains_key(&format!("{}.*", group)) {
+                return true;
+            }
         }
-    }
-
-    fn get_data(&self) -> Vec<Val> {
-        let case_items: Vec<Val> = self
-            .cases
-            .iter()
-            .map(|item| {
-                let mut case_info = HashMap::new();
-                case_info.insert("val".to_string(), item.0.clone());
-                case_info.insert(
-                    "start".to_string(),
-                    Val {
-                        typ: 3,
-                        data: Rc::new(RefCell::new(Box::new(item.1 as i64))),
+        self.allowed_api.contains_key("*")
+    }
+    pub fn register_host_function(&mut self, api_name: &str, holder: CallbackHolder) {
+        self.host_functions
+            .insert(api_name.to_string(), Rc::new(holder));
+    }
+    pub fn unregister_host_function(&mut self, api_name: &str) -> bool {
+        self.host_functions.remove(api_name).is_some()
+    }
+    // Collects the named functions inside a host call payload. A closure
+    // is refused when another function answers to its name, in this call,
+    // an earlier one or the globals, the host would call that one instead.
+    fn find_callbacks(
+        &self,
+        val: &Val,
+        seen: &mut HashSet<*const ()>,
+        found: &mut HashMap<String, Rc<RefCell<Function>>>,
+    ) -> Result<(), VmError> {
+        match val {
+            Val::Object(object) if seen.insert(Rc::as_ptr(object) as *const ()) => {
+                for prop in object.borrow().data.data.values() {
+                    self.find_callbacks(prop, seen, found)?;
+                }
+            }
+            Val::Array(array) if seen.insert(Rc::as_ptr(array) as *const ()) => {
+                for item in array.borrow().data.iter() {
+                    self.find_callbacks(item, seen, found)?;
+                }
+            }
+            Val::Function(func) => {
+                let name = func.borrow().name.clone();
+                if name.is_empty() {
+                    return Ok(());
+                }
+                let is_closure = !func.borrow().captured.is_empty();
+                let sent = found.get(&name).or_else(|| self.host_callbacks.get(&name));
+                let taken = match sent {
+                    Some(other) => {
+                        !Rc::ptr_eq(other, func)
+                            && (is_closure || !other.borrow().captured.is_empty())
+                    }
+                    None if is_closure => match self.global(&name) {
+                        Val::Function(global) => !Rc::ptr_eq(&global, func),
+                        Val::Native(_) => true,
+                        _ => false,
                     },
-                );
-                case_info.insert(
-                    "end".to_string(),
-                    Val {
-                        typ: 3,
-                        data: Rc::new(RefCell::new(Box::new(item.2 as i64))),
-                    },
-                );
-                Val {
-                    typ: 8,
-                    data: Rc::new(RefCell::new(Box::new(Rc::new(RefCell::new(Object::new(
-                        -1,
-                        ValGroup::new(case_info),
-                    )))))),
+                    None => false,
+                };
+                if taken {
+                    return Err(VmError::TypeMismatch(format!(
+                        "closure '{}' can not be sent to the host, another function has its name",
+                        name
+                    )));
                 }
-            })
+                found.insert(name, func.clone());
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+    // Pushes the frame of a script function called with `args`, its result
+    // goes where `ret` says. False when an error was raised.
+    fn enter_function(&mut self, func: &Rc<RefCell<Function>>, args: &[Val], ret: Ret) -> bool {
+        if self.ctx.frames.len() >= MAX_CALL_DEPTH {
+            self.raise_error(VmError::StackOverflow(MAX_CALL_DEPTH));
+            return false;
+        }
+        let code = self.code.clone();
+        let start = func.borrow().start;
+        let Some(&proto) = code.protos.get(&start) else {
+            // a function the program no longer defines, see `reload`
+            self.deliver(ret, Val::NoResult);
+            return self.reserved_error.is_none();
+        };
+        let entry = &code.program.functions[proto];
+        if !self.allocate(frame_size(entry.registers as usize, entry.cells as usize)) {
+            return false;
+        }
+        let base = self.ctx.registers.len();
+        self.ctx
+            .registers
+            .resize(base + entry.registers as usize, Val::Null);
+        let mut cells: Vec<VarCell> = (0..entry.cells)
+            .map(|_| Rc::new(RefCell::new(Val::Null)))
             .collect();
-        vec![
-            self.comparing_value.clone().unwrap(),
-            Val {
-                typ: 3,
-                data: Rc::new(RefCell::new(Box::new(self.branch_after_start as i64))),
-            },
-            Val {
-                typ: 3,
-                data: Rc::new(RefCell::new(Box::new(self.case_count as i64))),
-            },
-            Val {
-                typ: 9,
-                data: Rc::new(RefCell::new(Box::new(Rc::new(RefCell::new(Array::new(
-                    case_items,
-                )))))),
-            },
-        ]
-    }
-}
-
-struct Arithmetic {
-    typ: OperationTypes,
-    state: ExecStates,
-    pub arg1: Option<Val>,
-    pub arg2: Option<Val>,
-    pub op: i16,
-}
-
-impl Arithmetic {
-    pub fn new() -> Self {
-        Arithmetic {
-            typ: OperationTypes::Arithmetic,
-            state: ExecStates::ArithmeticStarted,
-            arg1: None,
-            arg2: None,
-            op: 0,
+        for (i, slot) in entry.param_slots.iter().enumerate() {
+            let arg = args.get(i).cloned().unwrap_or(Val::Null);
+            match *slot {
+                Slot::Register(r) => self.ctx.registers[base + r as usize] = arg,
+                Slot::Cell(c) => cells[c as usize] = Rc::new(RefCell::new(arg)),
+            }
+        }
+        let mut upvalues: Vec<VarCell> = func
+            .borrow()
+            .captured
+            .iter()
+            .map(|(_, cell)| cell.clone())
+            .collect();
+        if upvalues.len() < entry.upvalues.len() {
+            upvalues.resize_with(entry.upvalues.len(), || Rc::new(RefCell::new(Val::Null)));
+        }
+        self.ctx.frames.push(Frame {
+            func: Some(func.clone()),
+            proto,
+            pc: code.decoded.entries[proto] as usize,
+            base,
+            cells,
+            upvalues,
+            ret,
+        });
+        true
+    }
+    // Hands a returned value to whoever waits for it. Functions that end
+    // without a value give null, the entry function of a function run
+    // gives no result.
+    fn deliver(&mut self, ret: Ret, val: Val) {
+        let val_or_null = |val: Val| if val.typ() == 254 { Val::Null } else { val };
+        match ret {
+            Ret::Register(at) => self.ctx.registers[at] = val_or_null(val),
+            Ret::Walk => self.feed_walk(val_or_null(val)),
+            Ret::Entry => {
+                self.result = Some(if self.exec_globally { Val::Null } else { val });
+            }
+            Ret::Discard => {}
         }
     }
-}
-
-impl Operation for Arithmetic {
-    fn get_state(&self) -> ExecStates {
-        self.state.clone()
+    // takes what the call for the current item of the innermost walk
+    // returned, and calls the function for the next item
+    fn feed_walk(&mut self, returned: Val) {
+        let Some(walk) = self.walks.last_mut() else {
+            return;
+        };
+        let item = walk.items[walk.index].clone();
+        match (walk.kind.as_str(), &walk.acc) {
+            ("Array.map", Val::Array(acc)) => acc.borrow_mut().data.push(returned),
+            ("Array.filter", Val::Array(acc)) => {
+                if matches!(returned, Val::Bool(true)) {
+                    acc.borrow_mut().data.push(item);
+                }
+            }
+            _ => walk.acc = returned,
+        }
+        walk.index += 1;
+        if walk.index < walk.items.len() {
+            let args = walk.call_args();
+            let func = walk.func.clone();
+            self.enter_function(&func, &args, Ret::Walk);
+        } else {
+            let walk = self.walks.pop().unwrap();
+            self.ctx.registers[walk.dst] = walk.acc;
+        }
     }
-
-    fn get_type(&self) -> OperationTypes {
-        self.typ.clone()
+    // Calls a built-in of the standard library. Array walks start calling
+    // the function for the first item and yield Some(None); None when an
+    // error was raised.
+    fn call_native(&mut self, name: &str, args: &[Val], dst: usize) -> Option<Option<Val>> {
+        if stdlib::ARRAY_WALKS.contains(&name) {
+            let items = match args.first() {
+                Some(Val::Array(items)) => items.borrow().data.clone(),
+                _ => {
+                    self.raise_error(VmError::TypeMismatch(format!("{} expects an array", name)));
+                    return None;
+                }
+            };
+            let func = match args.get(1) {
+                Some(Val::Function(func)) => func.clone(),
+                _ => {
+                    self.raise_error(VmError::TypeMismatch(format!(
+                        "{} expects a function",
+                        name
+                    )));
+                    return None;
+                }
+            };
+            let acc = if name == "Array.reduce" {
+                match args.get(2) {
+                    Some(init) => init.clone(),
+                    None => {
+                        self.raise_error(VmError::TypeMismatch(
+                            "Array.reduce expects an initial value".to_string(),
+                        ));
+                        return None;
+                    }
+                }
+            } else {
+                stdlib::array(vec![])
+            };
+            if !self.allocate(shallow_size(&acc)) {
+                return None;
+            }
+            if items.is_empty() {
+                return Some(Some(acc));
+            }
+            let walk = Walk {
+                kind: name.to_string(),
+                items,
+                func: func.clone(),
+                acc,
+                index: 0,
+                dst,
+                frame: self.ctx.frames.len() - 1,
+            };
+            let call_args = walk.call_args();
+            self.walks.push(walk);
+            if !self.enter_function(&func, &call_args, Ret::Walk) {
+                return None;
+            }
+            return Some(None);
+        }
+        if name == "Array.push" && args.get(1).is_some_and(gc::is_reference) {
+            self.gc.track(&args[0]);
+        }
+        match stdlib::call(name, args, &mut self.rng) {
+            Ok(val) => {
+                if !self.allocate(shallow_size(&val)) {
+                    return None;
+                }
+                Some(Some(val))
+            }
+            Err(error) => {
+                self.raise_error(error);
+                None
+            }
+        }
     }
-
-    fn set_state(&mut self, state: ExecStates, data: Box<dyn Any>) {
-        self.state = state.clone();
-        if state == ExecStates::ArithmeticExtractOp {
-            self.op = *data.downcast::<i16>().unwrap();
-        } else if state == ExecStates::ArithmeticExtractArg1 {
-            self.arg1 = Some(*data.downcast::<Val>().unwrap());
-        } else if state == ExecStates::ArithmeticExtractArg2 {
-            self.arg2 = Some(*data.downcast::<Val>().unwrap());
+    // setTimeout(f, ms), setInterval(f, ms), requestFrame(f) and
+    // clearTimer(id); None when an error was raised
+    fn call_timer_function(&mut self, name: &str, args: &[Val]) -> Option<Val> {
+        if name == "clearTimer" {
+            let id = match args.first().and_then(Val::as_int) {
+                Some(id) => id,
+                None => {
+                    self.raise_error(VmError::TypeMismatch(
+                        "clearTimer expects a timer id".to_string(),
+                    ));
+                    return None;
+                }
+            };
+            let cleared = self.timers.borrow_mut().clear(id);
+            return Some(Val::Bool(cleared));
+        }
+        let func = match args.first() {
+            Some(Val::Function(func)) => func.clone(),
+            _ => {
+                self.raise_error(VmError::TypeMismatch(format!(
+                    "{} expects a function",
+                    name
+                )));
+                return None;
+            }
+        };
+        let delay = match args.get(1).and_then(Val::as_num) {
+            _ if name == "requestFrame" => 0,
+            Some(delay) => delay as i64,
+            None => {
+                self.raise_error(VmError::TypeMismatch(format!(
+                    "{} expects a delay in milliseconds",
+                    name
+                )));
+                return None;
+            }
+        };
+        let id = self
+            .timers
+            .borrow_mut()
+            .schedule(func, delay, name == "setInterval");
+        Some(Val::I64(id))
+    }
+    // askHost(apiName, input) pauses the run until the host answers into
+    // `dst`, askHostAsync(apiName, input) puts a promise there right away.
+    // False when the run stops, on the pause or an error.
+    fn ask_host(&mut self, name: &str, args: &[Val], dst: usize) -> bool {
+        let api_name = args.first().cloned().unwrap_or(Val::Null);
+        if let Val::Str(api) = &api_name {
+            if let Some(holder) = self.host_functions.get(&**api).cloned() {
+                let input = args.get(1).cloned().unwrap_or(Val::Null);
+                return self.call_host_function(&holder, name, api, input, dst);
+            }
+        }
+        let api_name = match &api_name {
+            Val::Str(api) if self.is_api_allowed(api) => api.to_string(),
+            Val::Str(api) => {
+                self.raise_error(VmError::HostApiDenied(api.to_string()));
+                return false;
+            }
+            _ => {
+                self.raise_error(VmError::HostApiDenied(api_name.stringify()));
+                return false;
+            }
+        };
+        let input = args.get(1).cloned().unwrap_or(Val::Null);
+        let mut callbacks = HashMap::new();
+        let checked = wire::encode(&input).and_then(|typed_input| {
+            self.find_callbacks(&input, &mut HashSet::new(), &mut callbacks)?;
+            Ok(typed_input)
+        });
+        let typed_input = match checked {
+            Ok(typed_input) => typed_input,
+            Err(error) => {
+                self.raise_error(error);
+                return false;
+            }
+        };
+        self.host_callbacks.extend(callbacks);
+        self.cb_counter += 1;
+        let cb_id = self.cb_counter;
+        if name == "askHostAsync" {
+            // the run goes on, the host gets the call with the result and
+            // settles the promise by its id
+            let promise = Rc::new(RefCell::new(Promise::new(
+                cb_id,
+                api_name,
+                input.stringify(),
+                typed_input,
+            )));
+            let val = Val::Promise(promise.clone());
+            if !self.allocate(shallow_size(&val)) {
+                return false;
+            }
+            self.promises.insert(cb_id, promise.clone());
+            self.host_requests.push(promise);
+            self.ctx.registers[dst] = val;
+            return true;
+        }
+        self.host_input = Some(typed_input);
+        self.reserved_host_call = Some((
+            0x02,
+            cb_id,
+            Val::array(Array::new(vec![
+                Val::string(api_name),
+                Val::I16(self.executor_id),
+                input,
+            ])),
+        ));
+        self.resume_to = Some(dst);
+        false
+    }
+    // Runs a host api registered in Rust in place of asking the host. The
+    // input is the array of the call's arguments for `host.name(..)` calls,
+    // any other input is passed as the only argument.
+    fn call_host_function(
+        &mut self,
+        holder: &CallbackHolder,
+        name: &str,
+        api_name: &str,
+        input: Val,
+        dst: usize,
+    ) -> bool {
+        let result = match &input {
+            Val::Array(items) => {
+                let items = items.borrow().data.clone();
+                (holder.callback)(&items)
+            }
+            _ => (holder.callback)(std::slice::from_ref(&input)),
+        };
+        if name == "askHostAsync" {
+            self.cb_counter += 1;
+            let mut promise = Promise::new(
+                self.cb_counter,
+                api_name.to_string(),
+                input.stringify(),
+                Value::Null,
+            );
+            promise.state = match result {
+                Ok(value) => PromiseState::Resolved(value),
+                Err(error) => PromiseState::Rejected(error),
+            };
+            let val = Val::Promise(Rc::new(RefCell::new(promise)));
+            if !self.allocate(deep_size(&val, &mut HashSet::new())) {
+                return false;
+            }
+            self.ctx.registers[dst] = val;
+            return true;
+        }
+        match result {
+            Ok(value) => {
+                if !self.allocate(deep_size(&value, &mut HashSet::new())) {
+                    return false;
+                }
+                self.ctx.registers[dst] = value;
+                true
+            }
+            Err(error) => {
+                self.throw_value(error);
+                false
+            }
         }
     }
-
-    fn get_data(&self) -> Vec<Val> {
-        vec![
-            Val {
-                typ: 1,
-                data: Rc::new(RefCell::new(Box::new(self.op))),
-            },
-            self.arg1.clone().unwrap(),
-            self.arg2.clone().unwrap(),
-        ]
+    // Calls a function value, its result goes to register `dst`. False when
+    // an error was raised.
+    fn call(&mut self, callee: Val, args: &[Val], dst: usize) -> bool {
+        match callee {
+            Val::Function(func) => self.enter_function(&func, args, Ret::Register(dst)),
+            Val::Native(name) => self.call_builtin(&name, args, dst),
+            callee => {
+                self.raise_error(VmError::TypeMismatch(format!(
+                    "{} value is not callable",
+                    callee.type_name()
+                )));
+                false
+            }
+        }
     }
-}
-
-struct IndexerValue {
-    typ: OperationTypes,
-    state: ExecStates,
-    pub var: Option<Val>,
-    pub index: Option<Val>,
-}
-
-impl IndexerValue {
-    pub fn new() -> Self {
-        IndexerValue {
-            typ: OperationTypes::Indexer,
-            state: ExecStates::IndexerStarted,
-            var: None,
-            index: None,
+    // Calls a function the vm provides itself, the result goes to `dst`.
+    // False when the run stops.
+    fn call_builtin(&mut self, name: &str, args: &[Val], dst: usize) -> bool {
+        if stdlib::is_native(name) {
+            return match self.call_native(name, args, dst) {
+                Some(Some(val)) => {
+                    self.ctx.registers[dst] = val;
+                    true
+                }
+                // an array walk called its first item
+                Some(None) => true,
+                None => false,
+            };
+        }
+        if TIMER_FUNCTIONS.contains(&name) {
+            return match self.call_timer_function(name, args) {
+                Some(val) => {
+                    self.ctx.registers[dst] = val;
+                    true
+                }
+                None => false,
+            };
         }
+        self.ask_host(name, args, dst)
+    }
+    /// The input of the host call the run just paused on, in the typed
+    /// wire encoding.
+    pub fn take_host_input(&mut self) -> Value {
+        self.host_input.take().unwrap_or(Value::Null)
+    }
+    /// Async host calls made since the last call. The vm hands them to the
+    /// host along with the run's result.
+    pub fn take_host_requests(&mut self) -> Vec<HostRequest> {
+        self.host_requests
+            .drain(..)
+            .map(|p| host_request(&p))
+            .collect()
+    }
+    /// Every async host call the host has not settled yet, by call id.
+    pub fn pending_host_requests(&self) -> Vec<HostRequest> {
+        let mut requests: Vec<_> = self.promises.values().map(host_request).collect();
+        requests.sort_by_key(|request| request.call_id);
+        requests
+    }
+    /// Whether an `await` suspended the run until the host settles a promise.
+    pub fn is_awaiting(&self) -> bool {
+        self.awaiting.is_some()
+    }
+    pub fn awaits_call(&self, call_id: i64) -> bool {
+        matches!(&self.awaiting, Some(promise) if promise.borrow().call_id == call_id)
+    }
+    /// Settles the promise of the async host call `call_id` with `value`, or
+    /// rejects it with `value` when `resolved` is false. Returns false when
+    /// no such call is pending. A run awaiting it goes on with op 0x08.
+    pub fn settle_promise(&mut self, call_id: i64, value: Val, resolved: bool) -> bool {
+        let Some(promise) = self.promises.remove(&call_id) else {
+            return false;
+        };
+        promise.borrow_mut().state = if resolved {
+            PromiseState::Resolved(value)
+        } else {
+            PromiseState::Rejected(value)
+        };
+        true
     }
-}
-
-impl Operation for IndexerValue {
-    fn get_state(&self) -> ExecStates {
-        self.state.clone()
+    fn raise_error(&mut self, error: VmError) {
+        self.reserved_error = Some(error);
     }
-
-    fn get_type(&self) -> OperationTypes {
-        self.typ.clone()
+    fn throw_value(&mut self, val: Val) {
+        self.raise_error(VmError::Thrown(val.stringify()));
+        self.thrown = Some(val);
     }
-
-    fn set_state(&mut self, state: ExecStates, data: Box<dyn Any>) {
-        self.state = state.clone();
-        if state == ExecStates::IndexerExtractVarName {
-            self.var = Some(*data.downcast::<Val>().unwrap());
-        } else if state == ExecStates::IndexerExtractIndex {
-            self.index = Some(*data.downcast::<Val>().unwrap());
+    // Hands the pending error to the innermost try statement that still has
+    // a catch or finally clause to run, dropping the frames and walks above
+    // it. Returns false when none takes it and the run fails.
+    fn catch_error(&mut self) -> bool {
+        let Some(error) = self.reserved_error.clone() else {
+            return false;
+        };
+        if !error.is_catchable() {
+            return false;
         }
-    }
-
-    fn get_data(&self) -> Vec<Val> {
-        vec![self.var.clone().unwrap(), self.index.clone().unwrap()]
-    }
-}
-
-struct NotValue {
-    typ: OperationTypes,
-    state: ExecStates,
-    pub value: Option<Val>,
-}
-
-impl NotValue {
-    pub fn new() -> Self {
-        NotValue {
-            typ: OperationTypes::NotVal,
-            state: ExecStates::NotValStarted,
-            value: None,
+        let code = self.code.clone();
+        while let Some(mut frame) = self.try_frames.pop() {
+            let clauses = code.decoded.tries[frame.clauses as usize];
+            let next = match frame.stage {
+                TryStage::Try if clauses.catch.is_some() => TryStage::Catch,
+                TryStage::Try | TryStage::Catch if clauses.finally.is_some() => TryStage::Finally,
+                _ => continue,
+            };
+            let thrown = self.thrown.take();
+            self.reserved_error = None;
+            self.resume_to = None;
+            self.ctx.frames.truncate(frame.frame + 1);
+            self.walks.truncate(frame.walks);
+            let top = self.ctx.frames.last_mut().unwrap();
+            let size = code.program.functions[top.proto].registers as usize;
+            self.ctx.registers.truncate(top.base + size);
+            if next == TryStage::Catch {
+                let (address, slot) = clauses.catch.unwrap();
+                let value = match thrown {
+                    Some(thrown) => thrown,
+                    None => error_value(&error),
+                };
+                match slot {
+                    Some(Slot::Register(r)) => self.ctx.registers[top.base + r as usize] = value,
+                    Some(Slot::Cell(c)) => top.cells[c as usize] = Rc::new(RefCell::new(value)),
+                    None => {}
+                }
+                top.pc = address as usize;
+            } else {
+                frame.pending = Some(Completion::Error(error, thrown));
+                top.pc = clauses.finally.unwrap() as usize;
+            }
+            frame.stage = next;
+            self.try_frames.push(frame);
+            return true;
+        }
+        false
+    }
+    // Called when a try statement's clause reached its `tryend`: the try or
+    // catch body moves on to the finally clause, the finally clause
+    // finishes whatever it held up.
+    fn finish_try_clause(&mut self) {
+        let current = self.ctx.frames.len() - 1;
+        if self
+            .try_frames
+            .last()
+            .is_none_or(|frame| frame.frame != current)
+        {
+            self.raise_error(VmError::BadBytecode(
+                "end of a try statement that is not running".to_string(),
+            ));
+            return;
+        }
+        let mut frame = self.try_frames.pop().unwrap();
+        let clauses = self.code.decoded.tries[frame.clauses as usize];
+        if frame.stage != TryStage::Finally {
+            self.ctx.frames[current].pc = match clauses.finally {
+                Some(finally) => {
+                    frame.stage = TryStage::Finally;
+                    self.try_frames.push(frame);
+                    finally as usize
+                }
+                None => clauses.after as usize,
+            };
+            return;
+        }
+        match frame.pending.take() {
+            None => self.ctx.frames[current].pc = clauses.after as usize,
+            Some(Completion::Error(error, thrown)) => {
+                self.reserved_error = Some(error);
+                self.thrown = thrown;
+            }
+            Some(Completion::Return(val)) => self.unwind_return(val),
+            Some(Completion::Leave(count, target)) => self.leave(count, target),
         }
     }
-}
-
-impl Operation for NotValue {
-    fn get_state(&self) -> ExecStates {
-        self.state.clone()
-    }
-
-    fn get_type(&self) -> OperationTypes {
-        self.typ.clone()
+    // Leaves the `count` innermost try statements of the current frame on
+    // the way to `target`, for a `break` or `continue`. Finally clauses on
+    // the way run first, the jump goes on after them.
+    fn leave(&mut self, mut count: u16, target: u32) {
+        let current = self.ctx.frames.len() - 1;
+        while count > 0 {
+            if self
+                .try_frames
+                .last()
+                .is_none_or(|frame| frame.frame != current)
+            {
+                self.raise_error(VmError::BadBytecode(
+                    "leaving a try statement that is not running".to_string(),
+                ));
+                return;
+            }
+            let mut frame = self.try_frames.pop().unwrap();
+            count -= 1;
+            let clauses = self.code.decoded.tries[frame.clauses as usize];
+            if let (true, Some(finally)) = (frame.stage != TryStage::Finally, clauses.finally) {
+                frame.pending = Some(Completion::Leave(count, target));
+                frame.stage = TryStage::Finally;
+                self.try_frames.push(frame);
+                self.ctx.frames[current].pc = finally as usize;
+                return;
+            }
+        }
+        self.ctx.frames[current].pc = target as usize;
+    }
+    // Leaves the current function with `val`. A try statement on the way
+    // out runs its finally clause first, the return goes on after it.
+    fn unwind_return(&mut self, val: Val) {
+        let current = self.ctx.frames.len() - 1;
+        while self
+            .try_frames
+            .last()
+            .is_some_and(|frame| frame.frame == current)
+        {
+            let mut frame = self.try_frames.pop().unwrap();
+            let clauses = self.code.decoded.tries[frame.clauses as usize];
+            if let (true, Some(finally)) = (frame.stage != TryStage::Finally, clauses.finally) {
+                frame.pending = Some(Completion::Return(val));
+                frame.stage = TryStage::Finally;
+                self.try_frames.push(frame);
+                self.ctx.frames[current].pc = finally as usize;
+                return;
+            }
+        }
+        let frame = self.ctx.frames.pop().unwrap();
+        self.ctx.registers.truncate(frame.base);
+        self.deliver(frame.ret, val);
+    }
+    /// Drops the frames of the run after a script error so the VM stays
+    /// usable for later calls. The error itself travels to the vm boxed in
+    /// a type 252 value.
+    fn take_error_result(&mut self, cb_id: i64) -> Option<(u8, i64, Val)> {
+        let error = self.reserved_error.take()?;
+        self.thrown = None;
+        self.stack_trace = self.capture_stack_trace(self.fault_offset());
+        self.reset_run_state();
+        Some((0x04, cb_id, Val::Error(Rc::new(error))))
+    }
+    /// Shares the queue the timer functions schedule their callbacks on.
+    pub fn set_timer_queue(&mut self, timers: Rc<RefCell<TimerQueue>>) {
+        self.timers = timers;
+    }
+    /// Lets stack traces resolve offsets to statements and source lines.
+    pub fn set_source_map(&mut self, source_map: Option<SourceMap>) {
+        self.source_map = source_map;
+    }
+    pub fn source_map(&self) -> Option<&SourceMap> {
+        self.source_map.as_ref()
+    }
+    pub fn take_stack_trace(&mut self) -> Vec<StackFrame> {
+        std::mem::take(&mut self.stack_trace)
+    }
+    // the offset of the instruction the innermost frame ran last
+    fn fault_offset(&self) -> usize {
+        match self.ctx.frames.last() {
+            Some(frame) => self.code.decoded.offsets[frame.pc.saturating_sub(1)],
+            None => 0,
+        }
     }
-
-    fn set_state(&mut self, state: ExecStates, data: Box<dyn Any>) {
-        self.state = state.clone();
-        if state == ExecStates::NotValFinished {
-            self.value = Some(*data.downcast::<Val>().unwrap());
+    // the offset of the instruction the innermost frame runs next
+    fn next_offset(&self) -> usize {
+        match self.ctx.frames.last() {
+            Some(frame) => self.code.decoded.offsets[frame.pc],
+            None => 0,
         }
     }
-
-    fn get_data(&self) -> Vec<Val> {
-        vec![self.value.clone().unwrap()]
-    }
-}
-
-struct ObjectExpr {
-    typ: OperationTypes,
-    state: ExecStates,
-    pub object_typ_id: i64,
-    pub prop_count: i32,
-    pub props: Vec<Val>,
-}
-
-impl ObjectExpr {
-    pub fn new() -> Self {
-        ObjectExpr {
-            typ: OperationTypes::ObjExpr,
-            state: ExecStates::ObjExprStarted,
-            object_typ_id: 0,
-            prop_count: 0,
-            props: vec![],
+    // Walks the call stack innermost first, the innermost frame being at
+    // `innermost`. A caller's position is the call it made, the instruction
+    // before the one its frame goes on with.
+    fn capture_stack_trace(&self, innermost: usize) -> Vec<StackFrame> {
+        let offsets = &self.code.decoded.offsets;
+        let mut trace = vec![];
+        let count = self.ctx.frames.len();
+        for (i, frame) in self.ctx.frames.iter().enumerate().rev() {
+            let function = match &frame.func {
+                Some(func) => func.borrow().name.clone(),
+                None if self.exec_globally => "<main>".to_string(),
+                None => continue,
+            };
+            let offset = if i + 1 == count {
+                innermost
+            } else {
+                offsets[frame.pc.saturating_sub(1)]
+            };
+            let entry = self.source_map.as_ref().and_then(|map| map.lookup(offset));
+            trace.push(StackFrame {
+                function,
+                offset,
+                path: entry.map(|entry| entry.path.clone()),
+                span: entry.and_then(|entry| entry.span),
+                module: entry.and_then(|entry| entry.module.clone()),
+            });
         }
-    }
-}
-
-impl Operation for ObjectExpr {
-    fn get_state(&self) -> ExecStates {
-        self.state.clone()
-    }
-
-    fn get_type(&self) -> OperationTypes {
-        self.typ.clone()
-    }
-
-    fn set_state(&mut self, state: ExecStates, data: Box<dyn Any>) {
-        self.state = state.clone();
-        if state == ExecStates::ObjExprExtractInfo {
-            let val = data.downcast::<(i64, i32)>().unwrap();
-            self.object_typ_id = val.as_ref().0;
-            self.prop_count = val.as_ref().1;
-        } else if state == ExecStates::ObjExprExtractProp {
-            let val = *data.downcast::<Val>().unwrap();
-            self.props.push(val.clone());
+        trace
+    }
+    /// Charges a fresh allocation against the memory quota. When the
+    /// estimate runs past the limit the live heap is measured again, and if
+    /// the allocation still does not fit a script error is raised.
+    fn allocate(&mut self, bytes: usize) -> bool {
+        if self.memory.needs_scan(bytes) {
+            let live = self.measure_memory();
+            self.memory.rescanned(live);
+            if self.memory.needs_scan(bytes) {
+                self.raise_error(VmError::MemoryLimitExceeded(self.memory.limit.unwrap_or(0)));
+                return false;
+            }
         }
-        if (self.prop_count as usize) == (self.props.len() / 2) {
-            self.state = ExecStates::ObjExprFinished;
+        self.memory.charge(bytes);
+        true
+    }
+    fn measure_memory(&self) -> usize {
+        let mut seen = HashSet::new();
+        let mut live = context_size(&self.ctx, &mut seen);
+        // callbacks keep their captured variables alive for the host
+        for func in self.host_callbacks.values() {
+            let val = Val::Function(func.clone());
+            live += deep_size(&val, &mut seen);
         }
-    }
-
-    fn get_data(&self) -> Vec<Val> {
-        vec![
-            Val {
-                typ: 3,
-                data: Rc::new(RefCell::new(Box::new(self.object_typ_id))),
-            },
-            Val {
-                typ: 2,
-                data: Rc::new(RefCell::new(Box::new(self.prop_count))),
-            },
-            Val {
-                typ: 9,
-                data: Rc::new(RefCell::new(Box::new(Rc::new(RefCell::new(Array::new(
-                    self.props.clone(),
-                )))))),
-            },
-        ]
-    }
-}
-
-struct ArrayExpr {
-    typ: OperationTypes,
-    state: ExecStates,
-    pub item_count: i32,
-    pub items: Vec<Val>,
-}
-
-impl ArrayExpr {
-    pub fn new() -> Self {
-        ArrayExpr {
-            typ: OperationTypes::ArrExpr,
-            state: ExecStates::ArrExprStarted,
-            item_count: 0,
-            items: vec![],
+        // so are the callbacks of pending timers
+        for timer in self.timers.borrow().timers.iter() {
+            let val = Val::Function(timer.func.clone());
+            live += deep_size(&val, &mut seen);
         }
-    }
-}
-
-impl Operation for ArrayExpr {
-    fn get_state(&self) -> ExecStates {
-        self.state.clone()
-    }
-
-    fn get_type(&self) -> OperationTypes {
-        self.typ.clone()
-    }
-
-    fn set_state(&mut self, state: ExecStates, data: Box<dyn Any>) {
-        self.state = state.clone();
-        if state == ExecStates::ArrExprExtractInfo {
-            self.item_count = *data.downcast::<i32>().unwrap();
-        } else if state == ExecStates::ArrExprExtractItem {
-            let val = *data.downcast::<Val>().unwrap();
-            self.items.push(val.clone());
+        // promises the host has yet to settle are kept for it
+        for promise in self.promises.values() {
+            let val = Val::Promise(promise.clone());
+            live += deep_size(&val, &mut seen);
         }
-        if (self.item_count as usize) == self.items.len() {
-            self.state = ExecStates::ArrExprFinished;
+        for walk in self.walks.iter() {
+            for item in walk.items.iter() {
+                live += deep_size(item, &mut seen);
+            }
+            live += deep_size(&walk.acc, &mut seen);
         }
-    }
-
-    fn get_data(&self) -> Vec<Val> {
-        vec![
-            Val {
-                typ: 2,
-                data: Rc::new(RefCell::new(Box::new(self.item_count))),
-            },
-            Val {
-                typ: 9,
-                data: Rc::new(RefCell::new(Box::new(Rc::new(RefCell::new(Array::new(
-                    self.items.clone(),
-                )))))),
-            },
-        ]
-    }
-}
-
-struct CondBranch {
-    typ: OperationTypes,
-    state: ExecStates,
-    pub condition: Option<Val>,
-    pub true_branch: i64,
-    pub false_branch: i64,
-}
-
-impl CondBranch {
-    pub fn new() -> Self {
-        CondBranch {
-            typ: OperationTypes::CondBrch,
-            state: ExecStates::CondBranchStarted,
-            condition: None,
-            true_branch: 0,
-            false_branch: 0,
+        for frame in self.try_frames.iter() {
+            match &frame.pending {
+                Some(Completion::Return(val)) | Some(Completion::Error(_, Some(val))) => {
+                    live += deep_size(val, &mut seen);
+                }
+                _ => {}
+            }
         }
-    }
-}
-
-impl Operation for CondBranch {
-    fn get_state(&self) -> ExecStates {
-        self.state.clone()
-    }
-
-    fn get_type(&self) -> OperationTypes {
-        self.typ.clone()
-    }
-
-    fn set_state(&mut self, state: ExecStates, data: Box<dyn Any>) {
-        self.state = state.clone();
-        if state == ExecStates::CondBranchFinished {
-            let (cond, tb, fb) = *data.downcast::<(Val, i64, i64)>().unwrap().clone();
-            self.condition = Some(cond);
-            self.true_branch = tb;
-            self.false_branch = fb;
+        live
+    }
+    pub fn set_memory_limit(&mut self, limit: Option<usize>) {
+        self.memory.limit = limit;
+    }
+    pub fn memory_limit(&self) -> Option<usize> {
+        self.memory.limit
+    }
+    /// Live bytes reachable from the globals and the call stack, measured
+    /// on demand.
+    pub fn memory_usage(&mut self) -> usize {
+        let live = self.measure_memory();
+        self.memory.rescanned(live);
+        live
+    }
+    /// Bytes charged over the lifetime of the executor.
+    pub fn allocated_bytes(&self) -> u64 {
+        self.memory.allocated
+    }
+    pub fn gc_stats(&self) -> GcStats {
+        self.gc.stats()
+    }
+    /// Frees the reference cycles nothing outside of them holds anymore,
+    /// without waiting for the next collection. Returns how many values
+    /// were freed.
+    pub fn collect_cycles(&mut self) -> usize {
+        self.gc.collect()
+    }
+    /// Captures the runtime state for `VM::snapshot`: the globals, the call
+    /// stack with its registers and where the run stands. A run paused by
+    /// the debugger can not be saved, its pause is not part of the state.
+    pub(crate) fn save(&self, saver: &mut Saver) -> Result<ExecutorSnapshot, VmError> {
+        if self.debugger.paused.is_some() {
+            return Err(snapshot::bad(
+                "a run paused by the debugger can not be saved",
+            ));
         }
-    }
-
-    fn get_data(&self) -> Vec<Val> {
-        vec![
-            self.condition.clone().unwrap(),
-            Val {
-                typ: 3,
-                data: Rc::new(RefCell::new(Box::new(self.true_branch))),
-            },
-            Val {
-                typ: 3,
-                data: Rc::new(RefCell::new(Box::new(self.false_branch))),
-            },
-        ]
-    }
-}
-
-struct CastOp {
-    typ: OperationTypes,
-    state: ExecStates,
-    pub data: Option<Val>,
-    pub target_type: String,
-}
-
-impl CastOp {
-    pub fn new() -> Self {
-        CastOp {
-            typ: OperationTypes::CastOprt,
-            state: ExecStates::CastOprtStarted,
-            data: None,
-            target_type: "".to_string(),
+        let globals = saver.props(&self.ctx.globals)?;
+        let mut classes = vec![];
+        for (name, blueprint) in self.ctx.blueprints.iter() {
+            classes.push((name.clone(), saver.props(&blueprint.def_props)?));
         }
-    }
-}
-
-impl Operation for CastOp {
-    fn get_state(&self) -> ExecStates {
-        self.state.clone()
-    }
-
-    fn get_type(&self) -> OperationTypes {
-        self.typ.clone()
-    }
-
-    fn set_state(&mut self, state: ExecStates, data: Box<dyn Any>) {
-        self.state = state.clone();
-        if state == ExecStates::CastOprtFinished {
-            let (data, tt) = *data.downcast::<(Val, String)>().unwrap().clone();
-            self.data = Some(data);
-            self.target_type = tt;
+        let mut frames = vec![];
+        for frame in self.ctx.frames.iter() {
+            frames.push(FrameData {
+                func: frame
+                    .func
+                    .as_ref()
+                    .map(|func| saver.function(func))
+                    .transpose()?,
+                proto: frame.proto,
+                pc: frame.pc,
+                base: frame.base,
+                cells: frame
+                    .cells
+                    .iter()
+                    .map(|cell| saver.capture(cell))
+                    .collect::<Result<_, _>>()?,
+                upvalues: frame
+                    .upvalues
+                    .iter()
+                    .map(|cell| saver.capture(cell))
+                    .collect::<Result<_, _>>()?,
+                ret: frame.ret,
+            });
         }
-    }
-
-    fn get_data(&self) -> Vec<Val> {
-        vec![
-            self.data.clone().unwrap(),
-            Val {
-                typ: 7,
-                data: Rc::new(RefCell::new(Box::new(self.target_type.clone()))),
+        let mut registers = vec![];
+        for val in self.ctx.registers.iter() {
+            registers.push(saver.val(val)?);
+        }
+        let mut walks = vec![];
+        for walk in self.walks.iter() {
+            walks.push(WalkData {
+                kind: walk.kind.clone(),
+                items: walk
+                    .items
+                    .iter()
+                    .map(|item| saver.val(item))
+                    .collect::<Result<_, _>>()?,
+                func: saver.function(&walk.func)?,
+                acc: saver.val(&walk.acc)?,
+                index: walk.index,
+                dst: walk.dst,
+                frame: walk.frame,
+            });
+        }
+        let mut callbacks = vec![];
+        for (name, func) in self.host_callbacks.iter() {
+            callbacks.push((name.clone(), saver.function(func)?));
+        }
+        callbacks.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut try_frames = vec![];
+        for frame in self.try_frames.iter() {
+            try_frames.push(frame.clone().map_vals(|val| saver.val(&val))?);
+        }
+        let mut call_ids: Vec<&i64> = self.promises.keys().collect();
+        call_ids.sort();
+        let mut promises = vec![];
+        for call_id in call_ids {
+            promises.push(saver.promise(&self.promises[call_id])?);
+        }
+        let awaiting = self
+            .awaiting
+            .as_ref()
+            .map(|promise| saver.promise(promise))
+            .transpose()?;
+        let queue = self.timers.borrow();
+        let mut timers = vec![];
+        for timer in queue.timers.iter() {
+            timers.push(TimerData {
+                id: timer.id,
+                func: saver.function(&timer.func)?,
+                due: timer.due,
+                interval: timer.interval,
+            });
+        }
+        Ok(ExecutorSnapshot {
+            cb_counter: self.cb_counter,
+            run_cb_id: self.run_cb_id,
+            exec_globally: self.exec_globally,
+            processing: self.processing,
+            globals,
+            classes,
+            frames,
+            registers,
+            walks,
+            callbacks,
+            try_frames,
+            promises,
+            awaiting,
+            resume_to: self.resume_to,
+            suspended: self.suspended,
+            fuel: self.fuel,
+            allocated_bytes: self.memory.allocated,
+            timers: TimerQueueData {
+                next_id: queue.next_id,
+                now: queue.now,
+                timers,
+                firing: queue.firing.iter().copied().collect(),
             },
-        ]
+            rng: self.rng,
+        })
     }
-}
-
-struct DummyOp {
-    typ: OperationTypes,
-    state: ExecStates,
-}
-
-impl DummyOp {
-    pub fn new() -> Self {
-        DummyOp {
-            typ: OperationTypes::Dummy,
-            state: ExecStates::Dummy,
+    /// Replaces the runtime state with a saved one. Nothing changes unless
+    /// the whole state loads and fits the program.
+    pub(crate) fn restore(
+        &mut self,
+        state: ExecutorSnapshot,
+        loader: &Loader,
+    ) -> Result<(), VmError> {
+        let code = self.code.clone();
+        let functions = &code.program.functions;
+        let mut registers = vec![];
+        for val in state.registers.into_iter() {
+            registers.push(loader.val(val)?);
         }
-    }
-}
-
-impl Operation for DummyOp {
-    fn get_state(&self) -> ExecStates {
-        self.state.clone()
-    }
-
-    fn get_type(&self) -> OperationTypes {
-        self.typ.clone()
-    }
-
-    fn set_state(&mut self, state: ExecStates, _data: Box<dyn Any>) {
-        self.state = state.clone();
-    }
-
-    fn get_data(&self) -> Vec<Val> {
-        vec![]
-    }
-}
-
-pub struct Executor {
-    executor_id: i16,
-    pointer: usize,
-    end_at: usize,
-    ctx: Context,
-    program: Vec<u8>,
-    cb_counter: i64,
-    pending_func_result_value: Val,
-    registers: Vec<Rc<RefCell<Box<dyn Operation>>>>,
-    _allowed_api: HashMap<String, bool>,
-    run_cb_id: i64,
-    exec_globally: bool,
-    reserved_host_call: Option<(u8, i64, Val)>,
-    pub processing: bool,
-}
-
-impl Executor {
-    pub fn create_in_single_thread(
-        program: Vec<u8>,
-        exec_id: i16,
-        func_group: Vec<String>,
-    ) -> Self {
-        let mut allowed_api: HashMap<String, bool> = HashMap::new();
-        for api_name in func_group.iter() {
-            allowed_api.insert(api_name.clone(), true);
+        let mut frames = vec![];
+        let mut stack_top = 0;
+        for (i, data) in state.frames.into_iter().enumerate() {
+            let fits = data.proto < functions.len() && {
+                let entry = &functions[data.proto];
+                code.decoded.owners.get(data.pc) == Some(&(data.proto as u16))
+                    && data.base == stack_top
+                    && data.cells.len() == entry.cells as usize
+                    && data.upvalues.len() >= entry.upvalues.len()
+                    && (data.func.is_some() || data.proto == 0)
+                    && match data.ret {
+                        Ret::Register(at) => at < data.base,
+                        Ret::Walk => true,
+                        Ret::Entry => i == 0,
+                        Ret::Discard => i > 0,
+                    }
+            };
+            if !fits {
+                return Err(snapshot::bad("call frame does not fit the program"));
+            }
+            stack_top = data.base + functions[data.proto].registers as usize;
+            frames.push(Frame {
+                func: data.func.map(|index| loader.function(index)).transpose()?,
+                proto: data.proto,
+                pc: data.pc,
+                base: data.base,
+                cells: data
+                    .cells
+                    .into_iter()
+                    .map(|index| loader.capture(index))
+                    .collect::<Result<_, _>>()?,
+                upvalues: data
+                    .upvalues
+                    .into_iter()
+                    .map(|index| loader.capture(index))
+                    .collect::<Result<_, _>>()?,
+                ret: data.ret,
+            });
         }
-        Executor {
-            _allowed_api: allowed_api,
-            executor_id: exec_id,
-            pointer: 0,
-            end_at: program.len(),
-            ctx: Context::new(),
-            program,
-            cb_counter: 0,
-            pending_func_result_value: Val::new(254, Rc::new(RefCell::new(Box::new(0)))),
-            registers: vec![],
-            run_cb_id: 0,
-            exec_globally: false,
-            reserved_host_call: None,
-            processing: false,
+        if registers.len() != stack_top {
+            return Err(snapshot::bad("registers do not fit the call stack"));
+        }
+        let mut walks = vec![];
+        for data in state.walks.into_iter() {
+            if !stdlib::ARRAY_WALKS.contains(&data.kind.as_str())
+                || data.index >= data.items.len()
+                || data.frame >= frames.len()
+                || data.dst >= registers.len()
+            {
+                return Err(snapshot::bad("array walk does not fit its array"));
+            }
+            walks.push(Walk {
+                kind: data.kind,
+                items: data
+                    .items
+                    .into_iter()
+                    .map(|item| loader.val(item))
+                    .collect::<Result<_, _>>()?,
+                func: loader.function(data.func)?,
+                acc: loader.val(data.acc)?,
+                index: data.index,
+                dst: data.dst,
+                frame: data.frame,
+            });
+        }
+        let mut host_callbacks = HashMap::new();
+        for (name, index) in state.callbacks.into_iter() {
+            host_callbacks.insert(name, loader.function(index)?);
+        }
+        let mut try_frames = vec![];
+        for frame in state.try_frames.into_iter() {
+            if frame.frame >= frames.len()
+                || frame.walks > walks.len()
+                || frame.clauses as usize >= code.decoded.tries.len()
+                || matches!(frame.pending, Some(Completion::Leave(_, target))
+                    if target as usize >= code.decoded.instrs.len())
+            {
+                return Err(snapshot::bad("try statement does not fit the run"));
+            }
+            try_frames.push(frame.map_vals(|val| loader.val(val))?);
+        }
+        if state.resume_to.is_some_and(|at| at >= registers.len()) {
+            return Err(snapshot::bad("host call answer does not fit the registers"));
         }
+        let mut promises = HashMap::new();
+        for index in state.promises.into_iter() {
+            let promise = loader.promise(index)?;
+            let call_id = promise.borrow().call_id;
+            promises.insert(call_id, promise);
+        }
+        let awaiting = state
+            .awaiting
+            .map(|index| loader.promise(index))
+            .transpose()?;
+        let mut timers = vec![];
+        for timer in state.timers.timers.into_iter() {
+            timers.push(Timer {
+                id: timer.id,
+                func: loader.function(timer.func)?,
+                due: timer.due,
+                interval: timer.interval,
+            });
+        }
+        let mut blueprints = vec![];
+        for (i, (name, props)) in state.classes.iter().enumerate() {
+            let blueprint = Blueprint::new(i as i64 + 1, loader.group_of(props)?);
+            blueprints.push((name.clone(), blueprint));
+        }
+        self.ctx = Context {
+            globals: loader.group_of(&state.globals)?,
+            blueprints,
+            frames,
+            registers,
+        };
+        self.walks = walks;
+        self.host_callbacks = host_callbacks;
+        self.try_frames = try_frames;
+        self.thrown = None;
+        self.promises = promises;
+        self.host_requests = vec![];
+        self.awaiting = awaiting;
+        self.resume_to = state.resume_to;
+        self.suspended = state.suspended;
+        // the queue is shared with the vm, it is refilled in place
+        *self.timers.borrow_mut() = TimerQueue {
+            timers,
+            next_id: state.timers.next_id,
+            now: state.timers.now,
+            firing: state.timers.firing.into(),
+        };
+        self.rng = state.rng;
+        self.cb_counter = state.cb_counter;
+        self.run_cb_id = state.run_cb_id;
+        self.exec_globally = state.exec_globally;
+        self.processing = state.processing;
+        self.fuel = state.fuel;
+        self.result = None;
+        self.reserved_host_call = None;
+        self.reserved_error = None;
+        self.stack_trace = vec![];
+        self.debugger.finish_run();
+        self.memory.allocated = state.allocated_bytes;
+        loader.track(&mut self.gc);
+        let live = self.measure_memory();
+        self.memory.rescanned(live);
+        Ok(())
+    }
+    /// Swaps in a new program between runs. The globals are kept and
+    /// rebound to the new code (see `reload::rebind_globals`); breakpoints
+    /// are cleared since their offsets belong to the old program.
+    pub fn reload_program(
+        &mut self,
+        program: Vec<u8>,
+        source_map: Option<SourceMap>,
+    ) -> ReloadReport {
+        let (code, load_error) = match Code::load(&program) {
+            Ok(code) => (code, None),
+            Err(error) => (Code::default(), Some(error)),
+        };
+        let top_level: Vec<FunctionEntry> = code.program.top_level().cloned().collect();
+        let functions = code.program.functions.get(1..).unwrap_or_default();
+        let mut callbacks: Vec<_> = self.host_callbacks.values().cloned().collect();
+        callbacks.extend(self.timers.borrow().timers.iter().map(|t| t.func.clone()));
+        let report =
+            reload::rebind_globals(&mut self.ctx.globals, &top_level, functions, &callbacks);
+        self.code = Rc::new(code);
+        self.load_error = load_error;
+        self.source_map = source_map;
+        self.debugger.forget_program();
+        report
+    }
+    /// Whether a run is waiting for `resume_with_fuel`.
+    pub fn is_out_of_fuel(&self) -> bool {
+        self.suspended && self.debugger.paused.is_none()
+    }
+    fn reset_run_state(&mut self) {
+        self.ctx.unwind();
+        self.walks.clear();
+        self.try_frames.clear();
+        self.thrown = None;
+        self.awaiting = None;
+        self.resume_to = None;
+        self.reserved_host_call = None;
+        self.reserved_error = None;
+        self.suspended = false;
+        self.result = None;
+        self.debugger.finish_run();
+        self.processing = false;
     }
     pub fn single_thread_operation(
         &mut self,
@@ -1006,3901 +1547,1233 @@ impl Executor {
         cb_id: i64,
         payload: Val,
     ) -> (u8, i64, Val) {
+        // a debugger pause only ends through 0x06 or an abort
+        if self.debugger.paused.is_some() && matches!(op_code, 0x01 | 0x03 | 0x04 | 0x07) {
+            return (0x06, cb_id, Val::Null);
+        }
         match op_code {
             0x01 => {
-                // println!("executor: run_func called");
+                self.reset_run_state();
                 self.run_cb_id = cb_id;
-                if payload.typ != 9 {
+                self.fuel = self.fuel_limit.unwrap_or(0);
+                self.processing = true;
+                if let Some(error) = self.load_error.clone() {
+                    self.raise_error(error);
+                    return self.settle_run(cb_id, Val::Null);
+                }
+                if payload.typ() != 9 {
+                    // a rerun executes in the globals of the first run
                     self.exec_globally = true;
-                    self.processing = true;
-                    let result = self.run_from(
-                        0,
-                        self.program.len(),
-                        false,
-                        Val {
-                            typ: 0,
-                            data: Rc::new(RefCell::new(Box::new(0))),
-                        },
-                        false,
-                    );
-                    if self.reserved_host_call.is_some() {
-                        let host_call_data = self.reserved_host_call.clone().unwrap();
-                        self.reserved_host_call = None;
-                        return host_call_data;
-                    } else if self.pointer == self.ctx.memory.get(0).unwrap().borrow().frozen_end {
-                        self.processing = false;
-                        return (0x01, cb_id, result);
-                    } else {
-                        self.processing = false;
-                        return (
-                            0x00,
-                            0,
-                            Val {
-                                typ: 0,
-                                data: Rc::new(RefCell::new(Box::new(0))),
-                            },
-                        );
+                    let main = &self.code.program.functions[0];
+                    self.ctx.registers = vec![Val::Null; main.registers as usize];
+                    self.ctx.frames.push(Frame {
+                        func: None,
+                        proto: 0,
+                        pc: 0,
+                        base: 0,
+                        cells: (0..main.cells)
+                            .map(|_| Rc::new(RefCell::new(Val::Null)))
+                            .collect(),
+                        upvalues: vec![],
+                        ret: Ret::Entry,
+                    });
+                    let result = self.run();
+                    return self.settle_run(cb_id, result);
+                }
+                self.exec_globally = false;
+                let (callee, input) = match &payload {
+                    Val::Array(arr) => {
+                        let arr = &arr.borrow().data;
+                        let arg = |i: usize| arr.get(i).cloned().unwrap_or(Val::Null);
+                        (arg(0), arg(1))
+                    }
+                    _ => (Val::Null, Val::Null),
+                };
+                // a timer hands over the function itself, the host its name
+                let (func_name, mut val) = match &callee {
+                    Val::Function(func) => (func.borrow().name.clone(), callee.clone()),
+                    Val::Str(name) => (name.to_string(), self.ctx.find_global(name)),
+                    _ => (callee.stringify(), Val::Null),
+                };
+                if val.typ() != 10 {
+                    if let Some(func) = self.host_callbacks.get(&func_name) {
+                        val = Val::Function(func.clone());
                     }
-                } else {
-                    self.exec_globally = false;
-                    self.processing = true;
-                    let arr = payload.as_array();
-                    let func_name = arr.borrow().data[0].as_string();
-                    let input = arr.borrow().data[1].clone();
-                    let val = self.ctx.find_val_in_first_scope(func_name);
-                    if !val.is_empty() {
-                        let func = val.as_func();
-                        let mut m = HashMap::new();
-                        if !func.borrow().params.is_empty() {
-                            m.insert(func.borrow().params[0].clone(), input);
-                        }
-                        self.ctx.push_scope_with_args(
-                            "funcBody".to_string(),
-                            func.borrow().start,
-                            func.borrow().start,
-                            func.borrow().end,
-                            m,
-                        );
-                        let result = self.run_from(
-                            func.borrow().start,
-                            func.borrow().end,
-                            false,
-                            Val {
-                                typ: 0,
-                                data: Rc::new(RefCell::new(Box::new(0))),
-                            },
-                            true,
-                        );
-                        if self.reserved_host_call.is_some() {
-                            let host_call_data = self.reserved_host_call.clone().unwrap();
-                            self.reserved_host_call = None;
-                            return host_call_data;
-                        } else if self.ctx.memory.len() == 1 {
-                            self.processing = false;
-                            return (0x01, cb_id, result);
-                        } else {
-                            self.processing = false;
-                            return (
-                                0x00,
-                                0,
-                                Val {
-                                    typ: 0,
-                                    data: Rc::new(RefCell::new(Box::new(0))),
-                                },
-                            );
-                        }
+                }
+                let Val::Function(func) = val else {
+                    self.raise_error(if val.is_empty() {
+                        VmError::UndefinedVariable(func_name)
                     } else {
-                        panic!("elpian error: global function not found");
+                        VmError::TypeMismatch(format!("'{}' is not a function", func_name))
+                    });
+                    return self.settle_run(cb_id, Val::Null);
+                };
+                if !self.enter_function(&func, &[input], Ret::Entry) {
+                    return self.settle_run(cb_id, Val::Null);
+                }
+                let result = self.run();
+                self.settle_run(cb_id, result)
+            }
+            // answer a host call, the payload is the call's result
+            0x03 => {
+                if self.ctx.frames.is_empty() {
+                    return (0x00, 0, Val::Null);
+                }
+                if payload.typ() != 254 && !self.allocate(deep_size(&payload, &mut HashSet::new()))
+                {
+                    return self.settle_run(cb_id, Val::Null);
+                }
+                if let Some(at) = self.resume_to.take() {
+                    if payload.typ() != 254 {
+                        self.ctx.registers[at] = payload;
                     }
                 }
+                let result = self.run();
+                self.settle_run(cb_id, result)
             }
-            0x02 => {
-                // println!("executor: print_memory called");
-                self.ctx.memory.iter().for_each(|scope| {
-                    scope
-                        .borrow()
-                        .memory
-                        .borrow()
-                        .data
-                        .iter()
-                        .for_each(|(key, val)| {
-                            println!("{{ key: {}, val: {} }}", key, val.stringify());
-                        });
-                });
-                return (
-                    0x00,
-                    0,
-                    Val {
-                        typ: 0,
-                        data: Rc::new(RefCell::new(Box::new(0))),
-                    },
-                );
+            // answer a host call with an error, the payload is thrown where
+            // the call was made
+            0x07 => {
+                if self.ctx.frames.is_empty() {
+                    return (0x00, 0, Val::Null);
+                }
+                self.resume_to = None;
+                self.throw_value(payload);
+                let result = self.run();
+                self.settle_run(cb_id, result)
             }
-            0x03 => {
-                let result = self.run_from(
-                    self.pointer,
-                    self.end_at,
-                    true,
-                    payload,
-                    !self.exec_globally,
-                );
-                if !self.ctx.memory.is_empty() {
-                    if self.exec_globally {
-                        if self.reserved_host_call.is_some() {
-                            let host_call_data = self.reserved_host_call.clone().unwrap();
-                            self.reserved_host_call = None;
-                            return host_call_data;
-                        } else if self.pointer
-                            == self.ctx.memory.get(0).unwrap().borrow().frozen_end
-                        {
-                            self.processing = false;
-                            return (0x01, cb_id, result);
-                        } else {
-                            self.processing = false;
-                            return (
-                                0x00,
-                                0,
-                                Val {
-                                    typ: 0,
-                                    data: Rc::new(RefCell::new(Box::new(0))),
-                                },
-                            );
-                        }
-                    } else {
-                        if self.reserved_host_call.is_some() {
-                            let host_call_data = self.reserved_host_call.clone().unwrap();
-                            self.reserved_host_call = None;
-                            return host_call_data;
-                        } else if self.ctx.memory.len() == 1 {
-                            self.processing = false;
-                            return (0x01, cb_id, result);
-                        } else {
-                            self.processing = false;
-                            return (
-                                0x00,
-                                0,
-                                Val {
-                                    typ: 0,
-                                    data: Rc::new(RefCell::new(Box::new(0))),
-                                },
-                            );
+            // resume a run that awaits a promise the host has settled
+            0x08 => {
+                let Some(promise) = self.awaiting.take() else {
+                    return (0x00, 0, Val::Null);
+                };
+                let state = promise.borrow().state.clone();
+                match state {
+                    PromiseState::Pending => {
+                        self.awaiting = Some(promise);
+                        return (0x07, cb_id, Val::Null);
+                    }
+                    PromiseState::Resolved(value) => {
+                        if let Some(at) = self.resume_to.take() {
+                            self.ctx.registers[at] = value;
                         }
                     }
-                } else {
-                    self.processing = false;
-                    return (
-                        0x00,
-                        0,
-                        Val {
-                            typ: 0,
-                            data: Rc::new(RefCell::new(Box::new(0))),
-                        },
-                    );
+                    PromiseState::Rejected(error) => {
+                        self.resume_to = None;
+                        self.throw_value(error);
+                    }
+                }
+                let result = self.run();
+                self.settle_run(cb_id, result)
+            }
+            // resume after running out of fuel, payload carries the new budget
+            0x04 => {
+                if !self.suspended {
+                    return (0x00, 0, Val::Null);
+                }
+                self.fuel = payload.as_int().unwrap_or(0).max(0) as u64;
+                self.suspended = false;
+                let result = self.run();
+                self.settle_run(cb_id, result)
+            }
+            // resume after a debugger pause, payload carries the step mode
+            0x06 => {
+                let Some(pause) = self.debugger.paused.take() else {
+                    return (0x00, 0, Val::Null);
+                };
+                if pause.reason == PauseReason::Error {
+                    return self.take_error_result(cb_id).unwrap();
                 }
+                let step = payload.as_int().and_then(StepMode::from_code);
+                if step.is_some() {
+                    self.load_statements();
+                }
+                let depth = self.call_depth();
+                self.debugger.resume(self.next_offset(), step, depth);
+                self.suspended = false;
+                let result = self.run();
+                self.settle_run(cb_id, result)
+            }
+            // abort a run that ran out of fuel or is paused
+            0x05 => {
+                self.reset_run_state();
+                (0x00, 0, Val::Null)
             }
             _ => {
                 self.processing = false;
-                return (
-                    0x00,
-                    0,
-                    Val {
-                        typ: 0,
-                        data: Rc::new(RefCell::new(Box::new(0))),
-                    },
-                );
+                (0x00, 0, Val::Null)
             }
         }
     }
-    fn extract_i16(&mut self) -> i16 {
-        let num_bytes: [u8; 2] = self.program[self.pointer..(self.pointer + 2)]
-            .try_into()
-            .unwrap();
-        self.pointer += 2;
-        i16::from_be_bytes(num_bytes)
-    }
-    fn extract_i32(&mut self) -> i32 {
-        let num_bytes: [u8; 4] = self.program[self.pointer..(self.pointer + 4)]
-            .try_into()
-            .unwrap();
-        self.pointer += 4;
-        i32::from_be_bytes(num_bytes)
-    }
-    fn extract_i64(&mut self) -> i64 {
-        let num_bytes: [u8; 8] = self.program[self.pointer..(self.pointer + 8)]
-            .try_into()
-            .unwrap();
-        self.pointer += 8;
-        i64::from_be_bytes(num_bytes)
-    }
-    fn extract_f32(&mut self) -> f32 {
-        let num_bytes: [u8; 4] = self.program[self.pointer..(self.pointer + 4)]
-            .try_into()
-            .unwrap();
-        self.pointer += 4;
-        f32::from_be_bytes(num_bytes)
-    }
-    fn extract_f64(&mut self) -> f64 {
-        let num_bytes: [u8; 8] = self.program[self.pointer..(self.pointer + 8)]
-            .try_into()
-            .unwrap();
-        self.pointer += 8;
-        f64::from_be_bytes(num_bytes)
+    // function activations on the call stack, the main body included
+    fn call_depth(&self) -> usize {
+        self.ctx.frames.len()
+    }
+    /// Replaces the breakpoints. Offsets that do not start a statement are
+    /// dropped; the accepted ones are returned in order.
+    pub fn set_breakpoints(&mut self, offsets: &[usize]) -> Vec<usize> {
+        self.load_statements();
+        self.debugger.set_breakpoints(offsets)
+    }
+    fn load_statements(&mut self) {
+        let statements = &self.code.program.statements;
+        self.debugger
+            .load_statements(|| statements.iter().copied().collect());
+    }
+    pub fn breakpoints(&self) -> Vec<usize> {
+        self.debugger.breakpoints()
+    }
+    pub fn set_pause_on_error(&mut self, enabled: bool) {
+        self.debugger.pause_on_error = enabled;
+    }
+    pub fn pause_on_error(&self) -> bool {
+        self.debugger.pause_on_error
+    }
+    pub fn pause_info(&self) -> Option<PauseInfo> {
+        self.debugger.paused.clone()
+    }
+    /// The scope chain from the globals inwards: the globals with the
+    /// variables of the main body, then every function on the call stack
+    /// with the locals live where it stands.
+    pub fn scope_chain(&self) -> Vec<ScopeView> {
+        let code = &self.code;
+        let mut globals: Vec<(String, String)> = self
+            .ctx
+            .globals
+            .data
+            .iter()
+            .map(|(name, val)| (name.clone(), val.stringify()))
+            .collect();
+        globals.sort();
+        let mut chain = vec![ScopeView {
+            tag: "funcBody".to_string(),
+            function: None,
+            variables: globals,
+        }];
+        let innermost = self.debugger.paused.as_ref().map(|pause| pause.offset);
+        let count = self.ctx.frames.len();
+        for (i, frame) in self.ctx.frames.iter().enumerate() {
+            let offset = match innermost {
+                Some(offset) if i + 1 == count => offset,
+                _ if i + 1 == count => code.decoded.offsets[frame.pc],
+                _ => code.decoded.offsets[frame.pc.saturating_sub(1)],
+            };
+            if let Some(func) = &frame.func {
+                chain.push(ScopeView {
+                    tag: "funcBody".to_string(),
+                    function: Some(func.borrow().name.clone()),
+                    variables: vec![],
+                });
+            }
+            let variables = &mut chain.last_mut().unwrap().variables;
+            let live = code.program.functions[frame.proto]
+                .locals
+                .iter()
+                .filter(|local| local.start <= offset && offset < local.end);
+            for local in live {
+                let val = match local.slot {
+                    Slot::Register(r) => self.ctx.registers[frame.base + r as usize].clone(),
+                    Slot::Cell(c) => frame.cells[c as usize].borrow().clone(),
+                };
+                // an inner block's variable shadows the outer one
+                variables.retain(|(name, _)| *name != local.name);
+                variables.push((local.name.clone(), val.stringify()));
+            }
+            variables.sort();
+        }
+        chain
+    }
+    /// Sets the instruction budget granted to every run (`None` = unlimited).
+    pub fn set_fuel_limit(&mut self, limit: Option<u64>) {
+        self.fuel_limit = limit;
+    }
+    pub fn remaining_fuel(&self) -> Option<u64> {
+        self.fuel_limit.map(|_| self.fuel)
+    }
+    /// Translates the executor state after a run stopped into the reply for
+    /// the vm: an error, a pause, a host call, or the end of the run.
+    fn settle_run(&mut self, cb_id: i64, result: Val) -> (u8, i64, Val) {
+        if self.debugger.pause_on_error && self.debugger.paused.is_none() {
+            if let Some(error) = self.reserved_error.clone() {
+                let offset = self.fault_offset();
+                self.debugger.paused = Some(PauseInfo {
+                    reason: PauseReason::Error,
+                    offset,
+                    stack_trace: self.capture_stack_trace(offset),
+                    error: Some(error),
+                });
+            }
+        }
+        if self.debugger.paused.is_some() {
+            return (0x06, cb_id, Val::Null);
+        }
+        if let Some(error) = self.take_error_result(cb_id) {
+            return error;
+        }
+        if self.suspended {
+            return (0x05, cb_id, Val::Null);
+        }
+        if let Some(host_call_data) = self.reserved_host_call.take() {
+            return host_call_data;
+        }
+        self.processing = false;
+        self.debugger.finish_run();
+        if self.ctx.frames.is_empty() {
+            (0x01, cb_id, result)
+        } else {
+            (0x00, 0, Val::Null)
+        }
     }
-    fn extract_bool(&mut self) -> bool {
-        let result = self.program[self.pointer] == 0x01;
-        self.pointer += 1;
-        result
+    // Runs until the entry function returns, the run pauses or an error no
+    // try statement catches is raised. Returns the run's result.
+    fn run(&mut self) -> Val {
+        loop {
+            self.run_steps();
+            if self.reserved_error.is_none() || !self.catch_error() {
+                break;
+            }
+        }
+        self.result.take().unwrap_or(Val::Null)
     }
-    fn extract_str(&mut self) -> String {
-        let len_bytes: [u8; 4] = self.program[self.pointer..(self.pointer + 4)]
-            .try_into()
-            .unwrap();
-        self.pointer += 4;
-        let length = i32::from_be_bytes(len_bytes) as usize;
-        let str_bytes = self.program[self.pointer..(self.pointer + length)].to_vec();
-        self.pointer += length;
-        String::from_utf8(str_bytes).unwrap()
+    fn check_float_range(&self, num: f64) -> Val {
+        if num.abs() < f32::MAX.into() {
+            Val::F32(num as f32)
+        } else {
+            Val::F64(num)
+        }
     }
-    fn extract_arr(&mut self) -> Rc<RefCell<Array>> {
-        let mut data: Vec<Val> = vec![];
-        let arr_len = self.extract_i32();
-        for _ in 0..arr_len {
-            data.push(self.extract_val());
+    fn check_int_range(&self, num: i64) -> Val {
+        if let Ok(num) = i16::try_from(num) {
+            Val::I16(num)
+        } else if let Ok(num) = i32::try_from(num) {
+            Val::I32(num)
+        } else {
+            Val::I64(num)
         }
-        Rc::new(RefCell::new(Array::new(data)))
     }
-    fn extract_func(&mut self) -> Rc<RefCell<Function>> {
-        let start = self.extract_i64() as usize;
-        let end = self.extract_i64() as usize;
-        let param_count = self.extract_i32();
-        let mut params = vec![];
-        for _i in 0..param_count {
-            params.push(self.extract_str());
+    // the narrowest integer or float holding the result of a numeric operator
+    fn number_of(
+        &self,
+        nums: Numbers,
+        on_ints: fn(i64, i64) -> i64,
+        on_floats: fn(f64, f64) -> f64,
+    ) -> Val {
+        match nums {
+            Numbers::Ints(a, b) => self.check_int_range(on_ints(a, b)),
+            Numbers::Floats(a, b) => self.check_float_range(on_floats(a, b)),
         }
-        Rc::new(RefCell::new(Function::new(
-            "".to_string(),
-            start,
-            end,
-            params,
-        )))
     }
-    fn extract_val(&mut self) -> Val {
-        let p = self.program[self.pointer];
-        self.pointer += 1;
-        match p {
-            0x01 => Val {
-                typ: 1,
-                data: Rc::new(RefCell::new(Box::new(self.extract_i16()))),
-            },
-            0x02 => Val {
-                typ: 2,
-                data: Rc::new(RefCell::new(Box::new(self.extract_i32()))),
-            },
-            0x03 => Val {
-                typ: 3,
-                data: Rc::new(RefCell::new(Box::new(self.extract_i64()))),
-            },
-            0x04 => Val {
-                typ: 4,
-                data: Rc::new(RefCell::new(Box::new(self.extract_f32()))),
-            },
-            0x05 => Val {
-                typ: 5,
-                data: Rc::new(RefCell::new(Box::new(self.extract_f64()))),
-            },
-            0x06 => Val {
-                typ: 6,
-                data: Rc::new(RefCell::new(Box::new(self.extract_bool()))),
-            },
-            0x07 => Val {
-                typ: 7,
-                data: Rc::new(RefCell::new(Box::new(self.extract_str()))),
+    // charges what it adds, before adding it
+    fn operate_sum(&mut self, arg1: Val, arg2: Val) -> Result<Val, VmError> {
+        if let Some(nums) = numbers(&arg1, &arg2) {
+            return Ok(self.number_of(nums, i64::wrapping_add, |a, b| a + b));
+        }
+        Ok(match (&arg1, &arg2) {
+            (Val::Str(_), _) | (_, Val::Str(_)) => match (text_of(&arg1), text_of(&arg2)) {
+                (Some(a), Some(b)) => {
+                    self.reserve(Some(string_size(a.len() + b.len())))?;
+                    Val::string(a + &b)
+                }
+                _ => return Err(mismatch(&arg1, &arg2, "summed")),
             },
-            0x09 => Val {
-                typ: 9,
-                data: Rc::new(RefCell::new(Box::new(self.extract_arr()))),
+            (Val::Bool(a), Val::Bool(b)) => Val::Bool(a ^ b),
+            // the array gets the item in front, in a copy
+            (_, Val::Array(items)) if matches!(arg1.typ(), 1..=6 | 8) => {
+                self.reserve(Some(array_size(items.borrow().data.len() + 1)))?;
+                let mut items = items.borrow().clone_arr();
+                items.data.insert(0, arg1.clone());
+                Val::array(items)
+            }
+            (Val::Array(a), Val::Array(b)) => {
+                let len = a.borrow().data.len() + b.borrow().data.len();
+                self.reserve(Some(array_size(len)))?;
+                let mut items = a.borrow().clone_arr();
+                items.data.extend(b.borrow().data.iter().cloned());
+                Val::array(items)
+            }
+            // other items are pushed in place, only their slot is new
+            (Val::Array(items), _) if (1..=10).contains(&arg2.typ()) => {
+                self.reserve(Some(slot_size()))?;
+                items.borrow_mut().data.push(arg2.clone());
+                arg1.clone()
+            }
+            // the props of the right object are merged into the left one,
+            // only the keys it did not have yet are new
+            (Val::Object(a), Val::Object(b)) => {
+                let added: usize = b
+                    .borrow()
+                    .data
+                    .data
+                    .keys()
+                    .filter(|k| !a.borrow().data.data.contains_key(*k))
+                    .map(|k| entry_size(k))
+                    .sum();
+                self.reserve(Some(added))?;
+                for (k, v) in b.borrow().data.data.iter() {
+                    a.borrow_mut().data.data.insert(k.clone(), v.clone());
+                }
+                arg1.clone()
+            }
+            _ => return Err(mismatch(&arg1, &arg2, "summed")),
+        })
+    }
+    // Charges a value of `bytes` before it is built, so a result that does
+    // not fit in the quota is refused without being allocated. None stands
+    // for a size too big to address at all.
+    fn reserve(&mut self, bytes: Option<usize>) -> Result<(), VmError> {
+        match bytes {
+            Some(bytes) if self.allocate(bytes) => Ok(()),
+            _ => Err(VmError::MemoryLimitExceeded(
+                self.memory.limit.unwrap_or(usize::MAX),
+            )),
+        }
+    }
+    // charges the values it builds itself
+    fn operate_multiply(&mut self, arg1: Val, arg2: Val) -> Result<Val, VmError> {
+        if let Some(nums) = numbers(&arg1, &arg2) {
+            return Ok(self.number_of(nums, i64::wrapping_mul, |a, b| a * b));
+        }
+        // strings and arrays repeat, on either side of the count
+        let repeat = match (arg1.as_int(), arg2.as_int()) {
+            (None, Some(count)) => Some((&arg1, count.max(0))),
+            (Some(count), None) => Some((&arg2, count.max(0))),
+            _ => None,
+        };
+        match repeat {
+            Some((target @ Val::Str(text), count)) => {
+                self.reserve(repeated_size(target, count))?;
+                return Ok(Val::string(text.repeat(count as usize)));
+            }
+            Some((target @ Val::Array(items), count)) => {
+                self.reserve(repeated_size(target, count))?;
+                let items = &items.borrow().data;
+                let len = items.len() * count as usize;
+                let repeated = items.iter().cycle().take(len).cloned().collect();
+                return Ok(Val::array(Array::new(repeated)));
+            }
+            _ => {}
+        }
+        let val = match (&arg1, &arg2) {
+            (Val::F32(_) | Val::F64(_) | Val::Bool(_), Val::Str(_)) => Val::string(
+                text_of(&arg1).unwrap_or_default() + &text_of(&arg2).unwrap_or_default(),
+            ),
+            (Val::Bool(a), Val::Bool(b)) => Val::Bool(*a && *b),
+            // a bool keeps or empties an object or array
+            (Val::Bool(keep), Val::Object(_)) => match keep {
+                true => arg2.clone(),
+                false => Val::object(Object::new(-2, ValGroup::new_empty())),
             },
-            0x0a => Val {
-                typ: 10,
-                data: Rc::new(RefCell::new(Box::new(self.extract_func()))),
+            (Val::Bool(keep), Val::Array(_)) | (Val::Array(_), Val::Bool(keep)) => match keep {
+                true if arg1.typ() == 9 => arg1.clone(),
+                true => arg2.clone(),
+                false => Val::array(Array::new_empty()),
             },
-            0x0b => {
-                let id = self.extract_str();
-                if id == "askHost" {
-                    return Val {
-                        typ: 255,
-                        data: Rc::new(RefCell::new(Box::new(0))),
-                    };
-                } else {
-                    return self.ctx.find_val_globally(id);
-                }
-            }
-            _ => Val {
-                typ: 0,
-                data: Rc::new(RefCell::new(Box::new(0))),
+            _ => return Err(mismatch(&arg1, &arg2, "multiplied")),
+        };
+        if val.typ() == 7 || val.typ() == 9 {
+            self.reserve(Some(shallow_size(&val)))?;
+        }
+        Ok(val)
+    }
+    fn operate_subtract(&self, arg1: Val, arg2: Val) -> Result<Val, VmError> {
+        if let Some(nums) = numbers(&arg1, &arg2) {
+            return Ok(self.number_of(nums, i64::wrapping_sub, |a, b| a - b));
+        }
+        Ok(match (&arg1, &arg2) {
+            // every occurrence of the right side's text is removed
+            (Val::Str(text), _) => match text_of(&arg2) {
+                Some(part) => Val::string(text.replace(&part, "")),
+                None => return Err(mismatch(&arg1, &arg2, "subtracted")),
             },
+            (Val::Object(object), Val::Str(part)) => {
+                Val::string(object.borrow().stringify().replace(&**part, ""))
+            }
+            (Val::Bool(a), Val::Bool(b)) => Val::Bool(a ^ b),
+            (Val::Bool(_), Val::Array(items)) => {
+                items.borrow_mut().data.insert(0, arg1.clone());
+                arg2.clone()
+            }
+            // props holding the same value on both sides are removed
+            (Val::Object(a), Val::Object(b)) => {
+                let removed: Vec<String> = b
+                    .borrow()
+                    .data
+                    .data
+                    .iter()
+                    .filter(|(k, v)| {
+                        a.borrow()
+                            .data
+                            .data
+                            .get(*k)
+                            .is_some_and(|mine| self.is_eq(mine.clone(), (*v).clone()))
+                    })
+                    .map(|(k, _)| k.clone())
+                    .collect();
+                for k in removed.iter() {
+                    a.borrow_mut().data.data.remove(k);
+                }
+                arg1.clone()
+            }
+            // items equal to the right side, or to one of its items, are removed
+            (Val::Array(items), _) if (1..=10).contains(&arg2.typ()) => {
+                let others = match &arg2 {
+                    Val::Array(others) => others.borrow().data.clone(),
+                    _ => vec![arg2.clone()],
+                };
+                let kept: Vec<Val> = items
+                    .borrow()
+                    .data
+                    .iter()
+                    .filter(|item| {
+                        !others
+                            .iter()
+                            .any(|o| self.is_eq((*item).clone(), o.clone()))
+                    })
+                    .cloned()
+                    .collect();
+                items.borrow_mut().data = kept;
+                arg1.clone()
+            }
+            _ => return Err(mismatch(&arg1, &arg2, "subtracted")),
+        })
+    }
+    fn operate_division(&self, arg1: Val, arg2: Val) -> Result<Val, VmError> {
+        match (arg1.as_num(), arg2.as_num()) {
+            (Some(a), Some(b)) => Ok(self.check_float_range(a / b)),
+            _ => Err(mismatch(&arg1, &arg2, "divided")),
         }
     }
-    fn check_float_range(&self, num: f64) -> Val {
-        if num < f32::MAX.into() {
-            return Val {
-                typ: 4,
-                data: Rc::new(RefCell::new(Box::new(num as f32))),
-            };
-        } else {
-            return Val {
-                typ: 5,
-                data: Rc::new(RefCell::new(Box::new(num))),
-            };
+    // an integer remainder by zero is NaN, as it is for floats
+    fn operate_remainder(&self, arg1: Val, arg2: Val) -> Result<Val, VmError> {
+        match numbers(&arg1, &arg2) {
+            Some(Numbers::Ints(a, b)) if b != 0 => Ok(self.check_int_range(a.wrapping_rem(b))),
+            Some(Numbers::Ints(a, b)) => Ok(self.check_float_range(a as f64 % b as f64)),
+            Some(Numbers::Floats(a, b)) => Ok(self.check_float_range(a % b)),
+            None => Err(mismatch(&arg1, &arg2, "divided")),
         }
     }
-    fn check_int_range(&self, num: i64) -> Val {
-        if num < i16::MAX.into() {
-            return Val {
-                typ: 1,
-                data: Rc::new(RefCell::new(Box::new(num as i16))),
-            };
-        } else if num < i32::MAX.into() {
-            return Val {
-                typ: 2,
-                data: Rc::new(RefCell::new(Box::new(num as i32))),
-            };
-        } else {
-            return Val {
-                typ: 3,
-                data: Rc::new(RefCell::new(Box::new(num))),
-            };
-        }
-    }
-    fn operate_sum(&self, arg1: Val, arg2: Val) -> Val {
-        match arg1.typ {
-            1 | 2 | 3 => {
-                let val1 = match arg1.typ {
-                    1 => arg1.as_i16() as i64,
-                    2 => arg1.as_i32() as i64,
-                    3 => arg1.as_i64() as i64,
-                    _ => 0,
-                };
-                match arg2.typ {
-                    1 => {
-                        let val2 = arg2.as_i16() as i64;
-                        self.check_int_range(val1 + val2)
-                    }
-                    2 => {
-                        let val2 = arg2.as_i32() as i64;
-                        self.check_int_range(val1 + val2)
-                    }
-                    3 => {
-                        let val2 = arg2.as_i64() as i64;
-                        self.check_int_range(val1 + val2)
-                    }
-                    4 => {
-                        let val2 = arg2.as_f32() as f64;
-                        let val1_temp = val1 as f64;
-                        self.check_float_range(val1_temp + val2)
-                    }
-                    5 => {
-                        let val2 = arg2.as_f64() as f64;
-                        let val1_temp = val1 as f64;
-                        self.check_float_range(val1_temp + val2)
-                    }
-                    6 => {
-                        panic!("elpian error: boolean and integer can not be summed");
-                    }
-                    7 => {
-                        let val2 = arg2.as_string();
-                        let val1_temp = val1.to_string();
-                        Val {
-                            typ: 7,
-                            data: Rc::new(RefCell::new(Box::new(format!("{}{}", val1_temp, val2)))),
-                        }
-                    }
-                    8 => {
-                        panic!("elpian error: object and integer can not be summed");
-                    }
-                    9 => {
-                        let mut val2 = arg2.as_array().borrow().clone_arr();
-                        val2.data.insert(0, arg1);
-                        Val {
-                            typ: 9,
-                            data: Rc::new(RefCell::new(Box::new(Rc::new(RefCell::new(val2))))),
-                        }
-                    }
-                    10 => {
-                        panic!("elpian error: function and integer can not be summed");
-                    }
-                    _ => {
-                        panic!("elpian error: unknown data type and integer can not be summed");
-                    }
-                }
-            }
-            4 | 5 => {
-                let val1 = match arg1.typ {
-                    4 => arg1.as_f32() as f64,
-                    5 => arg1.as_f64() as f64,
-                    _ => 0.0,
-                };
-                match arg2.typ {
-                    1 => {
-                        let val2 = arg2.as_i16() as f64;
-                        self.check_float_range(val1 + val2)
-                    }
-                    2 => {
-                        let val2 = arg2.as_i32() as f64;
-                        self.check_float_range(val1 + val2)
-                    }
-                    3 => {
-                        let val2 = arg2.as_i64() as f64;
-                        self.check_float_range(val1 + val2)
-                    }
-                    4 => {
-                        let val2 = arg2.as_f32() as f64;
-                        self.check_float_range(val1 + val2)
-                    }
-                    5 => {
-                        let val2 = arg2.as_f64() as f64;
-                        self.check_float_range(val1 + val2)
-                    }
-                    6 => {
-                        panic!("elpian error: boolean and float can not be summed");
-                    }
-                    7 => {
-                        let val2 = arg2.as_string();
-                        let val1_temp = val1.to_string();
-                        Val {
-                            typ: 7,
-                            data: Rc::new(RefCell::new(Box::new(format!("{}{}", val1_temp, val2)))),
-                        }
-                    }
-                    8 => {
-                        panic!("elpian error: object and float can not be summed");
-                    }
-                    9 => {
-                        let mut val2 = arg2.as_array().borrow().clone_arr();
-                        val2.data.insert(0, arg1);
-                        Val {
-                            typ: 9,
-                            data: Rc::new(RefCell::new(Box::new(Rc::new(RefCell::new(val2))))),
-                        }
-                    }
-                    10 => {
-                        panic!("elpian error: function and float can not be summed");
-                    }
-                    _ => {
-                        panic!("elpian error: unknown data type and float can not be summed");
-                    }
-                }
-            }
-            6 => {
-                let val1 = arg1.as_bool();
-                match arg2.typ {
-                    1 => {
-                        panic!("elpian error: bool and integer can not be summed");
-                    }
-                    2 => {
-                        panic!("elpian error: bool and integer can not be summed");
-                    }
-                    3 => {
-                        panic!("elpian error: objeboolt and integer can not be summed");
-                    }
-                    4 => {
-                        panic!("elpian error: bool and float can not be summed");
-                    }
-                    5 => {
-                        panic!("elpian error: bool and float can not be summed");
-                    }
-                    6 => {
-                        let val2 = arg2.as_bool();
-                        Val {
-                            typ: 7,
-                            data: Rc::new(RefCell::new(Box::new(val1 ^ val2))),
-                        }
-                    }
-                    7 => {
-                        let val2 = arg2.as_string();
-                        let val1_temp = val1.to_string();
-                        Val {
-                            typ: 7,
-                            data: Rc::new(RefCell::new(Box::new(format!("{}{}", val1_temp, val2)))),
-                        }
-                    }
-                    8 => {
-                        panic!("elpian error: object and bool can not be summed");
-                    }
-                    9 => {
-                        let mut val2 = arg2.as_array().borrow().clone_arr();
-                        val2.data.insert(0, arg1);
-                        Val {
-                            typ: 9,
-                            data: Rc::new(RefCell::new(Box::new(Rc::new(RefCell::new(val2))))),
-                        }
-                    }
-                    10 => {
-                        panic!("elpian error: function and bool can not be summed");
-                    }
-                    _ => {
-                        panic!("elpian error: unknown data type and bool can not be summed");
-                    }
-                }
-            }
-            7 => {
-                let val1 = arg1.as_string();
-                match arg2.typ {
-                    1 => {
-                        let val2 = arg2.as_i16().to_string();
-                        Val {
-                            typ: 7,
-                            data: Rc::new(RefCell::new(Box::new(format!("{}{}", val1, val2)))),
-                        }
-                    }
-                    2 => {
-                        let val2 = arg2.as_i32().to_string();
-                        Val {
-                            typ: 7,
-                            data: Rc::new(RefCell::new(Box::new(format!("{}{}", val1, val2)))),
-                        }
-                    }
-                    3 => {
-                        let val2 = arg2.as_i64().to_string();
-                        Val {
-                            typ: 7,
-                            data: Rc::new(RefCell::new(Box::new(format!("{}{}", val1, val2)))),
-                        }
-                    }
-                    4 => {
-                        let val2 = arg2.as_f32().to_string();
-                        Val {
-                            typ: 7,
-                            data: Rc::new(RefCell::new(Box::new(format!("{}{}", val1, val2)))),
-                        }
-                    }
-                    5 => {
-                        let val2 = arg2.as_f64().to_string();
-                        Val {
-                            typ: 7,
-                            data: Rc::new(RefCell::new(Box::new(format!("{}{}", val1, val2)))),
-                        }
-                    }
-                    6 => {
-                        let val2 = arg2.as_bool().to_string();
-                        Val {
-                            typ: 7,
-                            data: Rc::new(RefCell::new(Box::new(format!("{}{}", val1, val2)))),
-                        }
-                    }
-                    7 => {
-                        let val2 = arg2.as_string();
-                        Val {
-                            typ: 7,
-                            data: Rc::new(RefCell::new(Box::new(format!("{}{}", val1, val2)))),
-                        }
-                    }
-                    8 => {
-                        let val2 = arg2.as_object().borrow().stringify();
-                        Val {
-                            typ: 7,
-                            data: Rc::new(RefCell::new(Box::new(format!("{}{}", val1, val2)))),
-                        }
-                    }
-                    9 => {
-                        let val2 = arg2.as_array().borrow().stringify();
-                        Val {
-                            typ: 7,
-                            data: Rc::new(RefCell::new(Box::new(format!("{}{}", val1, val2)))),
-                        }
-                    }
-                    10 => {
-                        panic!("elpian error: function and string can not be summed");
-                    }
-                    _ => {
-                        panic!("elpian error: unknown data type and string can not be summed");
-                    }
-                }
-            }
-            8 => {
-                let val1 = arg1.as_object();
-                match arg2.typ {
-                    1 => {
-                        panic!("elpian error: object and integer can not be summed");
-                    }
-                    2 => {
-                        panic!("elpian error: object and integer can not be summed");
-                    }
-                    3 => {
-                        panic!("elpian error: object and integer can not be summed");
-                    }
-                    4 => {
-                        panic!("elpian error: object and float can not be summed");
-                    }
-                    5 => {
-                        panic!("elpian error: object and float can not be summed");
-                    }
-                    6 => {
-                        panic!("elpian error: object and bool can not be summed");
-                    }
-                    7 => {
-                        let val1_temp = val1.borrow().stringify();
-                        let val2 = arg2.as_string();
-                        Val {
-                            typ: 7,
-                            data: Rc::new(RefCell::new(Box::new(format!("{}{}", val1_temp, val2)))),
-                        }
-                    }
-                    8 => {
-                        let val2 = arg2.as_object();
-                        val2.borrow().data.data.iter().for_each(|(k, v)| {
-                            val1.borrow_mut().data.data.insert(k.clone(), v.clone());
-                        });
-                        Val {
-                            typ: 8,
-                            data: Rc::new(RefCell::new(Box::new(val2))),
-                        }
-                    }
-                    9 => {
-                        let mut val2 = arg2.as_array().borrow().clone_arr();
-                        val2.data.insert(0, arg1);
-                        Val {
-                            typ: 9,
-                            data: Rc::new(RefCell::new(Box::new(Rc::new(RefCell::new(val2))))),
-                        }
-                    }
-                    10 => {
-                        panic!("elpian error: function and object can not be summed");
-                    }
-                    _ => {
-                        panic!("elpian error: unknown data type and object can not be summed");
-                    }
-                }
-            }
-            9 => {
-                let val1 = arg1.as_array();
-                match arg2.typ {
-                    1 | 2 | 3 | 4 | 5 | 6 | 8 | 10 => {
-                        val1.borrow_mut().data.push(arg2);
-                        Val {
-                            typ: 9,
-                            data: Rc::new(RefCell::new(Box::new(val1))),
-                        }
-                    }
-                    7 => {
-                        let val1_temp = val1.borrow().stringify();
-                        let val2 = arg2.as_string();
-                        Val {
-                            typ: 7,
-                            data: Rc::new(RefCell::new(Box::new(format!("{}{}", val1_temp, val2)))),
-                        }
-                    }
-                    9 => {
-                        let mut val1 = arg2.as_array().borrow().clone_arr();
-                        val1.data.append(&mut arg2.as_array().borrow().data.clone());
-                        Val {
-                            typ: 9,
-                            data: Rc::new(RefCell::new(Box::new(Rc::new(RefCell::new(val1))))),
-                        }
-                    }
-                    _ => {
-                        panic!("elpian error: unknown data type and array can not be summed");
-                    }
-                }
-            }
-            10 => {
-                panic!("elpian error: function can not be summed with other types");
-            }
-            _ => {
-                panic!("elpian error: unknown type can not be summed with other types");
-            }
-        }
-    }
-    fn operate_multiply(&self, arg1: Val, arg2: Val) -> Val {
-        match arg1.typ {
-            1 | 2 | 3 => {
-                let val1 = match arg1.typ {
-                    1 => arg1.as_i16() as i64,
-                    2 => arg1.as_i32() as i64,
-                    3 => arg1.as_i64() as i64,
-                    _ => 0,
-                };
-                match arg2.typ {
-                    1 => {
-                        let val2 = arg2.as_i16() as i64;
-                        self.check_int_range(val1 * val2)
-                    }
-                    2 => {
-                        let val2 = arg2.as_i32() as i64;
-                        self.check_int_range(val1 * val2)
-                    }
-                    3 => {
-                        let val2 = arg2.as_i64() as i64;
-                        self.check_int_range(val1 * val2)
-                    }
-                    4 => {
-                        let val2 = arg2.as_f32() as f64;
-                        let val1_temp = val1 as f64;
-                        self.check_float_range(val1_temp * val2)
-                    }
-                    5 => {
-                        let val2 = arg2.as_f64() as f64;
-                        let val1_temp = val1 as f64;
-                        self.check_float_range(val1_temp * val2)
-                    }
-                    6 => {
-                        panic!("elpian error: boolean and integer can not be multiplied");
-                    }
-                    7 => {
-                        let val2 = arg2.as_string();
-                        let mut res = "".to_string();
-                        for _i in 0..val1 {
-                            res.push_str(&val2);
-                        }
-                        Val {
-                            typ: 7,
-                            data: Rc::new(RefCell::new(Box::new(res))),
-                        }
-                    }
-                    8 => {
-                        panic!("elpian error: object and integer can not be multiplied");
-                    }
-                    9 => {
-                        let val2 = arg2.as_array();
-                        let mut res: Vec<Val> = vec![];
-                        for _i in 0..val1 {
-                            res.append(&mut val2.borrow().data.clone());
-                        }
-                        Val {
-                            typ: 9,
-                            data: Rc::new(RefCell::new(Box::new(Rc::new(RefCell::new(
-                                Array::new(res),
-                            ))))),
-                        }
-                    }
-                    10 => {
-                        panic!("elpian error: function and integer can not be multiplied");
-                    }
-                    _ => {
-                        panic!("elpian error: unknown data type and integer can not be multiplied");
-                    }
-                }
-            }
-            4 | 5 => {
-                let val1 = match arg1.typ {
-                    4 => arg1.as_f32() as f64,
-                    5 => arg1.as_f64() as f64,
-                    _ => 0.0,
-                };
-                match arg2.typ {
-                    1 => {
-                        let val2 = arg2.as_i16() as f64;
-                        self.check_float_range(val1 * val2)
-                    }
-                    2 => {
-                        let val2 = arg2.as_i32() as f64;
-                        self.check_float_range(val1 * val2)
-                    }
-                    3 => {
-                        let val2 = arg2.as_i64() as f64;
-                        self.check_float_range(val1 * val2)
-                    }
-                    4 => {
-                        let val2 = arg2.as_f32() as f64;
-                        self.check_float_range(val1 * val2)
-                    }
-                    5 => {
-                        let val2 = arg2.as_f64() as f64;
-                        self.check_float_range(val1 * val2)
-                    }
-                    6 => {
-                        panic!("elpian error: boolean and float can not be multiplied");
-                    }
-                    7 => {
-                        let val2 = arg2.as_string();
-                        let val1_temp = val1.to_string();
-                        Val {
-                            typ: 7,
-                            data: Rc::new(RefCell::new(Box::new(format!("{}{}", val1_temp, val2)))),
-                        }
-                    }
-                    8 => {
-                        panic!("elpian error: object and float can not be multiplied");
-                    }
-                    9 => {
-                        panic!("elpian error: array and float can not be multiplied");
-                    }
-                    10 => {
-                        panic!("elpian error: function and float can not be multiplied");
-                    }
-                    _ => {
-                        panic!("elpian error: unknown data type and float can not be multiplied");
-                    }
-                }
-            }
-            6 => {
-                let val1 = arg1.as_bool();
-                match arg2.typ {
-                    1 => {
-                        panic!("elpian error: bool and integer can not be multiplied");
-                    }
-                    2 => {
-                        panic!("elpian error: bool and integer can not be multiplied");
-                    }
-                    3 => {
-                        panic!("elpian error: bool and integer can not be multiplied");
-                    }
-                    4 => {
-                        panic!("elpian error: bool and float can not be multiplied");
-                    }
-                    5 => {
-                        panic!("elpian error: bool and float can not be multiplied");
-                    }
-                    6 => {
-                        let val2 = arg2.as_bool();
-                        Val {
-                            typ: 7,
-                            data: Rc::new(RefCell::new(Box::new(val1 & val2))),
-                        }
-                    }
-                    7 => {
-                        let val2 = arg2.as_string();
-                        let val1_temp = val1.to_string();
-                        Val {
-                            typ: 7,
-                            data: Rc::new(RefCell::new(Box::new(format!("{}{}", val1_temp, val2)))),
-                        }
-                    }
-                    8 => {
-                        if val1 {
-                            return arg2.clone();
-                        } else {
-                            return Val {
-                                typ: 8,
-                                data: Rc::new(RefCell::new(Box::new(Rc::new(RefCell::new(
-                                    Object::new(-2, ValGroup::new_empty()),
-                                ))))),
-                            };
-                        }
-                    }
-                    9 => {
-                        if val1 {
-                            return arg2.clone();
-                        } else {
-                            return Val {
-                                typ: 9,
-                                data: Rc::new(RefCell::new(Box::new(Rc::new(RefCell::new(
-                                    Array::new_empty(),
-                                ))))),
-                            };
-                        }
-                    }
-                    10 => {
-                        panic!("elpian error: function and bool can not be multiplied");
-                    }
-                    _ => {
-                        panic!("elpian error: unknown data type and bool can not be multiplied");
-                    }
-                }
-            }
-            7 => {
-                let val1 = arg1.as_string();
-                match arg2.typ {
-                    1 => {
-                        let mut res = "".to_string();
-                        for _i in 0..arg2.as_i16() {
-                            res.push_str(&val1);
-                        }
-                        Val {
-                            typ: 7,
-                            data: Rc::new(RefCell::new(Box::new(res))),
-                        }
-                    }
-                    2 => {
-                        let mut res = "".to_string();
-                        for _i in 0..arg2.as_i32() {
-                            res.push_str(&val1);
-                        }
-                        Val {
-                            typ: 7,
-                            data: Rc::new(RefCell::new(Box::new(res))),
-                        }
-                    }
-                    3 => {
-                        let mut res = "".to_string();
-                        for _i in 0..arg2.as_i64() {
-                            res.push_str(&val1);
-                        }
-                        Val {
-                            typ: 7,
-                            data: Rc::new(RefCell::new(Box::new(res))),
-                        }
-                    }
-                    4 => {
-                        panic!("elpian error: string and float can not be multiplied");
-                    }
-                    5 => {
-                        panic!("elpian error: string and float can not be multiplied");
-                    }
-                    6 => {
-                        panic!("elpian error: string and bool can not be multiplied");
-                    }
-                    7 => {
-                        panic!("elpian error: string and string can not be multiplied");
-                    }
-                    8 => {
-                        panic!("elpian error: string and object can not be multiplied");
-                    }
-                    9 => {
-                        panic!("elpian error: string and array can not be multiplied");
-                    }
-                    10 => {
-                        panic!("elpian error: string and function can not be multiplied");
-                    }
-                    _ => {
-                        panic!("elpian error: string type and unknown data can not be multiplied");
-                    }
-                }
-            }
-            8 => {
-                panic!("elpian error: object can not be multiplied with other types");
-            }
-            9 => {
-                let val1 = arg1.as_array();
-                match arg2.typ {
-                    1 => {
-                        let mut res: Vec<Val> = vec![];
-                        for _i in 0..arg2.as_i16() {
-                            res.append(&mut val1.borrow().data.clone());
-                        }
-                        Val {
-                            typ: 9,
-                            data: Rc::new(RefCell::new(Box::new(Rc::new(RefCell::new(
-                                Array::new(res),
-                            ))))),
-                        }
-                    }
-                    2 => {
-                        let mut res: Vec<Val> = vec![];
-                        for _i in 0..arg2.as_i32() {
-                            res.append(&mut val1.borrow().data.clone());
-                        }
-                        Val {
-                            typ: 9,
-                            data: Rc::new(RefCell::new(Box::new(Rc::new(RefCell::new(
-                                Array::new(res),
-                            ))))),
-                        }
-                    }
-                    3 => {
-                        let mut res: Vec<Val> = vec![];
-                        for _i in 0..arg2.as_i64() {
-                            res.append(&mut val1.borrow().data.clone());
-                        }
-                        Val {
-                            typ: 9,
-                            data: Rc::new(RefCell::new(Box::new(Rc::new(RefCell::new(
-                                Array::new(res),
-                            ))))),
-                        }
-                    }
-                    4 | 5 => {
-                        panic!("elpian error: array and float can not be multiplied");
-                    }
-                    6 => {
-                        if arg2.as_bool() {
-                            return arg1.clone();
-                        } else {
-                            return Val {
-                                typ: 9,
-                                data: Rc::new(RefCell::new(Box::new(Rc::new(RefCell::new(
-                                    Array::new_empty(),
-                                ))))),
-                            };
-                        }
-                    }
-                    7 => {
-                        panic!("elpian error: array and string can not be multiplied");
-                    }
-                    8 => {
-                        panic!("elpian error: array and object can not be multiplied");
-                    }
-                    10 => {
-                        panic!("elpian error: array and function can not be multiplied");
-                    }
-                    _ => {
-                        panic!("elpian error: unknown data type and array can not be multiplied");
-                    }
-                }
-            }
-            10 => {
-                panic!("elpian error: function can not be multiplied with other types");
-            }
-            _ => {
-                panic!("elpian error: unknown type can not be multiplied with other types");
-            }
-        }
-    }
-    fn operate_subtract(&self, arg1: Val, arg2: Val) -> Val {
-        match arg1.typ {
-            1 | 2 | 3 => {
-                let val1 = match arg1.typ {
-                    1 => arg1.as_i16() as i64,
-                    2 => arg1.as_i32() as i64,
-                    3 => arg1.as_i64() as i64,
-                    _ => 0,
-                };
-                match arg2.typ {
-                    1 => {
-                        let val2 = arg2.as_i16() as i64;
-                        self.check_int_range(val1 - val2)
-                    }
-                    2 => {
-                        let val2 = arg2.as_i32() as i64;
-                        self.check_int_range(val1 - val2)
-                    }
-                    3 => {
-                        let val2 = arg2.as_i64() as i64;
-                        self.check_int_range(val1 - val2)
-                    }
-                    4 => {
-                        let val2 = arg2.as_f32() as f64;
-                        let val1_temp = val1 as f64;
-                        self.check_float_range(val1_temp - val2)
-                    }
-                    5 => {
-                        let val2 = arg2.as_f64() as f64;
-                        let val1_temp = val1 as f64;
-                        self.check_float_range(val1_temp - val2)
-                    }
-                    6 => {
-                        panic!("elpian error: boolean and integer can not be subtracted");
-                    }
-                    7 => {
-                        panic!("elpian error: string can not be subtracted from integer");
-                    }
-                    8 => {
-                        panic!("elpian error: object and integer can not be subtracted");
-                    }
-                    9 => {
-                        panic!("elpian error: array can not be subtracted from integer");
-                    }
-                    10 => {
-                        panic!("elpian error: function and integer can not be subtracted");
-                    }
-                    _ => {
-                        panic!("elpian error: unknown data type and integer can not be subtracted");
-                    }
-                }
-            }
-            4 | 5 => {
-                let val1 = match arg1.typ {
-                    4 => arg1.as_f32() as f64,
-                    5 => arg1.as_f64() as f64,
-                    _ => 0.0,
-                };
-                match arg2.typ {
-                    1 => {
-                        let val2 = arg2.as_i16() as f64;
-                        self.check_float_range(val1 - val2)
-                    }
-                    2 => {
-                        let val2 = arg2.as_i32() as f64;
-                        self.check_float_range(val1 - val2)
-                    }
-                    3 => {
-                        let val2 = arg2.as_i64() as f64;
-                        self.check_float_range(val1 - val2)
-                    }
-                    4 => {
-                        let val2 = arg2.as_f32() as f64;
-                        self.check_float_range(val1 - val2)
-                    }
-                    5 => {
-                        let val2 = arg2.as_f64() as f64;
-                        self.check_float_range(val1 - val2)
-                    }
-                    6 => {
-                        panic!("elpian error: boolean and float can not be subtracted");
-                    }
-                    7 => {
-                        panic!("elpian error: string can not be subtracted from float");
-                    }
-                    8 => {
-                        panic!("elpian error: object and float can not be subtracted");
-                    }
-                    9 => {
-                        panic!("elpian error: array can not be subtracted from float");
-                    }
-                    10 => {
-                        panic!("elpian error: function and float can not be subtracted");
-                    }
-                    _ => {
-                        panic!("elpian error: unknown data type and float can not be subtracted");
-                    }
-                }
-            }
-            6 => {
-                let val1 = arg1.as_bool();
-                match arg2.typ {
-                    1 => {
-                        panic!("elpian error: bool and float can not be subtracted");
-                    }
-                    2 => {
-                        panic!("elpian error: bool and integer can not be subtracted");
-                    }
-                    3 => {
-                        panic!("elpian error: bool and integer can not be subtracted");
-                    }
-                    4 => {
-                        panic!("elpian error: bool and float can not be subtracted");
-                    }
-                    5 => {
-                        panic!("elpian error: bool and float can not be subtracted");
-                    }
-                    6 => {
-                        let val2 = arg2.as_bool();
-                        Val {
-                            typ: 7,
-                            data: Rc::new(RefCell::new(Box::new(val1 ^ val2))),
-                        }
-                    }
-                    7 => {
-                        panic!("elpian error: bool and string can not be subtracted");
-                    }
-                    8 => {
-                        panic!("elpian error: bool and object can not be subtracted");
-                    }
-                    9 => {
-                        let val2 = arg2.as_array();
-                        val2.borrow_mut().data.insert(0, arg1);
-                        Val {
-                            typ: 9,
-                            data: Rc::new(RefCell::new(Box::new(val2))),
-                        }
-                    }
-                    10 => {
-                        panic!("elpian error: function and bool can not be subtracted");
-                    }
-                    _ => {
-                        panic!("elpian error: unknown data type and bool can not be subtracted");
-                    }
-                }
-            }
-            7 => {
-                let mut val1 = arg1.as_string();
-                match arg2.typ {
-                    1 => {
-                        let val2 = arg2.as_i16().to_string();
-                        val1 = val1.replace(&val2, "");
-                        Val {
-                            typ: 7,
-                            data: Rc::new(RefCell::new(Box::new(val1))),
-                        }
-                    }
-                    2 => {
-                        let val2 = arg2.as_i32().to_string();
-                        val1 = val1.replace(&val2, "");
-                        Val {
-                            typ: 7,
-                            data: Rc::new(RefCell::new(Box::new(val1))),
-                        }
-                    }
-                    3 => {
-                        let val2 = arg2.as_i64().to_string();
-                        val1 = val1.replace(&val2, "");
-                        Val {
-                            typ: 7,
-                            data: Rc::new(RefCell::new(Box::new(val1))),
-                        }
-                    }
-                    4 => {
-                        let val2 = arg2.as_f32().to_string();
-                        val1 = val1.replace(&val2, "");
-                        Val {
-                            typ: 7,
-                            data: Rc::new(RefCell::new(Box::new(val1))),
-                        }
-                    }
-                    5 => {
-                        let val2 = arg2.as_f64().to_string();
-                        val1 = val1.replace(&val2, "");
-                        Val {
-                            typ: 7,
-                            data: Rc::new(RefCell::new(Box::new(val1))),
-                        }
-                    }
-                    6 => {
-                        let val2 = arg2.as_bool().to_string();
-                        val1 = val1.replace(&val2, "");
-                        Val {
-                            typ: 7,
-                            data: Rc::new(RefCell::new(Box::new(val1))),
-                        }
-                    }
-                    7 => {
-                        let val2 = arg2.as_string();
-                        val1 = val1.replace(&val2, "");
-                        Val {
-                            typ: 7,
-                            data: Rc::new(RefCell::new(Box::new(val1))),
-                        }
-                    }
-                    8 => {
-                        let val2 = arg2.as_object().borrow().stringify();
-                        val1 = val1.replace(&val2, "");
-                        Val {
-                            typ: 7,
-                            data: Rc::new(RefCell::new(Box::new(val1))),
-                        }
-                    }
-                    9 => {
-                        let val2 = arg2.as_array().borrow().stringify();
-                        val1 = val1.replace(&val2, "");
-                        Val {
-                            typ: 7,
-                            data: Rc::new(RefCell::new(Box::new(val1))),
-                        }
-                    }
-                    10 => {
-                        panic!("elpian error: function and string can not be subtracted");
-                    }
-                    _ => {
-                        panic!("elpian error: unknown data type and string can not be subtracted");
-                    }
-                }
-            }
-            8 => {
-                let val1 = arg1.as_object();
-                match arg2.typ {
-                    1 => {
-                        panic!("elpian error: object and integer can not be subtracted");
-                    }
-                    2 => {
-                        panic!("elpian error: object and integer can not be subtracted");
-                    }
-                    3 => {
-                        panic!("elpian error: object and integer can not be subtracted");
-                    }
-                    4 => {
-                        panic!("elpian error: object and float can not be subtracted");
-                    }
-                    5 => {
-                        panic!("elpian error: object and float can not be subtracted");
-                    }
-                    6 => {
-                        panic!("elpian error: object and bool can not be subtracted");
-                    }
-                    7 => {
-                        let mut val1_temp = val1.borrow().stringify();
-                        let val2 = arg2.as_string();
-                        val1_temp = val1_temp.replace(&val2, "");
-                        Val {
-                            typ: 7,
-                            data: Rc::new(RefCell::new(Box::new(val1_temp))),
-                        }
-                    }
-                    8 => {
-                        let val2 = arg2.as_object();
-                        let mut deleted: Vec<String> = vec![];
-                        val2.borrow().data.data.iter().for_each(|(k, v)| {
-                            if val1.borrow().data.data.contains_key(k) {
-                                let val1_data = &val1.borrow().data.data;
-                                let v2 = val1_data.get(k).unwrap();
-                                if self.is_eq(v.clone(), v2.clone()) {
-                                    deleted.push(k.clone());
-                                }
-                            }
-                        });
-                        deleted.iter().for_each(|k| {
-                            val1.borrow_mut().data.data.remove(&k.clone());
-                        });
-                        Val {
-                            typ: 8,
-                            data: Rc::new(RefCell::new(Box::new(val2))),
-                        }
-                    }
-                    9 => {
-                        panic!("elpian error: array can not be subtracted from object");
-                    }
-                    10 => {
-                        panic!("elpian error: function and integer can not be summed");
-                    }
-                    _ => {
-                        panic!("elpian error: unknown data type and integer can not be summed");
-                    }
-                }
-            }
-            9 => {
-                let val1 = arg1.as_array();
-                match arg2.typ {
-                    1 | 2 | 3 | 4 | 5 | 6 | 7 | 8 | 10 => {
-                        val1.borrow_mut().data = val1
-                            .borrow()
-                            .data
-                            .iter()
-                            .filter_map(|item| {
-                                if self.is_eq(item.clone(), arg2.clone()) {
-                                    return None;
-                                } else {
-                                    return Some(item.clone());
-                                }
-                            })
-                            .collect();
-                        Val {
-                            typ: 9,
-                            data: Rc::new(RefCell::new(Box::new(val1))),
-                        }
-                    }
-                    9 => {
-                        let val2 = arg2.as_array();
-                        val1.borrow_mut().data = val1
-                            .borrow()
-                            .data
-                            .iter()
-                            .filter_map(|item| {
-                                for item2 in val2.borrow().data.iter() {
-                                    if self.is_eq(item.clone(), item2.clone()) {
-                                        return None;
-                                    }
-                                }
-                                return Some(item.clone());
-                            })
-                            .collect();
-                        Val {
-                            typ: 9,
-                            data: Rc::new(RefCell::new(Box::new(val1))),
-                        }
-                    }
-                    _ => {
-                        panic!("elpian error: unknown data type and integer can not be summed");
-                    }
-                }
-            }
-            10 => {
-                panic!("nothing can be subtracted from function");
-            }
-            _ => {
-                panic!("can not subtract unknown type with anything");
-            }
-        }
-    }
-    fn operate_division(&self, arg1: Val, arg2: Val) -> Val {
-        match arg1.typ {
-            1 | 2 | 3 => {
-                let val1 = match arg1.typ {
-                    1 => arg1.as_i16() as f64,
-                    2 => arg1.as_i32() as f64,
-                    3 => arg1.as_i64() as f64,
-                    _ => 0.0,
-                };
-                match arg2.typ {
-                    1 => {
-                        let val2 = arg2.as_i16() as f64;
-                        self.check_float_range(val1 / val2)
-                    }
-                    2 => {
-                        let val2 = arg2.as_i32() as f64;
-                        self.check_float_range(val1 / val2)
-                    }
-                    3 => {
-                        let val2 = arg2.as_i64() as f64;
-                        self.check_float_range(val1 / val2)
-                    }
-                    4 => {
-                        let val2 = arg2.as_f32() as f64;
-                        self.check_float_range(val1 / val2)
-                    }
-                    5 => {
-                        let val2 = arg2.as_f64() as f64;
-                        self.check_float_range(val1 / val2)
-                    }
-                    6 => {
-                        panic!("elpian error: integer and boolean can not be divisioned");
-                    }
-                    7 => {
-                        panic!("elpian error: integer and boolean can not be divisioned");
-                    }
-                    8 => {
-                        panic!("elpian error: integer and object can not be divisioned");
-                    }
-                    9 => {
-                        panic!("elpian error: integer and array can not be divisioned");
-                    }
-                    10 => {
-                        panic!("elpian error: integer and function can not be divisioned");
-                    }
-                    _ => {
-                        panic!("elpian error: integer and unknown data type can not be divisioned");
-                    }
-                }
-            }
-            4 | 5 => {
-                let val1 = match arg1.typ {
-                    4 => arg1.as_f32() as f64,
-                    5 => arg1.as_f64() as f64,
-                    _ => 0.0,
-                };
-                match arg2.typ {
-                    1 => {
-                        let val2 = arg2.as_i16() as f64;
-                        self.check_float_range(val1 / val2)
-                    }
-                    2 => {
-                        let val2 = arg2.as_i32() as f64;
-                        self.check_float_range(val1 / val2)
-                    }
-                    3 => {
-                        let val2 = arg2.as_i64() as f64;
-                        self.check_float_range(val1 / val2)
-                    }
-                    4 => {
-                        let val2 = arg2.as_f32() as f64;
-                        self.check_float_range(val1 / val2)
-                    }
-                    5 => {
-                        let val2 = arg2.as_f64() as f64;
-                        self.check_float_range(val1 / val2)
-                    }
-                    6 => {
-                        panic!("elpian error: float and boolean can not be divisioned");
-                    }
-                    7 => {
-                        panic!("elpian error: float and string can not be divisioned");
-                    }
-                    8 => {
-                        panic!("elpian error: float and object can not be divisioned");
-                    }
-                    9 => {
-                        panic!("elpian error: float and array can not be divisioned");
-                    }
-                    10 => {
-                        panic!("elpian error: float and function can not be divisioned");
-                    }
-                    _ => {
-                        panic!("elpian error: float and unknown data type can not be divisioned");
-                    }
-                }
-            }
-            6 => {
-                panic!("elpian error: bool can not be divisioned with other types");
-            }
-            7 => {
-                panic!("elpian error: bool can not be divisioned with other types");
-            }
-            8 => {
-                panic!("elpian error: object can not be divisioned with other types");
-            }
-            9 => {
-                panic!("elpian error: array can not be divisioned with other types");
-            }
-            10 => {
-                panic!("elpian error: function can not be divisioned with other types");
-            }
-            _ => {
-                panic!("elpian error: unknown type can not be divisioned with other types");
-            }
+    // integers raised to a whole power stay integers unless they overflow
+    fn operate_power(&self, arg1: Val, arg2: Val) -> Result<Val, VmError> {
+        match numbers(&arg1, &arg2) {
+            Some(Numbers::Ints(a, b)) => {
+                let exact = u32::try_from(b).ok().and_then(|b| a.checked_pow(b));
+                Ok(match exact {
+                    Some(num) => self.check_int_range(num),
+                    None => self.check_float_range((a as f64).powf(b as f64)),
+                })
+            }
+            Some(Numbers::Floats(a, b)) => Ok(self.check_float_range(a.powf(b))),
+            None => Err(mismatch(&arg1, &arg2, "raised to a power")),
         }
     }
     fn is_eq(&self, v: Val, v2: Val) -> bool {
-        return match v.typ {
-            1 | 2 | 3 => {
-                let v_val = match v.typ {
-                    1 => v.as_i16() as i64,
-                    2 => v.as_i32() as i64,
-                    3 => v.as_i64() as i64,
-                    _ => 0,
-                };
-                match v2.typ {
-                    1 | 2 | 3 => {
-                        let v2_val = match v2.typ {
-                            1 => v2.as_i16() as i64,
-                            2 => v2.as_i32() as i64,
-                            3 => v2.as_i64() as i64,
-                            _ => 0,
-                        };
-                        v_val == v2_val
-                    }
-                    4 | 5 => {
-                        let v_val_temp = v_val as f64;
-                        let v2_val = match v2.typ {
-                            4 => v2.as_f32() as f64,
-                            5 => v2.as_f64() as f64,
-                            _ => 0.0,
-                        };
-                        v_val_temp == v2_val
-                    }
-                    _ => false,
-                }
-            }
-            4 | 5 => {
-                let v_val = match v.typ {
-                    4 => v.as_f32() as f64,
-                    5 => v.as_f64() as f64,
-                    _ => 0.0,
-                };
-                match v2.typ {
-                    1 | 2 | 3 => {
-                        let v2_val = match v2.typ {
-                            1 => v2.as_i16() as f64,
-                            2 => v2.as_i32() as f64,
-                            3 => v2.as_i64() as f64,
-                            _ => 0.0,
-                        };
-                        v_val == v2_val
-                    }
-                    4 | 5 => {
-                        let v2_val = match v2.typ {
-                            4 => v2.as_f32() as f64,
-                            5 => v2.as_f64() as f64,
-                            _ => 0.0,
-                        };
-                        v_val == v2_val
-                    }
-                    _ => false,
-                }
-            }
-            6 => {
-                let v_val = v.as_bool();
-                match v2.typ {
-                    6 => {
-                        let v2_val = v2.as_bool();
-                        v_val == v2_val
-                    }
-                    _ => false,
-                }
-            }
-            7 => {
-                let v_val = v.as_string();
-                match v2.typ {
-                    7 => {
-                        let v2_val = v2.as_string();
-                        v_val == v2_val
-                    }
-                    _ => false,
-                }
-            }
-            8 => {
-                let v_val = v.as_object();
-                match v2.typ {
-                    8 => {
-                        let v2_val = v2.as_object();
-                        if v_val.borrow().data.data.iter().all(|(k, _d)| {
-                            if !v2_val.borrow().data.data.contains_key(&k.clone()) {
-                                return false;
-                            }
-                            true
-                        }) && v_val.borrow().data.data.iter().all(|(k, _d)| {
-                            if !v2_val.borrow().data.data.contains_key(&k.clone()) {
-                                return false;
-                            }
-                            true
-                        }) {
-                            return v_val.borrow().data.data.iter().all(|(k, d)| {
-                                self.is_eq(
-                                    d.clone(),
-                                    v2_val.borrow().data.data.get(&k.clone()).unwrap().clone(),
-                                )
-                            });
-                        }
-                        false
-                    }
-                    _ => false,
-                }
-            }
-            9 => {
-                let v_val = v.as_array();
-                match v2.typ {
-                    9 => {
-                        let v2_val = v2.as_array();
-                        if v_val.borrow().data.len() != v2_val.borrow().data.len() {
-                            return false;
-                        }
-                        let mut counter: usize = 0;
-                        return v_val.borrow().data.iter().all(|d| {
-                            if self.is_eq(
-                                d.clone(),
-                                v2_val.borrow().data.get(counter).unwrap().clone(),
-                            ) {
-                                counter += 1;
-                                return true;
-                            } else {
-                                return false;
-                            }
-                        });
-                    }
-                    _ => false,
-                }
+        if let Some(nums) = numbers(&v, &v2) {
+            return match nums {
+                Numbers::Ints(a, b) => a == b,
+                Numbers::Floats(a, b) => a == b,
+            };
+        }
+        match (&v, &v2) {
+            (Val::Bool(a), Val::Bool(b)) => a == b,
+            (Val::Str(a), Val::Str(b)) => a == b,
+            (Val::Object(a), Val::Object(b)) => {
+                let (a, b) = (a.borrow(), b.borrow());
+                a.data.data.len() == b.data.data.len()
+                    && a.data.data.iter().all(|(k, d)| match b.data.data.get(k) {
+                        Some(d2) => self.is_eq(d.clone(), d2.clone()),
+                        None => false,
+                    })
+            }
+            (Val::Array(a), Val::Array(b)) => {
+                let (a, b) = (a.borrow(), b.borrow());
+                a.data.len() == b.data.len()
+                    && a.data
+                        .iter()
+                        .zip(b.data.iter())
+                        .all(|(d, d2)| self.is_eq(d.clone(), d2.clone()))
             }
-            10 => {
-                let v_val = v.as_func();
-                match v2.typ {
-                    10 => {
-                        let v2_val = v2.as_func();
-                        v_val.borrow().start == v2_val.borrow().start
-                            && v_val.borrow().end == v2_val.borrow().end
-                    }
-                    _ => false,
-                }
+            (Val::Function(a), Val::Function(b)) => {
+                a.borrow().start == b.borrow().start && a.borrow().end == b.borrow().end
             }
             _ => false,
-        };
-    }
-    fn is_ge(&self, v: Val, v2: Val) -> bool {
-        return match v.typ {
-            1 | 2 | 3 => {
-                let v_val = match v.typ {
-                    1 => v.as_i16() as i64,
-                    2 => v.as_i32() as i64,
-                    3 => v.as_i64() as i64,
-                    _ => 0,
-                };
-                match v2.typ {
-                    1 | 2 | 3 => {
-                        let v2_val = match v2.typ {
-                            1 => v2.as_i16() as i64,
-                            2 => v2.as_i32() as i64,
-                            3 => v2.as_i64() as i64,
-                            _ => 0,
-                        };
-                        v_val > v2_val
-                    }
-                    4 | 5 => {
-                        let v_val_temp = v_val as f64;
-                        let v2_val = match v2.typ {
-                            4 => v2.as_f32() as f64,
-                            5 => v2.as_f64() as f64,
-                            _ => 0.0,
-                        };
-                        v_val_temp > v2_val
-                    }
-                    _ => panic!(
-                        "elpian error: numerical and non numerical values are not comparable unless it is just equality check"
-                    ),
-                }
-            }
-            4 | 5 => {
-                let v_val = match v.typ {
-                    4 => v.as_f32() as f64,
-                    5 => v.as_f64() as f64,
-                    _ => 0.0,
-                };
-                match v2.typ {
-                    1 | 2 | 3 => {
-                        let v2_val = match v2.typ {
-                            1 => v2.as_i16() as f64,
-                            2 => v2.as_i32() as f64,
-                            3 => v2.as_i64() as f64,
-                            _ => 0.0,
-                        };
-                        v_val > v2_val
-                    }
-                    4 | 5 => {
-                        let v2_val = match v2.typ {
-                            4 => v2.as_f32() as f64,
-                            5 => v2.as_f64() as f64,
-                            _ => 0.0,
-                        };
-                        v_val > v2_val
-                    }
-                    _ => panic!(
-                        "elpian error: numerical and non numerical values are not comparable unless it is just equality check"
-                    ),
-                }
-            }
-            6 => {
-                let v_val = v.as_bool();
-                match v2.typ {
-                    6 => {
-                        let v2_val = v2.as_bool();
-                        v_val > v2_val
-                    }
-                    _ => panic!(
-                        "elpian error: boolean and non boolean values are not comparable unless it is just equality check"
-                    ),
-                }
-            }
-            7 => {
-                let v_val = v.as_string();
-                match v2.typ {
-                    6 => {
-                        let v2_val = v2.as_string();
-                        v_val > v2_val
-                    }
-                    _ => panic!(
-                        "elpian error: string and non string values are not comparable unless it is just equality check"
-                    ),
-                }
-            }
-            8 => {
-                let v_val = v.as_object();
-                match v2.typ {
-                    6 => {
-                        let v2_val = v2.as_object();
-                        if v_val.borrow().data.data.iter().all(|(k, _d)| {
-                            if !v2_val.borrow().data.data.contains_key(&k.clone()) {
-                                return false;
-                            }
-                            true
-                        }) && v_val.borrow().data.data.iter().all(|(k, _d)| {
-                            if !v2_val.borrow().data.data.contains_key(&k.clone()) {
-                                return false;
-                            }
-                            true
-                        }) {
-                            let mut counter1 = 0;
-                            let mut counter2 = 0;
-                            v_val.borrow().data.data.iter().for_each(|(k, d)| {
-                                if self.is_ge(
-                                    d.clone(),
-                                    v2_val.borrow().data.data.get(&k.clone()).unwrap().clone(),
-                                ) {
-                                    counter1 += 1;
-                                } else {
-                                    counter2 += 1;
-                                }
-                            });
-                            return counter1 > counter2;
-                        }
-                        false
-                    }
-                    _ => panic!(
-                        "elpian error: object and non object values are not comparable unless it is just equality check"
-                    ),
-                }
-            }
-            9 => {
-                let v_val = v.as_array();
-                match v2.typ {
-                    9 => {
-                        let v2_val = v2.as_array();
-                        if v_val.borrow().data.len() != v2_val.borrow().data.len() {
-                            return false;
-                        }
-                        let mut counter1 = 0;
-                        let mut counter2 = 0;
-                        let mut counter = 0;
-                        v_val.borrow().data.iter().for_each(|d| {
-                            if self.is_ge(
-                                d.clone(),
-                                v2_val.borrow().data.get(counter).unwrap().clone(),
-                            ) {
-                                counter1 += 1;
-                            } else {
-                                counter2 += 1;
-                            }
-                            counter += 1;
-                        });
-                        return counter1 > counter2;
-                    }
-                    _ => panic!(
-                        "elpian error: array and non array values are not comparable unless it is just equality check"
-                    ),
-                }
-            }
-            10 => panic!(
-                "elpian error: function types are not comparable unless it is just equality check"
-            ),
-            _ => panic!("elpian error: unknown types are not comparable"),
-        };
-    }
-    fn is_gee(&self, v: Val, v2: Val) -> bool {
-        return match v.typ {
-            1 | 2 | 3 => {
-                let v_val = match v.typ {
-                    1 => v.as_i16() as i64,
-                    2 => v.as_i32() as i64,
-                    3 => v.as_i64() as i64,
-                    _ => 0,
-                };
-                match v2.typ {
-                    1 | 2 | 3 => {
-                        let v2_val = match v2.typ {
-                            1 => v2.as_i16() as i64,
-                            2 => v2.as_i32() as i64,
-                            3 => v2.as_i64() as i64,
-                            _ => 0,
-                        };
-                        v_val >= v2_val
-                    }
-                    4 | 5 => {
-                        let v_val_temp = v_val as f64;
-                        let v2_val = match v2.typ {
-                            4 => v2.as_f32() as f64,
-                            5 => v2.as_f64() as f64,
-                            _ => 0.0,
-                        };
-                        v_val_temp >= v2_val
-                    }
-                    _ => panic!(
-                        "elpian error: numerical and non numerical values are not comparable unless it is just equality check"
-                    ),
-                }
-            }
-            4 | 5 => {
-                let v_val = match v.typ {
-                    4 => v.as_f32() as f64,
-                    5 => v.as_f64() as f64,
-                    _ => 0.0,
-                };
-                match v2.typ {
-                    1 | 2 | 3 => {
-                        let v2_val = match v2.typ {
-                            1 => v2.as_i16() as f64,
-                            2 => v2.as_i32() as f64,
-                            3 => v2.as_i64() as f64,
-                            _ => 0.0,
-                        };
-                        v_val >= v2_val
-                    }
-                    4 | 5 => {
-                        let v2_val = match v2.typ {
-                            4 => v2.as_f32() as f64,
-                            5 => v2.as_f64() as f64,
-                            _ => 0.0,
-                        };
-                        v_val >= v2_val
-                    }
-                    _ => panic!(
-                        "elpian error: numerical and non numerical values are not comparable unless it is just equality check"
-                    ),
+        }
+    }
+    // `holds` tells whether the ordering of v to v2 satisfies the operator.
+    // Numbers, bools and strings compare among themselves; objects with the
+    // same keys and arrays of the same length hold when most of their items
+    // do
+    fn is_ordered(&self, v: Val, v2: Val, holds: fn(Ordering) -> bool) -> Result<bool, VmError> {
+        if let Some(nums) = numbers(&v, &v2) {
+            return Ok(match nums {
+                Numbers::Ints(a, b) => holds(a.cmp(&b)),
+                Numbers::Floats(a, b) => a.partial_cmp(&b).is_some_and(holds),
+            });
+        }
+        let majority = |pairs: Vec<(Val, Val)>| -> Result<bool, VmError> {
+            let mut held = 0;
+            for (d, d2) in pairs.iter() {
+                if self.is_ordered(d.clone(), d2.clone(), holds)? {
+                    held += 1;
                 }
             }
-            6 => {
-                let v_val = v.as_bool();
-                match v2.typ {
-                    6 => {
-                        let v2_val = v2.as_bool();
-                        v_val >= v2_val
-                    }
-                    _ => panic!(
-                        "elpian error: boolean and non boolean values are not comparable unless it is just equality check"
-                    ),
+            Ok(held * 2 > pairs.len())
+        };
+        Ok(match (&v, &v2) {
+            (Val::Bool(a), Val::Bool(b)) => holds(a.cmp(b)),
+            (Val::Str(a), Val::Str(b)) => holds(a.cmp(b)),
+            (Val::Object(a), Val::Object(b)) => {
+                let pairs: Option<Vec<(Val, Val)>> = a
+                    .borrow()
+                    .data
+                    .data
+                    .iter()
+                    .map(|(k, d)| {
+                        b.borrow()
+                            .data
+                            .data
+                            .get(k)
+                            .map(|d2| (d.clone(), d2.clone()))
+                    })
+                    .collect();
+                match pairs {
+                    Some(pairs) if pairs.len() == b.borrow().data.data.len() => majority(pairs)?,
+                    _ => false,
                 }
             }
-            7 => {
-                let v_val = v.as_string();
-                match v2.typ {
-                    6 => {
-                        let v2_val = v2.as_string();
-                        v_val >= v2_val
-                    }
-                    _ => panic!(
-                        "elpian error: string and non string values are not comparable unless it is just equality check"
-                    ),
+            (Val::Array(a), Val::Array(b)) => {
+                if a.borrow().data.len() != b.borrow().data.len() {
+                    return Ok(false);
                 }
+                let pairs = a
+                    .borrow()
+                    .data
+                    .iter()
+                    .cloned()
+                    .zip(b.borrow().data.iter().cloned())
+                    .collect();
+                majority(pairs)?
             }
-            8 => {
-                let v_val = v.as_object();
-                match v2.typ {
-                    6 => {
-                        let v2_val = v2.as_object();
-                        if v_val.borrow().data.data.iter().all(|(k, _d)| {
-                            if !v2_val.borrow().data.data.contains_key(&k.clone()) {
-                                return false;
-                            }
-                            true
-                        }) && v_val.borrow().data.data.iter().all(|(k, _d)| {
-                            if !v2_val.borrow().data.data.contains_key(&k.clone()) {
-                                return false;
-                            }
-                            true
-                        }) {
-                            let mut counter1 = 0;
-                            let mut counter2 = 0;
-                            v_val.borrow().data.data.iter().for_each(|(k, d)| {
-                                if self.is_gee(
-                                    d.clone(),
-                                    v2_val.borrow().data.data.get(&k.clone()).unwrap().clone(),
-                                ) {
-                                    counter1 += 1;
-                                } else {
-                                    counter2 += 1;
-                                }
-                            });
-                            return counter1 >= counter2;
-                        }
-                        false
+            _ => {
+                let kind = match v.typ() {
+                    1..=5 => "numerical and non numerical values are",
+                    6 => "boolean and non boolean values are",
+                    7 => "string and non string values are",
+                    8 => "object and non object values are",
+                    9 => "array and non array values are",
+                    10 => "function types are",
+                    _ => {
+                        return Err(VmError::TypeMismatch(
+                            "unknown types are not comparable".to_string(),
+                        ))
                     }
-                    _ => panic!(
-                        "elpian error: object and non object values are not comparable unless it is just equality check"
-                    ),
-                }
+                };
+                return Err(VmError::TypeMismatch(format!(
+                    "{} not comparable unless it is just equality check",
+                    kind
+                )));
+            }
+        })
+    }
+    fn is_ge(&self, v: Val, v2: Val) -> Result<bool, VmError> {
+        self.is_ordered(v, v2, Ordering::is_gt)
+    }
+    fn is_gee(&self, v: Val, v2: Val) -> Result<bool, VmError> {
+        self.is_ordered(v, v2, Ordering::is_ge)
+    }
+    fn is_le(&self, v: Val, v2: Val) -> Result<bool, VmError> {
+        self.is_ordered(v, v2, Ordering::is_lt)
+    }
+    fn is_lee(&self, v: Val, v2: Val) -> Result<bool, VmError> {
+        self.is_ordered(v, v2, Ordering::is_le)
+    }
+    fn binary(&mut self, op: BinOp, a: Val, b: Val) -> Result<Val, VmError> {
+        match op {
+            BinOp::Eq => Ok(Val::Bool(self.is_eq(a, b))),
+            BinOp::Ne => Ok(Val::Bool(!self.is_eq(a, b))),
+            BinOp::Gt => self.is_ge(a, b).map(Val::Bool),
+            BinOp::Ge => self.is_gee(a, b).map(Val::Bool),
+            BinOp::Lt => self.is_le(a, b).map(Val::Bool),
+            BinOp::Le => self.is_lee(a, b).map(Val::Bool),
+            BinOp::Add => self.operate_sum(a, b),
+            BinOp::Sub => self.operate_subtract(a, b),
+            BinOp::Mul => self.operate_multiply(a, b),
+            BinOp::Div => self.operate_division(a, b),
+            BinOp::Mod => self.operate_remainder(a, b),
+            BinOp::Pow => self.operate_power(a, b),
+        }
+    }
+    // A global by name. The host call functions can not be shadowed, the
+    // timer functions and library namespaces only fill in for names the
+    // script leaves undefined.
+    fn global(&self, name: &str) -> Val {
+        if name == "askHost" || name == "askHostAsync" {
+            return Val::Native(name.into());
+        }
+        match self.ctx.globals.data.get(name) {
+            Some(val) if !val.is_empty() => val.clone(),
+            _ if TIMER_FUNCTIONS.contains(&name) => Val::Native(name.into()),
+            _ => stdlib::namespace(name).unwrap_or(Val::Null),
+        }
+    }
+    // `target[index] = value` on the variable `name` holds. False when an
+    // error was raised.
+    fn set_index(&mut self, name: &str, indexed: Val, index: Val, data: Val) -> bool {
+        if indexed.is_empty() {
+            self.raise_error(VmError::UndefinedVariable(name.to_string()));
+            return false;
+        }
+        match (&indexed, &index) {
+            (Val::Object(obj), Val::Str(key)) => {
+                let key = key.to_string();
+                if !obj.borrow().data.data.contains_key(&key) && !self.allocate(entry_size(&key)) {
+                    return false;
+                }
+                if gc::is_reference(&data) {
+                    self.gc.track(&indexed);
+                }
+                obj.borrow_mut().data.data.insert(key, data);
+            }
+            (_, Val::Str(_)) => {
+                self.raise_error(VmError::TypeMismatch(
+                    "non object value can not be indexed by string".to_string(),
+                ));
+                return false;
+            }
+            (Val::Array(arr), _) if index.as_int().is_some() => {
+                let position = index.as_int().unwrap_or(-1);
+                let len = arr.borrow().data.len();
+                if position < 0 || position as usize >= len {
+                    self.raise_error(VmError::IndexOutOfBounds {
+                        index: position,
+                        len,
+                    });
+                    return false;
+                }
+                if gc::is_reference(&data) {
+                    self.gc.track(&indexed);
+                }
+                arr.borrow_mut().data[position as usize] = data;
+            }
+            _ if index.as_int().is_some() => {
+                self.raise_error(VmError::TypeMismatch(
+                    "non array value can not be indexed by integer".to_string(),
+                ));
+                return false;
             }
-            9 => {
-                let v_val = v.as_array();
-                match v2.typ {
-                    9 => {
-                        let v2_val = v2.as_array();
-                        if v_val.borrow().data.len() != v2_val.borrow().data.len() {
-                            return false;
-                        }
-                        let mut counter1 = 0;
-                        let mut counter2 = 0;
-                        let mut counter = 0;
-                        v_val.borrow().data.iter().for_each(|d| {
-                            if self.is_gee(
-                                d.clone(),
-                                v2_val.borrow().data.get(counter).unwrap().clone(),
-                            ) {
-                                counter1 += 1;
-                            } else {
-                                counter2 += 1;
-                            }
-                            counter += 1;
-                        });
-                        return counter1 >= counter2;
-                    }
-                    _ => panic!(
-                        "elpian error: array and non array values are not comparable unless it is just equality check"
-                    ),
-                }
+            _ => {
+                self.raise_error(VmError::TypeMismatch(
+                    "types other than integer and string can not be used to index anything"
+                        .to_string(),
+                ));
+                return false;
             }
-            10 => panic!(
-                "elpian error: function types are not comparable unless it is just equality check"
-            ),
-            _ => panic!("elpian error: unknown types are not comparable"),
-        };
+        }
+        true
     }
-    fn is_le(&self, v: Val, v2: Val) -> bool {
-        return match v.typ {
-            1 | 2 | 3 => {
-                let v_val = match v.typ {
-                    1 => v.as_i16() as i64,
-                    2 => v.as_i32() as i64,
-                    3 => v.as_i64() as i64,
-                    _ => 0,
-                };
-                match v2.typ {
-                    1 | 2 | 3 => {
-                        let v2_val = match v2.typ {
-                            1 => v2.as_i16() as i64,
-                            2 => v2.as_i32() as i64,
-                            3 => v2.as_i64() as i64,
-                            _ => 0,
-                        };
-                        v_val < v2_val
-                    }
-                    4 | 5 => {
-                        let v_val_temp = v_val as f64;
-                        let v2_val = match v2.typ {
-                            4 => v2.as_f32() as f64,
-                            5 => v2.as_f64() as f64,
-                            _ => 0.0,
-                        };
-                        v_val_temp < v2_val
+    // The dispatch loop. It returns once the entry function returned, on a
+    // host call, an await, a pause or an error.
+    fn run_steps(&mut self) {
+        if self.reserved_error.is_some() {
+            return;
+        }
+        let code = self.code.clone();
+        let decoded = &code.decoded;
+        let Some(frame) = self.ctx.frames.last() else {
+            return;
+        };
+        // the position of the innermost frame is kept here while it runs;
+        // it goes back to the frame before anything pushes or pops frames
+        let mut pc = frame.pc;
+        let mut base = frame.base;
+        macro_rules! reg {
+            ($r:expr) => {
+                self.ctx.registers[base + $r as usize]
+            };
+        }
+        macro_rules! save {
+            () => {
+                self.ctx.frames.last_mut().unwrap().pc = pc
+            };
+        }
+        macro_rules! load {
+            () => {
+                match self.ctx.frames.last() {
+                    Some(frame) => {
+                        pc = frame.pc;
+                        base = frame.base;
                     }
-                    _ => panic!(
-                        "elpian error: numerical and non numerical values are not comparable unless it is just equality check"
-                    ),
+                    None => return,
                 }
+            };
+        }
+        macro_rules! fail {
+            ($error:expr) => {{
+                self.raise_error($error);
+                break;
+            }};
+        }
+        loop {
+            // every value lives in the registers or the frames here
+            if self.gc.is_due() {
+                self.gc.collect();
             }
-            4 | 5 => {
-                let v_val = match v.typ {
-                    4 => v.as_f32() as f64,
-                    5 => v.as_f64() as f64,
-                    _ => 0.0,
+            if self.debugger.is_armed() {
+                let depth = if self.debugger.is_stepping() {
+                    self.call_depth()
+                } else {
+                    0
                 };
-                match v2.typ {
-                    1 | 2 | 3 => {
-                        let v2_val = match v2.typ {
-                            1 => v2.as_i16() as f64,
-                            2 => v2.as_i32() as f64,
-                            3 => v2.as_i64() as f64,
-                            _ => 0.0,
-                        };
-                        v_val < v2_val
-                    }
-                    4 | 5 => {
-                        let v2_val = match v2.typ {
-                            4 => v2.as_f32() as f64,
-                            5 => v2.as_f64() as f64,
-                            _ => 0.0,
-                        };
-                        v_val < v2_val
-                    }
-                    _ => panic!(
-                        "elpian error: numerical and non numerical values are not comparable unless it is just equality check"
-                    ),
+                let offset = decoded.offsets[pc];
+                if let Some(reason) = self.debugger.should_pause(offset, depth) {
+                    save!();
+                    self.debugger.paused = Some(PauseInfo {
+                        reason,
+                        offset,
+                        stack_trace: self.capture_stack_trace(offset),
+                        error: None,
+                    });
+                    self.suspended = true;
+                    break;
                 }
             }
-            6 => {
-                let v_val = v.as_bool();
-                match v2.typ {
-                    6 => {
-                        let v2_val = v2.as_bool();
-                        v_val < v2_val
-                    }
-                    _ => panic!(
-                        "elpian error: boolean and non boolean values are not comparable unless it is just equality check"
-                    ),
+            if self.fuel_limit.is_some() {
+                if self.fuel == 0 {
+                    self.suspended = true;
+                    break;
                 }
+                self.fuel -= 1;
             }
-            7 => {
-                let v_val = v.as_string();
-                match v2.typ {
-                    6 => {
-                        let v2_val = v2.as_string();
-                        v_val < v2_val
+            let instr = decoded.instrs[pc];
+            pc += 1;
+            match instr {
+                Instr::Nop => {}
+                Instr::Const(r, k) => {
+                    let val = code.consts[k as usize].clone();
+                    if val.typ() == 7 && !self.allocate(shallow_size(&val)) {
+                        break;
                     }
-                    _ => panic!(
-                        "elpian error: string and non string values are not comparable unless it is just equality check"
-                    ),
+                    reg!(r) = val;
                 }
-            }
-            8 => {
-                let v_val = v.as_object();
-                match v2.typ {
-                    6 => {
-                        let v2_val = v2.as_object();
-                        if v_val.borrow().data.data.iter().all(|(k, _d)| {
-                            if !v2_val.borrow().data.data.contains_key(&k.clone()) {
-                                return false;
-                            }
-                            true
-                        }) && v_val.borrow().data.data.iter().all(|(k, _d)| {
-                            if !v2_val.borrow().data.data.contains_key(&k.clone()) {
-                                return false;
-                            }
-                            true
-                        }) {
-                            let mut counter1 = 0;
-                            let mut counter2 = 0;
-                            v_val.borrow().data.data.iter().for_each(|(k, d)| {
-                                if self.is_le(
-                                    d.clone(),
-                                    v2_val.borrow().data.data.get(&k.clone()).unwrap().clone(),
-                                ) {
-                                    counter1 += 1;
-                                } else {
-                                    counter2 += 1;
-                                }
-                            });
-                            return counter1 < counter2;
-                        }
-                        false
+                Instr::Null(r) => reg!(r) = Val::Null,
+                Instr::Bool(r, b) => reg!(r) = Val::Bool(b),
+                Instr::Move(d, s) => reg!(d) = reg!(s).clone(),
+                Instr::GetGlobal(r, k) => reg!(r) = self.global(code.name(k)),
+                Instr::DefGlobal(k, r) => {
+                    let name = code.name(k);
+                    if !self.allocate(entry_size(name)) {
+                        break;
                     }
-                    _ => panic!(
-                        "elpian error: object and non object values are not comparable unless it is just equality check"
-                    ),
+                    let val = reg!(r).clone();
+                    self.ctx.define_global(name, val);
                 }
-            }
-            9 => {
-                let v_val = v.as_array();
-                match v2.typ {
-                    9 => {
-                        let v2_val = v2.as_array();
-                        if v_val.borrow().data.len() != v2_val.borrow().data.len() {
-                            return false;
-                        }
-                        let mut counter1 = 0;
-                        let mut counter2 = 0;
-                        let mut counter = 0;
-                        v_val.borrow().data.iter().for_each(|d| {
-                            if self.is_le(
-                                d.clone(),
-                                v2_val.borrow().data.get(counter).unwrap().clone(),
-                            ) {
-                                counter1 += 1;
-                            } else {
-                                counter2 += 1;
-                            }
-                            counter += 1;
-                        });
-                        return counter1 < counter2;
-                    }
-                    _ => panic!(
-                        "elpian error: array and non array values are not comparable unless it is just equality check"
-                    ),
+                Instr::SetGlobal(k, r) => {
+                    let val = reg!(r).clone();
+                    self.ctx.update_global(code.name(k), val);
                 }
-            }
-            10 => panic!(
-                "elpian error: function types are not comparable unless it is just equality check"
-            ),
-            _ => panic!("elpian error: unknown types are not comparable"),
-        };
-    }
-    fn is_lee(&self, v: Val, v2: Val) -> bool {
-        return match v.typ {
-            1 | 2 | 3 => {
-                let v_val = match v.typ {
-                    1 => v.as_i16() as i64,
-                    2 => v.as_i32() as i64,
-                    3 => v.as_i64() as i64,
-                    _ => 0,
-                };
-                match v2.typ {
-                    1 | 2 | 3 => {
-                        let v2_val = match v2.typ {
-                            1 => v2.as_i16() as i64,
-                            2 => v2.as_i32() as i64,
-                            3 => v2.as_i64() as i64,
-                            _ => 0,
-                        };
-                        v_val <= v2_val
-                    }
-                    4 | 5 => {
-                        let v_val_temp = v_val as f64;
-                        let v2_val = match v2.typ {
-                            4 => v2.as_f32() as f64,
-                            5 => v2.as_f64() as f64,
-                            _ => 0.0,
-                        };
-                        v_val_temp <= v2_val
-                    }
-                    _ => panic!(
-                        "elpian error: numerical and non numerical values are not comparable unless it is just equality check"
-                    ),
+                Instr::GetCell(r, c) => {
+                    let val = self.ctx.frames.last().unwrap().cells[c as usize]
+                        .borrow()
+                        .clone();
+                    reg!(r) = val;
                 }
-            }
-            4 | 5 => {
-                let v_val = match v.typ {
-                    4 => v.as_f32() as f64,
-                    5 => v.as_f64() as f64,
-                    _ => 0.0,
-                };
-                match v2.typ {
-                    1 | 2 | 3 => {
-                        let v2_val = match v2.typ {
-                            1 => v2.as_i16() as f64,
-                            2 => v2.as_i32() as f64,
-                            3 => v2.as_i64() as f64,
-                            _ => 0.0,
-                        };
-                        v_val <= v2_val
+                Instr::SetCell(c, r) => {
+                    let val = reg!(r).clone();
+                    let cell = &self.ctx.frames.last().unwrap().cells[c as usize];
+                    if gc::is_reference(&val) {
+                        self.gc.track_cell(cell);
                     }
-                    4 | 5 => {
-                        let v2_val = match v2.typ {
-                            4 => v2.as_f32() as f64,
-                            5 => v2.as_f64() as f64,
-                            _ => 0.0,
-                        };
-                        v_val <= v2_val
-                    }
-                    _ => panic!(
-                        "elpian error: numerical and non numerical values are not comparable unless it is just equality check"
-                    ),
+                    *cell.borrow_mut() = val;
                 }
-            }
-            6 => {
-                let v_val = v.as_bool();
-                match v2.typ {
-                    6 => {
-                        let v2_val = v2.as_bool();
-                        v_val <= v2_val
+                Instr::NewCell(c) => {
+                    self.ctx.frames.last_mut().unwrap().cells[c as usize] =
+                        Rc::new(RefCell::new(Val::Null));
+                }
+                Instr::GetUpvalue(r, u) => {
+                    let val = self.ctx.frames.last().unwrap().upvalues[u as usize]
+                        .borrow()
+                        .clone();
+                    reg!(r) = val;
+                }
+                Instr::SetUpvalue(u, r) => {
+                    let val = reg!(r).clone();
+                    let cell = &self.ctx.frames.last().unwrap().upvalues[u as usize];
+                    if gc::is_reference(&val) {
+                        self.gc.track_cell(cell);
+                    }
+                    *cell.borrow_mut() = val;
+                }
+                Instr::Closure(r, f) => {
+                    let entry = &code.program.functions[f as usize];
+                    let frame = self.ctx.frames.last().unwrap();
+                    let mut func = Function::new(
+                        entry.name.clone(),
+                        entry.start,
+                        entry.end,
+                        entry.params.clone(),
+                    );
+                    func.captured = entry
+                        .upvalues
+                        .iter()
+                        .map(|upvalue| {
+                            let cell = match upvalue.from {
+                                Capture::Cell(c) => &frame.cells[c as usize],
+                                Capture::Upvalue(u) => &frame.upvalues[u as usize],
+                            };
+                            (upvalue.name.clone(), cell.clone())
+                        })
+                        .collect();
+                    let val = Val::func(func);
+                    if !self.allocate(shallow_size(&val)) {
+                        break;
                     }
-                    _ => panic!(
-                        "elpian error: boolean and non boolean values are not comparable unless it is just equality check"
-                    ),
+                    reg!(r) = val;
                 }
-            }
-            7 => {
-                let v_val = v.as_string();
-                match v2.typ {
-                    6 => {
-                        let v2_val = v2.as_string();
-                        v_val <= v2_val
+                Instr::Array(r, first, count) => {
+                    let start = base + first as usize;
+                    let items = self.ctx.registers[start..start + count as usize].to_vec();
+                    let val = Val::array(Array::new(items));
+                    if !self.allocate(shallow_size(&val)) {
+                        break;
                     }
-                    _ => panic!(
-                        "elpian error: string and non string values are not comparable unless it is just equality check"
-                    ),
+                    reg!(r) = val;
                 }
-            }
-            8 => {
-                let v_val = v.as_object();
-                match v2.typ {
-                    6 => {
-                        let v2_val = v2.as_object();
-                        if v_val.borrow().data.data.iter().all(|(k, _d)| {
-                            if !v2_val.borrow().data.data.contains_key(&k.clone()) {
-                                return false;
-                            }
-                            true
-                        }) && v_val.borrow().data.data.iter().all(|(k, _d)| {
-                            if !v2_val.borrow().data.data.contains_key(&k.clone()) {
-                                return false;
-                            }
-                            true
-                        }) {
-                            let mut counter1 = 0;
-                            let mut counter2 = 0;
-                            v_val.borrow().data.data.iter().for_each(|(k, d)| {
-                                if self.is_lee(
-                                    d.clone(),
-                                    v2_val.borrow().data.data.get(&k.clone()).unwrap().clone(),
-                                ) {
-                                    counter1 += 1;
-                                } else {
-                                    counter2 += 1;
-                                }
-                            });
-                            return counter1 <= counter2;
-                        }
-                        false
+                Instr::Object(r, first, count, list) => {
+                    let mut props = HashMap::with_capacity(count as usize);
+                    for (i, key) in decoded.keys[list as usize].iter().enumerate() {
+                        props.insert(code.name(*key).to_string(), reg!(first + i as u16).clone());
+                    }
+                    let val = Val::object(Object::new(-2, ValGroup::new(props)));
+                    if !self.allocate(shallow_size(&val)) {
+                        break;
                     }
-                    _ => panic!(
-                        "elpian error: object and non object values are not comparable unless it is just equality check"
-                    ),
+                    reg!(r) = val;
                 }
-            }
-            9 => {
-                let v_val = v.as_array();
-                match v2.typ {
-                    9 => {
-                        let v2_val = v2.as_array();
-                        if v_val.borrow().data.len() != v2_val.borrow().data.len() {
-                            return false;
-                        }
-                        let mut counter1 = 0;
-                        let mut counter2 = 0;
-                        let mut counter = 0;
-                        v_val.borrow().data.iter().for_each(|d| {
-                            if self.is_lee(
-                                d.clone(),
-                                v2_val.borrow().data.get(counter).unwrap().clone(),
-                            ) {
-                                counter1 += 1;
-                            } else {
-                                counter2 += 1;
-                            }
-                            counter += 1;
-                        });
-                        return counter1 <= counter2;
+                Instr::Index(d, t, i) => match index_value(&reg!(t), &reg!(i)) {
+                    Ok(val) => reg!(d) = val,
+                    Err(error) => fail!(error),
+                },
+                Instr::SetIndex(t, i, v, k) => {
+                    let (target, index, value) =
+                        (reg!(t).clone(), reg!(i).clone(), reg!(v).clone());
+                    if !self.set_index(code.name(k), target, index, value) {
+                        break;
                     }
-                    _ => panic!(
-                        "elpian error: array and non array values are not comparable unless it is just equality check"
-                    ),
                 }
-            }
-            10 => panic!(
-                "elpian error: function types are not comparable unless it is just equality check"
-            ),
-            _ => panic!("elpian error: unknown types are not comparable"),
-        };
-    }
-    fn define(&mut self, id_name: String, val: Val) {
-        self.ctx.define_val_globally(id_name, val);
-    }
-    fn assign(&mut self, id_name: String, val: Val) {
-        self.ctx.update_val_globally(id_name, val);
-    }
-    pub fn run_from(
-        &mut self,
-        start: usize,
-        end: usize,
-        continue_exec: bool,
-        host_call_result: Val,
-        is_partial_exec: bool,
-    ) -> Val {
-        if !continue_exec {
-            if !is_partial_exec {
-                self.ctx
-                    .push_scope("funcBody".to_string(), start, start, end);
-            }
-            self.pointer = start;
-            self.end_at = end;
-        } else {
-            self.pending_func_result_value = host_call_result.clone();
-        }
-        let mut main_reg: Option<Val> = None;
-        let mut is_reg_state_final = false;
-        if continue_exec {
-            if self.pending_func_result_value.typ != 254 {
-                let returned_val = self.pending_func_result_value.clone();
-                self.pending_func_result_value = Val {
-                    typ: 254,
-                    data: Rc::new(RefCell::new(Box::new(0))),
-                };
-                if !self.registers.is_empty() {
-                    main_reg = Some(returned_val);
-                    is_reg_state_final = false;
+                Instr::Not(d, s) => {
+                    let val = match reg!(s) {
+                        Val::Bool(b) => !b,
+                        _ => fail!(VmError::TypeMismatch(
+                            "not operator (!) can not be applied to non-bool value".to_string(),
+                        )),
+                    };
+                    reg!(d) = Val::Bool(val);
                 }
-            }
-        }
-        loop {
-            if main_reg.is_some() {
-                if !self.registers.is_empty() {
-                    if self.registers.last().unwrap().borrow().get_type() == OperationTypes::ArrExpr
-                    {
-                        if self.registers.last().unwrap().borrow().get_state()
-                            == ExecStates::ArrExprExtractInfo
-                            || self.registers.last().unwrap().borrow().get_state()
-                                == ExecStates::ArrExprExtractItem
-                        {
-                            self.registers.last().unwrap().borrow_mut().set_state(
-                                ExecStates::ArrExprExtractItem,
-                                Box::new(main_reg.clone().unwrap()),
-                            );
-                            main_reg = None;
-                            is_reg_state_final =
-                                self.registers.last().unwrap().borrow_mut().get_state()
-                                    == ExecStates::ArrExprFinished;
-                            continue;
-                        }
-                    } else if self.registers.last().unwrap().borrow().get_type()
-                        == OperationTypes::ObjExpr
-                    {
-                        if self.registers.last().unwrap().borrow().get_state()
-                            == ExecStates::ObjExprExtractInfo
-                            || self.registers.last().unwrap().borrow().get_state()
-                                == ExecStates::ObjExprExtractProp
-                        {
-                            self.registers.last().unwrap().borrow_mut().set_state(
-                                ExecStates::ObjExprExtractProp,
-                                Box::new(main_reg.clone().unwrap()),
-                            );
-                            main_reg = None;
-                            is_reg_state_final =
-                                self.registers.last().unwrap().borrow_mut().get_state()
-                                    == ExecStates::ObjExprFinished;
-                            continue;
-                        }
-                    } else if self.registers.last().unwrap().borrow().get_type()
-                        == OperationTypes::CallFunc
-                    {
-                        if self.registers.last().unwrap().borrow().get_state()
-                            == ExecStates::CallFuncStarted
-                        {
-                            let arg_count = self.extract_i32() as usize;
-                            self.registers.last().unwrap().borrow_mut().set_state(
-                                ExecStates::CallFuncExtractFunc,
-                                Box::new((main_reg.clone().unwrap(), arg_count)),
-                            );
-                            main_reg = None;
-                            is_reg_state_final =
-                                self.registers.last().unwrap().borrow_mut().get_state()
-                                    == ExecStates::CallFuncFinished;
-                            continue;
-                        } else if self.registers.last().unwrap().borrow().get_state()
-                            == ExecStates::CallFuncExtractFunc
-                            || self.registers.last().unwrap().borrow().get_state()
-                                == ExecStates::CallFuncExtractParam
-                        {
-                            self.registers.last().unwrap().borrow_mut().set_state(
-                                ExecStates::CallFuncExtractParam,
-                                Box::new(main_reg.clone().unwrap()),
-                            );
-                            main_reg = None;
-                            is_reg_state_final =
-                                self.registers.last().unwrap().borrow_mut().get_state()
-                                    == ExecStates::CallFuncFinished;
-                            continue;
-                        }
-                    } else if self.registers.last().unwrap().borrow().get_type()
-                        == OperationTypes::ReturnVal
-                    {
-                        if self.registers.last().unwrap().borrow().get_state()
-                            == ExecStates::ReturnValStarted
-                        {
-                            self.registers.last().unwrap().borrow_mut().set_state(
-                                ExecStates::ReturnValFinished,
-                                Box::new(main_reg.clone().unwrap()),
-                            );
-                            main_reg = None;
-                            is_reg_state_final =
-                                self.registers.last().unwrap().borrow_mut().get_state()
-                                    == ExecStates::ReturnValFinished;
-                            continue;
-                        }
-                    } else if self.registers.last().unwrap().borrow().get_type()
-                        == OperationTypes::DefineVar
-                    {
-                        if self.registers.last().unwrap().borrow().get_state()
-                            == ExecStates::DefineVarExtractName
-                        {
-                            self.registers.last().unwrap().borrow_mut().set_state(
-                                ExecStates::DefineVarExtractValue,
-                                Box::new(main_reg.clone().unwrap()),
-                            );
-                            main_reg = None;
-                            is_reg_state_final =
-                                self.registers.last().unwrap().borrow_mut().get_state()
-                                    == ExecStates::DefineVarExtractValue;
-                            continue;
-                        }
-                    } else if self.registers.last().unwrap().borrow().get_type()
-                        == OperationTypes::AssignVar
-                    {
-                        if self.registers.last().unwrap().borrow().get_state()
-                            == ExecStates::AssignVarExtractName
-                        {
-                            if self.registers.last().unwrap().borrow().get_data()[1].as_i16() == 1 {
-                                self.registers.last().unwrap().borrow_mut().set_state(
-                                    ExecStates::AssignVarExtractValue,
-                                    Box::new(main_reg.clone().unwrap()),
-                                );
-                                main_reg = None;
-                                is_reg_state_final =
-                                    self.registers.last().unwrap().borrow_mut().get_state()
-                                        == ExecStates::AssignVarExtractValue;
-                                continue;
-                            } else if self.registers.last().unwrap().borrow().get_data()[1].as_i16()
-                                == 2
-                            {
-                                self.registers.last().unwrap().borrow_mut().set_state(
-                                    ExecStates::AssignVarExtractIndex,
-                                    Box::new(main_reg.clone().unwrap()),
-                                );
-                                main_reg = None;
-                                is_reg_state_final =
-                                    self.registers.last().unwrap().borrow_mut().get_state()
-                                        == ExecStates::AssignVarExtractValue;
-                                continue;
-                            }
-                        } else if self.registers.last().unwrap().borrow().get_state()
-                            == ExecStates::AssignVarExtractIndex
-                        {
-                            self.registers.last().unwrap().borrow_mut().set_state(
-                                ExecStates::AssignVarExtractValue,
-                                Box::new(main_reg.clone().unwrap()),
-                            );
-                            main_reg = None;
-                            is_reg_state_final =
-                                self.registers.last().unwrap().borrow_mut().get_state()
-                                    == ExecStates::AssignVarExtractValue;
-                            continue;
-                        }
-                    } else if self.registers.last().unwrap().borrow().get_type()
-                        == OperationTypes::IfStmt
-                    {
-                        if self.registers.last().unwrap().borrow().get_state()
-                            == ExecStates::IfStmtIsConditioned
-                        {
-                            self.registers.last().unwrap().borrow_mut().set_state(
-                                ExecStates::IfStmtFinished,
-                                Box::new(main_reg.clone().unwrap()),
-                            );
-                            main_reg = None;
-                            is_reg_state_final =
-                                self.registers.last().unwrap().borrow_mut().get_state()
-                                    == ExecStates::IfStmtFinished;
-                            continue;
-                        }
-                    } else if self.registers.last().unwrap().borrow().get_type()
-                        == OperationTypes::LoopStmt
-                    {
-                        if self.registers.last().unwrap().borrow().get_state()
-                            == ExecStates::LoopStmtStarted
-                        {
-                            self.registers.last().unwrap().borrow_mut().set_state(
-                                ExecStates::LoopStmtFinished,
-                                Box::new(main_reg.clone().unwrap()),
-                            );
-                            main_reg = None;
-                            is_reg_state_final =
-                                self.registers.last().unwrap().borrow_mut().get_state()
-                                    == ExecStates::LoopStmtFinished;
-                            continue;
-                        }
-                    } else if self.registers.last().unwrap().borrow().get_type()
-                        == OperationTypes::SwitchStmt
-                    {
-                        if self.registers.last().unwrap().borrow().get_state()
-                            == ExecStates::SwitchStmtStarted
-                        {
-                            let branch_after_start = self.extract_i64() as usize;
-                            let case_count = self.extract_i64() as usize;
-                            self.registers.last().unwrap().borrow_mut().set_state(
-                                ExecStates::SwitchStmtExtractVal,
-                                Box::new((
-                                    main_reg.clone().unwrap(),
-                                    branch_after_start,
-                                    case_count,
-                                )),
-                            );
-                            main_reg = None;
-                            is_reg_state_final =
-                                self.registers.last().unwrap().borrow_mut().get_state()
-                                    == ExecStates::SwitchStmtFinished;
-                            continue;
-                        } else if self.registers.last().unwrap().borrow().get_state()
-                            == ExecStates::SwitchStmtExtractVal
-                            || self.registers.last().unwrap().borrow().get_state()
-                                == ExecStates::SwitchStmtExtractCase
-                        {
-                            let branch_true_start = self.extract_i64() as usize;
-                            let branch_true_end = self.extract_i64() as usize;
-                            self.registers.last().unwrap().borrow_mut().set_state(
-                                ExecStates::SwitchStmtExtractCase,
-                                Box::new((
-                                    main_reg.clone().unwrap(),
-                                    branch_true_start,
-                                    branch_true_end,
-                                )),
-                            );
-                            main_reg = None;
-                            is_reg_state_final =
-                                self.registers.last().unwrap().borrow_mut().get_state()
-                                    == ExecStates::SwitchStmtFinished;
-                            continue;
-                        }
-                    } else if self.registers.last().unwrap().borrow().get_type()
-                        == OperationTypes::Arithmetic
-                    {
-                        if self.registers.last().unwrap().borrow().get_state()
-                            == ExecStates::ArithmeticExtractOp
-                        {
-                            self.registers.last().unwrap().borrow_mut().set_state(
-                                ExecStates::ArithmeticExtractArg1,
-                                Box::new(main_reg.clone().unwrap()),
-                            );
-                            main_reg = None;
-                            is_reg_state_final =
-                                self.registers.last().unwrap().borrow_mut().get_state()
-                                    == ExecStates::ArithmeticExtractArg2;
-                            continue;
-                        } else if self.registers.last().unwrap().borrow().get_state()
-                            == ExecStates::ArithmeticExtractArg1
-                        {
-                            self.registers.last().unwrap().borrow_mut().set_state(
-                                ExecStates::ArithmeticExtractArg2,
-                                Box::new(main_reg.clone().unwrap()),
-                            );
-                            main_reg = None;
-                            is_reg_state_final =
-                                self.registers.last().unwrap().borrow_mut().get_state()
-                                    == ExecStates::ArithmeticExtractArg2;
-                            continue;
-                        }
-                    } else if self.registers.last().unwrap().borrow().get_type()
-                        == OperationTypes::Indexer
-                    {
-                        if self.registers.last().unwrap().borrow().get_state()
-                            == ExecStates::IndexerStarted
-                        {
-                            self.registers.last().unwrap().borrow_mut().set_state(
-                                ExecStates::IndexerExtractVarName,
-                                Box::new(main_reg.clone().unwrap()),
-                            );
-                            main_reg = None;
-                            is_reg_state_final =
-                                self.registers.last().unwrap().borrow_mut().get_state()
-                                    == ExecStates::IndexerExtractIndex;
-                            continue;
-                        } else if self.registers.last().unwrap().borrow().get_state()
-                            == ExecStates::IndexerExtractVarName
-                        {
-                            self.registers.last().unwrap().borrow_mut().set_state(
-                                ExecStates::IndexerExtractIndex,
-                                Box::new(main_reg.clone().unwrap()),
-                            );
-                            main_reg = None;
-                            is_reg_state_final =
-                                self.registers.last().unwrap().borrow_mut().get_state()
-                                    == ExecStates::IndexerExtractIndex;
-                            continue;
-                        }
-                    } else if self.registers.last().unwrap().borrow().get_type()
-                        == OperationTypes::NotVal
-                    {
-                        if self.registers.last().unwrap().borrow().get_state()
-                            == ExecStates::NotValStarted
-                        {
-                            self.registers.last().unwrap().borrow_mut().set_state(
-                                ExecStates::NotValFinished,
-                                Box::new(main_reg.clone().unwrap()),
-                            );
-                            main_reg = None;
-                            is_reg_state_final =
-                                self.registers.last().unwrap().borrow_mut().get_state()
-                                    == ExecStates::NotValFinished;
-                            continue;
-                        }
-                    } else if self.registers.last().unwrap().borrow().get_type()
-                        == OperationTypes::CondBrch
-                    {
-                        if self.registers.last().unwrap().borrow().get_state()
-                            == ExecStates::CondBranchStarted
-                        {
-                            let tb = self.extract_i64();
-                            let fb = self.extract_i64();
-                            self.registers.last().unwrap().borrow_mut().set_state(
-                                ExecStates::CondBranchFinished,
-                                Box::new((main_reg.clone().unwrap(), tb, fb)),
-                            );
-                            main_reg = None;
-                            is_reg_state_final =
-                                self.registers.last().unwrap().borrow_mut().get_state()
-                                    == ExecStates::CondBranchFinished;
-                            continue;
-                        }
-                    } else if self.registers.last().unwrap().borrow().get_type()
-                        == OperationTypes::CastOprt
-                    {
-                        if self.registers.last().unwrap().borrow().get_state()
-                            == ExecStates::CastOprtStarted
-                        {
-                            let tt = self.extract_str();
-                            self.registers.last().unwrap().borrow_mut().set_state(
-                                ExecStates::CastOprtFinished,
-                                Box::new((main_reg.clone().unwrap(), tt)),
-                            );
-                            main_reg = None;
-                            is_reg_state_final =
-                                self.registers.last().unwrap().borrow_mut().get_state()
-                                    == ExecStates::CastOprtFinished;
-                            continue;
-                        }
+                Instr::Cast(d, s, k) => {
+                    let val = cast(&reg!(s), code.name(k));
+                    if val.typ() == 7 && !self.allocate(shallow_size(&val)) {
+                        break;
                     }
-                } else {
-                    main_reg = None;
+                    reg!(d) = val;
                 }
-            } else if is_reg_state_final {
-                if !self.registers.is_empty() {
-                    if self.registers.last().unwrap().borrow().get_state()
-                        == ExecStates::ArrExprFinished
-                    {
-                        let regs = self.registers.last().unwrap().borrow().get_data().clone();
-                        let items_vec = regs[1].clone();
-                        self.registers.pop();
-                        main_reg = Some(items_vec);
-                        is_reg_state_final = false;
-                        continue;
-                    } else if self.registers.last().unwrap().borrow().get_state()
-                        == ExecStates::ObjExprFinished
-                    {
-                        let regs = self.registers.last().unwrap().borrow().get_data().clone();
-                        let typ_id = regs[0].as_i64();
-                        let props_vec = regs[2].as_array();
-                        let mut props_map = HashMap::new();
-                        for i in (0..props_vec.borrow().data.len()).step_by(2) {
-                            props_map.insert(
-                                props_vec.borrow().data[i].as_string(),
-                                props_vec.borrow().data[i + 1].clone(),
-                            );
-                        }
-                        let result = Val {
-                            typ: 8,
-                            data: Rc::new(RefCell::new(Box::new(Rc::new(RefCell::new(
-                                Object::new(typ_id, ValGroup::new(props_map)),
-                            ))))),
-                        };
-                        self.registers.pop();
-                        main_reg = Some(result);
-                        is_reg_state_final = false;
+                Instr::Await(d, s) => {
+                    // anything but a promise is its own result
+                    let Val::Promise(promise) = reg!(s).clone() else {
+                        reg!(d) = reg!(s).clone();
                         continue;
-                    } else if self.registers.last().unwrap().borrow().get_state()
-                        == ExecStates::CallFuncFinished
-                    {
-                        let regs = self.registers.last().unwrap().borrow().get_data().clone();
-                        let is_native = regs[1].as_bool();
-                        if !is_native {
-                            let func = regs[0].as_func().clone();
-                            let expected_params = func.borrow().params.clone();
-                            let provided_args = regs[3].as_array().borrow().data.clone();
-                            let mut args = HashMap::new();
-                            for (i, param_name) in expected_params.iter().enumerate() {
-                                let arg = provided_args.get(i).cloned().unwrap_or_else(|| {
-                                    Val::new(0, Rc::new(RefCell::new(Box::new(0))))
-                                });
-                                args.insert(param_name.clone(), arg);
-                            }
-                            self.ctx
-                                .memory
-                                .last()
-                                .unwrap()
-                                .borrow_mut()
-                                .update_frozen_pointer(self.pointer);
-                            self.ctx.push_scope_with_args(
-                                "funcBody".to_string(),
-                                func.borrow().start,
-                                func.borrow().start,
-                                func.borrow().end,
-                                args,
-                            );
-                            self.pointer = func.borrow().start;
-                            self.end_at = func.borrow().end;
-                            self.registers.pop();
-                            self.registers
-                                .push(Rc::new(RefCell::new(Box::new(DummyOp::new()))));
-                            is_reg_state_final = false;
-                            continue;
-                        } else {
-                            let mut args = HashMap::new();
-                            let arg1 = regs[3].as_array().borrow().data[0].clone();
-                            // if !self.allowed_api.contains_key(&arg1.as_string().clone()) {
-                            //     panic!("elpian error: this api access is locked");
-                            // }
-                            args.insert("apiName".to_string(), arg1.clone());
-                            let arg2 = regs[3].as_array().borrow().data[1].clone();
-                            args.insert("input".to_string(), arg2.clone());
-                            self.cb_counter += 1;
-                            let cb_id = self.cb_counter;
-                            self.registers.pop();
-                            self.reserved_host_call = Some((
-                                0x02,
-                                cb_id,
-                                Val {
-                                    typ: 9,
-                                    data: Rc::new(RefCell::new(Box::new(Rc::new(RefCell::new(
-                                        Array::new(vec![
-                                            args["apiName"].clone(),
-                                            Val {
-                                                typ: 1,
-                                                data: Rc::new(RefCell::new(Box::new(
-                                                    self.executor_id,
-                                                ))),
-                                            },
-                                            args["input"].clone(),
-                                        ]),
-                                    ))))),
-                                },
-                            ));
+                    };
+                    let state = promise.borrow().state.clone();
+                    match state {
+                        PromiseState::Resolved(value) => reg!(d) = value,
+                        PromiseState::Rejected(error) => {
+                            self.throw_value(error);
                             break;
                         }
-                    } else if self.registers.last().unwrap().borrow().get_state()
-                        == ExecStates::ReturnValFinished
-                    {
-                        let data = self.registers.last().unwrap().borrow().get_data();
-                        let returned_val = data[0].clone();
-                        self.registers.pop();
-                        self.pointer = self.end_at;
-                        self.pending_func_result_value = returned_val;
-                        is_reg_state_final = false;
-                        continue;
-                    } else if self.registers.last().unwrap().borrow().get_state()
-                        == ExecStates::DefineVarExtractValue
-                    {
-                        let regs = self.registers.last().unwrap().borrow().get_data().clone();
-                        let var_name = regs[0].as_string();
-                        let var_value = regs[1].clone();
-                        self.registers.pop();
-                        self.define(var_name.clone(), var_value.clone());
-                        is_reg_state_final = false;
-                        continue;
-                    } else if self.registers.last().unwrap().borrow().get_state()
-                        == ExecStates::AssignVarExtractValue
-                    {
-                        let regs = self.registers.last().unwrap().borrow().get_data().clone();
-                        let var_name = regs[0].as_string();
-                        let assign_target_type = regs[1].as_i16();
-                        let data = regs[3].clone();
-                        if assign_target_type == 1 {
-                            self.assign(var_name.clone(), data);
-                        } else if assign_target_type == 2 {
-                            let index = regs[2].clone();
-                            self.pointer += 1;
-                            let indexed = self.ctx.find_val_globally(var_name);
-                            if index.typ == 7 {
-                                if indexed.typ == 8 {
-                                    let obj = indexed.as_object();
-                                    obj.borrow_mut().data.data.insert(index.as_string(), data);
-                                } else {
-                                    panic!(
-                                    "elpian error: non object value can not be indexed by string"
-                                );
-                                }
-                            } else if index.typ >= 1 && index.typ <= 3 {
-                                if indexed.typ == 9 {
-                                    let arr = indexed.as_array();
-                                    if index.typ == 1 {
-                                        arr.borrow_mut().data[index.as_i16() as usize] = data;
-                                    } else if index.typ == 2 {
-                                        arr.borrow_mut().data[index.as_i32() as usize] = data;
-                                    } else {
-                                        arr.borrow_mut().data[index.as_i64() as usize] = data;
-                                    }
-                                } else {
-                                    panic!(
-                                    "elpian error: non object value can not be indexed by string"
-                                );
-                                }
-                            } else {
-                                panic!(
-                                "elpian error: types other than integer and string can not be used to index anything"
-                            );
-                            }
-                        }
-                        self.registers.pop();
-                        is_reg_state_final = false;
-                        continue;
-                    } else if self.registers.last().unwrap().borrow().get_state()
-                        == ExecStates::IfStmtFinished
-                    {
-                        let regs = self.registers.last().unwrap().borrow().get_data().clone();
-                        let has_condition = regs[0].as_bool();
-                        let cond_val = regs[1].clone();
-                        let mut condition = false;
-                        if has_condition {
-                            if cond_val.typ == 6 {
-                                condition = cond_val.as_bool();
-                            }
-                        }
-                        if !has_condition {
-                            let branch_true_start = self.extract_i64() as usize;
-                            let branch_true_end = self.extract_i64() as usize;
-                            let branch_after_start = self.extract_i64() as usize;
-                            self.ctx
-                                .memory
-                                .last()
-                                .unwrap()
-                                .borrow_mut()
-                                .update_frozen_pointer(branch_after_start);
-                            self.ctx.push_scope(
-                                "ifBody".to_string(),
-                                branch_true_start,
-                                branch_true_start,
-                                branch_true_end,
-                            );
-                            self.pointer = branch_true_start;
-                            self.end_at = branch_true_end;
-                        } else {
-                            let branch_true_start = self.extract_i64() as usize;
-                            let branch_true_end = self.extract_i64() as usize;
-                            let branch_next_start = self.extract_i64() as usize;
-                            let branch_after_start = self.extract_i64() as usize;
-                            if condition {
-                                self.ctx
-                                    .memory
-                                    .last()
-                                    .unwrap()
-                                    .borrow_mut()
-                                    .update_frozen_pointer(branch_after_start);
-                                self.ctx.push_scope(
-                                    "ifBody".to_string(),
-                                    branch_true_start,
-                                    branch_true_start,
-                                    branch_true_end,
-                                );
-                                self.pointer = branch_true_start;
-                                self.end_at = branch_true_end;
-                            } else {
-                                self.pointer = branch_next_start;
-                            }
-                        }
-                        self.registers.pop();
-                        is_reg_state_final = false;
-                        continue;
-                    } else if self.registers.last().unwrap().borrow().get_state()
-                        == ExecStates::LoopStmtFinished
-                    {
-                        let regs = self.registers.last().unwrap().borrow().get_data().clone();
-                        let cond_val = regs[0].clone();
-                        let mut condition = false;
-                        if cond_val.typ == 6 {
-                            condition = cond_val.as_bool();
-                        }
-                        let branch_true_start = self.extract_i64() as usize;
-                        let branch_true_end = self.extract_i64() as usize;
-                        let branch_after_start = self.extract_i64() as usize;
-                        if condition {
-                            self.ctx
-                                .memory
-                                .last()
-                                .unwrap()
-                                .borrow_mut()
-                                .update_frozen_pointer(branch_after_start);
-                            self.ctx.push_scope(
-                                "loopBody".to_string(),
-                                branch_true_start,
-                                branch_true_start,
-                                branch_true_end,
-                            );
-                            self.pointer = branch_true_start;
-                            self.end_at = branch_true_end;
-                        } else {
-                            self.pointer = branch_after_start;
-                        }
-                        self.registers.pop();
-                        is_reg_state_final = false;
-                        continue;
-                    } else if self.registers.last().unwrap().borrow().get_state()
-                        == ExecStates::SwitchStmtFinished
-                    {
-                        let regs = self.registers.last().unwrap().borrow().get_data().clone();
-                        let comparing_val = regs[0].clone();
-                        let branch_after_start = regs[1].as_i64() as usize;
-                        let cases = regs[3].as_array();
-                        let mut matched = false;
-                        for case_info in cases.borrow().data.iter() {
-                            let data = case_info.as_object().borrow().data.data.clone();
-                            let case_val = data["val"].clone();
-                            let branch_true_start = data["start"].as_i64() as usize;
-                            let branch_true_end = data["end"].as_i64() as usize;
-                            if self.is_eq(comparing_val.clone(), case_val) {
-                                matched = true;
-                                self.ctx
-                                    .memory
-                                    .last()
-                                    .unwrap()
-                                    .borrow_mut()
-                                    .update_frozen_pointer(branch_after_start);
-                                self.ctx.push_scope(
-                                    "switchBody".to_string(),
-                                    branch_true_start,
-                                    branch_true_start,
-                                    branch_true_end,
-                                );
-                                self.pointer = branch_true_start;
-                                self.end_at = branch_true_end;
-                            }
-                        }
-                        if !matched {
-                            self.pointer = branch_after_start;
-                        }
-                        self.registers.pop();
-                        is_reg_state_final = false;
-                        continue;
-                    } else if self.registers.last().unwrap().borrow().get_state()
-                        == ExecStates::ArithmeticExtractArg2
-                    {
-                        let regs = self.registers.last().unwrap().borrow().get_data().clone();
-                        let op = regs[0].as_i16();
-                        let arg1 = regs[1].clone();
-                        let arg2 = regs[2].clone();
-                        self.registers.pop();
-                        match op {
-                            1 => {
-                                main_reg = Some(Val {
-                                    typ: 6,
-                                    data: Rc::new(RefCell::new(Box::new(self.is_eq(arg1, arg2)))),
-                                });
-                            }
-                            2 => {
-                                main_reg = Some(Val {
-                                    typ: 6,
-                                    data: Rc::new(RefCell::new(Box::new(self.is_ge(arg1, arg2)))),
-                                });
-                            }
-                            3 => {
-                                main_reg = Some(Val {
-                                    typ: 6,
-                                    data: Rc::new(RefCell::new(Box::new(self.is_gee(arg1, arg2)))),
-                                });
-                            }
-                            4 => {
-                                main_reg = Some(Val {
-                                    typ: 6,
-                                    data: Rc::new(RefCell::new(Box::new(self.is_le(arg1, arg2)))),
-                                });
-                            }
-                            5 => {
-                                main_reg = Some(Val {
-                                    typ: 6,
-                                    data: Rc::new(RefCell::new(Box::new(self.is_lee(arg1, arg2)))),
-                                });
-                            }
-                            6 => {
-                                main_reg = Some(Val {
-                                    typ: 6,
-                                    data: Rc::new(RefCell::new(Box::new(!self.is_eq(arg1, arg2)))),
-                                });
-                            }
-                            7 => {
-                                main_reg = Some(self.operate_sum(arg1, arg2));
-                            }
-                            8 => {
-                                main_reg = Some(self.operate_subtract(arg1, arg2));
-                            }
-                            9 => {
-                                main_reg = Some(self.operate_multiply(arg1, arg2));
-                            }
-                            10 => {
-                                main_reg = Some(self.operate_division(arg1, arg2));
-                            }
-                            _ => {}
-                        }
-                        is_reg_state_final = false;
-                        continue;
-                    } else if self.registers.last().unwrap().borrow().get_state()
-                        == ExecStates::IndexerExtractIndex
-                    {
-                        let regs = self.registers.last().unwrap().borrow().get_data().clone();
-                        let indexed = regs[0].clone();
-                        let index = regs[1].clone();
-                        self.registers.pop();
-                        if index.typ == 7 {
-                            if indexed.typ == 8 {
-                                let obj_ref = indexed.as_object();
-                                let obj = obj_ref.borrow();
-                                if let Some(o) = obj.data.data.get(&index.as_string()).clone() {
-                                    main_reg = Some(o.clone());
-                                } else {
-                                    main_reg = Some(Val {
-                                        typ: 0,
-                                        data: Rc::new(RefCell::new(Box::new(0))),
-                                    });
-                                }
-                            } else {
-                                println!(
-                                    "elpian error: non object value can not be indexed by string"
-                                );
-                                main_reg = Some(Val {
-                                    typ: 0,
-                                    data: Rc::new(RefCell::new(Box::new(0))),
-                                });
-                            }
-                        } else if index.typ >= 1 && index.typ <= 3 {
-                            if indexed.typ == 9 {
-                                let arr = indexed.as_array();
-                                if index.typ == 1 {
-                                    if let Some(o) =
-                                        arr.borrow().data.get(index.as_i16() as usize).clone()
-                                    {
-                                        main_reg = Some(o.clone());
-                                    } else {
-                                        main_reg = Some(Val {
-                                            typ: 0,
-                                            data: Rc::new(RefCell::new(Box::new(0))),
-                                        });
-                                    }
-                                } else if index.typ == 2 {
-                                    if let Some(o) =
-                                        arr.borrow().data.get(index.as_i32() as usize).clone()
-                                    {
-                                        main_reg = Some(o.clone());
-                                    } else {
-                                        main_reg = Some(Val {
-                                            typ: 0,
-                                            data: Rc::new(RefCell::new(Box::new(0))),
-                                        });
-                                    }
-                                } else {
-                                    if let Some(o) =
-                                        arr.borrow().data.get(index.as_i64() as usize).clone()
-                                    {
-                                        main_reg = Some(o.clone());
-                                    } else {
-                                        main_reg = Some(Val {
-                                            typ: 0,
-                                            data: Rc::new(RefCell::new(Box::new(0))),
-                                        });
-                                    }
-                                }
-                            } else {
-                                println!(
-                                    "elpian error: non object value can not be indexed by string"
-                                );
-                                main_reg = Some(Val {
-                                    typ: 0,
-                                    data: Rc::new(RefCell::new(Box::new(0))),
-                                });
-                            }
-                        } else {
-                            println!(
-                            "elpian error: types other than integer and string can not be used to index anything"
-                        );
-                            main_reg = Some(Val {
-                                typ: 0,
-                                data: Rc::new(RefCell::new(Box::new(0))),
-                            });
-                        }
-                        is_reg_state_final = false;
-                        continue;
-                    } else if self.registers.last().unwrap().borrow().get_state()
-                        == ExecStates::NotValFinished
-                    {
-                        let data = self.registers.last().unwrap().borrow().get_data();
-                        let val = data[0].clone();
-                        self.registers.pop();
-                        if val.typ == 6 {
-                            main_reg = Some(Val {
-                                typ: 6,
-                                data: Rc::new(RefCell::new(Box::new(!val.as_bool()))),
-                            });
-                        } else {
-                            panic!(
-                            "elpian error: not operator (!) can not be applied to non-bool value"
-                        );
+                        PromiseState::Pending => {
+                            let call_id = promise.borrow().call_id;
+                            self.awaiting = Some(promise);
+                            self.reserved_host_call = Some((0x07, call_id, Val::Null));
+                            self.resume_to = Some(base + d as usize);
+                            break;
                         }
-                        is_reg_state_final = false;
-                        continue;
-                    } else if self.registers.last().unwrap().borrow().get_state()
-                        == ExecStates::CondBranchFinished
-                    {
-                        let regs = self.registers.last().unwrap().borrow().get_data().clone();
-                        let condition = regs[0].as_bool();
-                        let branch_true_start = regs[1].as_i64() as usize;
-                        let branch_false_start = regs[2].as_i64() as usize;
-                        if condition {
-                            self.pointer = branch_true_start;
-                        } else {
-                            self.pointer = branch_false_start;
+                    }
+                }
+                Instr::Jump(address) => pc = address as usize,
+                Instr::JumpIfNot(r, address) => {
+                    // like if and loop statements, non bool conditions count as false
+                    if !matches!(reg!(r), Val::Bool(true)) {
+                        pc = address as usize;
+                    }
+                }
+                Instr::Call(d, f, first, count) => {
+                    let start = base + first as usize;
+                    let args = self.ctx.registers[start..start + count as usize].to_vec();
+                    save!();
+                    let go_on = self.call(reg!(f).clone(), &args, base + d as usize);
+                    load!();
+                    if !go_on {
+                        break;
+                    }
+                }
+                Instr::Invoke(d, first, count, k, c) => {
+                    let start = base + first as usize;
+                    let receiver = reg!(first).clone();
+                    let name = code.name(k);
+                    // properties of the instance come before its methods
+                    let class = match &receiver {
+                        _ if c != NO_CLASS => Some(code.name(c)),
+                        Val::Object(object) if !object.borrow().data.data.contains_key(name) => {
+                            self.ctx.class_of(object.borrow().typ)
                         }
-                        self.registers.pop();
-                        is_reg_state_final = false;
-                        continue;
-                    } else if self.registers.last().unwrap().borrow().get_state()
-                        == ExecStates::CastOprtFinished
-                    {
-                        let regs = self.registers.last().unwrap().borrow().get_data().clone();
-                        let data = regs[0].clone();
-                        let target_type = regs[1].as_string();
-                        if target_type == "i16" {
-                            match data.typ {
-                                1 => {
-                                    main_reg = Some(Val {
-                                        typ: 1,
-                                        data: Rc::new(RefCell::new(Box::new(data.as_i16() as i16))),
-                                    });
-                                }
-                                2 => {
-                                    main_reg = Some(Val {
-                                        typ: 1,
-                                        data: Rc::new(RefCell::new(Box::new(data.as_i32() as i16))),
-                                    });
-                                }
-                                3 => {
-                                    main_reg = Some(Val {
-                                        typ: 1,
-                                        data: Rc::new(RefCell::new(Box::new(data.as_i64() as i16))),
-                                    });
-                                }
-                                4 => {
-                                    main_reg = Some(Val {
-                                        typ: 1,
-                                        data: Rc::new(RefCell::new(Box::new(data.as_f32() as i16))),
-                                    });
-                                }
-                                5 => {
-                                    main_reg = Some(Val {
-                                        typ: 1,
-                                        data: Rc::new(RefCell::new(Box::new(data.as_f64() as i16))),
-                                    });
-                                }
-                                6 => {
-                                    main_reg =
-                                        Some(Val {
-                                            typ: 1,
-                                            data: Rc::new(RefCell::new(Box::new(
-                                                if data.as_bool() { 1 } else { 0 } as i16,
-                                            ))),
-                                        });
-                                }
-                                7 => {
-                                    main_reg = Some(Val {
-                                        typ: 1,
-                                        data: Rc::new(RefCell::new(Box::new(
-                                            data.as_string().parse::<i16>().unwrap(),
-                                        ))),
-                                    });
-                                }
-                                _ => {
-                                    main_reg = Some(Val {
-                                        typ: 0,
-                                        data: Rc::new(RefCell::new(Box::new(0))),
-                                    });
-                                }
-                            }
-                        } else if target_type == "i32" {
-                            match data.typ {
-                                1 => {
-                                    main_reg = Some(Val {
-                                        typ: 2,
-                                        data: Rc::new(RefCell::new(Box::new(data.as_i16() as i32))),
-                                    });
-                                }
-                                2 => {
-                                    main_reg = Some(Val {
-                                        typ: 2,
-                                        data: Rc::new(RefCell::new(Box::new(data.as_i32() as i32))),
-                                    });
-                                }
-                                3 => {
-                                    main_reg = Some(Val {
-                                        typ: 2,
-                                        data: Rc::new(RefCell::new(Box::new(data.as_i64() as i32))),
-                                    });
-                                }
-                                4 => {
-                                    main_reg = Some(Val {
-                                        typ: 2,
-                                        data: Rc::new(RefCell::new(Box::new(data.as_f32() as i32))),
-                                    });
-                                }
-                                5 => {
-                                    main_reg = Some(Val {
-                                        typ: 2,
-                                        data: Rc::new(RefCell::new(Box::new(data.as_f64() as i32))),
-                                    });
-                                }
-                                6 => {
-                                    main_reg =
-                                        Some(Val {
-                                            typ: 2,
-                                            data: Rc::new(RefCell::new(Box::new(
-                                                if data.as_bool() { 1 } else { 0 } as i32,
-                                            ))),
-                                        });
-                                }
-                                7 => {
-                                    main_reg = Some(Val {
-                                        typ: 2,
-                                        data: Rc::new(RefCell::new(Box::new(
-                                            data.as_string().parse::<i32>().unwrap(),
-                                        ))),
-                                    });
-                                }
-                                _ => {
-                                    main_reg = Some(Val {
-                                        typ: 0,
-                                        data: Rc::new(RefCell::new(Box::new(0))),
-                                    });
-                                }
-                            }
-                        } else if target_type == "i64" {
-                            match data.typ {
-                                1 => {
-                                    main_reg = Some(Val {
-                                        typ: 3,
-                                        data: Rc::new(RefCell::new(Box::new(data.as_i16() as i64))),
-                                    });
-                                }
-                                2 => {
-                                    main_reg = Some(Val {
-                                        typ: 3,
-                                        data: Rc::new(RefCell::new(Box::new(data.as_i32() as i64))),
-                                    });
-                                }
-                                3 => {
-                                    main_reg = Some(Val {
-                                        typ: 3,
-                                        data: Rc::new(RefCell::new(Box::new(data.as_i64() as i64))),
-                                    });
-                                }
-                                4 => {
-                                    main_reg = Some(Val {
-                                        typ: 3,
-                                        data: Rc::new(RefCell::new(Box::new(data.as_f32() as i64))),
-                                    });
-                                }
-                                5 => {
-                                    main_reg = Some(Val {
-                                        typ: 3,
-                                        data: Rc::new(RefCell::new(Box::new(data.as_f64() as i64))),
-                                    });
-                                }
-                                6 => {
-                                    main_reg =
-                                        Some(Val {
-                                            typ: 3,
-                                            data: Rc::new(RefCell::new(Box::new(
-                                                if data.as_bool() { 1 } else { 0 } as i64,
-                                            ))),
-                                        });
-                                }
-                                7 => {
-                                    main_reg = Some(Val {
-                                        typ: 3,
-                                        data: Rc::new(RefCell::new(Box::new(
-                                            data.as_string().parse::<i64>().unwrap(),
-                                        ))),
-                                    });
-                                }
-                                _ => {
-                                    main_reg = Some(Val {
-                                        typ: 0,
-                                        data: Rc::new(RefCell::new(Box::new(0))),
-                                    });
-                                }
-                            }
-                        } else if target_type == "f32" {
-                            match data.typ {
-                                1 => {
-                                    main_reg = Some(Val {
-                                        typ: 4,
-                                        data: Rc::new(RefCell::new(Box::new(data.as_i16() as f32))),
-                                    });
-                                }
-                                2 => {
-                                    main_reg = Some(Val {
-                                        typ: 4,
-                                        data: Rc::new(RefCell::new(Box::new(data.as_i32() as f32))),
-                                    });
-                                }
-                                3 => {
-                                    main_reg = Some(Val {
-                                        typ: 4,
-                                        data: Rc::new(RefCell::new(Box::new(data.as_i64() as f32))),
-                                    });
-                                }
-                                4 => {
-                                    main_reg = Some(Val {
-                                        typ: 4,
-                                        data: Rc::new(RefCell::new(Box::new(data.as_f32() as f32))),
-                                    });
-                                }
-                                5 => {
-                                    main_reg = Some(Val {
-                                        typ: 4,
-                                        data: Rc::new(RefCell::new(Box::new(data.as_f64() as f32))),
-                                    });
-                                }
-                                6 => {
-                                    main_reg =
-                                        Some(Val {
-                                            typ: 4,
-                                            data: Rc::new(RefCell::new(Box::new(
-                                                if data.as_bool() { 1 } else { 0 } as f32,
-                                            ))),
-                                        });
-                                }
-                                7 => {
-                                    main_reg = Some(Val {
-                                        typ: 4,
-                                        data: Rc::new(RefCell::new(Box::new(
-                                            data.as_string().parse::<f32>().unwrap(),
-                                        ))),
-                                    });
-                                }
-                                _ => {
-                                    main_reg = Some(Val {
-                                        typ: 0,
-                                        data: Rc::new(RefCell::new(Box::new(0))),
-                                    });
-                                }
-                            }
-                        } else if target_type == "f64" {
-                            match data.typ {
-                                1 => {
-                                    main_reg = Some(Val {
-                                        typ: 5,
-                                        data: Rc::new(RefCell::new(Box::new(data.as_i16() as f64))),
-                                    });
-                                }
-                                2 => {
-                                    main_reg = Some(Val {
-                                        typ: 5,
-                                        data: Rc::new(RefCell::new(Box::new(data.as_i32() as f64))),
-                                    });
-                                }
-                                3 => {
-                                    main_reg = Some(Val {
-                                        typ: 5,
-                                        data: Rc::new(RefCell::new(Box::new(data.as_i64() as f64))),
-                                    });
-                                }
-                                4 => {
-                                    main_reg = Some(Val {
-                                        typ: 5,
-                                        data: Rc::new(RefCell::new(Box::new(data.as_f32() as f64))),
-                                    });
-                                }
-                                5 => {
-                                    main_reg = Some(Val {
-                                        typ: 5,
-                                        data: Rc::new(RefCell::new(Box::new(data.as_f64() as f64))),
-                                    });
-                                }
-                                6 => {
-                                    main_reg =
-                                        Some(Val {
-                                            typ: 5,
-                                            data: Rc::new(RefCell::new(Box::new(
-                                                if data.as_bool() { 1 } else { 0 } as f64,
-                                            ))),
-                                        });
-                                }
-                                7 => {
-                                    main_reg = Some(Val {
-                                        typ: 5,
-                                        data: Rc::new(RefCell::new(Box::new(
-                                            data.as_string().parse::<f64>().unwrap(),
-                                        ))),
-                                    });
-                                }
-                                _ => {
-                                    main_reg = Some(Val {
-                                        typ: 0,
-                                        data: Rc::new(RefCell::new(Box::new(0))),
-                                    });
-                                }
+                        _ => None,
+                    };
+                    let method = class.and_then(|class| code.method(class, name));
+                    save!();
+                    let go_on = match method {
+                        Some(method) => {
+                            let args =
+                                self.ctx.registers[start..start + 1 + count as usize].to_vec();
+                            self.enter_function(&method, &args, Ret::Register(base + d as usize))
+                        }
+                        None if c != NO_CLASS => {
+                            self.raise_error(VmError::TypeMismatch(format!(
+                                "class {} has no method {}",
+                                code.name(c),
+                                name
+                            )));
+                            false
+                        }
+                        None => match index_value(&receiver, &Val::string(name)) {
+                            Ok(callee) => {
+                                let args = self.ctx.registers
+                                    [start + 1..start + 1 + count as usize]
+                                    .to_vec();
+                                self.call(callee, &args, base + d as usize)
                             }
-                        } else if target_type == "bool" {
-                            match data.typ {
-                                1 => {
-                                    main_reg = Some(Val {
-                                        typ: 6,
-                                        data: Rc::new(RefCell::new(Box::new(data.as_i16() > 0))),
-                                    });
-                                }
-                                2 => {
-                                    main_reg = Some(Val {
-                                        typ: 6,
-                                        data: Rc::new(RefCell::new(Box::new(data.as_i32() > 0))),
-                                    });
-                                }
-                                3 => {
-                                    main_reg = Some(Val {
-                                        typ: 6,
-                                        data: Rc::new(RefCell::new(Box::new(data.as_i64() > 0))),
-                                    });
-                                }
-                                4 => {
-                                    main_reg = Some(Val {
-                                        typ: 6,
-                                        data: Rc::new(RefCell::new(Box::new(data.as_f32() > 0.0))),
-                                    });
-                                }
-                                5 => {
-                                    main_reg = Some(Val {
-                                        typ: 6,
-                                        data: Rc::new(RefCell::new(Box::new(data.as_f64() > 0.0))),
-                                    });
-                                }
-                                6 => {
-                                    main_reg = Some(Val {
-                                        typ: 6,
-                                        data: Rc::new(RefCell::new(Box::new(data.as_bool()))),
-                                    });
-                                }
-                                7 => {
-                                    main_reg = Some(Val {
-                                        typ: 6,
-                                        data: Rc::new(RefCell::new(Box::new(
-                                            data.as_string() == "true",
-                                        ))),
-                                    });
-                                }
-                                _ => {
-                                    main_reg = Some(Val {
-                                        typ: 0,
-                                        data: Rc::new(RefCell::new(Box::new(0))),
-                                    });
-                                }
+                            Err(error) => {
+                                self.raise_error(error);
+                                false
                             }
-                        } else if target_type == "string" {
-                            match data.typ {
-                                1 => {
-                                    main_reg = Some(Val {
-                                        typ: 7,
-                                        data: Rc::new(RefCell::new(Box::new(
-                                            data.as_i16().to_string(),
-                                        ))),
-                                    });
-                                }
-                                2 => {
-                                    main_reg = Some(Val {
-                                        typ: 7,
-                                        data: Rc::new(RefCell::new(Box::new(
-                                            data.as_i32().to_string(),
-                                        ))),
-                                    });
-                                }
-                                3 => {
-                                    main_reg = Some(Val {
-                                        typ: 7,
-                                        data: Rc::new(RefCell::new(Box::new(
-                                            data.as_i64().to_string(),
-                                        ))),
-                                    });
-                                }
-                                4 => {
-                                    main_reg = Some(Val {
-                                        typ: 7,
-                                        data: Rc::new(RefCell::new(Box::new(
-                                            data.as_f32().to_string(),
-                                        ))),
-                                    });
-                                }
-                                5 => {
-                                    main_reg = Some(Val {
-                                        typ: 7,
-                                        data: Rc::new(RefCell::new(Box::new(
-                                            data.as_f64().to_string(),
-                                        ))),
-                                    });
-                                }
-                                6 => {
-                                    main_reg = Some(Val {
-                                        typ: 7,
-                                        data: Rc::new(RefCell::new(Box::new(
-                                            data.as_bool().to_string(),
-                                        ))),
-                                    });
-                                }
-                                7 => {
-                                    main_reg = Some(Val {
-                                        typ: 7,
-                                        data: Rc::new(RefCell::new(Box::new(data.as_string()))),
-                                    });
-                                }
-                                8 => {
-                                    main_reg = Some(Val {
-                                        typ: 7,
-                                        data: Rc::new(RefCell::new(Box::new(data.stringify()))),
-                                    });
-                                }
-                                9 => {
-                                    main_reg = Some(Val {
-                                        typ: 7,
-                                        data: Rc::new(RefCell::new(Box::new(data.stringify()))),
-                                    });
-                                }
-                                _ => {
-                                    main_reg = Some(Val {
-                                        typ: 0,
-                                        data: Rc::new(RefCell::new(Box::new(0))),
-                                    });
-                                }
+                        },
+                    };
+                    load!();
+                    if !go_on {
+                        break;
+                    }
+                }
+                Instr::Class(c, first) => {
+                    let class = &code.program.classes[c as usize];
+                    let mut props = match class.parent {
+                        Some(parent) => {
+                            let parent = &code.program.classes[parent as usize].name;
+                            match self.ctx.blueprint(parent) {
+                                Some(blueprint) => blueprint.new_instance().data,
+                                None => fail!(VmError::UndefinedVariable(parent.clone())),
                             }
                         }
-                        self.registers.pop();
-                        is_reg_state_final = false;
-                        continue;
+                        None => ValGroup::new_empty(),
+                    };
+                    for (i, field) in class.fields.iter().enumerate() {
+                        props
+                            .data
+                            .insert(field.clone(), reg!(first + i as u16).clone());
                     }
-                } else {
-                    main_reg = None;
-                }
-            }
-            let mut terminate = false;
-            if self.pointer == self.end_at {
-                while self.pointer == self.end_at {
-                    if self.ctx.memory.len() == 1 {
-                        terminate = true;
+                    let object = Rc::new(RefCell::new(Object::new(0, props)));
+                    let val = Val::Object(object.clone());
+                    if !self.allocate(deep_size(&val, &mut HashSet::new())) {
                         break;
                     }
-                    self.ctx.pop_scope();
-                    if is_partial_exec && (self.ctx.memory.len() == 1) {
-                        return self.pending_func_result_value.clone();
+                    let props =
+                        std::mem::replace(&mut object.borrow_mut().data, ValGroup::new_empty());
+                    self.ctx.define_class(&class.name, props);
+                }
+                Instr::New(d, k, first, count) => {
+                    let name = code.name(k);
+                    let Some(blueprint) = self.ctx.blueprint(name) else {
+                        fail!(VmError::UndefinedVariable(name.to_string()));
+                    };
+                    let instance = Val::object(blueprint.new_instance());
+                    if !self.allocate(deep_size(&instance, &mut HashSet::new())) {
+                        break;
                     }
-                    if !self.registers.is_empty()
-                        && self.registers.last().unwrap().borrow().get_type()
-                            == OperationTypes::Dummy
-                    {
-                        self.registers.pop();
+                    reg!(d) = instance.clone();
+                    if let Some(init) = code.method(name, "init") {
+                        let start = base + first as usize;
+                        let mut args = vec![instance];
+                        args.extend_from_slice(&self.ctx.registers[start..start + count as usize]);
+                        save!();
+                        let go_on = self.enter_function(&init, &args, Ret::Discard);
+                        load!();
+                        if !go_on {
+                            break;
+                        }
                     }
-                    if !self.ctx.memory.is_empty() {
-                        self.pointer = self.ctx.memory.last().unwrap().borrow().frozen_pointer;
-                        self.end_at = self.ctx.memory.last().unwrap().borrow().frozen_end;
-                        if self.pending_func_result_value.typ != 254 {
-                            let returned_val = self.pending_func_result_value.clone();
-                            self.pending_func_result_value = Val {
-                                typ: 254,
-                                data: Rc::new(RefCell::new(Box::new(0))),
-                            };
-                            while self.ctx.memory.last().unwrap().borrow().tag != "funcBody" {
-                                self.ctx.pop_scope();
-                            }
-                            if !self.registers.is_empty() {
-                                main_reg = Some(returned_val);
-                                is_reg_state_final = false;
-                                break;
-                            }
+                }
+                Instr::Is(d, s, k) => {
+                    let is = match &reg!(s) {
+                        Val::Object(object) => {
+                            let typ = object.borrow().typ;
+                            self.ctx
+                                .class_of(typ)
+                                .is_some_and(|class| code.lineage(class).contains(&code.name(k)))
                         }
-                    } else {
-                        terminate = true;
+                        _ => false,
+                    };
+                    reg!(d) = Val::Bool(is);
+                }
+                Instr::Return(r) => {
+                    let val = reg!(r).clone();
+                    save!();
+                    self.unwind_return(val);
+                    load!();
+                    if self.reserved_error.is_some() {
                         break;
                     }
                 }
-                if terminate {
-                    break;
-                }
-                continue;
-            }
-            let unit: u8 = self.program[self.pointer];
-            self.pointer += 1;
-            match unit {
-                // ----------------------------------
-                // arithmetic operators:
-                // equality operator
-                0xf0 => {
-                    let state_holder = Arithmetic::new();
-                    self.registers
-                        .push(Rc::new(RefCell::new(Box::new(state_holder))));
-                    self.registers
-                        .last()
-                        .unwrap()
-                        .borrow_mut()
-                        .set_state(ExecStates::ArithmeticExtractOp, Box::new(1 as i16));
-                }
-                // ge operator
-                0xf1 => {
-                    let state_holder = Arithmetic::new();
-                    self.registers
-                        .push(Rc::new(RefCell::new(Box::new(state_holder))));
-                    self.registers
-                        .last()
-                        .unwrap()
-                        .borrow_mut()
-                        .set_state(ExecStates::ArithmeticExtractOp, Box::new(2 as i16));
-                }
-                // gee operator
-                0xf2 => {
-                    let state_holder = Arithmetic::new();
-                    self.registers
-                        .push(Rc::new(RefCell::new(Box::new(state_holder))));
-                    self.registers
-                        .last()
-                        .unwrap()
-                        .borrow_mut()
-                        .set_state(ExecStates::ArithmeticExtractOp, Box::new(3 as i16));
-                }
-                // le operator
-                0xf3 => {
-                    let state_holder = Arithmetic::new();
-                    self.registers
-                        .push(Rc::new(RefCell::new(Box::new(state_holder))));
-                    self.registers
-                        .last()
-                        .unwrap()
-                        .borrow_mut()
-                        .set_state(ExecStates::ArithmeticExtractOp, Box::new(4 as i16));
-                }
-                // lee operator
-                0xf4 => {
-                    let state_holder = Arithmetic::new();
-                    self.registers
-                        .push(Rc::new(RefCell::new(Box::new(state_holder))));
-                    self.registers
-                        .last()
-                        .unwrap()
-                        .borrow_mut()
-                        .set_state(ExecStates::ArithmeticExtractOp, Box::new(5 as i16));
-                }
-                // inequality operator
-                0xf5 => {
-                    let state_holder = Arithmetic::new();
-                    self.registers
-                        .push(Rc::new(RefCell::new(Box::new(state_holder))));
-                    self.registers
-                        .last()
-                        .unwrap()
-                        .borrow_mut()
-                        .set_state(ExecStates::ArithmeticExtractOp, Box::new(6 as i16));
-                }
-                // sum operator
-                0xf6 => {
-                    let state_holder = Arithmetic::new();
-                    self.registers
-                        .push(Rc::new(RefCell::new(Box::new(state_holder))));
-                    self.registers
-                        .last()
-                        .unwrap()
-                        .borrow_mut()
-                        .set_state(ExecStates::ArithmeticExtractOp, Box::new(7 as i16));
-                }
-                // subtract operator
-                0xf7 => {
-                    let state_holder = Arithmetic::new();
-                    self.registers
-                        .push(Rc::new(RefCell::new(Box::new(state_holder))));
-                    self.registers
-                        .last()
-                        .unwrap()
-                        .borrow_mut()
-                        .set_state(ExecStates::ArithmeticExtractOp, Box::new(8 as i16));
-                }
-                // multiply operator
-                0xf8 => {
-                    let state_holder = Arithmetic::new();
-                    self.registers
-                        .push(Rc::new(RefCell::new(Box::new(state_holder))));
-                    self.registers
-                        .last()
-                        .unwrap()
-                        .borrow_mut()
-                        .set_state(ExecStates::ArithmeticExtractOp, Box::new(9 as i16));
-                }
-                // division operator
-                0xf9 => {
-                    let state_holder = Arithmetic::new();
-                    self.registers
-                        .push(Rc::new(RefCell::new(Box::new(state_holder))));
-                    self.registers
-                        .last()
-                        .unwrap()
-                        .borrow_mut()
-                        .set_state(ExecStates::ArithmeticExtractOp, Box::new(10 as i16));
-                }
-                // mod operator
-                0xfa => {
-                    let state_holder = Arithmetic::new();
-                    self.registers
-                        .push(Rc::new(RefCell::new(Box::new(state_holder))));
-                    self.registers
-                        .last()
-                        .unwrap()
-                        .borrow_mut()
-                        .set_state(ExecStates::ArithmeticExtractOp, Box::new(11 as i16));
-                }
-                // power operator
-                0xfb => {
-                    let state_holder = Arithmetic::new();
-                    self.registers
-                        .push(Rc::new(RefCell::new(Box::new(state_holder))));
-                    self.registers
-                        .last()
-                        .unwrap()
-                        .borrow_mut()
-                        .set_state(ExecStates::ArithmeticExtractOp, Box::new(12 as i16));
-                }
-                // not operator
-                0xfc => {
-                    let state_holder = NotValue::new();
-                    self.registers
-                        .push(Rc::new(RefCell::new(Box::new(state_holder))));
-                }
-                // cast operation
-                0xfd => {
-                    let state_holder = CastOp::new();
-                    self.registers
-                        .push(Rc::new(RefCell::new(Box::new(state_holder))));
-                }
-                // ----------------------------------
-                // program operators:
-                // data indexer
-                0x0c => {
-                    let state_holder = IndexerValue::new();
-                    self.registers
-                        .push(Rc::new(RefCell::new(Box::new(state_holder))));
-                }
-                // function call
-                0x0d => {
-                    let state_holder = CallFunction::new();
-                    self.registers
-                        .push(Rc::new(RefCell::new(Box::new(state_holder))));
-                }
-                // definition statement
-                0x0e => {
-                    if self.program[self.pointer] == 0x0b {
-                        self.pointer += 1;
-                        let state_holder = DefineVariable::new();
-                        self.registers
-                            .push(Rc::new(RefCell::new(Box::new(state_holder))));
-                        let var_name = self.extract_str();
-                        self.registers
-                            .last()
-                            .unwrap()
-                            .borrow_mut()
-                            .set_state(ExecStates::DefineVarExtractName, Box::new(var_name));
+                Instr::ReturnNull => {
+                    save!();
+                    self.unwind_return(Val::NoResult);
+                    load!();
+                    if self.reserved_error.is_some() {
+                        break;
                     }
                 }
-                // assignment statement
-                0x0f => {
-                    if self.program[self.pointer] == 0x0c {
-                        self.pointer += 1;
-                        let state_holder = AssignVariable::new();
-                        self.registers
-                            .push(Rc::new(RefCell::new(Box::new(state_holder))));
-                        let var_name = self.extract_str();
-                        self.registers.last().unwrap().borrow_mut().set_state(
-                            ExecStates::AssignVarExtractName,
-                            Box::new((var_name, 2 as i16)),
-                        );
-                    } else if self.program[self.pointer] == 0x0b {
-                        self.pointer += 1;
-                        let state_holder = AssignVariable::new();
-                        self.registers
-                            .push(Rc::new(RefCell::new(Box::new(state_holder))));
-                        let var_name = self.extract_str();
-                        self.registers.last().unwrap().borrow_mut().set_state(
-                            ExecStates::AssignVarExtractName,
-                            Box::new((var_name, 1 as i16)),
-                        );
-                    }
+                Instr::Throw(r) => {
+                    let val = reg!(r).clone();
+                    self.throw_value(val);
+                    break;
                 }
-                // if statement
-                0x10 => {
-                    let state_holder = IfStmt::new();
-                    self.registers
-                        .push(Rc::new(RefCell::new(Box::new(state_holder))));
-                    let has_condition = self.program[self.pointer] == 0x01;
-                    self.pointer += 1;
-                    if has_condition {
-                        self.registers
-                            .last()
-                            .unwrap()
-                            .borrow_mut()
-                            .set_state(ExecStates::IfStmtIsConditioned, Box::new(has_condition));
-                    } else {
-                        self.registers
-                            .last()
-                            .unwrap()
-                            .borrow_mut()
-                            .set_state(ExecStates::IfStmtIsConditioned, Box::new(has_condition));
-                        self.registers.last().unwrap().borrow_mut().set_state(
-                            ExecStates::IfStmtFinished,
-                            Box::new(Val {
-                                typ: 6,
-                                data: Rc::new(RefCell::new(Box::new(true))),
-                            }),
-                        );
-                        main_reg = None;
-                        is_reg_state_final = true;
-                        continue;
+                Instr::Try(clauses) => self.try_frames.push(TryFrame {
+                    frame: self.ctx.frames.len() - 1,
+                    walks: self.walks.len(),
+                    clauses,
+                    stage: TryStage::Try,
+                    pending: None,
+                }),
+                Instr::TryEnd => {
+                    save!();
+                    self.finish_try_clause();
+                    load!();
+                    if self.reserved_error.is_some() {
+                        break;
                     }
                 }
-                // loop statement
-                0x11 => {
-                    let state_holder = LoopStmt::new();
-                    self.registers
-                        .push(Rc::new(RefCell::new(Box::new(state_holder))));
-                }
-                // switch case statement
-                0x12 => {
-                    let state_holder = SwitchStmt::new();
-                    self.registers
-                        .push(Rc::new(RefCell::new(Box::new(state_holder))));
-                }
-                // function definiton
-                0x13 => {
-                    let func_name = self.extract_str();
-                    let param_count = self.extract_i32();
-                    let mut param_names = vec![];
-                    for _i in 0..param_count {
-                        let p_name = self.extract_str();
-                        param_names.push(p_name);
+                Instr::Leave(count, target) => {
+                    save!();
+                    self.leave(count, target);
+                    load!();
+                    if self.reserved_error.is_some() {
+                        break;
                     }
-                    let func_start = self.extract_i64() as usize;
-                    let func_end = self.extract_i64() as usize;
-                    let func = Function::new(func_name.clone(), func_start, func_end, param_names);
-                    self.define(
-                        func_name.clone(),
-                        Val {
-                            typ: 10,
-                            data: Rc::new(RefCell::new(Box::new(Rc::new(RefCell::new(
-                                func.clone(),
-                            ))))),
-                        },
-                    );
-                    self.pointer = func_end;
-                }
-                // return command
-                0x14 => {
-                    let state_holder = ReturnValue::new();
-                    self.registers
-                        .push(Rc::new(RefCell::new(Box::new(state_holder))));
                 }
-                // jump command
-                0x15 => {
-                    let dest = self.extract_i64() as usize;
-                    self.pointer = dest;
-                }
-                // conditional branch
-                0x16 => {
-                    let state_holder = CondBranch::new();
-                    self.registers
-                        .push(Rc::new(RefCell::new(Box::new(state_holder))));
-                }
-                // ----------------------------------
-                // expressions
-                // data expressions
-                1 | 2 | 3 | 4 | 5 | 6 | 7 | 10 | 11 => {
-                    println!("{}", unit);
-                    self.pointer -= 1;
-                    let val = self.extract_val();
-                    main_reg = Some(val);
-                    continue;
-                }
-                // object expressions
-                8 => {
-                    println!("{}", unit);
-                    let typ = self.extract_i64();
-                    let props_len = self.extract_i32();
-                    self.registers
-                        .push(Rc::new(RefCell::new(Box::new(ObjectExpr::new()))));
-                    self.registers
-                        .last()
-                        .unwrap()
-                        .borrow_mut()
-                        .set_state(ExecStates::ObjExprExtractInfo, Box::new((typ, props_len)));
-                    if self.registers.last().unwrap().borrow().get_state()
-                        == ExecStates::ObjExprFinished
-                    {
-                        main_reg = None;
-                        is_reg_state_final = true;
-                        continue;
+                Instr::Keys(d, s) => {
+                    let keys = match &reg!(s) {
+                        Val::Object(object) => {
+                            let object = object.borrow();
+                            let mut keys: Vec<&String> = object.data.data.keys().collect();
+                            keys.sort();
+                            keys.into_iter()
+                                .map(|key| Val::string(key.as_str()))
+                                .collect()
+                        }
+                        val => fail!(VmError::TypeMismatch(format!(
+                            "{} value has no keys to loop over",
+                            val.type_name()
+                        ))),
+                    };
+                    let keys = stdlib::array(keys);
+                    if !self.allocate(deep_size(&keys, &mut HashSet::new())) {
+                        break;
                     }
+                    reg!(d) = keys;
                 }
-                // array expressions
-                9 => {
-                    let arr_len = self.extract_i32();
-                    self.registers
-                        .push(Rc::new(RefCell::new(Box::new(ArrayExpr::new()))));
-                    self.registers
-                        .last()
-                        .unwrap()
-                        .borrow_mut()
-                        .set_state(ExecStates::ArrExprExtractInfo, Box::new(arr_len));
-                    if self.registers.last().unwrap().borrow().get_state()
-                        == ExecStates::ArrExprFinished
-                    {
-                        main_reg = None;
-                        is_reg_state_final = true;
-                        continue;
+                Instr::Next(d, s, i, exit) => {
+                    let index = match reg!(i) {
+                        Val::I16(v) => v as i64,
+                        Val::I32(v) => v as i64,
+                        Val::I64(v) => v,
+                        _ => fail!(VmError::TypeMismatch(
+                            "loop position is not an integer".to_string()
+                        )),
+                    };
+                    let item = match &reg!(s) {
+                        Val::Array(array) => array.borrow().data.get(index as usize).cloned(),
+                        val => fail!(VmError::TypeMismatch(format!(
+                            "{} value can not be looped over, expected an array",
+                            val.type_name()
+                        ))),
+                    };
+                    match item {
+                        Some(item) => {
+                            reg!(d) = item;
+                            reg!(i) = Val::I64(index + 1);
+                        }
+                        None => pc = exit as usize,
+                    }
+                }
+                Instr::Binary(op, d, a, b) => {
+                    let (a, b) = (reg!(a).clone(), reg!(b).clone());
+                    // summing pushes into or merges into the left side in place
+                    if op == BinOp::Add && gc::is_reference(&b) {
+                        self.gc.track(&a);
+                    }
+                    match self.binary(op, a, b) {
+                        // subtraction builds a fresh string, summing and
+                        // multiplying charge what they build themselves
+                        Ok(val) => {
+                            if !matches!(op, BinOp::Add | BinOp::Mul)
+                                && (val.typ() == 7 || val.typ() == 9)
+                                && !self.allocate(shallow_size(&val))
+                            {
+                                break;
+                            }
+                            reg!(d) = val;
+                        }
+                        Err(error) => fail!(error),
                     }
                 }
-                // ----------------------------------
-                // No-Op
-                _ => {}
             }
         }
-        Val::new(0, Rc::new(RefCell::new(Box::new(0))))
+        if let Some(frame) = self.ctx.frames.last_mut() {
+            frame.pc = pc;
+        }
+    }
+}
+
+// Reference counting alone leaks the cycles the script built, so once the
+// executor lets go of its roots whatever only the cycles hold is collected.
+// Values the host still holds stay alive.
+impl Drop for Executor {
+    fn drop(&mut self) {
+        self.ctx = Context::new();
+        self.walks.clear();
+        self.try_frames.clear();
+        self.host_callbacks.clear();
+        self.promises.clear();
+        self.host_requests.clear();
+        self.awaiting = None;
+        self.thrown = None;
+        self.result = None;
+        self.reserved_host_call = None;
+        self.timers.borrow_mut().timers.clear();
+        self.gc.collect();
+    }
+}
+
+// Functions come back from the host by the name they were handed out
+// under, or as the global or library function of that name.
+impl Refs for Executor {
+    fn function(&self, name: &str) -> Option<Val> {
+        if let Some(func) = self.host_callbacks.get(name) {
+            return Some(Val::Function(func.clone()));
+        }
+        match self.global(name) {
+            val @ (Val::Function(_) | Val::Native(_)) => Some(val),
+            _ => None,
+        }
+    }
+    fn promise(&self, call_id: i64) -> Option<Val> {
+        self.promises
+            .get(&call_id)
+            .map(|promise| Val::Promise(promise.clone()))
     }
 }
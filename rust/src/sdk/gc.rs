@@ -0,0 +1,259 @@
+use std::{
+    cell::RefCell,
+    collections::{hash_map::Entry, HashMap, HashSet},
+    rc::{Rc, Weak},
+};
+
+use serde_json::{json, Value};
+
+use crate::sdk::data::{Array, Function, Object, Promise, PromiseState, Val, VarCell};
+
+// tracked containers that trigger the first collection; later ones wait for
+// the tracked set to double
+const FIRST_COLLECTION: usize = 1024;
+
+/// What the cycle collector did so far.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct GcStats {
+    pub collections: u64,
+    /// Objects, arrays, functions, variables and promises freed in total.
+    pub freed: u64,
+    pub last_freed: usize,
+    /// Containers currently watched for cycles.
+    pub tracked: usize,
+}
+
+impl GcStats {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "collections": self.collections,
+            "freed": self.freed,
+            "lastFreed": self.last_freed,
+            "tracked": self.tracked,
+        })
+    }
+}
+
+/// Whether storing `val` somewhere can close a reference cycle.
+pub fn is_reference(val: &Val) -> bool {
+    matches!(
+        val,
+        Val::Object(_) | Val::Array(_) | Val::Function(_) | Val::Promise(_)
+    )
+}
+
+// a container watched for cycles, held weakly so watching keeps nothing alive
+enum Tracked {
+    Object(Weak<RefCell<Object>>),
+    Array(Weak<RefCell<Array>>),
+    Cell(Weak<RefCell<Val>>),
+}
+
+impl Tracked {
+    fn upgrade(&self) -> Option<Node> {
+        match self {
+            Tracked::Object(weak) => weak.upgrade().map(Node::Object),
+            Tracked::Array(weak) => weak.upgrade().map(Node::Array),
+            Tracked::Cell(weak) => weak.upgrade().map(Node::Cell),
+        }
+    }
+}
+
+// a value other values can hold a reference to
+enum Node {
+    Object(Rc<RefCell<Object>>),
+    Array(Rc<RefCell<Array>>),
+    Function(Rc<RefCell<Function>>),
+    Cell(VarCell),
+    Promise(Rc<RefCell<Promise>>),
+}
+
+impl Node {
+    fn of(val: &Val) -> Option<Node> {
+        match val {
+            Val::Object(object) => Some(Node::Object(object.clone())),
+            Val::Array(array) => Some(Node::Array(array.clone())),
+            Val::Function(func) => Some(Node::Function(func.clone())),
+            Val::Promise(promise) => Some(Node::Promise(promise.clone())),
+            _ => None,
+        }
+    }
+    fn addr(&self) -> usize {
+        match self {
+            Node::Object(rc) => Rc::as_ptr(rc) as *const () as usize,
+            Node::Array(rc) => Rc::as_ptr(rc) as *const () as usize,
+            Node::Function(rc) => Rc::as_ptr(rc) as *const () as usize,
+            Node::Cell(rc) => Rc::as_ptr(rc) as *const () as usize,
+            Node::Promise(rc) => Rc::as_ptr(rc) as *const () as usize,
+        }
+    }
+    fn strong_count(&self) -> usize {
+        match self {
+            Node::Object(rc) => Rc::strong_count(rc),
+            Node::Array(rc) => Rc::strong_count(rc),
+            Node::Function(rc) => Rc::strong_count(rc),
+            Node::Cell(rc) => Rc::strong_count(rc),
+            Node::Promise(rc) => Rc::strong_count(rc),
+        }
+    }
+    // every reference the node holds, once per reference
+    fn children(&self) -> Vec<Node> {
+        match self {
+            Node::Object(object) => object
+                .borrow()
+                .data
+                .data
+                .values()
+                .filter_map(Node::of)
+                .collect(),
+            Node::Array(array) => array.borrow().data.iter().filter_map(Node::of).collect(),
+            Node::Function(func) => func
+                .borrow()
+                .captured
+                .iter()
+                .map(|(_, cell)| Node::Cell(cell.clone()))
+                .collect(),
+            Node::Cell(cell) => Node::of(&cell.borrow()).into_iter().collect(),
+            Node::Promise(promise) => match &promise.borrow().state {
+                PromiseState::Resolved(val) | PromiseState::Rejected(val) => {
+                    Node::of(val).into_iter().collect()
+                }
+                PromiseState::Pending => vec![],
+            },
+        }
+    }
+    // drops the references the node holds
+    fn clear(&self) {
+        match self {
+            Node::Object(object) => object.borrow_mut().data.data.clear(),
+            Node::Array(array) => array.borrow_mut().data.clear(),
+            Node::Function(func) => func.borrow_mut().captured.clear(),
+            Node::Cell(cell) => *cell.borrow_mut() = Val::Null,
+            Node::Promise(promise) => promise.borrow_mut().state = PromiseState::Pending,
+        }
+    }
+}
+
+/// Frees script values that only keep each other alive through reference
+/// cycles, e.g. an object holding itself or a recursive inner function and
+/// the variable it is stored in.
+///
+/// A cycle can only be closed by storing a reference into a container that
+/// already exists, so every object, array and variable that gets one stored
+/// into it is tracked. A collection does trial deletion over everything
+/// reachable from the tracked containers: references from inside that graph
+/// are subtracted from the reference counts, whatever is still referenced
+/// from outside (the globals, the call stack, the host) is alive along with
+/// everything it reaches, and the rest is garbage. Garbage is freed by
+/// clearing its references, which lets the reference counts drop it.
+pub struct CycleCollector {
+    tracked: HashMap<usize, Tracked>,
+    // collect once this many containers are tracked
+    threshold: usize,
+    stats: GcStats,
+}
+
+impl Default for CycleCollector {
+    fn default() -> Self {
+        CycleCollector {
+            tracked: HashMap::new(),
+            threshold: FIRST_COLLECTION,
+            stats: GcStats::default(),
+        }
+    }
+}
+
+impl CycleCollector {
+    /// Watches an object or array that just got a reference stored into it.
+    pub fn track(&mut self, val: &Val) {
+        let (addr, tracked) = match val {
+            Val::Object(object) => (
+                Rc::as_ptr(object) as *const () as usize,
+                Tracked::Object(Rc::downgrade(object)),
+            ),
+            Val::Array(array) => (
+                Rc::as_ptr(array) as *const () as usize,
+                Tracked::Array(Rc::downgrade(array)),
+            ),
+            _ => return,
+        };
+        self.tracked.entry(addr).or_insert(tracked);
+    }
+    /// Watches a variable that was just assigned.
+    pub fn track_cell(&mut self, cell: &VarCell) {
+        let addr = Rc::as_ptr(cell) as *const () as usize;
+        self.tracked
+            .entry(addr)
+            .or_insert_with(|| Tracked::Cell(Rc::downgrade(cell)));
+    }
+    pub fn stats(&self) -> GcStats {
+        GcStats {
+            tracked: self.tracked.len(),
+            ..self.stats
+        }
+    }
+    /// Whether enough containers are tracked for a collection to be worth it.
+    pub fn is_due(&self) -> bool {
+        self.tracked.len() >= self.threshold
+    }
+    /// Frees the garbage cycles among the tracked containers. Returns how
+    /// many values were freed. Must only run while no value is borrowed.
+    pub fn collect(&mut self) -> usize {
+        // the weak handles of freed containers keep their addresses taken,
+        // so no other container can show up under the same key
+        self.tracked
+            .retain(|_, tracked| tracked.upgrade().is_some());
+        let mut nodes: Vec<Node> = vec![];
+        let mut index: HashMap<usize, usize> = HashMap::new();
+        for tracked in self.tracked.values() {
+            let node = tracked.upgrade().unwrap();
+            index.insert(node.addr(), nodes.len());
+            nodes.push(node);
+        }
+        let mut next = 0;
+        while next < nodes.len() {
+            for child in nodes[next].children() {
+                if let Entry::Vacant(entry) = index.entry(child.addr()) {
+                    entry.insert(nodes.len());
+                    nodes.push(child);
+                }
+            }
+            next += 1;
+        }
+        // references from inside the graph, by node
+        let mut internal = vec![0; nodes.len()];
+        for node in nodes.iter() {
+            for child in node.children() {
+                internal[index[&child.addr()]] += 1;
+            }
+        }
+        // nodes referenced from outside, besides the handle held here, are
+        // alive and so is everything they reach
+        let mut alive = HashSet::new();
+        let mut pending: Vec<usize> = (0..nodes.len())
+            .filter(|i| nodes[*i].strong_count() > internal[*i] + 1)
+            .collect();
+        while let Some(i) = pending.pop() {
+            if alive.insert(i) {
+                for child in nodes[i].children() {
+                    pending.push(index[&child.addr()]);
+                }
+            }
+        }
+        let mut freed = 0;
+        for (i, node) in nodes.iter().enumerate() {
+            if !alive.contains(&i) {
+                node.clear();
+                freed += 1;
+            }
+        }
+        drop(nodes);
+        self.tracked
+            .retain(|_, tracked| tracked.upgrade().is_some());
+        self.threshold = FIRST_COLLECTION.max(self.tracked.len() * 2);
+        self.stats.collections += 1;
+        self.stats.freed += freed as u64;
+        self.stats.last_freed = freed;
+        freed
+    }
+}
@@ -0,0 +1,293 @@
+use serde_json::{json, Value};
+
+use crate::sdk::error::SyntaxError;
+
+/// Location of a token in the source text. `offset` and `len` are in bytes,
+/// `line` and `column` are 1-based and count characters.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Span {
+    pub offset: usize,
+    pub len: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Span {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "line": self.line,
+            "column": self.column,
+            "offset": self.offset,
+            "length": self.len,
+        })
+    }
+    /// Reads back a span written by `to_json`, e.g. the `span` the parser
+    /// attaches to statement nodes.
+    pub fn from_json(value: &Value) -> Option<Span> {
+        let field = |name: &str| value.get(name)?.as_u64().map(|n| n as usize);
+        Some(Span {
+            offset: field("offset")?,
+            len: field("length")?,
+            line: field("line")?,
+            column: field("column")?,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TokenKind {
+    Ident(String),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Symbol(&'static str),
+    Eof,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+// longest first, so `==` wins over `=`
+const SYMBOLS: [&str; 24] = [
+    "==", "!=", ">=", "<=", "=", "+", "-", "*", "/", "^", "%", ">", "<", "!", ".", "(", ")", "[",
+    "]", "{", "}", ":", ",", ";",
+];
+
+struct Lexer<'a> {
+    src: &'a str,
+    offset: usize,
+    line: usize,
+    column: usize,
+    errors: Vec<SyntaxError>,
+}
+
+impl<'a> Lexer<'a> {
+    fn peek(&self) -> Option<char> {
+        self.src[self.offset..].chars().next()
+    }
+    fn peek_second(&self) -> Option<char> {
+        let mut chars = self.src[self.offset..].chars();
+        chars.next();
+        chars.next()
+    }
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.offset += c.len_utf8();
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+    fn start(&self) -> Span {
+        Span {
+            offset: self.offset,
+            len: 0,
+            line: self.line,
+            column: self.column,
+        }
+    }
+    fn finish(&self, mut span: Span) -> Span {
+        span.len = self.offset - span.offset;
+        span
+    }
+    fn error(&mut self, message: String, span: Span) {
+        self.errors
+            .push(SyntaxError::new(message, self.finish(span)));
+    }
+
+    fn skip_trivia(&mut self) {
+        loop {
+            match (self.peek(), self.peek_second()) {
+                (Some(c), _) if c.is_whitespace() => {
+                    self.bump();
+                }
+                (Some('/'), Some('/')) => {
+                    while !matches!(self.peek(), None | Some('\n')) {
+                        self.bump();
+                    }
+                }
+                (Some('/'), Some('*')) => {
+                    let span = self.start();
+                    self.bump();
+                    self.bump();
+                    loop {
+                        match (self.peek(), self.peek_second()) {
+                            (None, _) => {
+                                self.error("unterminated block comment".to_string(), span);
+                                return;
+                            }
+                            (Some('*'), Some('/')) => {
+                                self.bump();
+                                self.bump();
+                                break;
+                            }
+                            _ => {
+                                self.bump();
+                            }
+                        }
+                    }
+                }
+                _ => return,
+            }
+        }
+    }
+
+    fn number(&mut self, span: Span) -> TokenKind {
+        let mut is_float = false;
+        while matches!(self.peek(), Some(c) if c.is_ascii_digit()) {
+            self.bump();
+        }
+        if self.peek() == Some('.') && matches!(self.peek_second(), Some(c) if c.is_ascii_digit()) {
+            is_float = true;
+            self.bump();
+            while matches!(self.peek(), Some(c) if c.is_ascii_digit()) {
+                self.bump();
+            }
+        }
+        if matches!(self.peek(), Some('e') | Some('E')) {
+            let rest = &self.src[self.offset + 1..];
+            let digits = rest.strip_prefix(['+', '-']).unwrap_or(rest);
+            if digits.starts_with(|c: char| c.is_ascii_digit()) {
+                is_float = true;
+                self.bump();
+                if matches!(self.peek(), Some('+') | Some('-')) {
+                    self.bump();
+                }
+                while matches!(self.peek(), Some(c) if c.is_ascii_digit()) {
+                    self.bump();
+                }
+            }
+        }
+        let text = &self.src[span.offset..self.offset];
+        if is_float {
+            return TokenKind::Float(text.parse().unwrap_or(0.0));
+        }
+        match text.parse::<i64>() {
+            Ok(value) => TokenKind::Int(value),
+            Err(_) => {
+                self.error(format!("integer literal {} is out of range", text), span);
+                TokenKind::Int(0)
+            }
+        }
+    }
+
+    fn string(&mut self, span: Span) -> TokenKind {
+        self.bump();
+        let mut value = String::new();
+        loop {
+            match self.peek() {
+                None | Some('\n') => {
+                    self.error("unterminated string literal".to_string(), span);
+                    break;
+                }
+                Some('"') => {
+                    self.bump();
+                    break;
+                }
+                Some('\\') => {
+                    let escape = self.start();
+                    self.bump();
+                    match self.bump() {
+                        Some('n') => value.push('\n'),
+                        Some('t') => value.push('\t'),
+                        Some('r') => value.push('\r'),
+                        Some('0') => value.push('\0'),
+                        Some('"') => value.push('"'),
+                        Some('\\') => value.push('\\'),
+                        Some('u') => match self.unicode_escape() {
+                            Some(c) => value.push(c),
+                            None => self.error("invalid unicode escape".to_string(), escape),
+                        },
+                        Some(c) => self.error(format!("unknown escape sequence \\{}", c), escape),
+                        None => {}
+                    }
+                }
+                Some(c) => {
+                    self.bump();
+                    value.push(c);
+                }
+            }
+        }
+        TokenKind::Str(value)
+    }
+
+    // `\u{1F600}`, the backslash and `u` are already consumed
+    fn unicode_escape(&mut self) -> Option<char> {
+        if self.peek() != Some('{') {
+            return None;
+        }
+        self.bump();
+        let mut hex = String::new();
+        while matches!(self.peek(), Some(c) if c.is_ascii_hexdigit()) {
+            hex.push(self.bump().unwrap());
+        }
+        if self.peek() != Some('}') {
+            return None;
+        }
+        self.bump();
+        u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32)
+    }
+
+    fn next_token(&mut self) -> Option<Token> {
+        self.skip_trivia();
+        let span = self.start();
+        let c = self.peek()?;
+        let kind = if c.is_ascii_digit() {
+            self.number(span)
+        } else if c == '"' {
+            self.string(span)
+        } else if c.is_alphabetic() || c == '_' {
+            while matches!(self.peek(), Some(c) if c.is_alphanumeric() || c == '_') {
+                self.bump();
+            }
+            TokenKind::Ident(self.src[span.offset..self.offset].to_string())
+        } else if let Some(symbol) = SYMBOLS
+            .iter()
+            .find(|symbol| self.src[self.offset..].starts_with(**symbol))
+        {
+            for _ in 0..symbol.len() {
+                self.bump();
+            }
+            TokenKind::Symbol(symbol)
+        } else {
+            self.bump();
+            self.error(format!("unexpected character '{}'", c), span);
+            return self.next_token();
+        };
+        Some(Token {
+            kind,
+            span: self.finish(span),
+        })
+    }
+}
+
+/// Splits source text into tokens. The list always ends with an `Eof`
+/// token. Malformed literals and stray characters are reported all at once.
+pub fn tokenize(src: &str) -> Result<Vec<Token>, Vec<SyntaxError>> {
+    let mut lexer = Lexer {
+        src,
+        offset: 0,
+        line: 1,
+        column: 1,
+        errors: vec![],
+    };
+    let mut tokens = vec![];
+    while let Some(token) = lexer.next_token() {
+        tokens.push(token);
+    }
+    tokens.push(Token {
+        kind: TokenKind::Eof,
+        span: lexer.start(),
+    });
+    if lexer.errors.is_empty() {
+        Ok(tokens)
+    } else {
+        Err(lexer.errors)
+    }
+}
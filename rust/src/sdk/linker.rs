@@ -0,0 +1,216 @@
+use std::collections::{HashMap, HashSet};
+
+use serde_json::Value;
+
+use crate::sdk::{error::SyntaxError, lexer::Span, parser};
+
+/// Where the source of imported modules comes from: the host's callback,
+/// given a module name, returns its source or `None` when there is no such
+/// module.
+pub type ModuleResolver<'r> = dyn FnMut(&str) -> Option<String> + 'r;
+
+/// The global a top-level `name` of `module` is stored in. Every module
+/// but the program itself gets a namespace of its own this way, so two
+/// modules may define the same names.
+pub fn global_name(module: &str, name: &str) -> String {
+    format!("{}::{}", module, name)
+}
+
+/// A parsed module, ready to be compiled into a linked program.
+pub(crate) struct Unit {
+    /// `None` for the program itself.
+    pub name: Option<String>,
+    pub ast: Value,
+    /// The globals the module's top-level names and imports stand for.
+    /// Names without an entry, like those of the program itself, are
+    /// globals of the same name.
+    pub globals: HashMap<String, String>,
+}
+
+/// A program and every module it imports, directly or not, in the order
+/// their top-level statements run: each module after the ones it imports,
+/// the program last.
+pub(crate) struct Linked {
+    pub units: Vec<Unit>,
+    /// The source of every imported module, by name.
+    pub sources: HashMap<String, String>,
+}
+
+struct Linker<'a, 'r> {
+    resolve: &'a mut ModuleResolver<'r>,
+    // what each linked module exports
+    exports: HashMap<String, HashSet<String>>,
+    // the globals of every class declared so far
+    classes: HashSet<String>,
+    // modules that failed to load, reported once
+    failed: HashSet<String>,
+    // modules whose imports are being linked, outermost first
+    stack: Vec<String>,
+    linked: Linked,
+    errors: Vec<SyntaxError>,
+}
+
+/// Parses `program` and the modules it imports, asking `resolve` for the
+/// source of each module once. Fails with the syntax errors of every
+/// module, tagged with the module's name, and with link errors: a module
+/// that is not found, a name it does not export, a name imported twice or
+/// also defined by the importing module, imports that lead back to a
+/// module that is still being linked and classes extending anything but a
+/// class declared before them or imported.
+pub(crate) fn link(
+    program: &str,
+    resolve: &mut ModuleResolver,
+) -> Result<Linked, Vec<SyntaxError>> {
+    let ast = parser::parse(program)?;
+    let mut linker = Linker {
+        resolve,
+        exports: HashMap::new(),
+        classes: HashSet::new(),
+        failed: HashSet::new(),
+        stack: vec![],
+        linked: Linked {
+            units: vec![],
+            sources: HashMap::new(),
+        },
+        errors: vec![],
+    };
+    linker.add(None, ast);
+    if linker.errors.is_empty() {
+        Ok(linker.linked)
+    } else {
+        Err(linker.errors)
+    }
+}
+
+impl Linker<'_, '_> {
+    // links the imports of a parsed module, then the module itself
+    fn add(&mut self, name: Option<&str>, ast: Value) {
+        let mut declared = HashSet::new();
+        let mut exports = HashSet::new();
+        for statement in ast["body"].as_array().unwrap() {
+            if let Some(defined) = defined_name(statement) {
+                declared.insert(defined.to_string());
+                if statement["data"]["exported"].as_bool() == Some(true) {
+                    exports.insert(defined.to_string());
+                }
+            }
+        }
+        let mut globals: HashMap<String, String> = match name {
+            Some(module) => declared
+                .iter()
+                .map(|defined| (defined.clone(), global_name(module, defined)))
+                .collect(),
+            None => HashMap::new(),
+        };
+        let mut imported = HashSet::new();
+        for statement in ast["body"].as_array().unwrap() {
+            if statement["type"] != "importStmt" {
+                continue;
+            }
+            let span = Span::from_json(&statement["span"]).unwrap_or_default();
+            let module = statement["data"]["module"].as_str().unwrap();
+            if !self.load(module, span, name) {
+                continue;
+            }
+            for item in statement["data"]["names"].as_array().unwrap() {
+                let (export, alias) =
+                    (item["name"].as_str().unwrap(), item["as"].as_str().unwrap());
+                let message = if !self.exports[module].contains(export) {
+                    format!("module '{}' does not export '{}'", module, export)
+                } else if declared.contains(alias) {
+                    format!("'{}' is imported and also defined in this module", alias)
+                } else if !imported.insert(alias.to_string()) {
+                    format!("'{}' is imported twice", alias)
+                } else {
+                    globals.insert(alias.to_string(), global_name(module, export));
+                    continue;
+                };
+                self.errors
+                    .push(SyntaxError::new(message, span).in_module(name));
+            }
+        }
+        for statement in ast["body"].as_array().unwrap() {
+            if statement["type"] != "classDefinition" {
+                continue;
+            }
+            let class = statement["data"]["name"].as_str().unwrap();
+            if let Some(parent) = statement["data"]["parent"].as_str() {
+                let global = globals.get(parent).map_or(parent, String::as_str);
+                if !self.classes.contains(global) {
+                    let message = format!(
+                        "class '{}' extends '{}', which is not a class declared before it",
+                        class, parent
+                    );
+                    let span = Span::from_json(&statement["span"]).unwrap_or_default();
+                    self.errors
+                        .push(SyntaxError::new(message, span).in_module(name));
+                }
+            }
+            let global = globals.get(class).map_or(class, String::as_str);
+            self.classes.insert(global.to_string());
+        }
+        if let Some(module) = name {
+            self.exports.insert(module.to_string(), exports);
+        }
+        self.linked.units.push(Unit {
+            name: name.map(str::to_string),
+            ast,
+            globals,
+        });
+    }
+
+    // Links `module`, imported at `span` of module `from`, unless it already
+    // is. False when it can not be linked.
+    fn load(&mut self, module: &str, span: Span, from: Option<&str>) -> bool {
+        if self.exports.contains_key(module) {
+            return true;
+        }
+        if self.failed.contains(module) {
+            return false;
+        }
+        if let Some(at) = self.stack.iter().position(|linking| linking == module) {
+            let mut cycle = self.stack[at..].to_vec();
+            cycle.push(module.to_string());
+            let message = format!("cyclic import: {}", cycle.join(" -> "));
+            self.errors
+                .push(SyntaxError::new(message, span).in_module(from));
+            return false;
+        }
+        let Some(source) = (self.resolve)(module) else {
+            let message = format!("module '{}' is not found", module);
+            self.errors
+                .push(SyntaxError::new(message, span).in_module(from));
+            self.failed.insert(module.to_string());
+            return false;
+        };
+        let ast = match parser::parse(&source) {
+            Ok(ast) => ast,
+            Err(errors) => {
+                self.errors.extend(
+                    errors
+                        .into_iter()
+                        .map(|error| error.in_module(Some(module))),
+                );
+                self.failed.insert(module.to_string());
+                return false;
+            }
+        };
+        self.linked.sources.insert(module.to_string(), source);
+        self.stack.push(module.to_string());
+        self.add(Some(module), ast);
+        self.stack.pop();
+        true
+    }
+}
+
+// the global a top-level statement defines, if any
+fn defined_name(statement: &Value) -> Option<&str> {
+    let data = &statement["data"];
+    match statement["type"].as_str()? {
+        "definition" if data["leftSide"]["type"] == "identifier" => {
+            data["leftSide"]["data"]["name"].as_str()
+        }
+        "functionDefinition" | "classDefinition" => data["name"].as_str(),
+        _ => None,
+    }
+}
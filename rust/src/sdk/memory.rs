@@ -0,0 +1,228 @@
+use std::{cell::RefCell, collections::HashSet, mem::size_of, rc::Rc};
+
+use crate::sdk::{
+    context::{Context, Frame},
+    data::{Array, Function, Object, Promise, PromiseState, Val, VarCell},
+};
+
+// reference counted allocation header (strong + weak counters)
+const RC_HEADER: usize = 2 * size_of::<usize>();
+// a Val slot, scalars live inline in it
+const VAL_BYTES: usize = size_of::<Val>();
+// one slot of a ValGroup map, not counting the key text
+const ENTRY_BYTES: usize = size_of::<String>() + size_of::<Val>() + size_of::<u64>();
+const FRAME_BYTES: usize = size_of::<Frame>();
+// a captured variable
+const CELL_BYTES: usize = RC_HEADER + size_of::<RefCell<Val>>() + size_of::<VarCell>();
+
+pub fn entry_size(key: &str) -> usize {
+    ENTRY_BYTES + key.len()
+}
+
+// a string of `len` bytes
+pub fn string_size(len: usize) -> usize {
+    VAL_BYTES + RC_HEADER + len
+}
+
+// an array of `len` items
+pub fn array_size(len: usize) -> usize {
+    VAL_BYTES + RC_HEADER + size_of::<RefCell<Array>>() + len * VAL_BYTES
+}
+
+// one more item of an array
+pub fn slot_size() -> usize {
+    VAL_BYTES
+}
+
+// a call frame with its registers and cells
+pub fn frame_size(registers: usize, cells: usize) -> usize {
+    FRAME_BYTES + registers * VAL_BYTES + cells * CELL_BYTES
+}
+
+// bytes owned by the value itself, children of objects and arrays are
+// only counted as the slots holding them
+pub fn shallow_size(val: &Val) -> usize {
+    match val {
+        Val::Str(text) | Val::Native(text) => VAL_BYTES + RC_HEADER + text.len(),
+        Val::Object(obj) => {
+            let obj = obj.borrow();
+            VAL_BYTES
+                + RC_HEADER
+                + size_of::<RefCell<Object>>()
+                + obj.data.data.keys().map(|k| entry_size(k)).sum::<usize>()
+        }
+        Val::Array(arr) => {
+            let arr = arr.borrow();
+            VAL_BYTES
+                + RC_HEADER
+                + size_of::<RefCell<Array>>()
+                + arr.data.capacity() * size_of::<Val>()
+        }
+        Val::Function(func) => {
+            let func = func.borrow();
+            VAL_BYTES
+                + RC_HEADER
+                + size_of::<RefCell<Function>>()
+                + func.name.len()
+                + func
+                    .captured
+                    .iter()
+                    .map(|(name, _)| size_of::<(String, VarCell)>() + name.len())
+                    .sum::<usize>()
+                + func
+                    .params
+                    .iter()
+                    .map(|p| size_of::<String>() + p.len())
+                    .sum::<usize>()
+        }
+        Val::Promise(promise) => {
+            let promise = promise.borrow();
+            VAL_BYTES
+                + RC_HEADER
+                + size_of::<RefCell<Promise>>()
+                + promise.api_name.len()
+                + promise.payload.len()
+        }
+        _ => VAL_BYTES,
+    }
+}
+
+// bytes of a string or array repeated `count` times, before it is built;
+// None when no allocation could hold them
+pub fn repeated_size(val: &Val, count: i64) -> Option<usize> {
+    let (fixed, unit) = match val {
+        Val::Str(text) => (VAL_BYTES + RC_HEADER, text.len()),
+        Val::Array(arr) => (
+            VAL_BYTES + RC_HEADER + size_of::<RefCell<Array>>(),
+            arr.borrow().data.len() * size_of::<Val>(),
+        ),
+        _ => return Some(shallow_size(val)),
+    };
+    unit.checked_mul(usize::try_from(count).ok()?)?
+        .checked_add(fixed)
+        .filter(|bytes| *bytes <= isize::MAX as usize)
+}
+
+// bytes reachable from the value, shared objects are counted once and
+// reference cycles are cut through `seen`
+pub fn deep_size(val: &Val, seen: &mut HashSet<usize>) -> usize {
+    match val {
+        Val::Object(obj) => {
+            if !seen.insert(Rc::as_ptr(obj) as *const () as usize) {
+                return VAL_BYTES;
+            }
+            let mut total = shallow_size(val);
+            for item in obj.borrow().data.data.values() {
+                total += deep_size(item, seen);
+            }
+            total
+        }
+        Val::Array(arr) => {
+            if !seen.insert(Rc::as_ptr(arr) as *const () as usize) {
+                return VAL_BYTES;
+            }
+            let mut total = shallow_size(val);
+            for item in arr.borrow().data.iter() {
+                total += deep_size(item, seen);
+            }
+            total
+        }
+        Val::Function(func) => {
+            if !seen.insert(Rc::as_ptr(func) as *const () as usize) {
+                return VAL_BYTES;
+            }
+            let mut total = shallow_size(val);
+            for (_, cell) in func.borrow().captured.iter() {
+                total += cell_deep_size(cell, seen);
+            }
+            total
+        }
+        Val::Promise(promise) => {
+            if !seen.insert(Rc::as_ptr(promise) as *const () as usize) {
+                return VAL_BYTES;
+            }
+            let settled = match &promise.borrow().state {
+                PromiseState::Resolved(value) | PromiseState::Rejected(value) => {
+                    deep_size(value, seen)
+                }
+                PromiseState::Pending => 0,
+            };
+            shallow_size(val) + settled
+        }
+        _ => shallow_size(val),
+    }
+}
+
+// a captured variable with everything reachable from its value, counted
+// once however many frames and closures share it
+pub fn cell_deep_size(cell: &VarCell, seen: &mut HashSet<usize>) -> usize {
+    if !seen.insert(Rc::as_ptr(cell) as *const () as usize) {
+        return 0;
+    }
+    CELL_BYTES + deep_size(&cell.borrow(), seen)
+}
+
+// live bytes reachable from the globals and the call stack of a context
+pub fn context_size(ctx: &Context, seen: &mut HashSet<usize>) -> usize {
+    let mut total = 0;
+    for (key, item) in ctx.globals.data.iter() {
+        total += entry_size(key) + deep_size(item, seen);
+    }
+    for (class, blueprint) in ctx.blueprints.iter() {
+        total += entry_size(class);
+        for (key, item) in blueprint.def_props.data.iter() {
+            total += entry_size(key) + deep_size(item, seen);
+        }
+    }
+    for item in ctx.registers.iter() {
+        total += deep_size(item, seen);
+    }
+    for frame in ctx.frames.iter() {
+        total += FRAME_BYTES;
+        for cell in frame.cells.iter().chain(frame.upvalues.iter()) {
+            total += cell_deep_size(cell, seen);
+        }
+        if let Some(func) = &frame.func {
+            total += deep_size(&Val::Function(func.clone()), seen);
+        }
+    }
+    total
+}
+
+// Per executor allocation accounting. Allocations are charged as they
+// happen, and once the running estimate crosses the limit the live heap is
+// measured again from the roots, so values that went out of scope stop
+// counting against the quota.
+#[derive(Default)]
+pub struct MemoryMeter {
+    pub limit: Option<usize>,
+    // live bytes measured at the last scan
+    pub live: usize,
+    // bytes charged since the last scan
+    pub pending: usize,
+    // bytes charged over the lifetime of the executor
+    pub allocated: u64,
+}
+
+impl MemoryMeter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn estimate(&self) -> usize {
+        self.live + self.pending
+    }
+    pub fn needs_scan(&self, bytes: usize) -> bool {
+        match self.limit {
+            Some(limit) => self.estimate() + bytes > limit,
+            None => false,
+        }
+    }
+    pub fn rescanned(&mut self, live: usize) {
+        self.live = live;
+        self.pending = 0;
+    }
+    pub fn charge(&mut self, bytes: usize) {
+        self.pending += bytes;
+        self.allocated += bytes as u64;
+    }
+}
@@ -1,5 +1,20 @@
+pub mod bytecode;
 pub mod compiler;
 pub mod context;
 pub mod data;
+pub mod debugger;
+pub mod disassembler;
+pub mod error;
 pub mod executor;
+pub mod gc;
+pub mod lexer;
+pub mod linker;
+pub mod memory;
+pub mod parser;
+pub mod reload;
+pub mod snapshot;
+pub mod source_map;
+pub mod stdlib;
+pub mod timers;
 pub mod vm;
+pub mod wire;
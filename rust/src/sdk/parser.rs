@@ -0,0 +1,936 @@
+use serde_json::{json, Map, Value};
+
+use crate::sdk::{
+    error::SyntaxError,
+    lexer::{tokenize, Span, Token, TokenKind},
+};
+
+const KEYWORDS: [&str; 30] = [
+    "def", "func", "return", "if", "else", "loop", "switch", "case", "host", "true", "false", "as",
+    "try", "catch", "finally", "throw", "async", "await", "import", "export", "class", "extends",
+    "new", "this", "super", "is", "while", "for", "break", "continue",
+];
+
+const CAST_TYPES: [&str; 7] = ["i16", "i32", "i64", "f32", "f64", "string", "bool"];
+
+// guards the host stack against pathological input like 10k open parens
+const MAX_NESTING: usize = 128;
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    depth: usize,
+    errors: Vec<SyntaxError>,
+    // inside a method, whether its class extends another
+    method: Option<bool>,
+    // the loops around the statement being parsed in the current
+    // function, innermost last, with their labels
+    loops: Vec<Option<String>>,
+}
+
+fn describe(token: &Token) -> String {
+    match &token.kind {
+        TokenKind::Ident(name) if KEYWORDS.contains(&name.as_str()) => format!("'{}'", name),
+        TokenKind::Ident(name) => format!("identifier '{}'", name),
+        TokenKind::Int(value) => format!("number {}", value),
+        TokenKind::Float(value) => format!("number {}", value),
+        TokenKind::Str(value) => format!("string \"{}\"", value),
+        TokenKind::Symbol(symbol) => format!("'{}'", symbol),
+        TokenKind::Eof => "end of input".to_string(),
+    }
+}
+
+// integer literals take the narrowest type that holds them
+fn int_literal(value: i64) -> Value {
+    let typ = if i16::try_from(value).is_ok() {
+        "i16"
+    } else if i32::try_from(value).is_ok() {
+        "i32"
+    } else {
+        "i64"
+    };
+    node(typ, vec![("value", json!(value))])
+}
+
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.pos]
+    }
+    fn advance(&mut self) -> Token {
+        let token = self.tokens[self.pos].clone();
+        if token.kind != TokenKind::Eof {
+            self.pos += 1;
+        }
+        token
+    }
+    fn at_symbol(&self, symbol: &str) -> bool {
+        matches!(&self.peek().kind, TokenKind::Symbol(s) if *s == symbol)
+    }
+    fn at_keyword(&self, keyword: &str) -> bool {
+        matches!(&self.peek().kind, TokenKind::Ident(name) if name == keyword)
+    }
+    fn eat_symbol(&mut self, symbol: &str) -> bool {
+        if self.at_symbol(symbol) {
+            self.advance();
+            true
+        } else {
+            false
+        }
+    }
+    fn unexpected(&self, expected: &str) -> SyntaxError {
+        SyntaxError::new(
+            format!("expected {}, found {}", expected, describe(self.peek())),
+            self.peek().span,
+        )
+    }
+    fn expect_symbol(&mut self, symbol: &str, context: &str) -> Result<Token, SyntaxError> {
+        if self.at_symbol(symbol) {
+            Ok(self.advance())
+        } else {
+            Err(self.unexpected(&format!("'{}' {}", symbol, context)))
+        }
+    }
+    fn expect_ident(&mut self, what: &str) -> Result<String, SyntaxError> {
+        match &self.peek().kind {
+            TokenKind::Ident(name) if !KEYWORDS.contains(&name.as_str()) => {
+                let name = name.clone();
+                self.advance();
+                Ok(name)
+            }
+            _ => Err(self.unexpected(what)),
+        }
+    }
+    // span from `start` up to the end of the last consumed token
+    fn span_from(&self, start: Span) -> Span {
+        let last = &self.tokens[self.pos.saturating_sub(1)].span;
+        Span {
+            len: (last.offset + last.len).saturating_sub(start.offset),
+            ..start
+        }
+    }
+    fn enter(&mut self) -> Result<(), SyntaxError> {
+        self.depth += 1;
+        if self.depth > MAX_NESTING {
+            return Err(SyntaxError::new(
+                format!("nesting is deeper than {} levels", MAX_NESTING),
+                self.peek().span,
+            ));
+        }
+        Ok(())
+    }
+
+    // Skips the rest of a broken statement: up to a `;`, a closing brace or
+    // the first token on a new line.
+    fn synchronize(&mut self, failed_at: usize) {
+        if self.pos == failed_at && self.peek().kind != TokenKind::Eof {
+            self.advance();
+        }
+        while self.peek().kind != TokenKind::Eof {
+            if self.eat_symbol(";") || self.at_symbol("}") {
+                return;
+            }
+            if self.peek().span.line > self.tokens[self.pos - 1].span.line {
+                return;
+            }
+            self.advance();
+        }
+    }
+
+    fn parse_statements(&mut self, in_block: bool) -> Vec<Value> {
+        let mut body = vec![];
+        loop {
+            while self.eat_symbol(";") {}
+            if self.peek().kind == TokenKind::Eof || (in_block && self.at_symbol("}")) {
+                return body;
+            }
+            let (start, depth) = (self.pos, self.depth);
+            let span = self.peek().span;
+            match self.parse_statement() {
+                Ok(mut statement) => {
+                    statement["span"] = self.span_from(span).to_json();
+                    body.push(statement);
+                }
+                Err(error) => {
+                    self.errors.push(error);
+                    self.depth = depth;
+                    self.synchronize(start);
+                }
+            }
+        }
+    }
+
+    fn parse_block(&mut self, context: &str) -> Result<Vec<Value>, SyntaxError> {
+        self.expect_symbol("{", context)?;
+        self.enter()?;
+        let body = self.parse_statements(true);
+        self.depth -= 1;
+        self.expect_symbol("}", "to close the block")?;
+        Ok(body)
+    }
+
+    fn parse_statement(&mut self) -> Result<Value, SyntaxError> {
+        if self.at_keyword("import") || self.at_keyword("export") || self.at_keyword("class") {
+            return self.parse_module_statement();
+        }
+        if self.at_keyword("def") {
+            self.advance();
+            let name = self.expect_ident("a variable name after 'def'")?;
+            self.expect_symbol("=", "after the variable name")?;
+            let value = self.parse_expr()?;
+            return Ok(json!({
+                "type": "definition",
+                "data": {
+                    "leftSide": { "type": "identifier", "data": { "name": name } },
+                    "rightSide": value
+                }
+            }));
+        }
+        if self.at_keyword("func") {
+            return self.parse_function();
+        }
+        if self.at_keyword("return") {
+            self.advance();
+            let value = self.parse_expr()?;
+            return Ok(json!({ "type": "returnOperation", "data": { "value": value } }));
+        }
+        if self.at_keyword("if") {
+            return self.parse_if();
+        }
+        if self.at_keyword("loop") || self.at_keyword("while") || self.at_keyword("for") {
+            return self.parse_loop(None);
+        }
+        if let TokenKind::Ident(name) = &self.peek().kind {
+            let label = name.clone();
+            let next = &self.tokens[(self.pos + 1).min(self.tokens.len() - 1)];
+            if !KEYWORDS.contains(&label.as_str()) && next.kind == TokenKind::Symbol(":") {
+                self.advance();
+                self.advance();
+                if !self.at_keyword("loop") && !self.at_keyword("while") && !self.at_keyword("for")
+                {
+                    return Err(self.unexpected("a loop after the label"));
+                }
+                if self.loops.iter().flatten().any(|outer| *outer == label) {
+                    self.errors.push(SyntaxError::new(
+                        format!("label '{}' is already used by an enclosing loop", label),
+                        self.tokens[self.pos - 2].span,
+                    ));
+                }
+                return self.parse_loop(Some(label));
+            }
+        }
+        if self.at_keyword("break") || self.at_keyword("continue") {
+            return self.parse_jump();
+        }
+        if self.at_keyword("switch") {
+            return self.parse_switch();
+        }
+        if self.at_keyword("try") {
+            return self.parse_try();
+        }
+        if self.at_keyword("throw") {
+            self.advance();
+            let value = self.parse_expr()?;
+            return Ok(json!({ "type": "throwStmt", "data": { "value": value } }));
+        }
+
+        let start = self.peek().span;
+        let expr = self.parse_expr()?;
+        if self.at_symbol("=") {
+            let target_ok = match expr["type"].as_str() {
+                Some("identifier") => true,
+                Some("indexer") => expr["data"]["target"]["type"] == "identifier",
+                _ => false,
+            };
+            if !target_ok {
+                return Err(SyntaxError::new(
+                    "invalid assignment target, expected a variable or `name[index]`".to_string(),
+                    self.span_from(start),
+                ));
+            }
+            self.advance();
+            let value = self.parse_expr()?;
+            return Ok(json!({
+                "type": "assignment",
+                "data": { "leftSide": expr, "rightSide": value }
+            }));
+        }
+        match expr["type"].as_str() {
+            Some("functionCall") | Some("host_call") | Some("await") | Some("new")
+            | Some("superCall") => Ok(expr),
+            _ => Err(SyntaxError::new(
+                "expected a statement, this expression is never used".to_string(),
+                self.span_from(start),
+            )),
+        }
+    }
+
+    fn parse_function(&mut self) -> Result<Value, SyntaxError> {
+        self.advance();
+        let name = self.expect_ident("a function name after 'func'")?;
+        self.expect_symbol("(", "to start the parameter list")?;
+        let mut params = vec![];
+        while !self.at_symbol(")") {
+            params.push(self.expect_ident("a parameter name")?);
+            if !self.eat_symbol(",") {
+                break;
+            }
+        }
+        self.expect_symbol(")", "to close the parameter list")?;
+        // break and continue do not reach out of a function
+        let loops = std::mem::take(&mut self.loops);
+        let body = self.parse_block("to start the function body");
+        self.loops = loops;
+        let body = body?;
+        Ok(json!({
+            "type": "functionDefinition",
+            "data": { "name": name, "params": params, "body": body }
+        }))
+    }
+
+    // `import { a, b as c } from "module"`, `export def` / `export func` /
+    // `export class` and `class`, all only at the top level of a module
+    fn parse_module_statement(&mut self) -> Result<Value, SyntaxError> {
+        let keyword = self.advance();
+        // the whole statement is read first, so parsing goes on after it
+        let statement = self.parse_module_item(&keyword)?;
+        if self.depth > 0 {
+            return Err(SyntaxError::new(
+                format!("{} is only allowed at the top level", describe(&keyword)),
+                keyword.span,
+            ));
+        }
+        Ok(statement)
+    }
+
+    fn parse_module_item(&mut self, keyword: &Token) -> Result<Value, SyntaxError> {
+        if keyword.kind == TokenKind::Ident("class".to_string()) {
+            return self.parse_class();
+        }
+        if keyword.kind == TokenKind::Ident("export".to_string()) {
+            if self.at_keyword("class") {
+                let keyword = self.advance();
+                let mut statement = self.parse_module_item(&keyword)?;
+                statement["data"]["exported"] = json!(true);
+                return Ok(statement);
+            }
+            if !self.at_keyword("def") && !self.at_keyword("func") {
+                return Err(self.unexpected("'def', 'func' or 'class' after 'export'"));
+            }
+            let mut statement = self.parse_statement()?;
+            statement["data"]["exported"] = json!(true);
+            return Ok(statement);
+        }
+        self.expect_symbol("{", "to start the imported names")?;
+        let mut names = vec![];
+        while !self.at_symbol("}") {
+            let name = self.expect_ident("an imported name")?;
+            let alias = if self.at_keyword("as") {
+                self.advance();
+                self.expect_ident("a name after 'as'")?
+            } else {
+                name.clone()
+            };
+            names.push(json!({ "name": name, "as": alias }));
+            if !self.eat_symbol(",") {
+                break;
+            }
+        }
+        self.expect_symbol("}", "to close the imported names")?;
+        if !self.at_keyword("from") {
+            return Err(self.unexpected("'from' after the imported names"));
+        }
+        self.advance();
+        let TokenKind::Str(module) = self.peek().kind.clone() else {
+            return Err(self.unexpected("a module name string after 'from'"));
+        };
+        self.advance();
+        Ok(json!({
+            "type": "importStmt",
+            "data": { "module": module, "names": names }
+        }))
+    }
+
+    // `class Name extends Parent { field = default  func method(a) { } }`,
+    // fields without a default start out null
+    fn parse_class(&mut self) -> Result<Value, SyntaxError> {
+        let name = self.expect_ident("a class name after 'class'")?;
+        let parent = if self.at_keyword("extends") {
+            self.advance();
+            json!(self.expect_ident("a class name after 'extends'")?)
+        } else {
+            Value::Null
+        };
+        self.expect_symbol("{", "to start the class body")?;
+        self.enter()?;
+        let (mut fields, mut methods) = (vec![], vec![]);
+        let mut names: Vec<String> = vec![];
+        loop {
+            while self.eat_symbol(";") {}
+            if self.at_symbol("}") {
+                break;
+            }
+            let start = self.peek().span;
+            let member = if self.at_keyword("func") {
+                let outer = self.method.replace(!parent.is_null());
+                let method = self.parse_function();
+                self.method = outer;
+                let mut method = method?;
+                method["span"] = self.span_from(start).to_json();
+                methods.push(method);
+                methods.last().unwrap()["data"]["name"]
+                    .as_str()
+                    .unwrap()
+                    .to_string()
+            } else {
+                let field = self.expect_ident("a field, 'func' or '}' in the class body")?;
+                let value = if self.eat_symbol("=") {
+                    self.parse_expr()?
+                } else {
+                    Value::Null
+                };
+                fields.push(json!({ "name": field, "value": value }));
+                field
+            };
+            if names.contains(&member) {
+                self.errors.push(SyntaxError::new(
+                    format!("'{}' is declared twice in class '{}'", member, name),
+                    self.span_from(start),
+                ));
+            }
+            names.push(member);
+        }
+        self.depth -= 1;
+        self.advance();
+        Ok(json!({
+            "type": "classDefinition",
+            "data": { "name": name, "parent": parent, "fields": fields, "methods": methods }
+        }))
+    }
+
+    // `loop cond { }` / `while cond { }`, `for (init; cond; update) { }`,
+    // `for (k in obj) { }` and `for (v of arr) { }`, optionally labeled
+    fn parse_loop(&mut self, label: Option<String>) -> Result<Value, SyntaxError> {
+        let keyword = self.advance();
+        let mut data = if keyword.kind == TokenKind::Ident("for".to_string()) {
+            match self.parse_for_head() {
+                Ok(data) => data,
+                Err(error) => {
+                    // the body is read anyway, so parsing goes on after it
+                    let mut parens = 0;
+                    while self.peek().kind != TokenKind::Eof
+                        && !(parens <= 0 && self.at_symbol("{"))
+                    {
+                        match self.advance().kind {
+                            TokenKind::Symbol("(") => parens += 1,
+                            TokenKind::Symbol(")") => parens -= 1,
+                            _ => {}
+                        }
+                    }
+                    self.loops.push(label);
+                    let _ = self.parse_block("to start the loop body");
+                    self.loops.pop();
+                    return Err(error);
+                }
+            }
+        } else {
+            json!({ "condition": self.parse_expr()? })
+        };
+        let kind = match keyword.kind {
+            TokenKind::Ident(name) if name == "for" => match data.get("name") {
+                None => "forStmt",
+                Some(_) if data["of"] == true => "forOfStmt",
+                Some(_) => "forInStmt",
+            },
+            TokenKind::Ident(name) if name == "while" => "whileStmt",
+            _ => "loopStmt",
+        };
+        data.as_object_mut().unwrap().remove("of");
+        self.loops.push(label.clone());
+        let body = self.parse_block("to start the loop body");
+        self.loops.pop();
+        data["body"] = json!(body?);
+        if let Some(label) = label {
+            data["label"] = json!(label);
+        }
+        Ok(json!({ "type": kind, "data": data }))
+    }
+
+    fn parse_for_head(&mut self) -> Result<Value, SyntaxError> {
+        self.expect_symbol("(", "after 'for'")?;
+        if let TokenKind::Ident(name) = &self.peek().kind {
+            let next = &self.tokens[(self.pos + 1).min(self.tokens.len() - 1)].kind;
+            let of = matches!(next, TokenKind::Ident(word) if word == "of");
+            if of || matches!(next, TokenKind::Ident(word) if word == "in") {
+                let name = name.clone();
+                self.expect_ident("a variable name")?;
+                self.advance();
+                let value = self.parse_expr()?;
+                self.expect_symbol(")", "to close the loop head")?;
+                return Ok(json!({ "name": name, "value": value, "of": of }));
+            }
+        }
+        let init = self.parse_for_clause(";")?;
+        self.expect_symbol(";", "after the loop's first statement")?;
+        let condition = if self.at_symbol(";") {
+            Value::Null
+        } else {
+            self.parse_expr()?
+        };
+        self.expect_symbol(";", "after the loop condition")?;
+        let update = self.parse_for_clause(")")?;
+        self.expect_symbol(")", "to close the loop head")?;
+        Ok(json!({ "init": init, "condition": condition, "update": update }))
+    }
+
+    // a definition, an assignment or a call, or nothing before `end`
+    fn parse_for_clause(&mut self, end: &str) -> Result<Value, SyntaxError> {
+        if self.at_symbol(end) {
+            return Ok(Value::Null);
+        }
+        let start = self.peek().span;
+        let statement = self.parse_statement()?;
+        match statement["type"].as_str() {
+            Some("definition") | Some("assignment") | Some("functionCall") | Some("host_call")
+            | Some("await") => Ok(statement),
+            _ => Err(SyntaxError::new(
+                "expected a definition, an assignment or a call in the loop head".to_string(),
+                self.span_from(start),
+            )),
+        }
+    }
+
+    // `break` and `continue`, with the label of the loop they refer to
+    fn parse_jump(&mut self) -> Result<Value, SyntaxError> {
+        let keyword = self.advance();
+        let (kind, word) = match keyword.kind {
+            TokenKind::Ident(name) if name == "break" => ("breakStmt", "break"),
+            _ => ("continueStmt", "continue"),
+        };
+        let label = match &self.peek().kind {
+            TokenKind::Ident(name)
+                if !KEYWORDS.contains(&name.as_str())
+                    && self.peek().span.line == keyword.span.line =>
+            {
+                let label = name.clone();
+                self.advance();
+                Some(label)
+            }
+            _ => None,
+        };
+        if self.loops.is_empty() {
+            return Err(SyntaxError::new(
+                format!("'{}' is only allowed in loops", word),
+                keyword.span,
+            ));
+        }
+        if let Some(label) = &label {
+            if !self.loops.iter().flatten().any(|outer| outer == label) {
+                return Err(SyntaxError::new(
+                    format!("no enclosing loop is labeled '{}'", label),
+                    self.span_from(keyword.span),
+                ));
+            }
+        }
+        Ok(json!({ "type": kind, "data": { "label": label } }))
+    }
+
+    // `if cond { } else if cond { } else { }`, else-if arms nest as
+    // `elseifStmt` the same way compile_ast walks them
+    fn parse_if(&mut self) -> Result<Value, SyntaxError> {
+        self.advance();
+        let condition = self.parse_expr()?;
+        let body = self.parse_block("to start the if body")?;
+        let mut data = json!({ "condition": condition, "body": body });
+        if self.at_keyword("else") {
+            self.advance();
+            if self.at_keyword("if") {
+                self.enter()?;
+                data["elseifStmt"] = self.parse_if()?;
+                self.depth -= 1;
+            } else {
+                let body = self.parse_block("or 'if' after 'else'")?;
+                data["elseStmt"] = json!({ "data": { "body": body } });
+            }
+        }
+        Ok(json!({ "type": "ifStmt", "data": data }))
+    }
+
+    fn parse_switch(&mut self) -> Result<Value, SyntaxError> {
+        self.advance();
+        let value = self.parse_expr()?;
+        self.expect_symbol("{", "to start the switch cases")?;
+        let mut cases = vec![];
+        while !self.at_symbol("}") {
+            if !self.at_keyword("case") {
+                return Err(self.unexpected("'case' or '}'"));
+            }
+            self.advance();
+            let case_value = self.parse_expr()?;
+            let body = self.parse_block("to start the case body")?;
+            cases.push(json!({ "value": case_value, "body": { "body": body } }));
+        }
+        self.advance();
+        Ok(json!({
+            "type": "switchStmt",
+            "data": { "value": value, "cases": cases }
+        }))
+    }
+
+    // `try { } catch (e) { } finally { }`, the parentheses and the name are
+    // optional and either clause may be left out, but not both
+    fn parse_try(&mut self) -> Result<Value, SyntaxError> {
+        self.advance();
+        let body = self.parse_block("to start the try body")?;
+        let mut data = json!({ "body": body });
+        if self.at_keyword("catch") {
+            self.advance();
+            let parens = self.eat_symbol("(");
+            let param = if parens || matches!(self.peek().kind, TokenKind::Ident(_)) {
+                self.expect_ident("a name for the caught value")?
+            } else {
+                String::new()
+            };
+            if parens {
+                self.expect_symbol(")", "after the caught value's name")?;
+            }
+            let body = self.parse_block("to start the catch body")?;
+            data["catch"] = json!({ "param": param, "body": body });
+        }
+        if self.at_keyword("finally") {
+            self.advance();
+            let body = self.parse_block("to start the finally body")?;
+            data["finally"] = json!({ "body": body });
+        }
+        if data.get("catch").is_none() && data.get("finally").is_none() {
+            return Err(self.unexpected("'catch' or 'finally' after the try body"));
+        }
+        Ok(json!({ "type": "tryStmt", "data": data }))
+    }
+
+    fn parse_expr(&mut self) -> Result<Value, SyntaxError> {
+        self.enter()?;
+        let expr = self.parse_binary(0);
+        self.depth -= 1;
+        expr
+    }
+
+    // Precedence climbing, operators on `min_level` or tighter are folded
+    // in. `^` is right associative, everything else associates to the left.
+    // The functions on this recursive path stay small and leave building
+    // the nodes to `node`, so deep nesting doesn't eat the host stack.
+    fn parse_binary(&mut self, min_level: usize) -> Result<Value, SyntaxError> {
+        const LEVELS: [&[&str]; 5] = [
+            &["==", "!="],
+            &["<", "<=", ">", ">="],
+            &["+", "-"],
+            &["*", "/", "%"],
+            &["^"],
+        ];
+        // every fold nests the tree one level deeper, left or right
+        let depth = self.depth;
+        let mut left = self.parse_unary()?;
+        while let TokenKind::Symbol(operation) = self.peek().kind {
+            let level = match LEVELS.iter().position(|ops| ops.contains(&operation)) {
+                Some(level) if level >= min_level => level,
+                _ => break,
+            };
+            self.advance();
+            self.enter()?;
+            let right = if operation == "^" {
+                self.parse_binary(level)?
+            } else {
+                self.parse_binary(level + 1)?
+            };
+            left = arithmetic(operation, left, right);
+        }
+        self.depth = depth;
+        Ok(left)
+    }
+
+    fn parse_unary(&mut self) -> Result<Value, SyntaxError> {
+        if self.at_keyword("await") {
+            self.advance();
+            self.enter()?;
+            let value = self.parse_unary()?;
+            self.depth -= 1;
+            return Ok(node("await", vec![("value", value)]));
+        }
+        if self.at_symbol("!") || self.at_symbol("-") {
+            let negate = self.advance().kind == TokenKind::Symbol("-");
+            self.enter()?;
+            let value = self.parse_unary()?;
+            self.depth -= 1;
+            return Ok(if negate {
+                negative(value)
+            } else {
+                node("not", vec![("value", value)])
+            });
+        }
+        let mut value = self.parse_postfix()?;
+        while self.at_keyword("as") || self.at_keyword("is") {
+            if self.advance().kind == TokenKind::Ident("is".to_string()) {
+                let class = self.expect_ident("a class name after 'is'")?;
+                value = node(
+                    "instanceOf",
+                    vec![("value", value), ("class", json!(class))],
+                );
+                continue;
+            }
+            let target = match &self.peek().kind {
+                TokenKind::Ident(name) if CAST_TYPES.contains(&name.as_str()) => name.clone(),
+                _ => {
+                    return Err(
+                        self.unexpected("a cast type (i16, i32, i64, f32, f64, string, bool)")
+                    )
+                }
+            };
+            self.advance();
+            value = node(
+                "cast",
+                vec![("value", value), ("targetType", json!(target))],
+            );
+        }
+        Ok(value)
+    }
+
+    // `(a, b)`, also used for `[a, b]` with a different closing symbol
+    fn parse_list(
+        &mut self,
+        open: &str,
+        close: &str,
+        what: &str,
+    ) -> Result<Vec<Value>, SyntaxError> {
+        self.expect_symbol(open, &format!("to start the {}", what))?;
+        let mut items = vec![];
+        while !self.at_symbol(close) {
+            items.push(self.parse_expr()?);
+            if !self.eat_symbol(",") {
+                break;
+            }
+        }
+        self.expect_symbol(close, &format!("to close the {}", what))?;
+        Ok(items)
+    }
+
+    fn parse_postfix(&mut self) -> Result<Value, SyntaxError> {
+        let depth = self.depth;
+        let mut value = self.parse_primary()?;
+        loop {
+            if matches!(&self.peek().kind, TokenKind::Symbol("(" | "[" | ".")) {
+                self.enter()?;
+            }
+            if self.at_symbol("(") {
+                let args = self.parse_list("(", ")", "arguments")?;
+                value = node(
+                    "functionCall",
+                    vec![("callee", value), ("args", json!(args))],
+                );
+            } else if self.eat_symbol("[") {
+                let index = self.parse_expr()?;
+                self.expect_symbol("]", "to close the index")?;
+                value = node("indexer", vec![("target", value), ("index", index)]);
+            } else if self.eat_symbol(".") {
+                let key = self.expect_ident("a property name after '.'")?;
+                value = node("indexer", vec![("target", value), ("index", string(key))]);
+            } else {
+                self.depth = depth;
+                return Ok(value);
+            }
+        }
+    }
+
+    fn parse_primary(&mut self) -> Result<Value, SyntaxError> {
+        match &self.peek().kind {
+            TokenKind::Symbol("(") => {
+                self.advance();
+                let value = self.parse_expr()?;
+                self.expect_symbol(")", "to close the parenthesis")?;
+                Ok(value)
+            }
+            TokenKind::Symbol("[") => {
+                let items = self.parse_list("[", "]", "array")?;
+                Ok(node("array", vec![("value", json!(items))]))
+            }
+            TokenKind::Symbol("{") => self.parse_object(),
+            TokenKind::Ident(name) if name == "host" => self.parse_host_call(),
+            TokenKind::Ident(name) if name == "new" => {
+                self.advance();
+                let class = self.expect_ident("a class name after 'new'")?;
+                let args = self.parse_list("(", ")", "arguments")?;
+                Ok(node(
+                    "new",
+                    vec![("class", json!(class)), ("args", json!(args))],
+                ))
+            }
+            TokenKind::Ident(name) if name == "super" => {
+                if self.method != Some(true) {
+                    return Err(SyntaxError::new(
+                        "'super' is only allowed in methods of a class that extends another"
+                            .to_string(),
+                        self.peek().span,
+                    ));
+                }
+                self.advance();
+                self.expect_symbol(".", "after 'super'")?;
+                let method = self.expect_ident("a method name after 'super.'")?;
+                let args = self.parse_list("(", ")", "arguments")?;
+                Ok(node(
+                    "superCall",
+                    vec![("method", json!(method)), ("args", json!(args))],
+                ))
+            }
+            TokenKind::Ident(name) if name == "async" => {
+                self.advance();
+                if !self.at_keyword("host") {
+                    return Err(self.unexpected("a host call after 'async'"));
+                }
+                let mut call = self.parse_host_call()?;
+                call["data"]["async"] = json!(true);
+                Ok(call)
+            }
+            _ => self.parse_atom(),
+        }
+    }
+
+    fn parse_object(&mut self) -> Result<Value, SyntaxError> {
+        self.advance();
+        let mut entries = Map::new();
+        while !self.at_symbol("}") {
+            let key = match &self.peek().kind {
+                TokenKind::Str(key) => key.clone(),
+                TokenKind::Ident(key) => key.clone(),
+                _ => return Err(self.unexpected("a property name")),
+            };
+            self.advance();
+            self.expect_symbol(":", "after the property name")?;
+            entries.insert(key, self.parse_expr()?);
+            if !self.eat_symbol(",") {
+                break;
+            }
+        }
+        self.expect_symbol("}", "to close the object")?;
+        Ok(node("object", vec![("value", Value::Object(entries))]))
+    }
+
+    // `host.canvas.fillRect(x)` calls the host api "canvas.fillRect"
+    fn parse_host_call(&mut self) -> Result<Value, SyntaxError> {
+        self.advance();
+        self.expect_symbol(".", "after 'host'")?;
+        let mut api_name = self.expect_ident("a host api name")?;
+        while self.eat_symbol(".") {
+            api_name.push('.');
+            api_name.push_str(&self.expect_ident("a host api name")?);
+        }
+        let args = self.parse_list("(", ")", "arguments")?;
+        Ok(node(
+            "host_call",
+            vec![("name", json!(api_name)), ("args", json!(args))],
+        ))
+    }
+
+    fn parse_atom(&mut self) -> Result<Value, SyntaxError> {
+        let value = match &self.peek().kind {
+            TokenKind::Int(value) => int_literal(*value),
+            TokenKind::Float(value) => node("f64", vec![("value", json!(value))]),
+            TokenKind::Str(value) => string(value.clone()),
+            TokenKind::Ident(name) if name == "true" || name == "false" => {
+                node("bool", vec![("value", json!(name == "true"))])
+            }
+            TokenKind::Ident(name) if !KEYWORDS.contains(&name.as_str()) => {
+                node("identifier", vec![("name", json!(name))])
+            }
+            // methods get the instance as their parameter `this`
+            TokenKind::Ident(name) if name == "this" => {
+                if self.method.is_none() {
+                    return Err(SyntaxError::new(
+                        "'this' is only allowed in methods".to_string(),
+                        self.peek().span,
+                    ));
+                }
+                node("identifier", vec![("name", json!("this"))])
+            }
+            _ => return Err(self.unexpected("an expression")),
+        };
+        self.advance();
+        Ok(value)
+    }
+}
+
+fn node(typ: &str, data: Vec<(&str, Value)>) -> Value {
+    let data: Map<String, Value> = data
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), value))
+        .collect();
+    json!({ "type": typ, "data": data })
+}
+
+fn string(value: String) -> Value {
+    node("string", vec![("value", json!(value))])
+}
+
+fn arithmetic(operation: &str, left: Value, right: Value) -> Value {
+    node(
+        "arithmetic",
+        vec![
+            ("operation", json!(operation)),
+            ("operand1", left),
+            ("operand2", right),
+        ],
+    )
+}
+
+// negative literals are folded, anything else becomes `0 - value`
+fn negative(value: Value) -> Value {
+    let literal = &value["data"]["value"];
+    match value["type"].as_str() {
+        Some("i16") | Some("i32") | Some("i64") => {
+            int_literal(literal.as_i64().unwrap_or(0).wrapping_neg())
+        }
+        Some("f64") => node(
+            "f64",
+            vec![("value", json!(-literal.as_f64().unwrap_or(0.0)))],
+        ),
+        _ => arithmetic("-", int_literal(0), value),
+    }
+}
+
+/// Parses script source into the program AST `compile_ast` consumes.
+///
+/// Statements are definitions and assignments (`def x = e`, `x = e`,
+/// `x[i] = e`), calls, functions (`func f(a, b) { }` with `return e`),
+/// branches (`if c { } else if c { } else { }`, `switch v { case e { } }`),
+/// loops and errors. Loops are `loop c { }` or `while c { }`,
+/// `for (def i = 0; i < n; i = i + 1) { }`, `for (k in obj) { }` and
+/// `for (v of arr) { }`; any of them may be labeled (`outer: for ...`) for
+/// `break` and `continue` to name. Errors are raised with `throw e` and
+/// handled with `try { } catch (e) { } finally { }`.
+///
+/// The top level also holds imports (`import { a, b as c } from "module"`),
+/// `export` in front of `def`, `func` or `class` (see `linker`), and
+/// classes: `class C extends P { field = e  func method(a) { } }`, whose
+/// methods see the instance as `this` and reach the parent's methods
+/// through `super.name(args)`.
+///
+/// Expressions are literals, names, indexing, calls, the binary operators,
+/// `!`, `-`, `e as TYPE`, `new C(args)`, `e is C`, host api calls
+/// (`host.name(args)`, or `async host.name(args)` for a promise instead of
+/// a wait) and `await e`.
+///
+/// Every statement node carries the `span` it was parsed from. Parsing
+/// carries on past a broken statement, so every error is reported in one go.
+pub fn parse(src: &str) -> Result<Value, Vec<SyntaxError>> {
+    let mut parser = Parser {
+        tokens: tokenize(src)?,
+        pos: 0,
+        depth: 0,
+        errors: vec![],
+        method: None,
+        loops: vec![],
+    };
+    let body = parser.parse_statements(false);
+    if parser.errors.is_empty() {
+        Ok(json!({ "type": "program", "body": body }))
+    } else {
+        Err(parser.errors)
+    }
+}
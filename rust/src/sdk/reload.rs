@@ -0,0 +1,176 @@
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    rc::Rc,
+};
+
+use serde_json::{json, Value};
+
+use crate::sdk::{
+    bytecode::FunctionEntry,
+    data::{Function, Val, ValGroup},
+    error::SyntaxError,
+};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ReloadError {
+    /// A run is in flight; its frames point into the old program.
+    Busy,
+    Syntax(Vec<SyntaxError>),
+}
+
+/// A global whose value changed type because the new program binds a
+/// function under its name. Types are named like `Val::type_name`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TypeChange {
+    pub name: String,
+    pub from: String,
+    pub to: String,
+}
+
+/// What a reload did to the globals. Both lists are sorted by name.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ReloadReport {
+    /// Globals removed because they held a function the new program no
+    /// longer defines.
+    pub dropped: Vec<String>,
+    pub changed: Vec<TypeChange>,
+}
+
+impl ReloadReport {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "dropped": self.dropped,
+            "changed": self
+                .changed
+                .iter()
+                .map(|c| json!({ "name": c.name, "from": c.from, "to": c.to }))
+                .collect::<Vec<_>>(),
+        })
+    }
+}
+
+/// Moves the global scope over to a new program. Every function value
+/// reachable from `globals` or `callbacks` is pointed at the new definition
+/// of the same name, top-level definitions first, so aliases, closures and
+/// functions kept in arrays, objects or by the host follow the new code. A
+/// function without a definition is left with an empty body; globals
+/// holding one are dropped. Then the top-level functions of the new program
+/// are bound, replacing whatever value had their name.
+pub(crate) fn rebind_globals(
+    globals: &mut ValGroup,
+    top_level: &[FunctionEntry],
+    functions: &[FunctionEntry],
+    callbacks: &[Rc<RefCell<Function>>],
+) -> ReloadReport {
+    let mut by_name: HashMap<&str, &FunctionEntry> = HashMap::new();
+    for entry in functions.iter().chain(top_level.iter()) {
+        by_name.insert(entry.name.as_str(), entry);
+    }
+    let mut seen = HashSet::new();
+    for val in globals.data.values() {
+        retarget(val, &by_name, &mut seen);
+    }
+    for func in callbacks.iter() {
+        retarget_func(func, &by_name, &mut seen);
+    }
+
+    let mut report = ReloadReport::default();
+    let mut names: Vec<String> = globals.data.keys().cloned().collect();
+    names.sort();
+    for name in names.iter() {
+        let Val::Function(func) = &globals.data[name] else {
+            continue;
+        };
+        if !by_name.contains_key(func.borrow().name.as_str()) {
+            globals.data.remove(name);
+            report.dropped.push(name.clone());
+        }
+    }
+    for entry in top_level.iter() {
+        if let Some(old) = globals.data.get(&entry.name) {
+            // already pointed at this definition above, keep its identity
+            match old {
+                Val::Function(func) if func.borrow().name == entry.name => continue,
+                Val::Function(_) => {}
+                _ => report.changed.push(TypeChange {
+                    name: entry.name.clone(),
+                    from: old.type_name().to_string(),
+                    to: "function".to_string(),
+                }),
+            }
+        }
+        let func = Function::new(
+            entry.name.clone(),
+            entry.start,
+            entry.end,
+            entry.params.clone(),
+        );
+        globals.data.insert(entry.name.clone(), Val::func(func));
+    }
+    report.changed.sort_by(|a, b| a.name.cmp(&b.name));
+    report
+}
+
+fn retarget(val: &Val, by_name: &HashMap<&str, &FunctionEntry>, seen: &mut HashSet<*const ()>) {
+    match val {
+        Val::Object(object) if seen.insert(Rc::as_ptr(object) as *const ()) => {
+            for prop in object.borrow().data.data.values() {
+                retarget(prop, by_name, seen);
+            }
+        }
+        Val::Array(array) if seen.insert(Rc::as_ptr(array) as *const ()) => {
+            for item in array.borrow().data.iter() {
+                retarget(item, by_name, seen);
+            }
+        }
+        Val::Function(func) => retarget_func(func, by_name, seen),
+        _ => {}
+    }
+}
+
+fn retarget_func(
+    func: &Rc<RefCell<Function>>,
+    by_name: &HashMap<&str, &FunctionEntry>,
+    seen: &mut HashSet<*const ()>,
+) {
+    if !seen.insert(Rc::as_ptr(func) as *const ()) {
+        return;
+    }
+    let captured = {
+        let mut func = func.borrow_mut();
+        match by_name.get(func.name.as_str()) {
+            Some(entry) => {
+                func.start = entry.start;
+                func.end = entry.end;
+                func.params = entry.params.clone();
+                // the new body may capture other variables: keep the ones
+                // both versions share, new ones start out null
+                let old = std::mem::take(&mut func.captured);
+                func.captured = entry
+                    .upvalues
+                    .iter()
+                    .map(|upvalue| {
+                        let cell = old
+                            .iter()
+                            .find(|(name, _)| *name == upvalue.name)
+                            .map(|(_, cell)| cell.clone())
+                            .unwrap_or_else(|| Rc::new(RefCell::new(Val::Null)));
+                        (upvalue.name.clone(), cell)
+                    })
+                    .collect();
+            }
+            None => {
+                func.start = 0;
+                func.end = 0;
+                func.params = vec![];
+            }
+        }
+        func.captured.clone()
+    };
+    for (_, cell) in captured.iter() {
+        if seen.insert(Rc::as_ptr(cell) as *const ()) {
+            retarget(&cell.borrow(), by_name, seen);
+        }
+    }
+}
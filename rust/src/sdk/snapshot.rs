@@ -0,0 +1,497 @@
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::sdk::{
+    context::Ret,
+    data::{Array, Function, Object, Promise, PromiseState, Val, ValGroup, VarCell},
+    error::VmError,
+    executor::TryFrame,
+    gc::CycleCollector,
+};
+
+pub const SNAPSHOT_FORMAT: &str = "elpian-snapshot";
+/// Bumped whenever the layout below or the meaning of a saved field changes.
+pub const SNAPSHOT_VERSION: u32 = 9;
+
+/// A VM's runtime state as saved by `VM::snapshot`. Values and captured
+/// variables are stored once in `heap` and referenced by index, so shared
+/// references, cycles, functions and the variables they captured come back
+/// as the same graph.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Snapshot {
+    pub format: String,
+    pub version: u32,
+    /// FNV-1a of the program, a snapshot only restores into the same code.
+    pub program_checksum: u32,
+    pub heap: Heap,
+    pub executor: ExecutorSnapshot,
+    /// The host call the run is waiting on, sent again after a restore.
+    pub host_call: Option<HostCallData>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct HostCallData {
+    pub id: i64,
+    pub api_name: String,
+    /// Deprecated legacy output, the input stringified. `input` is the
+    /// lossless form hosts should read.
+    pub payload: String,
+    pub input: Value,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ExecutorSnapshot {
+    pub cb_counter: i64,
+    pub run_cb_id: i64,
+    pub exec_globally: bool,
+    pub processing: bool,
+    pub globals: Vec<(String, ValRef)>,
+    /// Field defaults of the declared classes, in declaration order.
+    pub classes: Vec<(String, Vec<(String, ValRef)>)>,
+    /// The call stack, outermost first.
+    pub frames: Vec<FrameData>,
+    pub registers: Vec<ValRef>,
+    /// Array walks (`map`, `filter`, `reduce`) waiting on their callback.
+    pub walks: Vec<WalkData>,
+    /// Functions handed to the host, as indexes into `Heap::functions`.
+    pub callbacks: Vec<(String, usize)>,
+    /// Try statements in progress.
+    pub try_frames: Vec<TryFrame<ValRef>>,
+    /// Promises of async host calls the host has not settled, as indexes
+    /// into `Heap::promises`; they are sent to the host again on restore.
+    pub promises: Vec<usize>,
+    /// The promise an `await` suspended the run on.
+    pub awaiting: Option<usize>,
+    /// The register a host call or `await` answers into.
+    pub resume_to: Option<usize>,
+    /// Whether the run stopped for fuel or a debugger pause.
+    pub suspended: bool,
+    pub fuel: u64,
+    pub allocated_bytes: u64,
+    pub timers: TimerQueueData,
+    /// State of the generator behind `Math.random`.
+    pub rng: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct FrameData {
+    /// Index into `Heap::functions`, absent for the main body.
+    pub func: Option<usize>,
+    pub proto: usize,
+    pub pc: usize,
+    pub base: usize,
+    /// Indexes into `Heap::captures`.
+    pub cells: Vec<usize>,
+    pub upvalues: Vec<usize>,
+    pub ret: Ret,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct WalkData {
+    pub kind: String,
+    pub items: Vec<ValRef>,
+    /// Index into `Heap::functions`.
+    pub func: usize,
+    pub acc: ValRef,
+    pub index: usize,
+    pub dst: usize,
+    pub frame: usize,
+}
+
+/// The timer queue, including a tick that is under way.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct TimerQueueData {
+    pub next_id: i64,
+    pub now: i64,
+    pub timers: Vec<TimerData>,
+    /// Ids the tick in progress has yet to run.
+    pub firing: Vec<i64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct TimerData {
+    pub id: i64,
+    /// Index into `Heap::functions`.
+    pub func: usize,
+    pub due: i64,
+    pub interval: Option<i64>,
+}
+
+/// A `Val`: its type code and the heap cell holding its data.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub(crate) struct ValRef(pub i64, pub usize);
+
+/// The data of a `Val`. Floats are kept as bits so they round trip
+/// exactly, NaN included.
+#[derive(Serialize, Deserialize)]
+pub(crate) enum CellData {
+    I16(i16),
+    I32(i32),
+    I64(i64),
+    F32(u32),
+    F64(u64),
+    Bool(bool),
+    Str(String),
+    Object(usize),
+    Array(usize),
+    Function(usize),
+    Promise(usize),
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct ObjectData {
+    pub typ: i64,
+    pub props: Vec<(String, ValRef)>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct FunctionData {
+    pub name: String,
+    pub start: usize,
+    pub end: usize,
+    pub params: Vec<String>,
+    /// Names and indexes into `Heap::captures`.
+    pub captured: Vec<(String, usize)>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) enum SettledData {
+    Pending,
+    Resolved(ValRef),
+    Rejected(ValRef),
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct PromiseData {
+    pub call_id: i64,
+    pub api_name: String,
+    pub payload: String,
+    pub input: Value,
+    pub state: SettledData,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub(crate) struct Heap {
+    pub cells: Vec<CellData>,
+    pub objects: Vec<ObjectData>,
+    pub arrays: Vec<Vec<ValRef>>,
+    pub functions: Vec<FunctionData>,
+    /// Variables shared by frames and closures.
+    pub captures: Vec<ValRef>,
+    pub promises: Vec<PromiseData>,
+}
+
+pub(crate) fn bad(detail: &str) -> VmError {
+    VmError::BadSnapshot(detail.to_string())
+}
+
+fn pick<T: Clone>(items: &[T], index: usize) -> Result<T, VmError> {
+    items
+        .get(index)
+        .cloned()
+        .ok_or_else(|| bad(&format!("reference {} points outside the heap", index)))
+}
+
+// identity of an Rc allocation, used to store every shared value once
+fn key<T: ?Sized>(rc: &Rc<T>) -> *const () {
+    Rc::as_ptr(rc) as *const ()
+}
+
+/// Flattens `Val` graphs into a `Heap`, keeping one entry per allocation.
+#[derive(Default)]
+pub(crate) struct Saver {
+    pub heap: Heap,
+    objects: HashMap<*const (), usize>,
+    arrays: HashMap<*const (), usize>,
+    functions: HashMap<*const (), usize>,
+    captures: HashMap<*const (), usize>,
+    promises: HashMap<*const (), usize>,
+}
+
+impl Saver {
+    pub fn val(&mut self, val: &Val) -> Result<ValRef, VmError> {
+        let data = match val {
+            Val::I16(v) => CellData::I16(*v),
+            Val::I32(v) => CellData::I32(*v),
+            Val::I64(v) => CellData::I64(*v),
+            Val::F32(v) => CellData::F32(v.to_bits()),
+            Val::F64(v) => CellData::F64(v.to_bits()),
+            Val::Bool(v) => CellData::Bool(*v),
+            Val::Str(v) | Val::Native(v) => CellData::Str(v.to_string()),
+            Val::Object(v) => CellData::Object(self.object(v)?),
+            Val::Array(v) => CellData::Array(self.array(v)?),
+            Val::Function(v) => CellData::Function(self.function(v)?),
+            Val::Promise(v) => CellData::Promise(self.promise(v)?),
+            Val::Error(_) => {
+                return Err(bad(&format!(
+                    "a value of type {} can not be saved",
+                    val.typ()
+                )))
+            }
+            // the type code says it all
+            _ => CellData::I32(0),
+        };
+        let cell = self.heap.cells.len();
+        self.heap.cells.push(data);
+        Ok(ValRef(val.typ(), cell))
+    }
+    fn object(&mut self, object: &Rc<RefCell<Object>>) -> Result<usize, VmError> {
+        if let Some(index) = self.objects.get(&key(object)) {
+            return Ok(*index);
+        }
+        let index = self.heap.objects.len();
+        self.objects.insert(key(object), index);
+        self.heap.objects.push(ObjectData {
+            typ: object.borrow().typ,
+            props: vec![],
+        });
+        let props = self.props(&object.borrow().data)?;
+        self.heap.objects[index].props = props;
+        Ok(index)
+    }
+    fn array(&mut self, array: &Rc<RefCell<Array>>) -> Result<usize, VmError> {
+        if let Some(index) = self.arrays.get(&key(array)) {
+            return Ok(*index);
+        }
+        let index = self.heap.arrays.len();
+        self.arrays.insert(key(array), index);
+        self.heap.arrays.push(vec![]);
+        let items = array
+            .borrow()
+            .data
+            .iter()
+            .map(|item| self.val(item))
+            .collect::<Result<Vec<_>, _>>()?;
+        self.heap.arrays[index] = items;
+        Ok(index)
+    }
+    pub fn function(&mut self, func: &Rc<RefCell<Function>>) -> Result<usize, VmError> {
+        if let Some(index) = self.functions.get(&key(func)) {
+            return Ok(*index);
+        }
+        let index = self.heap.functions.len();
+        self.functions.insert(key(func), index);
+        let func = func.borrow();
+        self.heap.functions.push(FunctionData {
+            name: func.name.clone(),
+            start: func.start,
+            end: func.end,
+            params: func.params.clone(),
+            captured: vec![],
+        });
+        let captured = func
+            .captured
+            .iter()
+            .map(|(name, cell)| Ok((name.clone(), self.capture(cell)?)))
+            .collect::<Result<Vec<_>, VmError>>()?;
+        self.heap.functions[index].captured = captured;
+        Ok(index)
+    }
+    pub fn promise(&mut self, promise: &Rc<RefCell<Promise>>) -> Result<usize, VmError> {
+        if let Some(index) = self.promises.get(&key(promise)) {
+            return Ok(*index);
+        }
+        let index = self.heap.promises.len();
+        self.promises.insert(key(promise), index);
+        let promise = promise.borrow();
+        self.heap.promises.push(PromiseData {
+            call_id: promise.call_id,
+            api_name: promise.api_name.clone(),
+            payload: promise.payload.clone(),
+            input: promise.input.clone(),
+            state: SettledData::Pending,
+        });
+        let state = match &promise.state {
+            PromiseState::Pending => SettledData::Pending,
+            PromiseState::Resolved(value) => SettledData::Resolved(self.val(value)?),
+            PromiseState::Rejected(error) => SettledData::Rejected(self.val(error)?),
+        };
+        self.heap.promises[index].state = state;
+        Ok(index)
+    }
+    pub fn capture(&mut self, cell: &VarCell) -> Result<usize, VmError> {
+        if let Some(index) = self.captures.get(&key(cell)) {
+            return Ok(*index);
+        }
+        let index = self.heap.captures.len();
+        self.captures.insert(key(cell), index);
+        self.heap.captures.push(ValRef(0, 0));
+        let val = self.val(&cell.borrow())?;
+        self.heap.captures[index] = val;
+        Ok(index)
+    }
+    // sorted by name so equal states give equal snapshots
+    pub fn props(&mut self, group: &ValGroup) -> Result<Vec<(String, ValRef)>, VmError> {
+        let mut names: Vec<&String> = group.data.keys().collect();
+        names.sort();
+        names
+            .into_iter()
+            .map(|name| Ok((name.clone(), self.val(&group.data[name])?)))
+            .collect()
+    }
+}
+
+/// Rebuilds the allocations of a `Heap`. Every container is created empty
+/// first and filled afterwards, so references between them (cycles
+/// included) resolve to the shared instance.
+pub(crate) struct Loader {
+    cells: Vec<CellData>,
+    objects: Vec<Rc<RefCell<Object>>>,
+    arrays: Vec<Rc<RefCell<Array>>>,
+    functions: Vec<Rc<RefCell<Function>>>,
+    captures: Vec<VarCell>,
+    promises: Vec<Rc<RefCell<Promise>>>,
+}
+
+impl Loader {
+    pub fn new(heap: Heap) -> Result<Self, VmError> {
+        let objects: Vec<Rc<RefCell<Object>>> = heap
+            .objects
+            .iter()
+            .map(|object| Rc::new(RefCell::new(Object::new(object.typ, ValGroup::new_empty()))))
+            .collect();
+        let arrays: Vec<Rc<RefCell<Array>>> = heap
+            .arrays
+            .iter()
+            .map(|_| Rc::new(RefCell::new(Array::new_empty())))
+            .collect();
+        // captured variables are created null and given their value once
+        // every container exists
+        let captures: Vec<VarCell> = heap
+            .captures
+            .iter()
+            .map(|_| Rc::new(RefCell::new(Val::Null)))
+            .collect();
+        let mut functions: Vec<Rc<RefCell<Function>>> = vec![];
+        for f in heap.functions.into_iter() {
+            let mut func = Function::new(f.name, f.start, f.end, f.params);
+            func.captured = f
+                .captured
+                .into_iter()
+                .map(|(name, index)| Ok((name, pick(&captures, index)?)))
+                .collect::<Result<_, VmError>>()?;
+            functions.push(Rc::new(RefCell::new(func)));
+        }
+        // promises are settled once every value exists
+        let promises: Vec<Rc<RefCell<Promise>>> = heap
+            .promises
+            .iter()
+            .map(|data| {
+                Rc::new(RefCell::new(Promise::new(
+                    data.call_id,
+                    data.api_name.clone(),
+                    data.payload.clone(),
+                    data.input.clone(),
+                )))
+            })
+            .collect();
+        let loader = Loader {
+            cells: heap.cells,
+            objects,
+            arrays,
+            functions,
+            captures,
+            promises,
+        };
+        for (object, data) in loader.objects.iter().zip(heap.objects.iter()) {
+            object.borrow_mut().data = loader.group_of(&data.props)?;
+        }
+        for (array, items) in loader.arrays.iter().zip(heap.arrays.iter()) {
+            array.borrow_mut().data = items
+                .iter()
+                .map(|item| loader.val(*item))
+                .collect::<Result<_, _>>()?;
+        }
+        for (promise, data) in loader.promises.iter().zip(heap.promises.iter()) {
+            promise.borrow_mut().state = match data.state {
+                SettledData::Pending => PromiseState::Pending,
+                SettledData::Resolved(value) => PromiseState::Resolved(loader.val(value)?),
+                SettledData::Rejected(error) => PromiseState::Rejected(loader.val(error)?),
+            };
+        }
+        for (cell, val) in loader.captures.iter().zip(heap.captures.iter()) {
+            *cell.borrow_mut() = loader.val(*val)?;
+        }
+        Ok(loader)
+    }
+    pub fn val(&self, val: ValRef) -> Result<Val, VmError> {
+        let data = self
+            .cells
+            .get(val.1)
+            .ok_or_else(|| bad(&format!("value {} points outside the heap", val.1)))?;
+        Ok(match (val.0, data) {
+            (1, CellData::I16(v)) => Val::I16(*v),
+            (2, CellData::I32(v)) => Val::I32(*v),
+            (3, CellData::I64(v)) => Val::I64(*v),
+            (4, CellData::F32(v)) => Val::F32(f32::from_bits(*v)),
+            (5, CellData::F64(v)) => Val::F64(f64::from_bits(*v)),
+            (6, CellData::Bool(v)) => Val::Bool(*v),
+            (7, CellData::Str(v)) => Val::string(v.as_str()),
+            (8, CellData::Object(i)) => Val::Object(pick(&self.objects, *i)?),
+            (9, CellData::Array(i)) => Val::Array(pick(&self.arrays, *i)?),
+            (10, CellData::Function(i)) => Val::Function(pick(&self.functions, *i)?),
+            (11, CellData::Promise(i)) => Val::Promise(pick(&self.promises, *i)?),
+            (255, CellData::Str(v)) => Val::Native(v.as_str().into()),
+            (249, _) => Val::Awaiting,
+            (250, _) => Val::Paused,
+            (251, _) => Val::OutOfFuel,
+            (253, _) => Val::HostCall,
+            (254, _) => Val::NoResult,
+            (typ, _) if !(1..=11).contains(&typ) && typ != 255 => Val::Null,
+            _ => {
+                return Err(bad(&format!(
+                    "value {} does not hold data of type {}",
+                    val.1, val.0
+                )))
+            }
+        })
+    }
+    pub fn capture(&self, index: usize) -> Result<VarCell, VmError> {
+        self.captures.get(index).cloned().ok_or_else(|| {
+            bad(&format!(
+                "captured variable {} points outside the heap",
+                index
+            ))
+        })
+    }
+    pub fn function(&self, index: usize) -> Result<Rc<RefCell<Function>>, VmError> {
+        self.functions
+            .get(index)
+            .cloned()
+            .ok_or_else(|| bad(&format!("function {} points outside the heap", index)))
+    }
+    pub fn promise(&self, index: usize) -> Result<Rc<RefCell<Promise>>, VmError> {
+        self.promises
+            .get(index)
+            .cloned()
+            .ok_or_else(|| bad(&format!("promise {} points outside the heap", index)))
+    }
+    /// Hands every loaded container to the cycle collector, the saved
+    /// state may well hold cycles.
+    pub fn track(&self, gc: &mut CycleCollector) {
+        for object in self.objects.iter() {
+            gc.track(&Val::Object(object.clone()));
+        }
+        for array in self.arrays.iter() {
+            gc.track(&Val::Array(array.clone()));
+        }
+        for cell in self.captures.iter() {
+            gc.track_cell(cell);
+        }
+    }
+    pub fn group_of(&self, vars: &[(String, ValRef)]) -> Result<ValGroup, VmError> {
+        let mut data = HashMap::new();
+        for (name, val) in vars.iter() {
+            data.insert(name.clone(), self.val(*val)?);
+        }
+        Ok(ValGroup::new(data))
+    }
+}
@@ -0,0 +1,108 @@
+use std::fmt;
+
+use serde_json::{json, Value};
+
+use crate::sdk::lexer::Span;
+
+/// One compiled statement: the bytecode range `start..end` it occupies, the
+/// path of its node in the AST (e.g. `body[2].data.body[0]`) and, when the
+/// AST came from source text, where the statement starts in that text.
+/// `module` names the imported module the statement belongs to, `None` for
+/// the program itself.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SourceMapEntry {
+    pub start: usize,
+    pub end: usize,
+    pub path: String,
+    pub span: Option<Span>,
+    pub module: Option<String>,
+}
+
+/// Side table from bytecode offsets back to the AST and source, emitted by
+/// `compile_ast_with_source_map`. Entries of nested statements overlap the
+/// statements that contain them.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SourceMap {
+    pub entries: Vec<SourceMapEntry>,
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        SourceMap { entries: vec![] }
+    }
+    /// The innermost statement whose bytecode holds `offset`.
+    pub fn lookup(&self, offset: usize) -> Option<&SourceMapEntry> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.start <= offset && offset < entry.end)
+            .min_by_key(|entry| entry.end - entry.start)
+    }
+    /// Where the first statement of the program itself starting on source
+    /// line `line` begins.
+    pub fn line_offset(&self, line: usize) -> Option<usize> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.module.is_none())
+            .filter(|entry| entry.span.is_some_and(|span| span.line == line))
+            .map(|entry| entry.start)
+            .min()
+    }
+    pub fn to_json(&self) -> Value {
+        Value::Array(
+            self.entries
+                .iter()
+                .map(|entry| {
+                    json!({
+                        "start": entry.start,
+                        "end": entry.end,
+                        "path": entry.path,
+                        "span": entry.span.map(|span| span.to_json()),
+                        "module": entry.module,
+                    })
+                })
+                .collect(),
+        )
+    }
+}
+
+/// One function activation of a failed run, innermost first. `offset` is
+/// where that function was executing; `path`, `span` and `module` are
+/// resolved from the source map when the VM has one.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StackFrame {
+    pub function: String,
+    pub offset: usize,
+    pub path: Option<String>,
+    pub span: Option<Span>,
+    pub module: Option<String>,
+}
+
+impl StackFrame {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "function": self.function,
+            "offset": self.offset,
+            "path": self.path,
+            "line": self.span.map(|span| span.line),
+            "column": self.span.map(|span| span.column),
+            "module": self.module,
+        })
+    }
+}
+
+impl fmt::Display for StackFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.span, &self.path) {
+            (Some(span), _) => match &self.module {
+                Some(module) => write!(
+                    f,
+                    "at {} ({}:{}:{})",
+                    self.function, module, span.line, span.column
+                ),
+                None => write!(f, "at {} ({}:{})", self.function, span.line, span.column),
+            },
+            (None, Some(path)) => write!(f, "at {} ({})", self.function, path),
+            (None, None) => write!(f, "at {} (offset {})", self.function, self.offset),
+        }
+    }
+}
@@ -0,0 +1,384 @@
+use std::{
+    cell::RefCell,
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    rc::Rc,
+};
+
+use serde_json::{Map, Number, Value};
+
+use crate::sdk::{
+    data::{Array, Object, Val, ValGroup},
+    error::VmError,
+};
+
+/// Globals holding the built-in functions, looked up when a script does
+/// not define a variable under the same name.
+pub const NAMESPACES: [&str; 5] = ["Math", "String", "Array", "Object", "JSON"];
+
+const MATH: [&str; 19] = [
+    "abs", "floor", "ceil", "round", "sqrt", "pow", "sin", "cos", "tan", "asin", "acos", "atan",
+    "atan2", "log", "exp", "min", "max", "random", "seed",
+];
+const STRING: [&str; 8] = [
+    "length",
+    "substring",
+    "split",
+    "indexOf",
+    "replace",
+    "upper",
+    "lower",
+    "trim",
+];
+const ARRAY: [&str; 9] = [
+    "length", "push", "pop", "slice", "sort", "join", "map", "filter", "reduce",
+];
+const OBJECT: [&str; 2] = ["keys", "values"];
+const JSON: [&str; 2] = ["parse", "stringify"];
+
+/// The built-ins that call a script function for every item. The executor
+/// runs them itself, so the callback may pause like any other call.
+pub const ARRAY_WALKS: [&str; 3] = ["Array.map", "Array.filter", "Array.reduce"];
+
+/// Seed of the random generator until a script calls `Math.seed`.
+pub const DEFAULT_SEED: u64 = 0x853c_49e6_748f_ea9b;
+
+fn functions(namespace: &str) -> &'static [&'static str] {
+    match namespace {
+        "Math" => &MATH,
+        "String" => &STRING,
+        "Array" => &ARRAY,
+        "Object" => &OBJECT,
+        "JSON" => &JSON,
+        _ => &[],
+    }
+}
+
+/// Whether `name`, like `Math.sqrt`, is a built-in function.
+pub fn is_native(name: &str) -> bool {
+    match name.split_once('.') {
+        Some((namespace, func)) => functions(namespace).contains(&func),
+        None => false,
+    }
+}
+
+/// The object a namespace global evaluates to: its functions as native
+/// function values (type 255) plus the constants of `Math`.
+pub fn namespace(name: &str) -> Option<Val> {
+    if !NAMESPACES.contains(&name) {
+        return None;
+    }
+    let mut props = HashMap::new();
+    for func in functions(name) {
+        props.insert(
+            func.to_string(),
+            Val::Native(format!("{}.{}", name, func).into()),
+        );
+    }
+    if name == "Math" {
+        props.insert("PI".to_string(), Val::F64(std::f64::consts::PI));
+        props.insert("E".to_string(), Val::F64(std::f64::consts::E));
+    }
+    Some(object(props))
+}
+
+/// Calls the built-in `name` with `args`. `rng` is the state of the random
+/// generator behind `Math.random`.
+pub fn call(name: &str, args: &[Val], rng: &mut u64) -> Result<Val, VmError> {
+    let args = Args { name, args };
+    Ok(match name {
+        "Math.abs" if args.all_ints() => Val::I64(args.int(0)?.wrapping_abs()),
+        "Math.abs" => Val::F64(args.num(0)?.abs()),
+        "Math.floor" => Val::I64(args.num(0)?.floor() as i64),
+        "Math.ceil" => Val::I64(args.num(0)?.ceil() as i64),
+        "Math.round" => Val::I64(args.num(0)?.round() as i64),
+        "Math.sqrt" => Val::F64(args.num(0)?.sqrt()),
+        "Math.pow" => Val::F64(args.num(0)?.powf(args.num(1)?)),
+        "Math.sin" => Val::F64(args.num(0)?.sin()),
+        "Math.cos" => Val::F64(args.num(0)?.cos()),
+        "Math.tan" => Val::F64(args.num(0)?.tan()),
+        "Math.asin" => Val::F64(args.num(0)?.asin()),
+        "Math.acos" => Val::F64(args.num(0)?.acos()),
+        "Math.atan" => Val::F64(args.num(0)?.atan()),
+        "Math.atan2" => Val::F64(args.num(0)?.atan2(args.num(1)?)),
+        "Math.log" => Val::F64(args.num(0)?.ln()),
+        "Math.exp" => Val::F64(args.num(0)?.exp()),
+        "Math.min" | "Math.max" => {
+            if args.args.is_empty() {
+                return Err(args.mismatch("at least one number"));
+            }
+            let max = name == "Math.max";
+            if args.all_ints() {
+                let ints = (0..args.args.len()).map(|i| args.int(i));
+                let ints = ints.collect::<Result<Vec<_>, _>>()?.into_iter();
+                Val::I64(if max { ints.max() } else { ints.min() }.unwrap())
+            } else {
+                let mut result = args.num(0)?;
+                for i in 1..args.args.len() {
+                    let n = args.num(i)?;
+                    result = if max { result.max(n) } else { result.min(n) };
+                }
+                Val::F64(result)
+            }
+        }
+        "Math.random" => Val::F64((next_random(rng) >> 11) as f64 / (1u64 << 53) as f64),
+        "Math.seed" => {
+            *rng = seed_state(args.int(0)?);
+            Val::Null
+        }
+        "String.length" => Val::I64(args.str(0)?.chars().count() as i64),
+        "String.substring" => {
+            let chars: Vec<char> = args.str(0)?.chars().collect();
+            let len = chars.len() as i64;
+            let start = args.int(1)?.clamp(0, len) as usize;
+            let end = match args.args.get(2) {
+                Some(_) => args.int(2)?.clamp(0, len) as usize,
+                None => len as usize,
+            };
+            Val::string(chars[start..end.max(start)].iter().collect::<String>())
+        }
+        "String.split" => {
+            let (text, sep) = (args.str(0)?, args.str(1)?);
+            let parts: Vec<Val> = if sep.is_empty() {
+                text.chars().map(|c| Val::string(c.to_string())).collect()
+            } else {
+                text.split(sep.as_str())
+                    .map(|p| Val::string(p.to_string()))
+                    .collect()
+            };
+            array(parts)
+        }
+        "String.indexOf" => {
+            let (text, part) = (args.str(0)?, args.str(1)?);
+            Val::I64(match text.find(part.as_str()) {
+                Some(byte) => text[..byte].chars().count() as i64,
+                None => -1,
+            })
+        }
+        "String.replace" => Val::string(args.str(0)?.replace(&args.str(1)?, &args.str(2)?)),
+        "String.upper" => Val::string(args.str(0)?.to_uppercase()),
+        "String.lower" => Val::string(args.str(0)?.to_lowercase()),
+        "String.trim" => Val::string(args.str(0)?.trim().to_string()),
+        "Array.length" => Val::I64(args.array(0)?.borrow().data.len() as i64),
+        "Array.push" => {
+            let items = args.array(0)?;
+            let item = args.get(1)?.clone();
+            items.borrow_mut().data.push(item);
+            let len = items.borrow().data.len();
+            Val::I64(len as i64)
+        }
+        "Array.pop" => args.array(0)?.borrow_mut().data.pop().unwrap_or(Val::Null),
+        "Array.slice" => {
+            let items = args.array(0)?;
+            let items = items.borrow();
+            let len = items.data.len() as i64;
+            // negative positions count from the end
+            let at = |i: i64| (if i < 0 { (len + i).max(0) } else { i.min(len) }) as usize;
+            let start = at(args.int(1)?);
+            let end = match args.args.get(2) {
+                Some(_) => at(args.int(2)?),
+                None => len as usize,
+            };
+            array(items.data[start..end.max(start)].to_vec())
+        }
+        "Array.sort" => {
+            let items = args.array(0)?;
+            let mut keys = vec![];
+            for item in items.borrow().data.iter() {
+                keys.push(match (item.as_num(), item) {
+                    (Some(num), _) => SortKey::Num(num),
+                    (None, Val::Str(s)) => SortKey::Str(s.to_string()),
+                    _ => return Err(args.mismatch("an array of numbers or strings")),
+                });
+            }
+            let mut order: Vec<usize> = (0..keys.len()).collect();
+            order.sort_by(|a, b| keys[*a].partial_cmp(&keys[*b]).unwrap_or(Ordering::Equal));
+            let sorted = order
+                .iter()
+                .map(|i| items.borrow().data[*i].clone())
+                .collect();
+            items.borrow_mut().data = sorted;
+            args.args[0].clone()
+        }
+        "Array.join" => {
+            let items = args.array(0)?;
+            let sep = match args.args.get(1) {
+                Some(_) => args.str(1)?,
+                None => ",".to_string(),
+            };
+            let parts: Vec<String> = items
+                .borrow()
+                .data
+                .iter()
+                .map(|item| match item {
+                    Val::Str(s) => s.to_string(),
+                    _ => item.stringify(),
+                })
+                .collect();
+            Val::string(parts.join(&sep))
+        }
+        "Object.keys" | "Object.values" => {
+            let props = args.object(0)?;
+            let props = props.borrow();
+            let mut keys: Vec<&String> = props.data.data.keys().collect();
+            keys.sort();
+            array(if name == "Object.keys" {
+                keys.into_iter().map(|k| Val::string(k.clone())).collect()
+            } else {
+                keys.into_iter()
+                    .map(|k| props.data.data[k].clone())
+                    .collect()
+            })
+        }
+        "JSON.stringify" => {
+            let value = to_json(args.get(0)?, &mut HashSet::new())
+                .ok_or_else(|| args.mismatch("a value without cycles"))?;
+            Val::string(value.to_string())
+        }
+        "JSON.parse" => match serde_json::from_str::<Value>(&args.str(0)?) {
+            Ok(value) => from_json(value),
+            Err(err) => {
+                return Err(VmError::TypeMismatch(format!(
+                    "JSON.parse got invalid JSON, {}",
+                    err
+                )))
+            }
+        },
+        _ => return Err(VmError::UndefinedVariable(name.to_string())),
+    })
+}
+
+// the arguments of a built-in, with checks naming the function
+struct Args<'a> {
+    name: &'a str,
+    args: &'a [Val],
+}
+
+impl Args<'_> {
+    fn mismatch(&self, expected: &str) -> VmError {
+        VmError::TypeMismatch(format!("{} expects {}", self.name, expected))
+    }
+    fn get(&self, i: usize) -> Result<&Val, VmError> {
+        self.args
+            .get(i)
+            .ok_or_else(|| self.mismatch(&format!("{} arguments", i + 1)))
+    }
+    fn num(&self, i: usize) -> Result<f64, VmError> {
+        self.get(i)?
+            .as_num()
+            .ok_or_else(|| self.mismatch("a number"))
+    }
+    fn int(&self, i: usize) -> Result<i64, VmError> {
+        self.get(i)?
+            .as_int()
+            .ok_or_else(|| self.mismatch("an integer"))
+    }
+    fn all_ints(&self) -> bool {
+        self.args.iter().all(|arg| arg.as_int().is_some())
+    }
+    fn str(&self, i: usize) -> Result<String, VmError> {
+        match self.get(i)? {
+            Val::Str(s) => Ok(s.to_string()),
+            _ => Err(self.mismatch("a string")),
+        }
+    }
+    fn array(&self, i: usize) -> Result<Rc<RefCell<Array>>, VmError> {
+        match self.get(i)? {
+            Val::Array(array) => Ok(array.clone()),
+            _ => Err(self.mismatch("an array")),
+        }
+    }
+    fn object(&self, i: usize) -> Result<Rc<RefCell<Object>>, VmError> {
+        match self.get(i)? {
+            Val::Object(object) => Ok(object.clone()),
+            _ => Err(self.mismatch("an object")),
+        }
+    }
+}
+
+#[derive(PartialEq, PartialOrd)]
+enum SortKey {
+    // numbers before strings
+    Num(f64),
+    Str(String),
+}
+
+// splitmix64, so that any seed, 0 included, gives a usable state
+fn seed_state(seed: i64) -> u64 {
+    let mut z = (seed as u64).wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    (z ^ (z >> 31)) | 1
+}
+
+// xorshift64*
+fn next_random(state: &mut u64) -> u64 {
+    let mut x = *state;
+    x ^= x >> 12;
+    x ^= x << 25;
+    x ^= x >> 27;
+    *state = x;
+    x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+}
+
+/// Plain JSON for a value. Functions, promises and other internal values
+/// become `null`; `None` if the value contains itself.
+pub fn to_json(val: &Val, path: &mut HashSet<*const ()>) -> Option<Value> {
+    let container = match val {
+        Val::Object(object) => Rc::as_ptr(object) as *const (),
+        Val::Array(array) => Rc::as_ptr(array) as *const (),
+        _ => std::ptr::null(),
+    };
+    if !container.is_null() && !path.insert(container) {
+        return None;
+    }
+    let value = match val {
+        Val::I16(_) | Val::I32(_) | Val::I64(_) => Value::Number(Number::from(val.as_int()?)),
+        Val::F32(_) | Val::F64(_) => {
+            Number::from_f64(val.as_num()?).map_or(Value::Null, Value::Number)
+        }
+        Val::Bool(v) => Value::Bool(*v),
+        Val::Str(s) => Value::String(s.to_string()),
+        Val::Object(object) => {
+            let mut map = Map::new();
+            for (key, prop) in object.borrow().data.data.iter() {
+                map.insert(key.clone(), to_json(prop, path)?);
+            }
+            Value::Object(map)
+        }
+        Val::Array(array) => Value::Array(
+            array
+                .borrow()
+                .data
+                .iter()
+                .map(|item| to_json(item, path))
+                .collect::<Option<Vec<_>>>()?,
+        ),
+        _ => Value::Null,
+    };
+    path.remove(&container);
+    Some(value)
+}
+
+/// A value for plain JSON: integers become `i64`, other numbers `f64`.
+pub fn from_json(value: Value) -> Val {
+    match value {
+        Value::Null => Val::Null,
+        Value::Bool(v) => Val::Bool(v),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => Val::I64(i),
+            None => Val::F64(n.as_f64().unwrap_or(0.0)),
+        },
+        Value::String(s) => Val::string(s),
+        Value::Array(items) => array(items.into_iter().map(from_json).collect()),
+        Value::Object(map) => object(map.into_iter().map(|(k, v)| (k, from_json(v))).collect()),
+    }
+}
+
+pub(crate) fn array(items: Vec<Val>) -> Val {
+    Val::array(Array::new(items))
+}
+
+fn object(props: HashMap<String, Val>) -> Val {
+    Val::object(Object::new(0, ValGroup::new(props)))
+}
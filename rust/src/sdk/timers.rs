@@ -0,0 +1,111 @@
+use std::{cell::RefCell, collections::VecDeque, rc::Rc};
+
+use crate::sdk::data::Function;
+
+/// Names of the native functions that schedule callbacks on the queue.
+pub const TIMER_FUNCTIONS: [&str; 4] = ["setTimeout", "setInterval", "requestFrame", "clearTimer"];
+
+/// A callback scheduled by `setTimeout`, `setInterval` or `requestFrame`.
+#[derive(Clone)]
+pub struct Timer {
+    pub id: i64,
+    pub func: Rc<RefCell<Function>>,
+    /// Host time in milliseconds at which the callback is due.
+    pub due: i64,
+    /// Set for `setInterval`, the callback is due again this much later.
+    pub interval: Option<i64>,
+}
+
+/// Callbacks waiting for their time, driven by `VM::tick`. Times are host
+/// milliseconds as passed to the tick; delays count from the last tick, so
+/// timers set before the first one count from 0.
+#[derive(Clone, Default)]
+pub struct TimerQueue {
+    pub(crate) timers: Vec<Timer>,
+    pub(crate) next_id: i64,
+    pub(crate) now: i64,
+    // ids still to run in the tick in progress, in order
+    pub(crate) firing: VecDeque<i64>,
+}
+
+impl TimerQueue {
+    /// Schedules `func` `delay` milliseconds from now, repeating every
+    /// `delay` if `repeat` is set. Negative delays count as 0. Returns the
+    /// timer id.
+    pub fn schedule(&mut self, func: Rc<RefCell<Function>>, delay: i64, repeat: bool) -> i64 {
+        let delay = delay.max(0);
+        self.next_id += 1;
+        self.timers.push(Timer {
+            id: self.next_id,
+            func,
+            due: self.now + delay,
+            interval: if repeat { Some(delay) } else { None },
+        });
+        self.next_id
+    }
+    /// Removes a timer, returns whether it existed. A timer cleared during a
+    /// tick does not run in it anymore.
+    pub fn clear(&mut self, id: i64) -> bool {
+        let len = self.timers.len();
+        self.timers.retain(|timer| timer.id != id);
+        self.firing.retain(|firing| *firing != id);
+        self.timers.len() != len
+    }
+    /// Starts a tick at `now`: every timer due by then is lined up to run,
+    /// earliest first and in the order they were set on ties. Timers set
+    /// while the tick runs wait for the next one.
+    pub fn start_tick(&mut self, now: i64) {
+        self.now = now;
+        let mut due: Vec<&Timer> = self.timers.iter().filter(|t| t.due <= now).collect();
+        due.sort_by_key(|t| (t.due, t.id));
+        self.firing = due.iter().map(|t| t.id).collect();
+    }
+    /// The callback to run next in the tick in progress. Intervals are set
+    /// up again, one-shot timers are removed.
+    pub fn next_firing(&mut self) -> Option<Rc<RefCell<Function>>> {
+        let index = loop {
+            let id = self.firing.pop_front()?;
+            if let Some(index) = self.timers.iter().position(|t| t.id == id) {
+                break index;
+            }
+        };
+        let timer = &mut self.timers[index];
+        let func = timer.func.clone();
+        match timer.interval {
+            Some(interval) => {
+                // beats missed by a late tick are skipped
+                let next = timer.due + interval;
+                timer.due = if next > self.now {
+                    next
+                } else {
+                    self.now + interval
+                };
+            }
+            None => {
+                self.timers.remove(index);
+            }
+        }
+        Some(func)
+    }
+    /// Ends the tick in progress, callbacks it did not run yet stay due.
+    pub fn stop_tick(&mut self) {
+        self.firing.clear();
+    }
+    pub fn is_ticking(&self) -> bool {
+        !self.firing.is_empty()
+    }
+    /// Host time of the last tick.
+    pub fn now(&self) -> i64 {
+        self.now
+    }
+    /// When the earliest timer is due, `None` if there is none.
+    pub fn next_due(&self) -> Option<i64> {
+        self.timers.iter().map(|t| t.due).min()
+    }
+    pub fn len(&self) -> usize {
+        self.timers.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.timers.is_empty()
+    }
+}
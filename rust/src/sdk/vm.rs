@@ -2,12 +2,31 @@ use std::{cell::RefCell, collections::HashMap, rc::Rc};
 
 use serde_json::{json, Value};
 
-use crate::sdk::{compiler, data::Val, executor::Executor};
+use crate::sdk::{
+    bytecode::{fnv1a, BytecodeModule},
+    compiler,
+    data::Val,
+    debugger::{PauseInfo, ScopeView, StepMode},
+    error::{SyntaxError, VmError},
+    executor::{Executor, HostRequest},
+    gc::GcStats,
+    reload::{ReloadError, ReloadReport},
+    snapshot::{self, HostCallData, Loader, Saver, Snapshot, SNAPSHOT_FORMAT, SNAPSHOT_VERSION},
+    source_map::{SourceMap, StackFrame},
+    timers::TimerQueue,
+    wire,
+};
 
-use crate::sdk::data::{Array, Object, ValGroup};
+use crate::sdk::data::Array;
 
+/// A host api implemented in Rust: gets the arguments of the call and
+/// returns its result, or a value that is thrown inside the script at the
+/// call site.
+pub type HostFunction = dyn Fn(&[Val]) -> Result<Val, Val>;
+
+/// A host function registered with `VM::register_host_function`.
 pub struct CallbackHolder {
-    pub callback: Box<dyn Fn(String) -> String>,
+    pub callback: Box<HostFunction>,
 }
 
 pub struct VM {
@@ -16,46 +35,191 @@ pub struct VM {
     single_thread_executor: Option<Rc<RefCell<Executor>>>,
     pending_host_call_id: i64,
     pub sending_host_call_data: Option<String>,
+    // the host call the run waits on, kept for snapshots and the binary
+    // input after the request was handed out
+    pending_host_call: Option<HostCallData>,
+    /// Async host calls made by the script that were not handed out yet,
+    /// JSON like `sending_host_call_data` plus their `callId`.
+    pub sending_async_host_calls: Vec<String>,
+    pub last_error: Option<VmError>,
+    /// Script frames of the run that raised `last_error`, innermost first.
+    pub last_stack_trace: Vec<StackFrame>,
+    pub out_of_fuel: bool,
+    // callbacks the script scheduled, run by `tick`
+    timers: Rc<RefCell<TimerQueue>>,
+    // source of the modules the program imports, by name, linked again on
+    // reloads
+    modules: HashMap<String, String>,
 }
 
 unsafe impl Send for VM {}
 unsafe impl Sync for VM {}
 
 impl VM {
+    /// Creates a VM from a bytecode container (see `BytecodeModule`). The
+    /// container is verified first, so malformed or truncated input is
+    /// rejected here instead of failing mid run.
     pub fn compile_and_create_of_bytecode(
         machine_id: String,
-        program: Vec<u8>,
+        bytecode: Vec<u8>,
         func_group: Vec<String>,
-    ) -> Self {
-        let executor = Executor::create_in_single_thread(program.clone(), 0, func_group);
+    ) -> Result<Self, VmError> {
+        let module = BytecodeModule::decode(&bytecode)?;
+        Ok(Self::create_of_program(
+            machine_id,
+            module.program.encode(),
+            func_group,
+        ))
+    }
+    // `program` is trusted, it comes straight from the compiler
+    fn create_of_program(machine_id: String, program: Vec<u8>, func_group: Vec<String>) -> Self {
+        let mut executor = Executor::create_in_single_thread(program.clone(), 0, func_group);
+        let timers = Rc::new(RefCell::new(TimerQueue::default()));
+        executor.set_timer_queue(timers.clone());
         VM {
             machine_id,
             program,
             single_thread_executor: Some(Rc::new(RefCell::new(executor))),
             pending_host_call_id: 0,
             sending_host_call_data: None,
+            pending_host_call: None,
+            sending_async_host_calls: vec![],
+            last_error: None,
+            last_stack_trace: vec![],
+            out_of_fuel: false,
+            timers,
+            modules: HashMap::new(),
         }
     }
+    fn create_with_source_map(
+        machine_id: String,
+        program: Vec<u8>,
+        source_map: SourceMap,
+        func_group: Vec<String>,
+    ) -> Self {
+        let vm = Self::create_of_program(machine_id, program, func_group);
+        vm.single_thread_executor
+            .as_ref()
+            .unwrap()
+            .borrow_mut()
+            .set_source_map(Some(source_map));
+        vm
+    }
+    /// Fails with every malformed node of `program`.
     pub fn compile_and_create_of_ast(
         machine_id: String,
         program: serde_json::Value,
         _executor_count: i32,
         func_group: Vec<String>,
-    ) -> Self {
-        let byte_code = compiler::compile_ast(program, 0);
-        Self::compile_and_create_of_bytecode(machine_id, byte_code, func_group)
+    ) -> Result<Self, Vec<SyntaxError>> {
+        let (byte_code, source_map) = compiler::compile_ast_with_source_map(program, 0)?;
+        Ok(Self::create_with_source_map(
+            machine_id, byte_code, source_map, func_group,
+        ))
     }
+    /// Fails with every syntax error found in `program`.
     pub fn compile_and_create_of_code(
         machine_id: String,
         program: String,
         _executor_count: i32,
         func_group: Vec<String>,
-    ) -> Self {
-        let byte_code = compiler::compile_code(program);
-        Self::compile_and_create_of_bytecode(machine_id, byte_code, func_group)
+    ) -> Result<Self, Vec<SyntaxError>> {
+        let (byte_code, source_map) = compiler::compile_code_with_source_map(program)?;
+        Ok(Self::create_with_source_map(
+            machine_id, byte_code, source_map, func_group,
+        ))
+    }
+    /// Like `compile_and_create_of_code` for a program that imports
+    /// modules, the source of each is taken from `modules` by name.
+    pub fn compile_and_create_of_modules(
+        machine_id: String,
+        program: String,
+        modules: HashMap<String, String>,
+        func_group: Vec<String>,
+    ) -> Result<Self, Vec<SyntaxError>> {
+        Self::compile_and_create_with_resolver(
+            machine_id,
+            program,
+            |name| modules.get(name).cloned(),
+            func_group,
+        )
+    }
+    /// Like `compile_and_create_of_code` for a program that imports
+    /// modules. `resolve` is asked for the source of each module once and
+    /// returns `None` for a module that does not exist. Syntax errors in a
+    /// module and link errors, like cyclic imports, name the module they
+    /// were found in (see `compiler::compile_modules`).
+    pub fn compile_and_create_with_resolver(
+        machine_id: String,
+        program: String,
+        mut resolve: impl FnMut(&str) -> Option<String>,
+        func_group: Vec<String>,
+    ) -> Result<Self, Vec<SyntaxError>> {
+        let linked = compiler::compile_modules(program, &mut resolve)?;
+        let mut vm =
+            Self::create_with_source_map(machine_id, linked.image, linked.source_map, func_group);
+        vm.modules = linked.modules;
+        Ok(vm)
+    }
+    /// Compiles `program` and swaps it in, keeping the global scope: every
+    /// function is rebound to its new definition while other globals keep
+    /// their values. Top-level statements of the new code do not run, so
+    /// globals it adds stay undefined until the main program runs again.
+    /// Fails while a run is in flight or when the code has syntax errors,
+    /// leaving the VM as it was. Imports are linked against the modules the
+    /// VM was created with.
+    pub fn reload_code(&mut self, program: String) -> Result<ReloadReport, ReloadError> {
+        if self.is_exec_processing() {
+            return Err(ReloadError::Busy);
+        }
+        let modules = &self.modules;
+        let linked = compiler::compile_modules(program, &mut |name| modules.get(name).cloned())
+            .map_err(ReloadError::Syntax)?;
+        let (byte_code, source_map) = (linked.image, linked.source_map);
+        let report = self
+            .single_thread_executor
+            .as_ref()
+            .unwrap()
+            .borrow_mut()
+            .reload_program(byte_code.clone(), Some(source_map));
+        self.program = byte_code;
+        self.last_error = None;
+        self.last_stack_trace = vec![];
+        Ok(report)
+    }
+    /// Registers `callback` as the host api `api_name`. Scripts calling it,
+    /// e.g. `host.api_name(a, b)`, run it inline with the arguments instead
+    /// of pausing for the host; async calls get an already settled promise.
+    /// Registering grants the api even when the allowlist does not name it.
+    /// The callback runs while the VM is busy and must not call back into
+    /// it. Replaces a function registered under the same name.
+    pub fn register_host_function(
+        &mut self,
+        api_name: &str,
+        callback: impl Fn(&[Val]) -> Result<Val, Val> + 'static,
+    ) {
+        self.single_thread_executor
+            .as_ref()
+            .unwrap()
+            .borrow_mut()
+            .register_host_function(
+                api_name,
+                CallbackHolder {
+                    callback: Box::new(callback),
+                },
+            );
+    }
+    /// Removes a registered host function, later calls go to the host again.
+    /// Returns false when none was registered under `api_name`.
+    pub fn unregister_host_function(&mut self, api_name: &str) -> bool {
+        self.single_thread_executor
+            .as_ref()
+            .unwrap()
+            .borrow_mut()
+            .unregister_host_function(api_name)
     }
     pub fn print_memory(&mut self) {}
-    pub fn run(&mut self) -> Val {
+    pub fn run(&mut self) -> Result<Val, VmError> {
         self.run_func_with_input("", None, 0)
     }
     pub fn is_exec_processing(&self) -> bool {
@@ -65,36 +229,39 @@ impl VM {
             .borrow()
             .processing
     }
-    pub fn run_func_with_input(&mut self, func_name: &str, input: Option<&str>, cb_id: i64) -> Val {
+    pub fn run_func_with_input(
+        &mut self,
+        func_name: &str,
+        input: Option<&str>,
+        cb_id: i64,
+    ) -> Result<Val, VmError> {
         let payload = if func_name.is_empty() {
-            Val::new(0, Rc::new(RefCell::new(Box::new(0))))
+            Val::Null
         } else {
             let input_val = match input {
                 Some(json_str) => {
                     let trimmed = json_str.trim();
                     if trimmed.is_empty() {
-                        Val::new(0, Rc::new(RefCell::new(Box::new(0))))
+                        Val::Null
                     } else {
                         match serde_json::from_str::<Value>(trimmed) {
-                            Ok(value) => self.convert_json_value_to_val(value),
+                            Ok(value) => match self.decode(&value) {
+                                Ok(val) => val,
+                                Err(error) => return self.fail(error),
+                            },
                             Err(_) => {
                                 // Fallback: treat non-JSON payloads as plain strings.
-                                Val::new(7, Rc::new(RefCell::new(Box::new(trimmed.to_string()))))
+                                Val::string(trimmed.to_string())
                             }
                         }
                     }
                 }
-                None => Val::new(0, Rc::new(RefCell::new(Box::new(0)))),
+                None => Val::Null,
             };
-            Val::new(
-                9,
-                Rc::new(RefCell::new(Box::new(Rc::new(RefCell::new(Array::new(
-                    vec![
-                        Val::new(7, Rc::new(RefCell::new(Box::new(func_name.to_string())))),
-                        input_val,
-                    ],
-                )))))),
-            )
+            Val::array(Array::new(vec![
+                Val::string(func_name.to_string()),
+                input_val,
+            ]))
         };
         let r = self
             .single_thread_executor
@@ -102,160 +269,483 @@ impl VM {
             .unwrap()
             .borrow_mut()
             .single_thread_operation(0x01, cb_id, payload);
-        self.handle_executor_request(r.0, r.1, r.2)
+        let result = self.handle_executor_request(r.0, r.1, r.2);
+        self.settle(result)
     }
-    pub fn continue_run(&mut self, res_raw: String) -> Val {
-        let res_json: Value = serde_json::from_str(&res_raw).unwrap();
-        let res = self.convert_json_value_to_val(res_json);
+    /// Runs the timer callbacks due at host time `now_ms`, earliest first,
+    /// each called with `now_ms` as its argument. A callback that pauses, on
+    /// a host call, an await, its fuel or the debugger, pauses the tick too;
+    /// the remaining callbacks run once that run is answered and ends. A
+    /// callback that fails ends the tick with its error and leaves the
+    /// callbacks after it due. Does nothing while a run is in flight.
+    pub fn tick(&mut self, now_ms: i64) -> Result<Val, VmError> {
+        let idle = Val::NoResult;
+        if self.is_exec_processing() {
+            return Ok(idle);
+        }
+        self.timers.borrow_mut().start_tick(now_ms);
+        self.run_due_timers(Ok(idle))
+    }
+    /// Host time at which the earliest timer is due, to sleep until then.
+    pub fn next_timer_due(&self) -> Option<i64> {
+        self.timers.borrow().next_due()
+    }
+    pub fn timer_count(&self) -> usize {
+        self.timers.borrow().len()
+    }
+    // goes on with the tick in progress, if any, once the last run ended
+    fn run_due_timers(&mut self, mut result: Result<Val, VmError>) -> Result<Val, VmError> {
+        while result.is_ok() && !self.is_exec_processing() {
+            let Some(func) = self.timers.borrow_mut().next_firing() else {
+                break;
+            };
+            let now = self.timers.borrow().now();
+            let payload = Val::array(Array::new(vec![Val::Function(func), Val::I64(now)]));
+            let r = self
+                .single_thread_executor
+                .as_ref()
+                .unwrap()
+                .borrow_mut()
+                .single_thread_operation(0x01, 0, payload);
+            let next = self.handle_executor_request(r.0, r.1, r.2);
+            result = self.settle_run(next);
+        }
+        if result.is_err() {
+            self.timers.borrow_mut().stop_tick();
+        }
+        result
+    }
+    /// Answers the pending host call with a typed JSON value. A reply that
+    /// does not parse aborts the paused run.
+    pub fn continue_run(&mut self, res_raw: String) -> Result<Val, VmError> {
+        self.answer_host_call(0x03, res_raw)
+    }
+    /// Fails the pending host call. The typed JSON value is thrown inside
+    /// the script where the call was made, so a surrounding `try` can catch
+    /// it; uncaught it ends the run with a `Thrown` error.
+    pub fn continue_run_with_error(&mut self, err_raw: String) -> Result<Val, VmError> {
+        self.answer_host_call(0x07, err_raw)
+    }
+    /// Like `continue_run`, with the value in the binary wire encoding
+    /// (see `wire::to_binary`).
+    pub fn continue_run_binary(&mut self, res: &[u8]) -> Result<Val, VmError> {
+        let res = wire::from_binary(res);
+        self.answer_host_call_with(0x03, res)
+    }
+    fn answer_host_call(&mut self, op_code: u8, res_raw: String) -> Result<Val, VmError> {
+        let res =
+            serde_json::from_str(&res_raw).map_err(|err| VmError::BadHostReply(err.to_string()));
+        self.answer_host_call_with(op_code, res)
+    }
+    fn answer_host_call_with(
+        &mut self,
+        op_code: u8,
+        res: Result<Value, VmError>,
+    ) -> Result<Val, VmError> {
+        if !self.is_exec_processing() {
+            return self.fail(VmError::BadHostReply("no host call is pending".to_string()));
+        }
+        let res = match res.and_then(|value| self.decode(&value)) {
+            Ok(res) => res,
+            Err(error) => {
+                self.abort_run();
+                return self.fail(error);
+            }
+        };
         let res_next = self
             .single_thread_executor
             .as_ref()
             .unwrap()
             .borrow_mut()
-            .single_thread_operation(0x03, self.pending_host_call_id, res);
-        self.handle_executor_request(res_next.0, res_next.1, res_next.2)
+            .single_thread_operation(op_code, self.pending_host_call_id, res);
+        let result = self.handle_executor_request(res_next.0, res_next.1, res_next.2);
+        self.settle(result)
     }
-    fn convert_json_value_to_val(&self, val: Value) -> Val {
-        let maybe_typed_value = val
-            .as_object()
-            .and_then(|obj| obj.get("type").and_then(Value::as_str).map(|t| (obj, t)));
-
-        if let Some((obj, typ)) = maybe_typed_value {
-            let data_value = obj
-                .get("data")
-                .and_then(Value::as_object)
-                .and_then(|data| data.get("value"))
-                .cloned()
-                .unwrap_or(Value::Null);
-
-            match typ {
-                "null" => return Val::new(0, Rc::new(RefCell::new(Box::new(0)))),
-                "i16" => {
-                    if let Some(v) = data_value.as_i64() {
-                        return Val::new(1, Rc::new(RefCell::new(Box::new(v as i16))));
-                    }
-                }
-                "i32" => {
-                    if let Some(v) = data_value.as_i64() {
-                        return Val::new(2, Rc::new(RefCell::new(Box::new(v as i32))));
-                    }
-                }
-                "i64" => {
-                    if let Some(v) = data_value.as_i64() {
-                        return Val::new(3, Rc::new(RefCell::new(Box::new(v))));
-                    }
-                }
-                "f32" => {
-                    if let Some(v) = data_value.as_f64() {
-                        return Val::new(4, Rc::new(RefCell::new(Box::new(v as f32))));
-                    }
-                }
-                "f64" => {
-                    if let Some(v) = data_value.as_f64() {
-                        return Val::new(5, Rc::new(RefCell::new(Box::new(v))));
-                    }
-                }
-                "bool" => {
-                    if let Some(v) = data_value.as_bool() {
-                        return Val::new(6, Rc::new(RefCell::new(Box::new(v))));
-                    }
-                }
-                "string" => {
-                    if let Some(v) = data_value.as_str() {
-                        return Val::new(7, Rc::new(RefCell::new(Box::new(v.to_string()))));
-                    }
-                }
-                "object" => {
-                    if let Some(map) = data_value.as_object() {
-                        let mut obj_map = HashMap::new();
-                        for (k, v) in map.iter() {
-                            obj_map.insert(k.clone(), self.convert_json_value_to_val(v.clone()));
-                        }
-                        return Val::new(
-                            8,
-                            Rc::new(RefCell::new(Box::new(Rc::new(RefCell::new(Object::new(
-                                -2,
-                                ValGroup::new(obj_map),
-                            )))))),
-                        );
-                    }
-                }
-                "array" => {
-                    if let Some(items) = data_value.as_array() {
-                        let vals: Vec<Val> = items
-                            .iter()
-                            .map(|item| self.convert_json_value_to_val(item.clone()))
-                            .collect();
-                        return Val::new(
-                            9,
-                            Rc::new(RefCell::new(Box::new(Rc::new(RefCell::new(Array::new(
-                                vals,
-                            )))))),
-                        );
-                    }
-                }
-                _ => {}
-            }
+    /// Settles the promise of the async host call `call_id` with a typed
+    /// JSON value. A run awaiting it goes on; otherwise the value is kept
+    /// until the script awaits the promise, and the reply is type 249 while
+    /// the run still awaits another call or 254 when nothing ran. Unknown
+    /// ids and bad JSON fail with `BadHostReply` and leave the run as it was.
+    pub fn resolve_host_call(&mut self, call_id: i64, res_raw: String) -> Result<Val, VmError> {
+        self.settle_host_call(call_id, res_raw, true)
+    }
+    /// Like `resolve_host_call`, but the value is thrown where the script
+    /// awaits the promise.
+    pub fn reject_host_call(&mut self, call_id: i64, err_raw: String) -> Result<Val, VmError> {
+        self.settle_host_call(call_id, err_raw, false)
+    }
+    fn settle_host_call(
+        &mut self,
+        call_id: i64,
+        res_raw: String,
+        resolved: bool,
+    ) -> Result<Val, VmError> {
+        let res_json: Value = match serde_json::from_str(&res_raw) {
+            Ok(value) => value,
+            Err(err) => return self.fail(VmError::BadHostReply(err.to_string())),
+        };
+        let res = match self.decode(&res_json) {
+            Ok(res) => res,
+            Err(error) => return self.fail(error),
+        };
+        let executor = self.single_thread_executor.clone().unwrap();
+        if !executor.borrow_mut().settle_promise(call_id, res, resolved) {
+            return self.fail(VmError::BadHostReply(format!(
+                "no async host call {} is pending",
+                call_id
+            )));
         }
-
-        match val {
-            Value::Null => Val::new(0, Rc::new(RefCell::new(Box::new(0)))),
-            Value::Bool(v) => Val::new(6, Rc::new(RefCell::new(Box::new(v)))),
-            Value::Number(n) => {
-                if let Some(i) = n.as_i64() {
-                    Val::new(3, Rc::new(RefCell::new(Box::new(i))))
-                } else {
-                    Val::new(
-                        5,
-                        Rc::new(RefCell::new(Box::new(n.as_f64().unwrap_or(0.0)))),
-                    )
-                }
-            }
-            Value::String(s) => Val::new(7, Rc::new(RefCell::new(Box::new(s)))),
-            Value::Array(items) => {
-                let vals: Vec<Val> = items
-                    .into_iter()
-                    .map(|item| self.convert_json_value_to_val(item))
-                    .collect();
-                Val::new(
-                    9,
-                    Rc::new(RefCell::new(Box::new(Rc::new(RefCell::new(Array::new(
-                        vals,
-                    )))))),
-                )
-            }
-            Value::Object(map) => {
-                let mut obj_map = HashMap::new();
-                for (k, v) in map.into_iter() {
-                    obj_map.insert(k, self.convert_json_value_to_val(v));
-                }
-                Val::new(
-                    8,
-                    Rc::new(RefCell::new(Box::new(Rc::new(RefCell::new(Object::new(
-                        -2,
-                        ValGroup::new(obj_map),
-                    )))))),
-                )
+        if !executor.borrow().awaits_call(call_id) {
+            return Ok(if executor.borrow().is_awaiting() {
+                Val::Awaiting
+            } else {
+                Val::NoResult
+            });
+        }
+        let res_next = executor
+            .borrow_mut()
+            .single_thread_operation(0x08, call_id, Val::Null);
+        let result = self.handle_executor_request(res_next.0, res_next.1, res_next.2);
+        self.settle(result)
+    }
+    /// Whether the run waits for the host to settle an awaited promise.
+    pub fn is_awaiting(&self) -> bool {
+        self.single_thread_executor
+            .as_ref()
+            .unwrap()
+            .borrow()
+            .is_awaiting()
+    }
+    /// Limits every run to `limit` executed instructions (`None` = unlimited).
+    /// A run that exhausts its budget yields with type 251 and keeps its state
+    /// until `resume_with_fuel` or `abort_run` is called.
+    pub fn set_fuel_limit(&mut self, limit: Option<u64>) {
+        self.single_thread_executor
+            .as_ref()
+            .unwrap()
+            .borrow_mut()
+            .set_fuel_limit(limit);
+    }
+    pub fn remaining_fuel(&self) -> Option<u64> {
+        self.single_thread_executor
+            .as_ref()
+            .unwrap()
+            .borrow()
+            .remaining_fuel()
+    }
+    pub fn resume_with_fuel(&mut self, fuel: u64) -> Result<Val, VmError> {
+        let res_next = self
+            .single_thread_executor
+            .as_ref()
+            .unwrap()
+            .borrow_mut()
+            .single_thread_operation(0x04, 0, Val::I64(fuel as i64));
+        let result = self.handle_executor_request(res_next.0, res_next.1, res_next.2);
+        self.settle(result)
+    }
+    pub fn abort_run(&mut self) {
+        self.single_thread_executor
+            .as_ref()
+            .unwrap()
+            .borrow_mut()
+            .single_thread_operation(0x05, 0, Val::Null);
+        self.out_of_fuel = false;
+        self.sending_host_call_data = None;
+        self.pending_host_call = None;
+        self.timers.borrow_mut().stop_tick();
+    }
+    /// Saves the runtime state as a versioned JSON blob: every scope with
+    /// its values, the in-flight operations, the run position and the host
+    /// call the run waits on. Values are stored once, so shared references,
+    /// cycles and functions keep their identity. Works between runs, while a
+    /// host call is pending and while a run is out of fuel; a run paused by
+    /// the debugger is refused with `BadSnapshot`.
+    pub fn snapshot(&self) -> Result<String, VmError> {
+        let mut saver = Saver::default();
+        let executor = self
+            .single_thread_executor
+            .as_ref()
+            .unwrap()
+            .borrow()
+            .save(&mut saver)?;
+        let snapshot = Snapshot {
+            format: SNAPSHOT_FORMAT.to_string(),
+            version: SNAPSHOT_VERSION,
+            program_checksum: fnv1a(&self.program),
+            heap: saver.heap,
+            executor,
+            host_call: self.pending_host_call.clone(),
+        };
+        serde_json::to_string(&snapshot).map_err(|err| snapshot::bad(&err.to_string()))
+    }
+    /// Replaces the runtime state with one saved by `snapshot`, typically in
+    /// a fresh VM created from the same program. Limits, breakpoints and the
+    /// host api allowlist stay as configured on this VM. A restored host call
+    /// is handed out again through `sending_host_call_data` and a run that
+    /// was out of fuel sets `out_of_fuel`. Malformed blobs, other versions
+    /// and other programs fail with `BadSnapshot` and leave the VM as it was.
+    pub fn restore(&mut self, snapshot: &str) -> Result<(), VmError> {
+        let snapshot: Snapshot =
+            serde_json::from_str(snapshot).map_err(|err| snapshot::bad(&err.to_string()))?;
+        if snapshot.format != SNAPSHOT_FORMAT {
+            return Err(snapshot::bad("not a vm snapshot"));
+        }
+        if snapshot.version != SNAPSHOT_VERSION {
+            return Err(snapshot::bad(&format!(
+                "version {} is not supported, expected {}",
+                snapshot.version, SNAPSHOT_VERSION
+            )));
+        }
+        if snapshot.program_checksum != fnv1a(&self.program) {
+            return Err(snapshot::bad("it was taken from another program"));
+        }
+        let loader = Loader::new(snapshot.heap)?;
+        let executor = self.single_thread_executor.as_ref().unwrap();
+        executor.borrow_mut().restore(snapshot.executor, &loader)?;
+        self.out_of_fuel = executor.borrow().is_out_of_fuel();
+        self.last_error = None;
+        self.last_stack_trace = vec![];
+        self.sending_host_call_data = None;
+        self.pending_host_call = None;
+        let requests = executor.borrow().pending_host_requests();
+        self.sending_async_host_calls = requests
+            .iter()
+            .map(|request| self.async_host_call_json(request))
+            .collect();
+        if let Some(host_call) = snapshot.host_call {
+            self.pending_host_call_id = host_call.id;
+            self.sending_host_call_data = Some(self.host_call_json(&host_call));
+            self.pending_host_call = Some(host_call);
+        }
+        Ok(())
+    }
+    /// The input of the host call the run waits on in the binary wire
+    /// encoding (see `wire::to_binary`), `None` when no call is pending.
+    pub fn host_call_input_binary(&self) -> Option<Vec<u8>> {
+        let host_call = self.pending_host_call.as_ref()?;
+        wire::to_binary(&host_call.input).ok()
+    }
+    fn host_call_json(&self, host_call: &HostCallData) -> String {
+        json!({
+            "machineId": self.machine_id,
+            "apiName": host_call.api_name,
+            // deprecated, kept for hosts written before `input`
+            "payload": host_call.payload,
+            "input": host_call.input,
+        })
+        .to_string()
+    }
+    fn async_host_call_json(&self, request: &HostRequest) -> String {
+        json!({
+            "machineId": self.machine_id,
+            "callId": request.call_id,
+            "apiName": request.api_name,
+            // deprecated, kept for hosts written before `input`
+            "payload": request.payload,
+            "input": request.input,
+        })
+        .to_string()
+    }
+    /// Replaces the breakpoints with `offsets`, keeping those where a
+    /// statement starts. Returns the accepted offsets in order. A run that
+    /// reaches one yields with type 250 until `debug_resume` or `abort_run`.
+    pub fn set_breakpoints(&mut self, offsets: &[usize]) -> Vec<usize> {
+        self.single_thread_executor
+            .as_ref()
+            .unwrap()
+            .borrow_mut()
+            .set_breakpoints(offsets)
+    }
+    pub fn breakpoints(&self) -> Vec<usize> {
+        self.single_thread_executor
+            .as_ref()
+            .unwrap()
+            .borrow()
+            .breakpoints()
+    }
+    /// Offset of the first statement on source line `line`, for VMs created
+    /// from code.
+    pub fn line_offset(&self, line: usize) -> Option<usize> {
+        self.single_thread_executor
+            .as_ref()
+            .unwrap()
+            .borrow()
+            .source_map()
+            .and_then(|map| map.line_offset(line))
+    }
+    /// Pauses runs on script errors instead of aborting them, so the scope
+    /// chain can still be inspected. Resuming then delivers the error.
+    pub fn set_pause_on_error(&mut self, enabled: bool) {
+        self.single_thread_executor
+            .as_ref()
+            .unwrap()
+            .borrow_mut()
+            .set_pause_on_error(enabled);
+    }
+    pub fn pause_on_error(&self) -> bool {
+        self.single_thread_executor
+            .as_ref()
+            .unwrap()
+            .borrow()
+            .pause_on_error()
+    }
+    /// Where and why the current run is paused, if it is.
+    pub fn pause_info(&self) -> Option<PauseInfo> {
+        self.single_thread_executor
+            .as_ref()
+            .unwrap()
+            .borrow()
+            .pause_info()
+    }
+    pub fn is_paused(&self) -> bool {
+        self.pause_info().is_some()
+    }
+    /// Continues a paused run until the next breakpoint, or until `step`
+    /// is done when one is given.
+    pub fn debug_resume(&mut self, step: Option<StepMode>) -> Result<Val, VmError> {
+        let res_next = self
+            .single_thread_executor
+            .as_ref()
+            .unwrap()
+            .borrow_mut()
+            .single_thread_operation(0x06, 0, Val::I64(StepMode::code(step)));
+        let result = self.handle_executor_request(res_next.0, res_next.1, res_next.2);
+        self.settle(result)
+    }
+    /// The scope chain from the global scope inwards.
+    pub fn scope_chain(&self) -> Vec<ScopeView> {
+        self.single_thread_executor
+            .as_ref()
+            .unwrap()
+            .borrow()
+            .scope_chain()
+    }
+    /// Caps the approximate bytes held by the script (`None` = unlimited).
+    /// An allocation past the cap fails the run with a script error.
+    pub fn set_memory_limit(&mut self, limit: Option<usize>) {
+        self.single_thread_executor
+            .as_ref()
+            .unwrap()
+            .borrow_mut()
+            .set_memory_limit(limit);
+    }
+    pub fn memory_limit(&self) -> Option<usize> {
+        self.single_thread_executor
+            .as_ref()
+            .unwrap()
+            .borrow()
+            .memory_limit()
+    }
+    pub fn memory_usage(&self) -> usize {
+        self.single_thread_executor
+            .as_ref()
+            .unwrap()
+            .borrow_mut()
+            .memory_usage()
+    }
+    pub fn allocated_bytes(&self) -> u64 {
+        self.single_thread_executor
+            .as_ref()
+            .unwrap()
+            .borrow()
+            .allocated_bytes()
+    }
+    pub fn gc_stats(&self) -> GcStats {
+        self.single_thread_executor
+            .as_ref()
+            .unwrap()
+            .borrow()
+            .gc_stats()
+    }
+    /// Runs the cycle collector now instead of at its next safe point.
+    /// Returns how many values were freed.
+    pub fn collect_garbage(&self) -> usize {
+        self.single_thread_executor
+            .as_ref()
+            .unwrap()
+            .borrow_mut()
+            .collect_cycles()
+    }
+    // a value from the host, typed or plain JSON, see `wire::decode`
+    fn decode(&self, value: &Value) -> Result<Val, VmError> {
+        let executor = self.single_thread_executor.as_ref().unwrap().borrow();
+        wire::decode(value, &*executor)
+    }
+    fn fail(&mut self, error: VmError) -> Result<Val, VmError> {
+        self.last_error = Some(error.clone());
+        self.last_stack_trace = vec![];
+        Err(error)
+    }
+    // a run that ended goes on with the tick it belongs to
+    fn settle(&mut self, result: Val) -> Result<Val, VmError> {
+        let result = self.settle_run(result);
+        self.run_due_timers(result)
+    }
+    // a type 252 reply means the run was aborted by the error kept in last_error
+    fn settle_run(&mut self, result: Val) -> Result<Val, VmError> {
+        if result.typ() == 252 {
+            if let Some(error) = self.last_error.clone() {
+                return Err(error);
             }
         }
+        Ok(result)
     }
     fn handle_executor_request(&mut self, op_code: u8, cb_id: i64, payload: Val) -> Val {
+        self.pending_host_call = None;
+        let requests = self
+            .single_thread_executor
+            .as_ref()
+            .unwrap()
+            .borrow_mut()
+            .take_host_requests();
+        for request in requests.iter() {
+            let request = self.async_host_call_json(request);
+            self.sending_async_host_calls.push(request);
+        }
         match op_code {
             0x01 => payload,
             0x02 => {
-                let params = payload.as_array().borrow().data.clone();
+                let params = match &payload {
+                    Val::Array(params) => params.borrow().data.clone(),
+                    _ => vec![],
+                };
+                let param = |i: usize| params.get(i).cloned().unwrap_or(Val::Null);
+                let input = self
+                    .single_thread_executor
+                    .as_ref()
+                    .unwrap()
+                    .borrow_mut()
+                    .take_host_input();
+                let host_call = HostCallData {
+                    id: cb_id,
+                    api_name: param(0).as_string().unwrap_or_default(),
+                    payload: param(2).stringify(),
+                    input,
+                };
                 self.pending_host_call_id = cb_id;
-                self.sending_host_call_data = Some(
-                    json!({
-                        "machineId": self.machine_id,
-                        "apiName": params[0].as_string(),
-                        "payload": params[2].stringify(),
-                    })
-                    .to_string(),
-                );
-                Val::new(253, Rc::new(RefCell::new(Box::new(0))))
+                self.sending_host_call_data = Some(self.host_call_json(&host_call));
+                self.pending_host_call = Some(host_call);
+                Val::HostCall
+            }
+            0x04 => {
+                if let Val::Error(error) = &payload {
+                    self.last_error = Some((**error).clone());
+                }
+                self.last_stack_trace = self
+                    .single_thread_executor
+                    .as_ref()
+                    .unwrap()
+                    .borrow_mut()
+                    .take_stack_trace();
+                payload
+            }
+            0x05 => {
+                self.out_of_fuel = true;
+                Val::OutOfFuel
             }
-            _ => Val::new(0, Rc::new(RefCell::new(Box::new(0)))),
+            0x06 => Val::Paused,
+            0x07 => Val::Awaiting,
+            _ => Val::Null,
         }
     }
 }
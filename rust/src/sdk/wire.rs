@@ -0,0 +1,482 @@
+use std::{collections::HashMap, rc::Rc};
+
+use serde_json::{json, Map, Number, Value};
+
+use crate::sdk::{
+    data::{Array, Object, Val, ValGroup},
+    error::VmError,
+};
+
+// deeper values are refused instead of overflowing the stack
+const MAX_DEPTH: usize = 128;
+// type of objects the host creates
+const HOST_OBJECT: i64 = -2;
+
+/// Looks up the values the wire refers to instead of carrying them:
+/// functions by name and promises by the call id of their host call.
+pub trait Refs {
+    fn function(&self, name: &str) -> Option<Val>;
+    fn promise(&self, call_id: i64) -> Option<Val>;
+}
+
+fn typed(typ: &str, value: Value) -> Value {
+    json!({ "type": typ, "data": { "value": value } })
+}
+
+// JSON has no NaN or infinities, they travel as strings
+fn float(v: f64) -> Value {
+    match Number::from_f64(v) {
+        Some(n) => Value::Number(n),
+        None if v.is_nan() => json!("NaN"),
+        None if v > 0.0 => json!("Infinity"),
+        None => json!("-Infinity"),
+    }
+}
+
+fn float_of(value: &Value) -> Option<f64> {
+    match value {
+        Value::String(s) if s == "NaN" => Some(f64::NAN),
+        Value::String(s) if s == "Infinity" => Some(f64::INFINITY),
+        Value::String(s) if s == "-Infinity" => Some(f64::NEG_INFINITY),
+        _ => value.as_f64(),
+    }
+}
+
+/// Encodes `val` as a typed value, `{"type": .., "data": {"value": ..}}`,
+/// the form the host answers with. Objects and arrays nest typed values;
+/// objects of a type other than the host's carry it in `data.typ`.
+/// Promises travel by call id. Two things do not round-trip: functions
+/// travel by name and come back as whatever the name refers to when the
+/// reply is decoded, and values shared between containers come back as
+/// separate copies. A container holding itself is refused.
+pub fn encode(val: &Val) -> Result<Value, VmError> {
+    encode_in(val, &mut vec![])
+}
+
+fn encode_in(val: &Val, path: &mut Vec<*const ()>) -> Result<Value, VmError> {
+    let container = match val {
+        Val::Object(object) => Some(Rc::as_ptr(object) as *const ()),
+        Val::Array(array) => Some(Rc::as_ptr(array) as *const ()),
+        _ => None,
+    };
+    if let Some(ptr) = container {
+        if path.contains(&ptr) {
+            return Err(VmError::TypeMismatch(
+                "a value that contains itself can not be sent to the host".to_string(),
+            ));
+        }
+        if path.len() >= MAX_DEPTH {
+            return Err(VmError::TypeMismatch(format!(
+                "a value nested deeper than {} can not be sent to the host",
+                MAX_DEPTH
+            )));
+        }
+        path.push(ptr);
+    }
+    let encoded = match val {
+        Val::I16(v) => typed("i16", json!(v)),
+        Val::I32(v) => typed("i32", json!(v)),
+        Val::I64(v) => typed("i64", json!(v)),
+        Val::F32(v) => typed("f32", float(*v as f64)),
+        Val::F64(v) => typed("f64", float(*v)),
+        Val::Bool(v) => typed("bool", json!(v)),
+        Val::Str(v) => typed("string", json!(&**v)),
+        Val::Object(object) => {
+            let object = object.borrow();
+            let mut props = Map::new();
+            for (key, prop) in object.data.data.iter() {
+                props.insert(key.clone(), encode_in(prop, path)?);
+            }
+            let mut encoded = typed("object", Value::Object(props));
+            if object.typ != HOST_OBJECT {
+                encoded["data"]["typ"] = json!(object.typ);
+            }
+            encoded
+        }
+        Val::Array(array) => {
+            let items = array
+                .borrow()
+                .data
+                .iter()
+                .map(|item| encode_in(item, path))
+                .collect::<Result<_, _>>()?;
+            typed("array", Value::Array(items))
+        }
+        Val::Function(func) => typed("function", json!(func.borrow().name)),
+        Val::Native(name) => typed("function", json!(&**name)),
+        Val::Promise(promise) => typed("promise", json!(promise.borrow().call_id)),
+        _ => typed("null", Value::Null),
+    };
+    if container.is_some() {
+        path.pop();
+    }
+    Ok(encoded)
+}
+
+fn out_of_range(v: i64, typ: &str) -> VmError {
+    VmError::BadHostReply(format!("{} does not fit in an {}", v, typ))
+}
+
+/// Decodes a value from the host. Typed values come back as what `encode`
+/// was given, functions and promises are looked up through `refs`; a typed
+/// value whose payload does not match its type, an unknown type and an
+/// integer that does not fit its type are refused. Plain JSON is accepted
+/// too: integers become i64, other numbers f64, and objects and arrays are
+/// decoded item by item.
+pub fn decode(value: &Value, refs: &dyn Refs) -> Result<Val, VmError> {
+    let typed = value
+        .as_object()
+        .and_then(|obj| obj.get("type").and_then(Value::as_str).map(|t| (obj, t)));
+    if let Some((obj, typ)) = typed {
+        let data = obj.get("data").and_then(Value::as_object);
+        let inner = data
+            .and_then(|data| data.get("value"))
+            .unwrap_or(&Value::Null);
+        match typ {
+            "null" => return Ok(Val::Null),
+            "i16" => {
+                if let Some(v) = inner.as_i64() {
+                    return i16::try_from(v)
+                        .map(Val::I16)
+                        .map_err(|_| out_of_range(v, "i16"));
+                }
+            }
+            "i32" => {
+                if let Some(v) = inner.as_i64() {
+                    return i32::try_from(v)
+                        .map(Val::I32)
+                        .map_err(|_| out_of_range(v, "i32"));
+                }
+            }
+            "i64" => {
+                if let Some(v) = inner.as_i64() {
+                    return Ok(Val::I64(v));
+                }
+            }
+            "f32" => {
+                if let Some(v) = float_of(inner) {
+                    return Ok(Val::F32(v as f32));
+                }
+            }
+            "f64" => {
+                if let Some(v) = float_of(inner) {
+                    return Ok(Val::F64(v));
+                }
+            }
+            "bool" => {
+                if let Some(v) = inner.as_bool() {
+                    return Ok(Val::Bool(v));
+                }
+            }
+            "string" => {
+                if let Some(v) = inner.as_str() {
+                    return Ok(Val::string(v.to_string()));
+                }
+            }
+            "object" => {
+                if let Some(map) = inner.as_object() {
+                    let typ = data
+                        .and_then(|data| data.get("typ"))
+                        .and_then(Value::as_i64)
+                        .unwrap_or(HOST_OBJECT);
+                    let mut props = HashMap::new();
+                    for (k, v) in map.iter() {
+                        props.insert(k.clone(), decode(v, refs)?);
+                    }
+                    return Ok(Val::object(Object::new(typ, ValGroup::new(props))));
+                }
+            }
+            "array" => {
+                if let Some(items) = inner.as_array() {
+                    let items = items
+                        .iter()
+                        .map(|item| decode(item, refs))
+                        .collect::<Result<_, _>>()?;
+                    return Ok(Val::array(Array::new(items)));
+                }
+            }
+            "function" => {
+                if let Some(name) = inner.as_str() {
+                    return refs.function(name).ok_or_else(|| {
+                        VmError::BadHostReply(format!("no function named '{}'", name))
+                    });
+                }
+            }
+            "promise" => {
+                if let Some(call_id) = inner.as_i64() {
+                    return refs.promise(call_id).ok_or_else(|| {
+                        VmError::BadHostReply(format!("no async host call {} is pending", call_id))
+                    });
+                }
+            }
+            // an object with nothing but a type and data is meant as a
+            // typed value, other objects are plain JSON with a type prop
+            _ if obj.len() == 2 && data.is_some() => {
+                return Err(VmError::BadHostReply(format!("unknown type '{}'", typ)));
+            }
+            _ => return decode_plain(value, refs),
+        }
+        return Err(VmError::BadHostReply(format!(
+            "{} is not a valid {} value",
+            inner, typ
+        )));
+    }
+    decode_plain(value, refs)
+}
+
+fn decode_plain(value: &Value, refs: &dyn Refs) -> Result<Val, VmError> {
+    Ok(match value {
+        Value::Null => Val::Null,
+        Value::Bool(v) => Val::Bool(*v),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => Val::I64(i),
+            None => Val::F64(n.as_f64().unwrap_or(0.0)),
+        },
+        Value::String(s) => Val::string(s.clone()),
+        Value::Array(items) => Val::array(Array::new(
+            items
+                .iter()
+                .map(|item| decode(item, refs))
+                .collect::<Result<_, _>>()?,
+        )),
+        Value::Object(map) => {
+            let mut props = HashMap::new();
+            for (k, v) in map.iter() {
+                props.insert(k.clone(), decode(v, refs)?);
+            }
+            Val::object(Object::new(HOST_OBJECT, ValGroup::new(props)))
+        }
+    })
+}
+
+/// The compact form of a typed value for hosts that would rather not parse
+/// JSON. Every value is a type code byte followed by its data, numbers are
+/// little endian and lengths u32:
+///
+/// | Code | Data |
+/// |------|------|
+/// | 0 | null, nothing |
+/// | 1, 2, 3 | i16, i32, i64 |
+/// | 4, 5 | f32, f64 (IEEE 754 bits) |
+/// | 6 | bool, one byte 0 or 1 |
+/// | 7 | string, length and UTF-8 bytes |
+/// | 8 | object, i64 type, prop count, then key (like a string) and value per prop |
+/// | 9 | array, item count, then the items |
+/// | 10 | function, its name like a string |
+/// | 11 | promise, the i64 call id |
+///
+/// Object props are written in key order.
+pub fn to_binary(value: &Value) -> Result<Vec<u8>, VmError> {
+    let mut out = vec![];
+    write_binary(value, &mut out, 0)?;
+    Ok(out)
+}
+
+fn not_typed(reason: &str) -> VmError {
+    VmError::BadHostReply(format!("not a typed value: {}", reason))
+}
+
+fn write_str(s: &str, out: &mut Vec<u8>) {
+    out.extend_from_slice(&(s.len() as u32).to_le_bytes());
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn write_binary(value: &Value, out: &mut Vec<u8>, depth: usize) -> Result<(), VmError> {
+    if depth > MAX_DEPTH {
+        return Err(not_typed(&format!("nested deeper than {}", MAX_DEPTH)));
+    }
+    let typ = value
+        .get("type")
+        .and_then(Value::as_str)
+        .ok_or_else(|| not_typed("the type is missing"))?;
+    let data = value.get("data");
+    let inner = data
+        .and_then(|data| data.get("value"))
+        .unwrap_or(&Value::Null);
+    let int = || {
+        inner
+            .as_i64()
+            .ok_or_else(|| not_typed("expected an integer"))
+    };
+    let num = || float_of(inner).ok_or_else(|| not_typed("expected a number"));
+    let text = || inner.as_str().ok_or_else(|| not_typed("expected a string"));
+    match typ {
+        "null" => out.push(0),
+        "i16" => {
+            out.push(1);
+            let v = int()?;
+            let v = i16::try_from(v).map_err(|_| out_of_range(v, "i16"))?;
+            out.extend_from_slice(&v.to_le_bytes());
+        }
+        "i32" => {
+            out.push(2);
+            let v = int()?;
+            let v = i32::try_from(v).map_err(|_| out_of_range(v, "i32"))?;
+            out.extend_from_slice(&v.to_le_bytes());
+        }
+        "i64" => {
+            out.push(3);
+            out.extend_from_slice(&int()?.to_le_bytes());
+        }
+        "f32" => {
+            out.push(4);
+            out.extend_from_slice(&(num()? as f32).to_bits().to_le_bytes());
+        }
+        "f64" => {
+            out.push(5);
+            out.extend_from_slice(&num()?.to_bits().to_le_bytes());
+        }
+        "bool" => {
+            out.push(6);
+            out.push(
+                inner
+                    .as_bool()
+                    .ok_or_else(|| not_typed("expected a bool"))? as u8,
+            );
+        }
+        "string" => {
+            out.push(7);
+            write_str(text()?, out);
+        }
+        "object" => {
+            let props = inner
+                .as_object()
+                .ok_or_else(|| not_typed("expected an object"))?;
+            let typ = data
+                .and_then(|data| data.get("typ"))
+                .and_then(Value::as_i64)
+                .unwrap_or(HOST_OBJECT);
+            out.push(8);
+            out.extend_from_slice(&typ.to_le_bytes());
+            out.extend_from_slice(&(props.len() as u32).to_le_bytes());
+            let mut keys: Vec<&String> = props.keys().collect();
+            keys.sort();
+            for key in keys {
+                write_str(key, out);
+                write_binary(&props[key], out, depth + 1)?;
+            }
+        }
+        "array" => {
+            let items = inner
+                .as_array()
+                .ok_or_else(|| not_typed("expected an array"))?;
+            out.push(9);
+            out.extend_from_slice(&(items.len() as u32).to_le_bytes());
+            for item in items {
+                write_binary(item, out, depth + 1)?;
+            }
+        }
+        "function" => {
+            out.push(10);
+            write_str(text()?, out);
+        }
+        "promise" => {
+            out.push(11);
+            out.extend_from_slice(&int()?.to_le_bytes());
+        }
+        other => return Err(not_typed(&format!("unknown type '{}'", other))),
+    }
+    Ok(())
+}
+
+/// Reads a value written like `to_binary` back into its typed JSON form.
+pub fn from_binary(bytes: &[u8]) -> Result<Value, VmError> {
+    let mut reader = BinaryReader { bytes, at: 0 };
+    let value = reader.value(0)?;
+    if reader.at != bytes.len() {
+        return Err(VmError::BadHostReply(format!(
+            "binary value has {} bytes left over",
+            bytes.len() - reader.at
+        )));
+    }
+    Ok(value)
+}
+
+struct BinaryReader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl<'a> BinaryReader<'a> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], VmError> {
+        let end = self.at + N;
+        let bytes = self.bytes.get(self.at..end).ok_or_else(|| {
+            VmError::BadHostReply(format!("binary value is truncated at byte {}", self.at))
+        })?;
+        self.at = end;
+        Ok(bytes.try_into().unwrap())
+    }
+    fn len(&mut self) -> Result<usize, VmError> {
+        Ok(u32::from_le_bytes(self.take()?) as usize)
+    }
+    fn str(&mut self) -> Result<String, VmError> {
+        let len = self.len()?;
+        let start = self.at;
+        let bytes = self
+            .bytes
+            .get(start..start.saturating_add(len))
+            .ok_or_else(|| {
+                VmError::BadHostReply(format!("binary value is truncated at byte {}", start))
+            })?;
+        self.at += len;
+        String::from_utf8(bytes.to_vec()).map_err(|_| {
+            VmError::BadHostReply(format!("binary string at byte {} is not UTF-8", start))
+        })
+    }
+    fn value(&mut self, depth: usize) -> Result<Value, VmError> {
+        if depth > MAX_DEPTH {
+            return Err(VmError::BadHostReply(format!(
+                "binary value is nested deeper than {}",
+                MAX_DEPTH
+            )));
+        }
+        let at = self.at;
+        let [code] = self.take()?;
+        Ok(match code {
+            0 => typed("null", Value::Null),
+            1 => typed("i16", json!(i16::from_le_bytes(self.take()?))),
+            2 => typed("i32", json!(i32::from_le_bytes(self.take()?))),
+            3 => typed("i64", json!(i64::from_le_bytes(self.take()?))),
+            4 => typed(
+                "f32",
+                float(f32::from_bits(u32::from_le_bytes(self.take()?)) as f64),
+            ),
+            5 => typed(
+                "f64",
+                float(f64::from_bits(u64::from_le_bytes(self.take()?))),
+            ),
+            6 => typed("bool", json!(u8::from_le_bytes(self.take()?) != 0)),
+            7 => typed("string", json!(self.str()?)),
+            8 => {
+                let typ = i64::from_le_bytes(self.take()?);
+                let count = self.len()?;
+                let mut props = Map::new();
+                for _ in 0..count {
+                    let key = self.str()?;
+                    props.insert(key, self.value(depth + 1)?);
+                }
+                let mut object = typed("object", Value::Object(props));
+                if typ != HOST_OBJECT {
+                    object["data"]["typ"] = json!(typ);
+                }
+                object
+            }
+            9 => {
+                let count = self.len()?;
+                let mut items = vec![];
+                for _ in 0..count {
+                    items.push(self.value(depth + 1)?);
+                }
+                typed("array", Value::Array(items))
+            }
+            10 => typed("function", json!(self.str()?)),
+            11 => typed("promise", json!(i64::from_le_bytes(self.take()?))),
+            code => {
+                return Err(VmError::BadHostReply(format!(
+                    "unknown type code {} at byte {}",
+                    code, at
+                )))
+            }
+        })
+    }
+}
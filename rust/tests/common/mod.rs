@@ -0,0 +1,54 @@
+// Helpers shared by the integration tests. Every test crate compiles its
+// own copy of this module and uses only part of it.
+#![allow(dead_code)]
+
+use elpian_vm::sdk::{compiler, vm::VM};
+use serde_json::{json, Value};
+
+/// A vm running `code` that may call the host apis in `apis`.
+pub fn vm_of(machine_id: &str, code: &str, apis: &[&str]) -> VM {
+    VM::compile_and_create_of_code(
+        machine_id.to_string(),
+        code.to_string(),
+        0,
+        apis.iter().map(|api| api.to_string()).collect(),
+    )
+    .unwrap()
+}
+
+/// The host call the vm is paused on.
+pub fn host_call(vm: &VM) -> Value {
+    serde_json::from_str(vm.sending_host_call_data.as_ref().unwrap()).unwrap()
+}
+
+/// Calls `func_name` with `input` as an i64, the result stringified.
+pub fn call(vm: &mut VM, func_name: &str, input: i64) -> String {
+    let input = json!({ "type": "i64", "data": { "value": input } }).to_string();
+    vm.run_func_with_input(func_name, Some(&input), 0)
+        .unwrap()
+        .stringify()
+}
+
+/// Payloads of the host calls of a run, answering each one with `true`.
+pub fn printed(vm: &mut VM) -> Vec<String> {
+    let mut lines = vec![];
+    let mut result = vm.run().unwrap();
+    while result.typ() == 253 {
+        lines.push(host_call(vm)["payload"].as_str().unwrap().to_string());
+        result = vm.continue_run("true".to_string()).unwrap();
+    }
+    lines
+}
+
+/// The syntax errors of `code`, as `line:column: message`.
+pub fn errors_of(code: &str) -> Vec<String> {
+    match compiler::compile_code(code.to_string()) {
+        Ok(_) => vec![],
+        Err(errors) => errors.iter().map(|error| error.to_string()).collect(),
+    }
+}
+
+/// A host reply holding the string `value`.
+pub fn string(value: &str) -> String {
+    json!({ "type": "string", "data": { "value": value } }).to_string()
+}
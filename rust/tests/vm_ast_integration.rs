@@ -1,11 +1,22 @@
-use elpian_vm::sdk::{data::Val, vm::VM};
+use elpian_vm::api;
+use elpian_vm::sdk::{
+    bytecode::{BytecodeModule, FunctionEntry, Program, FORMAT_VERSION},
+    data::Val,
+    error::VmError,
+    vm::VM,
+};
 use serde_json::{json, Value};
 
+/// Host apis the test programs below are allowed to call through `askHost`.
+fn allowed() -> Vec<String> {
+    vec!["println".to_string(), "render".to_string()]
+}
+
 fn collect_host_calls(mut vm: VM) -> (Vec<Value>, Val) {
     let mut host_calls = Vec::new();
-    let mut result = vm.run();
+    let mut result = vm.run().expect("vm run should not fail");
 
-    while result.typ == 253 {
+    while result.typ() == 253 {
         let raw = vm
             .sending_host_call_data
             .clone()
@@ -13,7 +24,9 @@ fn collect_host_calls(mut vm: VM) -> (Vec<Value>, Val) {
         let payload: Value =
             serde_json::from_str(&raw).expect("host call payload should be valid JSON");
         host_calls.push(payload);
-        result = vm.continue_run("{\"type\":\"bool\",\"data\":{\"value\":true}}".to_string());
+        result = vm
+            .continue_run("{\"type\":\"bool\",\"data\":{\"value\":true}}".to_string())
+            .expect("host reply should be accepted");
     }
 
     (host_calls, result)
@@ -29,6 +42,7 @@ fn host_call_from_paused_vm(vm: &VM) -> Value {
 
 fn continue_past_host_call(vm: &mut VM) -> Val {
     vm.continue_run("{\"type\":\"bool\",\"data\":{\"value\":true}}".to_string())
+        .expect("host reply should be accepted")
 }
 
 #[test]
@@ -86,7 +100,7 @@ fn arithmetic_indexer_and_cast_ast_executes_correctly() {
       ]
     });
 
-    let vm = VM::compile_and_create_of_ast("arith-vm".to_string(), program, 0, vec![]);
+    let vm = VM::compile_and_create_of_ast("arith-vm".to_string(), program, 0, allowed()).unwrap();
     let (calls, final_result) = collect_host_calls(vm);
 
     assert_eq!(calls.len(), 1);
@@ -181,7 +195,7 @@ fn if_elseif_else_ast_picks_expected_branch() {
       ]
     });
 
-    let vm = VM::compile_and_create_of_ast("if-vm".to_string(), program, 0, vec![]);
+    let vm = VM::compile_and_create_of_ast("if-vm".to_string(), program, 0, allowed()).unwrap();
     let (calls, _) = collect_host_calls(vm);
 
     assert_eq!(calls.len(), 1);
@@ -218,15 +232,18 @@ fn function_definition_and_input_argument_ast_executes() {
       ]
     });
 
-    let mut vm = VM::compile_and_create_of_ast("func-vm".to_string(), program, 0, vec![]);
-    let boot = vm.run();
+    let mut vm =
+        VM::compile_and_create_of_ast("func-vm".to_string(), program, 0, allowed()).unwrap();
+    let boot = vm.run().unwrap();
     assert_eq!(boot.stringify(), "\"[undefined]\"");
 
-    let result = vm.run_func_with_input(
-        "greet",
-        Some(r#"{"type":"string","data":{"value":"Elpian"}}"#),
-        0,
-    );
+    let result = vm
+        .run_func_with_input(
+            "greet",
+            Some(r#"{"type":"string","data":{"value":"Elpian"}}"#),
+            0,
+        )
+        .unwrap();
     assert_eq!(result.stringify(), "\"Hello, Elpian\"");
 }
 
@@ -295,7 +312,7 @@ fn switch_statement_ast_matches_case() {
       ]
     });
 
-    let vm = VM::compile_and_create_of_ast("switch-vm".to_string(), program, 0, vec![]);
+    let vm = VM::compile_and_create_of_ast("switch-vm".to_string(), program, 0, allowed()).unwrap();
     let (calls, _) = collect_host_calls(vm);
 
     assert_eq!(calls.len(), 1);
@@ -370,15 +387,16 @@ fn vm_counter_example_boot_and_increment_render_without_errors() {
         ]
     });
 
-    let mut vm = VM::compile_and_create_of_ast("vm-counter".to_string(), program, 0, vec![]);
+    let mut vm =
+        VM::compile_and_create_of_ast("vm-counter".to_string(), program, 0, allowed()).unwrap();
 
-    let boot = vm.run();
-    assert_eq!(boot.typ, 253);
+    let boot = vm.run().unwrap();
+    assert_eq!(boot.typ(), 253);
     assert_eq!(host_call_from_paused_vm(&vm)["payload"], "[0]");
     let _ = continue_past_host_call(&mut vm);
 
-    let increment = vm.run_func_with_input("increment", None, 0);
-    assert_eq!(increment.typ, 253);
+    let increment = vm.run_func_with_input("increment", None, 0).unwrap();
+    assert_eq!(increment.typ(), 253);
     assert_eq!(host_call_from_paused_vm(&vm)["payload"], "[1]");
 }
 
@@ -443,14 +461,15 @@ fn vm_theme_example_toggle_switches_background_value() {
         ]
     });
 
-    let mut vm = VM::compile_and_create_of_ast("vm-theme".to_string(), program, 0, vec![]);
-    let boot = vm.run();
-    assert_eq!(boot.typ, 253);
+    let mut vm =
+        VM::compile_and_create_of_ast("vm-theme".to_string(), program, 0, allowed()).unwrap();
+    let boot = vm.run().unwrap();
+    assert_eq!(boot.typ(), 253);
     assert_eq!(host_call_from_paused_vm(&vm)["payload"], "[false]");
     let _ = continue_past_host_call(&mut vm);
 
-    let toggled = vm.run_func_with_input("toggleTheme", None, 0);
-    assert_eq!(toggled.typ, 253);
+    let toggled = vm.run_func_with_input("toggleTheme", None, 0).unwrap();
+    assert_eq!(toggled.typ(), 253);
     assert_eq!(host_call_from_paused_vm(&vm)["payload"], "[true]");
 }
 
@@ -515,21 +534,24 @@ fn vm_message_example_updates_text_from_function_input() {
         ]
     });
 
-    let mut vm = VM::compile_and_create_of_ast("vm-message".to_string(), program, 0, vec![]);
-    let boot = vm.run();
-    assert_eq!(boot.typ, 253);
+    let mut vm =
+        VM::compile_and_create_of_ast("vm-message".to_string(), program, 0, allowed()).unwrap();
+    let boot = vm.run().unwrap();
+    assert_eq!(boot.typ(), 253);
     assert_eq!(
         host_call_from_paused_vm(&vm)["payload"],
         "[\"Waiting for Flutter input...\"]"
     );
     let _ = continue_past_host_call(&mut vm);
 
-    let updated = vm.run_func_with_input(
-        "setMessage",
-        Some(r#"{"type":"string","data":{"value":"Hello from Flutter"}}"#),
-        0,
-    );
-    assert_eq!(updated.typ, 253);
+    let updated = vm
+        .run_func_with_input(
+            "setMessage",
+            Some(r#"{"type":"string","data":{"value":"Hello from Flutter"}}"#),
+            0,
+        )
+        .unwrap();
+    assert_eq!(updated.typ(), 253);
     assert_eq!(
         host_call_from_paused_vm(&vm)["payload"],
         "[\"Hello from Flutter\"]"
@@ -564,10 +586,11 @@ fn host_call_ast_round_trips_through_continue_run() {
       ]
     });
 
-    let mut vm = VM::compile_and_create_of_ast("host-vm".to_string(), program, 0, vec![]);
-    let first = vm.run();
+    let mut vm =
+        VM::compile_and_create_of_ast("host-vm".to_string(), program, 0, allowed()).unwrap();
+    let first = vm.run().unwrap();
 
-    assert_eq!(first.typ, 253);
+    assert_eq!(first.typ(), 253);
     let call_data = vm
         .sending_host_call_data
         .clone()
@@ -580,7 +603,9 @@ fn host_call_ast_round_trips_through_continue_run() {
         .unwrap_or_default()
         .contains("\"Text\""));
 
-    let final_result = vm.continue_run("{\"type\":\"bool\",\"data\":{\"value\":true}}".to_string());
+    let final_result = vm
+        .continue_run("{\"type\":\"bool\",\"data\":{\"value\":true}}".to_string())
+        .unwrap();
     assert_eq!(final_result.stringify(), "\"[undefined]\"");
 }
 
@@ -621,14 +646,16 @@ fn function_call_with_extra_input_args_does_not_panic_for_zero_param_functions()
       ]
     });
 
-    let mut vm = VM::compile_and_create_of_ast("vm-extra-args".to_string(), program, 0, vec![]);
-    let boot = vm.run();
+    let mut vm =
+        VM::compile_and_create_of_ast("vm-extra-args".to_string(), program, 0, allowed()).unwrap();
+    let boot = vm.run().unwrap();
     assert_eq!(boot.stringify(), "\"[undefined]\"");
 
     // Simulates current UI event routing that always forwards an input payload.
-    let with_input =
-        vm.run_func_with_input("rerender", Some(r#"{"type":"tap","target":"card-1"}"#), 0);
-    assert_eq!(with_input.typ, 253);
+    let with_input = vm
+        .run_func_with_input("rerender", Some(r#"{"type":"tap","target":"card-1"}"#), 0)
+        .unwrap();
+    assert_eq!(with_input.typ(), 253);
     assert_eq!(host_call_from_paused_vm(&vm)["apiName"], "render");
     assert!(host_call_from_paused_vm(&vm)["payload"]
         .as_str()
@@ -668,16 +695,688 @@ fn function_call_with_plain_json_input_maps_to_vm_object() {
       ]
     });
 
-    let mut vm = VM::compile_and_create_of_ast("vm-plain-json".to_string(), program, 0, vec![]);
-    let boot = vm.run();
+    let mut vm =
+        VM::compile_and_create_of_ast("vm-plain-json".to_string(), program, 0, allowed()).unwrap();
+    let boot = vm.run().unwrap();
     assert_eq!(boot.stringify(), "\"[undefined]\"");
 
-    let result = vm.run_func_with_input(
-        "handleEvent",
-        Some(r#"{"type":"tap","currentTarget":"button_1"}"#),
-        0,
-    );
-    assert_eq!(result.typ, 253);
+    let result = vm
+        .run_func_with_input(
+            "handleEvent",
+            Some(r#"{"type":"tap","currentTarget":"button_1"}"#),
+            0,
+        )
+        .unwrap();
+    assert_eq!(result.typ(), 253);
     assert_eq!(host_call_from_paused_vm(&vm)["apiName"], "println");
     assert_eq!(host_call_from_paused_vm(&vm)["payload"], "[\"tap\"]");
 }
+
+fn println_program(api_name: &str) -> Value {
+    json!({
+      "type": "program",
+      "body": [
+        {
+          "type": "host_call",
+          "data": {
+            "name": api_name,
+            "args": [{ "type": "string", "data": { "value": "hi" } }]
+          }
+        }
+      ]
+    })
+}
+
+#[test]
+fn host_call_outside_allowlist_is_rejected_with_script_error() {
+    let mut vm = VM::compile_and_create_of_ast(
+        "vm-denied".to_string(),
+        println_program("dom.clear"),
+        0,
+        allowed(),
+    )
+    .unwrap();
+    let error = vm
+        .run()
+        .expect_err("denied host call should report an error");
+
+    assert_eq!(error, VmError::HostApiDenied("dom.clear".to_string()));
+    assert!(error.to_string().contains("dom.clear"));
+    assert!(vm.sending_host_call_data.is_none());
+    assert!(!vm.is_exec_processing());
+}
+
+#[test]
+fn wildcard_capability_groups_allow_nested_apis() {
+    let mut vm = VM::compile_and_create_of_ast(
+        "vm-wildcard".to_string(),
+        println_program("canvas.fillRect"),
+        0,
+        vec!["canvas.*".to_string()],
+    )
+    .unwrap();
+    assert_eq!(vm.run().unwrap().typ(), 253);
+    assert_eq!(host_call_from_paused_vm(&vm)["apiName"], "canvas.fillRect");
+
+    let mut denied = VM::compile_and_create_of_ast(
+        "vm-wildcard-denied".to_string(),
+        println_program("canvasX.fillRect"),
+        0,
+        vec!["canvas.*".to_string()],
+    )
+    .unwrap();
+    assert_eq!(
+        denied.run().unwrap_err(),
+        VmError::HostApiDenied("canvasX.fillRect".to_string())
+    );
+}
+
+#[test]
+fn api_create_vm_honours_caller_supplied_capabilities() {
+    let ast = println_program("println").to_string();
+
+    assert!(api::create_vm_from_ast(
+        "api-caps-denied".to_string(),
+        ast.clone(),
+        Some(vec!["render".to_string()]),
+    ));
+    let denied = api::execute_vm("api-caps-denied".to_string());
+    assert!(!denied.has_host_call);
+    assert!(denied.error.is_some());
+
+    assert!(api::create_vm_from_ast(
+        "api-caps-allowed".to_string(),
+        ast,
+        api::parse_capabilities(r#"["println"]"#),
+    ));
+    let allowed = api::execute_vm("api-caps-allowed".to_string());
+    assert!(allowed.has_host_call);
+    assert!(allowed.error.is_none());
+
+    api::destroy_vm("api-caps-denied".to_string());
+    api::destroy_vm("api-caps-allowed".to_string());
+}
+
+/// `def i = 0; loop (i < limit) { i = i + 1 }; println(i)`
+fn counting_loop_program(limit: i64) -> Value {
+    json!({
+      "type": "program",
+      "body": [
+        {
+          "type": "definition",
+          "data": {
+            "leftSide": { "type": "identifier", "data": { "name": "i" } },
+            "rightSide": { "type": "i16", "data": { "value": 0 } }
+          }
+        },
+        {
+          "type": "loopStmt",
+          "data": {
+            "condition": {
+              "type": "arithmetic",
+              "data": {
+                "operation": "<",
+                "operand1": { "type": "identifier", "data": { "name": "i" } },
+                "operand2": { "type": "i64", "data": { "value": limit } }
+              }
+            },
+            "body": [
+              {
+                "type": "assignment",
+                "data": {
+                  "leftSide": { "type": "identifier", "data": { "name": "i" } },
+                  "rightSide": {
+                    "type": "arithmetic",
+                    "data": {
+                      "operation": "+",
+                      "operand1": { "type": "identifier", "data": { "name": "i" } },
+                      "operand2": { "type": "i16", "data": { "value": 1 } }
+                    }
+                  }
+                }
+              }
+            ]
+          }
+        },
+        {
+          "type": "host_call",
+          "data": {
+            "name": "println",
+            "args": [{ "type": "identifier", "data": { "name": "i" } }]
+          }
+        }
+      ]
+    })
+}
+
+#[test]
+fn out_of_fuel_pauses_and_resumes_mid_loop() {
+    let mut vm = VM::compile_and_create_of_ast(
+        "vm-fuel".to_string(),
+        counting_loop_program(40),
+        0,
+        allowed(),
+    )
+    .unwrap();
+    vm.set_fuel_limit(Some(25));
+
+    let mut result = vm.run().unwrap();
+    let mut pauses = 0;
+    while result.typ() == 251 {
+        assert!(vm.is_exec_processing());
+        assert_eq!(vm.remaining_fuel(), Some(0));
+        pauses += 1;
+        result = vm.resume_with_fuel(25).unwrap();
+    }
+    assert!(pauses > 1);
+
+    assert_eq!(result.typ(), 253);
+    assert_eq!(host_call_from_paused_vm(&vm)["payload"], "[40]");
+    let done = continue_past_host_call(&mut vm);
+    assert_eq!(done.stringify(), "\"[undefined]\"");
+    assert!(!vm.is_exec_processing());
+}
+
+#[test]
+fn infinite_loop_yields_and_can_be_aborted() {
+    let program = json!({
+      "type": "program",
+      "body": [
+        {
+          "type": "functionDefinition",
+          "data": {
+            "name": "ping",
+            "params": [],
+            "body": [
+              {
+                "type": "host_call",
+                "data": { "name": "println", "args": [{ "type": "string", "data": { "value": "pong" } }] }
+              }
+            ]
+          }
+        },
+        {
+          "type": "loopStmt",
+          "data": {
+            "condition": { "type": "bool", "data": { "value": true } },
+            "body": []
+          }
+        }
+      ]
+    });
+    let mut vm =
+        VM::compile_and_create_of_ast("vm-spin".to_string(), program, 0, allowed()).unwrap();
+    vm.set_fuel_limit(Some(1_000));
+
+    assert_eq!(vm.run().unwrap().typ(), 251);
+    assert_eq!(vm.resume_with_fuel(1_000).unwrap().typ(), 251);
+
+    vm.abort_run();
+    assert!(!vm.is_exec_processing());
+
+    // Globals defined before the abort survive, so the vm is still usable.
+    assert_eq!(vm.run_func_with_input("ping", None, 0).unwrap().typ(), 253);
+    assert_eq!(host_call_from_paused_vm(&vm)["payload"], "[\"pong\"]");
+}
+
+#[test]
+fn api_reports_out_of_fuel_and_resumes() {
+    let ast = counting_loop_program(10).to_string();
+    assert!(api::create_vm_from_ast("api-fuel".to_string(), ast, None));
+    assert!(api::set_vm_fuel_limit("api-fuel".to_string(), 5));
+
+    let mut result = api::execute_vm("api-fuel".to_string());
+    assert!(result.out_of_fuel);
+    assert_eq!(
+        api::execute_vm("api-fuel".to_string()).result_value,
+        "\"vm_busy\""
+    );
+    while result.out_of_fuel {
+        result = api::resume_vm_with_fuel("api-fuel".to_string(), 5);
+    }
+    assert!(result.has_host_call);
+    assert!(result.host_call_data.contains("[10]"));
+
+    api::destroy_vm("api-fuel".to_string());
+}
+
+// appends `chunk` to a global string `iterations` times, then reports the count
+fn string_building_program(iterations: i64, chunk: &str, keep_result: bool) -> Value {
+    let append = if keep_result {
+        json!({
+          "type": "assignment",
+          "data": {
+            "leftSide": { "type": "identifier", "data": { "name": "s" } },
+            "rightSide": {
+              "type": "arithmetic",
+              "data": {
+                "operation": "+",
+                "operand1": { "type": "identifier", "data": { "name": "s" } },
+                "operand2": { "type": "string", "data": { "value": chunk } }
+              }
+            }
+          }
+        })
+    } else {
+        json!({
+          "type": "definition",
+          "data": {
+            "leftSide": { "type": "identifier", "data": { "name": "tmp" } },
+            "rightSide": {
+              "type": "arithmetic",
+              "data": {
+                "operation": "+",
+                "operand1": { "type": "identifier", "data": { "name": "s" } },
+                "operand2": { "type": "string", "data": { "value": chunk } }
+              }
+            }
+          }
+        })
+    };
+    json!({
+      "type": "program",
+      "body": [
+        {
+          "type": "definition",
+          "data": {
+            "leftSide": { "type": "identifier", "data": { "name": "s" } },
+            "rightSide": { "type": "string", "data": { "value": "" } }
+          }
+        },
+        {
+          "type": "definition",
+          "data": {
+            "leftSide": { "type": "identifier", "data": { "name": "i" } },
+            "rightSide": { "type": "i16", "data": { "value": 0 } }
+          }
+        },
+        {
+          "type": "loopStmt",
+          "data": {
+            "condition": {
+              "type": "arithmetic",
+              "data": {
+                "operation": "<",
+                "operand1": { "type": "identifier", "data": { "name": "i" } },
+                "operand2": { "type": "i64", "data": { "value": iterations } }
+              }
+            },
+            "body": [
+              append,
+              {
+                "type": "assignment",
+                "data": {
+                  "leftSide": { "type": "identifier", "data": { "name": "i" } },
+                  "rightSide": {
+                    "type": "arithmetic",
+                    "data": {
+                      "operation": "+",
+                      "operand1": { "type": "identifier", "data": { "name": "i" } },
+                      "operand2": { "type": "i16", "data": { "value": 1 } }
+                    }
+                  }
+                }
+              }
+            ]
+          }
+        },
+        {
+          "type": "host_call",
+          "data": {
+            "name": "println",
+            "args": [{ "type": "identifier", "data": { "name": "i" } }]
+          }
+        }
+      ]
+    })
+}
+
+#[test]
+fn memory_limit_stops_unbounded_growth_with_script_error() {
+    let mut vm = VM::compile_and_create_of_ast(
+        "vm-memory".to_string(),
+        string_building_program(100_000, "0123456789abcdef", true),
+        0,
+        allowed(),
+    )
+    .unwrap();
+    vm.set_memory_limit(Some(8 * 1024));
+
+    let error = vm
+        .run()
+        .expect_err("exceeding the quota should report an error");
+    assert_eq!(error, VmError::MemoryLimitExceeded(8 * 1024));
+    assert!(!vm.is_exec_processing());
+    assert!(vm.memory_usage() <= 8 * 1024);
+}
+
+fn repeat_program(value: Value, count: i64) -> Value {
+    json!({
+      "type": "program",
+      "body": [
+        {
+          "type": "definition",
+          "data": {
+            "leftSide": { "type": "identifier", "data": { "name": "big" } },
+            "rightSide": {
+              "type": "arithmetic",
+              "data": {
+                "operation": "*",
+                "operand1": value,
+                "operand2": { "type": "i64", "data": { "value": count } }
+              }
+            }
+          }
+        }
+      ]
+    })
+}
+
+#[test]
+fn huge_repeats_are_refused_before_they_are_built() {
+    let text = json!({ "type": "string", "data": { "value": "x" } });
+    let items = json!({ "type": "array", "data": { "value": [{ "type": "i16", "data": { "value": 0 } }] } });
+    for (value, count) in [(text.clone(), 2_000_000_000), (items, 1_000_000_000)] {
+        let mut vm = VM::compile_and_create_of_ast(
+            "vm-repeat".to_string(),
+            repeat_program(value, count),
+            0,
+            allowed(),
+        )
+        .unwrap();
+        vm.set_memory_limit(Some(8 * 1024));
+        assert_eq!(
+            vm.run().unwrap_err(),
+            VmError::MemoryLimitExceeded(8 * 1024)
+        );
+        assert!(vm.allocated_bytes() < 8 * 1024);
+    }
+
+    // without a quota a repeat too big to address is refused as well
+    let mut vm = VM::compile_and_create_of_ast(
+        "vm-repeat".to_string(),
+        repeat_program(text, i64::MAX),
+        0,
+        allowed(),
+    )
+    .unwrap();
+    assert_eq!(
+        vm.run().unwrap_err(),
+        VmError::MemoryLimitExceeded(usize::MAX)
+    );
+}
+
+#[test]
+fn pushing_in_place_charges_only_the_new_slot() {
+    let allocated = |count: usize| {
+        let code = format!(
+            "def a = []\ndef i = 0\nwhile i < {} {{\n    a = a + i\n    i = i + 1\n}}\n",
+            count
+        );
+        let mut vm =
+            VM::compile_and_create_of_code("vm-push".to_string(), code, 0, allowed()).unwrap();
+        vm.set_memory_limit(Some(1024 * 1024));
+        vm.run().unwrap();
+        vm.allocated_bytes()
+    };
+    let base = allocated(0);
+    let first = allocated(1000) - base;
+    let second = allocated(2000) - base;
+    // twice the pushes cost twice the bytes, not four times
+    assert_eq!(second, 2 * first);
+}
+
+#[test]
+fn short_lived_allocations_do_not_exhaust_the_quota() {
+    let mut vm = VM::compile_and_create_of_ast(
+        "vm-memory-churn".to_string(),
+        string_building_program(300, "0123456789abcdef0123456789abcdef", false),
+        0,
+        allowed(),
+    )
+    .unwrap();
+    vm.set_memory_limit(Some(8 * 1024));
+
+    assert_eq!(vm.run().unwrap().typ(), 253);
+    assert_eq!(host_call_from_paused_vm(&vm)["payload"], "[300]");
+    assert!(vm.last_error.is_none());
+    assert!(vm.allocated_bytes() > 8 * 1024);
+}
+
+#[test]
+fn api_reports_memory_usage() {
+    let ast = string_building_program(20, "0123456789abcdef", true).to_string();
+    assert!(api::create_vm_from_ast("api-memory".to_string(), ast, None));
+    assert!(api::set_vm_memory_limit(
+        "api-memory".to_string(),
+        64 * 1024
+    ));
+
+    let result = api::execute_vm("api-memory".to_string());
+    assert!(result.has_host_call);
+    let usage: Value =
+        serde_json::from_str(&api::get_vm_memory_usage("api-memory".to_string())).unwrap();
+    let used = usage["usedBytes"].as_u64().unwrap();
+    assert!(used >= 20 * 16);
+    assert!(usage["allocatedBytes"].as_u64().unwrap() >= used);
+    assert_eq!(usage["limitBytes"], 64 * 1024);
+
+    assert!(api::set_vm_memory_limit("api-memory".to_string(), 0));
+    let usage: Value =
+        serde_json::from_str(&api::get_vm_memory_usage("api-memory".to_string())).unwrap();
+    assert!(usage["limitBytes"].is_null());
+    assert_eq!(
+        api::get_vm_memory_usage("missing-vm".to_string()),
+        "\"vm_not_found\""
+    );
+
+    api::destroy_vm("api-memory".to_string());
+}
+
+fn single_function_program(name: &str, body: Value) -> Value {
+    json!({
+      "type": "program",
+      "body": [
+        {
+          "type": "functionDefinition",
+          "data": { "name": name, "params": [], "body": body }
+        }
+      ]
+    })
+}
+
+#[test]
+fn type_mismatch_reports_error_and_leaves_vm_usable() {
+    let program = single_function_program(
+        "broken",
+        json!([
+          {
+            "type": "returnOperation",
+            "data": {
+              "value": {
+                "type": "arithmetic",
+                "data": {
+                  "operation": "-",
+                  "operand1": { "type": "bool", "data": { "value": true } },
+                  "operand2": { "type": "i16", "data": { "value": 1 } }
+                }
+              }
+            }
+          }
+        ]),
+    );
+    let mut vm =
+        VM::compile_and_create_of_ast("vm-mismatch".to_string(), program, 0, vec![]).unwrap();
+    vm.run().unwrap();
+
+    let error = vm.run_func_with_input("broken", None, 0).unwrap_err();
+    assert!(matches!(error, VmError::TypeMismatch(_)));
+    assert_eq!(error.kind(), "typeMismatch");
+    assert!(!vm.is_exec_processing());
+
+    assert_eq!(
+        vm.run_func_with_input("missing", None, 0).unwrap_err(),
+        VmError::UndefinedVariable("missing".to_string())
+    );
+}
+
+#[test]
+fn runaway_recursion_is_reported_as_stack_overflow() {
+    let program = single_function_program(
+        "recurse",
+        json!([
+          {
+            "type": "functionCall",
+            "data": {
+              "callee": { "type": "identifier", "data": { "name": "recurse" } },
+              "args": []
+            }
+          }
+        ]),
+    );
+    let mut vm =
+        VM::compile_and_create_of_ast("vm-recursion".to_string(), program, 0, vec![]).unwrap();
+    vm.run().unwrap();
+
+    let error = vm.run_func_with_input("recurse", None, 0).unwrap_err();
+    assert!(matches!(error, VmError::StackOverflow(_)));
+    assert!(!vm.is_exec_processing());
+}
+
+fn indexed_assignment(index: i64, value: i64) -> Value {
+    json!({
+      "type": "assignment",
+      "data": {
+        "leftSide": {
+          "type": "indexer",
+          "data": {
+            "target": { "type": "identifier", "data": { "name": "items" } },
+            "index": { "type": "i16", "data": { "value": index } }
+          }
+        },
+        "rightSide": { "type": "i16", "data": { "value": value } }
+      }
+    })
+}
+
+#[test]
+fn array_assignment_checks_bounds() {
+    let program = json!({
+      "type": "program",
+      "body": [
+        {
+          "type": "definition",
+          "data": {
+            "leftSide": { "type": "identifier", "data": { "name": "items" } },
+            "rightSide": {
+              "type": "array",
+              "data": { "value": [{ "type": "i16", "data": { "value": 1 } }] }
+            }
+          }
+        },
+        indexed_assignment(0, 7),
+        {
+          "type": "host_call",
+          "data": {
+            "name": "println",
+            "args": [{ "type": "identifier", "data": { "name": "items" } }]
+          }
+        },
+        indexed_assignment(5, 2)
+      ]
+    });
+    let mut vm =
+        VM::compile_and_create_of_ast("vm-bounds".to_string(), program, 0, allowed()).unwrap();
+
+    assert_eq!(vm.run().unwrap().typ(), 253);
+    assert_eq!(host_call_from_paused_vm(&vm)["payload"], "[[7]]");
+    assert_eq!(
+        vm.continue_run("true".to_string()).unwrap_err(),
+        VmError::IndexOutOfBounds { index: 5, len: 1 }
+    );
+}
+
+// wraps raw instructions in a container whose header, tables and checksum
+// are all in order, so only the instructions themselves can be wrong
+fn container_of(code: Vec<u8>) -> Vec<u8> {
+    BytecodeModule {
+        version: FORMAT_VERSION,
+        flags: 0,
+        program: Program {
+            constants: vec![],
+            functions: vec![FunctionEntry {
+                name: "<main>".to_string(),
+                params: vec![],
+                start: 0,
+                end: code.len(),
+                registers: 1,
+                cells: 0,
+                param_slots: vec![],
+                upvalues: vec![],
+                locals: vec![],
+                top_level: false,
+            }],
+            classes: vec![],
+            statements: vec![],
+            code,
+        },
+    }
+    .encode()
+}
+
+#[test]
+fn malformed_bytecode_is_rejected_instead_of_panicking() {
+    let unknown_op = VM::compile_and_create_of_bytecode(
+        "vm-bad-op".to_string(),
+        container_of(vec![0xee]),
+        vec![],
+    );
+    assert_eq!(
+        unknown_op.err(),
+        Some(VmError::BadBytecode(
+            "unknown opcode 0xee at offset 0".to_string()
+        ))
+    );
+
+    // a constant load whose operands run past the end of the program
+    let truncated = VM::compile_and_create_of_bytecode(
+        "vm-truncated".to_string(),
+        container_of(vec![0x01, 0x00, 0x00, 0x00]),
+        vec![],
+    );
+    assert!(matches!(truncated.err(), Some(VmError::BadBytecode(_))));
+}
+
+#[test]
+fn bad_host_reply_aborts_the_paused_run() {
+    let mut vm = VM::compile_and_create_of_ast(
+        "vm-bad-reply".to_string(),
+        println_program("println"),
+        0,
+        allowed(),
+    )
+    .unwrap();
+    assert_eq!(
+        vm.continue_run("true".to_string()).unwrap_err(),
+        VmError::BadHostReply("no host call is pending".to_string())
+    );
+
+    assert_eq!(vm.run().unwrap().typ(), 253);
+    let error = vm.continue_run("{not json".to_string()).unwrap_err();
+    assert_eq!(error.kind(), "badHostReply");
+    assert!(!vm.is_exec_processing());
+    assert!(vm.sending_host_call_data.is_none());
+
+    let ast = println_program("println").to_string();
+    assert!(api::create_vm_from_ast(
+        "api-bad-reply".to_string(),
+        ast,
+        None
+    ));
+    assert!(api::execute_vm("api-bad-reply".to_string()).has_host_call);
+    let result = api::continue_execution("api-bad-reply".to_string(), "{not json".to_string());
+    assert!(!result.has_host_call);
+    assert_eq!(result.error.unwrap().to_json()["kind"], "badHostReply");
+
+    api::destroy_vm("api-bad-reply".to_string());
+}
@@ -0,0 +1,189 @@
+mod common;
+
+use common::{host_call, string, vm_of};
+use elpian_vm::api;
+use elpian_vm::sdk::{compiler, error::VmError, vm::VM};
+use serde_json::{json, Value};
+
+const HOST_APIS: &[&str] = &["println", "fetch"];
+
+// the async calls handed out since the last look, as (callId, payload)
+fn async_calls(vm: &mut VM) -> Vec<(i64, String)> {
+    std::mem::take(&mut vm.sending_async_host_calls)
+        .iter()
+        .map(|call| {
+            let call: Value = serde_json::from_str(call).unwrap();
+            assert_eq!(call["apiName"], "fetch");
+            (
+                call["callId"].as_i64().unwrap(),
+                call["payload"].as_str().unwrap().to_string(),
+            )
+        })
+        .collect()
+}
+
+const FETCH_BOTH: &str = r#"def a = async host.fetch("/a")
+def b = async host.fetch("/b")
+host.println("fired")
+def pages = [await a, await b]
+host.println(pages)
+"#;
+
+#[test]
+fn async_host_calls_run_concurrently_and_resolve_in_any_order() {
+    let mut vm = vm_of("async-both", FETCH_BOTH, HOST_APIS);
+    // both requests are out before the script blocks on anything
+    assert_eq!(vm.run().unwrap().typ(), 253);
+    assert_eq!(host_call(&vm)["payload"], "[\"fired\"]");
+    let calls = async_calls(&mut vm);
+    assert_eq!(calls.len(), 2);
+    assert_eq!(calls[0].1, "[\"/a\"]");
+    assert_eq!(calls[1].1, "[\"/b\"]");
+    let (a, b) = (calls[0].0, calls[1].0);
+
+    assert_eq!(vm.continue_run("true".to_string()).unwrap().typ(), 249);
+    assert!(vm.is_awaiting());
+    // b is kept until the script gets to it, the run still waits on a
+    assert_eq!(vm.resolve_host_call(b, string("B")).unwrap().typ(), 249);
+    assert_eq!(vm.resolve_host_call(a, string("A")).unwrap().typ(), 253);
+    assert_eq!(host_call(&vm)["payload"], "[[\"A\", \"B\"]]");
+    assert!(!vm.is_awaiting());
+    vm.continue_run("true".to_string()).unwrap();
+    assert!(!vm.is_exec_processing());
+
+    // settled calls are gone
+    assert_eq!(
+        vm.resolve_host_call(a, string("again")).unwrap_err(),
+        VmError::BadHostReply(format!("no async host call {} is pending", a))
+    );
+}
+
+#[test]
+fn rejected_promises_throw_where_they_are_awaited() {
+    let code = r#"func load(path) {
+    def page = async host.fetch(path)
+    try {
+        return await page
+    } catch (e) {
+        return "failed: " + e
+    }
+}
+host.println(load("/a"))
+def plain = await 5
+host.println(plain)
+"#;
+    let mut vm = vm_of("async-reject", code, HOST_APIS);
+    assert_eq!(vm.run().unwrap().typ(), 249);
+    let calls = async_calls(&mut vm);
+    assert_eq!(
+        vm.reject_host_call(calls[0].0, string("offline"))
+            .unwrap()
+            .typ(),
+        253
+    );
+    assert_eq!(host_call(&vm)["payload"], "[\"failed: offline\"]");
+    // awaiting a value that is not a promise gives the value
+    assert_eq!(vm.continue_run("true".to_string()).unwrap().typ(), 253);
+    assert_eq!(host_call(&vm)["payload"], "[5]");
+}
+
+#[test]
+fn promises_settled_before_the_await_do_not_block() {
+    let code = r#"def p = async host.fetch("/a")
+host.println(p)
+await p
+host.println(await p)
+"#;
+    let mut vm = vm_of("async-early", code, HOST_APIS);
+    assert_eq!(vm.run().unwrap().typ(), 253);
+    assert_eq!(host_call(&vm)["payload"], "[\"[promise]\"]");
+    let calls = async_calls(&mut vm);
+    // nothing runs, the vm still waits on the println
+    assert_eq!(
+        vm.resolve_host_call(calls[0].0, string("A")).unwrap().typ(),
+        254
+    );
+    assert_eq!(vm.continue_run("true".to_string()).unwrap().typ(), 253);
+    assert_eq!(host_call(&vm)["payload"], "[\"A\"]");
+
+    let errors = compiler::parse_code("def p = async fetch()\n".to_string()).unwrap_err();
+    assert_eq!(
+        errors[0].message,
+        "expected a host call after 'async', found identifier 'fetch'"
+    );
+    assert_eq!(
+        api::disassemble_code("await async host.fetch(1)\n".to_string()),
+        json!({
+            "bytecodeLength": 132,
+            "disassembly": concat!(
+                "0000  05  r0 = global askHostAsync\n",
+                "0007  01  r1 = \"fetch\"\n",
+                "000e  01  r3 = i16 1\n",
+                "0015  0e  r2 = [r3]\n",
+                "001c  17  r4 = call r0(r1, r2)\n",
+                "0025  14  r5 = await r4\n",
+                "002a  19  return\n",
+            )
+        })
+        .to_string()
+    );
+}
+
+#[test]
+fn pending_async_calls_survive_a_snapshot() {
+    let mut vm = vm_of("async-snapshot", FETCH_BOTH, HOST_APIS);
+    vm.run().unwrap();
+    let calls = async_calls(&mut vm);
+    vm.continue_run("true".to_string()).unwrap();
+    vm.resolve_host_call(calls[1].0, string("B")).unwrap();
+    let snapshot = vm.snapshot().unwrap();
+
+    let mut restored = vm_of("async-snapshot-copy", FETCH_BOTH, HOST_APIS);
+    restored.restore(&snapshot).unwrap();
+    assert!(restored.is_awaiting());
+    // only the unsettled call is sent again
+    assert_eq!(
+        async_calls(&mut restored),
+        vec![(calls[0].0, "[\"/a\"]".to_string())]
+    );
+    assert_eq!(
+        restored
+            .resolve_host_call(calls[0].0, string("A"))
+            .unwrap()
+            .typ(),
+        253
+    );
+    assert_eq!(host_call(&restored)["payload"], "[[\"A\", \"B\"]]");
+}
+
+#[test]
+fn api_reports_async_calls_and_settles_them() {
+    let id = "async-api";
+    assert!(api::create_vm_from_code(
+        id.to_string(),
+        "def p = async host.println(1)\ndef v = await p\nhost.println(v)\n".to_string(),
+        None
+    ));
+    let result = api::execute_vm(id.to_string());
+    assert!(result.awaiting);
+    assert!(!result.has_host_call);
+    assert_eq!(result.host_calls.len(), 1);
+    let call: Value = serde_json::from_str(&result.host_calls[0]).unwrap();
+    assert_eq!(call["machineId"], id);
+    let call_id = call["callId"].as_i64().unwrap();
+
+    let result = api::resolve_host_call(id.to_string(), call_id, string("ok"));
+    assert!(!result.awaiting);
+    assert!(result.has_host_call);
+    assert!(result.host_calls.is_empty());
+    assert!(
+        api::resolve_host_call(id.to_string(), call_id, string("ok"))
+            .error
+            .is_some()
+    );
+    assert_eq!(
+        api::reject_host_call("async-missing".to_string(), 1, string("x")).result_value,
+        "\"vm_not_found\""
+    );
+    api::destroy_vm(id.to_string());
+}
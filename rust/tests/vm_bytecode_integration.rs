@@ -0,0 +1,326 @@
+use std::ffi::CString;
+
+use elpian_vm::api::{self, ffi};
+use elpian_vm::sdk::{
+    bytecode::{BytecodeModule, Constant, FunctionEntry, Program, Slot, FORMAT_VERSION},
+    compiler,
+    error::VmError,
+    vm::VM,
+};
+use serde_json::json;
+
+const PROGRAM: &str = r#"
+    func greet(name) {
+        return "hi " + name
+    }
+    host.println(greet("Ada"))
+"#;
+
+fn packaged() -> BytecodeModule {
+    BytecodeModule::new(compiler::compile_code(PROGRAM.to_string()).unwrap()).unwrap()
+}
+
+fn decode_error(bytes: &[u8]) -> String {
+    match BytecodeModule::decode(bytes) {
+        Err(VmError::BadBytecode(detail)) => detail,
+        other => panic!("expected a bad bytecode error, got {:?}", other),
+    }
+}
+
+// a program of just a main body around `code`, with a frame of two
+// registers
+fn program_of(code: Vec<u8>) -> Vec<u8> {
+    Program {
+        constants: vec![Constant::I16(7)],
+        functions: vec![FunctionEntry {
+            name: "<main>".to_string(),
+            params: vec![],
+            start: 0,
+            end: code.len(),
+            registers: 2,
+            cells: 0,
+            param_slots: vec![],
+            upvalues: vec![],
+            locals: vec![],
+            top_level: false,
+        }],
+        classes: vec![],
+        statements: vec![],
+        code,
+    }
+    .encode()
+}
+
+fn verify_error(code: Vec<u8>) -> Option<VmError> {
+    BytecodeModule::new(program_of(code)).err()
+}
+
+#[test]
+fn containers_round_trip_into_a_running_vm() {
+    let module = packaged();
+    assert_eq!(module.version, FORMAT_VERSION);
+    assert_eq!(
+        module.program.constants,
+        ["hi ", "greet", "askHost", "println", "Ada"].map(|text| Constant::Str(text.to_string()))
+    );
+    let greet = &module.program.functions[1];
+    assert_eq!(module.program.functions.len(), 2);
+    assert_eq!(greet.name, "greet");
+    assert_eq!(greet.params, vec!["name".to_string()]);
+    assert_eq!(greet.param_slots, vec![Slot::Register(0)]);
+    assert!(greet.top_level);
+
+    let bytes = module.encode();
+    assert_eq!(&bytes[..4], b"ELPB");
+    assert_eq!(BytecodeModule::decode(&bytes).unwrap(), module);
+
+    let mut vm =
+        VM::compile_and_create_of_bytecode("bc-run".to_string(), bytes, vec!["println".into()])
+            .unwrap();
+    assert_eq!(vm.run().unwrap().typ(), 253);
+    assert!(vm
+        .sending_host_call_data
+        .as_ref()
+        .unwrap()
+        .contains("hi Ada"));
+}
+
+#[test]
+fn damaged_containers_are_rejected() {
+    let bytes = packaged().encode();
+
+    assert_eq!(
+        decode_error(b"\x0e\x0b"),
+        "missing the bytecode magic number"
+    );
+    // every truncation fails cleanly
+    for len in 0..bytes.len() {
+        assert!(
+            BytecodeModule::decode(&bytes[..len]).is_err(),
+            "len {}",
+            len
+        );
+    }
+
+    let mut flipped = bytes.clone();
+    let last_code_byte = bytes.len() - 5;
+    flipped[last_code_byte] ^= 0x01;
+    assert_eq!(decode_error(&flipped), "checksum mismatch");
+
+    let mut future = packaged();
+    future.version = FORMAT_VERSION + 1;
+    assert_eq!(
+        decode_error(&future.encode()),
+        format!(
+            "format version {} is not supported, expected {}",
+            FORMAT_VERSION + 1,
+            FORMAT_VERSION
+        )
+    );
+
+    let mut flagged = packaged();
+    flagged.flags = 0x8000;
+    assert_eq!(decode_error(&flagged.encode()), "unknown flags 0x8000");
+
+    let mut shrunk = packaged();
+    shrunk.program.functions[1].registers = 2;
+    assert_eq!(
+        decode_error(&shrunk.encode()),
+        "register 2 at offset 12 is outside the frame of greet"
+    );
+
+    let mut missing = packaged();
+    missing.program.constants.pop();
+    assert_eq!(
+        decode_error(&missing.encode()),
+        "constant 4 at offset 56 is outside the constant pool"
+    );
+}
+
+#[test]
+fn verifier_checks_jump_targets() {
+    // `jump @3` lands inside its own operand
+    assert_eq!(
+        verify_error(vec![0x15, 0, 0, 0, 3]),
+        Some(VmError::BadBytecode(
+            "address 3 at offset 1 does not start an instruction".to_string()
+        ))
+    );
+    assert_eq!(
+        verify_error(vec![0x15, 0, 0, 0, 100]),
+        Some(VmError::BadBytecode(
+            "address 100 at offset 1 is outside the program".to_string()
+        ))
+    );
+
+    // jumping back to the start is fine
+    assert_eq!(verify_error(vec![0x00, 0x15, 0, 0, 0, 0]), None);
+}
+
+#[test]
+fn verifier_checks_operands_and_flow() {
+    // `r5 = null` names a register outside the frame
+    assert_eq!(
+        verify_error(vec![0x02, 0, 5, 0x19]),
+        Some(VmError::BadBytecode(
+            "register 5 at offset 0 is outside the frame of <main>".to_string()
+        ))
+    );
+    // `r0 = const 1` when the pool has one constant
+    assert_eq!(
+        verify_error(vec![0x01, 0, 0, 0, 0, 0, 1, 0x19]),
+        Some(VmError::BadBytecode(
+            "constant 1 at offset 0 is outside the constant pool".to_string()
+        ))
+    );
+    // `r0 = global <i16 7>`
+    assert_eq!(
+        verify_error(vec![0x05, 0, 0, 0, 0, 0, 0, 0x19]),
+        Some(VmError::BadBytecode(
+            "constant 0 at offset 0 is not a string".to_string()
+        ))
+    );
+    // a body must not run off its end
+    assert_eq!(
+        verify_error(vec![0x02, 0, 0]),
+        Some(VmError::BadBytecode(
+            "instruction at offset 0 runs past the end of <main>".to_string()
+        ))
+    );
+    assert_eq!(verify_error(vec![0x02, 0, 0, 0x19]), None);
+}
+
+#[test]
+fn raw_instructions_without_a_container_are_rejected() {
+    let code = compiler::compile_code(PROGRAM.to_string()).unwrap();
+    assert_eq!(
+        VM::compile_and_create_of_bytecode("bc-raw".to_string(), code, vec![]).err(),
+        Some(VmError::BadBytecode(
+            "missing the bytecode magic number".to_string()
+        ))
+    );
+}
+
+#[test]
+fn api_loads_precompiled_bytecode() {
+    let bytecode = api::compile_to_bytecode(PROGRAM.to_string()).unwrap();
+    assert!(api::compile_to_bytecode("def = 1".to_string()).is_none());
+
+    assert!(api::create_vm_from_bytecode(
+        "bc-api".to_string(),
+        bytecode.clone(),
+        Some(vec!["println".to_string()]),
+    ));
+    let result = api::execute_vm("bc-api".to_string());
+    assert!(result.has_host_call);
+    assert!(result.host_call_data.contains("hi Ada"));
+    api::destroy_vm("bc-api".to_string());
+
+    assert!(!api::create_vm_from_bytecode(
+        "bc-api-bad".to_string(),
+        bytecode[..bytecode.len() - 1].to_vec(),
+        None,
+    ));
+    assert!(!api::vm_exists("bc-api-bad".to_string()));
+
+    let ast = r#"{"type": "program", "body": [
+        {"type": "host_call", "data": {"name": "println", "args": []}}
+    ]}"#;
+    let from_ast = api::compile_ast_to_bytecode(ast.to_string()).unwrap();
+    assert!(api::create_vm_from_bytecode(
+        "bc-api-ast".to_string(),
+        from_ast,
+        None
+    ));
+    assert!(api::execute_vm("bc-api-ast".to_string()).has_host_call);
+    api::destroy_vm("bc-api-ast".to_string());
+}
+
+#[test]
+fn malformed_asts_are_refused_instead_of_panicking() {
+    let int = |value: i64| json!({ "type": "i16", "data": { "value": value } });
+    let ast = json!({ "type": "program", "body": [
+        { "type": "host_call", "data": { "name": "println", "args": [
+            { "type": "arithmetic", "data": { "operation": "%%", "operand1": int(1), "operand2": int(2) } },
+            { "type": "mystery", "data": {} },
+        ] } },
+        { "type": "definition", "data": {
+            "leftSide": { "type": "identifier", "data": { "name": "x" } },
+            "rightSide": int(70_000),
+        } },
+        { "type": "whileStmt", "data": {
+            "condition": { "type": "bool", "data": { "value": true } },
+            "body": [{ "type": "functionCall", "data": { "callee": { "type": "identifier", "data": {} }, "args": 3 } }],
+        } },
+    ] });
+    let messages: Vec<String> = compiler::compile_ast(ast.clone(), 0)
+        .unwrap_err()
+        .into_iter()
+        .map(|error| error.message)
+        .collect();
+    assert_eq!(
+        messages,
+        vec![
+            "body[0]: unknown operator '%%'",
+            "body[0]: unknown expression type 'mystery'",
+            "body[1]: 70000 does not fit in an i16",
+            "body[2].data.body[0]: expected a string for an identifier name, found nothing",
+            "body[2].data.body[0]: expected an array for the arguments of a call, found a number",
+        ]
+    );
+    let messages: Vec<String> = compiler::compile_ast(json!({ "type": "program" }), 0)
+        .unwrap_err()
+        .into_iter()
+        .map(|error| error.message)
+        .collect();
+    assert_eq!(
+        messages,
+        vec!["expected an array for a block body, found nothing"]
+    );
+
+    // the api and the ffi report the failure instead of aborting the host
+    let ast = ast.to_string();
+    assert_eq!(api::compile_ast_to_bytecode(ast.clone()), None);
+    assert!(!api::validate_ast(ast.clone()));
+    assert!(!api::create_vm_from_ast(
+        "bc-bad-ast".to_string(),
+        ast.clone(),
+        None
+    ));
+    assert!(!api::vm_exists("bc-bad-ast".to_string()));
+    let ast = CString::new(ast).unwrap();
+    let mut len = 7usize;
+    assert!(ffi::elpian_compile_ast_to_bytecode(ast.as_ptr(), &mut len).is_null());
+    assert_eq!(len, 0);
+}
+
+#[test]
+fn ffi_hands_out_and_loads_bytecode_buffers() {
+    let code = CString::new(PROGRAM).unwrap();
+    let mut len = 0usize;
+    let buf = ffi::elpian_compile_to_bytecode(code.as_ptr(), &mut len);
+    assert!(!buf.is_null());
+    assert_eq!(
+        unsafe { std::slice::from_raw_parts(buf, len) },
+        api::compile_to_bytecode(PROGRAM.to_string())
+            .unwrap()
+            .as_slice()
+    );
+
+    let machine_id = CString::new("bc-ffi").unwrap();
+    assert_eq!(
+        ffi::elpian_create_vm_from_bytecode(machine_id.as_ptr(), buf, len),
+        1
+    );
+    assert_eq!(
+        ffi::elpian_create_vm_from_bytecode(machine_id.as_ptr(), buf, len - 1),
+        0
+    );
+    ffi::elpian_free_bytes(buf, len);
+    ffi::elpian_destroy_vm(machine_id.as_ptr());
+
+    let broken = CString::new("def = 1").unwrap();
+    let mut len = 7usize;
+    assert!(ffi::elpian_compile_to_bytecode(broken.as_ptr(), &mut len).is_null());
+    assert_eq!(len, 0);
+}
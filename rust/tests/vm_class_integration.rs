@@ -0,0 +1,205 @@
+mod common;
+
+use std::collections::HashMap;
+
+use common::{errors_of, host_call, printed, vm_of};
+use elpian_vm::sdk::{error::VmError, vm::VM};
+use serde_json::Value;
+
+const HOST_APIS: &[&str] = &["println"];
+
+const SHAPES: &str = r#"class Shape {
+    name = "shape"
+    tags = []
+    func area() {
+        return 0
+    }
+    func describe() {
+        return this.name + ":" + (this.area() as string)
+    }
+}
+class Rect extends Shape {
+    w = 1
+    h = 1
+    func init(w, h) {
+        this.name = "rect"
+        this.w = w
+        this.h = h
+    }
+    func area() {
+        return this.w * this.h
+    }
+}
+class Square extends Rect {
+    func init(side) {
+        super.init(side, side)
+        this.name = "square"
+    }
+    func describe() {
+        return "[" + super.describe() + "]"
+    }
+}
+"#;
+
+#[test]
+fn instances_start_from_their_field_defaults() {
+    let code = format!(
+        "{}{}",
+        SHAPES,
+        r#"def a = new Shape()
+def b = new Shape()
+Array.push(a.tags, 1)
+a.name = "first"
+host.println(a.name, b.name, a.tags, b.tags)
+"#
+    );
+    let mut vm = vm_of("class-fields", &code, HOST_APIS);
+    assert_eq!(printed(&mut vm), vec!["[\"first\", \"shape\", [1], []]"]);
+}
+
+#[test]
+fn methods_see_the_instance_as_this() {
+    let code = format!(
+        "{}{}",
+        SHAPES,
+        r#"def r = new Rect(2, 3)
+host.println(r.w, r.h, r.name, r.tags, r.area(), r.describe())
+"#
+    );
+    let mut vm = vm_of("class-methods", &code, HOST_APIS);
+    assert_eq!(
+        printed(&mut vm),
+        vec!["[2, 3, \"rect\", [], 6, \"rect:6\"]"]
+    );
+}
+
+#[test]
+fn subclasses_override_and_call_super() {
+    let code = format!(
+        "{}{}",
+        SHAPES,
+        r#"def s = new Square(4)
+host.println(s.w, s.area(), s.describe())
+"#
+    );
+    let mut vm = vm_of("class-super", &code, HOST_APIS);
+    assert_eq!(printed(&mut vm), vec!["[4, 16, \"[square:16]\"]"]);
+}
+
+#[test]
+fn is_checks_the_class_and_its_parents() {
+    let code = format!(
+        "{}{}",
+        SHAPES,
+        r#"def s = new Square(1)
+def r = new Rect(1, 1)
+host.println(s is Square, s is Rect, s is Shape, r is Square)
+host.println({} is Shape, 1 is Shape, r is Unknown)
+"#
+    );
+    let mut vm = vm_of("class-is", &code, HOST_APIS);
+    assert_eq!(
+        printed(&mut vm),
+        vec!["[true, true, true, false]", "[false, false, false]"]
+    );
+}
+
+#[test]
+fn instances_carry_their_class_type() {
+    let code = "class Point {\n    x = 1\n}\nhost.println(new Point(), {})\n";
+    let mut vm = vm_of("class-typ", code, HOST_APIS);
+    assert_eq!(vm.run().unwrap().typ(), 253);
+    let items = &host_call(&vm)["input"]["data"]["value"];
+    assert_eq!(items[0]["data"]["typ"], 1);
+    assert_eq!(items[0]["data"]["value"]["x"]["data"]["value"], 1);
+    assert_eq!(items[1]["data"]["typ"], Value::Null);
+}
+
+#[test]
+fn properties_and_plain_objects_are_still_called() {
+    let code = r#"class Box {
+    open = 0
+    func close() {
+        return "method"
+    }
+}
+func hello() {
+    return "hello"
+}
+def plain = { greet: hello }
+def b = new Box()
+b.open = hello
+b.close = hello
+host.println(plain.greet(), b.open(), b.close())
+"#;
+    let mut vm = vm_of("class-fallback", code, HOST_APIS);
+    assert_eq!(printed(&mut vm), vec!["[\"hello\", \"hello\", \"hello\"]"]);
+    let mut vm = vm_of("class-missing", "def p = {}\np.nothing()\n", HOST_APIS);
+    assert!(matches!(vm.run(), Err(VmError::TypeMismatch(_))));
+}
+
+#[test]
+fn class_errors_are_reported_at_compile_time() {
+    assert_eq!(
+        errors_of("func f() {\n    return this\n}\nsuper.f()\n"),
+        vec![
+            "2:12: 'this' is only allowed in methods",
+            "4:1: 'super' is only allowed in methods of a class that extends another",
+        ]
+    );
+    assert_eq!(
+        errors_of("class A {\n    x = 1\n    func x() {\n    }\n}\n"),
+        vec!["3:5: 'x' is declared twice in class 'A'"]
+    );
+    assert_eq!(
+        errors_of("class B extends A {\n}\nclass A {\n}\n"),
+        vec!["1:1: class 'B' extends 'A', which is not a class declared before it"]
+    );
+    assert_eq!(
+        errors_of("if true {\n    class A {\n    }\n}\n"),
+        vec!["2:5: 'class' is only allowed at the top level"]
+    );
+}
+
+#[test]
+fn classes_are_imported_from_modules() {
+    let shapes = SHAPES.replace("class ", "export class ");
+    let code = r#"import { Rect, Square as Sq } from "shapes"
+class Cube extends Sq {
+    func volume() {
+        return this.area() * this.w
+    }
+}
+def c = new Cube(3)
+host.println(c.volume(), c is Rect, c.describe())
+"#;
+    let mut vm = VM::compile_and_create_of_modules(
+        "class-modules".to_string(),
+        code.to_string(),
+        HashMap::from([("shapes".to_string(), shapes)]),
+        vec!["println".to_string()],
+    )
+    .unwrap();
+    assert_eq!(printed(&mut vm), vec!["[27, true, \"[square:9]\"]"]);
+}
+
+#[test]
+fn blueprints_survive_a_snapshot() {
+    let code = format!(
+        "{}{}",
+        SHAPES,
+        "def r = new Rect(2, 5)\nhost.println(r.area())\ndef s = new Square(3)\nhost.println(s.describe(), s is Shape)\n"
+    );
+    let mut vm = vm_of("class-snapshot", &code, HOST_APIS);
+    assert_eq!(vm.run().unwrap().typ(), 253);
+    let snapshot = vm.snapshot().unwrap();
+
+    let mut restored = vm_of("class-snapshot-copy", &code, HOST_APIS);
+    restored.restore(&snapshot).unwrap();
+    assert_eq!(host_call(&restored)["payload"], "[10]");
+    assert_eq!(
+        restored.continue_run("true".to_string()).unwrap().typ(),
+        253
+    );
+    assert_eq!(host_call(&restored)["payload"], "[\"[square:9]\", true]");
+}
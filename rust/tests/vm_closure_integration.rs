@@ -0,0 +1,137 @@
+mod common;
+
+use common::{call, host_call, vm_of};
+use elpian_vm::api;
+use serde_json::json;
+
+const HOST_APIS: &[&str] = &["println", "dom.addEventListener"];
+
+const COUNTERS: &str = r#"func counter(start) {
+    def count = start
+    func next(step) {
+        count = count + step
+        return count
+    }
+    return next
+}
+def a = counter(10)
+def b = counter(100)
+host.println(a(1), a(1), b(5), a(1))
+"#;
+
+#[test]
+fn inner_functions_keep_the_locals_of_their_defining_call() {
+    let mut vm = vm_of("closure-counter", COUNTERS, HOST_APIS);
+    assert_eq!(vm.run().unwrap().typ(), 253);
+    // a and b each keep their own count after counter has returned
+    assert_eq!(host_call(&vm)["payload"], "[11, 12, 105, 13]");
+    vm.continue_run("true".to_string()).unwrap();
+}
+
+#[test]
+fn higher_order_functions_see_their_own_scope_not_the_callers() {
+    let code = r#"def factor = 1000
+func apply(f, v) {
+    def factor = 2
+    return f(v)
+}
+func make_adder(n) {
+    func add(v) {
+        return v + n
+    }
+    return add
+}
+func scale(v) {
+    return v * factor
+}
+host.println(apply(make_adder(5), 1), apply(scale, 3))
+"#;
+    let mut vm = vm_of("closure-higher-order", code, HOST_APIS);
+    assert_eq!(vm.run().unwrap().typ(), 253);
+    // scale reads the global factor, not the local one of apply
+    assert_eq!(host_call(&vm)["payload"], "[6, 3000]");
+}
+
+const LISTENER: &str = r#"func setup(label) {
+    def clicks = 0
+    func on_click(n) {
+        clicks = clicks + n
+        return [label, clicks]
+    }
+    host.dom.addEventListener("click", on_click)
+}
+setup("ok")
+"#;
+
+#[test]
+fn callbacks_handed_to_the_host_are_called_with_their_scope() {
+    let mut vm = vm_of("closure-listener", LISTENER, HOST_APIS);
+    assert_eq!(vm.run().unwrap().typ(), 253);
+    assert_eq!(host_call(&vm)["payload"], "[\"click\", \"on_click\"]");
+    vm.continue_run("true".to_string()).unwrap();
+
+    // on_click is not a global, the host calls it back by name
+    assert_eq!(call(&mut vm, "on_click", 2), "[\"ok\", 2]");
+    assert_eq!(call(&mut vm, "on_click", 3), "[\"ok\", 5]");
+
+    // the captured scope travels with a snapshot
+    let snapshot = vm.snapshot().unwrap();
+    let mut restored = vm_of("closure-listener-copy", LISTENER, HOST_APIS);
+    restored.restore(&snapshot).unwrap();
+    assert_eq!(call(&mut restored, "on_click", 1), "[\"ok\", 6]");
+    assert_eq!(call(&mut vm, "on_click", 1), "[\"ok\", 6]");
+}
+
+#[test]
+fn closures_keep_working_after_a_reload() {
+    let mut vm = vm_of("closure-reload", COUNTERS, HOST_APIS);
+    vm.run().unwrap();
+    vm.continue_run("true".to_string()).unwrap();
+    assert_eq!(call(&mut vm, "a", 0), "13");
+
+    let changed = COUNTERS.replace("count + step", "count + step * 10");
+    vm.reload_code(changed).unwrap();
+    assert_eq!(call(&mut vm, "a", 1), "23");
+    assert_eq!(call(&mut vm, "b", 1), "115");
+}
+
+#[test]
+fn api_calls_a_host_callback_by_name() {
+    let id = "closure-api";
+    assert!(api::create_vm_from_code(
+        id.to_string(),
+        LISTENER.to_string(),
+        None
+    ));
+    assert!(api::execute_vm(id.to_string()).has_host_call);
+    api::continue_execution(id.to_string(), "true".to_string());
+    let result = api::execute_vm_func_with_input(
+        id.to_string(),
+        "on_click".to_string(),
+        json!({ "type": "i64", "data": { "value": 4 } }).to_string(),
+        0,
+    );
+    assert_eq!(result.result_value, "[\"ok\", 4]");
+    api::destroy_vm(id.to_string());
+}
+
+#[test]
+fn every_loop_iteration_captures_its_own_variable() {
+    let code = r#"def getters = []
+def i = 0
+loop i < 3 {
+    def j = i * 10
+    func get() {
+        return j
+    }
+    getters = getters + get
+    i = i + 1
+}
+def first = getters[0]
+def last = getters[2]
+host.println(first(), last())
+"#;
+    let mut vm = vm_of("closure-loop", code, HOST_APIS);
+    assert_eq!(vm.run().unwrap().typ(), 253);
+    assert_eq!(host_call(&vm)["payload"], "[0, 20]");
+}
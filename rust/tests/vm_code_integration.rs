@@ -0,0 +1,490 @@
+use elpian_vm::api;
+use elpian_vm::sdk::{
+    bytecode::Program,
+    compiler, disassembler,
+    error::{SyntaxError, VmError},
+    vm::VM,
+};
+use serde_json::{json, Value};
+
+fn run_code(machine_id: &str, code: &str) -> Vec<String> {
+    let mut vm = VM::compile_and_create_of_code(
+        machine_id.to_string(),
+        code.to_string(),
+        0,
+        vec!["println".to_string()],
+    )
+    .expect("code should compile");
+    let mut payloads = vec![];
+    let mut result = vm.run().expect("vm run should not fail");
+    while result.typ() == 253 {
+        let raw = vm.sending_host_call_data.clone().unwrap();
+        let call: Value = serde_json::from_str(&raw).unwrap();
+        payloads.push(call["payload"].as_str().unwrap().to_string());
+        result = vm.continue_run("true".to_string()).unwrap();
+    }
+    payloads
+}
+
+fn syntax_errors(code: &str) -> Vec<SyntaxError> {
+    compiler::parse_code(code.to_string()).expect_err("code should not parse")
+}
+
+#[test]
+fn long_programs_are_not_truncated() {
+    let mut code = "def total = 0\n".to_string();
+    for _ in 0..120 {
+        code.push_str("total = total + 1\n");
+    }
+    code.push_str("host.println(total)\n");
+
+    assert_eq!(run_code("code-long", &code), vec!["[120]"]);
+}
+
+#[test]
+fn multi_character_operators_are_single_tokens() {
+    let code = r#"
+        func compare(n) {
+            if n == 5 { return "eq" }
+            else if n >= 10 { return "ge" }
+            else if n <= 0 { return "le" }
+            else if n != 7 { return "ne" }
+            return "seven"
+        }
+    "#;
+    let mut vm =
+        VM::compile_and_create_of_code("code-ops".to_string(), code.to_string(), 0, vec![])
+            .unwrap();
+    vm.run().unwrap();
+
+    for (input, expected) in [(5, "eq"), (12, "ge"), (-3, "le"), (6, "ne"), (7, "seven")] {
+        let input = json!({ "type": "i16", "data": { "value": input } }).to_string();
+        let result = vm.run_func_with_input("compare", Some(&input), 0).unwrap();
+        assert_eq!(result.stringify(), format!("\"{}\"", expected));
+    }
+}
+
+#[test]
+fn parsed_program_runs_every_construct() {
+    let code = r#"
+        // names a small count
+        func describe(n) {
+            switch n {
+                case 1 { return "one" }
+                case 2 { return "two" }
+            }
+            return "many"
+        }
+
+        def user = { name: "Ada", "age": 36 }
+        def scores = [1, 2, 3];
+        scores[1] = scores[1] * 10
+        def i = 0; def total = 0
+        loop i < 3 {
+            total = total + scores[i]
+            i = i + 1
+        }
+        /* 1 + 20 + 3 */
+        if !(total == 24) {
+            host.println("unexpected")
+        } else if total >= 24 {
+            host.println(user.name + " scored " + (total as string) + ", " + describe(2))
+        } else {
+            host.println("low")
+        }
+        host.println(-2 * 3 + 10 / 2, "tab\there")
+    "#;
+
+    assert_eq!(
+        run_code("code-all", code),
+        vec![
+            "[\"Ada scored 24, two\"]".to_string(),
+            "[-1, \"tab\\there\"]".to_string(),
+        ]
+    );
+}
+
+#[test]
+fn syntax_errors_are_reported_with_positions() {
+    let errors = syntax_errors("def a = 1\ndef = 2\na + 1\nhost.println(a\ndef b = [1, 2");
+
+    let found: Vec<(usize, usize)> = errors
+        .iter()
+        .map(|e| (e.span.line, e.span.column))
+        .collect();
+    assert_eq!(found, vec![(2, 5), (3, 1), (5, 1), (5, 14)]);
+    assert_eq!(
+        errors[0].message,
+        "expected a variable name after 'def', found '='"
+    );
+    assert_eq!(
+        errors[1].to_string(),
+        "3:1: expected a statement, this expression is never used"
+    );
+    assert_eq!(
+        errors[3].message,
+        "expected ']' to close the array, found end of input"
+    );
+}
+
+#[test]
+fn lexer_errors_are_reported_with_positions() {
+    let errors =
+        syntax_errors("def a = 1\ndef b = \"open\ndef c = 2 # 3\ndef d = 99999999999999999999");
+
+    let found: Vec<(usize, usize, &str)> = errors
+        .iter()
+        .map(|e| (e.span.line, e.span.column, e.message.as_str()))
+        .collect();
+    assert_eq!(
+        found,
+        vec![
+            (2, 9, "unterminated string literal"),
+            (3, 11, "unexpected character '#'"),
+            (4, 9, "integer literal 99999999999999999999 is out of range"),
+        ]
+    );
+}
+
+#[test]
+fn deeply_nested_input_is_rejected_instead_of_overflowing() {
+    let code = format!("def a = {}1{}", "(".repeat(10_000), ")".repeat(10_000));
+    let errors = syntax_errors(&code);
+
+    assert!(errors[0].message.starts_with("nesting is deeper than"));
+}
+
+#[test]
+fn api_rejects_code_with_syntax_errors() {
+    assert!(!api::create_vm_from_code(
+        "code-api-bad".to_string(),
+        "def = 1".to_string(),
+        None,
+    ));
+    assert!(!api::vm_exists("code-api-bad".to_string()));
+
+    let report: Value =
+        serde_json::from_str(&api::disassemble_code("def x = \n}".to_string())).unwrap();
+    assert_eq!(report["errors"][0]["line"], 2);
+    assert_eq!(report["errors"][0]["column"], 1);
+
+    assert!(api::create_vm_from_code(
+        "code-api-good".to_string(),
+        "host.println(\"hi\")".to_string(),
+        None,
+    ));
+    assert!(api::execute_vm("code-api-good".to_string()).has_host_call);
+    api::destroy_vm("code-api-good".to_string());
+}
+
+#[test]
+fn runtime_errors_carry_a_script_stack_trace() {
+    let code = r#"func inner(v) {
+    def a = 1
+    if v > 0 {
+        return v - true
+    }
+    return a
+}
+func outer(v) {
+    def r = inner(v)
+    return r
+}
+host.println(outer(3))
+"#;
+    let mut vm = VM::compile_and_create_of_code(
+        "code-trace".to_string(),
+        code.to_string(),
+        0,
+        vec!["println".to_string()],
+    )
+    .unwrap();
+    assert!(matches!(vm.run(), Err(VmError::TypeMismatch(_))));
+
+    let frames: Vec<String> = vm.last_stack_trace.iter().map(|f| f.to_string()).collect();
+    assert_eq!(
+        frames,
+        vec!["at inner (4:9)", "at outer (9:5)", "at <main> (12:1)"]
+    );
+    assert_eq!(
+        vm.last_stack_trace[0].path.as_deref(),
+        Some("body[0].data.body[1].data.body[0]")
+    );
+
+    // entering through a function leaves the globals out of the trace
+    let input = json!({ "type": "i16", "data": { "value": 1 } }).to_string();
+    assert!(vm.run_func_with_input("outer", Some(&input), 0).is_err());
+    let frames: Vec<String> = vm.last_stack_trace.iter().map(|f| f.to_string()).collect();
+    assert_eq!(frames, vec!["at inner (4:9)", "at outer (9:5)"]);
+}
+
+#[test]
+fn source_map_points_into_ast_without_spans() {
+    let ast = json!({
+        "type": "program",
+        "body": [
+            { "type": "definition", "data": {
+                "leftSide": { "type": "identifier", "data": { "name": "a" } },
+                "rightSide": { "type": "i16", "data": { "value": 1 } }
+            }},
+            { "type": "functionDefinition", "data": {
+                "name": "f",
+                "params": [],
+                "body": [
+                    { "type": "returnOperation", "data": {
+                        "value": { "type": "identifier", "data": { "name": "a" } }
+                    }}
+                ]
+            }}
+        ]
+    });
+    let (byte_code, map) = compiler::compile_ast_with_source_map(ast.clone(), 0).unwrap();
+    assert_eq!(byte_code, compiler::compile_ast(ast, 0).unwrap());
+
+    let program = Program::decode(&byte_code).unwrap();
+    let ret = map.lookup(program.functions[1].start).unwrap();
+    assert_eq!(ret.path, "body[1].data.body[0]");
+    assert_eq!(ret.span, None);
+    assert_eq!(map.lookup(0).unwrap().path, "body[0]");
+    assert!(map.lookup(program.code.len()).is_none());
+}
+
+#[test]
+fn api_results_include_the_stack_trace() {
+    assert!(api::create_vm_from_code(
+        "code-api-trace".to_string(),
+        "func boom() {\n  def x = !3\n}\nboom()".to_string(),
+        None,
+    ));
+    let result = api::execute_vm("code-api-trace".to_string());
+    assert!(result.error.is_some());
+    let names: Vec<&str> = result
+        .stack_trace
+        .iter()
+        .map(|f| f.function.as_str())
+        .collect();
+    assert_eq!(names, vec!["boom", "<main>"]);
+    assert_eq!(result.stack_trace[0].to_json()["line"], 2);
+    api::destroy_vm("code-api-trace".to_string());
+}
+
+#[test]
+fn disassembler_lists_nested_bodies_with_offsets() {
+    let code = r#"
+        def total = 0
+        loop total < 3 {
+            total = total + 1
+        }
+        func pick(n) {
+            switch n {
+                case 1 { return [n, "one"] }
+            }
+            return !true
+        }
+    "#;
+    let program = compiler::compile_code(code.to_string()).unwrap();
+
+    assert_eq!(
+        disassembler::disassemble(&program).unwrap(),
+        [
+            "0000  01  r0 = i16 0",
+            "0007  06  def global total = r0",
+            "000e  05  r0 = global total",
+            "0015  01  r1 = i16 3",
+            "001c  23  r2 = r0 < r1",
+            "0023  16  jump @004b unless r2",
+            "002a  05  r3 = global total",
+            "0031  01  r4 = i16 1",
+            "0038  26  r5 = r3 + r4",
+            "003f  07  global total = r5",
+            "0046  15  jump @000e",
+            "004b  15  jump @0092",
+            "0050      func pick(n): 7 registers, 0 cells",
+            "0050  04    r1 = r0",
+            "0055  01    r2 = i16 1",
+            "005c  20    r3 = r1 == r2",
+            "0063  16    jump @0085 unless r3",
+            "006a  04    r4 = r0",
+            "006f  01    r5 = \"one\"",
+            "0076  0e    r6 = [r4, r5]",
+            "007d  18    return r6",
+            "0080  15    jump @0085",
+            "0085  03    r1 = true",
+            "0089  12    r2 = !r1",
+            "008e  18    return r2",
+            "0091  19    return",
+            "0092  0d  r0 = func pick",
+            "0097  06  def global pick = r0",
+            "009e  19  return",
+            "",
+        ]
+        .join("\n")
+    );
+}
+
+#[test]
+fn disassembler_rejects_malformed_bytecode() {
+    // one constant, a string whose length runs past the end
+    let truncated = disassembler::disassemble(&[0, 0, 0, 1, 7, 0, 0, 0, 0x40, b'x']);
+    assert_eq!(
+        truncated,
+        Err(VmError::BadBytecode(
+            "bytecode is truncated at offset 9".to_string()
+        ))
+    );
+    let mut program =
+        Program::decode(&compiler::compile_code("def a = 1".to_string()).unwrap()).unwrap();
+    program.code.insert(0, 0xee);
+    program.functions[0].end += 1;
+    assert!(matches!(
+        disassembler::disassemble(&program.encode()),
+        Err(VmError::BadBytecode(detail)) if detail == "unknown opcode 0xee at offset 0"
+    ));
+}
+
+#[test]
+fn api_disassembles_code_and_loaded_vms() {
+    let code = "host.println(\"hi\")";
+    let listing: Value = serde_json::from_str(&api::disassemble_code(code.to_string())).unwrap();
+    assert_eq!(
+        listing["disassembly"],
+        [
+            "0000  05  r0 = global askHost",
+            "0007  01  r1 = \"println\"",
+            "000e  01  r3 = \"hi\"",
+            "0015  0e  r2 = [r3]",
+            "001c  17  r4 = call r0(r1, r2)",
+            "0025  19  return",
+            "",
+        ]
+        .join("\n")
+    );
+
+    assert!(api::create_vm_from_code(
+        "code-api-disasm".to_string(),
+        code.to_string(),
+        None,
+    ));
+    assert_eq!(
+        api::disassemble_vm("code-api-disasm".to_string()),
+        listing.to_string()
+    );
+    assert_eq!(
+        api::disassemble_vm("code-api-missing".to_string()),
+        "\"vm_not_found\""
+    );
+    api::destroy_vm("code-api-disasm".to_string());
+}
+
+#[test]
+fn rerunning_main_executes_the_program_once() {
+    let code = "func one() { return 1 }\ndef a = one()\nhost.println(a)\n";
+    let mut vm = VM::compile_and_create_of_code(
+        "code-rerun".to_string(),
+        code.to_string(),
+        0,
+        vec!["println".to_string()],
+    )
+    .unwrap();
+    for _ in 0..2 {
+        let mut calls = 0;
+        let mut result = vm.run().unwrap();
+        while result.typ() == 253 {
+            calls += 1;
+            result = vm.continue_run("true".to_string()).unwrap();
+        }
+        assert_eq!(calls, 1);
+    }
+}
+
+#[test]
+fn host_answers_land_in_the_middle_of_an_expression() {
+    let code = r#"func twice(n) {
+    return n * 2
+}
+def items = [1, twice(host.println("x")) + 1, 3]
+host.println(items)
+"#;
+    let mut vm = VM::compile_and_create_of_code(
+        "code-mid-expr".to_string(),
+        code.to_string(),
+        0,
+        vec!["println".to_string()],
+    )
+    .unwrap();
+    assert_eq!(vm.run().unwrap().typ(), 253);
+    let five = json!({ "type": "i16", "data": { "value": 5 } }).to_string();
+    assert_eq!(vm.continue_run(five).unwrap().typ(), 253);
+    let call: Value = serde_json::from_str(vm.sending_host_call_data.as_ref().unwrap()).unwrap();
+    assert_eq!(call["payload"], "[[1, 11, 3]]");
+}
+
+#[test]
+fn strings_that_do_not_convert_cast_to_null() {
+    let code = r#"host.println("abc" as i32, "1.5" as i32, "" as f64, "x" as f32, "70000" as i16)
+host.println("7" as i16, "-12" as i64, "2.5" as f64)
+"#;
+    assert_eq!(
+        run_code("code-bad-cast", code),
+        vec![
+            "[\"[undefined]\", \"[undefined]\", \"[undefined]\", \"[undefined]\", \"[undefined]\"]",
+            "[7, -12, 2.5]"
+        ]
+    );
+}
+
+#[test]
+fn bad_indexing_is_a_type_mismatch() {
+    let cases = [
+        (
+            "def n = 5\nhost.println(n[\"a\"])\n",
+            "non object value can not be indexed by string",
+        ),
+        (
+            "def o = {}\nhost.println(o[0])\n",
+            "non array value can not be indexed by integer",
+        ),
+        (
+            "def a = [1]\nhost.println(a[true])\n",
+            "types other than integer and string can not be used to index anything",
+        ),
+    ];
+    for (code, message) in cases {
+        let mut vm = VM::compile_and_create_of_code(
+            "code-bad-index".to_string(),
+            code.to_string(),
+            0,
+            vec!["println".to_string()],
+        )
+        .unwrap();
+        assert_eq!(
+            vm.run().unwrap_err(),
+            VmError::TypeMismatch(message.to_string())
+        );
+    }
+    // reading past the end is not an error
+    assert_eq!(
+        run_code(
+            "code-index-end",
+            "def a = [1]\nhost.println(a[3], a[-1], {}[\"k\"])\n"
+        ),
+        vec!["[\"[undefined]\", \"[undefined]\", \"[undefined]\"]"]
+    );
+}
+
+#[test]
+fn remainder_and_power_operators_run() {
+    let code = "host.println(7 % 3, -7 % 3, 7.5 % 2, 5 % 0)\nhost.println(2 ^ 10, 2 ^ 3 ^ 2, 2 ^ -1, 9.0 ^ 0.5, 3 ^ 40 > 0)\n";
+    assert_eq!(
+        run_code("code-rem-pow", code),
+        vec!["[1, -1, 1.5, NaN]", "[1024, 512, 0.5, 3, true]"]
+    );
+    let mut vm = VM::compile_and_create_of_code(
+        "code-rem-pow-bool".to_string(),
+        "def x = true % 2\n".to_string(),
+        0,
+        vec![],
+    )
+    .unwrap();
+    assert!(matches!(vm.run(), Err(VmError::TypeMismatch(_))));
+}
@@ -0,0 +1,218 @@
+mod common;
+
+use common::vm_of;
+use elpian_vm::api;
+use elpian_vm::sdk::{
+    debugger::{PauseReason, StepMode},
+    error::VmError,
+    vm::VM,
+};
+use serde_json::{json, Value};
+
+const HOST_APIS: &[&str] = &["println"];
+
+const PROGRAM: &str = r#"func add(a) {
+    def b = a + 1
+    return b
+}
+def x = 1
+def y = add(x)
+def z = y * 2
+"#;
+
+fn paused_line(vm: &VM) -> usize {
+    let pause = vm.pause_info().expect("run should be paused");
+    pause.stack_trace[0].span.unwrap().line
+}
+
+fn variables(vm: &VM, scope: usize) -> Vec<(String, String)> {
+    vm.scope_chain()[scope].variables.clone()
+}
+
+fn debug(machine_id: &str, command: Value) -> Value {
+    serde_json::from_str(&api::debug_vm(machine_id.to_string(), command.to_string())).unwrap()
+}
+
+#[test]
+fn breakpoints_pause_before_the_statement_runs() {
+    let mut vm = vm_of("debug-break", PROGRAM, HOST_APIS);
+    let line = vm.line_offset(6).unwrap();
+    assert_eq!(vm.set_breakpoints(&[line, line + 1]), vec![line]);
+
+    assert_eq!(vm.run().unwrap().typ(), 250);
+    let pause = vm.pause_info().unwrap();
+    assert_eq!(pause.reason, PauseReason::Breakpoint);
+    assert_eq!(pause.offset, line);
+    let globals = variables(&vm, 0);
+    assert!(globals.contains(&("x".to_string(), "1".to_string())));
+    assert!(globals.iter().all(|(name, _)| name != "y"));
+
+    // host replies and new runs wait until the pause is resolved
+    assert_eq!(vm.continue_run("true".to_string()).unwrap().typ(), 250);
+    assert_eq!(vm.run().unwrap().typ(), 250);
+    assert!(vm.is_exec_processing());
+
+    vm.debug_resume(None).unwrap();
+    assert!(!vm.is_paused());
+    let globals = variables(&vm, 0);
+    assert!(globals.contains(&("z".to_string(), "4".to_string())));
+
+    // the breakpoint stays set for the next run
+    assert_eq!(vm.run().unwrap().typ(), 250);
+    vm.abort_run();
+    assert!(!vm.is_paused());
+    assert_eq!(vm.run().unwrap().typ(), 250);
+}
+
+#[test]
+fn stepping_follows_calls_into_and_out_of_functions() {
+    let mut vm = vm_of("debug-step", PROGRAM, HOST_APIS);
+    let line = vm.line_offset(6).unwrap();
+    vm.set_breakpoints(&[line]);
+    vm.run().unwrap();
+
+    let mut lines = vec![paused_line(&vm)];
+    for step in [StepMode::Into, StepMode::Into, StepMode::Out] {
+        assert_eq!(vm.debug_resume(Some(step)).unwrap().typ(), 250);
+        assert_eq!(vm.pause_info().unwrap().reason, PauseReason::Step);
+        lines.push(paused_line(&vm));
+    }
+    assert_eq!(lines, vec![6, 2, 3, 7]);
+
+    // stepping over the call stays on the top level
+    vm.set_breakpoints(&[]);
+    vm.abort_run();
+    vm.set_breakpoints(&[line]);
+    vm.run().unwrap();
+    vm.debug_resume(Some(StepMode::Over)).unwrap();
+    assert_eq!(paused_line(&vm), 7);
+    let names: Vec<String> = vm
+        .pause_info()
+        .unwrap()
+        .stack_trace
+        .iter()
+        .map(|f| f.function.clone())
+        .collect();
+    assert_eq!(names, vec!["<main>"]);
+
+    // the step ends with the run
+    vm.debug_resume(Some(StepMode::Over)).unwrap();
+    assert!(!vm.is_paused());
+}
+
+#[test]
+fn scope_chain_lists_every_scope_while_paused_inside_a_function() {
+    let mut vm = vm_of("debug-scopes", PROGRAM, HOST_APIS);
+    let line = vm.line_offset(3).unwrap();
+    vm.set_breakpoints(&[line]);
+    vm.run().unwrap();
+
+    let chain = vm.scope_chain();
+    let tags: Vec<&str> = chain.iter().map(|s| s.tag.as_str()).collect();
+    assert_eq!(tags, vec!["funcBody", "funcBody"]);
+    assert_eq!(chain[1].function.as_deref(), Some("add"));
+    assert_eq!(
+        chain[1].variables,
+        vec![
+            ("a".to_string(), "1".to_string()),
+            ("b".to_string(), "2".to_string()),
+        ]
+    );
+    let names: Vec<String> = vm
+        .pause_info()
+        .unwrap()
+        .stack_trace
+        .iter()
+        .map(|f| f.to_string())
+        .collect();
+    assert_eq!(names, vec!["at add (3:5)", "at <main> (6:1)"]);
+}
+
+#[test]
+fn pause_on_error_keeps_the_failing_state_for_inspection() {
+    let code = "func f(n) {\n    def local = n * 2\n    return local - true\n}\nf(4)\n";
+    let mut vm = vm_of("debug-error", code, HOST_APIS);
+    vm.set_pause_on_error(true);
+
+    assert_eq!(vm.run().unwrap().typ(), 250);
+    let pause = vm.pause_info().unwrap();
+    assert_eq!(pause.reason, PauseReason::Error);
+    assert!(matches!(pause.error, Some(VmError::TypeMismatch(_))));
+    assert_eq!(paused_line(&vm), 3);
+    assert!(variables(&vm, 1).contains(&("local".to_string(), "8".to_string())));
+
+    // resuming delivers the error and the vm stays usable
+    assert!(matches!(
+        vm.debug_resume(None),
+        Err(VmError::TypeMismatch(_))
+    ));
+    assert!(!vm.is_paused());
+    assert_eq!(vm.last_stack_trace[0].function, "f");
+    vm.set_pause_on_error(false);
+    assert!(vm.run().is_err());
+}
+
+#[test]
+fn api_drives_the_debugger_with_json_commands() {
+    let id = "debug-api";
+    let code = "def a = 1\nhost.println(a)\ndef b = a + 1\n";
+    assert!(api::create_vm_from_code(
+        id.to_string(),
+        code.to_string(),
+        None
+    ));
+
+    let set = debug(
+        id,
+        json!({ "command": "setBreakpoints", "lines": [2, 9], "offsets": [1] }),
+    );
+    assert_eq!(set["unresolvedLines"], json!([9]));
+    assert_eq!(set["unresolvedOffsets"], json!([1]));
+    assert_eq!(set["breakpoints"].as_array().unwrap().len(), 1);
+
+    let result = api::execute_vm(id.to_string());
+    assert!(result.paused);
+    assert!(!result.has_host_call);
+    let state = debug(id, json!({ "command": "state" }));
+    assert_eq!(state["paused"], true);
+    assert_eq!(state["pause"]["reason"], "breakpoint");
+    assert_eq!(state["pause"]["line"], 2);
+
+    // stepping runs into the host call, which is answered as usual
+    let stepped = debug(id, json!({ "command": "stepOver" }));
+    assert_eq!(stepped["hasHostCall"], true);
+    assert_eq!(stepped["pause"], Value::Null);
+    let result = api::continue_execution(
+        id.to_string(),
+        json!({ "type": "bool", "data": { "value": true } }).to_string(),
+    );
+    assert!(result.paused);
+    assert_eq!(debug(id, json!({ "command": "state" }))["pause"]["line"], 3);
+
+    let scopes = debug(id, json!({ "command": "scopes" }));
+    assert_eq!(
+        scopes["scopes"][0]["variables"],
+        json!([{ "name": "a", "value": "1" }])
+    );
+
+    let finished = debug(id, json!({ "command": "continue" }));
+    assert_eq!(finished["paused"], false);
+    assert_eq!(finished["resultValue"], "\"done\"");
+    assert_eq!(
+        debug(id, json!({ "command": "continue" })),
+        json!({ "error": "not_paused" })
+    );
+    assert_eq!(
+        debug(id, json!({ "command": "jump" })),
+        json!({ "error": "unknown_command" })
+    );
+    assert_eq!(
+        api::debug_vm(id.to_string(), "{".to_string()),
+        json!({ "error": "bad_command" }).to_string()
+    );
+    assert_eq!(
+        api::debug_vm("debug-missing".to_string(), "{}".to_string()),
+        "\"vm_not_found\""
+    );
+    api::destroy_vm(id.to_string());
+}
@@ -0,0 +1,210 @@
+mod common;
+
+use common::{host_call, printed, string, vm_of};
+use elpian_vm::api;
+use elpian_vm::sdk::{compiler, disassembler, error::VmError};
+use serde_json::json;
+
+const HOST_APIS: &[&str] = &["println", "fetch"];
+
+#[test]
+fn thrown_values_are_caught_and_finally_always_runs() {
+    let code = r#"def log = []
+try {
+    host.println("before")
+    throw { code: 7 }
+    host.println("skipped")
+} catch (e) {
+    host.println("caught", e.code)
+} finally {
+    host.println("finally")
+}
+try {
+    host.println("clean")
+} catch {
+    host.println("skipped")
+} finally {
+    host.println("finally again")
+}
+host.println("after")
+"#;
+    assert_eq!(
+        printed(&mut vm_of("try-basic", code, HOST_APIS)),
+        vec![
+            "[\"before\"]",
+            "[\"caught\", 7]",
+            "[\"finally\"]",
+            "[\"clean\"]",
+            "[\"finally again\"]",
+            "[\"after\"]",
+        ]
+    );
+}
+
+#[test]
+fn runtime_errors_unwind_calls_to_the_nearest_catch() {
+    let code = r#"func inner(v) {
+    def broken = !v
+    return broken
+}
+func outer(v) {
+    def result = inner(v)
+    return result
+}
+func guarded(v) {
+    try {
+        return outer(v)
+    } catch (e) {
+        return [e.kind, e.message]
+    }
+}
+host.println(guarded(3))
+host.println(guarded(false))
+"#;
+    assert_eq!(
+        printed(&mut vm_of("try-runtime", code, HOST_APIS)),
+        vec![
+            "[[\"typeMismatch\", \"elpian error: not operator (!) can not be applied to non-bool value\"]]",
+            "[true]",
+        ]
+    );
+}
+
+#[test]
+fn finally_runs_on_return_and_rethrows_uncaught_errors() {
+    let code = r#"def trail = []
+func leave() {
+    try {
+        return "from try"
+    } finally {
+        host.println("cleanup")
+    }
+    return "unreachable"
+}
+func nested() {
+    try {
+        try {
+            throw "inner"
+        } finally {
+            host.println("inner finally")
+        }
+    } catch (e) {
+        host.println("outer caught", e)
+        throw "again"
+    }
+}
+host.println(leave())
+nested()
+"#;
+    let mut vm = vm_of("try-finally", code, HOST_APIS);
+    let mut lines = vec![];
+    let mut result = vm.run();
+    while let Ok(val) = &result {
+        if val.typ() != 253 {
+            break;
+        }
+        lines.push(host_call(&vm)["payload"].as_str().unwrap().to_string());
+        result = vm.continue_run("true".to_string());
+    }
+    assert_eq!(
+        lines,
+        vec![
+            "[\"cleanup\"]",
+            "[\"from try\"]",
+            "[\"inner finally\"]",
+            "[\"outer caught\", \"inner\"]",
+        ]
+    );
+    let err = result.unwrap_err();
+    assert_eq!(err, VmError::Thrown("\"again\"".to_string()));
+    assert_eq!(err.kind(), "thrown");
+    // the failed run leaves nothing behind
+    assert!(!vm.is_exec_processing());
+}
+
+#[test]
+fn host_calls_can_fail_into_the_script() {
+    let code = r#"try {
+    def page = host.fetch("/a")
+    host.println("got", page)
+} catch (e) {
+    host.println("failed", e)
+}
+def page = host.fetch("/b")
+"#;
+    let mut vm = vm_of("try-host", code, HOST_APIS);
+    assert_eq!(vm.run().unwrap().typ(), 253);
+    assert_eq!(host_call(&vm)["apiName"], "fetch");
+
+    // a snapshot taken while the call is pending keeps the try statement
+    let snapshot = vm.snapshot().unwrap();
+    let mut restored = vm_of("try-host-copy", code, HOST_APIS);
+    restored.restore(&snapshot).unwrap();
+
+    for vm in [&mut vm, &mut restored] {
+        assert_eq!(
+            vm.continue_run_with_error(string("offline")).unwrap().typ(),
+            253
+        );
+        assert_eq!(host_call(vm)["payload"], "[\"failed\", \"offline\"]");
+        assert_eq!(vm.continue_run("true".to_string()).unwrap().typ(), 253);
+        assert_eq!(host_call(vm)["payload"], "[\"/b\"]");
+        // outside a try the error ends the run
+        assert_eq!(
+            vm.continue_run_with_error(string("offline")).unwrap_err(),
+            VmError::Thrown("\"offline\"".to_string())
+        );
+    }
+}
+
+#[test]
+fn try_statements_compile_from_the_ast_and_disassemble() {
+    let ast = json!({
+        "type": "program",
+        "body": [
+            {
+                "type": "tryStmt",
+                "data": {
+                    "body": [
+                        { "type": "throwStmt", "data": { "value": { "type": "i16", "data": { "value": 1 } } } }
+                    ],
+                    "catch": { "param": "e", "body": [] },
+                    "finally": { "body": [] }
+                }
+            }
+        ]
+    });
+    let program = compiler::compile_ast(ast, 0).unwrap();
+    let listing = disassembler::disassemble(&program).unwrap();
+    assert!(listing.contains("0000  1b  try catch @001b into r0, finally @001c, after @001d"));
+    assert!(listing.contains("0017  1a  throw r0"));
+    assert!(listing.contains("001b  1c  end try"));
+    assert!(listing.contains("001c  1c  end try"));
+
+    let errors = compiler::parse_code("try {\n    throw 1\n}\n".to_string()).unwrap_err();
+    assert_eq!(
+        errors[0].message,
+        "expected 'catch' or 'finally' after the try body, found end of input"
+    );
+}
+
+#[test]
+fn api_fails_a_host_call_and_reports_uncaught_throws() {
+    let id = "try-api";
+    assert!(api::create_vm_from_code(
+        id.to_string(),
+        "def a = host.println(1)\n".to_string(),
+        None
+    ));
+    assert!(api::execute_vm(id.to_string()).has_host_call);
+    let result = api::continue_execution_with_error(id.to_string(), string("denied"));
+    assert_eq!(
+        result.error,
+        Some(VmError::Thrown("\"denied\"".to_string()))
+    );
+    assert_eq!(
+        api::continue_execution_with_error("try-missing".to_string(), string("x")).result_value,
+        "\"vm_not_found\""
+    );
+    api::destroy_vm(id.to_string());
+}
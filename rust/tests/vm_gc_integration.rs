@@ -0,0 +1,184 @@
+mod common;
+
+use std::rc::Rc;
+
+use common::{call, vm_of};
+use elpian_vm::api;
+use serde_json::{json, Value};
+
+const HOST_APIS: &[&str] = &["println"];
+
+const CHURN: &str = r#"func churn(n) {
+    def i = 0
+    loop i < n {
+        def o = { id: i }
+        o.self = o
+        i = i + 1
+    }
+    return i
+}
+"#;
+
+#[test]
+fn self_referencing_objects_are_freed_while_the_loop_runs() {
+    let mut vm = vm_of("gc-churn", CHURN, HOST_APIS);
+    vm.run().unwrap();
+    assert_eq!(call(&mut vm, "churn", 3000), "3000");
+    let stats = vm.gc_stats();
+    // the collector ran at its safe points without being asked
+    assert!(stats.collections >= 1);
+    assert!(stats.freed >= 1000);
+    assert!(stats.tracked < 3000);
+    // whatever the last collection left is freed now that churn returned
+    vm.collect_garbage();
+    let stats = vm.gc_stats();
+    assert_eq!(stats.freed, 3000);
+    assert_eq!(stats.tracked, 0);
+}
+
+#[test]
+fn parents_and_children_pointing_at_each_other_are_freed() {
+    let code = r#"func family(n) {
+    def parent = { kids: [] }
+    def i = 0
+    loop i < n {
+        Array.push(parent.kids, { parent: parent })
+        i = i + 1
+    }
+    return Array.length(parent.kids)
+}
+"#;
+    let mut vm = vm_of("gc-family", code, HOST_APIS);
+    vm.run().unwrap();
+    assert_eq!(call(&mut vm, "family", 5), "5");
+    // the parent, its array of kids and the five kids
+    assert_eq!(vm.collect_garbage(), 7);
+    assert_eq!(vm.collect_garbage(), 0);
+}
+
+#[test]
+fn recursive_inner_functions_are_freed_with_their_variable() {
+    let code = r#"func outer(n) {
+    func fact(k) {
+        if k < 2 {
+            return 1
+        }
+        return k * fact(k - 1)
+    }
+    return fact(n)
+}
+"#;
+    let mut vm = vm_of("gc-inner", code, HOST_APIS);
+    vm.run().unwrap();
+    assert_eq!(call(&mut vm, "outer", 5), "120");
+    // fact holds the variable it is stored in
+    assert_eq!(vm.collect_garbage(), 2);
+    assert_eq!(call(&mut vm, "outer", 4), "24");
+}
+
+#[test]
+fn cycles_reachable_from_the_globals_stay_intact() {
+    let code = r#"def keep = { n: 7 }
+keep.me = keep
+def ring = [keep]
+Array.push(ring, ring)
+func check(x) {
+    return keep.me.me.n + Array.length(ring[1][1]) + x
+}
+"#
+    .to_string()
+        + CHURN;
+    let mut vm = vm_of("gc-globals", &code, HOST_APIS);
+    vm.run().unwrap();
+    assert_eq!(call(&mut vm, "churn", 2000), "2000");
+    vm.collect_garbage();
+    assert_eq!(vm.gc_stats().freed, 2000);
+    assert_eq!(call(&mut vm, "check", 1), "10");
+}
+
+#[test]
+fn values_the_host_holds_survive_collection() {
+    let code = r#"func make(n) {
+    def o = { n: n }
+    o.self = o
+    return o
+}
+"#;
+    let mut vm = vm_of("gc-host", code, HOST_APIS);
+    vm.run().unwrap();
+    let input = json!({ "type": "i64", "data": { "value": 3 } }).to_string();
+    let held = vm.run_func_with_input("make", Some(&input), 0).unwrap();
+    let weak = Rc::downgrade(&held.as_object().unwrap());
+    vm.collect_garbage();
+    assert_eq!(
+        held.as_object().unwrap().borrow().data.data["self"]
+            .as_object()
+            .unwrap()
+            .borrow()
+            .data
+            .data["n"]
+            .stringify(),
+        "3"
+    );
+    drop(held);
+    vm.collect_garbage();
+    assert!(weak.upgrade().is_none());
+}
+
+#[test]
+fn destroying_the_vm_frees_the_cycles_of_its_globals() {
+    let code = r#"def keep = {}
+keep.me = keep
+func get(x) {
+    return keep
+}
+"#;
+    let mut vm = vm_of("gc-destroy", code, HOST_APIS);
+    vm.run().unwrap();
+    let input = json!({ "type": "i64", "data": { "value": 0 } }).to_string();
+    let weak = Rc::downgrade(
+        &vm.run_func_with_input("get", Some(&input), 0)
+            .unwrap()
+            .as_object()
+            .unwrap(),
+    );
+    assert!(weak.upgrade().is_some());
+    drop(vm);
+    assert!(weak.upgrade().is_none());
+}
+
+#[test]
+fn api_reports_and_forces_collections() {
+    let code = r#"def i = 0
+loop i < 10 {
+    def a = []
+    Array.push(a, a)
+    i = i + 1
+}
+"#;
+    assert!(api::create_vm_from_code(
+        "api-gc".to_string(),
+        code.to_string(),
+        None
+    ));
+    api::execute_vm("api-gc".to_string());
+    let stats: Value = serde_json::from_str(&api::get_vm_gc_stats("api-gc".to_string())).unwrap();
+    assert_eq!(stats["collections"], 0);
+    assert_eq!(stats["tracked"], 10);
+    let stats: Value =
+        serde_json::from_str(&api::collect_vm_garbage("api-gc".to_string())).unwrap();
+    // a is local to the loop body, none of the arrays outlived the run
+    assert_eq!(
+        stats,
+        json!({ "collections": 1, "freed": 10, "lastFreed": 10, "tracked": 0 })
+    );
+    assert!(api::destroy_vm("api-gc".to_string()));
+    assert_eq!(
+        api::get_vm_gc_stats("api-gc".to_string()),
+        "\"vm_not_found\""
+    );
+    assert_eq!(
+        api::collect_vm_garbage("api-gc".to_string()),
+        "\"vm_not_found\""
+    );
+}
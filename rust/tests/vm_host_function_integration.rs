@@ -0,0 +1,164 @@
+mod common;
+
+use std::{cell::RefCell, rc::Rc};
+
+use common::vm_of;
+use elpian_vm::sdk::{
+    data::{Array, Val},
+    error::VmError,
+    vm::VM,
+};
+use serde_json::{json, Value};
+
+const HOST_APIS: &[&str] = &["println"];
+
+fn call(vm: &mut VM, func_name: &str, input: i64) -> Result<Val, VmError> {
+    let input = json!({ "type": "i64", "data": { "value": input } }).to_string();
+    vm.run_func_with_input(func_name, Some(&input), 0)
+}
+
+fn int(val: &Val) -> Option<i64> {
+    match val {
+        Val::I16(n) => Some(*n as i64),
+        Val::I32(n) => Some(*n as i64),
+        Val::I64(n) => Some(*n),
+        _ => None,
+    }
+}
+
+fn add(args: &[Val]) -> Result<Val, Val> {
+    match args {
+        [a, b] => match (int(a), int(b)) {
+            (Some(a), Some(b)) => Ok(Val::I64(a + b)),
+            _ => Err(Val::string("add takes two integers")),
+        },
+        _ => Err(Val::string("add takes two integers")),
+    }
+}
+
+#[test]
+fn registered_functions_run_without_pausing() {
+    let code = r#"func sum(n) {
+    def total = 0
+    def i = 0
+    loop i < n {
+        total = host.add(total, i)
+        i = i + 1
+    }
+    return total
+}
+"#;
+    let mut vm = vm_of("native-sum", code, HOST_APIS);
+    let calls = Rc::new(RefCell::new(0));
+    let counter = calls.clone();
+    vm.register_host_function("add", move |args| {
+        *counter.borrow_mut() += 1;
+        add(args)
+    });
+    vm.run().unwrap();
+    assert_eq!(call(&mut vm, "sum", 100).unwrap().stringify(), "4950");
+    assert_eq!(*calls.borrow(), 100);
+    assert!(vm.sending_host_call_data.is_none());
+    assert!(!vm.is_exec_processing());
+}
+
+#[test]
+fn arguments_and_results_keep_their_types() {
+    let code = r#"func pick(x) {
+    return host.inspect(1, 2.5, "s", [true], { k: null })
+}
+"#;
+    let mut vm = vm_of("native-types", code, HOST_APIS);
+    vm.register_host_function("inspect", |args| {
+        let types: Vec<Val> = args.iter().map(|arg| Val::I64(arg.typ())).collect();
+        Ok(Val::array(Array::new(types)))
+    });
+    vm.run().unwrap();
+    assert_eq!(
+        call(&mut vm, "pick", 0).unwrap().stringify(),
+        "[1, 5, 7, 9, 8]"
+    );
+}
+
+#[test]
+fn unregistered_apis_still_pause_for_the_host() {
+    let code = "host.println(host.add(1, 2))\n";
+    let mut vm = vm_of("native-fallback", code, HOST_APIS);
+    vm.register_host_function("add", add);
+    assert_eq!(vm.run().unwrap().typ(), 253);
+    let call: Value = serde_json::from_str(vm.sending_host_call_data.as_ref().unwrap()).unwrap();
+    assert_eq!(call["apiName"], "println");
+    assert_eq!(call["payload"], "[3]");
+    vm.continue_run("true".to_string()).unwrap();
+
+    // without the function the same call goes to the host, which the
+    // allowlist refuses
+    assert!(vm.unregister_host_function("add"));
+    assert!(!vm.unregister_host_function("add"));
+    assert_eq!(
+        vm.run().unwrap_err(),
+        VmError::HostApiDenied("add".to_string())
+    );
+}
+
+#[test]
+fn errors_are_thrown_at_the_call_site() {
+    let code = r#"func safe(x) {
+    try {
+        return host.add(x)
+    } catch (e) {
+        return "caught " + e
+    }
+}
+func unsafe(x) {
+    return host.add(x)
+}
+"#;
+    let mut vm = vm_of("native-errors", code, HOST_APIS);
+    vm.register_host_function("add", add);
+    vm.run().unwrap();
+    assert_eq!(
+        call(&mut vm, "safe", 1).unwrap().as_string().unwrap(),
+        "caught add takes two integers"
+    );
+    assert_eq!(
+        call(&mut vm, "unsafe", 1).unwrap_err(),
+        VmError::Thrown("\"add takes two integers\"".to_string())
+    );
+    // the failed run is dropped, the vm goes on
+    assert!(!vm.is_exec_processing());
+    assert_eq!(call(&mut vm, "safe", 1).unwrap().typ(), 7);
+}
+
+#[test]
+fn async_calls_get_a_settled_promise() {
+    let code = r#"func both(x) {
+    def ok = async host.add(x, 1)
+    def bad = async host.add(x)
+    def result = await ok
+    try {
+        await bad
+    } catch (e) {
+        result = result + 100
+    }
+    return result
+}
+"#;
+    let mut vm = vm_of("native-async", code, HOST_APIS);
+    vm.register_host_function("add", add);
+    vm.run().unwrap();
+    assert_eq!(call(&mut vm, "both", 1).unwrap().stringify(), "102");
+    assert!(vm.sending_async_host_calls.is_empty());
+    assert!(!vm.is_awaiting());
+}
+
+#[test]
+fn registering_again_replaces_the_function() {
+    let code = "func get(x) {\n    return host.value()\n}\n";
+    let mut vm = vm_of("native-replace", code, HOST_APIS);
+    vm.register_host_function("value", |_| Ok(Val::I64(1)));
+    vm.run().unwrap();
+    assert_eq!(call(&mut vm, "get", 0).unwrap().stringify(), "1");
+    vm.register_host_function("value", |_| Ok(Val::string("two")));
+    assert_eq!(call(&mut vm, "get", 0).unwrap().as_string().unwrap(), "two");
+}
@@ -0,0 +1,304 @@
+mod common;
+
+use common::{errors_of, host_call, printed, vm_of};
+use elpian_vm::api;
+use elpian_vm::sdk::{error::VmError, vm::VM};
+use serde_json::{json, Value};
+
+const HOST_APIS: &[&str] = &["println"];
+
+#[test]
+fn while_and_for_loops_count() {
+    let code = r#"def total = 0
+def i = 0
+while i < 4 {
+    total = total + i
+    i = i + 1
+}
+for (def j = 0; j < 3; j = j + 1) {
+    total = total + 10
+}
+def spins = 0
+for (;;) {
+    spins = spins + 1
+    if spins == 5 {
+        break
+    }
+}
+host.println(total, i, spins)
+"#;
+    let mut vm = vm_of("loops-count", code, HOST_APIS);
+    assert_eq!(printed(&mut vm), vec!["[36, 4, 5]"]);
+}
+
+#[test]
+fn for_in_walks_keys_and_for_of_walks_items() {
+    let code = r#"def scores = { bob: 2, amy: 1, cid: 3 }
+def names = ""
+def sum = 0
+for (name in scores) {
+    names = names + name + " "
+    sum = sum + scores[name]
+}
+def items = [5, 6]
+def seen = []
+for (item of items) {
+    Array.push(seen, item)
+    if item == 5 {
+        Array.push(items, 7)
+    }
+}
+host.println(names, sum, seen)
+"#;
+    let mut vm = vm_of("loops-each", code, HOST_APIS);
+    assert_eq!(printed(&mut vm), vec!["[\"amy bob cid \", 6, [5, 6, 7]]"]);
+}
+
+#[test]
+fn labeled_break_and_continue_pick_their_loop() {
+    let code = r#"def pairs = []
+outer: for (def a = 0; a < 4; a = a + 1) {
+    for (b of [0, 1, 2]) {
+        if b == 1 {
+            continue
+        }
+        if a == 1 {
+            continue outer
+        }
+        if a == 3 {
+            break outer
+        }
+        Array.push(pairs, a * 10 + b)
+    }
+}
+host.println(pairs)
+"#;
+    let mut vm = vm_of("loops-labels", code, HOST_APIS);
+    assert_eq!(printed(&mut vm), vec!["[[0, 2, 20, 22]]"]);
+}
+
+#[test]
+fn host_calls_pause_inside_loop_bodies() {
+    let code = r#"for (v of [1, 2]) {
+    for (k in { a: 0, b: 0 }) {
+        host.println(k, v)
+    }
+}
+def n = 0
+while n < 2 {
+    n = n + 1
+    host.println(n)
+}
+"#;
+    let mut vm = vm_of("loops-pause", code, HOST_APIS);
+    assert_eq!(
+        printed(&mut vm),
+        vec![
+            "[\"a\", 1]",
+            "[\"b\", 1]",
+            "[\"a\", 2]",
+            "[\"b\", 2]",
+            "[1]",
+            "[2]"
+        ]
+    );
+
+    // a snapshot taken in the middle of a loop goes on from there
+    let mut vm = vm_of("loops-snapshot", code, HOST_APIS);
+    assert_eq!(vm.run().unwrap().typ(), 253);
+    assert_eq!(vm.continue_run("true".to_string()).unwrap().typ(), 253);
+    let snapshot = vm.snapshot().unwrap();
+    let mut restored = vm_of("loops-snapshot-copy", code, HOST_APIS);
+    restored.restore(&snapshot).unwrap();
+    assert_eq!(host_call(&restored)["payload"], "[\"b\", 1]");
+    let mut rest = vec![];
+    while restored.continue_run("true".to_string()).unwrap().typ() == 253 {
+        rest.push(
+            host_call(&restored)["payload"]
+                .as_str()
+                .unwrap()
+                .to_string(),
+        );
+    }
+    assert_eq!(rest, vec!["[\"a\", 2]", "[\"b\", 2]", "[1]", "[2]"]);
+}
+
+#[test]
+fn leaving_a_try_statement_runs_its_finally_clause() {
+    let code = r#"def log = []
+for (i of [1, 2, 3]) {
+    try {
+        try {
+            if i == 1 {
+                continue
+            }
+            if i == 2 {
+                throw "two"
+            }
+            break
+        } finally {
+            Array.push(log, "inner " + (i as string))
+        }
+    } catch (e) {
+        Array.push(log, e)
+        continue
+    } finally {
+        Array.push(log, "outer " + (i as string))
+    }
+    Array.push(log, "never")
+}
+try {
+    while true {
+        try {
+            break
+        } finally {
+            host.println("paused in finally")
+        }
+    }
+} finally {
+    Array.push(log, "done")
+}
+host.println(log)
+"#;
+    let mut vm = vm_of("loops-finally", code, HOST_APIS);
+    assert_eq!(
+        printed(&mut vm),
+        vec![
+            "[\"paused in finally\"]",
+            "[[\"inner 1\", \"outer 1\", \"inner 2\", \"two\", \"outer 2\", \"inner 3\", \"outer 3\", \"done\"]]"
+        ]
+    );
+}
+
+#[test]
+fn loops_disassemble_to_their_own_instructions() {
+    let code = "for (k in {}) {\n    try {\n        break\n    } finally {\n    }\n}\n";
+    assert_eq!(
+        api::disassemble_code(code.to_string()),
+        json!({
+            "bytecodeLength": 147,
+            "disassembly": concat!(
+                "0000  0f  r0 = {}\n",
+                "0007  2d  r0 = keys r0\n",
+                "000c  01  r1 = i16 0\n",
+                "0013  2e  r2 = next r0[r1] else jump @003c\n",
+                "001e  1b  try finally @0036, after @0037\n",
+                "002e  2f  leave 1 try, jump @003c\n",
+                "0035  1c  end try\n",
+                "0036  1c  end try\n",
+                "0037  15  jump @0013\n",
+                "003c  19  return\n",
+            )
+        })
+        .to_string()
+    );
+}
+
+#[test]
+fn closures_capture_the_variable_of_their_round() {
+    let code = r#"def getters = []
+for (v of ["a", "b"]) {
+    func get() {
+        return v
+    }
+    Array.push(getters, get)
+}
+def first = getters[0]
+def second = getters[1]
+host.println(first(), second())
+"#;
+    let mut vm = vm_of("loops-closures", code, HOST_APIS);
+    assert_eq!(printed(&mut vm), vec!["[\"a\", \"b\"]"]);
+}
+
+#[test]
+fn looping_over_the_wrong_value_is_an_error() {
+    let mut vm = vm_of("loops-of-object", "for (v of { a: 1 }) {\n}\n", HOST_APIS);
+    assert!(matches!(vm.run(), Err(VmError::TypeMismatch(_))));
+    let mut vm = vm_of("loops-in-array", "for (k in [1]) {\n}\n", HOST_APIS);
+    assert!(matches!(vm.run(), Err(VmError::TypeMismatch(_))));
+}
+
+#[test]
+fn misplaced_break_and_continue_are_syntax_errors() {
+    assert_eq!(
+        errors_of(
+            "break\nwhile true {\n    func f() {\n        continue\n    }\n    break nowhere\n}\n"
+        ),
+        vec![
+            "1:1: 'break' is only allowed in loops",
+            "4:9: 'continue' is only allowed in loops",
+            "6:5: no enclosing loop is labeled 'nowhere'",
+        ]
+    );
+    assert_eq!(
+        errors_of("here: def x = 1\na: while true {\n    a: loop true {\n    }\n}\n"),
+        vec![
+            "1:7: expected a loop after the label, found 'def'",
+            "3:5: label 'a' is already used by an enclosing loop",
+        ]
+    );
+    assert_eq!(
+        errors_of("for (return 1; true; ) {\n}\n"),
+        vec!["1:6: expected a definition, an assignment or a call in the loop head"]
+    );
+}
+
+#[test]
+fn loop_nodes_compile_from_the_ast() {
+    let int = |value: i64| json!({ "type": "i16", "data": { "value": value } });
+    let name = |name: &str| json!({ "type": "identifier", "data": { "name": name } });
+    let push = |value: Value| {
+        json!({
+            "type": "functionCall",
+            "data": {
+                "callee": {
+                    "type": "indexer",
+                    "data": {
+                        "target": name("Array"),
+                        "index": { "type": "string", "data": { "value": "push" } }
+                    }
+                },
+                "args": [name("out"), value]
+            }
+        })
+    };
+    let ast = json!({
+        "type": "program",
+        "body": [
+            { "type": "definition", "data": { "leftSide": name("out"), "rightSide": { "type": "array", "data": { "value": [] } } } },
+            {
+                "type": "forOfStmt",
+                "data": {
+                    "name": "x",
+                    "value": { "type": "array", "data": { "value": [int(1), int(2), int(3)] } },
+                    "label": "items",
+                    "body": [
+                        {
+                            "type": "whileStmt",
+                            "data": {
+                                "condition": { "type": "bool", "data": { "value": true } },
+                                "body": [
+                                    {
+                                        "type": "ifStmt",
+                                        "data": {
+                                            "condition": { "type": "arithmetic", "data": { "operation": "==", "operand1": name("x"), "operand2": int(2) } },
+                                            "body": [{ "type": "continueStmt", "data": { "label": "items" } }]
+                                        }
+                                    },
+                                    push(name("x")),
+                                    { "type": "breakStmt", "data": { "label": null } }
+                                ]
+                            }
+                        }
+                    ]
+                }
+            },
+            { "type": "host_call", "data": { "name": "println", "args": [name("out")] } }
+        ]
+    });
+    let mut vm =
+        VM::compile_and_create_of_ast("loops-ast".to_string(), ast, 0, vec!["println".to_string()])
+            .unwrap();
+    assert_eq!(printed(&mut vm), vec!["[[1, 3]]"]);
+}
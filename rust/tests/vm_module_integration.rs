@@ -0,0 +1,256 @@
+mod common;
+
+use std::collections::HashMap;
+
+use common::printed;
+use elpian_vm::api;
+use elpian_vm::sdk::{compiler, error::VmError, vm::VM};
+use serde_json::{json, Value};
+
+fn modules(sources: &[(&str, &str)]) -> HashMap<String, String> {
+    sources
+        .iter()
+        .map(|(name, source)| (name.to_string(), source.to_string()))
+        .collect()
+}
+
+fn vm_of(machine_id: &str, code: &str, sources: &[(&str, &str)]) -> VM {
+    VM::compile_and_create_of_modules(
+        machine_id.to_string(),
+        code.to_string(),
+        modules(sources),
+        vec!["println".to_string()],
+    )
+    .unwrap()
+}
+
+fn errors_of(code: &str, sources: &[(&str, &str)]) -> Vec<String> {
+    match VM::compile_and_create_of_modules(
+        "errors".to_string(),
+        code.to_string(),
+        modules(sources),
+        vec![],
+    ) {
+        Ok(_) => vec![],
+        Err(errors) => errors.iter().map(|error| error.to_string()).collect(),
+    }
+}
+
+const MATH: &str = r#"export def unit = 10
+export func add(a, b) {
+    return a + b
+}
+export func scale(a) {
+    return a * unit
+}
+"#;
+
+#[test]
+fn imported_functions_and_values_are_used_by_name() {
+    let code = r#"import { add, scale as times, unit } from "math"
+host.println(add(1, 2), times(3), unit)
+"#;
+    let mut vm = vm_of("modules-basic", code, &[("math", MATH)]);
+    assert_eq!(printed(&mut vm), vec!["[3, 30, 10]"]);
+}
+
+#[test]
+fn every_module_has_a_namespace_of_its_own() {
+    let counter = |step: i64| {
+        format!(
+            "def count = 0\nexport func next() {{\n    count = count + {}\n    return count\n}}\n",
+            step
+        )
+    };
+    let (ones, tens) = (counter(1), counter(10));
+    let code = r#"import { next } from "ones"
+import { next as nextTen } from "tens"
+def count = 100
+next()
+nextTen()
+host.println(next(), nextTen(), count)
+"#;
+    let mut vm = vm_of(
+        "modules-namespaces",
+        code,
+        &[("ones", &ones), ("tens", &tens)],
+    );
+    assert_eq!(printed(&mut vm), vec!["[2, 20, 100]"]);
+}
+
+#[test]
+fn modules_run_once_after_what_they_import() {
+    let code = r#"import { a } from "a"
+import { b } from "b"
+host.println("main")
+"#;
+    let a = "import { c } from \"c\"\nhost.println(\"a\")\nexport def a = c\n";
+    let b = "import { c } from \"c\"\nhost.println(\"b\")\nexport def b = c\n";
+    let c = "host.println(\"c\")\nexport def c = 1\n";
+    let mut asked = vec![];
+    let mut vm = VM::compile_and_create_with_resolver(
+        "modules-order".to_string(),
+        code.to_string(),
+        |name| {
+            asked.push(name.to_string());
+            modules(&[("a", a), ("b", b), ("c", c)]).remove(name)
+        },
+        vec!["println".to_string()],
+    )
+    .unwrap();
+    assert_eq!(asked, vec!["a", "c", "b"]);
+    assert_eq!(
+        printed(&mut vm),
+        vec!["[\"c\"]", "[\"a\"]", "[\"b\"]", "[\"main\"]"]
+    );
+}
+
+#[test]
+fn cyclic_imports_are_reported() {
+    let code = "import { a } from \"a\"\n";
+    let a = "import { b } from \"b\"\nexport def a = 1\n";
+    let b = "def x = 1\nimport { a } from \"a\"\nexport def b = 2\n";
+    assert_eq!(
+        errors_of(code, &[("a", a), ("b", b)]),
+        vec!["b:2:1: cyclic import: a -> b -> a"]
+    );
+    let own = "import { me } from \"me\"\nexport def me = 1\n";
+    assert_eq!(
+        errors_of("import { me } from \"me\"\n", &[("me", own)]),
+        vec!["me:1:1: cyclic import: me -> me"]
+    );
+}
+
+#[test]
+fn link_errors_name_the_module_they_are_in() {
+    let code = r#"import { add, missing } from "math"
+import { nothing } from "absent"
+import { add } from "math"
+"#;
+    assert_eq!(
+        errors_of(code, &[("math", MATH)]),
+        vec![
+            "1:1: module 'math' does not export 'missing'",
+            "2:1: module 'absent' is not found",
+            "3:1: 'add' is imported twice",
+        ]
+    );
+    let broken = "export def add = (1\n";
+    assert_eq!(
+        errors_of("import { add } from \"broken\"\n", &[("broken", broken)]),
+        vec!["broken:2:1: expected ')' to close the parenthesis, found end of input"]
+    );
+    let clash = "import { add } from \"math\"\nfunc add(a) {\n    return a\n}\n";
+    assert_eq!(
+        errors_of(clash, &[("math", MATH)]),
+        vec!["1:1: 'add' is imported and also defined in this module"]
+    );
+    // names that are not exported stay private
+    let private = "def hidden = 1\nexport def shown = 2\n";
+    assert_eq!(
+        errors_of("import { hidden } from \"p\"\n", &[("p", private)]),
+        vec!["1:1: module 'p' does not export 'hidden'"]
+    );
+}
+
+#[test]
+fn imports_and_exports_only_go_at_the_top_level() {
+    let errors = compiler::compile_code(
+        "func f(x) {\n    import { a } from \"a\"\n}\nif true {\n    export def b = 1\n}\n"
+            .to_string(),
+    )
+    .unwrap_err();
+    let messages: Vec<String> = errors.iter().map(|error| error.to_string()).collect();
+    assert_eq!(
+        messages,
+        vec![
+            "2:5: 'import' is only allowed at the top level",
+            "5:5: 'export' is only allowed at the top level",
+        ]
+    );
+    // a program compiled on its own has no modules to import
+    let errors = compiler::compile_code("import { a } from \"a\"\n".to_string()).unwrap_err();
+    assert_eq!(errors[0].to_string(), "1:1: module 'a' is not found");
+}
+
+#[test]
+fn stack_traces_point_into_the_module() {
+    let failing = "export func fail(x) {\n    return x - true\n}\n";
+    let code = "import { fail } from \"failing\"\nfunc main(x) {\n    return fail(x)\n}\n";
+    let mut vm = vm_of("modules-trace", code, &[("failing", failing)]);
+    vm.run().unwrap();
+    let input = json!({ "type": "i64", "data": { "value": 1 } }).to_string();
+    assert!(matches!(
+        vm.run_func_with_input("main", Some(&input), 0),
+        Err(VmError::TypeMismatch(_))
+    ));
+    let trace: Vec<String> = vm
+        .last_stack_trace
+        .iter()
+        .map(|frame| frame.to_string())
+        .collect();
+    assert_eq!(
+        trace,
+        vec!["at failing::fail (failing:2:5)", "at main (3:5)"]
+    );
+    assert_eq!(vm.last_stack_trace[0].to_json()["module"], "failing");
+    assert_eq!(vm.last_stack_trace[1].to_json()["module"], Value::Null);
+}
+
+#[test]
+fn reloads_link_against_the_same_modules() {
+    let code = "import { add } from \"math\"\nfunc run(x) {\n    return add(x, 1)\n}\n";
+    let mut vm = vm_of("modules-reload", code, &[("math", MATH)]);
+    vm.run().unwrap();
+    let input = json!({ "type": "i64", "data": { "value": 1 } }).to_string();
+    assert_eq!(
+        vm.run_func_with_input("run", Some(&input), 0)
+            .unwrap()
+            .stringify(),
+        "2"
+    );
+    let code = "import { add } from \"math\"\nfunc run(x) {\n    return add(x, 100)\n}\n";
+    vm.reload_code(code.to_string()).unwrap();
+    assert_eq!(
+        vm.run_func_with_input("run", Some(&input), 0)
+            .unwrap()
+            .stringify(),
+        "101"
+    );
+}
+
+#[test]
+fn api_creates_vms_from_modules() {
+    let sources = json!({ "math": MATH }).to_string();
+    let created = api::create_vm_from_modules(
+        "api-modules".to_string(),
+        "import { add } from \"math\"\nhost.println(add(2, 2))\n".to_string(),
+        sources.clone(),
+        None,
+    );
+    assert_eq!(created, json!({ "created": true }).to_string());
+    let result = api::execute_vm("api-modules".to_string());
+    let call: Value = serde_json::from_str(&result.host_call_data).unwrap();
+    assert_eq!(call["payload"], "[4]");
+    api::destroy_vm("api-modules".to_string());
+
+    let failed: Value = serde_json::from_str(&api::create_vm_from_modules(
+        "api-modules".to_string(),
+        "import { add } from \"math\"\n".to_string(),
+        json!({ "math": "export func add(a, b) {\n    return a +\n}\n" }).to_string(),
+        None,
+    ))
+    .unwrap();
+    assert_eq!(failed["errors"][0]["module"], "math");
+    assert_eq!(failed["errors"][0]["line"], 3);
+    assert!(!api::vm_exists("api-modules".to_string()));
+    assert_eq!(
+        api::create_vm_from_modules(
+            "api-modules".to_string(),
+            String::new(),
+            "[]".to_string(),
+            None
+        ),
+        json!({ "error": "bad_modules" }).to_string()
+    );
+}
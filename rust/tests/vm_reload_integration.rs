@@ -0,0 +1,142 @@
+mod common;
+
+use common::{call, vm_of};
+use elpian_vm::api;
+use elpian_vm::sdk::{
+    disassembler,
+    reload::{ReloadError, TypeChange},
+    vm::VM,
+};
+use serde_json::{json, Value};
+
+const HOST_APIS: &[&str] = &[];
+
+const FIRST: &str = r#"func tick(n) {
+    score = score + n
+    return score
+}
+func old() {
+    return 1
+}
+def score = 10
+def handlers = [tick]
+def alias = tick
+def keep = old
+def label = "a"
+"#;
+
+const SECOND: &str = r#"def score = 0
+func tick(n) {
+    score = score + n * 100
+    return score
+}
+func label() {
+    return "now a function"
+}
+func via_array(n) {
+    def h = handlers[0]
+    return h(n)
+}
+func via_alias(n) {
+    return alias(n)
+}
+"#;
+
+#[test]
+fn reload_rebinds_functions_and_keeps_other_globals() {
+    let mut vm = vm_of("reload-rebind", FIRST, HOST_APIS);
+    vm.run().unwrap();
+    assert_eq!(call(&mut vm, "tick", 1), "11");
+
+    let report = vm.reload_code(SECOND.to_string()).unwrap();
+    assert_eq!(report.dropped, vec!["keep".to_string(), "old".to_string()]);
+    assert_eq!(
+        report.changed,
+        vec![TypeChange {
+            name: "label".to_string(),
+            from: "string".to_string(),
+            to: "function".to_string(),
+        }]
+    );
+
+    // score keeps its value, the top-level def of the new code does not run
+    assert_eq!(call(&mut vm, "tick", 1), "111");
+    // copies of tick held in an array or another global follow the new code
+    assert_eq!(call(&mut vm, "via_array", 2), "311");
+    assert_eq!(call(&mut vm, "via_alias", 3), "611");
+    assert_eq!(call(&mut vm, "label", 0), "\"now a function\"");
+    assert!(disassembler::disassemble(&vm.program)
+        .unwrap()
+        .contains(" = i16 100"));
+}
+
+#[test]
+fn reload_is_refused_mid_run_and_on_syntax_errors() {
+    let code = "func f(n) {\n    return n + 1\n}\ndef a = host.println(1)\n";
+    let mut vm = VM::compile_and_create_of_code(
+        "reload-refused".to_string(),
+        code.to_string(),
+        0,
+        vec!["println".to_string()],
+    )
+    .unwrap();
+    let line = vm.line_offset(4).unwrap();
+    vm.set_breakpoints(&[line]);
+    assert_eq!(vm.run().unwrap().typ(), 250);
+    assert_eq!(
+        vm.reload_code("func f(n) { return n }".to_string()),
+        Err(ReloadError::Busy)
+    );
+    vm.abort_run();
+
+    let errors = match vm.reload_code("func f(n) {\n    return n +\n".to_string()) {
+        Err(ReloadError::Syntax(errors)) => errors,
+        other => panic!("expected syntax errors, got {:?}", other),
+    };
+    assert!(!errors.is_empty());
+    assert_eq!(call(&mut vm, "f", 1), "2");
+
+    // breakpoints belong to the old program and are cleared
+    vm.reload_code("func f(n) {\n    return n + 2\n}\n".to_string())
+        .unwrap();
+    assert!(vm.breakpoints().is_empty());
+    assert_eq!(call(&mut vm, "f", 1), "3");
+}
+
+#[test]
+fn api_reports_the_reload_as_json() {
+    let id = "reload-api";
+    assert!(api::create_vm_from_code(
+        id.to_string(),
+        FIRST.to_string(),
+        None
+    ));
+    api::execute_vm(id.to_string());
+
+    let report: Value =
+        serde_json::from_str(&api::reload_vm_code(id.to_string(), SECOND.to_string())).unwrap();
+    assert_eq!(
+        report,
+        json!({
+            "reloaded": true,
+            "dropped": ["keep", "old"],
+            "changed": [{ "name": "label", "from": "string", "to": "function" }],
+        })
+    );
+    let result = api::execute_vm_func_with_input(
+        id.to_string(),
+        "tick".to_string(),
+        json!({ "type": "i64", "data": { "value": 1 } }).to_string(),
+        0,
+    );
+    assert_eq!(result.result_value, "110");
+
+    let failed: Value =
+        serde_json::from_str(&api::reload_vm_code(id.to_string(), "def = 1".to_string())).unwrap();
+    assert_eq!(failed["errors"][0]["line"], 1);
+    assert_eq!(
+        api::reload_vm_code("reload-missing".to_string(), String::new()),
+        "\"vm_not_found\""
+    );
+    api::destroy_vm(id.to_string());
+}
@@ -0,0 +1,168 @@
+mod common;
+
+use common::{host_call, vm_of};
+use elpian_vm::api;
+use elpian_vm::sdk::error::VmError;
+use serde_json::{json, Value};
+
+const HOST_APIS: &[&str] = &["println"];
+
+const PROGRAM: &str = r#"func add(a) {
+    return a + 1
+}
+def base = [1, 2]
+def pair = [base, base]
+def node = [0, 0]
+node[1] = node
+def f = add
+def got = host.println(f(41))
+base[0] = got
+node[0] = got
+def back = node[1]
+host.println(pair, back[0], f(1))
+"#;
+
+#[test]
+fn a_run_waiting_on_the_host_continues_in_a_fresh_vm() {
+    let mut vm = vm_of("snap-source", PROGRAM, HOST_APIS);
+    assert_eq!(vm.run().unwrap().typ(), 253);
+    assert_eq!(host_call(&vm)["payload"], "[42]");
+    let snapshot = vm.snapshot().unwrap();
+
+    let mut restored = vm_of("snap-target", PROGRAM, HOST_APIS);
+    restored.restore(&snapshot).unwrap();
+    let call = host_call(&restored);
+    assert_eq!(call["machineId"], "snap-target");
+    assert_eq!(call["apiName"], "println");
+    assert_eq!(call["payload"], "[42]");
+    assert!(restored.is_exec_processing());
+
+    // both elements of pair still share base, node still contains itself
+    // and f still calls add
+    assert_eq!(restored.continue_run("7".to_string()).unwrap().typ(), 253);
+    assert_eq!(host_call(&restored)["payload"], "[[[7, 2], [7, 2]], 7, 2]");
+    restored.continue_run("true".to_string()).unwrap();
+    assert!(!restored.is_exec_processing());
+}
+
+#[test]
+fn restoring_and_saving_again_gives_the_same_snapshot() {
+    let mut vm = vm_of("snap-stable", PROGRAM, HOST_APIS);
+    vm.run().unwrap();
+    let snapshot = vm.snapshot().unwrap();
+
+    let mut restored = vm_of("snap-stable-copy", PROGRAM, HOST_APIS);
+    restored.restore(&snapshot).unwrap();
+    assert_eq!(restored.snapshot().unwrap(), snapshot);
+
+    let parsed: Value = serde_json::from_str(&snapshot).unwrap();
+    assert_eq!(parsed["format"], "elpian-snapshot");
+    assert_eq!(parsed["version"], 9);
+    assert_eq!(parsed["hostCall"]["apiName"], "println");
+    assert_eq!(parsed["hostCall"]["input"]["type"], "array");
+}
+
+#[test]
+fn globals_and_fuel_suspensions_survive_a_restore() {
+    let code = "def total = 0\nfunc grow(n) {\n    total = total + n\n    return total\n}\nloop total < 20 {\n    total = total + 1\n}\nhost.println(total)\n";
+    let mut vm = vm_of("snap-fuel", code, HOST_APIS);
+    vm.set_fuel_limit(Some(10));
+    assert_eq!(vm.run().unwrap().typ(), 251);
+    let snapshot = vm.snapshot().unwrap();
+
+    let mut restored = vm_of("snap-fuel-copy", code, HOST_APIS);
+    restored.restore(&snapshot).unwrap();
+    assert!(restored.out_of_fuel);
+    assert_eq!(restored.resume_with_fuel(10_000).unwrap().typ(), 253);
+    assert_eq!(host_call(&restored)["payload"], "[20]");
+    restored.continue_run("true".to_string()).unwrap();
+
+    // an idle vm carries its globals over
+    let idle = restored.snapshot().unwrap();
+    let mut copy = vm_of("snap-idle-copy", code, HOST_APIS);
+    copy.restore(&idle).unwrap();
+    let input = json!({ "type": "i64", "data": { "value": 5 } }).to_string();
+    let result = copy.run_func_with_input("grow", Some(&input), 0).unwrap();
+    assert_eq!(result.stringify(), "25");
+}
+
+#[test]
+fn snapshots_that_do_not_fit_are_rejected() {
+    let mut vm = vm_of("snap-reject", PROGRAM, HOST_APIS);
+    vm.run().unwrap();
+    let snapshot = vm.snapshot().unwrap();
+
+    let mut other = vm_of("snap-other", "def a = 1\n", HOST_APIS);
+    assert!(matches!(
+        other.restore(&snapshot),
+        Err(VmError::BadSnapshot(_))
+    ));
+
+    let mut target = vm_of("snap-reject-target", PROGRAM, HOST_APIS);
+    let mut newer: Value = serde_json::from_str(&snapshot).unwrap();
+    newer["version"] = json!(99);
+    let mut mistyped: Value = serde_json::from_str(&snapshot).unwrap();
+    mistyped["heap"]["cells"][0] = json!({ "Str": "not a number" });
+    for blob in [
+        "{".to_string(),
+        newer.to_string(),
+        mistyped.to_string(),
+        json!({ "format": "other" }).to_string(),
+    ] {
+        let err = target.restore(&blob).unwrap_err();
+        assert_eq!(err.kind(), "badSnapshot");
+    }
+    // a failed restore leaves the vm untouched
+    assert!(!target.is_exec_processing());
+    assert_eq!(target.run().unwrap().typ(), 253);
+
+    // a debugger pause is not part of a snapshot
+    let mut paused = vm_of("snap-paused", PROGRAM, HOST_APIS);
+    let line = paused.line_offset(5).unwrap();
+    paused.set_breakpoints(&[line]);
+    assert_eq!(paused.run().unwrap().typ(), 250);
+    assert!(matches!(paused.snapshot(), Err(VmError::BadSnapshot(_))));
+}
+
+#[test]
+fn api_moves_a_pending_run_to_another_vm() {
+    let code = "def a = host.println(1)\nhost.println(a + 1)\n";
+    assert!(api::create_vm_from_code(
+        "snap-api".to_string(),
+        code.to_string(),
+        None
+    ));
+    assert!(api::execute_vm("snap-api".to_string()).has_host_call);
+    let snapshot = api::snapshot_vm("snap-api".to_string());
+    api::destroy_vm("snap-api".to_string());
+
+    assert!(api::create_vm_from_code(
+        "snap-api-copy".to_string(),
+        code.to_string(),
+        None
+    ));
+    let restored: Value =
+        serde_json::from_str(&api::restore_vm("snap-api-copy".to_string(), snapshot)).unwrap();
+    assert_eq!(restored["hasHostCall"], true);
+    let call: Value = serde_json::from_str(restored["hostCallData"].as_str().unwrap()).unwrap();
+    assert_eq!(call["machineId"], "snap-api-copy");
+
+    let result = api::continue_execution(
+        "snap-api-copy".to_string(),
+        json!({ "type": "i64", "data": { "value": 4 } }).to_string(),
+    );
+    let call: Value = serde_json::from_str(&result.host_call_data).unwrap();
+    assert_eq!(call["payload"], "[5]");
+
+    let rejected: Value = serde_json::from_str(&api::restore_vm(
+        "snap-api-copy".to_string(),
+        "{}".to_string(),
+    ))
+    .unwrap();
+    assert_eq!(rejected["error"]["kind"], "badSnapshot");
+    assert_eq!(
+        api::snapshot_vm("snap-missing".to_string()),
+        "\"vm_not_found\""
+    );
+    api::destroy_vm("snap-api-copy".to_string());
+}
@@ -0,0 +1,158 @@
+mod common;
+
+use common::{host_call, vm_of};
+use elpian_vm::sdk::{data::Val, error::VmError, vm::VM};
+
+const HOST_APIS: &[&str] = &["println", "fetch"];
+
+// answers every println until the run ends and collects their payloads
+fn drain(vm: &mut VM, mut result: Result<Val, VmError>) -> Vec<String> {
+    let mut lines = vec![];
+    while result.as_ref().unwrap().typ() == 253 {
+        lines.push(host_call(vm)["payload"].as_str().unwrap().to_string());
+        result = vm.continue_run("true".to_string());
+    }
+    lines
+}
+
+fn printed(machine_id: &str, code: &str) -> Vec<String> {
+    let mut vm = vm_of(machine_id, code, HOST_APIS);
+    let result = vm.run();
+    drain(&mut vm, result)
+}
+
+#[test]
+fn strings_math_and_json_are_built_in() {
+    let code = r#"def s = "  Hello, World  "
+host.println(String.trim(s), String.length(s), String.upper("ab"), String.lower("CD"))
+host.println(String.split("a,b,c", ","), String.indexOf("hello", "l"), String.replace("a-b-c", "-", "+"))
+host.println(String.substring("hello", 1, 3), Math.sqrt(16), Math.floor(2.7), Math.max(3, 9, 4), Math.abs(-2))
+def data = JSON.parse("{\"b\": [1, 2.5, true], \"a\": \"x\"}")
+host.println(data.b, JSON.stringify({ x: [1, "two"] }), Object.keys(data))
+def xs = [3, 1, 2]
+Array.push(xs, 0)
+host.println(Array.sort(xs), Array.slice(xs, -2), Array.join(xs, "-"), Array.length(xs))
+"#;
+    assert_eq!(
+        printed("stdlib-basic", code),
+        vec![
+            "[\"Hello, World\", 16, \"AB\", \"cd\"]",
+            "[[\"a\", \"b\", \"c\"], 2, \"a+b+c\"]",
+            "[\"el\", 4, 2, 9, 2]",
+            "[[1, 2.5, true], \"{\\\"x\\\":[1,\\\"two\\\"]}\", [\"a\", \"b\"]]",
+            "[[0, 1, 2, 3], [2, 3], \"0-1-2-3\", 4]",
+        ]
+    );
+}
+
+const WALKS: &str = r#"def base = 10
+func make_adder(n) {
+    func add(v, i) {
+        host.println("item", i)
+        return v + n + base
+    }
+    return add
+}
+func sum(acc, v) {
+    return acc + v
+}
+func big(v) {
+    return v > 1
+}
+def xs = [1, 2, 3]
+host.println(Array.map(xs, make_adder(100)), Array.filter(xs, big), Array.reduce(xs, sum, 0), Array.map([], big))
+"#;
+
+#[test]
+fn map_filter_and_reduce_call_script_functions() {
+    assert_eq!(
+        printed("stdlib-walks", WALKS),
+        vec![
+            "[\"item\", 0]",
+            "[\"item\", 1]",
+            "[\"item\", 2]",
+            "[[111, 112, 113], [2, 3], 6, []]",
+        ]
+    );
+}
+
+#[test]
+fn a_walk_paused_in_a_callback_survives_a_snapshot() {
+    let mut vm = vm_of("stdlib-snapshot", WALKS, HOST_APIS);
+    assert_eq!(vm.run().unwrap().typ(), 253);
+    assert_eq!(host_call(&vm)["payload"], "[\"item\", 0]");
+    let result = vm.continue_run("true".to_string());
+    assert_eq!(host_call(&vm)["payload"], "[\"item\", 1]");
+
+    let snapshot = vm.snapshot().unwrap();
+    let mut restored = vm_of("stdlib-snapshot-copy", WALKS, HOST_APIS);
+    restored.restore(&snapshot).unwrap();
+    let expected = vec![
+        "[\"item\", 1]",
+        "[\"item\", 2]",
+        "[[111, 112, 113], [2, 3], 6, []]",
+    ];
+    assert_eq!(drain(&mut vm, result), expected);
+    let result = Ok(restored.continue_run("true".to_string()).unwrap());
+    assert_eq!(drain(&mut restored, result), expected[1..]);
+}
+
+#[test]
+fn seeded_random_numbers_repeat() {
+    let code = r#"Math.seed(42)
+def a = [Math.random(), Math.random()]
+Math.seed(42)
+def b = [Math.random(), Math.random()]
+host.println(a == b, a[0] == a[1], a[0] >= 0, a[0] < 1)
+"#;
+    assert_eq!(
+        printed("stdlib-random", code),
+        vec!["[true, false, true, true]"]
+    );
+}
+
+#[test]
+fn bad_arguments_raise_catchable_type_errors() {
+    let code = r#"try {
+    Math.sqrt("x")
+} catch (e) {
+    host.println(e.kind, e.message)
+}
+try {
+    JSON.parse("{")
+} catch (e) {
+    host.println(e.kind)
+}
+Array.map(1, Math.abs)
+"#;
+    let mut vm = vm_of("stdlib-errors", code, HOST_APIS);
+    let mut lines = vec![];
+    let mut result = vm.run();
+    while let Ok(val) = &result {
+        if val.typ() != 253 {
+            break;
+        }
+        lines.push(host_call(&vm)["payload"].as_str().unwrap().to_string());
+        result = vm.continue_run("true".to_string());
+    }
+    assert_eq!(
+        lines,
+        vec![
+            "[\"typeMismatch\", \"elpian error: Math.sqrt expects a number\"]",
+            "[\"typeMismatch\"]",
+        ]
+    );
+    // uncaught they end the run
+    assert_eq!(
+        result.unwrap_err(),
+        VmError::TypeMismatch("Array.map expects an array".to_string())
+    );
+}
+
+#[test]
+fn scripts_may_define_their_own_namespaces() {
+    let code = r#"def Math = { sqrt: 7 }
+host.println(Math.sqrt, String.length("abc"))
+"#;
+    assert_eq!(printed("stdlib-shadowed", code), vec!["[7, 3]"]);
+}
@@ -0,0 +1,170 @@
+mod common;
+
+use common::{host_call, vm_of};
+use elpian_vm::api;
+use elpian_vm::sdk::{error::VmError, vm::VM};
+
+const HOST_APIS: &[&str] = &["println"];
+
+// ticks at `now` and answers every println the callbacks make
+fn tick(vm: &mut VM, now: i64) -> Vec<String> {
+    let mut lines = vec![];
+    let mut result = vm.tick(now).unwrap();
+    while result.typ() == 253 {
+        lines.push(host_call(vm)["payload"].as_str().unwrap().to_string());
+        result = vm.continue_run("true".to_string()).unwrap();
+    }
+    lines
+}
+
+const GAME_LOOP: &str = r#"def frames = 0
+func frame(now) {
+    frames = frames + 1
+    host.println("frame", now)
+    if frames < 3 {
+        requestFrame(frame)
+    }
+}
+func once(now) {
+    host.println("once", now)
+}
+func beat(now) {
+    host.println("beat", now)
+}
+def beat_id = setInterval(beat, 100)
+setTimeout(once, 50)
+requestFrame(frame)
+func stop(now) {
+    clearTimer(beat_id)
+}
+setTimeout(stop, 250)
+"#;
+
+#[test]
+fn due_callbacks_run_in_order_on_each_tick() {
+    let mut vm = vm_of("timers-loop", GAME_LOOP, HOST_APIS);
+    vm.run().unwrap();
+    assert_eq!(vm.timer_count(), 4);
+    assert_eq!(vm.next_timer_due(), Some(0));
+
+    assert_eq!(tick(&mut vm, 16), vec!["[\"frame\", 16]"]);
+    // the frame asked for another one, due from this tick on
+    assert_eq!(vm.next_timer_due(), Some(16));
+    assert_eq!(tick(&mut vm, 60), vec!["[\"frame\", 60]", "[\"once\", 60]"]);
+    assert_eq!(
+        tick(&mut vm, 120),
+        vec!["[\"frame\", 120]", "[\"beat\", 120]"]
+    );
+    assert_eq!(vm.next_timer_due(), Some(200));
+    assert!(tick(&mut vm, 150).is_empty());
+    // stop clears the interval after its last beat
+    assert_eq!(tick(&mut vm, 260), vec!["[\"beat\", 260]"]);
+    assert_eq!(vm.next_timer_due(), None);
+    assert!(tick(&mut vm, 1000).is_empty());
+}
+
+#[test]
+fn a_failing_callback_ends_the_tick_and_leaves_the_rest_due() {
+    let code = r#"func bad(now) {
+    def flipped = !now
+}
+func good(now) {
+    host.println("good", now)
+}
+setTimeout(bad, 10)
+setTimeout(good, 10)
+"#;
+    let mut vm = vm_of("timers-error", code, HOST_APIS);
+    vm.run().unwrap();
+    assert!(matches!(vm.tick(10), Err(VmError::TypeMismatch(_))));
+    assert!(!vm.is_exec_processing());
+    assert_eq!(vm.next_timer_due(), Some(10));
+    assert_eq!(tick(&mut vm, 11), vec!["[\"good\", 11]"]);
+
+    let mut vm = vm_of("timers-bad-args", "setTimeout(1, 10)\n", HOST_APIS);
+    assert_eq!(
+        vm.run().unwrap_err(),
+        VmError::TypeMismatch("setTimeout expects a function".to_string())
+    );
+}
+
+#[test]
+fn scripts_may_define_their_own_timer_functions() {
+    let code = r#"func setTimeout(f, ms) {
+    return ms * 2
+}
+host.println(setTimeout(1, 21))
+"#;
+    let mut vm = vm_of("timers-shadowed", code, HOST_APIS);
+    assert_eq!(vm.run().unwrap().typ(), 253);
+    assert_eq!(host_call(&vm)["payload"], "[42]");
+    assert_eq!(vm.timer_count(), 0);
+}
+
+const COUNTER: &str = r#"func every(label, ms) {
+    def count = 0
+    func fire(now) {
+        count = count + 1
+        host.println(label, count)
+    }
+    return setInterval(fire, ms)
+}
+every("x", 10)
+every("y", 10)
+"#;
+
+#[test]
+fn a_tick_paused_on_a_host_call_survives_a_snapshot() {
+    let mut vm = vm_of("timers-snapshot", COUNTER, HOST_APIS);
+    vm.run().unwrap();
+    assert_eq!(vm.tick(10).unwrap().typ(), 253);
+    assert_eq!(host_call(&vm)["payload"], "[\"x\", 1]");
+
+    let snapshot = vm.snapshot().unwrap();
+    let mut restored = vm_of("timers-snapshot-copy", COUNTER, HOST_APIS);
+    restored.restore(&snapshot).unwrap();
+
+    for vm in [&mut vm, &mut restored] {
+        // the rest of the tick runs once the call is answered
+        assert_eq!(vm.continue_run("true".to_string()).unwrap().typ(), 253);
+        assert_eq!(host_call(vm)["payload"], "[\"y\", 1]");
+        assert_eq!(vm.continue_run("true".to_string()).unwrap().typ(), 254);
+        assert_eq!(tick(vm, 20), vec!["[\"x\", 2]", "[\"y\", 2]"]);
+    }
+}
+
+#[test]
+fn timer_callbacks_follow_a_reload() {
+    let mut vm = vm_of("timers-reload", COUNTER, HOST_APIS);
+    vm.run().unwrap();
+    assert_eq!(tick(&mut vm, 10), vec!["[\"x\", 1]", "[\"y\", 1]"]);
+    vm.reload_code(COUNTER.replace("count + 1", "count + 10"))
+        .unwrap();
+    assert_eq!(tick(&mut vm, 20), vec!["[\"x\", 11]", "[\"y\", 11]"]);
+}
+
+#[test]
+fn api_ticks_and_reports_the_next_wake_up() {
+    let id = "timers-api";
+    assert!(api::create_vm_from_code(
+        id.to_string(),
+        GAME_LOOP.to_string(),
+        None
+    ));
+    let result = api::execute_vm(id.to_string());
+    assert_eq!(result.next_timer_ms, Some(0));
+
+    let result = api::tick_vm(id.to_string(), 16);
+    assert!(result.has_host_call);
+    // a tick paused on a host call keeps the vm busy
+    assert_eq!(api::tick_vm(id.to_string(), 17).result_value, "\"vm_busy\"");
+    let result = api::continue_execution(id.to_string(), "true".to_string());
+    assert_eq!(result.result_value, "\"done\"");
+    assert_eq!(result.next_timer_ms, Some(16));
+
+    assert_eq!(
+        api::tick_vm("timers-missing".to_string(), 0).result_value,
+        "\"vm_not_found\""
+    );
+    api::destroy_vm(id.to_string());
+}
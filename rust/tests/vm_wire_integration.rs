@@ -0,0 +1,354 @@
+mod common;
+
+use std::collections::HashMap;
+
+use common::{host_call, vm_of};
+use elpian_vm::api;
+use elpian_vm::sdk::{
+    data::{Array, Object, Val, ValGroup},
+    error::VmError,
+    vm::VM,
+    wire::{self, Refs},
+};
+use serde_json::{json, Value};
+
+const HOST_APIS: &[&str] = &["println", "echo"];
+
+// the values the host call's arguments array holds
+fn args(vm: &VM) -> Value {
+    host_call(vm)["input"]["data"]["value"].clone()
+}
+
+struct NoRefs;
+
+impl Refs for NoRefs {
+    fn function(&self, _: &str) -> Option<Val> {
+        None
+    }
+    fn promise(&self, _: i64) -> Option<Val> {
+        None
+    }
+}
+
+fn round_trip(val: &Val) -> Val {
+    let typed = wire::encode(val).unwrap();
+    let text = typed.to_string();
+    let back = wire::decode(&serde_json::from_str(&text).unwrap(), &NoRefs).unwrap();
+    // the binary form decodes to the same typed tree
+    assert_eq!(
+        wire::from_binary(&wire::to_binary(&typed).unwrap()).unwrap(),
+        typed
+    );
+    back
+}
+
+#[test]
+fn host_call_inputs_keep_their_types() {
+    let code = r#"func double(x) {
+    return x * 2
+}
+host.println(1, 2.5, "two", true, { n: 3 }, [4], double)
+"#;
+    let mut vm = vm_of("wire-types", code, HOST_APIS);
+    assert_eq!(vm.run().unwrap().typ(), 253);
+    let call = host_call(&vm);
+    // the deprecated stringified payload is still there for older hosts
+    assert_eq!(
+        call["payload"],
+        "[1, 2.5, \"two\", true, { \"n\": 3 }, [4], \"double\"]"
+    );
+    assert_eq!(call["input"]["type"], "array");
+    assert_eq!(
+        args(&vm),
+        json!([
+            { "type": "i16", "data": { "value": 1 } },
+            { "type": "f64", "data": { "value": 2.5 } },
+            { "type": "string", "data": { "value": "two" } },
+            { "type": "bool", "data": { "value": true } },
+            { "type": "object", "data": { "value": { "n": { "type": "i16", "data": { "value": 3 } } } } },
+            { "type": "array", "data": { "value": [{ "type": "i16", "data": { "value": 4 } }] } },
+            { "type": "function", "data": { "value": "double" } },
+        ])
+    );
+}
+
+#[test]
+fn every_value_survives_the_round_trip() {
+    let mut props = HashMap::new();
+    props.insert("tiny".to_string(), Val::F32(0.1));
+    props.insert("wide".to_string(), Val::F64(0.1 + 0.2));
+    props.insert("nan".to_string(), Val::F64(f64::NAN));
+    props.insert("inf".to_string(), Val::F32(f32::NEG_INFINITY));
+    let object = Val::object(Object::new(5, ValGroup::new(props)));
+    let val = Val::array(Array::new(vec![
+        Val::I16(-7),
+        Val::I32(70_000),
+        Val::I64(i64::MAX),
+        Val::Bool(false),
+        Val::string("\u{e9}t\u{e9}".to_string()),
+        Val::Null,
+        object,
+    ]));
+    let back = round_trip(&val);
+    let items = back.as_array().unwrap().borrow().data.clone();
+    let types: Vec<i64> = items.iter().map(|item| item.typ()).collect();
+    assert_eq!(types, vec![1, 2, 3, 6, 7, 0, 8]);
+    assert_eq!(items[2].as_i64(), Ok(i64::MAX));
+    let object = items[6].as_object().unwrap();
+    let object = object.borrow();
+    assert_eq!(object.typ, 5);
+    assert_eq!(object.data.data["tiny"].as_f32(), Ok(0.1));
+    assert_eq!(object.data.data["wide"].as_f64(), Ok(0.1 + 0.2));
+    assert!(object.data.data["nan"].as_f64().unwrap().is_nan());
+    assert_eq!(object.data.data["inf"].as_f32(), Ok(f32::NEG_INFINITY));
+}
+
+#[test]
+fn functions_handed_out_come_back_callable() {
+    let code = r#"func double(x) {
+    return x * 2
+}
+def back = host.echo(double)
+host.println(back(21))
+"#;
+    let mut vm = vm_of("wire-function", code, HOST_APIS);
+    vm.run().unwrap();
+    let handed = args(&vm)[0].clone();
+    assert_eq!(
+        handed,
+        json!({ "type": "function", "data": { "value": "double" } })
+    );
+    vm.continue_run(handed.to_string()).unwrap();
+    assert_eq!(
+        args(&vm)[0],
+        json!({ "type": "i16", "data": { "value": 42 } })
+    );
+    vm.continue_run("true".to_string()).unwrap();
+    assert!(!vm.is_exec_processing());
+}
+
+#[test]
+fn unknown_functions_in_a_reply_abort_the_run() {
+    let mut vm = vm_of("wire-unknown", "def back = host.echo(1)\n", HOST_APIS);
+    vm.run().unwrap();
+    let reply = json!({ "type": "function", "data": { "value": "missing" } }).to_string();
+    assert_eq!(
+        vm.continue_run(reply).unwrap_err(),
+        VmError::BadHostReply("no function named 'missing'".to_string())
+    );
+    assert!(!vm.is_exec_processing());
+}
+
+#[test]
+fn integers_out_of_their_range_are_refused() {
+    let error = |typ: &str, value: i64| {
+        let reply = json!({ "type": typ, "data": { "value": value } });
+        (
+            wire::decode(&reply, &NoRefs).unwrap_err(),
+            wire::to_binary(&reply).unwrap_err(),
+        )
+    };
+    let expected = VmError::BadHostReply("40000 does not fit in an i16".to_string());
+    assert_eq!(error("i16", 40_000), (expected.clone(), expected));
+    let expected = VmError::BadHostReply("-3000000000 does not fit in an i32".to_string());
+    assert_eq!(error("i32", -3_000_000_000), (expected.clone(), expected));
+
+    let mut vm = vm_of("wire-overflow", "def back = host.echo(1)\n", HOST_APIS);
+    vm.run().unwrap();
+    let reply = json!({ "type": "i16", "data": { "value": 70_000 } }).to_string();
+    assert_eq!(
+        vm.continue_run(reply).unwrap_err(),
+        VmError::BadHostReply("70000 does not fit in an i16".to_string())
+    );
+}
+
+#[test]
+fn typed_values_with_the_wrong_payload_are_refused() {
+    let error = |reply: Value| wire::decode(&reply, &NoRefs).unwrap_err();
+    assert_eq!(
+        error(json!({ "type": "i64", "data": { "value": 1.5 } })),
+        VmError::BadHostReply("1.5 is not a valid i64 value".to_string())
+    );
+    assert_eq!(
+        error(json!({ "type": "bool", "data": { "value": "x" } })),
+        VmError::BadHostReply("\"x\" is not a valid bool value".to_string())
+    );
+    assert_eq!(
+        error(json!({ "type": "array", "data": {} })),
+        VmError::BadHostReply("null is not a valid array value".to_string())
+    );
+    assert_eq!(
+        error(json!({ "type": "date", "data": { "value": 1 } })),
+        VmError::BadHostReply("unknown type 'date'".to_string())
+    );
+    // plain JSON that happens to have a type prop is still an object
+    let plain = wire::decode(&json!({ "type": "click", "x": 1 }), &NoRefs).unwrap();
+    let plain = plain.as_object().unwrap();
+    assert_eq!(plain.borrow().data.data["type"].as_str(), Ok("click"));
+}
+
+#[test]
+fn closures_the_host_can_not_call_back_are_refused() {
+    let code = r#"func double(x) {
+    return x * 2
+}
+func make(k) {
+    func double(x) {
+        return x * k
+    }
+    host.println(double)
+}
+make(3)
+"#;
+    let mut vm = vm_of("wire-closure", code, HOST_APIS);
+    assert_eq!(
+        vm.run().unwrap_err(),
+        VmError::TypeMismatch(
+            "closure 'double' can not be sent to the host, another function has its name"
+                .to_string()
+        )
+    );
+    // a closure of its own name is sent, as often as it is passed
+    let code = r#"func make(k) {
+    func times(x) {
+        return x * k
+    }
+    host.println([times, times])
+}
+make(3)
+"#;
+    let mut vm = vm_of("wire-closure-ok", code, HOST_APIS);
+    assert_eq!(vm.run().unwrap().typ(), 253);
+}
+
+#[test]
+fn closures_sent_by_earlier_host_calls_keep_their_name() {
+    let code = r#"func make(i) {
+    func handler() {
+        return i
+    }
+    return handler
+}
+def first = make(1)
+host.println(first)
+host.println(first)
+host.println(make(2))
+"#;
+    let mut vm = vm_of("wire-closure-calls", code, HOST_APIS);
+    assert_eq!(vm.run().unwrap().typ(), 253);
+    // the same closure again is fine
+    assert_eq!(vm.continue_run("true".to_string()).unwrap().typ(), 253);
+    // another one under its name is not, the host holds the first
+    assert_eq!(
+        vm.continue_run("true".to_string()).unwrap_err(),
+        VmError::TypeMismatch(
+            "closure 'handler' can not be sent to the host, another function has its name"
+                .to_string()
+        )
+    );
+}
+
+#[test]
+fn values_holding_themselves_are_refused() {
+    let code = "def a = [1]\nArray.push(a, a)\nhost.println(a)\n";
+    let mut vm = vm_of("wire-cycle", code, HOST_APIS);
+    assert_eq!(
+        vm.run().unwrap_err(),
+        VmError::TypeMismatch(
+            "a value that contains itself can not be sent to the host".to_string()
+        )
+    );
+    // shared values that do not hold themselves are copied
+    let code = "def a = [1]\nhost.println([a, a])\n";
+    let mut vm = vm_of("wire-shared", code, HOST_APIS);
+    vm.run().unwrap();
+    assert_eq!(host_call(&vm)["payload"], "[[[1], [1]]]");
+}
+
+#[test]
+fn async_host_calls_carry_the_typed_input() {
+    let code = "def p = askHostAsync(\"echo\", [3, \"x\"])\n";
+    let mut vm = vm_of("wire-async", code, HOST_APIS);
+    vm.run().unwrap();
+    let call: Value = serde_json::from_str(&vm.sending_async_host_calls[0]).unwrap();
+    assert_eq!(call["payload"], "[3, \"x\"]");
+    assert_eq!(
+        call["input"],
+        json!({ "type": "array", "data": { "value": [
+            { "type": "i16", "data": { "value": 3 } },
+            { "type": "string", "data": { "value": "x" } },
+        ] } })
+    );
+}
+
+#[test]
+fn binary_replies_answer_host_calls() {
+    let code = "def v = host.echo(0.5)\nhost.println(v)\n";
+    let mut vm = vm_of("wire-binary", code, HOST_APIS);
+    vm.run().unwrap();
+    let input = vm.host_call_input_binary().unwrap();
+    // an array of one f64
+    let mut expected = vec![9, 1, 0, 0, 0, 5];
+    expected.extend_from_slice(&0.5f64.to_bits().to_le_bytes());
+    assert_eq!(input, expected);
+    assert_eq!(wire::from_binary(&input).unwrap(), host_call(&vm)["input"]);
+
+    // an i32 5
+    vm.continue_run_binary(&[2, 5, 0, 0, 0]).unwrap();
+    assert_eq!(
+        args(&vm)[0],
+        json!({ "type": "i32", "data": { "value": 5 } })
+    );
+    vm.continue_run("true".to_string()).unwrap();
+    assert!(vm.host_call_input_binary().is_none());
+}
+
+#[test]
+fn malformed_binary_values_are_rejected() {
+    let error = |bytes: &[u8]| wire::from_binary(bytes).unwrap_err();
+    assert_eq!(
+        error(&[3, 1, 0]),
+        VmError::BadHostReply("binary value is truncated at byte 1".to_string())
+    );
+    assert_eq!(
+        error(&[0, 0]),
+        VmError::BadHostReply("binary value has 1 bytes left over".to_string())
+    );
+    assert_eq!(
+        error(&[12]),
+        VmError::BadHostReply("unknown type code 12 at byte 0".to_string())
+    );
+    assert_eq!(
+        error(&[7, 2, 0, 0, 0, 0xff, 0xfe]),
+        VmError::BadHostReply("binary string at byte 5 is not UTF-8".to_string())
+    );
+}
+
+#[test]
+fn api_hands_out_and_takes_binary_values() {
+    assert!(api::create_vm_from_code(
+        "api-wire".to_string(),
+        "def v = host.echo(7)\nhost.println(v + 1)\n".to_string(),
+        Some(vec!["echo".to_string(), "println".to_string()]),
+    ));
+    api::execute_vm("api-wire".to_string());
+    assert_eq!(
+        api::get_host_call_input_binary("api-wire".to_string()),
+        Some(vec![9, 1, 0, 0, 0, 1, 7, 0])
+    );
+    let result =
+        api::continue_execution_binary("api-wire".to_string(), vec![3, 9, 0, 0, 0, 0, 0, 0, 0]);
+    assert!(result.has_host_call);
+    let call: Value = serde_json::from_str(&result.host_call_data).unwrap();
+    assert_eq!(
+        call["input"]["data"]["value"][0],
+        json!({ "type": "i16", "data": { "value": 10 } })
+    );
+    let result = api::continue_execution_binary("api-wire".to_string(), vec![1, 0]);
+    assert!(!result.has_host_call);
+    assert_eq!(
+        api::get_host_call_input_binary("api-wire".to_string()),
+        None
+    );
+    api::destroy_vm("api-wire".to_string());
+}